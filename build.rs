@@ -0,0 +1,27 @@
+//! Writes `include/ubuffer.h`, the header for the C interface in `src/ffi.rs`,
+//! so that the two cannot drift apart. (See: `cbindgen.toml`.)
+
+use std::env;
+use std::path::Path;
+
+fn main() {
+	let root = env::var("CARGO_MANIFEST_DIR").expect("cargo sets the manifest dir");
+	let root = Path::new(&root);
+
+	println!("cargo:rerun-if-changed=cbindgen.toml");
+	let config = cbindgen::Config::from_file(root.join("cbindgen.toml"))
+		.expect("could not read cbindgen.toml");
+
+	// only the interface is read, the opaque types it hands out are declared
+	// in the config since their rust docs mean nothing to a C caller
+	println!("cargo:rerun-if-changed=src/ffi.rs");
+	let builder = cbindgen::Builder::new()
+		.with_config(config)
+		.with_src(root.join("src/ffi.rs"));
+
+	// the header is only rewritten if it changed, so this does not rebuild
+	// anything which includes it
+	builder.generate()
+		.expect("could not generate the C header from src/ffi.rs")
+		.write_to_file(root.join("include/ubuffer.h"));
+}
@@ -0,0 +1,147 @@
+/*
+ * The C interface to the ubuffer library. (See: src/ffi.rs.)
+ *
+ * Link against libubuffer.so or libubuffer.a from `cargo build --release`.
+ * Each sender or receiver is created w/ its address & key, run once, and then
+ * freed. A function which fails returns NULL or -1, and ubuffer_last_error()
+ * describes what went wrong on the calling thread.
+ */
+
+#ifndef UBUFFER_H
+#define UBUFFER_H
+
+/* Written by cbindgen from src/ffi.rs when ubuffer is built, do not edit. */
+
+#include <stddef.h>
+#include <stdint.h>
+/* The functions which take a file descriptor only exist on unix. */
+#if !defined(_WIN32)
+#define UBUFFER_UNIX
+#endif
+
+/* A sender or receiver, which is only ever handled through a pointer. */
+typedef struct ubuffer_sender ubuffer_sender;
+typedef struct ubuffer_receiver ubuffer_receiver;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Describes the last failure on the calling thread, or returns `NULL` if
+ nothing has failed. The string is valid until the next call which fails.
+ */
+const char *ubuffer_last_error(void);
+
+/*
+ Connects to the receiver at `addr` (i.e: "10.0.0.1:9999"), which will
+ use the `key_len` bytes at `key` to encrypt the session.
+
+ # Safety
+
+ `addr` must be a NUL terminated string, and `key` must point to at least
+ `key_len` bytes.
+ */
+ubuffer_sender *ubuffer_sender_new(const char *addr, const uint8_t *key, size_t key_len);
+
+/*
+ Sends the file at `path`, returning 0 once the receiver has all of it.
+
+ # Safety
+
+ `sender` must have come from `ubuffer_sender_new()`, and `path` must be
+ a NUL terminated string.
+ */
+int ubuffer_sender_run_file(ubuffer_sender *sender, const char *path);
+
+#if defined(UBUFFER_UNIX)
+/*
+ Sends everything read from the file descriptor `fd` until EOF. The
+ descriptor stays open, and is still owned by the caller.
+
+ # Safety
+
+ `sender` must have come from `ubuffer_sender_new()`, and `fd` must be
+ open for reading.
+ */
+int ubuffer_sender_run_fd(ubuffer_sender *sender, int fd);
+#endif
+
+/*
+ Returns the number of plaintext bytes the sender has sent so far.
+
+ # Safety
+
+ `sender` must have come from `ubuffer_sender_new()`.
+ */
+uint64_t ubuffer_sender_bytes(const ubuffer_sender *sender);
+
+/*
+ Hangs up on the receiver, if the session is not over, & frees `sender`.
+
+ # Safety
+
+ `sender` must have come from `ubuffer_sender_new()`, or be `NULL`. It
+ must not be used again.
+ */
+void ubuffer_sender_free(ubuffer_sender *sender);
+
+/*
+ Waits for a sender on `addr` (i.e: "0.0.0.0:9999"), which will use the
+ `key_len` bytes at `key` to encrypt the session.
+
+ # Safety
+
+ `addr` must be a NUL terminated string, and `key` must point to at least
+ `key_len` bytes.
+ */
+ubuffer_receiver *ubuffer_receiver_new(const char *addr, const uint8_t *key, size_t key_len);
+
+/*
+ Writes the session to the file at `path`, which is created or truncated.
+ Returns 0 once the sender has said goodbye.
+
+ # Safety
+
+ `receiver` must have come from `ubuffer_receiver_new()`, and `path` must
+ be a NUL terminated string.
+ */
+int ubuffer_receiver_run_file(ubuffer_receiver *receiver, const char *path);
+
+#if defined(UBUFFER_UNIX)
+/*
+ Writes the session to the file descriptor `fd`. The descriptor stays
+ open, and is still owned by the caller.
+
+ # Safety
+
+ `receiver` must have come from `ubuffer_receiver_new()`, and `fd` must
+ be open for writing.
+ */
+int ubuffer_receiver_run_fd(ubuffer_receiver *receiver, int fd);
+#endif
+
+/*
+ Returns the number of plaintext bytes the receiver has written so far.
+
+ # Safety
+
+ `receiver` must have come from `ubuffer_receiver_new()`.
+ */
+uint64_t ubuffer_receiver_bytes(const ubuffer_receiver *receiver);
+
+/*
+ Frees `receiver`.
+
+ # Safety
+
+ `receiver` must have come from `ubuffer_receiver_new()`, or be `NULL`.
+ It must not be used again.
+ */
+void ubuffer_receiver_free(ubuffer_receiver *receiver);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* UBUFFER_H */
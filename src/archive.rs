@@ -0,0 +1,176 @@
+use glob::{MatchOptions, Pattern, PatternError};
+use std::fs;
+use std::io::{self, PipeReader, PipeWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+type Worker = JoinHandle<Result<(), io::Error>>;
+
+/// Chooses which entries of a directory are archived.
+///
+/// A pattern which contains a `/` is matched against the entry's path within
+/// the directory, otherwise it is matched against the entry's name alone. So
+/// `target` excludes every directory named `target`, while `src/*.rs` only
+/// includes the sources directly beneath `src`.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+	include: Vec<Pattern>,
+	exclude: Vec<Pattern>,
+}
+
+impl Filter {
+	/// Creates a filter which only archives files matching one of `include`,
+	/// (or every file, if it is empty) and skips anything matching one of
+	/// `exclude`. An excluded directory is not descended into.
+	pub fn new(include: &[&str], exclude: &[&str]) -> Result<Self, PatternError> {
+		let compile = |patterns: &[&str]| patterns.iter()
+			.map(|pattern| Pattern::new(pattern))
+			.collect::<Result<Vec<_>, _>>();
+
+		Ok(Self { include: compile(include)?, exclude: compile(exclude)? })
+	}
+
+	fn is_empty(&self) -> bool {
+		self.include.is_empty() && self.exclude.is_empty()
+	}
+
+	fn matches(patterns: &[Pattern], path: &Path) -> bool {
+		let name = path.file_name().map(Path::new).unwrap_or(path);
+
+		// `*` stays within one directory, `**` may be used to cross them
+		let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+
+		patterns.iter().any(|pattern| match pattern.as_str().contains('/') {
+			true => pattern.matches_path_with(path, options),
+			false => pattern.matches_path_with(name, options),
+		})
+	}
+
+	fn excludes(&self, path: &Path) -> bool {
+		Self::matches(&self.exclude, path)
+	}
+
+	fn includes(&self, path: &Path) -> bool {
+		self.include.is_empty() || Self::matches(&self.include, path)
+	}
+}
+
+/// Reads a tar archive of a directory as it is built on a helper thread.
+///
+/// If the archive cannot be built the error is returned in place of EOF, so
+/// that a truncated archive is never mistaken for a complete one.
+pub struct Packer {
+	pipe: PipeReader,
+	worker: Option<Worker>,
+}
+
+/// Extracts a tar archive into a directory on a helper thread as it is
+/// written. Call `finish()` once the entire archive has been written.
+pub struct Unpacker {
+	pipe: PipeWriter,
+	worker: Worker,
+}
+
+/// Starts archiving the contents of `dir` which pass the `filter`.
+pub fn pack<P: Into<PathBuf>>(dir: P, filter: Filter) -> Result<Packer, io::Error> {
+	let dir = dir.into();
+	let (reader, writer) = io::pipe()?;
+
+	let worker = thread::spawn(move || {
+		// symbolic links are archived as links, like `tar` itself does
+		let mut builder = tar::Builder::new(writer);
+		builder.follow_symlinks(false);
+
+		match filter.is_empty() {
+			true => builder.append_dir_all(".", &dir)?,
+			false => append_filtered(&mut builder, &dir, Path::new("."), &filter)?,
+		}
+
+		builder.into_inner()?;
+		Ok(())
+	});
+
+	Ok(Packer { pipe: reader, worker: Some(worker) })
+}
+
+/// Archives the entries beneath `root/relative` which pass the `filter`.
+///
+/// When only some files are included the directories themselves are left out
+/// of the archive, so that skipped directories are not recreated empty. The
+/// directories leading to an included file are created when it is extracted.
+fn append_filtered<W: Write>(builder: &mut tar::Builder<W>, root: &Path, relative: &Path, filter: &Filter) -> Result<(), io::Error> {
+	let mut entries = fs::read_dir(root.join(relative))?
+		.collect::<Result<Vec<_>, _>>()?;
+	entries.sort_by_key(|entry| entry.file_name());
+
+	for entry in entries {
+		let path = relative.join(entry.file_name());
+		let within = path.strip_prefix(".").unwrap_or(&path);
+		if filter.excludes(within) { continue; }
+
+		if entry.file_type()?.is_dir() {
+			if filter.include.is_empty() {
+				builder.append_path_with_name(entry.path(), &path)?;
+			}
+
+			append_filtered(builder, root, &path, filter)?;
+		} else if filter.includes(within) {
+			builder.append_path_with_name(entry.path(), &path)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Starts extracting an archive into `dir`, entries which would be written
+/// outside of `dir` are skipped.
+pub fn unpack<P: Into<PathBuf>>(dir: P) -> Result<Unpacker, io::Error> {
+	let dir = dir.into();
+	let (reader, writer) = io::pipe()?;
+
+	let worker = thread::spawn(move || {
+		let mut archive = tar::Archive::new(reader);
+		archive.set_preserve_mtime(true);
+		archive.unpack(&dir)
+	});
+
+	Ok(Unpacker { pipe: writer, worker })
+}
+
+fn join(worker: Worker) -> Result<(), io::Error> {
+	worker.join()
+		.unwrap_or_else(|_| Err(io::Error::other("archive thread panicked")))
+}
+
+impl Read for Packer {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+		let len = self.pipe.read(buf)?;
+
+		if len == 0 {
+			if let Some(worker) = self.worker.take() {
+				join(worker)?;
+			}
+		}
+
+		Ok(len)
+	}
+}
+
+impl Unpacker {
+	/// Closes the pipe and waits for the rest of the archive to be extracted.
+	pub fn finish(self) -> Result<(), io::Error> {
+		let Unpacker { pipe, worker } = self;
+		drop(pipe);
+		join(worker)
+	}
+}
+
+impl Write for Unpacker {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+		self.pipe.write(buf)
+	}
+
+	fn flush(&mut self) -> Result<(), io::Error> {
+		Ok(())
+	}
+}
@@ -0,0 +1,46 @@
+use ring::digest;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Computes the SHA-256 digest of everything written to it.
+///
+/// Clones share the same digest, so one can be given to the `Receiver` as a
+/// tee while another is kept to read the result once the session is over.
+#[derive(Clone)]
+pub struct Checksum {
+	context: Arc<Mutex<digest::Context>>,
+}
+
+impl Checksum {
+	pub fn new() -> Self {
+		Self { context: Arc::new(Mutex::new(digest::Context::new(&digest::SHA256))) }
+	}
+
+	/// Returns a line for the `.sha256` file of `output`, in the same format
+	/// as `sha256sum` so it can be checked w/ `sha256sum -c`.
+	pub fn line(&self, output: &str) -> String {
+		let context = self.context.lock().expect("checksum lock poisoned").clone();
+		let name = Path::new(output).file_name()
+			.map(|name| name.to_string_lossy().into_owned())
+			.unwrap_or_else(|| output.to_string());
+
+		format!("{}  {}\n", hex(context.finish().as_ref()), name)
+	}
+}
+
+impl Write for Checksum {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+		self.context.lock().expect("checksum lock poisoned").update(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> Result<(), io::Error> {
+		Ok(())
+	}
+}
+
+/// Formats `bytes` as lowercase hexadecimal.
+pub fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
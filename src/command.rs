@@ -0,0 +1,154 @@
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+/// Builds a `Command` which runs `command` w/ the shell.
+pub fn shell(command: &str) -> Command {
+	let mut shell = if cfg!(windows) {
+		let mut shell = Command::new("cmd");
+		shell.arg("/C");
+		shell
+	} else {
+		let mut shell = Command::new("sh");
+		shell.arg("-c");
+		shell
+	};
+
+	shell.arg(command);
+	shell
+}
+
+/// Reads the input as it comes out of a filter command (e.g: `zstd -T0`),
+/// which it is fed to on a helper thread.
+///
+/// If the command fails, or the input cannot be read, the error is returned
+/// in place of EOF, so that a truncated stream is never mistaken for a
+/// complete one.
+pub struct CommandReader {
+	child: Child,
+	stdout: ChildStdout,
+	feeder: Option<JoinHandle<Result<(), io::Error>>>,
+}
+
+/// Writes the output through a filter command (e.g: `zstd -d`), whose own
+/// output is copied to `W` on a helper thread. Call `finish()` once all of
+/// the output has been written, or `close()` to wait for the command w/o
+/// giving up the writer.
+pub struct CommandWriter<W> {
+	child: Child,
+	stdin: Option<ChildStdin>,
+	drain: Option<JoinHandle<Result<W, io::Error>>>,
+	output: Option<W>,
+}
+
+impl CommandReader {
+	/// Starts `command`, w/ `input` as its stdin.
+	pub fn spawn<R: Read + Send + 'static>(command: &str, mut input: R) -> Result<Self, io::Error> {
+		let mut child = shell(command)
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.spawn()?;
+
+		let mut stdin = child.stdin.take().expect("filter stdin is piped");
+		let stdout = child.stdout.take().expect("filter stdout is piped");
+
+		// the command sees EOF once its stdin is dropped along w/ the thread
+		let feeder = thread::spawn(move || io::copy(&mut input, &mut stdin).map(drop));
+
+		Ok(Self { child, stdout, feeder: Some(feeder) })
+	}
+}
+
+impl Read for CommandReader {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+		let len = self.stdout.read(buf)?;
+
+		if len == 0 {
+			if let Some(feeder) = self.feeder.take() {
+				let fed = join(feeder);
+				exited(&mut self.child)?;
+				fed?;
+			}
+		}
+
+		Ok(len)
+	}
+}
+
+impl<W: Write + Send + 'static> CommandWriter<W> {
+	/// Starts `command`, w/ its stdout copied to `output`.
+	pub fn spawn(command: &str, mut output: W) -> Result<Self, io::Error> {
+		let mut child = shell(command)
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.spawn()?;
+
+		let stdin = child.stdin.take();
+		let mut stdout = child.stdout.take().expect("filter stdout is piped");
+
+		let drain = thread::spawn(move || {
+			io::copy(&mut stdout, &mut output)?;
+			output.flush()?;
+			Ok(output)
+		});
+
+		Ok(Self { child, stdin, drain: Some(drain), output: None })
+	}
+
+	/// Closes the command's stdin and waits for the rest of its output to be
+	/// written, failing if the command did. Once it has been closed this does
+	/// nothing, whatever it returned the first time.
+	pub fn close(&mut self) -> Result<(), io::Error> {
+		self.stdin = None;
+
+		let drain = match self.drain.take() {
+			Some(drain) => drain,
+			None => return Ok(()),
+		};
+
+		let drained = join(drain);
+		exited(&mut self.child)?;
+		self.output = Some(drained?);
+		Ok(())
+	}
+
+	/// Closes the command (See: `close()`.) and returns the output.
+	pub fn finish(mut self) -> Result<W, io::Error> {
+		self.close()?;
+		self.output.take()
+			.ok_or_else(|| io::Error::other("the filter command already failed"))
+	}
+}
+
+impl<W> Write for CommandWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+		match self.stdin.as_mut() {
+			Some(stdin) => stdin.write(buf),
+			None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "the filter command was closed")),
+		}
+	}
+
+	fn flush(&mut self) -> Result<(), io::Error> {
+		match self.stdin.as_mut() {
+			Some(stdin) => stdin.flush(),
+			None => Ok(()),
+		}
+	}
+}
+
+fn join<T>(worker: JoinHandle<Result<T, io::Error>>) -> Result<T, io::Error> {
+	worker.join()
+		.unwrap_or_else(|_| Err(io::Error::other("filter thread panicked")))
+}
+
+/// Waits for the command to exit, which is an error unless it succeeded.
+/// Its failure is the more useful error, since the other end of its pipes
+/// would only see them break.
+fn exited(child: &mut Child) -> Result<(), io::Error> {
+	let status = child.wait()?;
+	if !status.success() {
+		return Err(io::Error::other(format!("the filter command failed: {}", status)));
+	}
+
+	Ok(())
+}
@@ -0,0 +1,121 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use toml::value::{Table, Value};
+
+/// The key in a profile which stands in for the address argument.
+const ADDRESS: &str = "address";
+
+/// The directory ubuffer keeps its configuration in, under `$XDG_CONFIG_HOME`
+/// or `$HOME/.config`.
+pub fn dir() -> Option<PathBuf> {
+	let config = env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty())
+		.map(PathBuf::from)
+		.or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+
+	Some(config.join("ubuffer"))
+}
+
+/// The named profiles in `~/.config/ubuffer/config.toml`.
+///
+/// Each profile is a `[profile.<name>]` table of long options & their values,
+/// which are given to the subcommand as though they were on the command line.
+/// (i.e: `key-file = "nightly.key"` for `--key-file nightly.key`.) A flag is
+/// set w/ `true`, an option which may be repeated takes an array, and the
+/// `address` key stands in for the address argument. An option given on the
+/// command line takes precedence over the profile's.
+pub struct Config {
+	path: PathBuf,
+	profiles: Table,
+}
+
+impl Config {
+	/// Where the file lives.
+	pub fn default_path() -> Option<PathBuf> {
+		Some(dir()?.join("config.toml"))
+	}
+
+	/// Reads the file at `path`, which is treated as empty if it does not
+	/// exist yet.
+	pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, failure::Error> {
+		let path = path.into();
+		let text = match fs::read_to_string(&path) {
+			Ok(text) => text,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+			Err(err) => bail!("could not read {}: {}", path.display(), err),
+		};
+
+		let mut config: Table = toml::from_str(&text)
+			.map_err(|err| format_err!("could not parse {}: {}", path.display(), err))?;
+
+		let profiles = match config.remove("profile") {
+			Some(Value::Table(profiles)) => profiles,
+			Some(_) => bail!("{}: `profile` must be a table of profiles", path.display()),
+			None => Table::new(),
+		};
+
+		Ok(Self { path, profiles })
+	}
+
+	/// Returns `args` w/ the options of the profile `name` inserted after the
+	/// first of the `subcommands`, leaving out any which `args` already gives.
+	pub fn apply(&self, name: &str, mut args: Vec<OsString>, subcommands: &[&str]) -> Result<Vec<OsString>, failure::Error> {
+		let profile = match self.profiles.get(name) {
+			Some(Value::Table(profile)) => profile,
+			Some(_) => bail!("{}: profile {:?} must be a table of options", self.path.display(), name),
+			None => bail!("there is no profile {:?} in {}", name, self.path.display()),
+		};
+
+		let at = match args.iter().position(|arg| arg.to_str().is_some_and(|arg| subcommands.contains(&arg))) {
+			Some(at) => at + 1,
+			None => return Ok(args),
+		};
+
+		// the address goes first, so that it is taken as the first argument
+		let mut inserted = vec![];
+		if let Some(address) = profile.get(ADDRESS) {
+			inserted.push(self.format(name, ADDRESS, address)?);
+		}
+
+		for (option, value) in profile.iter().filter(|(option, _)| *option != ADDRESS) {
+			let flag = format!("--{}", option);
+			if given(&args[at..], &flag) {
+				continue;
+			}
+
+			let values = match value {
+				Value::Array(values) => values.iter().collect(),
+				value => vec![value],
+			};
+
+			for value in values {
+				match value {
+					Value::Boolean(true) => inserted.push(flag.clone()),
+					Value::Boolean(false) => {},
+					value => inserted.push(format!("{}={}", flag, self.format(name, option, value)?)),
+				}
+			}
+		}
+
+		args.splice(at..at, inserted.into_iter().map(OsString::from));
+		Ok(args)
+	}
+
+	fn format(&self, name: &str, option: &str, value: &Value) -> Result<String, failure::Error> {
+		match value {
+			Value::String(text) => Ok(text.clone()),
+			Value::Integer(number) => Ok(number.to_string()),
+			Value::Float(number) => Ok(number.to_string()),
+			_ => bail!("{}: {} in profile {:?} must be a string, a number, or a boolean", self.path.display(), option, name),
+		}
+	}
+}
+
+/// Returns true if the long option `flag` is among `args`.
+fn given(args: &[OsString], flag: &str) -> bool {
+	args.iter()
+		.filter_map(|arg| arg.to_str())
+		.any(|arg| arg == flag || arg.strip_prefix(flag).is_some_and(|rest| rest.starts_with('=')))
+}
@@ -0,0 +1,244 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+use ubuffer::proto::{human_bytes, Event};
+
+/// How often the transfer is sampled for the dashboard, unless the
+/// `--stats-interval` says otherwise.
+pub const DASHBOARD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many throughput samples the graph spans.
+const GRAPH_WIDTH: usize = 60;
+
+/// How many of the most recent events are listed.
+const RECENT_EVENTS: usize = 8;
+
+/// The bars the graph is drawn w/, from an idle link to the busiest sample.
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Draws a live view of a session on the terminal, for someone keeping an eye
+/// on a long transfer: a graph of the recent throughput, the bytes done & how
+/// long is left, the round-trip time & loss measured by the transport, and
+/// the last few noteworthy events.
+///
+/// The dashboard is redrawn each time the transfer is sampled (See:
+/// `Event::Stats`.) on the terminal's alternate screen, which is left once the
+/// session ends so that the summary is printed where it usually is.
+pub struct Dashboard {
+	role: &'static str,
+	started: Instant,
+	drawn: bool,
+
+	session_id: Option<String>,
+	name: Option<String>,
+	total_bytes: u64,
+	expected_bytes: Option<u64>,
+	samples: VecDeque<f64>,
+	rtt_ms: Option<f64>,
+	lost_packets: Option<u64>,
+	queues: Option<(u64, u64)>,
+	recent: VecDeque<String>,
+}
+
+impl Dashboard {
+	/// Creates a dashboard for `role`, which requires stderr to be a terminal.
+	pub fn new(role: &'static str) -> Result<Self, io::Error> {
+		if !io::stderr().is_terminal() {
+			return Err(io::Error::other("stderr is not a terminal"));
+		}
+
+		Ok(Self {
+			role,
+			started: Instant::now(),
+			drawn: false,
+			session_id: None,
+			name: None,
+			total_bytes: 0,
+			expected_bytes: None,
+			samples: VecDeque::with_capacity(GRAPH_WIDTH),
+			rtt_ms: None,
+			lost_packets: None,
+			queues: None,
+			recent: VecDeque::with_capacity(RECENT_EVENTS),
+		})
+	}
+
+	/// Updates the dashboard w/ `event`, and redraws it if anything shown
+	/// has changed.
+	pub fn observe(&mut self, event: &Event) {
+		match event {
+			Event::HandshakeComplete { session_id, .. } => {
+				self.started = Instant::now();
+				self.session_id = Some(session_id.clone());
+				self.note("handshake complete".to_string());
+			},
+
+			Event::Metadata { name, .. } => {
+				self.name = Some(name.clone());
+				return;
+			},
+
+			Event::TotalSize { bytes } => {
+				self.expected_bytes = Some(*bytes);
+				return;
+			},
+
+			Event::FileStart { name, .. } => {
+				self.name = Some(name.clone());
+				self.note(format!("started {}", name));
+			},
+
+			Event::FileEnd { name, bytes } => self.note(format!("{} complete, {}", name, human_bytes(*bytes as f64))),
+
+			Event::Stats { total_bytes, expected_bytes, throughput_bps, send_queue, recv_queue, rtt_ms, lost_packets, .. } => {
+				self.total_bytes = *total_bytes;
+				self.expected_bytes = expected_bytes.or(self.expected_bytes);
+				self.rtt_ms = *rtt_ms;
+				self.lost_packets = *lost_packets;
+				self.queues = send_queue.zip(*recv_queue);
+
+				if self.samples.len() == GRAPH_WIDTH {
+					self.samples.pop_front();
+				}
+
+				self.samples.push_back(*throughput_bps);
+			},
+
+			Event::Nack { seq } => self.note(format!("block {} was nacked", seq)),
+			Event::Resumed { seq } => self.note(format!("resumed from message {}", seq)),
+			Event::Quiet { idle_secs } => self.note(format!("no word from the sender in {:.0}s", idle_secs)),
+			Event::Heard { idle_secs } => self.note(format!("heard from the sender after {:.0}s", idle_secs)),
+			Event::ReKey { epoch } => self.note(format!("switched to sub-key {}", epoch)),
+			Event::Abort => self.note("aborted by the peer".to_string()),
+			Event::Goodbye => self.note("closed cleanly".to_string()),
+
+			Event::Finished { .. } => {
+				self.close();
+				return;
+			},
+
+			Event::Block { .. } | Event::Skip { .. } | Event::Ack { .. } => return,
+		}
+
+		if let Err(err) = self.draw() {
+			debug!("could not draw the dashboard: {}", err);
+		}
+	}
+
+	/// Adds `text` to the recent events, stamped w/ the time into the session.
+	fn note(&mut self, text: String) {
+		if self.recent.len() == RECENT_EVENTS {
+			self.recent.pop_front();
+		}
+
+		self.recent.push_back(format!("{:>6}  {}", clock(self.started.elapsed()), text));
+	}
+
+	fn draw(&mut self) -> Result<(), io::Error> {
+		let mut screen = String::new();
+
+		if !self.drawn {
+			// switch to the alternate screen & hide the cursor
+			screen += "\x1b[?1049h\x1b[?25l";
+			self.drawn = true;
+		}
+
+		// each line overwrites the last frame's, w/o clearing it first
+		screen += "\x1b[H";
+		let mut line = |text: String| {
+			screen += &text;
+			screen += "\x1b[K\n";
+		};
+
+		let mut title = format!("ubuffer {}, {} elapsed", self.role, clock(self.started.elapsed()));
+		if let Some(session_id) = &self.session_id {
+			let _ = write!(title, ", session {}", session_id);
+		}
+
+		line(title);
+		line(self.name.as_ref().map(|name| format!("  {}", name)).unwrap_or_default());
+		line(String::new());
+
+		let throughput = self.samples.back().copied().unwrap_or(0.0);
+		let peak = self.samples.iter().copied().fold(0.0, f64::max);
+
+		match self.expected_bytes.filter(|&expected| expected > 0) {
+			Some(expected) => {
+				let percent = self.total_bytes as f64 * 100.0 / expected as f64;
+				line(format!("  done        {} of {} ({:.1}%)", human_bytes(self.total_bytes as f64), human_bytes(expected as f64), percent));
+
+				let left = expected.saturating_sub(self.total_bytes) as f64;
+				let eta = Duration::try_from_secs_f64(left / throughput).map(clock);
+				line(format!("  eta         {}", eta.unwrap_or_else(|_| "unknown".to_string())));
+			},
+
+			None => {
+				line(format!("  done        {}", human_bytes(self.total_bytes as f64)));
+				line("  eta         unknown, the size was not announced".to_string());
+			},
+		}
+
+		line(format!("  throughput  {}/s, peak {}/s", human_bytes(throughput), human_bytes(peak)));
+
+		let rtt = self.rtt_ms.map(|rtt| format!("{:.2}ms", rtt)).unwrap_or_else(|| "-".to_string());
+		let lost = self.lost_packets.map(|lost| lost.to_string()).unwrap_or_else(|| "-".to_string());
+		line(format!("  rtt         {}, {} packets lost", rtt, lost));
+
+		if let Some((send_queue, recv_queue)) = self.queues {
+			line(format!("  queues      {} to send, {} to read", send_queue, recv_queue));
+		}
+
+		line(String::new());
+		line(format!("  {}", graph(&self.samples, peak)));
+		line(String::new());
+		line("  recent events".to_string());
+
+		for event in &self.recent {
+			line(format!("  {}", event));
+		}
+
+		// clear whatever is left below the last frame
+		screen += "\x1b[J";
+
+		let mut out = io::stderr().lock();
+		out.write_all(screen.as_bytes())?;
+		out.flush()
+	}
+
+	/// Leaves the alternate screen, if it was drawn on.
+	fn close(&mut self) {
+		if std::mem::replace(&mut self.drawn, false) {
+			let mut out = io::stderr().lock();
+			let _ = out.write_all(b"\x1b[?25h\x1b[?1049l");
+			let _ = out.flush();
+		}
+	}
+}
+
+impl Drop for Dashboard {
+	fn drop(&mut self) {
+		// e.g: the session failed before it could finish
+		self.close();
+	}
+}
+
+/// Draws the throughput `samples` as a row of bars, relative to the `peak`.
+fn graph(samples: &VecDeque<f64>, peak: f64) -> String {
+	samples.iter()
+		.map(|&sample| {
+			let level = if peak > 0.0 { (sample / peak * (BARS.len() - 1) as f64).round() as usize } else { 0 };
+			BARS[level.min(BARS.len() - 1)]
+		})
+		.collect()
+}
+
+/// Formats a duration as hours, minutes & seconds. (i.e: `1h02m03s`)
+fn clock(elapsed: Duration) -> String {
+	let secs = elapsed.as_secs();
+	match (secs / 3600, secs / 60 % 60, secs % 60) {
+		(0, 0, s) => format!("{}s", s),
+		(0, m, s) => format!("{}m{:02}s", m, s),
+		(h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+	}
+}
@@ -1,3 +1,6 @@
+// `failure_derive` generates its impls inside of an anonymous constant.
+#![allow(non_local_definitions)]
+
 use std::convert::From;
 
 #[derive(Fail, Debug)]
@@ -5,17 +8,166 @@ pub enum ProtoError {
 	#[fail(display = "unexpected crypto error")]
 	CryptoErr,
 
-	#[fail(display = "unexpected i/o error")]
+	#[fail(display = "the receiver hung up during the handshake, most likely the keys do not match")]
+	HandshakeRejected,
+
+	#[fail(display = "unexpected i/o error: {}", inner)]
 	IoErr { inner: std::io::Error },
 
-	#[fail(display = "serialization failure")]
+	#[fail(display = "serialization failure: {}", inner)]
 	SerializeErr { inner: bincode::Error },
 
+	#[cfg(feature = "udt")]
 	#[fail(display = "unexpected network socket error")]
-	SocketErr { inner: udt::UdtError },
+	SocketErr { inner: crate::udt::UdtError },
+
+	#[cfg(feature = "udt")]
+	#[fail(display = "could not establish a connection with the peer")]
+	ConnectErr { inner: crate::udt::UdtError },
+
+	#[fail(display = "timed out waiting for the peer to answer")]
+	ConnectTimeout,
 
 	#[fail(display = "message type was not expected at this time ...")]
 	UnexpectedMessage,
+
+	#[fail(display = "message type #{} is not known to this version of ubuffer", ty)]
+	UnknownMessage { ty: u8 },
+
+	#[fail(display = "the peer does not speak the ubuffer protocol, is it the right host & port?")]
+	NotAPeer,
+
+	#[fail(display = "peer is using version {} of the protocol, which is not supported", version)]
+	UnsupportedVersion { version: u8 },
+
+	#[fail(display = "the peer does not support {}", feature)]
+	UnsupportedFeature { feature: crate::proto::Features },
+
+	#[fail(display = "a key must be 16 or 32 bytes, not {} bytes", len)]
+	InvalidKeyLength { len: usize },
+
+	#[fail(display = "{} cannot be used w/ a {}-byte key", cipher, len)]
+	KeyLengthMismatch { cipher: crate::proto::Cipher, len: usize },
+
+	#[fail(display = "cipher #{} is not known to this version of ubuffer", id)]
+	UnknownCipher { id: u8 },
+
+	#[fail(display = "this peer requires {} but the other peer asked for {}", ours, theirs)]
+	CipherMismatch { ours: crate::proto::Cipher, theirs: crate::proto::Cipher },
+
+	#[fail(display = "the identity file does not hold an Ed25519 keypair")]
+	InvalidIdentity,
+
+	#[fail(display = "expected the peer's fingerprint to be {} but it has no identity", expected)]
+	NoIdentity { expected: String },
+
+	#[fail(display = "expected the peer's fingerprint to be {} but it is {}", expected, actual)]
+	IdentityMismatch { expected: String, actual: String },
+
+	#[fail(display = "{} is not a known host, its fingerprint is {}", addr, fingerprint)]
+	UnknownHost { addr: String, fingerprint: String },
+
+	#[fail(display = "the identity of {} has changed! it was {} but is now {}", addr, expected, actual)]
+	HostIdentityChanged { addr: String, expected: String, actual: String },
+
+	#[fail(display = "the sender presented a resumption token which was not issued for this session")]
+	InvalidToken,
+
+	#[fail(display = "message had an invalid length for its type")]
+	MalformedMessage,
+
+	#[fail(display = "block of {} bytes exceeds the maximum of {} bytes", len, max)]
+	OversizedBlock { len: usize, max: usize },
+
+	#[fail(display = "expected message #{} but received #{}, it was replayed, dropped, or reordered", expected, received)]
+	ReplayOrReorder { expected: u64, received: u64 },
+
+	#[fail(display = "the receiver could not decrypt message #{}, and it is too old to be resent", seq)]
+	BlockLost { seq: u64 },
+
+	#[fail(display = "refusing to write a file named {:?} outside of the output directory", name)]
+	UnsafeFileName { name: String },
+
+	#[fail(display = "refusing to overwrite {:?}, which is already in the output directory", name)]
+	FileExists { name: String },
+
+	#[fail(display = "file {:?} was {} bytes but the sender sent {} bytes", name, expected, received)]
+	FileLengthMismatch { name: String, expected: u64, received: u64 },
+
+	#[fail(display = "the sender sent {} bytes in {} blocks but {} bytes in {} blocks were received", sent_bytes, sent_blocks, received_bytes, received_blocks)]
+	TotalsMismatch { sent_bytes: u64, sent_blocks: u64, received_bytes: u64, received_blocks: u64 },
+
+	#[fail(display = "the data received does not match the SHA-256 digest the sender sent")]
+	DigestMismatch,
+
+	#[fail(display = "the receiver did not sign a receipt, it needs an identity to do so")]
+	NoReceipt,
+
+	#[fail(display = "the receiver's receipt does not match the data which was sent")]
+	ReceiptMismatch,
+
+	#[fail(display = "the sender is sending multiple files but no output directory was given")]
+	NoOutputDir,
+
+	#[fail(display = "the sender announced {} bytes but {} were sent", expected, sent)]
+	SizeMismatch { expected: u64, sent: u64 },
+
+	#[fail(display = "the checkpoint is corrupt, or was saved by an incompatible version")]
+	InvalidCheckpoint,
+
+	#[fail(display = "not enough disk space for the {} bytes the sender is sending", needed)]
+	NoSpace { needed: u64 },
+
+	#[fail(display = "could not resolve a socket address for the peer")]
+	NoSocketAddr,
+
+	#[fail(display = "could not resolve an {} address for the peer", family)]
+	NoAddrInFamily { family: crate::proto::AddrFamily },
+
+	#[fail(display = "udt cannot use the ipv6 address {}, the udp transport can", addr)]
+	Ipv6Unsupported { addr: std::net::SocketAddr },
+
+	#[fail(display = "invalid argument: {}", reason)]
+	InvalidArgument { reason: &'static str },
+
+	#[fail(display = "transfer was interrupted by a signal")]
+	Interrupted,
+
+	#[fail(display = "transfer was cancelled")]
+	Cancelled,
+
+	#[fail(display = "remote peer aborted the transfer")]
+	PeerAborted,
+
+	#[fail(display = "remote peer failed w/ a {}: {}", code, message)]
+	PeerFailed { code: crate::proto::FaultCode, message: String },
+}
+
+impl ProtoError {
+	/// Returns true if the error means the connection to the peer was lost.
+	pub fn is_hangup(&self) -> bool {
+		use std::io::ErrorKind;
+
+		match self {
+			#[cfg(feature = "udt")]
+			ProtoError::SocketErr { .. } => true,
+			ProtoError::IoErr { inner } => matches!(inner.kind(),
+				ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset),
+
+			_ => false,
+		}
+	}
+
+	/// Returns true if the peer could not be reached, which the dialing side
+	/// tries again after. (See: `StreamOpts::retries`.)
+	pub fn is_unreachable(&self) -> bool {
+		match self {
+			#[cfg(feature = "udt")]
+			ProtoError::ConnectErr { .. } => true,
+			ProtoError::ConnectTimeout => true,
+			_ => false,
+		}
+	}
 }
 
 impl From<ring::error::Unspecified> for ProtoError {
@@ -30,8 +182,9 @@ impl From<std::io::Error> for ProtoError {
 	}
 }
 
-impl From<udt::UdtError> for ProtoError {
-	fn from(err: udt::UdtError) -> Self {
+#[cfg(feature = "udt")]
+impl From<crate::udt::UdtError> for ProtoError {
+	fn from(err: crate::udt::UdtError) -> Self {
 		ProtoError::SocketErr { inner: err }
 	}
 }
@@ -41,3 +194,12 @@ impl From<bincode::Error> for ProtoError {
 		ProtoError::SerializeErr { inner: err }
 	}
 }
+
+impl From<ProtoError> for std::io::Error {
+	fn from(err: ProtoError) -> Self {
+		match err {
+			ProtoError::IoErr { inner } => inner,
+			err => std::io::Error::other(err.to_string()),
+		}
+	}
+}
@@ -0,0 +1,225 @@
+//! A C interface to the `Sender` & `Receiver`, for software which would
+//! rather link against `ubuffer` than run the binary. (See: `include/ubuffer.h`.)
+//!
+//! The header is written from this file by `build.rs`, doc comments & all,
+//! so a function is declared there exactly as it is defined here.
+//!
+//! Each peer is created w/ the address & key, run once, and then freed. A
+//! function which fails returns `NULL` or `-1`, and `ubuffer_last_error()`
+//! describes what went wrong on the calling thread.
+
+use crate::error::ProtoError;
+use crate::proto::{Receiver, Sender, StreamOpts};
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+thread_local! {
+	static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Describes the last failure on the calling thread, or returns `NULL` if
+/// nothing has failed. The string is valid until the next call which fails.
+#[no_mangle]
+pub extern "C" fn ubuffer_last_error() -> *const c_char {
+	LAST_ERROR.with(|last| {
+		last.borrow().as_ref().map_or(ptr::null(), |err| err.as_ptr())
+	})
+}
+
+/// Connects to the receiver at `addr` (i.e: "10.0.0.1:9999"), which will
+/// use the `key_len` bytes at `key` to encrypt the session.
+///
+/// # Safety
+///
+/// `addr` must be a NUL terminated string, and `key` must point to at least
+/// `key_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ubuffer_sender_new(addr: *const c_char, key: *const u8, key_len: usize) -> *mut Sender {
+	let created = guard(|| {
+		let addr = read_str(addr)?;
+		Sender::new(addr, read_key(key, key_len)?, &StreamOpts::default())
+	});
+
+	created.map_or(ptr::null_mut(), |sender| Box::into_raw(Box::new(sender)))
+}
+
+/// Sends the file at `path`, returning 0 once the receiver has all of it.
+///
+/// # Safety
+///
+/// `sender` must have come from `ubuffer_sender_new()`, and `path` must be
+/// a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ubuffer_sender_run_file(sender: *mut Sender, path: *const c_char) -> c_int {
+	let sender = &mut *sender;
+	status(guard(|| {
+		let file = File::open(read_str(path)?)?;
+		sender.run_file(file)
+	}))
+}
+
+/// Sends everything read from the file descriptor `fd` until EOF. The
+/// descriptor stays open, and is still owned by the caller.
+///
+/// # Safety
+///
+/// `sender` must have come from `ubuffer_sender_new()`, and `fd` must be
+/// open for reading.
+#[cfg(unix)]
+#[no_mangle]
+pub unsafe extern "C" fn ubuffer_sender_run_fd(sender: *mut Sender, fd: c_int) -> c_int {
+	let sender = &mut *sender;
+	status(guard(|| sender.run(borrow_fd(fd)?)))
+}
+
+/// Returns the number of plaintext bytes the sender has sent so far.
+///
+/// # Safety
+///
+/// `sender` must have come from `ubuffer_sender_new()`.
+#[no_mangle]
+pub unsafe extern "C" fn ubuffer_sender_bytes(sender: *const Sender) -> u64 {
+	(*sender).summary().plaintext_bytes
+}
+
+/// Hangs up on the receiver, if the session is not over, & frees `sender`.
+///
+/// # Safety
+///
+/// `sender` must have come from `ubuffer_sender_new()`, or be `NULL`. It
+/// must not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn ubuffer_sender_free(sender: *mut Sender) {
+	if !sender.is_null() {
+		drop(Box::from_raw(sender));
+	}
+}
+
+/// Waits for a sender on `addr` (i.e: "0.0.0.0:9999"), which will use the
+/// `key_len` bytes at `key` to encrypt the session.
+///
+/// # Safety
+///
+/// `addr` must be a NUL terminated string, and `key` must point to at least
+/// `key_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ubuffer_receiver_new(addr: *const c_char, key: *const u8, key_len: usize) -> *mut Receiver {
+	let created = guard(|| {
+		let addr = read_str(addr)?;
+		Receiver::new(addr, read_key(key, key_len)?, &StreamOpts::default())
+	});
+
+	created.map_or(ptr::null_mut(), |receiver| Box::into_raw(Box::new(receiver)))
+}
+
+/// Writes the session to the file at `path`, which is created or truncated.
+/// Returns 0 once the sender has said goodbye.
+///
+/// # Safety
+///
+/// `receiver` must have come from `ubuffer_receiver_new()`, and `path` must
+/// be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ubuffer_receiver_run_file(receiver: *mut Receiver, path: *const c_char) -> c_int {
+	let receiver = &mut *receiver;
+	status(guard(|| {
+		let file = File::create(read_str(path)?)?;
+		receiver.set_output_file(&file)?;
+		receiver.run_seekable(file)
+	}))
+}
+
+/// Writes the session to the file descriptor `fd`. The descriptor stays
+/// open, and is still owned by the caller.
+///
+/// # Safety
+///
+/// `receiver` must have come from `ubuffer_receiver_new()`, and `fd` must
+/// be open for writing.
+#[cfg(unix)]
+#[no_mangle]
+pub unsafe extern "C" fn ubuffer_receiver_run_fd(receiver: *mut Receiver, fd: c_int) -> c_int {
+	let receiver = &mut *receiver;
+	status(guard(|| receiver.run(borrow_fd(fd)?)))
+}
+
+/// Returns the number of plaintext bytes the receiver has written so far.
+///
+/// # Safety
+///
+/// `receiver` must have come from `ubuffer_receiver_new()`.
+#[no_mangle]
+pub unsafe extern "C" fn ubuffer_receiver_bytes(receiver: *const Receiver) -> u64 {
+	(*receiver).summary().plaintext_bytes
+}
+
+/// Frees `receiver`.
+///
+/// # Safety
+///
+/// `receiver` must have come from `ubuffer_receiver_new()`, or be `NULL`.
+/// It must not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn ubuffer_receiver_free(receiver: *mut Receiver) {
+	if !receiver.is_null() {
+		drop(Box::from_raw(receiver));
+	}
+}
+
+/// Runs `call`, recording its error (or a panic, which must not unwind into
+/// the caller's C code) as the thread's last error.
+fn guard<T, F>(call: F) -> Option<T>
+where F: FnOnce() -> Result<T, ProtoError> {
+	let err = match panic::catch_unwind(AssertUnwindSafe(call)) {
+		Ok(Ok(value)) => return Some(value),
+		Ok(Err(err)) => err.to_string(),
+		Err(_) => "ubuffer panicked".to_string(),
+	};
+
+	// an error w/ a NUL in it is cut short there, rather than lost
+	let err = CString::new(err).unwrap_or_else(|err| {
+		let end = err.nul_position();
+		CString::new(&err.into_vec()[..end]).expect("cut at the first NUL")
+	});
+
+	LAST_ERROR.with(|last| *last.borrow_mut() = Some(err));
+	None
+}
+
+fn status(result: Option<()>) -> c_int {
+	if result.is_some() { 0 } else { -1 }
+}
+
+unsafe fn read_str<'a>(ptr: *const c_char) -> Result<&'a str, ProtoError> {
+	if ptr.is_null() {
+		return Err(ProtoError::InvalidArgument { reason: "a string was NULL" });
+	}
+
+	CStr::from_ptr(ptr).to_str()
+		.map_err(|_| ProtoError::InvalidArgument { reason: "a string was not UTF-8" })
+}
+
+unsafe fn read_key<'a>(ptr: *const u8, len: usize) -> Result<&'a [u8], ProtoError> {
+	if ptr.is_null() {
+		return Err(ProtoError::InvalidArgument { reason: "the key was NULL" });
+	}
+
+	Ok(slice::from_raw_parts(ptr, len))
+}
+
+/// Returns a `File` for a copy of `fd`, so that the caller's descriptor is
+/// not closed along w/ it.
+#[cfg(unix)]
+unsafe fn borrow_fd(fd: c_int) -> Result<File, ProtoError> {
+	use std::mem::ManuallyDrop;
+	use std::os::unix::io::FromRawFd;
+
+	let borrowed = ManuallyDrop::new(File::from_raw_fd(fd));
+	Ok(borrowed.try_clone()?)
+}
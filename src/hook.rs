@@ -0,0 +1,89 @@
+use crate::checksum::hex;
+use crate::command::shell;
+use std::io;
+use std::process::Stdio;
+use ubuffer::proto::Summary;
+
+/// The commands given by `--on-complete` & `--on-error`, one of which is run
+/// once a session ends so that a pipeline can start its next step w/o
+/// wrapping `ubuffer` in a script.
+///
+/// The command is run by the shell, w/ stdout sent to stderr since the
+/// receiver's stdout may be carrying the data, and w/ the outcome of the
+/// session in its environment:
+///
+/// - `UBUFFER_ROLE`: `sender` or `receiver`
+/// - `UBUFFER_STATUS`: the status `ubuffer` exits w/, zero on success
+/// - `UBUFFER_ERROR`: why the session failed, for `--on-error`
+/// - `UBUFFER_BYTES`: the plaintext bytes transferred
+/// - `UBUFFER_ELAPSED_SECS`: the time spent transferring them
+/// - `UBUFFER_SHA256`: the digest of what was transferred, if it is known
+/// - `UBUFFER_SESSION_ID`: the id of the session, once it was agreed on
+pub struct Hooks {
+	on_complete: Option<String>,
+	on_error: Option<String>,
+}
+
+impl Hooks {
+	pub fn new(on_complete: Option<&str>, on_error: Option<&str>) -> Self {
+		Self {
+			on_complete: on_complete.map(String::from),
+			on_error: on_error.map(String::from),
+		}
+	}
+
+	/// Runs the hook for how the session summed up by `summary` ended: w/
+	/// the `status` & error it `failed` w/, if it did. A failed `--on-complete`
+	/// fails the transfer, while a failed `--on-error` is only warned about
+	/// so that the session's own error is the one reported.
+	pub fn run(&self, role: &str, summary: &Summary, digest: Option<&[u8]>, failed: Option<(i32, &failure::Error)>) -> Result<(), failure::Error> {
+		let (flag, command) = match failed {
+			None => ("--on-complete", &self.on_complete),
+			Some(_) => ("--on-error", &self.on_error),
+		};
+
+		let command = match command {
+			Some(command) => command,
+			None => return Ok(()),
+		};
+
+		let mut env = vec![
+			("UBUFFER_ROLE", role.to_string()),
+			("UBUFFER_STATUS", failed.map_or(0, |(status, _)| status).to_string()),
+			("UBUFFER_BYTES", summary.plaintext_bytes.to_string()),
+			("UBUFFER_ELAPSED_SECS", format!("{:.3}", summary.elapsed_secs())),
+		];
+
+		if let Some((_, err)) = failed {
+			env.push(("UBUFFER_ERROR", err.to_string()));
+		}
+
+		if let Some(digest) = digest {
+			env.push(("UBUFFER_SHA256", hex(digest)));
+		}
+
+		if let Some(session_id) = summary.session_id {
+			env.push(("UBUFFER_SESSION_ID", session_id.to_string()));
+		}
+
+		info!("running the {} command: {}", flag, command);
+		let result = shell(command)
+			.envs(env)
+			.stdin(Stdio::null())
+			.stdout(io::stderr())
+			.status();
+
+		let err = match result {
+			Ok(status) if status.success() => return Ok(()),
+			Ok(status) => format_err!("the {} command failed: {}", flag, status),
+			Err(err) => format_err!("could not run the {} command: {}", flag, err),
+		};
+
+		if failed.is_some() {
+			warn!("{}", err);
+			return Ok(());
+		}
+
+		Err(err)
+	}
+}
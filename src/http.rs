@@ -0,0 +1,33 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+
+/// Returns true if `text` is an `http://` or `https://` URL.
+pub fn is_url(text: &str) -> bool {
+	text.starts_with("http://") || text.starts_with("https://")
+}
+
+/// Builds an HTTP client which verifies TLS certificates against the system's
+/// trust store.
+pub fn agent() -> Result<ureq::Agent, io::Error> {
+	let tls = native_tls::TlsConnector::new()
+		.map_err(io::Error::other)?;
+
+	Ok(ureq::AgentBuilder::new().tls_connector(Arc::new(tls)).build())
+}
+
+/// Starts downloading `url`, redirects are followed. The body is streamed as
+/// it is read rather than being fetched up front.
+pub fn get(url: &str) -> Result<Box<dyn Read + Send>, io::Error> {
+	match agent()?.get(url).call() {
+		Ok(response) => {
+			debug!("GET {}: {} ({} bytes)", url, response.status(), response.header("content-length").unwrap_or("?"));
+			Ok(Box::new(response.into_reader()))
+		},
+
+		Err(ureq::Error::Status(status, response)) => {
+			Err(io::Error::other(format!("server answered {} {}", status, response.status_text())))
+		},
+
+		Err(err) => Err(io::Error::other(err)),
+	}
+}
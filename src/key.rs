@@ -0,0 +1,102 @@
+use ring::{constant_time, digest};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// The number of digest bytes shown in a key fingerprint, all of them since
+/// an identity's fingerprint is what a peer is pinned by.
+const FINGERPRINT_LEN: usize = 32;
+
+/// The length of a key's id. (See: `id()`.)
+pub const ID_LEN: usize = 4;
+
+/// Prefixed to a key before it is hashed to its id, so that the id is not
+/// the start of its fingerprint.
+const ID_CONTEXT: &[u8] = b"ubuffer key id v1";
+
+/// Decodes a key from the contents of a key file (or any other string.)
+///
+/// Blank lines, and lines starting with `#`, are ignored so that key files
+/// may carry a human readable label above the base64 encoded key.
+pub fn parse(text: &str) -> Result<Vec<u8>, base64::DecodeError> {
+	let key_b64: String = text.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.collect();
+
+	base64::decode(&key_b64)
+}
+
+/// Formats a key (and an optional label) the way it is stored in a key file.
+pub fn format(key: &[u8], label: Option<&str>) -> String {
+	match label {
+		Some(label) => format!("# {}\n{}\n", label, base64::encode(key)),
+		None => format!("{}\n", base64::encode(key)),
+	}
+}
+
+/// Writes a key file at `path` which is only readable by its owner.
+pub fn write_file<P: AsRef<Path>>(path: P, key: &[u8], label: Option<&str>) -> Result<(), io::Error> {
+	let mut options = OpenOptions::new();
+	options.write(true).create(true).truncate(true);
+
+	#[cfg(unix)]
+	{
+		use std::fs;
+		use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+		options.mode(0o600);
+
+		// `mode()` only applies to newly created files, so tighten the
+		// permissions of an existing file before writing the key to it.
+		if path.as_ref().exists() {
+			fs::set_permissions(path.as_ref(), fs::Permissions::from_mode(0o600))?;
+		}
+	}
+
+	let mut file = options.open(path)?;
+	file.write_all(format(key, label).as_bytes())?;
+	file.sync_all()
+}
+
+/// Returns a fingerprint of the key which is safe to display, it is the
+/// SHA-256 digest of the key in groups of four hex digits. (i.e:
+/// `a1b2:c3d4:...:0718`, sixteen groups in all.)
+pub fn fingerprint(key: &[u8]) -> String {
+	let digest = digest::digest(&digest::SHA256, key);
+
+	digest.as_ref()[..FINGERPRINT_LEN]
+		.chunks(2)
+		.map(|pair| pair.iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+		.collect::<Vec<_>>()
+		.join(":")
+}
+
+/// Returns true if `text` is a whole fingerprint, as `fingerprint()` formats
+/// one. (i.e: not one cut short by an older version.)
+pub fn is_fingerprint(text: &str) -> bool {
+	let groups: Vec<&str> = text.trim().split(':').collect();
+
+	groups.len() == FINGERPRINT_LEN / 2
+		&& groups.iter().all(|group| group.len() == 4 && group.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Compares two fingerprints in constant time, ignoring case & surrounding
+/// whitespace.
+pub fn fingerprints_match(a: &str, b: &str) -> bool {
+	let a = a.trim().to_lowercase();
+	let b = b.trim().to_lowercase();
+	constant_time::verify_slices_are_equal(a.as_bytes(), b.as_bytes()).is_ok()
+}
+
+/// Returns the short id a sender names its key by, so that a receiver which
+/// accepts several keys knows which one to use. It is sent in the clear, so
+/// it is derived from the key w/ a digest which reveals nothing else about it.
+pub fn id(key: &[u8]) -> [u8; ID_LEN] {
+	let mut context = digest::Context::new(&digest::SHA256);
+	context.update(ID_CONTEXT);
+	context.update(key);
+
+	let mut id = [0u8; ID_LEN];
+	id.copy_from_slice(&context.finish().as_ref()[..ID_LEN]);
+	id
+}
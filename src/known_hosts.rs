@@ -0,0 +1,104 @@
+use crate::config;
+use crate::logging;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use ubuffer::error::ProtoError;
+use ubuffer::key;
+
+/// The fingerprints of the receivers a sender has trusted before, keyed by
+/// the address they were reached at. (i.e: `~/.config/ubuffer/known_hosts`.)
+///
+/// Each line of the file is an address & a fingerprint separated by
+/// whitespace, blank lines and lines starting w/ `#` are ignored. A receiver
+/// is only ever added to the file, a changed identity must be removed by hand.
+///
+/// The whole fingerprint is stored & compared. A line w/ a shorter one (as
+/// older versions recorded) is ignored, so that receiver must be trusted
+/// again w/ `--accept-new`.
+pub struct KnownHosts {
+	path: PathBuf,
+	hosts: HashMap<String, String>,
+}
+
+impl KnownHosts {
+	/// Where the file lives unless another is given. (See: `config::dir()`.)
+	pub fn default_path() -> Option<PathBuf> {
+		Some(config::dir()?.join("known_hosts"))
+	}
+
+	/// Reads the file at `path`, which is treated as empty if it does not
+	/// exist yet.
+	pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, io::Error> {
+		let path = path.into();
+		let text = match fs::read_to_string(&path) {
+			Ok(text) => text,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+			Err(err) => return Err(err),
+		};
+
+		let hosts = text.lines()
+			.map(str::trim)
+			.filter(|line| !line.is_empty() && !line.starts_with('#'))
+			.filter_map(|line| {
+				let mut fields = line.split_whitespace();
+				Some((fields.next()?.to_string(), fields.next()?.to_lowercase()))
+			})
+			.filter(|(addr, fingerprint)| {
+				let whole = key::is_fingerprint(fingerprint);
+				if !whole {
+					warn!("ignoring the known host {} in {}, its fingerprint is cut short", addr, path.display());
+				}
+
+				whole
+			})
+			.collect();
+
+		Ok(Self { path, hosts })
+	}
+
+	/// Checks the `fingerprint` of the receiver at `addr` against the one we
+	/// saw last time. A receiver we have not seen before is refused, unless
+	/// `accept_new` is set in which case it is added to the file. A receiver
+	/// w/o an identity is only refused if it used to have one.
+	pub fn check(&mut self, addr: &str, fingerprint: Option<&str>, accept_new: bool) -> Result<(), ProtoError> {
+		let fingerprint = match (self.hosts.get(addr), fingerprint) {
+			(Some(known), Some(fingerprint)) if key::fingerprints_match(known, fingerprint) => return Ok(()),
+			(Some(known), Some(fingerprint)) => {
+				return Err(ProtoError::HostIdentityChanged {
+					addr: addr.to_string(),
+					expected: known.clone(),
+					actual: fingerprint.to_string(),
+				});
+			},
+
+			(Some(known), None) => return Err(ProtoError::NoIdentity { expected: known.clone() }),
+			(None, None) => return Ok(()),
+			(None, Some(fingerprint)) => fingerprint,
+		};
+
+		if !accept_new {
+			return Err(ProtoError::UnknownHost { addr: addr.to_string(), fingerprint: fingerprint.to_string() });
+		}
+
+		self.add(addr, fingerprint)?;
+		if !logging::quiet() {
+			eprintln!("ubuffer: added {} ({}) to the known hosts in {}", addr, fingerprint, self.path.display());
+		}
+
+		Ok(())
+	}
+
+	fn add(&mut self, addr: &str, fingerprint: &str) -> Result<(), io::Error> {
+		if let Some(dir) = self.path.parent() {
+			fs::create_dir_all(dir)?;
+		}
+
+		let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+		writeln!(file, "{} {}", addr, fingerprint)?;
+
+		self.hosts.insert(addr.to_string(), fingerprint.to_string());
+		Ok(())
+	}
+}
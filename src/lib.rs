@@ -0,0 +1,30 @@
+//! `ubuffer` moves a stream of bytes between two hosts over UDT, encrypting
+//! each block with AES-256-GCM.
+//!
+//! The `ubuffer` binary is a thin command line wrapper around this library.
+//! The `proto` module contains the `Sender` & `Receiver` state machines, which
+//! may also be run over any other `proto::Transport`. (See: `proto::Loopback`.)
+
+#[macro_use] extern crate failure;
+#[macro_use] extern crate log;
+#[macro_use] extern crate serde_derive;
+
+extern crate base64;
+extern crate bincode;
+extern crate byteorder;
+extern crate curve25519_dalek;
+extern crate rand;
+extern crate ring;
+extern crate serde;
+extern crate untrusted;
+
+#[cfg(target_os = "linux")] extern crate libc;
+#[cfg(feature = "async")] extern crate tokio;
+#[cfg(feature = "udt-sys")] extern crate udt;
+
+pub mod error;
+pub mod ffi;
+pub mod key;
+pub mod proto;
+#[cfg(all(feature = "udt", not(feature = "udt-sys")))]
+pub mod udt;
@@ -0,0 +1,239 @@
+use log::{LevelFilter, Log};
+use std::env;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use ubuffer::proto::SessionId;
+
+#[cfg(unix)]
+use log::{Level, Metadata, Record};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::process;
+
+/// The name messages are logged under.
+const PROGRAM_NAME: &str = "ubuffer";
+
+/// The socket a local syslog daemon listens on.
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// The socket journald listens on for its native protocol.
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// The syslog facility messages are logged to. (`LOG_DAEMON`)
+const SYSLOG_FACILITY: u8 = 3;
+
+/// Set by `-q`, see `quiet()`.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// How much is logged, as chosen by `-v` & `-q`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verbosity {
+	/// Only errors are logged, and the notices printed along the way are
+	/// silenced, leaving just the final summary.
+	Quiet,
+
+	/// The level is set by `RUST_LOG`.
+	Default,
+
+	/// At least this level is logged, whatever `RUST_LOG` says.
+	Level(LevelFilter),
+}
+
+impl Verbosity {
+	/// The verbosity for `-v` given `count` times.
+	pub fn from_count(count: u64) -> Self {
+		match count {
+			0 => Verbosity::Default,
+			1 => Verbosity::Level(LevelFilter::Info),
+			2 => Verbosity::Level(LevelFilter::Debug),
+			_ => Verbosity::Level(LevelFilter::Trace),
+		}
+	}
+
+	fn level(self) -> Option<LevelFilter> {
+		match self {
+			Verbosity::Quiet => Some(LevelFilter::Error),
+			Verbosity::Default => None,
+			Verbosity::Level(level) => Some(level),
+		}
+	}
+}
+
+/// Returns true if `-q` was given, in which case only errors & the final
+/// summary should be printed.
+pub fn quiet() -> bool {
+	QUIET.load(Ordering::Relaxed)
+}
+
+/// Where log messages are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+	/// Standard error, via `env_logger`. (The default.)
+	Stderr,
+
+	/// The local syslog daemon.
+	Syslog,
+
+	/// The systemd journal.
+	Journald,
+}
+
+impl Target {
+	pub fn parse(name: &str) -> Option<Self> {
+		match name {
+			"stderr" => Some(Target::Stderr),
+			"syslog" => Some(Target::Syslog),
+			"journald" => Some(Target::Journald),
+			_ => None,
+		}
+	}
+}
+
+/// Sends each message to syslog or journald as a single datagram.
+#[cfg(unix)]
+struct SystemLogger {
+	target: Target,
+	socket: UnixDatagram,
+	level: LevelFilter,
+}
+
+/// Installs the logger for `target`.
+///
+/// The stderr logger is configured by `RUST_LOG` as usual. The system loggers
+/// only honour a plain level in `RUST_LOG` (i.e: `debug`) and default to
+/// `info`, since a service manager is where those messages are wanted. Either
+/// way a `verbosity` other than the default replaces the level `RUST_LOG` sets,
+/// though the stderr logger still honours its per-module levels.
+///
+/// Every logger tags a message logged by a session w/ the session's id, so
+/// that it can be matched up w/ the other peer's logs. (See: `SessionId`.)
+pub fn init(target: Target, verbosity: Verbosity) -> Result<(), failure::Error> {
+	QUIET.store(verbosity == Verbosity::Quiet, Ordering::Relaxed);
+
+	if target == Target::Stderr {
+		let mut builder = env_logger::Builder::from_default_env();
+		if let Some(level) = verbosity.level() {
+			builder.filter_level(level);
+		}
+
+		builder
+			.format(|buf, record| {
+				let session = SessionId::current().map(|id| format!(" {}", id)).unwrap_or_default();
+				writeln!(buf, "[{} {:<5} {}{}] {}",
+				         buf.timestamp(),
+				         buf.default_styled_level(record.level()),
+				         record.module_path().unwrap_or_default(),
+				         session,
+				         record.args())
+			})
+			.init();
+
+		return Ok(());
+	}
+
+	let level = verbosity.level()
+		.or_else(|| env::var("RUST_LOG").ok().and_then(|level| level.parse().ok()))
+		.unwrap_or(LevelFilter::Info);
+
+	let logger = connect(target, level)?;
+	log::set_logger(Box::leak(logger))?;
+	log::set_max_level(level);
+	Ok(())
+}
+
+#[cfg(unix)]
+fn connect(target: Target, level: LevelFilter) -> Result<Box<dyn Log>, failure::Error> {
+	let path = match target {
+		Target::Journald => JOURNALD_SOCKET,
+		_ => SYSLOG_SOCKET,
+	};
+
+	let socket = UnixDatagram::unbound()?;
+	socket.connect(path)
+		.map_err(|err| format_err!("could not connect to the system logger at {}: {}", path, err))?;
+
+	Ok(Box::new(SystemLogger { target, socket, level }))
+}
+
+#[cfg(not(unix))]
+fn connect(_target: Target, _level: LevelFilter) -> Result<Box<dyn Log>, failure::Error> {
+	bail!("logging to syslog or journald is not supported on this platform")
+}
+
+#[cfg(unix)]
+impl SystemLogger {
+	/// Formats a message for the BSD syslog protocol, the daemon adds the
+	/// timestamp & hostname itself.
+	fn syslog_datagram(record: &Record) -> Vec<u8> {
+		let priority = SYSLOG_FACILITY * 8 + severity(record.level());
+		let session = SessionId::current().map(|id| format!("[{}] ", id)).unwrap_or_default();
+		format!("<{}>{}[{}]: {}{}", priority, PROGRAM_NAME, process::id(), session, record.args()).into_bytes()
+	}
+
+	/// Formats a message for journald's native protocol. Fields are written
+	/// as `KEY=value` lines, except that a value which spans several lines is
+	/// written as `KEY`, a newline, and a little-endian `u64` length prefix.
+	fn journald_datagram(record: &Record) -> Vec<u8> {
+		let mut datagram = vec![];
+		let mut fields = vec![
+			("PRIORITY", severity(record.level()).to_string()),
+			("SYSLOG_IDENTIFIER", PROGRAM_NAME.to_string()),
+			("SYSLOG_PID", process::id().to_string()),
+			("TARGET", record.target().to_string()),
+			("MESSAGE", record.args().to_string()),
+		];
+
+		if let Some(session_id) = SessionId::current() {
+			fields.push(("UBUFFER_SESSION_ID", session_id.to_string()));
+		}
+
+		for (key, value) in fields.iter() {
+			datagram.extend_from_slice(key.as_bytes());
+
+			if value.contains('\n') {
+				datagram.push(b'\n');
+				datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+			} else {
+				datagram.push(b'=');
+			}
+
+			datagram.extend_from_slice(value.as_bytes());
+			datagram.push(b'\n');
+		}
+
+		datagram
+	}
+}
+
+#[cfg(unix)]
+impl Log for SystemLogger {
+	fn enabled(&self, metadata: &Metadata) -> bool {
+		metadata.level() <= self.level
+	}
+
+	fn log(&self, record: &Record) {
+		if !self.enabled(record.metadata()) { return; }
+
+		let datagram = match self.target {
+			Target::Journald => Self::journald_datagram(record),
+			_ => Self::syslog_datagram(record),
+		};
+
+		// there is nowhere left to report a failure to log
+		let _ = self.socket.send(&datagram);
+	}
+
+	fn flush(&self) {}
+}
+
+/// Maps a log level to a syslog severity, which journald also uses.
+#[cfg(unix)]
+fn severity(level: Level) -> u8 {
+	match level {
+		Level::Error => 3,
+		Level::Warn => 4,
+		Level::Info => 6,
+		Level::Debug | Level::Trace => 7,
+	}
+}
@@ -1,92 +1,962 @@
 #[macro_use] extern crate failure;
 #[macro_use] extern crate log;
-#[macro_use] extern crate serde_derive;
 
-extern crate base64;
-extern crate bincode;
-extern crate byteorder;
 extern crate clap;
 extern crate env_logger;
+extern crate glob;
+extern crate native_tls;
 extern crate rand;
 extern crate ring;
-extern crate serde;
-extern crate udt;
+extern crate serde_json;
+extern crate signal_hook;
+extern crate tar;
+extern crate toml;
+extern crate ubuffer;
+extern crate ureq;
 
-use crate::proto::{Sender, Receiver};
-use clap::{Arg, App, SubCommand};
-use std::io;
+#[cfg(target_os = "linux")] extern crate libc;
 
-mod error;
-mod proto;
+use clap::{Arg, ArgGroup, App, ArgMatches, SubCommand};
+use signal_hook::consts::TERM_SIGNALS;
+#[cfg(feature = "udt")]
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+#[cfg(feature = "udt")]
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+#[cfg(feature = "udt")]
+use std::time::UNIX_EPOCH;
+use ubuffer::error::ProtoError;
+use crate::checksum::Checksum;
+use crate::command::{CommandReader, CommandWriter};
+use crate::config::Config;
+use crate::dashboard::{Dashboard, DASHBOARD_INTERVAL};
+use crate::hook::Hooks;
+use crate::known_hosts::KnownHosts;
+#[cfg(feature = "udt")]
+use crate::metrics::{Metrics, SessionMetrics};
+use crate::progress::Progress;
+use ubuffer::key;
+use ubuffer::proto::{human_bytes, AddrFamily, BLOCK_SIZE, CancelToken, CaptureDecoder, Checkpoint, Cidr, Cipher, Congestion, Event, FanOut, FaultCode, FileMeta, generate_code, Identity, Impairment, MemoryBudget, Preserve, Sender, SessionId, Receiver, StreamOpts, Summary, TransportKind, UdtMode};
+#[cfg(feature = "udt")]
+use ubuffer::proto::{Hub, Relay, Session};
+
+mod archive;
+mod checksum;
+mod command;
+mod config;
+mod dashboard;
+mod hook;
+mod http;
+mod known_hosts;
+mod logging;
+#[cfg(feature = "udt")]
+mod metrics;
+mod progress;
+mod prompt;
+mod s3;
 
 const CLI_TITLE: &str = "UDT buffer"; 
 
 const CLI_SUB_GENKEY: &str = "genkey";
 const CLI_SUB_SEND: &str = "sender";
 const CLI_SUB_RECV: &str = "receiver";
+const CLI_SUB_RELAY: &str = "relay";
+const CLI_SUB_PING: &str = "ping";
+const CLI_SUB_DEBUG: &str = "debug";
+const CLI_SUB_DECODE: &str = "decode";
 
 const CLI_ARG_KEY: &str = "KEY";
 const CLI_ARG_KEY_SHORT: &str = "k";
 const CLI_ARG_KEY_LONG: &str = "key";
+const CLI_ARG_KEY_FILE: &str = "KEY_FILE";
+const CLI_ARG_KEY_FILE_LONG: &str = "key-file";
+const CLI_ARG_CODE: &str = "CODE";
+const CLI_ARG_CODE_LONG: &str = "code";
 const CLI_ARG_INET_ADDR: &str = "INET_ADDR";
+const CLI_ARG_TARGET_ADDR: &str = "TARGET_ADDR";
+const CLI_ARG_OUT: &str = "OUT";
+const CLI_ARG_OUT_SHORT: &str = "o";
+const CLI_ARG_OUT_LONG: &str = "out";
+const CLI_ARG_LABEL: &str = "LABEL";
+const CLI_ARG_LABEL_LONG: &str = "label";
+const CLI_ARG_FINGERPRINT: &str = "FINGERPRINT";
+const CLI_ARG_FINGERPRINT_LONG: &str = "fingerprint";
+const CLI_ARG_BITS: &str = "BITS";
+const CLI_ARG_BITS_LONG: &str = "bits";
+const CLI_ARG_LISTEN: &str = "LISTEN";
+const CLI_ARG_LISTEN_LONG: &str = "listen";
+const CLI_ARG_LISTEN_ADDR: &str = "LISTEN_ADDR";
+const CLI_ARG_CONNECT: &str = "CONNECT";
+const CLI_ARG_CONNECT_LONG: &str = "connect";
+const CLI_ARG_BIND: &str = "BIND";
+const CLI_ARG_BIND_LONG: &str = "bind";
+const CLI_ARG_MULTIPATH: &str = "MULTIPATH";
+const CLI_ARG_MULTIPATH_LONG: &str = "multipath";
+const CLI_ARG_REKEY: &str = "REKEY_INTERVAL";
+const CLI_ARG_REKEY_LONG: &str = "rekey-interval";
+const CLI_ARG_MAX_IN_FLIGHT: &str = "MAX_IN_FLIGHT";
+const CLI_ARG_MAX_IN_FLIGHT_LONG: &str = "max-in-flight";
+const CLI_ARG_FILE: &str = "FILE";
+const CLI_ARG_FILE_SHORT: &str = "f";
+const CLI_ARG_FILE_LONG: &str = "file";
+const CLI_ARG_INPUT: &str = "INPUT";
+const CLI_ARG_URL: &str = "URL";
+const CLI_ARG_URL_LONG: &str = "input";
+const CLI_ARG_CONCAT: &str = "CONCAT";
+const CLI_ARG_CONCAT_LONG: &str = "concat";
+const CLI_ARG_OUTPUT: &str = "OUTPUT";
+const CLI_ARG_TAR: &str = "TAR";
+const CLI_ARG_TAR_LONG: &str = "tar";
+const CLI_ARG_MIRROR: &str = "MIRROR";
+const CLI_ARG_MIRROR_LONG: &str = "mirror";
+const CLI_ARG_MMAP: &str = "MMAP";
+const CLI_ARG_MMAP_LONG: &str = "mmap";
+const CLI_ARG_SPARSE: &str = "SPARSE";
+const CLI_ARG_SPARSE_LONG: &str = "sparse";
+const CLI_ARG_PAD: &str = "PAD";
+const CLI_ARG_PAD_LONG: &str = "pad";
+const CLI_ARG_LOW_LATENCY: &str = "LOW_LATENCY";
+const CLI_ARG_LOW_LATENCY_LONG: &str = "low-latency";
+const CLI_ARG_MIN_BLOCK: &str = "MIN_BLOCK";
+const CLI_ARG_MIN_BLOCK_LONG: &str = "min-block";
+const CLI_ARG_MAX_WAIT: &str = "MAX_WAIT";
+const CLI_ARG_MAX_WAIT_LONG: &str = "max-wait";
+const CLI_ARG_LINE_BUFFERED: &str = "LINE_BUFFERED";
+const CLI_ARG_LINE_BUFFERED_LONG: &str = "line-buffered";
+const CLI_ARG_RATE_LIMIT: &str = "RATE_LIMIT";
+const CLI_ARG_RATE_LIMIT_LONG: &str = "rate-limit";
+const CLI_ARG_RECEIPT: &str = "RECEIPT";
+const CLI_ARG_RECEIPT_LONG: &str = "receipt";
+const CLI_ARG_EXPECT_SIZE: &str = "EXPECT_SIZE";
+const CLI_ARG_EXPECT_SIZE_LONG: &str = "expect-size";
+const CLI_ARG_VERIFY: &str = "VERIFY";
+const CLI_ARG_ALLOW: &str = "ALLOW";
+const CLI_ARG_ALLOW_LONG: &str = "allow";
+const CLI_ARG_VERIFY_LONG: &str = "verify";
+const CLI_ARG_INCLUDE: &str = "INCLUDE";
+const CLI_ARG_INCLUDE_LONG: &str = "include";
+const CLI_ARG_EXCLUDE: &str = "EXCLUDE";
+const CLI_ARG_EXCLUDE_LONG: &str = "exclude";
+const CLI_ARG_UNTAR: &str = "UNTAR";
+const CLI_ARG_UNTAR_LONG: &str = "untar";
+const CLI_ARG_HUB: &str = "HUB";
+const CLI_ARG_HUB_LONG: &str = "hub";
+const CLI_ARG_DIR: &str = "DIR";
+const CLI_ARG_DIR_LONG: &str = "dir";
+const CLI_GRP_OUTPUT: &str = "OUTPUTS";
+const CLI_ARG_TEE: &str = "TEE";
+const CLI_ARG_TEE_LONG: &str = "tee";
+const CLI_ARG_CHECKSUM: &str = "WRITE_CHECKSUM";
+const CLI_ARG_CHECKSUM_LONG: &str = "write-checksum";
+const CLI_ARG_DIRECT_IO: &str = "DIRECT_IO";
+const CLI_ARG_DIRECT_IO_LONG: &str = "direct-io";
+const CLI_ARG_FORCE: &str = "FORCE";
+const CLI_ARG_FORCE_LONG: &str = "force";
+const CLI_ARG_NO_CLOBBER: &str = "NO_CLOBBER";
+const CLI_ARG_NO_CLOBBER_LONG: &str = "no-clobber";
+const CLI_ARG_BACKUP_EXISTING: &str = "BACKUP_EXISTING";
+const CLI_ARG_BACKUP_EXISTING_LONG: &str = "backup-existing";
+const CLI_ARG_CHECKPOINT: &str = "CHECKPOINT";
+const CLI_ARG_CHECKPOINT_LONG: &str = "checkpoint";
+const CLI_ARG_MIGRATE: &str = "MIGRATE";
+const CLI_ARG_MIGRATE_LONG: &str = "migrate";
+const CLI_ARG_FSYNC_INTERVAL: &str = "FSYNC_INTERVAL";
+const CLI_ARG_FSYNC_INTERVAL_LONG: &str = "fsync-interval";
+const CLI_ARG_SYNC_ON_CLOSE: &str = "SYNC_ON_CLOSE";
+const CLI_ARG_SYNC_ON_CLOSE_LONG: &str = "sync-on-close";
+const CLI_ARG_STALL_WARNING: &str = "STALL_WARNING";
+const CLI_ARG_STALL_WARNING_LONG: &str = "stall-warning";
+const CLI_ARG_APPEND: &str = "APPEND";
+const CLI_ARG_APPEND_LONG: &str = "append";
+const CLI_ARG_PRESERVE: &str = "PRESERVE";
+const CLI_ARG_PRESERVE_LONG: &str = "preserve";
+const CLI_ARG_PRESERVE_PERMS: &str = "PRESERVE_PERMS";
+const CLI_ARG_PRESERVE_PERMS_LONG: &str = "preserve-perms";
+const CLI_ARG_PRESERVE_OWNER: &str = "PRESERVE_OWNER";
+const CLI_ARG_PRESERVE_OWNER_LONG: &str = "preserve-owner";
+const CLI_ARG_KEEPALIVE: &str = "KEEPALIVE";
+const CLI_ARG_KEEPALIVE_LONG: &str = "keepalive";
+const CLI_ARG_RETRY: &str = "RETRY";
+const CLI_ARG_RETRY_LONG: &str = "retry";
+const CLI_ARG_RETRY_DELAY: &str = "RETRY_DELAY";
+const CLI_ARG_RETRY_DELAY_LONG: &str = "retry-delay";
+const CLI_ARG_SUMMARY: &str = "SUMMARY";
+const CLI_ARG_SUMMARY_LONG: &str = "summary";
+const CLI_ARG_SIMULATE: &str = "SIMULATE";
+const CLI_ARG_SIMULATE_LONG: &str = "simulate";
+const CLI_ARG_JSON: &str = "JSON";
+const CLI_ARG_JSON_LONG: &str = "json";
+const CLI_ARG_METRICS: &str = "METRICS_LISTEN";
+const CLI_ARG_METRICS_LONG: &str = "metrics-listen";
+const CLI_ARG_LOG: &str = "LOG";
+const CLI_ARG_VERBOSE: &str = "VERBOSE";
+const CLI_ARG_VERBOSE_SHORT: &str = "v";
+const CLI_ARG_VERBOSE_LONG: &str = "verbose";
+const CLI_ARG_QUIET: &str = "QUIET";
+const CLI_ARG_QUIET_SHORT: &str = "q";
+const CLI_ARG_QUIET_LONG: &str = "quiet";
+const CLI_ARG_PROFILE: &str = "PROFILE";
+const CLI_ARG_PROFILE_LONG: &str = "profile";
+const CLI_ARG_LOG_LONG: &str = "log";
+const CLI_ARG_STATS: &str = "STATS_INTERVAL";
+const CLI_ARG_STATS_LONG: &str = "stats-interval";
+const CLI_ARG_PROGRESS_FD: &str = "PROGRESS_FD";
+const CLI_ARG_TRANSPORT: &str = "TRANSPORT";
+const CLI_ARG_TRANSPORT_LONG: &str = "transport";
+const CLI_ARG_IPV4: &str = "IPV4";
+const CLI_ARG_IPV4_SHORT: &str = "4";
+const CLI_ARG_IPV4_LONG: &str = "ipv4";
+const CLI_ARG_IPV6: &str = "IPV6";
+const CLI_ARG_IPV6_SHORT: &str = "6";
+const CLI_ARG_IPV6_LONG: &str = "ipv6";
+const CLI_ARG_PROGRESS_FD_LONG: &str = "progress-fd";
+const CLI_ARG_TUI: &str = "TUI";
+const CLI_ARG_TUI_LONG: &str = "tui";
+const CLI_ARG_ON_COMPLETE: &str = "ON_COMPLETE";
+const CLI_ARG_ON_COMPLETE_LONG: &str = "on-complete";
+const CLI_ARG_ON_ERROR: &str = "ON_ERROR";
+const CLI_ARG_ON_ERROR_LONG: &str = "on-error";
+const CLI_ARG_FILTER_CMD: &str = "FILTER_CMD";
+const CLI_ARG_FILTER_CMD_LONG: &str = "filter-cmd";
+const CLI_ARG_CONGESTION: &str = "CONGESTION";
+const CLI_ARG_CONGESTION_LONG: &str = "congestion";
+const CLI_ARG_UDT_MODE: &str = "UDT_MODE";
+const CLI_ARG_UDT_MODE_LONG: &str = "mode";
+const CLI_ARG_MESSAGE_TTL: &str = "MESSAGE_TTL";
+const CLI_ARG_MESSAGE_TTL_LONG: &str = "message-ttl";
+const CLI_ARG_UNORDERED: &str = "UNORDERED";
+const CLI_ARG_UNORDERED_LONG: &str = "unordered";
+const CLI_ARG_CRYPTO_THREADS: &str = "CRYPTO_THREADS";
+const CLI_ARG_CRYPTO_THREADS_LONG: &str = "crypto-threads";
+const CLI_ARG_MAX_MEMORY: &str = "MAX_MEMORY";
+const CLI_ARG_MAX_MEMORY_LONG: &str = "max-memory";
+const CLI_ARG_CIPHER: &str = "CIPHER";
+const CLI_ARG_CIPHER_LONG: &str = "cipher";
+const CLI_ARG_RESUME_TIMEOUT: &str = "RESUME_TIMEOUT";
+const CLI_ARG_RESUME_TIMEOUT_LONG: &str = "resume-timeout";
+const CLI_ARG_IDENTITY: &str = "IDENTITY";
+const CLI_ARG_IDENTITY_LONG: &str = "identity";
+const CLI_ARG_EXPECT_FINGERPRINT: &str = "EXPECT_FINGERPRINT";
+const CLI_ARG_EXPECT_FINGERPRINT_LONG: &str = "expect-fingerprint";
+const CLI_ARG_KNOWN_HOSTS: &str = "KNOWN_HOSTS";
+const CLI_ARG_KNOWN_HOSTS_LONG: &str = "known-hosts";
+const CLI_ARG_ACCEPT_NEW: &str = "ACCEPT_NEW";
+const CLI_ARG_ACCEPT_NEW_LONG: &str = "accept-new";
+const CLI_ARG_SEED: &str = "SEED";
+const CLI_ARG_SEED_LONG: &str = "seed";
+const CLI_ARG_CAPTURE: &str = "CAPTURE";
+const CLI_ARG_IV: &str = "IV";
+const CLI_ARG_IV_LONG: &str = "iv";
+const CLI_ARG_PREVIEW: &str = "PREVIEW";
+const CLI_ARG_PREVIEW_LONG: &str = "preview";
+
+const CLI_SUMMARY_TEXT: &str = "text";
+const CLI_SUMMARY_JSON: &str = "json";
+
+const CLI_TRANSPORT_UDT: &str = "udt";
+const CLI_TRANSPORT_UDP: &str = "udp";
+
+// w/o the `udt` feature there is only the one transport to default to
+#[cfg(feature = "udt")]
+const CLI_TRANSPORT_DEFAULT: &str = CLI_TRANSPORT_UDT;
+#[cfg(not(feature = "udt"))]
+const CLI_TRANSPORT_DEFAULT: &str = CLI_TRANSPORT_UDP;
+
+const CLI_CONGESTION_ADAPTIVE: &str = "adaptive";
+const CLI_CONGESTION_FIXED: &str = "fixed";
+const CLI_UDT_MODE_STREAM: &str = "stream";
+const CLI_UDT_MODE_MESSAGE: &str = "message";
+
+const CLI_CIPHER_AUTO: &str = "auto";
+const CLI_CIPHER_AES_256_GCM: &str = "aes-256-gcm";
+const CLI_CIPHER_AES_128_GCM: &str = "aes-128-gcm";
+const CLI_CIPHER_CHACHA20_POLY1305: &str = "chacha20-poly1305";
 
 const CLI_TXT_APP: &str = "Transfer files between two nodes using the UDT protocol.";
 const CLI_TXT_INET: &str = "The network address & port used to send & receive data. (i.e: 0.0.0.0:9999)";
-const CLI_TXT_KEY: &str = "The encryption key used to encrypt data blocks. (Must match on both sender & receiver.)";
-const CLI_TXT_GENKEY: &str = "generates a random encryption key on stdout (256-bits, base64 encoded)";
+const CLI_TXT_LISTEN: &str = "Listen on INET_ADDR for the receiver to connect, instead of connecting to it. (See: receiver --connect.)";
+const CLI_TXT_LISTEN_ADDR: &str = "Also listen on this address & port, accepting the first sender to reach any of them. (i.e: --listen 0.0.0.0:9999 --listen [::]:9999, IPv6 requires --transport udp.) May be repeated.";
+const CLI_TXT_CONNECT: &str = "Connect to a sender listening on INET_ADDR, instead of listening for it. (See: sender --listen.)";
+const CLI_TXT_BIND: &str = "The local address & port the sender connects from. (i.e: 0.0.0.0:9000)";
+const CLI_TXT_MULTIPATH: &str = "Also open a path to the receiver from this local address (i.e: the address of a second uplink, 192.0.2.7 or 192.0.2.7:9000) and stripe the session across every path. May be repeated, once per path.";
+const CLI_TXT_REKEY: &str = "Rotate the session key after sending this many bytes. (i.e: 64G, suffixes K/M/G/T are powers of 1024.)";
+const CLI_TXT_MAX_IN_FLIGHT: &str = "Wait on the receiver while this much data is unacknowledged. (i.e: 16M, the default.) This is at least 512K, and at most what the retransmit window holds. (Requires a receiver which acknowledges blocks.)";
+const CLI_TXT_FILE: &str = "Send this file instead of stdin, its name, permissions, and modification time are sent along with it. May be repeated to send several files in one session.";
+const CLI_TXT_INPUT: &str = "Files to send instead of stdin, in order. (The same as giving each one w/ --file.)";
+const CLI_TXT_URL: &str = "Send the body of this URL instead of stdin. (i.e: https://example.com/disk.img, or s3://bucket/key w/ credentials taken from the usual AWS_* environment variables.)";
+const CLI_TXT_CONCAT: &str = "Send the files back to back as a single stream, w/o their names or the boundaries between them.";
+const CLI_TXT_MMAP: &str = "Map the input files into memory rather than reading them. A file which is truncated while it is sent crashes the sender.";
+const CLI_TXT_LOW_LATENCY: &str = "Send each chunk of input as soon as it is read, rather than batching it w/ the chunks read after it. This trades throughput for latency, e.g: for interactive input.";
+const CLI_TXT_MIN_BLOCK: &str = "Wait on the input to fill out blocks shorter than this, e.g: 64K, so that an input which trickles in is not sent as many tiny blocks. (At most 8K.)";
+const CLI_TXT_MAX_WAIT: &str = "How many milliseconds to wait on the input to fill out a block, before sending it as it is.";
+const CLI_TXT_LINE_BUFFERED: &str = "Send the input a line at a time, holding back a partial line until its newline is read. e.g: for a log piped from `tail -f`.";
+const CLI_TXT_RATE_LIMIT: &str = "Send at most this many bytes per second on average, e.g: 50M, so that a transfer over a shared link leaves room for everything else on it.";
+const CLI_TXT_SPARSE: &str = "Skip over the holes in sparse files, or long runs of zeros in any other input, rather than sending their zeros. The receiver leaves them as holes in its output. (Requires a receiver which supports it.)";
+const CLI_TXT_PAD: &str = "Pad every block to the full block size, and send cover blocks while the input is idle, so that an observer cannot tell how much data is sent from the ciphertext. (Requires a receiver which supports it.)";
+const CLI_TXT_OUTPUT: &str = "Write the received data to this file instead of stdout, or upload it to an object given as s3://bucket/key.";
+const CLI_TXT_TAR: &str = "Send a tar archive of this directory instead of stdin.";
+const CLI_TXT_INCLUDE: &str = "Only archive the files which match this glob pattern. (i.e: '*.img', patterns w/o a '/' match names, others match paths within the directory.) May be repeated.";
+const CLI_TXT_EXCLUDE: &str = "Skip the files & directories which match this glob pattern. (i.e: target, '*.tmp') May be repeated.";
+const CLI_TXT_EXPECT_SIZE: &str = "Tell the receiver the input is this many bytes (i.e: 64G), so that it can report its progress. The transfer fails if the input is any other length. (Files are measured w/o this.)";
+const CLI_TXT_RECEIPT: &str = "Once the transfer succeeds, write the receipt the receiver signed for the data to this file. Fails unless the receiver has an --identity.";
+const CLI_TXT_MIRROR: &str = "Also send the input to the receiver at this address, w/ its own handshake. The input is only read once. May be repeated.";
+const CLI_TXT_UNTAR: &str = "Extract the tar archive sent by the sender into this directory instead of writing it to stdout.";
+const CLI_TXT_HUB: &str = "Accept any number of simultaneous senders until interrupted, writing each one to a file named by this template. ({n} or {seq} is the session number, {addr} & {port} are the sender's address, {date} & {time} are when it connected in UTC, and {timestamp} is that time in seconds since the epoch.)";
+const CLI_TXT_DIR: &str = "Write each file of a multi-file session into this directory.";
+const CLI_TXT_TEE: &str = "Also write the received data to this file, as well as to stdout or the --out file. May be repeated.";
+const CLI_TXT_DIRECT_IO: &str = "Open the --out file w/ O_DIRECT, so that writing it bypasses the page cache. (Linux only, for fast disks where the cache only gets in the way.)";
+const CLI_TXT_FORCE: &str = "Truncate an existing --out file, which is what happens unless one of the options below is given. (This overrides them, i.e: when set by a profile.)";
+const CLI_TXT_NO_CLOBBER: &str = "Refuse to start if the --out file already exists.";
+const CLI_TXT_BACKUP_EXISTING: &str = "Rename an existing --out file to <PATH>.bak (or .bak.1, .bak.2, ...) before writing the new one.";
+const CLI_TXT_MIGRATE: &str = "Keep listening during the session, so that a sender which reconnects from a new address (i.e: after moving networks) takes the session over right away, rather than once this side notices the old connection is gone.";
+const CLI_TXT_CHECKPOINT: &str = "Save the session's progress to this file every so often, so that a receiver restarted w/ the same options after a crash picks up where it left off.";
+const CLI_TXT_FSYNC_INTERVAL: &str = "Sync the data written to a file output to disk this often, rather than leaving it to the OS. (i.e: 10s)";
+const CLI_TXT_STALL_WARNING: &str = "Warn each time this long passes w/o a word from the sender, once blocks are flowing. (i.e: 60s, the default. 0 disables the warning.)";
+const CLI_TXT_SYNC_ON_CLOSE: &str = "Sync a file output to disk before acknowledging the sender's goodbye, so the sender only succeeds once the data is on stable storage.";
+const CLI_TXT_CHECKSUM: &str = "Once the transfer succeeds, write the SHA-256 of the received data to a file named after the --out file w/ .sha256 appended. (The format of `sha256sum`.)";
+const CLI_TXT_APPEND: &str = "Append the received data to the --out file instead of truncating it, creating it if it does not exist.";
+const CLI_TXT_PRESERVE: &str = "Apply the permissions & modification time sent by the sender to the --out file, or to each file written to --dir.";
+const CLI_TXT_PRESERVE_PERMS: &str = "Apply only the permissions sent by the sender to the --out file, or to each file written to --dir.";
+const CLI_TXT_PRESERVE_OWNER: &str = "Apply the numeric owner & group sent by the sender to the --out file, or to each file written to --dir. (Requires the privileges to do so, i.e: root.)";
+const CLI_TXT_KEEPALIVE: &str = "Send a keepalive after the input has been idle for this many seconds. (0 disables keepalives.)";
+const CLI_TXT_RETRY: &str = "If the receiver cannot be reached, try again this many times before giving up. (e.g: when it is started independently & may not be up yet.)";
+const CLI_TXT_RETRY_DELAY: &str = "How long to wait before the first retry, this doubles after each one up to a minute. (i.e: 500ms)";
+const CLI_TXT_SUMMARY: &str = "The format of the transfer summary printed on stderr when the session ends.";
+const CLI_TXT_SIMULATE: &str = "For testing: simulate a poor network by delaying, dropping, reordering, or corrupting what this side sends. (i.e: loss=1%,reorder=0.5%,corrupt=0.1%,delay=50ms)";
+const CLI_TXT_JSON: &str = "Emit newline-delimited JSON events describing the session's progress on stderr.";
+const CLI_TXT_PROFILE: &str = "Use the options of a profile in ~/.config/ubuffer/config.toml, those given on the command line take precedence. A profile w/ an `address` is used in place of the address argument.";
+const CLI_TXT_VERBOSE: &str = "Log more: `-v` for info messages, `-vv` for debug messages, and `-vvv` for everything. (This overrides the level set by $RUST_LOG.)";
+const CLI_TXT_QUIET: &str = "Only print errors and the final summary.";
+const CLI_TXT_LOG: &str = "Where log messages are written, `syslog` & `journald` log as \"ubuffer\" for use under a service manager. (The level is set by $RUST_LOG.)";
+const CLI_TXT_METRICS: &str = "Serve Prometheus metrics for the hub's sessions over HTTP on this address. (i.e: 0.0.0.0:9100)";
+const CLI_TXT_STATS: &str = "Report the throughput, UDT queue lengths, round-trip time & packets lost on stderr at this interval during the transfer. (i.e: 5s, 500ms)";
+const CLI_TXT_IPV4: &str = "Only use the IPv4 addresses a name resolves to.";
+const CLI_TXT_IPV6: &str = "Only use the IPv6 addresses a name resolves to. (Requires --transport udp.)";
+const CLI_TXT_TRANSPORT: &str = "The protocol which carries the session, both peers must use the same one. `udp` uses a simple retransmission scheme instead of UDT's congestion control.";
+const CLI_TXT_CONGESTION: &str = "How the transport responds to loss. `fixed` keeps sending at the full window instead of backing off, for dedicated links. (Requires --transport udp on a build w/ the `udt-sys` feature.)";
+const CLI_TXT_UDT_MODE: &str = "The kind of UDT socket which carries the session, both peers must use the same one. `message` sends each block as a single UDT message, which it is read back as, for lower latency on small payloads. (Requires --transport udt.)";
+const CLI_TXT_MESSAGE_TTL: &str = "Let UDT drop a block which has not been delivered within this long, e.g: 500ms, rather than retransmitting it for as long as it takes. The receiver asks for it again, so nothing is lost. (Requires --mode message.)";
+const CLI_TXT_UNORDERED: &str = "Let UDT deliver a block ahead of the blocks sent before it, rather than holding it back until they arrive. The receiver asks for those again. (Requires --mode message.)";
+const CLI_TXT_CIPHER: &str = "The cipher to insist on. `auto` uses AES-256-GCM when both peers can accelerate AES, and ChaCha20-Poly1305 otherwise. (A 128-bit key always uses AES-128-GCM.)";
+const CLI_TXT_RESUME_TIMEOUT: &str = "If the connection drops mid-transfer, keep trying to resume the session for this long. (60s by default, 0 never resumes.) Both peers must be able to resume.";
+const CLI_TXT_ALLOW: &str = "Only accept senders from this range of addresses (i.e: 10.0.0.0/8 or 192.0.2.7), connections from anywhere else are dropped before the handshake. May be repeated.";
+const CLI_TXT_VERIFY: &str = "Print a fingerprint of the session (a few words) once the handshake is done, to read out & compare w/ the one printed by the other side. They only match if both sides hold the same key & nobody is in the middle. The sender asks on the terminal whether they match before sending anything.";
+const CLI_TXT_CRYPTO_THREADS: &str = "How many threads encrypt (or decrypt) blocks in parallel, for links faster than one core can keep up with.";
+const CLI_TXT_MAX_MEMORY: &str = "Size the socket's buffers, the retransmit window & the crypto threads to fit the session in this much memory. (i.e: 16M, at least 4M.)";
+const CLI_TXT_PROGRESS_FD: &str = "Write a line of JSON w/ the bytes transferred & the rate to this inherited file descriptor about once a second, and when the session ends.";
+const CLI_TXT_TUI: &str = "Draw a live dashboard of the transfer on the terminal: a graph of the throughput, the bytes done & how long is left, the round-trip time & loss, and the most recent events. (Samples every second, unless --stats-interval is given.)";
+const CLI_TXT_ON_COMPLETE: &str = "A shell command to run once the transfer has completed, e.g: to start the next step of a pipeline. Its environment has $UBUFFER_BYTES, $UBUFFER_ELAPSED_SECS, $UBUFFER_SHA256 & $UBUFFER_STATUS. (If it fails, so does `ubuffer`.)";
+const CLI_TXT_ON_ERROR: &str = "A shell command to run if the transfer failed, w/ the same environment as --on-complete plus $UBUFFER_ERROR. `ubuffer` still exits w/ the transfer's error.";
+const CLI_TXT_FILTER_CMD: &str = "Pipe the input through this shell command before it is encrypted, e.g: `zstd -T0` to compress it w/ a codec `ubuffer` does not have. The receiver undoes it w/ its own --filter-cmd. (The input is sent as a single stream, so several files need --concat or --tar.)";
+const CLI_TXT_FILTER_CMD_RECV: &str = "Pipe the data through this shell command after it is decrypted & before it is written out, e.g: `zstd -d` to undo the sender's --filter-cmd.";
+const CLI_TXT_KEY: &str = "The encryption key used to encrypt data blocks. (Must match on both sender & receiver.) If neither this nor --key-file is given the key is read from $UBUFFER_KEY, or else asked for on the terminal.";
+const CLI_TXT_KEY_FILE: &str = "A file containing the encryption key, as printed by `ubuffer genkey`. May be repeated on the receiver to accept senders using any of the keys, e.g: one per sender.";
+const CLI_TXT_GENKEY: &str = "generates a random encryption key on stdout (base64 encoded)";
+const CLI_TXT_OUT: &str = "Write the key to this file (readable only by its owner) instead of stdout.";
+const CLI_TXT_LABEL: &str = "A human readable label stored as a comment above the key.";
+const CLI_TXT_FINGERPRINT: &str = "Print a short fingerprint of the key on stderr.";
+const CLI_TXT_BITS: &str = "The size of the key, a 128-bit key uses AES-128-GCM rather than AES-256-GCM.";
+const CLI_TXT_CODE: &str = "A short code (e.g: from `ubuffer genkey --code`) to agree on the encryption key from, instead of giving one. (Must match on both sender & receiver.) Someone who does not know the code only gets one guess at it per session, so it can be short enough to read out.";
+const CLI_TXT_GEN_CODE: &str = "Generate a short code for --code instead of an encryption key.";
+const CLI_TXT_GEN_IDENTITY: &str = "Generate an identity (an Ed25519 keypair) for a sender or receiver instead of an encryption key, and print its fingerprint on stderr.";
+const CLI_TXT_IDENTITY: &str = "A file containing this receiver's identity, as written by `ubuffer genkey --identity`. It proves to senders w/ --expect-fingerprint that they reached this receiver.";
+const CLI_TXT_SENDER_IDENTITY: &str = "A file containing this sender's identity, as written by `ubuffer genkey --identity`. It proves to receivers w/ --expect-fingerprint that they reached this sender.";
+const CLI_TXT_EXPECT_SENDER: &str = "Refuse senders unless they prove they hold the identity w/ this fingerprint. May be repeated to accept any of several senders.";
+const CLI_TXT_EXPECT_FINGERPRINT: &str = "Refuse to send unless the receiver proves it holds the identity w/ this fingerprint, instead of checking it against the known hosts. (i.e: a1b2:c3d4:...:0718, the sixteen groups `genkey --identity` prints)";
+const CLI_TXT_KNOWN_HOSTS: &str = "The file recording the fingerprints of the receivers trusted so far. (Defaults to ~/.config/ubuffer/known_hosts.)";
+const CLI_TXT_ACCEPT_NEW: &str = "Trust a receiver w/ an identity the first time it is seen, and add it to the known hosts. A receiver whose identity has changed is still refused.";
 const CLI_TXT_SEND: &str = "starts `ubuffer` in sender mode.";
 const CLI_TXT_RECV: &str = "starts `ubuffer` in receiver mode.";
+const CLI_TXT_RELAY: &str = "forwards one encrypted session from a sender to a receiver, without the key.";
+const CLI_TXT_RELAY_LISTEN: &str = "The network address & port the sender connects to. (i.e: 0.0.0.0:9999)";
+const CLI_TXT_RELAY_TARGET: &str = "The network address & port of the receiver. (i.e: 10.0.0.2:9999)";
+const CLI_TXT_PING: &str = "checks that a receiver is reachable & has the same key, without sending any data.";
+const CLI_TXT_SEED: &str = "For testing only: draw the random parts of the session (or key) from a generator seeded w/ this number, so that they are the same every time.";
+const CLI_TXT_DEBUG: &str = "tools for diagnosing problems w/ the protocol itself.";
+const CLI_TXT_DECODE: &str = "describes each message in a recorded byte stream of a session, opening the sealed ones if given the key.";
+const CLI_TXT_CAPTURE: &str = "A file holding everything one peer sent to the other, in order. Give the receiver's first, the IV it chose is needed to open what the sender sealed. May be repeated.";
+const CLI_TXT_DECODE_KEY: &str = "The session's encryption key, w/o one only the headers & plaintext messages are described.";
+const CLI_TXT_IV: &str = "The IV the receiver chose, as 8 hex digits, for a sender's capture decoded w/o the receiver's.";
+const CLI_TXT_DECODE_CIPHER: &str = "The cipher the session used, otherwise it is read from the capture or each one which fits the key is tried.";
+const CLI_TXT_PREVIEW: &str = "How many bytes of each payload to show.";
+
+const CLI_TXT_EXIT: &str = "EXIT STATUS:
+    0      The transfer completed successfully.
+    1      An unclassified error occurred. (e.g: bad arguments, output i/o errors.)
+    2      Could not connect to, or accept a connection from, the peer. (retryable)
+    3      A block failed to decrypt, most likely the keys do not match.
+    4      The peer hung up or aborted in the middle of a transfer. (retryable)
+    5      The peer violated the protocol.
+    130    The transfer was interrupted by SIGINT or SIGTERM.";
+
+/// Environment variable consulted for the key when neither `--key` nor
+/// `--key-file` are given.
+const ENV_KEY: &str = "UBUFFER_KEY";
+
+/// Exit status used for errors which do not fall into any other category.
+const EXIT_FAILURE: i32 = 1;
+
+/// Exit status used when a connection with the peer could not be established.
+const EXIT_CONNECT_FAILED: i32 = 2;
+
+/// Exit status used when a message fails to decrypt. (i.e: mismatched keys.)
+const EXIT_CRYPTO_FAILED: i32 = 3;
+
+/// Exit status used when the peer hangs up or aborts mid-transfer.
+const EXIT_PEER_HANGUP: i32 = 4;
+
+/// Exit status used when the peer sends something it should not have.
+const EXIT_PROTOCOL_ERROR: i32 = 5;
+
+/// Exit status used when the transfer was cut short by SIGINT or SIGTERM.
+const EXIT_INTERRUPTED: i32 = 130;
+
+fn main() {
+	if let Err(err) = run() {
+		match err.downcast_ref::<ProtoError>() {
+			#[cfg(feature = "udt")]
+			Some(ProtoError::SocketErr { inner }) | Some(ProtoError::ConnectErr { inner }) => {
+				eprintln!("ubuffer: {}: {}", err, inner.err_msg);
+			},
+
+			Some(ProtoError::UnknownHost { .. }) => {
+				eprintln!("ubuffer: {}, check it & pass --accept-new to trust it", err);
+			},
+
+			_ => eprintln!("ubuffer: {}", err),
+		}
+
+		debug!("{:?}", err);
+		process::exit(exit_code(&err));
+	}
+}
+
+/// Inserts the options of the `--profile` named in `args` after the subcommand,
+/// before they are parsed. (See: `Config`.)
+fn with_profile(args: Vec<OsString>) -> Result<Vec<OsString>, failure::Error> {
+	let flag = format!("--{}", CLI_ARG_PROFILE_LONG);
+	let name = args.iter().enumerate().find_map(|(i, arg)| match arg.to_str() {
+		Some(arg) if arg == flag => args.get(i + 1).and_then(|name| name.to_str()),
+		Some(arg) => arg.strip_prefix(&flag).and_then(|rest| rest.strip_prefix('=')),
+		None => None,
+	});
+
+	let name = match name {
+		Some(name) => name.to_string(),
+		None => return Ok(args),
+	};
+
+	let path = Config::default_path()
+		.ok_or_else(|| format_err!("cannot find profile {:?}, there is no home directory for the config file", name))?;
+
+	let subcommands = [CLI_SUB_GENKEY, CLI_SUB_SEND, CLI_SUB_RECV, CLI_SUB_RELAY, CLI_SUB_PING];
+	Config::open(path)?.apply(&name, args, &subcommands)
+}
+
+/// Maps an error to one of the documented exit statuses. (See: `CLI_TXT_EXIT`.)
+fn exit_code(err: &failure::Error) -> i32 {
+	use std::io::ErrorKind;
+
+	match err.downcast_ref::<ProtoError>() {
+		#[cfg(feature = "udt")]
+		Some(ProtoError::ConnectErr { .. }) => EXIT_CONNECT_FAILED,
+		Some(ProtoError::ConnectTimeout)
+			| Some(ProtoError::NoSocketAddr)
+			| Some(ProtoError::NoAddrInFamily { .. })
+			| Some(ProtoError::Ipv6Unsupported { .. }) => EXIT_CONNECT_FAILED,
+
+		Some(ProtoError::CryptoErr)
+			| Some(ProtoError::HandshakeRejected)
+			| Some(ProtoError::InvalidKeyLength { .. })
+			| Some(ProtoError::KeyLengthMismatch { .. })
+			| Some(ProtoError::CipherMismatch { .. })
+			| Some(ProtoError::NoIdentity { .. })
+			| Some(ProtoError::IdentityMismatch { .. })
+			| Some(ProtoError::UnknownHost { .. })
+			| Some(ProtoError::HostIdentityChanged { .. })
+			| Some(ProtoError::NoReceipt)
+			| Some(ProtoError::BlockLost { .. }) => EXIT_CRYPTO_FAILED,
+
+		#[cfg(feature = "udt")]
+		Some(ProtoError::SocketErr { .. }) => EXIT_PEER_HANGUP,
+		Some(ProtoError::PeerAborted) => EXIT_PEER_HANGUP,
+		Some(ProtoError::IoErr { inner }) => match inner.kind() {
+			ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset => EXIT_PEER_HANGUP,
+			_ => EXIT_FAILURE,
+		},
+
+		Some(ProtoError::UnexpectedMessage)
+			| Some(ProtoError::UnknownMessage { .. })
+			| Some(ProtoError::NotAPeer)
+			| Some(ProtoError::UnsupportedVersion { .. })
+			| Some(ProtoError::UnsupportedFeature { .. })
+			| Some(ProtoError::UnknownCipher { .. })
+			| Some(ProtoError::InvalidToken)
+			| Some(ProtoError::MalformedMessage)
+			| Some(ProtoError::OversizedBlock { .. })
+			| Some(ProtoError::ReplayOrReorder { .. })
+			| Some(ProtoError::UnsafeFileName { .. })
+			| Some(ProtoError::FileExists { .. })
+			| Some(ProtoError::FileLengthMismatch { .. })
+			| Some(ProtoError::TotalsMismatch { .. })
+			| Some(ProtoError::SizeMismatch { .. })
+			| Some(ProtoError::DigestMismatch)
+			| Some(ProtoError::ReceiptMismatch)
+			| Some(ProtoError::SerializeErr { .. }) => EXIT_PROTOCOL_ERROR,
+
+		Some(ProtoError::Interrupted) | Some(ProtoError::Cancelled) => EXIT_INTERRUPTED,
+
+		// the peer's failure is reported as though it were our own
+		Some(ProtoError::PeerFailed { code, .. }) => match code {
+			FaultCode::Crypto => EXIT_CRYPTO_FAILED,
+			FaultCode::Protocol => EXIT_PROTOCOL_ERROR,
+			FaultCode::Io | FaultCode::Other => EXIT_FAILURE,
+		},
 
-fn main() -> Result<(), failure::Error> {
-	env_logger::init();
+		Some(ProtoError::NoOutputDir)
+			| Some(ProtoError::NoSpace { .. })
+			| Some(ProtoError::InvalidCheckpoint)
+			| Some(ProtoError::InvalidIdentity)
+			| Some(ProtoError::InvalidArgument { .. })
+			| None => EXIT_FAILURE,
+	}
+}
 
+fn run() -> Result<(), failure::Error> {
 	let matches = App::new(CLI_TITLE)
 		.version(env!("CARGO_PKG_VERSION")) 
 		.about(CLI_TXT_APP)
+		.after_help(CLI_TXT_EXIT)
+		.arg(Arg::with_name(CLI_ARG_LOG)
+			 .long(CLI_ARG_LOG_LONG)
+			 .help(CLI_TXT_LOG)
+			 .takes_value(true)
+			 .possible_values(&["stderr", "syslog", "journald"])
+			 .global(true))
+		.arg(Arg::with_name(CLI_ARG_VERBOSE)
+			 .short(CLI_ARG_VERBOSE_SHORT)
+			 .long(CLI_ARG_VERBOSE_LONG)
+			 .help(CLI_TXT_VERBOSE)
+			 .multiple(true)
+			 .global(true))
+		.arg(Arg::with_name(CLI_ARG_QUIET)
+			 .short(CLI_ARG_QUIET_SHORT)
+			 .long(CLI_ARG_QUIET_LONG)
+			 .help(CLI_TXT_QUIET)
+			 .conflicts_with(CLI_ARG_VERBOSE)
+			 .global(true))
+		.arg(Arg::with_name(CLI_ARG_PROFILE)
+			 .long(CLI_ARG_PROFILE_LONG)
+			 .help(CLI_TXT_PROFILE)
+			 .takes_value(true)
+			 .global(true))
 		.subcommand(SubCommand::with_name(CLI_SUB_GENKEY)
-					.about(CLI_TXT_GENKEY))
+					.about(CLI_TXT_GENKEY)
+					.arg(Arg::with_name(CLI_ARG_OUT)
+						 .short(CLI_ARG_OUT_SHORT)
+						 .long(CLI_ARG_OUT_LONG)
+						 .help(CLI_TXT_OUT)
+						 .takes_value(true))
+					.arg(Arg::with_name(CLI_ARG_LABEL)
+						 .long(CLI_ARG_LABEL_LONG)
+						 .help(CLI_TXT_LABEL)
+						 .takes_value(true))
+					.arg(Arg::with_name(CLI_ARG_FINGERPRINT)
+						 .long(CLI_ARG_FINGERPRINT_LONG)
+						 .help(CLI_TXT_FINGERPRINT))
+					.arg(Arg::with_name(CLI_ARG_BITS)
+						 .long(CLI_ARG_BITS_LONG)
+						 .help(CLI_TXT_BITS)
+						 .takes_value(true)
+						 .possible_values(&["128", "256"])
+						 .default_value("256"))
+					.arg(Arg::with_name(CLI_ARG_IDENTITY)
+						 .long(CLI_ARG_IDENTITY_LONG)
+						 .help(CLI_TXT_GEN_IDENTITY))
+					.arg(Arg::with_name(CLI_ARG_CODE)
+						 .long(CLI_ARG_CODE_LONG)
+						 .help(CLI_TXT_GEN_CODE)
+						 .conflicts_with_all(&[CLI_ARG_IDENTITY, CLI_ARG_OUT, CLI_ARG_LABEL, CLI_ARG_FINGERPRINT]))
+					.arg(seed_arg()
+						 .conflicts_with_all(&[CLI_ARG_IDENTITY, CLI_ARG_CODE])))
 		.subcommand(SubCommand::with_name(CLI_SUB_SEND)
 					.about(CLI_TXT_SEND)
 					.arg(Arg::with_name(CLI_ARG_INET_ADDR)
 						 .help(CLI_TXT_INET)
 						 .required(true))
-					.arg(Arg::with_name(CLI_ARG_KEY)
-						 .short(CLI_ARG_KEY_SHORT)
-						 .long(CLI_ARG_KEY_LONG)
-						 .help(CLI_TXT_KEY)
+					.arg(Arg::with_name(CLI_ARG_INPUT)
+						 .help(CLI_TXT_INPUT)
+						 .multiple(true))
+					.arg(Arg::with_name(CLI_ARG_CONCAT)
+						 .long(CLI_ARG_CONCAT_LONG)
+						 .help(CLI_TXT_CONCAT))
+					.arg(Arg::with_name(CLI_ARG_URL)
+						 .long(CLI_ARG_URL_LONG)
+						 .help(CLI_TXT_URL)
+						 .takes_value(true)
+						 .conflicts_with_all(&[CLI_ARG_FILE, CLI_ARG_INPUT, CLI_ARG_CONCAT]))
+					.args(&session_args())
+					.arg(Arg::with_name(CLI_ARG_LISTEN)
+						 .long(CLI_ARG_LISTEN_LONG)
+						 .help(CLI_TXT_LISTEN))
+					.arg(Arg::with_name(CLI_ARG_BIND)
+						 .long(CLI_ARG_BIND_LONG)
+						 .help(CLI_TXT_BIND)
+						 .takes_value(true)
+						 .conflicts_with(CLI_ARG_LISTEN))
+					.arg(Arg::with_name(CLI_ARG_MULTIPATH)
+						 .long(CLI_ARG_MULTIPATH_LONG)
+						 .help(CLI_TXT_MULTIPATH)
+						 .takes_value(true)
+						 .multiple(true)
+						 .number_of_values(1)
+						 .conflicts_with_all(&[CLI_ARG_LISTEN, CLI_ARG_BIND]))
+					.args(&identity_args())
+					.arg(Arg::with_name(CLI_ARG_REKEY)
+						 .long(CLI_ARG_REKEY_LONG)
+						 .help(CLI_TXT_REKEY)
+						 .takes_value(true))
+					.arg(Arg::with_name(CLI_ARG_MAX_IN_FLIGHT)
+						 .long(CLI_ARG_MAX_IN_FLIGHT_LONG)
+						 .help(CLI_TXT_MAX_IN_FLIGHT)
+						 .takes_value(true))
+					.arg(Arg::with_name(CLI_ARG_KEEPALIVE)
+						 .long(CLI_ARG_KEEPALIVE_LONG)
+						 .help(CLI_TXT_KEEPALIVE)
+						 .takes_value(true)
+						 .default_value("15"))
+					.arg(Arg::with_name(CLI_ARG_RETRY)
+						 .long(CLI_ARG_RETRY_LONG)
+						 .help(CLI_TXT_RETRY)
+						 .takes_value(true)
+						 .default_value("0"))
+					.arg(Arg::with_name(CLI_ARG_RETRY_DELAY)
+						 .long(CLI_ARG_RETRY_DELAY_LONG)
+						 .help(CLI_TXT_RETRY_DELAY)
+						 .takes_value(true)
+						 .default_value("1s"))
+					.arg(Arg::with_name(CLI_ARG_FILE)
+						 .short(CLI_ARG_FILE_SHORT)
+						 .long(CLI_ARG_FILE_LONG)
+						 .help(CLI_TXT_FILE)
+						 .takes_value(true)
+						 .multiple(true)
+						 .number_of_values(1))
+					.arg(Arg::with_name(CLI_ARG_TAR)
+						 .long(CLI_ARG_TAR_LONG)
+						 .help(CLI_TXT_TAR)
+						 .takes_value(true)
+						 .conflicts_with_all(&[CLI_ARG_FILE, CLI_ARG_INPUT, CLI_ARG_CONCAT, CLI_ARG_URL]))
+					.arg(Arg::with_name(CLI_ARG_INCLUDE)
+						 .long(CLI_ARG_INCLUDE_LONG)
+						 .help(CLI_TXT_INCLUDE)
+						 .takes_value(true)
+						 .multiple(true)
+						 .number_of_values(1)
+						 .requires(CLI_ARG_TAR))
+					.arg(Arg::with_name(CLI_ARG_EXCLUDE)
+						 .long(CLI_ARG_EXCLUDE_LONG)
+						 .help(CLI_TXT_EXCLUDE)
+						 .takes_value(true)
+						 .multiple(true)
+						 .number_of_values(1)
+						 .requires(CLI_ARG_TAR))
+					.arg(Arg::with_name(CLI_ARG_MIRROR)
+						 .long(CLI_ARG_MIRROR_LONG)
+						 .help(CLI_TXT_MIRROR)
+						 .takes_value(true)
+						 .multiple(true)
+						 .number_of_values(1)
+						 .conflicts_with_all(&[CLI_ARG_LISTEN, CLI_ARG_CODE, CLI_ARG_VERIFY, CLI_ARG_TUI, CLI_ARG_ON_COMPLETE, CLI_ARG_ON_ERROR]))
+					.arg(Arg::with_name(CLI_ARG_MMAP)
+						 .long(CLI_ARG_MMAP_LONG)
+						 .help(CLI_TXT_MMAP))
+					.arg(Arg::with_name(CLI_ARG_MESSAGE_TTL)
+						 .long(CLI_ARG_MESSAGE_TTL_LONG)
+						 .help(CLI_TXT_MESSAGE_TTL)
+						 .takes_value(true))
+					.arg(Arg::with_name(CLI_ARG_UNORDERED)
+						 .long(CLI_ARG_UNORDERED_LONG)
+						 .help(CLI_TXT_UNORDERED))
+					.arg(Arg::with_name(CLI_ARG_SPARSE)
+						 .long(CLI_ARG_SPARSE_LONG)
+						 .help(CLI_TXT_SPARSE)
+						 .conflicts_with_all(&[CLI_ARG_TAR, CLI_ARG_CONCAT, CLI_ARG_MIRROR, CLI_ARG_URL, CLI_ARG_FILTER_CMD]))
+					.arg(Arg::with_name(CLI_ARG_PAD)
+						 .long(CLI_ARG_PAD_LONG)
+						 .help(CLI_TXT_PAD)
+						 .conflicts_with(CLI_ARG_SPARSE))
+					.arg(Arg::with_name(CLI_ARG_LOW_LATENCY)
+						 .long(CLI_ARG_LOW_LATENCY_LONG)
+						 .help(CLI_TXT_LOW_LATENCY))
+					.arg(Arg::with_name(CLI_ARG_MIN_BLOCK)
+						 .long(CLI_ARG_MIN_BLOCK_LONG)
+						 .help(CLI_TXT_MIN_BLOCK)
+						 .takes_value(true)
+						 .conflicts_with(CLI_ARG_LOW_LATENCY))
+					.arg(Arg::with_name(CLI_ARG_MAX_WAIT)
+						 .long(CLI_ARG_MAX_WAIT_LONG)
+						 .help(CLI_TXT_MAX_WAIT)
+						 .takes_value(true)
+						 .default_value("50"))
+					.arg(Arg::with_name(CLI_ARG_LINE_BUFFERED)
+						 .long(CLI_ARG_LINE_BUFFERED_LONG)
+						 .help(CLI_TXT_LINE_BUFFERED)
+						 .conflicts_with_all(&[CLI_ARG_SPARSE, CLI_ARG_MIN_BLOCK]))
+					.arg(Arg::with_name(CLI_ARG_RATE_LIMIT)
+						 .long(CLI_ARG_RATE_LIMIT_LONG)
+						 .help(CLI_TXT_RATE_LIMIT)
+						 .takes_value(true))
+					.arg(Arg::with_name(CLI_ARG_FILTER_CMD)
+						 .long(CLI_ARG_FILTER_CMD_LONG)
+						 .help(CLI_TXT_FILTER_CMD)
 						 .takes_value(true)
-						 .required(true)))
+						 .empty_values(false)
+						 .conflicts_with(CLI_ARG_EXPECT_SIZE))
+					.arg(Arg::with_name(CLI_ARG_RECEIPT)
+						 .long(CLI_ARG_RECEIPT_LONG)
+						 .help(CLI_TXT_RECEIPT)
+						 .takes_value(true)
+						 .conflicts_with(CLI_ARG_MIRROR))
+					.arg(Arg::with_name(CLI_ARG_EXPECT_SIZE)
+						 .long(CLI_ARG_EXPECT_SIZE_LONG)
+						 .help(CLI_TXT_EXPECT_SIZE)
+						 .takes_value(true)))
 		.subcommand(SubCommand::with_name(CLI_SUB_RECV)
 					.about(CLI_TXT_RECV)
 					.arg(Arg::with_name(CLI_ARG_INET_ADDR)
 						 .help(CLI_TXT_INET)
-						 .required(true))
-					.arg(Arg::with_name(CLI_ARG_KEY)
-						 .short(CLI_ARG_KEY_SHORT)
-						 .long(CLI_ARG_KEY_LONG)
-						 .help(CLI_TXT_KEY)
+						 .required_unless(CLI_ARG_LISTEN_ADDR))
+					.args(&session_args())
+					.arg(Arg::with_name(CLI_ARG_CONNECT)
+						 .long(CLI_ARG_CONNECT_LONG)
+						 .help(CLI_TXT_CONNECT))
+					.arg(Arg::with_name(CLI_ARG_LISTEN_ADDR)
+						 .long(CLI_ARG_LISTEN_LONG)
+						 .help(CLI_TXT_LISTEN_ADDR)
+						 .takes_value(true)
+						 .multiple(true)
+						 .number_of_values(1)
+						 .conflicts_with_all(&[CLI_ARG_CONNECT, CLI_ARG_HUB]))
+					.arg(Arg::with_name(CLI_ARG_ALLOW)
+						 .long(CLI_ARG_ALLOW_LONG)
+						 .help(CLI_TXT_ALLOW)
+						 .takes_value(true)
+						 .multiple(true)
+						 .number_of_values(1)
+						 .conflicts_with(CLI_ARG_CONNECT))
+					.arg(Arg::with_name(CLI_ARG_MIGRATE)
+						 .long(CLI_ARG_MIGRATE_LONG)
+						 .help(CLI_TXT_MIGRATE)
+						 .conflicts_with_all(&[CLI_ARG_CONNECT, CLI_ARG_HUB]))
+					.arg(Arg::with_name(CLI_ARG_IDENTITY)
+						 .long(CLI_ARG_IDENTITY_LONG)
+						 .help(CLI_TXT_IDENTITY)
+						 .takes_value(true))
+					.arg(Arg::with_name(CLI_ARG_EXPECT_FINGERPRINT)
+						 .long(CLI_ARG_EXPECT_FINGERPRINT_LONG)
+						 .help(CLI_TXT_EXPECT_SENDER)
+						 .takes_value(true)
+						 .multiple(true)
+						 .number_of_values(1))
+					.arg(Arg::with_name(CLI_ARG_OUTPUT)
+						 .short(CLI_ARG_OUT_SHORT)
+						 .long(CLI_ARG_OUT_LONG)
+						 .help(CLI_TXT_OUTPUT)
+						 .takes_value(true))
+					.arg(Arg::with_name(CLI_ARG_APPEND)
+						 .long(CLI_ARG_APPEND_LONG)
+						 .help(CLI_TXT_APPEND)
+						 .requires(CLI_ARG_OUTPUT))
+					.arg(Arg::with_name(CLI_ARG_TEE)
+						 .long(CLI_ARG_TEE_LONG)
+						 .help(CLI_TXT_TEE)
+						 .takes_value(true)
+						 .multiple(true)
+						 .number_of_values(1)
+						 .conflicts_with_all(&[CLI_ARG_DIR, CLI_ARG_HUB]))
+					.arg(Arg::with_name(CLI_ARG_DIRECT_IO)
+						 .long(CLI_ARG_DIRECT_IO_LONG)
+						 .help(CLI_TXT_DIRECT_IO)
+						 .requires(CLI_ARG_OUTPUT)
+						 .conflicts_with(CLI_ARG_APPEND))
+					.arg(Arg::with_name(CLI_ARG_FSYNC_INTERVAL)
+						 .long(CLI_ARG_FSYNC_INTERVAL_LONG)
+						 .help(CLI_TXT_FSYNC_INTERVAL)
+						 .takes_value(true))
+					.arg(Arg::with_name(CLI_ARG_SYNC_ON_CLOSE)
+						 .long(CLI_ARG_SYNC_ON_CLOSE_LONG)
+						 .help(CLI_TXT_SYNC_ON_CLOSE))
+					.arg(Arg::with_name(CLI_ARG_STALL_WARNING)
+						 .long(CLI_ARG_STALL_WARNING_LONG)
+						 .help(CLI_TXT_STALL_WARNING)
+						 .takes_value(true)
+						 .default_value("60s"))
+					.arg(Arg::with_name(CLI_ARG_FORCE)
+						 .long(CLI_ARG_FORCE_LONG)
+						 .help(CLI_TXT_FORCE)
+						 .requires(CLI_ARG_OUTPUT)
+						 .overrides_with_all(&[CLI_ARG_NO_CLOBBER, CLI_ARG_BACKUP_EXISTING]))
+					.arg(Arg::with_name(CLI_ARG_NO_CLOBBER)
+						 .long(CLI_ARG_NO_CLOBBER_LONG)
+						 .help(CLI_TXT_NO_CLOBBER)
+						 .requires(CLI_ARG_OUTPUT)
+						 .conflicts_with(CLI_ARG_APPEND)
+						 .overrides_with_all(&[CLI_ARG_FORCE, CLI_ARG_BACKUP_EXISTING]))
+					.arg(Arg::with_name(CLI_ARG_BACKUP_EXISTING)
+						 .long(CLI_ARG_BACKUP_EXISTING_LONG)
+						 .help(CLI_TXT_BACKUP_EXISTING)
+						 .requires(CLI_ARG_OUTPUT)
+						 .conflicts_with(CLI_ARG_APPEND)
+						 .overrides_with_all(&[CLI_ARG_FORCE, CLI_ARG_NO_CLOBBER]))
+					.arg(Arg::with_name(CLI_ARG_CHECKPOINT)
+						 .long(CLI_ARG_CHECKPOINT_LONG)
+						 .help(CLI_TXT_CHECKPOINT)
+						 .takes_value(true)
+						 .requires(CLI_ARG_OUTPUT)
+						 .conflicts_with_all(&[CLI_ARG_APPEND, CLI_ARG_DIRECT_IO, CLI_ARG_TEE, CLI_ARG_CHECKSUM]))
+					.arg(Arg::with_name(CLI_ARG_CHECKSUM)
+						 .long(CLI_ARG_CHECKSUM_LONG)
+						 .help(CLI_TXT_CHECKSUM)
+						 .requires(CLI_ARG_OUTPUT)
+						 .conflicts_with(CLI_ARG_APPEND))
+					.arg(Arg::with_name(CLI_ARG_DIR)
+						 .long(CLI_ARG_DIR_LONG)
+						 .help(CLI_TXT_DIR)
+						 .takes_value(true))
+					.arg(Arg::with_name(CLI_ARG_HUB)
+						 .long(CLI_ARG_HUB_LONG)
+						 .help(CLI_TXT_HUB)
 						 .takes_value(true)
-						 .required(true)))
-		.get_matches();
+						 .conflicts_with_all(&[CLI_ARG_CODE, CLI_ARG_VERIFY, CLI_GRP_OUTPUT, CLI_ARG_UNTAR, CLI_ARG_PRESERVE, CLI_ARG_PRESERVE_PERMS, CLI_ARG_PRESERVE_OWNER, CLI_ARG_CONNECT, CLI_ARG_SIMULATE, CLI_ARG_PROGRESS_FD, CLI_ARG_TUI, CLI_ARG_ON_COMPLETE, CLI_ARG_ON_ERROR, CLI_ARG_FILTER_CMD, CLI_ARG_UDT_MODE, CLI_ARG_MAX_MEMORY]))
+					.arg(Arg::with_name(CLI_ARG_METRICS)
+						 .long(CLI_ARG_METRICS_LONG)
+						 .help(CLI_TXT_METRICS)
+						 .takes_value(true)
+						 .requires(CLI_ARG_HUB))
+					.arg(Arg::with_name(CLI_ARG_UNTAR)
+						 .long(CLI_ARG_UNTAR_LONG)
+						 .help(CLI_TXT_UNTAR)
+						 .takes_value(true)
+						 .conflicts_with(CLI_GRP_OUTPUT))
+					.arg(Arg::with_name(CLI_ARG_FILTER_CMD)
+						 .long(CLI_ARG_FILTER_CMD_LONG)
+						 .help(CLI_TXT_FILTER_CMD_RECV)
+						 .takes_value(true)
+						 .empty_values(false)
+						 .conflicts_with_all(&[CLI_ARG_DIR, CLI_ARG_APPEND, CLI_ARG_DIRECT_IO, CLI_ARG_CHECKPOINT, CLI_ARG_CHECKSUM]))
+					.group(ArgGroup::with_name(CLI_GRP_OUTPUT)
+						   .args(&[CLI_ARG_OUTPUT, CLI_ARG_DIR]))
+					.arg(Arg::with_name(CLI_ARG_PRESERVE)
+						 .long(CLI_ARG_PRESERVE_LONG)
+						 .help(CLI_TXT_PRESERVE)
+						 .requires(CLI_GRP_OUTPUT))
+					.arg(Arg::with_name(CLI_ARG_PRESERVE_PERMS)
+						 .long(CLI_ARG_PRESERVE_PERMS_LONG)
+						 .help(CLI_TXT_PRESERVE_PERMS)
+						 .requires(CLI_GRP_OUTPUT))
+					.arg(Arg::with_name(CLI_ARG_PRESERVE_OWNER)
+						 .long(CLI_ARG_PRESERVE_OWNER_LONG)
+						 .help(CLI_TXT_PRESERVE_OWNER)
+						 .requires(CLI_GRP_OUTPUT)))
+		.subcommand(SubCommand::with_name(CLI_SUB_RELAY)
+					.about(CLI_TXT_RELAY)
+					.arg(Arg::with_name(CLI_ARG_INET_ADDR)
+						 .help(CLI_TXT_RELAY_LISTEN)
+						 .required(true))
+					.arg(Arg::with_name(CLI_ARG_TARGET_ADDR)
+						 .help(CLI_TXT_RELAY_TARGET)
+						 .required(true))
+					.arg(Arg::with_name(CLI_ARG_BIND)
+						 .long(CLI_ARG_BIND_LONG)
+						 .help(CLI_TXT_BIND)
+						 .takes_value(true)))
+		.subcommand(SubCommand::with_name(CLI_SUB_PING)
+					.about(CLI_TXT_PING)
+					.arg(Arg::with_name(CLI_ARG_INET_ADDR)
+						 .help(CLI_TXT_INET)
+						 .required(true))
+					.args(&key_args())
+					.arg(transport_arg())
+					.args(&family_args())
+					.arg(Arg::with_name(CLI_ARG_BIND)
+						 .long(CLI_ARG_BIND_LONG)
+						 .help(CLI_TXT_BIND)
+						 .takes_value(true))
+					.args(&identity_args()))
+		.subcommand(SubCommand::with_name(CLI_SUB_DEBUG)
+					.about(CLI_TXT_DEBUG)
+					.subcommand(SubCommand::with_name(CLI_SUB_DECODE)
+								.about(CLI_TXT_DECODE)
+								.arg(Arg::with_name(CLI_ARG_CAPTURE)
+									 .help(CLI_TXT_CAPTURE)
+									 .required(true)
+									 .multiple(true))
+								.arg(Arg::with_name(CLI_ARG_KEY)
+									 .short(CLI_ARG_KEY_SHORT)
+									 .long(CLI_ARG_KEY_LONG)
+									 .help(CLI_TXT_DECODE_KEY)
+									 .takes_value(true))
+								.arg(Arg::with_name(CLI_ARG_KEY_FILE)
+									 .long(CLI_ARG_KEY_FILE_LONG)
+									 .help(CLI_TXT_DECODE_KEY)
+									 .takes_value(true)
+									 .conflicts_with(CLI_ARG_KEY))
+								.arg(Arg::with_name(CLI_ARG_IV)
+									 .long(CLI_ARG_IV_LONG)
+									 .help(CLI_TXT_IV)
+									 .takes_value(true))
+								.arg(Arg::with_name(CLI_ARG_CIPHER)
+									 .long(CLI_ARG_CIPHER_LONG)
+									 .help(CLI_TXT_DECODE_CIPHER)
+									 .possible_values(&[CLI_CIPHER_AES_256_GCM, CLI_CIPHER_AES_128_GCM, CLI_CIPHER_CHACHA20_POLY1305])
+									 .takes_value(true))
+								.arg(Arg::with_name(CLI_ARG_PREVIEW)
+									 .long(CLI_ARG_PREVIEW_LONG)
+									 .help(CLI_TXT_PREVIEW)
+									 .default_value("32"))))
+		.get_matches_from(with_profile(env::args_os().collect())?);
 
-	if let Some(cmd) = matches.subcommand_matches("sender") {
-		let key = cmd.value_of(CLI_ARG_KEY)
-			.expect("fatal: sender requires an encryption key.");
+	// `--log` may be given before or after the subcommand
+	let log = matches.subcommand().1
+		.and_then(|cmd| cmd.value_of(CLI_ARG_LOG))
+		.or_else(|| matches.value_of(CLI_ARG_LOG))
+		.and_then(logging::Target::parse)
+		.unwrap_or(logging::Target::Stderr);
+	// global flags are seen by the top level wherever they were given
+	let verbosity = if matches.is_present(CLI_ARG_QUIET) {
+		logging::Verbosity::Quiet
+	} else {
+		logging::Verbosity::from_count(matches.occurrences_of(CLI_ARG_VERBOSE))
+	};
 
-		let addr = cmd.value_of(CLI_ARG_INET_ADDR)
-			.expect("fatal: sender requires a remote address.");
+	logging::init(log, verbosity)?;
 
-		start_sender(addr, key)?;
+	if let Some(cmd) = matches.subcommand_matches("sender") {
+		start_sender(cmd)?;
 	} else if let Some(cmd) = matches.subcommand_matches("receiver") {
-		let key = cmd.value_of(CLI_ARG_KEY)
-			.expect("fatal: receiver requires an encryption key.");
-
-		let addr = cmd.value_of(CLI_ARG_INET_ADDR)
-			.expect("fatal: receiver requires a remote address.");
-
-		start_receiver(addr, key)?;
-	} else if let Some(_cmd) = matches.subcommand_matches("genkey") {
-		genkey();
+		start_receiver(cmd)?;
+	} else if let Some(cmd) = matches.subcommand_matches("relay") {
+		start_relay(cmd)?;
+	} else if let Some(cmd) = matches.subcommand_matches("ping") {
+		start_ping(cmd)?;
+	} else if let Some(cmd) = matches.subcommand_matches("genkey") {
+		genkey(cmd)?;
+	} else if let Some(cmd) = matches.subcommand_matches("debug") {
+		match cmd.subcommand_matches("decode") {
+			Some(cmd) => decode_capture(cmd)?,
+			None => println!("Please enter a debug subcommand. See `ubuffer debug --help` for more details."),
+		}
 	} else {
 		println!("Please enter a subcommand. See `ubuffer --help` for more details.");
 	}
@@ -94,34 +964,1774 @@ fn main() -> Result<(), failure::Error> {
 	Ok(())
 }
 
-fn start_sender(addr: &str, key: &str) -> Result<(), failure::Error> {
-	let key = base64::decode(key)?;
-	let mut sender = Sender::new(addr, &key)?;
-	let stdin = io::stdin();
-	sender.run(stdin.lock())?;
+/// Arguments which select the key, shared by every subcommand which needs one.
+fn key_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+	vec![
+		Arg::with_name(CLI_ARG_KEY)
+			.short(CLI_ARG_KEY_SHORT)
+			.long(CLI_ARG_KEY_LONG)
+			.help(CLI_TXT_KEY)
+			.takes_value(true),
 
-	Ok(())
+		Arg::with_name(CLI_ARG_KEY_FILE)
+			.long(CLI_ARG_KEY_FILE_LONG)
+			.help(CLI_TXT_KEY_FILE)
+			.takes_value(true)
+			.multiple(true)
+			.number_of_values(1)
+			.conflicts_with(CLI_ARG_KEY),
+	]
 }
 
-fn start_receiver(addr: &str, key: &str) -> Result<(), failure::Error> {
-	let key = base64::decode(key)?;
-	let mut receiver = Receiver::new(addr, &key)?;
-	let stdout = io::stdout();
-	receiver.run(stdout.lock())?;
+/// Arguments which are shared by both the `sender` and `receiver`.
+fn session_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+	let mut args = key_args();
+	args.extend(vec![
+		Arg::with_name(CLI_ARG_CODE)
+			.long(CLI_ARG_CODE_LONG)
+			.help(CLI_TXT_CODE)
+			.takes_value(true)
+			.conflicts_with_all(&[CLI_ARG_KEY, CLI_ARG_KEY_FILE]),
+
+		Arg::with_name(CLI_ARG_SUMMARY)
+			.long(CLI_ARG_SUMMARY_LONG)
+			.help(CLI_TXT_SUMMARY)
+			.possible_values(&[CLI_SUMMARY_TEXT, CLI_SUMMARY_JSON])
+			.default_value(CLI_SUMMARY_TEXT),
+
+		Arg::with_name(CLI_ARG_JSON)
+			.long(CLI_ARG_JSON_LONG)
+			.help(CLI_TXT_JSON),
+
+		Arg::with_name(CLI_ARG_SIMULATE)
+			.long(CLI_ARG_SIMULATE_LONG)
+			.help(CLI_TXT_SIMULATE)
+			.takes_value(true),
+
+		Arg::with_name(CLI_ARG_STATS)
+			.long(CLI_ARG_STATS_LONG)
+			.help(CLI_TXT_STATS)
+			.takes_value(true),
+
+		Arg::with_name(CLI_ARG_PROGRESS_FD)
+			.long(CLI_ARG_PROGRESS_FD_LONG)
+			.help(CLI_TXT_PROGRESS_FD)
+			.takes_value(true),
+
+		Arg::with_name(CLI_ARG_TUI)
+			.long(CLI_ARG_TUI_LONG)
+			.help(CLI_TXT_TUI)
+			.conflicts_with_all(&[CLI_ARG_JSON, CLI_ARG_VERIFY]),
+
+		Arg::with_name(CLI_ARG_ON_COMPLETE)
+			.long(CLI_ARG_ON_COMPLETE_LONG)
+			.help(CLI_TXT_ON_COMPLETE)
+			.takes_value(true),
+
+		Arg::with_name(CLI_ARG_ON_ERROR)
+			.long(CLI_ARG_ON_ERROR_LONG)
+			.help(CLI_TXT_ON_ERROR)
+			.takes_value(true),
+
+		transport_arg(),
+
+		Arg::with_name(CLI_ARG_CONGESTION)
+			.long(CLI_ARG_CONGESTION_LONG)
+			.help(CLI_TXT_CONGESTION)
+			.possible_values(&[CLI_CONGESTION_ADAPTIVE, CLI_CONGESTION_FIXED])
+			.default_value(CLI_CONGESTION_ADAPTIVE),
+
+		Arg::with_name(CLI_ARG_UDT_MODE)
+			.long(CLI_ARG_UDT_MODE_LONG)
+			.help(CLI_TXT_UDT_MODE)
+			.possible_values(&[CLI_UDT_MODE_STREAM, CLI_UDT_MODE_MESSAGE])
+			.default_value(CLI_UDT_MODE_STREAM),
+
+		Arg::with_name(CLI_ARG_CRYPTO_THREADS)
+			.long(CLI_ARG_CRYPTO_THREADS_LONG)
+			.help(CLI_TXT_CRYPTO_THREADS)
+			.default_value("1"),
+
+		Arg::with_name(CLI_ARG_MAX_MEMORY)
+			.long(CLI_ARG_MAX_MEMORY_LONG)
+			.help(CLI_TXT_MAX_MEMORY)
+			.takes_value(true),
+
+		Arg::with_name(CLI_ARG_CIPHER)
+			.long(CLI_ARG_CIPHER_LONG)
+			.help(CLI_TXT_CIPHER)
+			.possible_values(&[CLI_CIPHER_AUTO, CLI_CIPHER_AES_256_GCM, CLI_CIPHER_AES_128_GCM, CLI_CIPHER_CHACHA20_POLY1305])
+			.default_value(CLI_CIPHER_AUTO),
+
+		Arg::with_name(CLI_ARG_RESUME_TIMEOUT)
+			.long(CLI_ARG_RESUME_TIMEOUT_LONG)
+			.help(CLI_TXT_RESUME_TIMEOUT)
+			.takes_value(true),
+
+		Arg::with_name(CLI_ARG_VERIFY)
+			.long(CLI_ARG_VERIFY_LONG)
+			.help(CLI_TXT_VERIFY),
+
+		seed_arg(),
+	]);
+
+	args.extend(family_args());
+	args
+}
+
+/// The hidden `--seed`, which makes a session (or key) reproducible in tests.
+fn seed_arg<'a, 'b>() -> Arg<'a, 'b> {
+	Arg::with_name(CLI_ARG_SEED)
+		.long(CLI_ARG_SEED_LONG)
+		.help(CLI_TXT_SEED)
+		.takes_value(true)
+		.hidden(true)
+}
+
+/// The options which decide whether a sender trusts the receiver it reached,
+/// and how it proves its own identity.
+fn identity_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+	vec![
+		Arg::with_name(CLI_ARG_IDENTITY)
+			.long(CLI_ARG_IDENTITY_LONG)
+			.help(CLI_TXT_SENDER_IDENTITY)
+			.takes_value(true),
+
+		Arg::with_name(CLI_ARG_EXPECT_FINGERPRINT)
+			.long(CLI_ARG_EXPECT_FINGERPRINT_LONG)
+			.help(CLI_TXT_EXPECT_FINGERPRINT)
+			.takes_value(true),
+
+		Arg::with_name(CLI_ARG_KNOWN_HOSTS)
+			.long(CLI_ARG_KNOWN_HOSTS_LONG)
+			.help(CLI_TXT_KNOWN_HOSTS)
+			.takes_value(true)
+			.conflicts_with(CLI_ARG_EXPECT_FINGERPRINT),
+
+		Arg::with_name(CLI_ARG_ACCEPT_NEW)
+			.long(CLI_ARG_ACCEPT_NEW_LONG)
+			.help(CLI_TXT_ACCEPT_NEW)
+			.conflicts_with(CLI_ARG_EXPECT_FINGERPRINT),
+	]
+}
+
+fn transport_arg<'a, 'b>() -> Arg<'a, 'b> {
+	Arg::with_name(CLI_ARG_TRANSPORT)
+		.long(CLI_ARG_TRANSPORT_LONG)
+		.help(CLI_TXT_TRANSPORT)
+		.possible_values(&[CLI_TRANSPORT_UDT, CLI_TRANSPORT_UDP])
+		.default_value(CLI_TRANSPORT_DEFAULT)
+}
+
+/// The `-4` & `-6` flags, which pick the addresses a name is reached at.
+fn family_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+	vec![
+		Arg::with_name(CLI_ARG_IPV4)
+			.short(CLI_ARG_IPV4_SHORT)
+			.long(CLI_ARG_IPV4_LONG)
+			.help(CLI_TXT_IPV4)
+			.conflicts_with(CLI_ARG_IPV6),
+
+		Arg::with_name(CLI_ARG_IPV6)
+			.short(CLI_ARG_IPV6_SHORT)
+			.long(CLI_ARG_IPV6_LONG)
+			.help(CLI_TXT_IPV6),
+	]
+}
+
+/// Reads the `-4` or `-6`, if either was given.
+fn read_family(cmd: &ArgMatches) -> AddrFamily {
+	if cmd.is_present(CLI_ARG_IPV4) {
+		AddrFamily::V4
+	} else if cmd.is_present(CLI_ARG_IPV6) {
+		AddrFamily::V6
+	} else {
+		AddrFamily::Any
+	}
+}
+
+/// Reads the `--transport`, which clap has already validated.
+fn read_transport(cmd: &ArgMatches) -> TransportKind {
+	match cmd.value_of(CLI_ARG_TRANSPORT) {
+		Some(CLI_TRANSPORT_UDT) => TransportKind::Udt,
+		Some(CLI_TRANSPORT_UDP) => TransportKind::Udp,
+		_ => TransportKind::default(),
+	}
+}
+
+/// Reads the `--congestion`, which UDT built w/ the `udt-sys` feature
+/// cannot change.
+fn read_congestion(cmd: &ArgMatches) -> Result<Congestion, failure::Error> {
+	match cmd.value_of(CLI_ARG_CONGESTION) {
+		Some(CLI_CONGESTION_FIXED) if cfg!(feature = "udt-sys") && read_transport(cmd) == TransportKind::Udt => {
+			bail!("--congestion fixed requires --transport udp on a build w/ the udt-sys feature, whose UDT's congestion control cannot be replaced");
+		},
+
+		Some(CLI_CONGESTION_FIXED) => Ok(Congestion::Fixed),
+		_ => Ok(Congestion::Adaptive),
+	}
+}
+
+/// Reads the `--mode`, which only the UDT transport has.
+fn read_udt_mode(cmd: &ArgMatches) -> Result<UdtMode, failure::Error> {
+	match cmd.value_of(CLI_ARG_UDT_MODE) {
+		Some(CLI_UDT_MODE_MESSAGE) if read_transport(cmd) == TransportKind::Udp => {
+			bail!("--mode message requires --transport udt, the udp transport has no message sockets");
+		},
+
+		Some(CLI_UDT_MODE_MESSAGE) => Ok(UdtMode::Message),
+		_ => Ok(UdtMode::Stream),
+	}
+}
+
+/// Reads the `--crypto-threads`, which must be at least one, & which may be
+/// fewer under the `--max-memory`.
+fn read_crypto_threads(cmd: &ArgMatches) -> Result<usize, failure::Error> {
+	let text = cmd.value_of(CLI_ARG_CRYPTO_THREADS)
+		.expect("fatal: session requires a number of crypto threads.");
+
+	let threads = match text.parse() {
+		Ok(threads) if threads > 0 => threads,
+		_ => bail!("invalid --crypto-threads: {} (expected a number of threads greater than zero)", text),
+	};
+
+	match read_memory_budget(cmd)? {
+		Some(budget) => Ok(budget.crypto_threads(threads)),
+		None => Ok(threads),
+	}
+}
+
+/// Parses the `--max-memory`, if given.
+fn read_memory_budget(cmd: &ArgMatches) -> Result<Option<MemoryBudget>, failure::Error> {
+	match cmd.value_of(CLI_ARG_MAX_MEMORY) {
+		Some(text) => Ok(Some(MemoryBudget::new(parse_size(text)?)?)),
+		None => Ok(None),
+	}
+}
+
+/// Returns the size of the UDT socket's buffers under the `--max-memory`, if
+/// the session runs over UDT.
+fn read_socket_buffer(cmd: &ArgMatches) -> Result<Option<usize>, failure::Error> {
+	if read_transport(cmd) != TransportKind::Udt {
+		return Ok(None);
+	}
+
+	Ok(read_memory_budget(cmd)?.map(|budget| budget.socket_buffer()))
+}
+
+/// Reads the `--cipher`, if one is forced, checking that it can be used w/ `key`.
+fn read_cipher(cmd: &ArgMatches, key: &[u8]) -> Result<Option<Cipher>, failure::Error> {
+	let cipher = match cmd.value_of(CLI_ARG_CIPHER) {
+		Some(CLI_CIPHER_AES_256_GCM) => Cipher::Aes256Gcm,
+		Some(CLI_CIPHER_AES_128_GCM) => Cipher::Aes128Gcm,
+		Some(CLI_CIPHER_CHACHA20_POLY1305) => Cipher::ChaCha20Poly1305,
+		_ => return Ok(None),
+	};
+
+	cipher.check_key(key)?;
+	Ok(Some(cipher))
+}
+
+/// Reads the `--seed`, if one is given, warning that what it seeds is no
+/// longer random.
+fn read_seed(cmd: &ArgMatches) -> Result<Option<u64>, failure::Error> {
+	let seed = match cmd.value_of(CLI_ARG_SEED) {
+		Some(text) => text.parse().map_err(|_| format_err!("invalid --seed: {}", text))?,
+		None => return Ok(None),
+	};
+
+	warn!("--seed {} is set, the output is predictable & only fit for testing", seed);
+	Ok(Some(seed))
+}
+
+/// Loads our `--identity`, if one is given.
+fn read_identity(cmd: &ArgMatches) -> Result<Option<Identity>, failure::Error> {
+	match cmd.value_of(CLI_ARG_IDENTITY) {
+		Some(path) => {
+			let text = fs::read_to_string(path)
+				.map_err(|err| format_err!("could not read identity {}: {}", path, err))?;
+
+			let identity = Identity::from_pkcs8(&key::parse(&text)?)?;
+			info!("our fingerprint is {}", identity.fingerprint());
+			Ok(Some(identity))
+		},
+
+		None => Ok(None),
+	}
+}
+
+/// Reads every `--expect-fingerprint`, refusing one which is not a whole
+/// fingerprint. (i.e: one shortened by hand, or printed by an older version.)
+fn read_expected_fingerprints(cmd: &ArgMatches) -> Result<Option<Vec<String>>, failure::Error> {
+	let fingerprints = match cmd.values_of(CLI_ARG_EXPECT_FINGERPRINT) {
+		Some(fingerprints) => fingerprints,
+		None => return Ok(None),
+	};
+
+	let mut expected = vec![];
+	for fingerprint in fingerprints {
+		if !key::is_fingerprint(fingerprint) {
+			bail!("{} is not a whole fingerprint, --expect-fingerprint needs all sixteen groups `genkey --identity` prints", fingerprint);
+		}
+
+		expected.push(fingerprint.to_string());
+	}
+
+	Ok(Some(expected))
+}
+
+/// Parses the network conditions to simulate from `--simulate`, if given.
+fn read_impairment(cmd: &ArgMatches) -> Result<Option<Impairment>, failure::Error> {
+	match cmd.value_of(CLI_ARG_SIMULATE) {
+		Some(text) => {
+			let impairment = text.parse()
+				.map_err(|err| format_err!("invalid --simulate: {}", err))?;
+
+			Ok(Some(impairment))
+		},
+
+		None => Ok(None),
+	}
+}
+
+/// Parses the `--allow`ed ranges of addresses, if any.
+fn read_allow(cmd: &ArgMatches) -> Result<Vec<Cidr>, failure::Error> {
+	cmd.values_of(CLI_ARG_ALLOW).into_iter().flatten()
+		.map(|text| text.parse().map_err(|err| format_err!("invalid --allow: {}", err)))
+		.collect()
+}
+
+/// Parses the local addresses the sender opens a `--multipath` from, an
+/// address w/o a port is given an ephemeral one.
+fn read_multipath(cmd: &ArgMatches) -> Result<Vec<SocketAddr>, failure::Error> {
+	cmd.values_of(CLI_ARG_MULTIPATH).into_iter().flatten()
+		.map(|text| text.parse()
+			.or_else(|_| text.parse().map(|ip| SocketAddr::new(ip, 0)))
+			.map_err(|_| format_err!("invalid --multipath: {} is not a local address", text)))
+		.collect()
+}
+
+/// Resolves the receiver's INET_ADDR & every address it was told to
+/// `--listen` on, in that order, leaving out those not in the `-4` or `-6`
+/// family.
+fn read_listen(cmd: &ArgMatches) -> Result<Vec<SocketAddr>, failure::Error> {
+	let family = read_family(cmd);
+	let texts = cmd.values_of(CLI_ARG_INET_ADDR).into_iter().flatten()
+		.chain(cmd.values_of(CLI_ARG_LISTEN_ADDR).into_iter().flatten());
+
+	let mut addrs = vec![];
+	for text in texts {
+		let resolved = text.to_socket_addrs()
+			.map_err(|err| format_err!("could not resolve {}: {}", text, err))?;
+
+		for addr in resolved.filter(|addr| family.matches(addr)) {
+			if !addrs.contains(&addr) {
+				addrs.push(addr);
+			}
+		}
+	}
+
+	if addrs.is_empty() {
+		return Err(ProtoError::NoAddrInFamily { family }.into());
+	}
+
+	Ok(addrs)
+}
+
+/// Parses the `--fsync-interval`, if given.
+fn read_fsync_interval(cmd: &ArgMatches) -> Result<Option<Duration>, failure::Error> {
+	match cmd.value_of(CLI_ARG_FSYNC_INTERVAL) {
+		Some(text) => Ok(Some(parse_interval(text)?)),
+		None => Ok(None),
+	}
+}
+
+/// Parses the `--stall-warning`, which has a default.
+fn read_stall_warning(cmd: &ArgMatches) -> Result<Duration, failure::Error> {
+	let text = cmd.value_of(CLI_ARG_STALL_WARNING)
+		.expect("fatal: receiver requires a stall warning interval.");
+
+	parse_interval(text)
+}
+
+/// Parses the `--stats-interval`, if given. The `--tui` samples the transfer
+/// every second by default.
+fn read_stats_interval(cmd: &ArgMatches) -> Result<Option<Duration>, failure::Error> {
+	match cmd.value_of(CLI_ARG_STATS) {
+		Some(text) => Ok(Some(parse_interval(text)?)),
+		None if cmd.is_present(CLI_ARG_TUI) => Ok(Some(DASHBOARD_INTERVAL)),
+		None => Ok(None),
+	}
+}
+
+/// Opens the descriptor named by `--progress-fd`, if given.
+fn read_progress(cmd: &ArgMatches) -> Result<Option<Progress>, failure::Error> {
+	match cmd.value_of(CLI_ARG_PROGRESS_FD) {
+		Some(text) => {
+			let fd = text.parse()
+				.map_err(|_| format_err!("invalid --progress-fd: {}", text))?;
+
+			let progress = Progress::from_fd(fd)
+				.map_err(|err| format_err!("could not use --progress-fd {}: {}", fd, err))?;
+
+			Ok(Some(progress))
+		},
+
+		None => Ok(None),
+	}
+}
+
+/// Checks that there is a terminal to draw the `--tui` on, if given.
+fn read_dashboard(cmd: &ArgMatches, role: &'static str) -> Result<Option<Dashboard>, failure::Error> {
+	if !cmd.is_present(CLI_ARG_TUI) {
+		return Ok(None);
+	}
+
+	let dashboard = Dashboard::new(role)
+		.map_err(|err| format_err!("could not draw the --tui: {}", err))?;
+
+	Ok(Some(dashboard))
+}
+
+/// Reads the base64 encoded key from `--key`, `--key-file`, or the
+/// `UBUFFER_KEY` environment variable, in that order. Failing those it is
+/// asked for on the terminal, if there is one.
+fn read_key(cmd: &ArgMatches) -> Result<Vec<u8>, failure::Error> {
+	if cmd.occurrences_of(CLI_ARG_KEY_FILE) > 1 {
+		bail!("only the receiver accepts more than one --key-file");
+	}
+
+	// the key agreed from the code replaces this one before anything is sealed
+	if cmd.is_present(CLI_ARG_CODE) {
+		return Ok(vec![0u8; 32]);
+	}
+
+	let key_text = if let Some(key) = cmd.value_of(CLI_ARG_KEY) {
+		key.to_string()
+	} else if let Some(path) = cmd.value_of(CLI_ARG_KEY_FILE) {
+		fs::read_to_string(path)?
+	} else if let Some(key) = env::var_os(ENV_KEY) {
+		key.into_string()
+			.map_err(|_| format_err!("{} is not valid unicode", ENV_KEY))?
+	} else if let Some(key) = prompt::read_secret("ubuffer key: ")? {
+		key
+	} else {
+		bail!("an encryption key is required, use --key, --key-file, or set {}", ENV_KEY);
+	};
+
+	Ok(key::parse(&key_text)?)
+}
+
+/// Reads every key a receiver accepts. The first is used for senders whose
+/// key is not among them, as it is when there is only one.
+fn read_keys(cmd: &ArgMatches) -> Result<Vec<Vec<u8>>, failure::Error> {
+	let paths = match cmd.values_of(CLI_ARG_KEY_FILE) {
+		Some(paths) if paths.len() > 1 => paths,
+		_ => return Ok(vec![read_key(cmd)?]),
+	};
+
+	let mut keys = vec![];
+	for path in paths {
+		let key = key::parse(&fs::read_to_string(path)?)?;
+		Cipher::for_key(&key).map_err(|err| format_err!("{}: {}", path, err))?;
+		keys.push(key);
+	}
+
+	Ok(keys)
+}
+
+/// Parses a byte count w/ an optional binary unit suffix. (i.e: `512K`, `4G`.)
+fn parse_size(text: &str) -> Result<u64, failure::Error> {
+	let text = text.trim();
+	let (digits, multiplier) = match text.chars().last().map(|ch| ch.to_ascii_uppercase()) {
+		Some('K') => (&text[..text.len() - 1], 1 << 10),
+		Some('M') => (&text[..text.len() - 1], 1 << 20),
+		Some('G') => (&text[..text.len() - 1], 1 << 30),
+		Some('T') => (&text[..text.len() - 1], 1 << 40),
+		_ => (text, 1),
+	};
+
+	let value: u64 = digits.parse()
+		.map_err(|_| format_err!("invalid size: {}", text))?;
+
+	value.checked_mul(multiplier)
+		.ok_or_else(|| format_err!("size is too large: {}", text))
+}
+
+/// Parses an interval in seconds w/ an optional unit suffix. (i.e: `5s`, `500ms`.)
+fn parse_interval(text: &str) -> Result<Duration, failure::Error> {
+	let text = text.trim();
+	let (number, scale) = match text.strip_suffix("ms") {
+		Some(number) => (number, 1e-3),
+		None => (text.strip_suffix('s').unwrap_or(text), 1.0),
+	};
+
+	let secs = number.parse::<f64>()
+		.map_err(|_| format_err!("invalid interval: {}", text))? * scale;
+
+	Duration::try_from_secs_f64(secs)
+		.map_err(|_| format_err!("invalid interval: {}", text))
+}
+
+fn start_sender(cmd: &ArgMatches) -> Result<(), failure::Error> {
+	let addr = cmd.value_of(CLI_ARG_INET_ADDR)
+		.expect("fatal: sender requires a remote address.");
+
+	let summary = cmd.value_of(CLI_ARG_SUMMARY)
+		.expect("fatal: sender requires a summary format.");
+
+	let retries = cmd.value_of(CLI_ARG_RETRY)
+		.expect("fatal: sender requires a retry count.");
+
+	let retry_delay = cmd.value_of(CLI_ARG_RETRY_DELAY)
+		.expect("fatal: sender requires a retry delay.");
+
+	let mut opts = StreamOpts {
+		reverse: cmd.is_present(CLI_ARG_LISTEN),
+		impairment: read_impairment(cmd)?,
+		transport: read_transport(cmd),
+		congestion: read_congestion(cmd)?,
+		udt_mode: read_udt_mode(cmd)?,
+		socket_buffer: read_socket_buffer(cmd)?,
+		retries: retries.parse()?,
+		retry_delay: parse_interval(retry_delay)?,
+		family: read_family(cmd),
+		..StreamOpts::default()
+	};
+
+	if let Some(bind) = cmd.value_of(CLI_ARG_BIND) {
+		opts.bind = Some(bind.parse()?);
+	}
+
+	opts.paths = read_multipath(cmd)?;
+	if !opts.paths.is_empty() && opts.transport == TransportKind::Udp {
+		bail!("--multipath stripes the session across UDT connections, it cannot be combined w/ --transport udp");
+	}
+
+	if !opts.paths.is_empty() && opts.udt_mode == UdtMode::Message {
+		bail!("--multipath stripes the session across UDT streams, it cannot be combined w/ --mode message");
+	}
+
+	opts.message_ttl = cmd.value_of(CLI_ARG_MESSAGE_TTL).map(parse_interval).transpose()?;
+	opts.unordered = cmd.is_present(CLI_ARG_UNORDERED);
+	if (opts.message_ttl.is_some() || opts.unordered) && opts.udt_mode != UdtMode::Message {
+		bail!("--message-ttl & --unordered only apply to blocks sent as UDT messages, they require --mode message");
+	}
+
+	let key = read_key(cmd)?;
+	let progress = read_progress(cmd)?;
+	let dashboard = read_dashboard(cmd, CLI_SUB_SEND)?;
+
+	// a missing input (or a bad pattern) should not cost the receiver a session
+	for path in read_inputs(cmd) {
+		fs::metadata(path).map_err(|err| format_err!("could not open {}: {}", path, err))?;
+	}
+
+	read_filter(cmd)?;
+
+	let remote = cmd.value_of(CLI_ARG_URL)
+		.map(open_url)
+		.transpose()?;
+
+	if let Some(mirrors) = cmd.values_of(CLI_ARG_MIRROR) {
+		let addrs: Vec<&str> = Some(addr).into_iter().chain(mirrors).collect();
+		return start_fan_out(cmd, &addrs, &key, &opts, remote, progress);
+	}
+
+	let mut sender = Sender::new(addr, &key, &opts)?;
+	configure_sender(cmd, &key, &mut sender, install_signal_handlers()?)?;
+	configure_identity(cmd, addr, &mut sender)?;
+
+	let json = cmd.is_present(CLI_ARG_JSON);
+	let verify = cmd.is_present(CLI_ARG_VERIFY);
+	let observer = session_observer(CLI_SUB_SEND, json, progress, dashboard);
+	sender.set_observer(verify_observer(CLI_SUB_SEND, verify, Some(sender.cancel_token()), observer));
+
+	let files = read_inputs(cmd);
+	let result = match files.as_slice() {
+		// the filter's output is sent as a single stream, named for its input
+		_ if cmd.is_present(CLI_ARG_FILTER_CMD) => {
+			let (input, metadata) = read_single_input(cmd, remote, CLI_ARG_FILTER_CMD_LONG)?;
+			if let Some(metadata) = metadata {
+				sender.set_metadata(metadata);
+			}
+
+			sender.run(read_command_filter(cmd, input)?)
+		},
+
+		[] => match (remote, cmd.value_of(CLI_ARG_TAR)) {
+			(Some(input), _) => sender.run(input),
+			(None, Some(dir)) => sender.run(archive::pack(dir, read_filter(cmd)?)?),
+			(None, None) => sender.run(io::stdin()),
+		},
+
+		paths if cmd.is_present(CLI_ARG_CONCAT) => sender.run(concat(paths)?),
+
+		[path] => {
+			let file = fs::File::open(path)?;
+			sender.set_metadata(FileMeta::from_path(path)?);
+			sender.run_file(file)
+		},
+
+		paths => sender.run_files(paths),
+	};
+
+	print_summary(CLI_SUB_SEND, sender.summary(), summary);
+	if json { print_error_event(&result); }
+
+	let result = result.map_err(failure::Error::from)
+		.and_then(|_| write_receipt(cmd, &sender));
+
+	run_hooks(cmd, CLI_SUB_SEND, sender.summary(), Some(&sender.digest()), &result)?;
+	result
+}
+
+/// Writes the receipt the receiver signed to the `--receipt` file, if both
+/// are present.
+fn write_receipt(cmd: &ArgMatches, sender: &Sender) -> Result<(), failure::Error> {
+	if let (Some(path), Some(receipt)) = (cmd.value_of(CLI_ARG_RECEIPT), sender.receipt()) {
+		fs::write(path, receipt.to_string())?;
+		info!("wrote the receiver's receipt to {}", path);
+	}
 
 	Ok(())
 }
 
-fn genkey() {
-	use rand::Rng;
+/// Runs the `--on-complete` or `--on-error` command for how a session ended,
+/// once its `result` is known. (See: `Hooks`.)
+fn run_hooks(cmd: &ArgMatches, role: &str, summary: &Summary, digest: Option<&[u8]>, result: &Result<(), failure::Error>) -> Result<(), failure::Error> {
+	let hooks = Hooks::new(cmd.value_of(CLI_ARG_ON_COMPLETE), cmd.value_of(CLI_ARG_ON_ERROR));
+	let failed = result.as_ref().err().map(|err| (exit_code(err), err));
+	hooks.run(role, summary, digest, failed)
+}
+
+/// The length of the input, from `--expect-size` or else the length of the
+/// files being sent. Other inputs (i.e: stdin, or a `--tar`) are unknown.
+fn read_total_size(cmd: &ArgMatches) -> Result<Option<u64>, failure::Error> {
+	if let Some(size) = cmd.value_of(CLI_ARG_EXPECT_SIZE) {
+		return Ok(Some(parse_size(size)?));
+	}
+
+	// the filter's output is rarely as long as its input
+	if cmd.is_present(CLI_ARG_FILTER_CMD) {
+		return Ok(None);
+	}
+
+	let files = read_inputs(cmd);
+	if files.is_empty() {
+		return Ok(None);
+	}
 
-	let mut rng = rand::thread_rng();
-	let mut key = [0u8; 32];
+	let mut total = 0;
+	for path in files {
+		let stat = fs::metadata(path)?;
+		if !stat.is_file() {
+			return Ok(None);
+		}
 
-	for key_byte in &mut key {
-		*key_byte = rng.gen();
+		total += stat.len();
 	}
 
-	let key_b64 = base64::encode(&key);
-	println!("{}", key_b64);
+	Ok(Some(total))
+}
+
+/// The files named by `--file` and the positional inputs, in that order.
+fn read_inputs<'a>(cmd: &'a ArgMatches) -> Vec<&'a str> {
+	cmd.values_of(CLI_ARG_FILE).into_iter().flatten()
+		.chain(cmd.values_of(CLI_ARG_INPUT).into_iter().flatten())
+		.collect()
+}
+
+/// Compiles the `--include` & `--exclude` patterns for `--tar`.
+fn read_filter(cmd: &ArgMatches) -> Result<archive::Filter, failure::Error> {
+	let include: Vec<&str> = cmd.values_of(CLI_ARG_INCLUDE).into_iter().flatten().collect();
+	let exclude: Vec<&str> = cmd.values_of(CLI_ARG_EXCLUDE).into_iter().flatten().collect();
+
+	archive::Filter::new(&include, &exclude)
+		.map_err(|err| format_err!("invalid glob pattern: {}", err))
+}
+
+/// Starts reading the body of `url`, so that it is ready to be sent once the
+/// receiver connects.
+fn open_url(url: &str) -> Result<Box<dyn Read + Send>, failure::Error> {
+	let input = if s3::is_url(url) {
+		s3::get(url)
+	} else if http::is_url(url) {
+		http::get(url)
+	} else {
+		bail!("unsupported url: {} (expected http://, https://, or s3://)", url)
+	};
+
+	input.map_err(|err| format_err!("could not open {}: {}", url, err))
+}
+
+/// Opens every file in `paths` up front, so that a missing file fails before
+/// anything is sent, and reads them back to back as a single stream.
+fn concat(paths: &[&str]) -> Result<Box<dyn Read + Send>, failure::Error> {
+	let mut input: Box<dyn Read + Send> = Box::new(io::empty());
+	for path in paths {
+		let file = fs::File::open(path)
+			.map_err(|err| format_err!("could not open {}: {}", path, err))?;
+
+		input = Box::new(input.chain(file));
+	}
+
+	Ok(input)
+}
+
+/// Opens the sender's input for a `flag` which sends it as a single stream,
+/// along w/ the metadata of the file it is, if it is one.
+fn read_single_input(cmd: &ArgMatches, remote: Option<Box<dyn Read + Send>>, flag: &str) -> Result<(Box<dyn Read + Send>, Option<FileMeta>), failure::Error> {
+	let files = read_inputs(cmd);
+	let input: (Box<dyn Read + Send>, _) = match files.as_slice() {
+		[] => match (remote, cmd.value_of(CLI_ARG_TAR)) {
+			(Some(input), _) => (input, None),
+			(None, Some(dir)) => (Box::new(archive::pack(dir, read_filter(cmd)?)?), None),
+			(None, None) => (Box::new(io::stdin()), None),
+		},
+
+		paths if cmd.is_present(CLI_ARG_CONCAT) => (concat(paths)?, None),
+
+		[path] => (Box::new(fs::File::open(path)?), Some(FileMeta::from_path(path)?)),
+		_ => bail!("--{} sends a single input, it cannot be combined w/ several files", flag),
+	};
+
+	Ok(input)
+}
+
+/// Starts piping the sender's `input` through its `--filter-cmd`, if given.
+fn read_command_filter(cmd: &ArgMatches, input: Box<dyn Read + Send>) -> Result<Box<dyn Read + Send>, failure::Error> {
+	match cmd.value_of(CLI_ARG_FILTER_CMD) {
+		Some(command) => {
+			let filter = CommandReader::spawn(command, input)
+				.map_err(|err| format_err!("could not run the --filter-cmd: {}", err))?;
+
+			Ok(Box::new(filter))
+		},
+
+		None => Ok(input),
+	}
+}
+
+/// Applies the options shared by every `sender` session.
+fn configure_sender(cmd: &ArgMatches, key: &[u8], sender: &mut Sender, interrupt: Arc<AtomicBool>) -> Result<(), failure::Error> {
+	sender.set_interrupt(interrupt);
+	sender.set_sparse(cmd.is_present(CLI_ARG_SPARSE));
+	sender.set_mmap(cmd.is_present(CLI_ARG_MMAP));
+	sender.set_padding(cmd.is_present(CLI_ARG_PAD));
+	sender.set_low_latency(cmd.is_present(CLI_ARG_LOW_LATENCY));
+	sender.set_line_buffered(cmd.is_present(CLI_ARG_LINE_BUFFERED));
+	sender.set_require_receipt(cmd.is_present(CLI_ARG_RECEIPT));
+
+	if let Some(code) = cmd.value_of(CLI_ARG_CODE) {
+		sender.set_code(code);
+	}
+
+	if let Some(interval) = cmd.value_of(CLI_ARG_REKEY) {
+		sender.set_rekey_interval(parse_size(interval)?);
+	}
+
+	if let Some(rate) = cmd.value_of(CLI_ARG_RATE_LIMIT) {
+		sender.set_rate_limit(parse_size(rate)?);
+	}
+
+	if let Some(budget) = read_memory_budget(cmd)? {
+		sender.set_retransmit_window(budget.retransmit_window());
+	}
+
+	if let Some(size) = cmd.value_of(CLI_ARG_MAX_IN_FLIGHT) {
+		let blocks = parse_size(size)? / BLOCK_SIZE as u64;
+		sender.set_max_unacked(usize::try_from(blocks).unwrap_or(usize::MAX));
+	}
+
+	if let Some(size) = cmd.value_of(CLI_ARG_MIN_BLOCK) {
+		let max_wait = cmd.value_of(CLI_ARG_MAX_WAIT)
+			.expect("fatal: sender requires a max wait.");
+
+		let min_block = parse_size(size)?;
+		if min_block > BLOCK_SIZE as u64 {
+			bail!("--min-block is at most {} bytes", BLOCK_SIZE);
+		}
+
+		sender.set_coalescing(min_block as usize, Duration::from_millis(max_wait.parse()?));
+	}
+
+	if let Some(size) = read_total_size(cmd)? {
+		sender.set_total_size(size);
+	}
+
+	let keepalive = cmd.value_of(CLI_ARG_KEEPALIVE)
+		.expect("fatal: sender requires a keepalive interval.");
+	sender.set_keepalive_interval(Duration::from_secs(keepalive.parse()?));
+	sender.set_crypto_threads(read_crypto_threads(cmd)?);
+
+	if let Some(cipher) = read_cipher(cmd, key)? {
+		sender.set_cipher(cipher)?;
+	}
+
+	if let Some(seed) = read_seed(cmd)? {
+		sender.set_seed(seed);
+	}
+
+	if let Some(timeout) = cmd.value_of(CLI_ARG_RESUME_TIMEOUT) {
+		sender.set_resume_timeout(parse_interval(timeout)?);
+	}
+
+	if let Some(interval) = read_stats_interval(cmd)? {
+		sender.set_stats_interval(interval);
+	}
+
+	Ok(())
+}
+
+/// Gives the sender its `--identity`, and decides whether it trusts the
+/// receiver it reaches at `addr`: it must have the `--expect-fingerprint` if
+/// one is given, otherwise it is checked against the known hosts.
+fn configure_identity(cmd: &ArgMatches, addr: &str, sender: &mut Sender) -> Result<(), failure::Error> {
+	if let Some(identity) = read_identity(cmd)? {
+		sender.set_identity(identity);
+	}
+
+	if let Some(expected) = read_expected_fingerprints(cmd)? {
+		sender.set_expected_fingerprint(&expected[0]);
+		return Ok(());
+	}
+
+	// a listening sender cannot tell which receiver will connect to it
+	if cmd.is_present(CLI_ARG_LISTEN) {
+		return Ok(());
+	}
+
+	let path = match cmd.value_of(CLI_ARG_KNOWN_HOSTS) {
+		Some(path) => PathBuf::from(path),
+		None => match KnownHosts::default_path() {
+			Some(path) => path,
+			None => {
+				debug!("not checking the receiver's identity, there is no home directory for the known hosts");
+				return Ok(());
+			},
+		},
+	};
+
+	let mut known_hosts = KnownHosts::open(&path)
+		.map_err(|err| format_err!("could not read the known hosts in {}: {}", path.display(), err))?;
+
+	let addr = addr.to_string();
+	let accept_new = cmd.is_present(CLI_ARG_ACCEPT_NEW);
+	sender.set_identity_check(move |fingerprint| known_hosts.check(&addr, fingerprint, accept_new));
+	Ok(())
+}
+
+/// Sends one input to every receiver in `addrs`, each in its own session.
+fn start_fan_out(cmd: &ArgMatches, addrs: &[&str], key: &[u8], opts: &StreamOpts, remote: Option<Box<dyn Read + Send>>, mut progress: Option<Progress>) -> Result<(), failure::Error> {
+	let summary = cmd.value_of(CLI_ARG_SUMMARY)
+		.expect("fatal: sender requires a summary format.");
+
+	let (input, metadata) = read_single_input(cmd, remote, CLI_ARG_MIRROR_LONG)?;
+	let input = read_command_filter(cmd, input)?;
+
+	let json = cmd.is_present(CLI_ARG_JSON);
+	let interrupt = install_signal_handlers()?;
+	let mut senders = vec![];
+
+	for (id, addr) in addrs.iter().enumerate() {
+		let mut sender = Sender::new(*addr, key, opts)?;
+		configure_sender(cmd, key, &mut sender, Arc::clone(&interrupt))?;
+		configure_identity(cmd, addr, &mut sender)?;
+
+		if let Some(metadata) = &metadata {
+			sender.set_metadata(metadata.clone());
+		}
+
+		// the first receiver's progress stands in for the whole transfer
+		let role = format!("{} {}", CLI_SUB_SEND, addr);
+		let mut progress = progress.take();
+		sender.set_observer(move |event| {
+			if let Some(progress) = progress.as_mut() {
+				progress.observe(event);
+			}
+
+			if json { print_session_event(id as u64, event); } else { print_stats(&role, event); }
+		});
+
+		senders.push(sender);
+	}
+
+	let mut fan_out = FanOut::new(senders);
+	let results = fan_out.run(input);
+	let mut failure = None;
+
+	for ((addr, sender), result) in addrs.iter().zip(fan_out.senders()).zip(results) {
+		print_summary(&format!("{} {}", CLI_SUB_SEND, addr), sender.summary(), summary);
+
+		if let Err(err) = result {
+			eprintln!("ubuffer {} {}: {}", CLI_SUB_SEND, addr, err);
+			failure.get_or_insert(err);
+		}
+	}
+
+	match failure {
+		Some(err) => Err(err.into()),
+		None => Ok(()),
+	}
+}
+
+fn start_receiver(cmd: &ArgMatches) -> Result<(), failure::Error> {
+	let summary = cmd.value_of(CLI_ARG_SUMMARY)
+		.expect("fatal: receiver requires a summary format.");
+
+	let keys = read_keys(cmd)?;
+	let key = &keys[0];
+	let addrs = read_listen(cmd)?;
+	if let Some(template) = cmd.value_of(CLI_ARG_HUB) {
+		return start_hub(cmd, &addrs, &keys, template);
+	}
+
+	let opts = StreamOpts {
+		reverse: cmd.is_present(CLI_ARG_CONNECT),
+		impairment: read_impairment(cmd)?,
+		transport: read_transport(cmd),
+		congestion: read_congestion(cmd)?,
+		udt_mode: read_udt_mode(cmd)?,
+		socket_buffer: read_socket_buffer(cmd)?,
+		allow: read_allow(cmd)?,
+		family: read_family(cmd),
+		..StreamOpts::default()
+	};
+
+	// the UDP listener holds on to its port for the whole session
+	if cmd.is_present(CLI_ARG_MIGRATE) && opts.transport == TransportKind::Udp {
+		bail!("--migrate only applies to UDT sessions, it cannot be combined w/ --transport udp");
+	}
+
+	let progress = read_progress(cmd)?;
+	let dashboard = read_dashboard(cmd, CLI_SUB_RECV)?;
+
+	// the tees are opened before waiting on a sender, so a bad path fails fast
+	let mut tees = vec![];
+	for path in cmd.values_of(CLI_ARG_TEE).into_iter().flatten() {
+		tees.push(fs::File::create(path).map_err(|err| format_err!("could not create {}: {}", path, err))?);
+	}
+
+	let output = cmd.value_of(CLI_ARG_OUTPUT);
+	let upload = match output {
+		Some(url) if s3::is_url(url) => {
+			if [CLI_ARG_APPEND, CLI_ARG_PRESERVE, CLI_ARG_PRESERVE_PERMS, CLI_ARG_PRESERVE_OWNER, CLI_ARG_DIRECT_IO, CLI_ARG_CHECKPOINT].iter().any(|arg| cmd.is_present(arg)) {
+				bail!("--append, --preserve, --preserve-perms, --preserve-owner, --direct-io & --checkpoint cannot be used when uploading to S3");
+			}
+
+			Some(s3::Upload::new(url)?)
+		},
+
+		_ => None,
+	};
+
+	let identity = read_identity(cmd)?;
+	let checkpoint = cmd.value_of(CLI_ARG_CHECKPOINT)
+		.map(|path| Checkpoint::load(path).map_err(|err| format_err!("could not load the checkpoint at {}: {}", path, err)))
+		.transpose()?
+		.flatten();
+
+	if let (Some(path), None, None) = (output, upload.as_ref(), checkpoint.as_ref()) {
+		if !cmd.is_present(CLI_ARG_APPEND) {
+			prepare_output(path, read_overwrite(cmd))?;
+		}
+	}
+
+	let mut receiver = Receiver::new(&addrs[..], key, &opts)?;
+	receiver.set_interrupt(install_signal_handlers()?);
+
+	for key in &keys[1..] {
+		receiver.add_key(key)?;
+	}
+
+	if let Some(code) = cmd.value_of(CLI_ARG_CODE) {
+		receiver.set_code(code);
+	}
+
+	if let Some(identity) = identity {
+		receiver.set_identity(identity);
+	}
+
+	if let Some(expected) = read_expected_fingerprints(cmd)? {
+		receiver.set_expected_fingerprints(&expected);
+	}
+
+	for tee in tees {
+		receiver.add_tee(tee);
+	}
+
+	let checksum = if cmd.is_present(CLI_ARG_CHECKSUM) {
+		let checksum = Checksum::new();
+		receiver.add_tee(checksum.clone());
+		Some(checksum)
+	} else {
+		None
+	};
+
+	if let Some(interval) = read_stats_interval(cmd)? {
+		receiver.set_stats_interval(interval);
+	}
+
+	receiver.set_crypto_threads(read_crypto_threads(cmd)?);
+	receiver.set_fsync_interval(read_fsync_interval(cmd)?);
+	receiver.set_sync_on_close(cmd.is_present(CLI_ARG_SYNC_ON_CLOSE));
+	receiver.set_quiet_threshold(read_stall_warning(cmd)?);
+
+	if let Some(cipher) = read_cipher(cmd, key)? {
+		receiver.set_cipher(cipher)?;
+	}
+
+	if let Some(seed) = read_seed(cmd)? {
+		receiver.set_seed(seed);
+	}
+
+	if let Some(timeout) = cmd.value_of(CLI_ARG_RESUME_TIMEOUT) {
+		receiver.set_resume_timeout(parse_interval(timeout)?);
+	}
+
+	receiver.set_migrate(cmd.is_present(CLI_ARG_MIGRATE));
+
+	if let Some(path) = cmd.value_of(CLI_ARG_CHECKPOINT) {
+		receiver.set_checkpoint(path);
+	}
+
+	let json = cmd.is_present(CLI_ARG_JSON);
+	let verify = cmd.is_present(CLI_ARG_VERIFY);
+	let observer = session_observer(CLI_SUB_RECV, json, progress, dashboard);
+	receiver.set_observer(verify_observer(CLI_SUB_RECV, verify, None, observer));
+
+	if let Some(dir) = cmd.value_of(CLI_ARG_DIR) {
+		receiver.set_output_dir(dir);
+		receiver.set_preserve(cmd.is_present(CLI_ARG_PRESERVE));
+		receiver.set_preserve_owner(cmd.is_present(CLI_ARG_PRESERVE_OWNER));
+		if cmd.is_present(CLI_ARG_PRESERVE_PERMS) {
+			receiver.set_preserve_perms(true);
+		}
+	}
+
+	// a --filter-cmd cannot be empty, so an empty one is none at all
+	let filter = cmd.value_of(CLI_ARG_FILTER_CMD).unwrap_or_default();
+	let result = match (upload, output, cmd.value_of(CLI_ARG_UNTAR)) {
+		// the filter's output goes wherever the session's would have
+		(Some(upload), _, _) if !filter.is_empty() => {
+			run_filtered(&mut receiver, filter, upload)
+				.and_then(|upload| upload.finish().map_err(ProtoError::from))
+		},
+
+		(None, Some(path), _) if !filter.is_empty() => {
+			run_filtered(&mut receiver, filter, create_output(path, read_overwrite(cmd), false)?).map(drop)
+		},
+
+		(None, None, Some(dir)) if !filter.is_empty() => {
+			run_filtered(&mut receiver, filter, archive::unpack(dir)?)
+				.and_then(|unpacker| unpacker.finish().map_err(ProtoError::from))
+		},
+
+		(None, None, None) if !filter.is_empty() => run_filtered(&mut receiver, filter, io::stdout()).map(drop),
+
+		// the object is only created once the sender has hung up cleanly,
+		// otherwise what was uploaded of it is thrown away.
+		(Some(mut upload), _, _) => {
+			receiver.run(&mut upload)
+				.and_then(|_| upload.finish().map_err(ProtoError::from))
+		},
+
+		// writes to a file opened for appending always land at its end, so
+		// holes cannot be seeked past & are written out as zeros instead.
+		(None, Some(path), _) if cmd.is_present(CLI_ARG_APPEND) => {
+			let file = fs::OpenOptions::new().append(true).create(true).open(path)?;
+			if file.metadata()?.is_file() {
+				receiver.set_output_file(&file)?;
+			}
+
+			receiver.run(file)
+		},
+
+		(None, Some(path), _) if cmd.is_present(CLI_ARG_DIRECT_IO) => {
+			let file = create_output(path, read_overwrite(cmd), true)?;
+			receiver.set_output_file(&file)?;
+			receiver.run_direct(file)
+		},
+
+		(None, Some(path), _) => match checkpoint {
+			// whatever was written after the checkpoint was saved is sent again
+			Some(checkpoint) => {
+				if !logging::quiet() {
+					eprintln!("ubuffer: restoring the session saved after {} of {}", human_bytes(checkpoint.bytes() as f64), path);
+				}
+
+				let mut file = fs::OpenOptions::new().write(true).open(path)?;
+				file.set_len(checkpoint.bytes())?;
+				file.seek(SeekFrom::End(0))?;
+
+				receiver.restore(checkpoint)?;
+				run_to_file(&mut receiver, file)
+			},
+
+			None => run_to_file(&mut receiver, create_output(path, read_overwrite(cmd), false)?),
+		},
+
+		(None, None, Some(dir)) => {
+			// an archive which failed to extract is the more useful error,
+			// the receiver would only see the pipe to the extractor break.
+			let mut unpacker = archive::unpack(dir)?;
+			let result = receiver.run(&mut unpacker);
+			unpacker.finish().map_err(ProtoError::from).and(result)
+		},
+
+		(None, None, None) => {
+			let stdout = io::stdout();
+			receiver.run(stdout.lock())
+		},
+	};
+
+	print_summary(CLI_SUB_RECV, receiver.summary(), summary);
+	if json { print_error_event(&result); }
+
+	let result = result.map_err(failure::Error::from)
+		.and_then(|_| finish_output(cmd, output, &receiver, checksum));
+
+	let digest = receiver.digest();
+	run_hooks(cmd, CLI_SUB_RECV, receiver.summary(), digest.as_ref().map(|digest| &digest[..]), &result)?;
+	result
+}
+
+/// Applies the sender's metadata to the `output` as asked, and writes its
+/// `checksum` if there is one, once the session has completed.
+fn finish_output(cmd: &ArgMatches, output: Option<&str>, receiver: &Receiver, checksum: Option<Checksum>) -> Result<(), failure::Error> {
+	let preserve = Preserve {
+		perms: cmd.is_present(CLI_ARG_PRESERVE) || cmd.is_present(CLI_ARG_PRESERVE_PERMS),
+		times: cmd.is_present(CLI_ARG_PRESERVE),
+		owner: cmd.is_present(CLI_ARG_PRESERVE_OWNER),
+	};
+
+	if let (Some(path), true) = (output, preserve != Preserve::default()) {
+		match receiver.metadata() {
+			Some(metadata) => metadata.apply_with(path, preserve)?,
+			None if logging::quiet() => {},
+			None => eprintln!("ubuffer: sender did not send file metadata, nothing to preserve"),
+		}
+	}
+
+	if let (Some(path), Some(checksum)) = (output, checksum) {
+		write_checksum(path, &checksum)?;
+	}
+
+	Ok(())
+}
+
+/// Writes the `.sha256` file which sits alongside the output at `path`.
+fn write_checksum(path: &str, checksum: &Checksum) -> Result<(), failure::Error> {
+	let sidecar = format!("{}.sha256", path);
+	let line = checksum.line(path);
+
+	if s3::is_url(&sidecar) {
+		let mut upload = s3::Upload::new(&sidecar)?;
+		upload.write_all(line.as_bytes())?;
+		upload.finish()?;
+	} else {
+		fs::write(&sidecar, line)?;
+	}
+
+	info!("wrote checksum to {}", sidecar);
+	Ok(())
+}
+
+/// What the receiver does w/ an existing `--out` file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Overwrite {
+	/// The file is truncated, as it always was. (i.e: `--force`.)
+	Truncate,
+
+	/// The receiver refuses to start. (i.e: `--no-clobber`.)
+	Refuse,
+
+	/// The file is renamed out of the way first. (i.e: `--backup-existing`.)
+	Backup,
+}
+
+fn read_overwrite(cmd: &ArgMatches) -> Overwrite {
+	if cmd.is_present(CLI_ARG_NO_CLOBBER) {
+		Overwrite::Refuse
+	} else if cmd.is_present(CLI_ARG_BACKUP_EXISTING) {
+		Overwrite::Backup
+	} else {
+		Overwrite::Truncate
+	}
+}
+
+/// Deals w/ an existing `--out` file as `overwrite` says, before waiting on
+/// a sender so that a refusal fails fast. Anything which is not a regular
+/// file (e.g: a device or a FIFO) is left alone.
+fn prepare_output(path: &str, overwrite: Overwrite) -> Result<(), failure::Error> {
+	if !fs::metadata(path).map(|meta| meta.is_file()).unwrap_or(false) {
+		return Ok(());
+	}
+
+	match overwrite {
+		Overwrite::Truncate => {},
+		Overwrite::Refuse => bail!("{} already exists, give --force to overwrite it or --backup-existing to keep it", path),
+		Overwrite::Backup => {
+			let backup = backup_path(path);
+			fs::rename(path, &backup)
+				.map_err(|err| format_err!("could not move {} out of the way: {}", path, err))?;
+
+			if !logging::quiet() {
+				eprintln!("ubuffer: moved the existing {} to {}", path, backup.display());
+			}
+		},
+	}
+
+	Ok(())
+}
+
+/// Creates the `--out` file, w/ `O_DIRECT` if `direct` is set so that writes
+/// bypass the page cache. (See: `prepare_output()`.)
+fn create_output(path: &str, overwrite: Overwrite, direct: bool) -> Result<fs::File, failure::Error> {
+	let mut options = fs::OpenOptions::new();
+	options.write(true).create(true).truncate(true);
+
+	// a file which appeared while waiting on the sender is still not clobbered
+	if overwrite == Overwrite::Refuse && fs::metadata(path).map_or(true, |meta| meta.is_file()) {
+		options.create_new(true);
+	}
+
+	if direct {
+		set_direct(&mut options)?;
+	}
+
+	options.open(path)
+		.map_err(|err| format_err!("could not create {}: {}", path, err))
+}
+
+/// Returns the first of `<path>.bak`, `<path>.bak.1`, `<path>.bak.2`, ...
+/// which does not exist yet, so that an earlier backup is never replaced.
+fn backup_path(path: &str) -> PathBuf {
+	let mut backup = PathBuf::from(format!("{}.bak", path));
+	let mut n = 0;
+
+	while fs::symlink_metadata(&backup).is_ok() {
+		n += 1;
+		backup = PathBuf::from(format!("{}.bak.{}", path, n));
+	}
+
+	backup
+}
+
+#[cfg(target_os = "linux")]
+fn set_direct(options: &mut fs::OpenOptions) -> Result<(), failure::Error> {
+	use std::os::unix::fs::OpenOptionsExt;
+
+	options.custom_flags(libc::O_DIRECT);
+	Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_direct(_options: &mut fs::OpenOptions) -> Result<(), failure::Error> {
+	bail!("--direct-io is only supported on Linux")
+}
+
+/// Runs the session through the receiver's `--filter-cmd` into `output`,
+/// returning the output once the command has exited. The command is waited
+/// for before the sender's goodbye is answered, so that the sender fails as
+/// well if it does.
+fn run_filtered<W: Write + Send + 'static>(receiver: &mut Receiver, command: &str, output: W) -> Result<W, ProtoError> {
+	let mut filter = CommandWriter::spawn(command, output)?;
+	let result = receiver.run_finished(&mut filter, |filter| filter.close());
+
+	// the command's failure is the more useful error, if the session failed
+	// first the receiver would only have seen the pipe to it break.
+	filter.close()?;
+	result?;
+	Ok(filter.finish()?)
+}
+
+/// Runs `receiver` into `file`, leaving holes the sender skipped as holes if
+/// `file` can seek. (i.e: it is not a pipe or a terminal.)
+fn run_to_file(receiver: &mut Receiver, file: fs::File) -> Result<(), ProtoError> {
+	if file.metadata()?.is_file() {
+		receiver.set_output_file(&file)?;
+		receiver.run_seekable(file)
+	} else {
+		receiver.run(file)
+	}
+}
+
+#[cfg(feature = "udt")]
+fn start_hub(cmd: &ArgMatches, addrs: &[SocketAddr], keys: &[Vec<u8>], template: &str) -> Result<(), failure::Error> {
+	if read_transport(cmd) == TransportKind::Udp {
+		bail!("--hub only accepts UDT sessions, it cannot be combined w/ --transport udp");
+	}
+
+	// each session would draw the same IV, & so reuse the same nonces
+	if cmd.is_present(CLI_ARG_SEED) {
+		bail!("--seed cannot be combined w/ --hub, every session would be sealed w/ the same nonces");
+	}
+
+	let summary = cmd.value_of(CLI_ARG_SUMMARY)
+		.expect("fatal: receiver requires a summary format.")
+		.to_string();
+
+	let json = cmd.is_present(CLI_ARG_JSON);
+	let stats_interval = read_stats_interval(cmd)?;
+	let fsync_interval = read_fsync_interval(cmd)?;
+	let sync_on_close = cmd.is_present(CLI_ARG_SYNC_ON_CLOSE);
+	let stall_warning = read_stall_warning(cmd)?;
+	let crypto_threads = read_crypto_threads(cmd)?;
+	let cipher = read_cipher(cmd, &keys[0])?;
+	let extra_keys = keys[1..].to_vec();
+	let identity = read_identity(cmd)?;
+	let expected = read_expected_fingerprints(cmd)?;
+	let template = template.to_string();
+	let interrupt = install_signal_handlers()?;
+
+	let metrics = match cmd.value_of(CLI_ARG_METRICS) {
+		Some(listen) => {
+			let metrics = Arc::new(Metrics::default());
+			metrics::serve(listen, Arc::clone(&metrics))?;
+			Some(metrics)
+		},
+
+		None => None,
+	};
+
+	let mut hub = Hub::new(addrs, &keys[0])?;
+	hub.set_interrupt(Arc::clone(&interrupt));
+	hub.set_allow(read_allow(cmd)?);
+
+	// each session's metrics are picked up again once it is over
+	let trackers = Arc::new(Mutex::new(HashMap::new()));
+	let closing = Arc::clone(&trackers);
+
+	let result = hub.run(|receiver, session| {
+		receiver.set_interrupt(Arc::clone(&interrupt));
+
+		if let Some(interval) = stats_interval {
+			receiver.set_stats_interval(interval);
+		}
+
+		receiver.set_crypto_threads(crypto_threads);
+		receiver.set_fsync_interval(fsync_interval);
+		receiver.set_sync_on_close(sync_on_close);
+		receiver.set_quiet_threshold(stall_warning);
+
+		if let Some(cipher) = cipher {
+			receiver.set_cipher(cipher).expect("cipher was checked against the key");
+		}
+
+		for key in &extra_keys {
+			receiver.add_key(key).expect("keys were checked when they were read");
+		}
+
+		if let Some(identity) = &identity {
+			receiver.set_identity(identity.clone());
+		}
+
+		if let Some(expected) = &expected {
+			receiver.set_expected_fingerprints(expected);
+		}
+
+		let id = session.id;
+		let role = format!("receiver #{}", id);
+		let tracker = metrics.as_ref().map(Metrics::begin_session);
+		let observed = tracker.clone();
+
+		receiver.set_observer(move |event| {
+			if let Some(observed) = observed.as_ref() { observed.observe(event); }
+			if json { print_session_event(id, event); } else { print_stats(&role, event); }
+		});
+
+		let path = expand_template(&template, session);
+		info!("writing session #{} from {} to {}", id, session.peer, path);
+
+		if let Some(tracker) = tracker {
+			trackers.lock().expect("metrics lock poisoned").insert(id, tracker);
+		}
+
+		Ok(fs::File::create(&path)?)
+	}, move |receiver, session, result| {
+		let tracker = closing.lock().expect("metrics lock poisoned").remove(&session.id);
+		end_hub_session(tracker, &receiver, &session, &result, &summary);
+	});
+
+	Ok(result?)
+}
+
+#[cfg(not(feature = "udt"))]
+fn start_hub(_cmd: &ArgMatches, _addrs: &[SocketAddr], _keys: &[Vec<u8>], _template: &str) -> Result<(), failure::Error> {
+	bail!("--hub only accepts UDT sessions, & ubuffer was built w/o the udt feature");
+}
+
+#[cfg(feature = "udt")]
+fn start_relay(cmd: &ArgMatches) -> Result<(), failure::Error> {
+	let listen = cmd.value_of(CLI_ARG_INET_ADDR)
+		.expect("fatal: relay requires a listen address.");
+
+	let target = cmd.value_of(CLI_ARG_TARGET_ADDR)
+		.expect("fatal: relay requires a target address.");
+
+	let mut opts = StreamOpts::default();
+	if let Some(bind) = cmd.value_of(CLI_ARG_BIND) {
+		opts.bind = Some(bind.parse()?);
+	}
+
+	let relay = Relay::new(listen, target, &opts)?;
+	let stats = relay.run()?;
+	eprintln!("ubuffer {}: forwarded {} bytes to the receiver and {} bytes back to the sender in {:.2}s",
+	          CLI_SUB_RELAY, stats.forwarded, stats.returned, stats.elapsed.as_secs_f64());
+
+	Ok(())
+}
+
+#[cfg(not(feature = "udt"))]
+fn start_relay(_cmd: &ArgMatches) -> Result<(), failure::Error> {
+	bail!("the relay forwards UDT sessions, & ubuffer was built w/o the udt feature");
+}
+
+fn start_ping(cmd: &ArgMatches) -> Result<(), failure::Error> {
+	let addr = cmd.value_of(CLI_ARG_INET_ADDR)
+		.expect("fatal: ping requires a remote address.");
+
+	let mut opts = StreamOpts {
+		transport: read_transport(cmd),
+		family: read_family(cmd),
+		..StreamOpts::default()
+	};
+
+	if let Some(bind) = cmd.value_of(CLI_ARG_BIND) {
+		opts.bind = Some(bind.parse()?);
+	}
+
+	let key = read_key(cmd)?;
+	let mut sender = Sender::new(addr, &key, &opts)?;
+	configure_identity(cmd, addr, &mut sender)?;
+
+	let rtt = sender.ping()?;
+
+	eprintln!("ubuffer {}: {} is reachable and the keys match, round-trip time {:.2}ms",
+	          CLI_SUB_PING, addr, rtt.as_secs_f64() * 1e3);
+
+	Ok(())
+}
+
+/// Describes each message of the captures given to `debug decode`, sharing
+/// what was learned from one (i.e: the receiver's IV) w/ those after it.
+fn decode_capture(cmd: &ArgMatches) -> Result<(), failure::Error> {
+	let mut decoder = CaptureDecoder::new();
+
+	if cmd.is_present(CLI_ARG_KEY) || cmd.is_present(CLI_ARG_KEY_FILE) {
+		let key = read_key(cmd)?;
+		decoder.set_key(&key)?;
+
+		if let Some(cipher) = read_cipher(cmd, &key)? {
+			decoder.set_cipher(cipher);
+		}
+	}
+
+	if let Some(text) = cmd.value_of(CLI_ARG_IV) {
+		let iv = u32::from_str_radix(text.trim_start_matches("0x"), 16)
+			.map_err(|_| format_err!("invalid --iv: {} (expected 8 hex digits)", text))?;
+		decoder.set_iv(iv);
+	}
+
+	let preview = cmd.value_of(CLI_ARG_PREVIEW).unwrap_or("32");
+	decoder.set_preview(preview.parse().map_err(|_| format_err!("invalid --preview: {}", preview))?);
+
+	let stdout = io::stdout();
+	let mut out = stdout.lock();
+	for path in cmd.values_of(CLI_ARG_CAPTURE).expect("fatal: decode requires a capture.") {
+		let capture = fs::File::open(path).map_err(|err| format_err!("could not open {}: {}", path, err))?;
+
+		writeln!(out, "{}:", path)?;
+		let count = decoder.decode(io::BufReader::new(capture), &mut out)?;
+		writeln!(out, "{} messages\n", count)?;
+	}
+
+	Ok(())
+}
+
+/// Reports how a hub `session` ended, and stops counting it in the metrics.
+#[cfg(feature = "udt")]
+fn end_hub_session(tracker: Option<SessionMetrics>, receiver: &Receiver, session: &Session, result: &Result<(), ProtoError>, summary: &str) {
+	if let Some(tracker) = tracker { tracker.end(result); }
+
+	print_summary(&format!("receiver #{}", session.id), receiver.summary(), summary);
+	if let Err(err) = result {
+		eprintln!("ubuffer: session #{} from {}: {}", session.id, session.peer, err);
+	}
+}
+
+/// Replaces the placeholders in an output path template w/ the details of a
+/// hub `session`. (See: `CLI_TXT_HUB`.)
+#[cfg(feature = "udt")]
+fn expand_template(template: &str, session: &Session) -> String {
+	let timestamp = session.accepted.duration_since(UNIX_EPOCH)
+		.map(|since| since.as_secs())
+		.unwrap_or_default();
+
+	let (year, month, day) = civil_from_days((timestamp / 86_400) as i64);
+	let seconds = timestamp % 86_400;
+
+	template
+		.replace("{n}", &session.id.to_string())
+		.replace("{seq}", &session.id.to_string())
+		.replace("{addr}", &session.peer.ip().to_string())
+		.replace("{port}", &session.peer.port().to_string())
+		.replace("{date}", &format!("{:04}{:02}{:02}", year, month, day))
+		.replace("{time}", &format!("{:02}{:02}{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60))
+		.replace("{timestamp}", &timestamp.to_string())
+}
+
+/// Converts a count of days since the unix epoch to a (year, month, day) in
+/// the proleptic Gregorian calendar. (See: Howard Hinnant's `civil_from_days`.)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+	let days = days + 719_468;
+	let era = days.div_euclid(146_097);
+	let day_of_era = days.rem_euclid(146_097);
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let mp = (5 * day_of_year + 2) / 153;
+	let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+
+	(year_of_era + era * 400 + (month <= 2) as i64, month, day)
+}
+
+/// Builds the observer for a single session's events, which are drawn on the
+/// `dashboard` or else printed as JSON or stats for `role`, and copied to the
+/// `progress` descriptor if any.
+fn session_observer(role: &'static str, json: bool, mut progress: Option<Progress>, mut dashboard: Option<Dashboard>) -> impl FnMut(&Event) + Send + 'static {
+	move |event| {
+		if let Some(progress) = progress.as_mut() {
+			progress.observe(event);
+		}
+
+		match dashboard.as_mut() {
+			Some(dashboard) => dashboard.observe(event),
+			None if json => print_event(event),
+			None => print_stats(role, event),
+		}
+	}
+}
+
+/// Wraps `observer` so that the session's fingerprint is printed once the
+/// handshake is done, if `verify` is set. A sender's `cancel` token is
+/// cancelled unless whoever is at the terminal says it matches the other side.
+fn verify_observer<F>(role: &'static str, verify: bool, cancel: Option<CancelToken>, mut observer: F) -> impl FnMut(&Event) + Send + 'static
+where F: FnMut(&Event) + Send + 'static {
+	move |event| {
+		observer(event);
+
+		let fingerprint = match event {
+			Event::HandshakeComplete { fingerprint, .. } if verify => fingerprint,
+			_ => return,
+		};
+
+		eprintln!("ubuffer {}: the session's fingerprint is {}", role, fingerprint);
+		let cancel = match cancel {
+			Some(ref cancel) => cancel,
+			None => return,
+		};
+
+		// w/o a terminal to ask on, the fingerprint can only be compared later
+		match prompt::confirm("ubuffer: does the other side show the same fingerprint? [y/N] ") {
+			Ok(Some(true)) | Ok(None) => {},
+			Ok(Some(false)) => cancel.cancel(),
+			Err(err) => {
+				warn!("could not ask whether the fingerprints match: {}", err);
+				cancel.cancel();
+			},
+		}
+	}
+}
+
+/// Prints a protocol `Event` from one of several sessions (a hub's, or those
+/// of a `--mirror`ed sender) as a single line of JSON on stderr, tagged w/ the
+/// session number.
+fn print_session_event(id: u64, event: &Event) {
+	if let Some(mut json) = event_json(event) {
+		json["session"] = id.into();
+		eprintln!("{}", json);
+	}
+}
+
+/// Prints a protocol `Event` as a single line of JSON on stderr.
+fn print_event(event: &Event) {
+	if let Some(json) = event_json(event) {
+		eprintln!("{}", json);
+	}
+}
+
+/// Serializes a protocol `Event`, tagged w/ the id of the session it belongs
+/// to once the peers have agreed on one.
+fn event_json(event: &Event) -> Option<serde_json::Value> {
+	match serde_json::to_value(event) {
+		Ok(mut json) => {
+			if let Some(session_id) = SessionId::current() {
+				json["session_id"] = session_id.to_string().into();
+			}
+
+			Some(json)
+		},
+
+		Err(err) => {
+			warn!("could not serialize event {:?}: {}", event, err);
+			None
+		},
+	}
+}
+
+/// Prints the periodic `Event::Stats` samples, and each file completed in a
+/// multi-file session, for `role` as text on stderr. All other events are
+/// ignored, as are these if `-q` was given.
+fn print_stats(role: &str, event: &Event) {
+	if logging::quiet() {
+		return;
+	}
+
+	match event {
+		Event::Stats { total_bytes, expected_bytes, interval_secs, throughput_bps, send_queue, recv_queue, rtt_ms, lost_packets } => {
+			let mut line = format!("ubuffer {}: {}/s over {:.2}s, {} total",
+			                       role, human_bytes(*throughput_bps), interval_secs, human_bytes(*total_bytes as f64));
+
+			if let Some(expected) = expected_bytes.filter(|&expected| expected > 0) {
+				let left = expected.saturating_sub(*total_bytes) as f64;
+				line += &format!(" ({:.1}% of {}", *total_bytes as f64 * 100.0 / expected as f64, human_bytes(expected as f64));
+				if *throughput_bps > 0.0 {
+					line += &format!(", about {:.0}s left", left / throughput_bps);
+				}
+
+				line += ")";
+			}
+
+			if let (Some(send_queue), Some(recv_queue)) = (send_queue, recv_queue) {
+				line += &format!(", {} packets queued to send, {} packets waiting to be read", send_queue, recv_queue);
+			}
+
+			if let Some(rtt) = rtt_ms {
+				line += &format!(", {:.2}ms rtt", rtt);
+			}
+
+			if let Some(lost) = lost_packets {
+				line += &format!(", {} packets lost", lost);
+			}
+
+			eprintln!("{}", line);
+		},
+
+		Event::FileEnd { name, bytes } => {
+			eprintln!("ubuffer {}: {} complete, {}", role, name, human_bytes(*bytes as f64));
+		},
+
+		_ => {},
+	}
+}
+
+/// Prints an `error` event on stderr if the session failed.
+fn print_error_event(result: &Result<(), ProtoError>) {
+	if let Err(err) = result {
+		let json = serde_json::json!({
+			"event": "error",
+			"message": err.to_string(),
+		});
+
+		eprintln!("{}", json);
+	}
+}
+
+/// Prints the final transfer summary for `role` on stderr in the requested
+/// `format`, so that it does not interfere with data on stdout.
+fn print_summary(role: &str, summary: &Summary, format: &str) {
+	if format == CLI_SUMMARY_JSON {
+		let json = serde_json::json!({
+			"role": role,
+			"session_id": summary.session_id.map(|id| id.to_string()),
+			"plaintext_bytes": summary.plaintext_bytes,
+			"ciphertext_bytes": summary.ciphertext_bytes,
+			"blocks": summary.blocks,
+			"elapsed_secs": summary.elapsed_secs(),
+			"throughput_bps": summary.throughput(),
+			"rtt_ms": summary.link.and_then(|link| link.rtt).map(|rtt| rtt.as_secs_f64() * 1e3),
+			"bandwidth_bps": summary.link.and_then(|link| link.bandwidth_bps),
+			"lost_packets": summary.link.map(|link| link.lost_packets),
+			"retransmitted_packets": summary.link.map(|link| link.retransmitted_packets),
+		});
+
+		eprintln!("{}", json);
+		return;
+	}
+
+	let mut line = format!("ubuffer {}: {}", role, summary);
+	if let Some(link) = summary.link {
+		if let Some(rtt) = link.rtt {
+			line += &format!(", {:.2}ms rtt", rtt.as_secs_f64() * 1e3);
+		}
+
+		line += &format!(", {} of {} packets lost, {} retransmitted", link.lost_packets, link.sent_packets, link.retransmitted_packets);
+	}
+
+	if let Some(session_id) = summary.session_id {
+		line += &format!(", session {}", session_id);
+	}
+
+	eprintln!("{}", line);
+}
+
+/// Returns a flag which is raised when the process receives SIGINT or SIGTERM.
+///
+/// The first signal only raises the flag so the state machines can hang up
+/// gracefully, a second signal terminates the process immediately.
+fn install_signal_handlers() -> Result<Arc<AtomicBool>, io::Error> {
+	let flag = Arc::new(AtomicBool::new(false));
+
+	for signal in TERM_SIGNALS {
+		signal_hook::flag::register_conditional_shutdown(*signal, EXIT_INTERRUPTED, Arc::clone(&flag))?;
+		signal_hook::flag::register(*signal, Arc::clone(&flag))?;
+	}
+
+	Ok(flag)
+}
+
+fn genkey(cmd: &ArgMatches) -> Result<(), failure::Error> {
+	use rand::{Rng, RngCore, SeedableRng};
+	use rand::rngs::StdRng;
+
+	if cmd.is_present(CLI_ARG_CODE) {
+		println!("{}", generate_code());
+		return Ok(());
+	}
+
+	let key = if cmd.is_present(CLI_ARG_IDENTITY) {
+		Identity::generate()?
+	} else {
+		let bits: usize = cmd.value_of(CLI_ARG_BITS).unwrap_or("256").parse()?;
+
+		let mut rng: Box<dyn RngCore> = match read_seed(cmd)? {
+			Some(seed) => Box::new(StdRng::seed_from_u64(seed)),
+			None => Box::new(rand::thread_rng()),
+		};
+
+		let mut key = vec![0u8; bits / 8];
+		for key_byte in &mut key {
+			*key_byte = rng.gen();
+		}
+
+		key
+	};
+
+	let label = cmd.value_of(CLI_ARG_LABEL);
+	match cmd.value_of(CLI_ARG_OUT) {
+		Some(path) => key::write_file(path, &key, label)?,
+		None => print!("{}", key::format(&key, label)),
+	}
+
+	// the fingerprint of an identity is what senders pin, so it is always shown
+	if cmd.is_present(CLI_ARG_IDENTITY) {
+		eprintln!("fingerprint: {}", Identity::from_pkcs8(&key)?.fingerprint());
+	} else if cmd.is_present(CLI_ARG_FINGERPRINT) {
+		eprintln!("fingerprint: {}", key::fingerprint(&key));
+	}
+
+	Ok(())
 }
@@ -0,0 +1,151 @@
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use ubuffer::error::ProtoError;
+use ubuffer::proto::Event;
+
+/// Counters describing every session a hub has accepted, exported over HTTP
+/// in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+	ciphertext_bytes: AtomicU64,
+	plaintext_bytes: AtomicU64,
+	blocks: AtomicU64,
+	sessions_active: AtomicU64,
+	sessions: AtomicU64,
+	session_failures: AtomicU64,
+	handshake_failures: AtomicU64,
+	crypto_errors: AtomicU64,
+	nacks: AtomicU64,
+}
+
+/// Tracks one session's contribution to the `Metrics`. Clones share the same
+/// session, so that one may be handed to the receiver's observer.
+#[derive(Clone, Debug)]
+pub struct SessionMetrics {
+	metrics: Arc<Metrics>,
+	handshaken: Arc<AtomicBool>,
+}
+
+impl Metrics {
+	/// Counts a newly accepted session as active until it is ended.
+	pub fn begin_session(self: &Arc<Self>) -> SessionMetrics {
+		self.sessions.fetch_add(1, Ordering::Relaxed);
+		self.sessions_active.fetch_add(1, Ordering::Relaxed);
+
+		SessionMetrics {
+			metrics: Arc::clone(self),
+			handshaken: Arc::new(AtomicBool::new(false)),
+		}
+	}
+
+	/// Formats the counters in the Prometheus text exposition format.
+	pub fn render(&self) -> String {
+		let metrics = [
+			("ubuffer_ciphertext_bytes_total", "counter", "Encrypted payload bytes received from senders.", &self.ciphertext_bytes),
+			("ubuffer_plaintext_bytes_total", "counter", "Decrypted bytes written to session outputs.", &self.plaintext_bytes),
+			("ubuffer_blocks_total", "counter", "Blocks received from senders.", &self.blocks),
+			("ubuffer_sessions_active", "gauge", "Sessions which are currently running.", &self.sessions_active),
+			("ubuffer_sessions_total", "counter", "Sessions accepted since the hub started.", &self.sessions),
+			("ubuffer_session_failures_total", "counter", "Sessions which ended w/ an error.", &self.session_failures),
+			("ubuffer_handshake_failures_total", "counter", "Sessions which ended before completing the handshake.", &self.handshake_failures),
+			("ubuffer_crypto_errors_total", "counter", "Sessions which ended because a message failed to decrypt.", &self.crypto_errors),
+			("ubuffer_nacks_total", "counter", "Blocks which failed to decrypt and were asked to be resent.", &self.nacks),
+		];
+
+		let mut text = String::new();
+		for (name, ty, help, value) in metrics.iter() {
+			let _ = writeln!(text, "# HELP {} {}", name, help);
+			let _ = writeln!(text, "# TYPE {} {}", name, ty);
+			let _ = writeln!(text, "{} {}", name, value.load(Ordering::Relaxed));
+		}
+
+		text
+	}
+}
+
+impl SessionMetrics {
+	/// Updates the counters from one of the session's events.
+	pub fn observe(&self, event: &Event) {
+		match event {
+			Event::HandshakeComplete { .. } => self.handshaken.store(true, Ordering::Relaxed),
+
+			Event::Block { plaintext_len, ciphertext_len, .. } => {
+				self.metrics.plaintext_bytes.fetch_add(*plaintext_len as u64, Ordering::Relaxed);
+				self.metrics.ciphertext_bytes.fetch_add(*ciphertext_len as u64, Ordering::Relaxed);
+				self.metrics.blocks.fetch_add(1, Ordering::Relaxed);
+			},
+
+			Event::Skip { len, .. } => {
+				self.metrics.plaintext_bytes.fetch_add(*len, Ordering::Relaxed);
+			},
+
+			Event::Nack { .. } => {
+				self.metrics.nacks.fetch_add(1, Ordering::Relaxed);
+			},
+
+			_ => {},
+		}
+	}
+
+	/// Records how the session ended and stops counting it as active.
+	pub fn end(self, result: &Result<(), ProtoError>) {
+		self.metrics.sessions_active.fetch_sub(1, Ordering::Relaxed);
+
+		if let Err(err) = result {
+			self.metrics.session_failures.fetch_add(1, Ordering::Relaxed);
+
+			if !self.handshaken.load(Ordering::Relaxed) {
+				self.metrics.handshake_failures.fetch_add(1, Ordering::Relaxed);
+			}
+
+			if let ProtoError::CryptoErr = err {
+				self.metrics.crypto_errors.fetch_add(1, Ordering::Relaxed);
+			}
+		}
+	}
+}
+
+/// Serves `metrics` on `addr` from a helper thread, `GET /metrics` returns
+/// the counters and any other request is answered w/ a 404.
+pub fn serve<A: ToSocketAddrs>(addr: A, metrics: Arc<Metrics>) -> Result<(), io::Error> {
+	let listener = TcpListener::bind(addr)?;
+	info!("serving metrics on {}", listener.local_addr()?);
+
+	thread::spawn(move || {
+		for conn in listener.incoming() {
+			let result = conn.and_then(|conn| respond(conn, &metrics));
+			if let Err(err) = result {
+				debug!("could not answer metrics request: {}", err);
+			}
+		}
+	});
+
+	Ok(())
+}
+
+fn respond(conn: TcpStream, metrics: &Metrics) -> Result<(), io::Error> {
+	let mut reader = BufReader::new(conn.try_clone()?);
+	let mut request = String::new();
+	reader.read_line(&mut request)?;
+
+	// the headers are not needed, but are read so the client sees a clean close
+	let mut header = String::new();
+	while reader.read_line(&mut header)? > 2 {
+		header.clear();
+	}
+
+	let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>().as_slice() {
+		["GET", "/metrics"] => ("200 OK", metrics.render()),
+		_ => ("404 Not Found", String::from("not found\n")),
+	};
+
+	let mut conn = conn;
+	write!(conn, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+	       status, body.len(), body)?;
+
+	conn.flush()
+}
@@ -0,0 +1,118 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use ubuffer::proto::Event;
+
+/// The shortest time between two progress records.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Writes progress records to a file descriptor inherited from the process
+/// which started `ubuffer`, since stdout & stderr are already spoken for.
+///
+/// Each record is a single line of JSON, i.e:
+/// `{"bytes":1048576,"done":false,"rate_bps":524288.0}`. The rate is measured
+/// since the previous record, and a final record w/ `done` set is written
+/// when the session ends. Once the sender has announced how large its input
+/// is, records also carry the `total_bytes`, `percent` & `eta_secs`.
+///
+/// A sender whose receiver acknowledges what it has written reports those
+/// bytes once the first ack arrives, rather than what it has sent.
+pub struct Progress {
+	out: File,
+	last: Instant,
+	last_bytes: u64,
+	total: Option<u64>,
+	acked: bool,
+}
+
+impl Progress {
+	/// Takes ownership of the inherited descriptor `fd`.
+	#[cfg(unix)]
+	pub fn from_fd(fd: i32) -> Result<Self, io::Error> {
+		use std::os::unix::io::FromRawFd;
+
+		if fd < 0 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "file descriptors cannot be negative"));
+		}
+
+		// the descriptor is checked before anything is written to it, so a
+		// typo fails at startup rather than silently dropping the progress.
+		let out = unsafe { File::from_raw_fd(fd) };
+		if let Err(err) = out.metadata() {
+			std::mem::forget(out);
+			return Err(err);
+		}
+
+		Ok(Self { out, last: Instant::now(), last_bytes: 0, total: None, acked: false })
+	}
+
+	#[cfg(not(unix))]
+	pub fn from_fd(_fd: i32) -> Result<Self, io::Error> {
+		Err(io::Error::other("--progress-fd is not supported on this platform"))
+	}
+
+	/// Writes a record for `event` if one is due.
+	pub fn observe(&mut self, event: &Event) {
+		let result = match event {
+			Event::HandshakeComplete { .. } => {
+				self.last = Instant::now();
+				Ok(())
+			},
+
+			Event::TotalSize { bytes } => {
+				self.total = Some(*bytes);
+				Ok(())
+			},
+
+			Event::Block { total_bytes, .. } | Event::Skip { total_bytes, .. } if !self.acked && self.last.elapsed() >= PROGRESS_INTERVAL => {
+				self.record(*total_bytes)
+			},
+
+			Event::Ack { total_bytes, .. } => {
+				self.acked = true;
+				if self.last.elapsed() >= PROGRESS_INTERVAL {
+					self.record(*total_bytes)
+				} else {
+					Ok(())
+				}
+			},
+
+			Event::Finished { plaintext_bytes, throughput_bps, .. } => {
+				self.write(*plaintext_bytes, *throughput_bps, true)
+			},
+
+			_ => Ok(()),
+		};
+
+		if let Err(err) = result {
+			debug!("could not write progress: {}", err);
+		}
+	}
+
+	/// Writes a record for `total_bytes`, w/ the rate since the last one.
+	fn record(&mut self, total_bytes: u64) -> Result<(), io::Error> {
+		// the first ack may trail what was already reported as sent
+		let rate = total_bytes.saturating_sub(self.last_bytes) as f64 / self.last.elapsed().as_secs_f64();
+		self.last = Instant::now();
+		self.last_bytes = total_bytes;
+		self.write(total_bytes, rate, false)
+	}
+
+	fn write(&mut self, bytes: u64, rate: f64, done: bool) -> Result<(), io::Error> {
+		let mut record = serde_json::json!({
+			"bytes": bytes,
+			"rate_bps": rate,
+			"done": done,
+		});
+
+		if let Some(total) = self.total.filter(|&total| total > 0) {
+			record["total_bytes"] = total.into();
+			record["percent"] = (bytes as f64 * 100.0 / total as f64).into();
+			if rate > 0.0 {
+				record["eta_secs"] = (total.saturating_sub(bytes) as f64 / rate).into();
+			}
+		}
+
+		writeln!(self.out, "{}", record)
+	}
+}
@@ -0,0 +1,88 @@
+use std::io;
+
+/// The controlling terminal, which is read instead of stdin so that a secret
+/// can be asked for while the data is piped through.
+#[cfg(target_os = "linux")]
+const TTY: &str = "/dev/tty";
+
+/// Asks for a secret on the controlling terminal w/ echo turned off, returning
+/// the line which was typed. Returns `None` if there is no terminal to ask on.
+/// (e.g: under a service manager or cron.)
+#[cfg(target_os = "linux")]
+pub fn read_secret(prompt: &str) -> Result<Option<String>, io::Error> {
+	use std::fs::OpenOptions;
+	use std::io::{BufRead, BufReader, Write};
+	use std::os::unix::io::AsRawFd;
+
+	let mut tty = match OpenOptions::new().read(true).write(true).open(TTY) {
+		Ok(tty) => tty,
+		Err(_) => return Ok(None),
+	};
+
+	let fd = tty.as_raw_fd();
+	if unsafe { libc::isatty(fd) } != 1 {
+		return Ok(None);
+	}
+
+	let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+	if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	write!(tty, "{}", prompt)?;
+	tty.flush()?;
+
+	let saved = termios;
+	termios.c_lflag &= !libc::ECHO;
+	termios.c_lflag |= libc::ECHONL;
+	if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	let mut line = String::new();
+	let read = BufReader::new(&tty).read_line(&mut line);
+
+	// the terminal is restored before anything else, even if the read failed
+	unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+	read?;
+
+	Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_string()))
+}
+
+/// Asks a yes or no `question` on the controlling terminal, returning true
+/// only if the answer starts w/ `y`. Returns `None` if there is no terminal to
+/// ask on.
+#[cfg(target_os = "linux")]
+pub fn confirm(question: &str) -> Result<Option<bool>, io::Error> {
+	use std::fs::OpenOptions;
+	use std::io::{BufRead, BufReader, Write};
+	use std::os::unix::io::AsRawFd;
+
+	let mut tty = match OpenOptions::new().read(true).write(true).open(TTY) {
+		Ok(tty) => tty,
+		Err(_) => return Ok(None),
+	};
+
+	if unsafe { libc::isatty(tty.as_raw_fd()) } != 1 {
+		return Ok(None);
+	}
+
+	write!(tty, "{}", question)?;
+	tty.flush()?;
+
+	let mut line = String::new();
+	BufReader::new(&tty).read_line(&mut line)?;
+	Ok(Some(line.trim_start().to_lowercase().starts_with('y')))
+}
+
+/// There is no portable way to turn off echo, so a secret is never asked for.
+#[cfg(not(target_os = "linux"))]
+pub fn read_secret(_prompt: &str) -> Result<Option<String>, io::Error> {
+	Ok(None)
+}
+
+/// The controlling terminal is only opened on Linux, so nothing is asked.
+#[cfg(not(target_os = "linux"))]
+pub fn confirm(_question: &str) -> Result<Option<bool>, io::Error> {
+	Ok(None)
+}
@@ -0,0 +1,525 @@
+use crate::error::ProtoError;
+use crate::proto::banner::BANNER_LEN;
+use crate::proto::plaintext::Pending;
+use crate::proto::reader::Plain;
+use crate::proto::sender::POLL_INTERVAL;
+use crate::proto::{Message, Receiver, Sender, Summary, Transport, BLOCK_SIZE, MESSAGE_SIZE};
+
+use std::collections::VecDeque;
+use std::future::{self, Future};
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time;
+
+/// How much the peer may have sent ahead of the state machine before it is
+/// no longer read from while something else is waited on.
+const MAX_STAGED: usize = 64 * BLOCK_SIZE;
+
+/// An `AsyncSender` runs a `Sender` as a task on an async runtime, so that
+/// many sessions can share the runtime's threads rather than each of them
+/// blocking one of its own.
+///
+/// The session runs over any `AsyncRead + AsyncWrite` transport (e.g: a
+/// `TcpStream`, or a `tokio::io::duplex` in tests) and reads its input from an
+/// `AsyncRead`. It is the same state machine `Sender::run()` drives, but it
+/// is stepped a message at a time: each step is taken once whatever it reads
+/// has arrived, and whatever it writes is then written to the transport.
+/// Nothing blocks while the receiver or the input is waited on.
+///
+/// The input is sent as it is read, so it is never treated as sparse, line
+/// buffered or coalesced. A session run this way is not held to a rate limit
+/// & is not resumed if the transport fails. The runtime must have its timer
+/// enabled, which the sender wakes up on while its input is idle.
+///
+pub struct AsyncSender {
+	inner: Sender,
+	conduit: Conduit,
+}
+
+/// An `AsyncReceiver` runs a `Receiver` as a task on an async runtime, writing
+/// its output to an `AsyncWrite`. (See: `AsyncSender`.)
+///
+/// The holes a sparse sender skips over are written as zeros. A session run
+/// this way has no quiet threshold & is not resumed if the transport fails.
+pub struct AsyncReceiver {
+	inner: Receiver,
+	conduit: Conduit,
+}
+
+/// What the sender's input did while it was waited on.
+enum Input {
+	Read(usize),
+	Replied,
+	Idle,
+}
+
+/// The async transport a session runs over.
+trait Link: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Link for T {}
+
+/// The `Transport` a state machine runs over when it is driven by a
+/// `Conduit`, which never waits on the peer: each message is read from it once
+/// it has arrived in full, and what is written to it is held until the
+/// `Conduit` writes it out.
+struct Staged {
+	stage: Arc<Mutex<Stage>>,
+}
+
+/// What is shared between a `Staged` transport & its `Conduit`.
+#[derive(Default)]
+struct Stage {
+	/// The messages (or the banner) which have arrived in full.
+	inbox: VecDeque<u8>,
+
+	/// What has arrived of the next message.
+	partial: Vec<u8>,
+	bannered: bool,
+
+	/// Set once the peer hung up, or could not be read from.
+	eof: bool,
+	failed: Option<io::Error>,
+
+	outbox: Vec<u8>,
+	closed: bool,
+}
+
+/// Moves messages between a `Staged` transport & the async transport it
+/// stands in for.
+struct Conduit {
+	link: Box<dyn Link>,
+	stage: Arc<Mutex<Stage>>,
+	shut_down: bool,
+}
+
+impl AsyncSender {
+	/// Creates a sender which will run over `transport` and use `key` to
+	/// encrypt outgoing blocks.
+	pub fn new<T>(transport: T, key: &[u8]) -> Result<Self, ProtoError>
+	where T: AsyncRead + AsyncWrite + Send + Unpin + 'static {
+		let (conduit, staged) = Conduit::new(transport);
+		let inner = Sender::with_transport(staged, key)?;
+		Ok(Self { inner, conduit })
+	}
+
+	/// Returns the underlying `Sender` so that it may be configured before the
+	/// session is started. (e.g: to set a rekey interval or an observer.)
+	pub fn get_mut(&mut self) -> &mut Sender {
+		&mut self.inner
+	}
+
+	/// Sends everything read from `input` to the receiver, resolving to a
+	/// tally of the transfer once the session has ended.
+	pub async fn run<R: AsyncRead + Unpin>(mut self, mut input: R) -> Result<Summary, ProtoError> {
+		info!("starting sender ...");
+		self.inner.set_rate_limit(0);
+		self.inner.set_resume_timeout(Duration::from_secs(0));
+
+		let result = self.run_states(&mut input).await;
+		let result = self.inner.conclude(result);
+
+		// a receiver is told why the session failed before it is hung up on
+		let sent = self.conduit.send().await;
+		result?;
+		sent?;
+
+		Ok(self.inner.summary().clone())
+	}
+
+	async fn run_states<R: AsyncRead + Unpin>(&mut self, input: &mut R) -> Result<(), ProtoError> {
+		while self.inner.handshaking() {
+			if self.inner.awaits_peer() {
+				self.conduit.recv().await?;
+			}
+
+			self.inner.shake()?;
+			self.conduit.send().await?;
+		}
+
+		self.transmit(input).await?;
+		self.hang_up().await
+	}
+
+	/// Sends blocks read from `input` until it reaches EOF.
+	async fn transmit<R: AsyncRead + Unpin>(&mut self, input: &mut R) -> Result<(), ProtoError> {
+		let mut last_sent = Instant::now();
+		let mut buf = vec![];
+
+		loop {
+			if let Some(err) = self.inner.stopped() {
+				warn!("{}, aborting transfer ...", err);
+				if let Err(abort_err) = self.abort().await {
+					debug!("could not deliver abort: {}", abort_err);
+				}
+
+				return Err(err);
+			}
+
+			self.inner.between_blocks()?;
+
+			// a block may be followed by a rekey, & neither of them can wait on
+			// the receiver once they have been sealed.
+			while self.inner.awaits_acks(2) {
+				self.conduit.send().await?;
+				self.conduit.recv().await?;
+				self.inner.between_blocks()?;
+			}
+
+			self.conduit.send().await?;
+
+			buf.resize(BLOCK_SIZE, 0);
+			let len = match self.read_input(input, &mut buf).await {
+				Ok(Input::Read(0)) => {
+					debug!("buffer reached eof");
+					return Ok(());
+				},
+
+				Ok(Input::Read(len)) => len,
+				Ok(Input::Replied) => continue,
+				Ok(Input::Idle) => {
+					if self.inner.idle_interval().is_some_and(|interval| last_sent.elapsed() >= interval) {
+						self.inner.send_idle()?;
+						last_sent = Instant::now();
+					}
+
+					continue;
+				},
+
+				Err(err) => {
+					warn!("could not read input, aborting transfer: {}", err);
+					self.abort().await?;
+					return Err(err.into());
+				},
+			};
+
+			buf.truncate(len);
+			let chunks = vec![Plain::Read(mem::take(&mut buf))];
+			buf = self.inner.send_chunks(chunks, &mut 0)?.pop().unwrap_or_default();
+			self.conduit.send().await?;
+			last_sent = Instant::now();
+		}
+	}
+
+	/// Reads the next chunk of `input` into `buf`, unless the receiver sends
+	/// something or `POLL_INTERVAL` passes first.
+	async fn read_input<R: AsyncRead + Unpin>(&mut self, input: &mut R, buf: &mut [u8]) -> Result<Input, io::Error> {
+		let mut timer = None;
+
+		future::poll_fn(|cx| {
+			let mut read = ReadBuf::new(&mut *buf);
+			if let Poll::Ready(result) = Pin::new(&mut *input).poll_read(cx, &mut read) {
+				return Poll::Ready(result.map(|()| Input::Read(read.filled().len())));
+			}
+
+			if self.conduit.pump(cx) {
+				return Poll::Ready(Ok(Input::Replied));
+			}
+
+			let timer = timer.get_or_insert_with(|| Box::pin(time::sleep(POLL_INTERVAL)));
+			timer.as_mut().poll(cx).map(|()| Ok(Input::Idle))
+		}).await
+	}
+
+	/// Says goodbye to the receiver once everything has been sent.
+	async fn hang_up(&mut self) -> Result<(), ProtoError> {
+		match self.inner.send_goodbye() {
+			Err(err @ ProtoError::SizeMismatch { .. }) => {
+				self.abort().await?;
+				return Err(err);
+			},
+
+			sent => sent?,
+		}
+
+		self.wait_goodbye().await?;
+		self.inner.hung_up()?;
+		Ok(self.conduit.send().await?)
+	}
+
+	/// Tells the receiver the transfer is being abandoned, & waits on it to
+	/// answer w/ its goodbye.
+	async fn abort(&mut self) -> Result<(), ProtoError> {
+		self.inner.send_abort()?;
+		self.wait_goodbye().await
+	}
+
+	async fn wait_goodbye(&mut self) -> Result<(), ProtoError> {
+		info!("receiving goodbye ...");
+
+		loop {
+			self.conduit.send().await?;
+			self.conduit.recv().await?;
+			if self.inner.recv_goodbye_reply()? {
+				info!("goodbye world ...");
+				return Ok(());
+			}
+		}
+	}
+}
+
+impl AsyncReceiver {
+	/// Creates a receiver which will run over `transport` and use `key` to
+	/// decrypt incoming blocks.
+	pub fn new<T>(transport: T, key: &[u8]) -> Result<Self, ProtoError>
+	where T: AsyncRead + AsyncWrite + Send + Unpin + 'static {
+		let (conduit, staged) = Conduit::new(transport);
+		let inner = Receiver::with_transport(staged, key)?;
+		Ok(Self { inner, conduit })
+	}
+
+	/// Returns the underlying `Receiver` so that it may be configured before
+	/// the session is started. (e.g: to set an output directory.)
+	pub fn get_mut(&mut self) -> &mut Receiver {
+		&mut self.inner
+	}
+
+	/// Writes everything sent by the sender to `out`, resolving to a tally of
+	/// the transfer along w/ `out` itself once the session has ended.
+	pub async fn run<W: AsyncWrite + Unpin>(mut self, mut out: W) -> Result<(Summary, W), ProtoError> {
+		self.inner.set_quiet_threshold(Duration::from_secs(0));
+		self.inner.set_resume_timeout(Duration::from_secs(0));
+
+		let mut pending = Pending::default();
+		let mut buf = vec![0u8; BLOCK_SIZE];
+		let result = self.run_states(&mut pending, &mut out, &mut buf).await;
+		let result = self.inner.conclude(result);
+
+		// what was received before the session failed is still written out
+		let written = write_out(&mut pending, &mut out, &mut buf).await;
+		let sent = self.conduit.send().await;
+		result?;
+		written?;
+		sent?;
+
+		Ok((self.inner.summary().clone(), out))
+	}
+
+	async fn run_states<W>(&mut self, pending: &mut Pending, out: &mut W, buf: &mut [u8]) -> Result<(), ProtoError>
+	where W: AsyncWrite + Unpin {
+		loop {
+			if self.inner.awaits_peer() {
+				self.conduit.recv().await?;
+			}
+
+			let finished = match self.inner.handshaking() {
+				true => {
+					self.inner.shake()?;
+					false
+				},
+
+				false => self.inner.step(pending)?,
+			};
+
+			// the output is written before the sender is answered, so that its
+			// goodbye is not answered until all of it has been.
+			write_out(pending, out, buf).await?;
+			if finished {
+				out.flush().await?;
+			}
+
+			self.conduit.send().await?;
+			if finished {
+				return Ok(());
+			}
+		}
+	}
+}
+
+/// Writes what has been received to `out`, by way of `buf`.
+async fn write_out<W>(pending: &mut Pending, out: &mut W, buf: &mut [u8]) -> Result<(), io::Error>
+where W: AsyncWrite + Unpin {
+	loop {
+		match pending.read_into(buf) {
+			0 => return Ok(()),
+			len => out.write_all(&buf[..len]).await?,
+		}
+	}
+}
+
+impl Conduit {
+	fn new<T: Link + 'static>(link: T) -> (Self, Staged) {
+		let stage = Arc::new(Mutex::new(Stage::default()));
+		let staged = Staged { stage: Arc::clone(&stage) };
+		(Self { link: Box::new(link), stage, shut_down: false }, staged)
+	}
+
+	/// Waits until the peer has sent a whole message (or its banner), unless
+	/// it hangs up first.
+	async fn recv(&mut self) -> Result<(), io::Error> {
+		future::poll_fn(|cx| {
+			while !self.stage().ready() {
+				ready!(self.poll_read(cx));
+			}
+
+			Poll::Ready(Ok(()))
+		}).await
+	}
+
+	/// Reads whatever the peer has sent so far w/o waiting on it, returning
+	/// true if anything is ready to be read from the `Staged` transport.
+	fn pump(&mut self, cx: &mut Context) -> bool {
+		loop {
+			{
+				let stage = self.stage();
+				if stage.eof || stage.inbox.len() + stage.partial.len() >= MAX_STAGED {
+					return stage.ready();
+				}
+			}
+
+			if self.poll_read(cx).is_pending() {
+				return self.stage().ready();
+			}
+		}
+	}
+
+	/// Reads from the peer once, staging each message once it is whole.
+	fn poll_read(&mut self, cx: &mut Context) -> Poll<()> {
+		let mut buf = [0u8; BLOCK_SIZE];
+		let mut read = ReadBuf::new(&mut buf);
+		let result = ready!(Pin::new(&mut *self.link).poll_read(cx, &mut read));
+
+		let mut stage = self.stage();
+		match result {
+			Ok(()) if !read.filled().is_empty() => stage.receive(read.filled()),
+			Ok(()) => stage.hang_up(None),
+			Err(err) => stage.hang_up(Some(err)),
+		}
+
+		Poll::Ready(())
+	}
+
+	/// Writes out whatever the state machine has written, then hangs up if
+	/// it closed the transport. The peer is read from in the meantime, so
+	/// that neither waits on the other w/ its buffers full.
+	async fn send(&mut self) -> Result<(), io::Error> {
+		let (mut outbox, closed) = {
+			let mut stage = self.stage();
+			(mem::take(&mut stage.outbox), stage.closed)
+		};
+
+		if !outbox.is_empty() {
+			let mut written = 0;
+			future::poll_fn(|cx| {
+				while written < outbox.len() {
+					self.pump(cx);
+					match ready!(Pin::new(&mut *self.link).poll_write(cx, &outbox[written..]))? {
+						0 => return Poll::Ready(Err(io::Error::from(ErrorKind::WriteZero))),
+						len => written += len,
+					}
+				}
+
+				Poll::Ready(Ok(()))
+			}).await?;
+
+			self.link.flush().await?;
+
+			// the buffer is kept for whatever is written next
+			outbox.clear();
+			let mut stage = self.stage();
+			if stage.outbox.is_empty() {
+				stage.outbox = outbox;
+			}
+		}
+
+		if closed && !self.shut_down {
+			self.shut_down = true;
+			self.link.shutdown().await?;
+		}
+
+		Ok(())
+	}
+
+	fn stage(&self) -> MutexGuard<'_, Stage> {
+		self.stage.lock().expect("stage lock poisoned")
+	}
+}
+
+impl Stage {
+	/// Returns true if a whole message can be read, or the peer is gone.
+	fn ready(&self) -> bool {
+		!self.inbox.is_empty() || self.eof
+	}
+
+	/// Appends `buf` to what has arrived so far, moving each message to the
+	/// inbox once it is whole.
+	fn receive(&mut self, buf: &[u8]) {
+		self.partial.extend_from_slice(buf);
+
+		while let Some(len) = self.next_len().filter(|&len| len <= self.partial.len()) {
+			self.inbox.extend(self.partial.drain(..len));
+			self.bannered = true;
+		}
+	}
+
+	/// Returns the length of what arrives next, once its header has. (The
+	/// connection is opened by the banner, which has none.)
+	fn next_len(&self) -> Option<usize> {
+		if !self.bannered {
+			return Some(BANNER_LEN);
+		}
+
+		// a header which is not valid is read by itself, & fails to decode
+		let header = self.partial.get(..MESSAGE_SIZE)?;
+		Some(MESSAGE_SIZE + Message::decode(header).map_or(0, |msg| msg.len))
+	}
+
+	/// Notes that the peer is gone, after whatever it sent of its last message.
+	fn hang_up(&mut self, err: Option<io::Error>) {
+		self.inbox.extend(self.partial.drain(..));
+		self.eof = true;
+		self.failed = err;
+	}
+}
+
+impl Transport for Staged {
+	fn close(&mut self) -> Result<(), ProtoError> {
+		self.stage().closed = true;
+		Ok(())
+	}
+
+	fn has_pending(&mut self) -> bool {
+		!self.stage().inbox.is_empty()
+	}
+}
+
+impl Staged {
+	fn stage(&self) -> MutexGuard<'_, Stage> {
+		self.stage.lock().expect("stage lock poisoned")
+	}
+}
+
+impl Read for Staged {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+		let mut stage = self.stage();
+		if !stage.inbox.is_empty() {
+			return stage.inbox.read(buf);
+		}
+
+		if let Some(err) = stage.failed.take() {
+			return Err(err);
+		}
+
+		match stage.eof {
+			true => Ok(0),
+
+			// each step is only taken once what it reads has arrived
+			false => Err(io::Error::new(ErrorKind::WouldBlock, "read ahead of the staged messages")),
+		}
+	}
+}
+
+impl Write for Staged {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+		self.stage().outbox.extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> Result<(), io::Error> {
+		Ok(())
+	}
+}
@@ -0,0 +1,53 @@
+use crate::error::ProtoError;
+use crate::proto::PROTOCOL_VERSION;
+
+use std::io::{Read, Write};
+
+/// Each peer opens every connection w/ these bytes, followed by its
+/// `PROTOCOL_VERSION`, before anything else is sent.
+pub const BANNER_MAGIC: [u8; 7] = *b"ubuffer";
+
+/// The length of the banner: `BANNER_MAGIC` & the version as a single byte.
+pub const BANNER_LEN: usize = BANNER_MAGIC.len() + 1;
+
+/// Sends our banner & checks the peer's, so that a connection to (or from)
+/// something which is not a ubuffer peer of this version fails straight away
+/// w/ an error which says so, rather than somewhere in the handshake.
+///
+/// Both peers send theirs before reading the other's, so neither waits on
+/// the other to go first.
+pub(super) fn exchange<S: Read + Write + ?Sized>(stream: &mut S) -> Result<(), ProtoError> {
+	send(stream)?;
+	recv(stream)
+}
+
+/// Sends our half of the `exchange()`.
+pub(super) fn send<S: Write + ?Sized>(stream: &mut S) -> Result<(), ProtoError> {
+	let mut banner = [0u8; BANNER_LEN];
+	banner[..BANNER_MAGIC.len()].copy_from_slice(&BANNER_MAGIC);
+	banner[BANNER_MAGIC.len()] = PROTOCOL_VERSION;
+	stream.write_all(&banner)?;
+	stream.flush()?;
+	Ok(())
+}
+
+/// Reads & checks the peer's half of the `exchange()`.
+pub(super) fn recv<S: Read + ?Sized>(stream: &mut S) -> Result<(), ProtoError> {
+	let mut banner = [0u8; BANNER_LEN];
+	stream.read_exact(&mut banner)?;
+	let version = check(&banner)?;
+	if version != PROTOCOL_VERSION {
+		return Err(ProtoError::UnsupportedVersion { version });
+	}
+
+	Ok(())
+}
+
+/// Returns the version a banner names, if it is a banner at all.
+pub(super) fn check(banner: &[u8]) -> Result<u8, ProtoError> {
+	if banner.len() != BANNER_LEN || banner[..BANNER_MAGIC.len()] != BANNER_MAGIC {
+		return Err(ProtoError::NotAPeer);
+	}
+
+	Ok(banner[BANNER_MAGIC.len()])
+}
@@ -0,0 +1,72 @@
+use crate::error::ProtoError;
+use crate::proto::sender::RETRANSMIT_WINDOW;
+use crate::proto::workers::BLOCKS_PER_THREAD;
+use crate::proto::BLOCK_SIZE;
+
+use std::convert::TryFrom;
+
+/// The smallest budget a session fits in, w/ room to keep a useful number
+/// of messages in flight.
+pub const MIN_MEMORY: u64 = 4 << 20;
+
+/// What each block costs on top of its data, once it is sealed & framed:
+/// its header, its tag, & the length of a padded block, rounded up.
+const BLOCK_OVERHEAD: usize = 64;
+
+/// The size of UDT's send & receive buffers by default: 8192 packets each.
+const UDT_BUFFER: usize = 8192 * 1500;
+
+/// Splits a budget for the memory a session's buffers may use between them,
+/// for a peer w/ little to spare. (e.g: a receiver on a small VPS.)
+///
+/// By default UDT's send & receive buffers, the messages a sender keeps to
+/// resend, and the batches being sealed or opened by the crypto workers come
+/// to well over 64 MiB. Under a budget half of it goes to the socket's
+/// buffers, an eighth to the crypto workers, and the rest to the retransmit
+/// window, which also bounds what a sender leaves unacknowledged. A budget
+/// only ever shrinks them, a large one leaves the defaults as they are.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryBudget {
+	bytes: u64,
+}
+
+impl MemoryBudget {
+	/// Creates a budget of `bytes`, which must be at least `MIN_MEMORY`.
+	pub fn new(bytes: u64) -> Result<Self, ProtoError> {
+		if bytes < MIN_MEMORY {
+			return Err(ProtoError::InvalidArgument { reason: "a memory budget must be at least 4 MiB" });
+		}
+
+		Ok(Self { bytes })
+	}
+
+	/// The size of each of the socket's send & receive buffers. (See:
+	/// `StreamOpts::socket_buffer`.)
+	pub fn socket_buffer(&self) -> usize {
+		share(self.bytes / 4, 1).min(UDT_BUFFER)
+	}
+
+	/// How many of the `threads` asked for can seal or open their batches
+	/// w/o going over the budget, which is always at least one. Each batch is
+	/// held twice: as it was read, and as it was sealed or opened.
+	pub fn crypto_threads(&self, threads: usize) -> usize {
+		let batch = 2 * BLOCKS_PER_THREAD * (BLOCK_SIZE + BLOCK_OVERHEAD);
+		threads.min(share(self.bytes / 8, batch)).max(1)
+	}
+
+	/// How many sealed messages a sender keeps to resend. (See:
+	/// `Sender::set_retransmit_window()`.)
+	pub fn retransmit_window(&self) -> usize {
+		share(self.bytes * 3 / 8, BLOCK_SIZE + BLOCK_OVERHEAD).min(RETRANSMIT_WINDOW)
+	}
+
+	/// Returns the budget in bytes.
+	pub fn bytes(&self) -> u64 {
+		self.bytes
+	}
+}
+
+/// How many pieces of `size` bytes fit in `bytes`.
+fn share(bytes: u64, size: usize) -> usize {
+	usize::try_from(bytes / size as u64).unwrap_or(usize::MAX)
+}
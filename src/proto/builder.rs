@@ -0,0 +1,368 @@
+use crate::error::ProtoError;
+use crate::proto::{Cipher, Event, Features, FileMeta, Identity, Receiver, Sender, StreamOpts, Transport, TransportKind};
+
+use std::fs::File;
+use std::io::Write;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+/// A setting which is applied to the `Sender` or `Receiver` once it exists.
+type Setup<T> = Box<dyn FnOnce(&mut T) -> Result<(), ProtoError> + Send>;
+
+/// Gathers the options of a `Sender` before it connects, as an alternative to
+/// calling its setters one at a time.
+///
+/// Each option corresponds to one of `Sender`'s setters, or to a field of the
+/// `StreamOpts` it connects with, and is documented there. Options which can
+/// fail (e.g: a cipher which does not suit the key) fail the build.
+///
+/// The block size & compression are not options: every block is at most
+/// `BLOCK_SIZE`, which both peers are built w/, and the input is sent as it
+/// is read, so it is compressed (if at all) before it is handed over. A
+/// bandwidth cap is set w/ `rate_limit()`.
+pub struct SenderBuilder {
+	key: Vec<u8>,
+	opts: StreamOpts,
+	setup: Vec<Setup<Sender>>,
+}
+
+/// Gathers the options of a `Receiver` before it accepts the sender. (See:
+/// `SenderBuilder`.)
+pub struct ReceiverBuilder {
+	key: Vec<u8>,
+	opts: StreamOpts,
+	setup: Vec<Setup<Receiver>>,
+}
+
+impl SenderBuilder {
+	/// Starts a sender which will use `key` to encrypt outgoing blocks.
+	pub fn new(key: &[u8]) -> Self {
+		Self { key: key.to_vec(), opts: StreamOpts::default(), setup: vec![] }
+	}
+
+	/// Connects to the receiver at `addr` & applies the options.
+	pub fn build<S: ToSocketAddrs>(self, addr: S) -> Result<Sender, ProtoError> {
+		let sender = Sender::new(addr, &self.key, &self.opts)?;
+		apply(sender, self.setup)
+	}
+
+	/// Applies the options to a sender which runs over `transport`, the
+	/// options of the stream itself are ignored.
+	pub fn build_with_transport<T: Transport + 'static>(self, transport: T) -> Result<Sender, ProtoError> {
+		let sender = Sender::with_transport(transport, &self.key)?;
+		apply(sender, self.setup)
+	}
+
+	/// Replaces every option of the stream. (See: `StreamOpts`.)
+	pub fn stream_opts(mut self, opts: StreamOpts) -> Self {
+		self.opts = opts;
+		self
+	}
+
+	pub fn bind(mut self, addr: SocketAddr) -> Self {
+		self.opts.bind = Some(addr);
+		self
+	}
+
+	pub fn transport(mut self, transport: TransportKind) -> Self {
+		self.opts.transport = transport;
+		self
+	}
+
+	pub fn accept_timeout(mut self, timeout: Duration) -> Self {
+		self.opts.accept_timeout = Some(timeout);
+		self
+	}
+
+	pub fn retries(mut self, retries: u32, delay: Duration) -> Self {
+		self.opts.retries = retries;
+		self.opts.retry_delay = delay;
+		self
+	}
+
+	pub fn cipher(self, cipher: Cipher) -> Self {
+		self.try_then(move |sender| sender.set_cipher(cipher))
+	}
+
+	pub fn code(self, code: &str) -> Self {
+		let code = code.to_string();
+		self.then(move |sender| sender.set_code(&code))
+	}
+
+	pub fn identity(self, identity: Identity) -> Self {
+		self.then(move |sender| sender.set_identity(identity))
+	}
+
+	pub fn expected_fingerprint(self, fingerprint: &str) -> Self {
+		let fingerprint = fingerprint.to_string();
+		self.then(move |sender| sender.set_expected_fingerprint(&fingerprint))
+	}
+
+	pub fn identity_check<F>(self, check: F) -> Self
+	where F: FnMut(Option<&str>) -> Result<(), ProtoError> + Send + 'static {
+		self.then(move |sender| sender.set_identity_check(check))
+	}
+
+	pub fn crypto_threads(self, threads: usize) -> Self {
+		self.then(move |sender| sender.set_crypto_threads(threads))
+	}
+
+	pub fn retransmit_window(self, messages: usize) -> Self {
+		self.then(move |sender| sender.set_retransmit_window(messages))
+	}
+
+	pub fn max_unacked(self, messages: usize) -> Self {
+		self.then(move |sender| sender.set_max_unacked(messages))
+	}
+
+	pub fn resume_timeout(self, timeout: Duration) -> Self {
+		self.then(move |sender| sender.set_resume_timeout(timeout))
+	}
+
+	pub fn rekey_interval(self, bytes: u64) -> Self {
+		self.then(move |sender| sender.set_rekey_interval(bytes))
+	}
+
+	pub fn keepalive_interval(self, interval: Duration) -> Self {
+		self.then(move |sender| sender.set_keepalive_interval(interval))
+	}
+
+	pub fn metadata(self, metadata: FileMeta) -> Self {
+		self.then(move |sender| sender.set_metadata(metadata))
+	}
+
+	pub fn total_size(self, size: u64) -> Self {
+		self.then(move |sender| sender.set_total_size(size))
+	}
+
+	pub fn sparse(self, sparse: bool) -> Self {
+		self.then(move |sender| sender.set_sparse(sparse))
+	}
+
+	pub fn mmap(self, mmap: bool) -> Self {
+		self.then(move |sender| sender.set_mmap(mmap))
+	}
+
+	pub fn padding(self, padding: bool) -> Self {
+		self.then(move |sender| sender.set_padding(padding))
+	}
+
+	pub fn low_latency(self, low_latency: bool) -> Self {
+		self.then(move |sender| sender.set_low_latency(low_latency))
+	}
+
+	pub fn coalescing(self, min_block: usize, max_wait: Duration) -> Self {
+		self.then(move |sender| sender.set_coalescing(min_block, max_wait))
+	}
+
+	pub fn line_buffered(self, line_buffered: bool) -> Self {
+		self.then(move |sender| sender.set_line_buffered(line_buffered))
+	}
+
+	pub fn rate_limit(self, bytes_per_sec: u64) -> Self {
+		self.then(move |sender| sender.set_rate_limit(bytes_per_sec))
+	}
+
+	pub fn require_receipt(self, require: bool) -> Self {
+		self.then(move |sender| sender.set_require_receipt(require))
+	}
+
+	pub fn features(self, features: Features) -> Self {
+		self.then(move |sender| sender.set_features(features))
+	}
+
+	pub fn observer<F: FnMut(&Event) + Send + 'static>(self, observer: F) -> Self {
+		self.then(move |sender| sender.set_observer(observer))
+	}
+
+	pub fn stats_interval(self, interval: Duration) -> Self {
+		self.then(move |sender| sender.set_stats_interval(interval))
+	}
+
+	pub fn interrupt(self, flag: Arc<AtomicBool>) -> Self {
+		self.then(move |sender| sender.set_interrupt(flag))
+	}
+
+	pub fn seed(self, seed: u64) -> Self {
+		self.then(move |sender| sender.set_seed(seed))
+	}
+
+	fn then<F>(self, setup: F) -> Self
+	where F: FnOnce(&mut Sender) + Send + 'static {
+		self.try_then(move |sender| {
+			setup(sender);
+			Ok(())
+		})
+	}
+
+	fn try_then<F>(mut self, setup: F) -> Self
+	where F: FnOnce(&mut Sender) -> Result<(), ProtoError> + Send + 'static {
+		self.setup.push(Box::new(setup));
+		self
+	}
+}
+
+impl ReceiverBuilder {
+	/// Starts a receiver which will use `key` to decrypt incoming blocks.
+	pub fn new(key: &[u8]) -> Self {
+		Self { key: key.to_vec(), opts: StreamOpts::default(), setup: vec![] }
+	}
+
+	/// Waits for the sender on `addr` & applies the options.
+	pub fn build<S: ToSocketAddrs>(self, addr: S) -> Result<Receiver, ProtoError> {
+		let receiver = Receiver::new(addr, &self.key, &self.opts)?;
+		apply(receiver, self.setup)
+	}
+
+	/// Applies the options to a receiver which runs over `transport`, the
+	/// options of the stream itself are ignored.
+	pub fn build_with_transport<T: Transport + 'static>(self, transport: T) -> Result<Receiver, ProtoError> {
+		let receiver = Receiver::with_transport(transport, &self.key)?;
+		apply(receiver, self.setup)
+	}
+
+	/// Replaces every option of the stream. (See: `StreamOpts`.)
+	pub fn stream_opts(mut self, opts: StreamOpts) -> Self {
+		self.opts = opts;
+		self
+	}
+
+	pub fn transport(mut self, transport: TransportKind) -> Self {
+		self.opts.transport = transport;
+		self
+	}
+
+	pub fn accept_timeout(mut self, timeout: Duration) -> Self {
+		self.opts.accept_timeout = Some(timeout);
+		self
+	}
+
+	pub fn cipher(self, cipher: Cipher) -> Self {
+		self.try_then(move |receiver| receiver.set_cipher(cipher))
+	}
+
+	pub fn add_key(self, key: &[u8]) -> Self {
+		let key = key.to_vec();
+		self.try_then(move |receiver| receiver.add_key(&key))
+	}
+
+	pub fn code(self, code: &str) -> Self {
+		let code = code.to_string();
+		self.then(move |receiver| receiver.set_code(&code))
+	}
+
+	pub fn identity(self, identity: Identity) -> Self {
+		self.then(move |receiver| receiver.set_identity(identity))
+	}
+
+	pub fn expected_fingerprints<S: AsRef<str>>(self, fingerprints: &[S]) -> Self {
+		let fingerprints: Vec<String> = fingerprints.iter().map(|fingerprint| fingerprint.as_ref().to_string()).collect();
+		self.then(move |receiver| receiver.set_expected_fingerprints(&fingerprints))
+	}
+
+	pub fn identity_check<F>(self, check: F) -> Self
+	where F: FnMut(Option<&str>) -> Result<(), ProtoError> + Send + 'static {
+		self.then(move |receiver| receiver.set_identity_check(check))
+	}
+
+	pub fn crypto_threads(self, threads: usize) -> Self {
+		self.then(move |receiver| receiver.set_crypto_threads(threads))
+	}
+
+	pub fn resume_timeout(self, timeout: Duration) -> Self {
+		self.then(move |receiver| receiver.set_resume_timeout(timeout))
+	}
+
+	pub fn migrate(self, migrate: bool) -> Self {
+		self.then(move |receiver| receiver.set_migrate(migrate))
+	}
+
+	pub fn checkpoint<P: Into<PathBuf>>(self, path: P) -> Self {
+		let path = path.into();
+		self.then(move |receiver| receiver.set_checkpoint(path))
+	}
+
+	pub fn output_file(self, file: &File) -> Result<Self, ProtoError> {
+		let file = file.try_clone()?;
+		Ok(self.try_then(move |receiver| receiver.set_output_file(&file)))
+	}
+
+	pub fn output_dir<P: Into<PathBuf>>(self, dir: P) -> Self {
+		let dir = dir.into();
+		self.then(move |receiver| receiver.set_output_dir(dir))
+	}
+
+	pub fn preserve(self, preserve: bool) -> Self {
+		self.then(move |receiver| receiver.set_preserve(preserve))
+	}
+
+	pub fn preserve_perms(self, preserve: bool) -> Self {
+		self.then(move |receiver| receiver.set_preserve_perms(preserve))
+	}
+
+	pub fn preserve_owner(self, preserve: bool) -> Self {
+		self.then(move |receiver| receiver.set_preserve_owner(preserve))
+	}
+
+	pub fn fsync_interval(self, interval: Option<Duration>) -> Self {
+		self.then(move |receiver| receiver.set_fsync_interval(interval))
+	}
+
+	pub fn sync_on_close(self, sync: bool) -> Self {
+		self.then(move |receiver| receiver.set_sync_on_close(sync))
+	}
+
+	pub fn quiet_threshold(self, threshold: Duration) -> Self {
+		self.then(move |receiver| receiver.set_quiet_threshold(threshold))
+	}
+
+	pub fn tee<W: Write + Send + 'static>(self, tee: W) -> Self {
+		self.then(move |receiver| receiver.add_tee(tee))
+	}
+
+	pub fn features(self, features: Features) -> Self {
+		self.then(move |receiver| receiver.set_features(features))
+	}
+
+	pub fn observer<F: FnMut(&Event) + Send + 'static>(self, observer: F) -> Self {
+		self.then(move |receiver| receiver.set_observer(observer))
+	}
+
+	pub fn stats_interval(self, interval: Duration) -> Self {
+		self.then(move |receiver| receiver.set_stats_interval(interval))
+	}
+
+	pub fn interrupt(self, flag: Arc<AtomicBool>) -> Self {
+		self.then(move |receiver| receiver.set_interrupt(flag))
+	}
+
+	pub fn seed(self, seed: u64) -> Self {
+		self.then(move |receiver| receiver.set_seed(seed))
+	}
+
+	fn then<F>(self, setup: F) -> Self
+	where F: FnOnce(&mut Receiver) + Send + 'static {
+		self.try_then(move |receiver| {
+			setup(receiver);
+			Ok(())
+		})
+	}
+
+	fn try_then<F>(mut self, setup: F) -> Self
+	where F: FnOnce(&mut Receiver) -> Result<(), ProtoError> + Send + 'static {
+		self.setup.push(Box::new(setup));
+		self
+	}
+}
+
+/// Applies each of the `setup` steps to `peer` in the order they were given.
+fn apply<T>(mut peer: T, setup: Vec<Setup<T>>) -> Result<T, ProtoError> {
+	for step in setup {
+		step(&mut peer)?;
+	}
+
+	Ok(peer)
+}
@@ -0,0 +1,24 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A handle which cancels a running `Sender` or `Receiver` from another thread.
+///
+/// Tokens are cheap to clone, every clone cancels the same session. The state
+/// machine notices at the next block boundary, tells the peer the transfer was
+/// aborted (if it can), and stops with `ProtoError::Cancelled`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+	flag: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+	/// Asks the session to stop at the next block boundary.
+	pub fn cancel(&self) {
+		self.flag.store(true, Ordering::SeqCst);
+	}
+
+	/// Returns true once `cancel()` has been called on any clone of this token.
+	pub fn is_cancelled(&self) -> bool {
+		self.flag.load(Ordering::SeqCst)
+	}
+}
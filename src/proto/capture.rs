@@ -0,0 +1,386 @@
+use crate::error::ProtoError;
+use crate::key;
+use crate::proto::cipher::CIPHER_AUTO;
+use crate::proto::banner::{self, BANNER_LEN, BANNER_MAGIC};
+use crate::proto::resume::{self, TOKEN_LEN};
+use crate::proto::session::SESSION_ID_LEN;
+use crate::proto::{padding, util};
+use crate::proto::{Cipher, FaultCode, Features, MessageTy, ACK_LEN, HEADER_MAGIC, MESSAGE_SIZE, PROTOCOL_VERSION, REKEY_SALT_LEN};
+
+use byteorder::{ByteOrder, NetworkEndian};
+use ring::aead::{self, OpeningKey};
+use std::io::{self, Cursor, Read, Write};
+use std::mem;
+
+/// How many bytes of each payload are shown, unless told otherwise.
+pub const DEFAULT_PREVIEW: usize = 32;
+
+/// A `CaptureDecoder` walks a recorded byte stream of the protocol (i.e:
+/// everything one peer wrote to the other) and describes each message it
+/// finds, for diagnosing interop problems between versions.
+///
+/// Every header is shown, even those of another version or of an unknown
+/// type. Given the key the sealed payloads are opened & previewed as well,
+/// which needs the IV the receiver chose: it is picked up from the `RepIV` in
+/// the receiver's capture, so that capture should be decoded first (by the
+/// same decoder) unless the IV is set. A session which rotates its key is
+/// followed through each `ReKey`, but one whose key was agreed w/ a code
+/// cannot be opened. Nor can what follows the `Identity`s of peers which
+/// exchanged them, since the session key is then mixed w/ a secret only the
+/// peers know. (See: `identity::Ephemeral`.) The receiver's replies are opened
+/// w/ the key derived from the session's id, which is picked up from either
+/// peer's `Hello`.
+///
+pub struct CaptureDecoder {
+	key: Option<Vec<u8>>,
+	session_key: Option<Vec<u8>>,
+	reply_key: Option<Vec<u8>>,
+
+	/// Set once the peers exchanged identities, after which the session key
+	/// is not known.
+	mixed: bool,
+	cipher: Option<Cipher>,
+	iv: Option<u32>,
+	preview: usize,
+}
+
+impl Default for CaptureDecoder {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl CaptureDecoder {
+	/// Creates a decoder which only describes the headers & plaintext.
+	pub fn new() -> Self {
+		Self { key: None, session_key: None, reply_key: None, mixed: false, cipher: None, iv: None, preview: DEFAULT_PREVIEW }
+	}
+
+	/// Opens sealed payloads w/ `key`, the session's pre-shared key.
+	pub fn set_key(&mut self, key: &[u8]) -> Result<(), ProtoError> {
+		Cipher::for_key(key)?;
+		self.key = Some(key.to_vec());
+		self.session_key = Some(key.to_vec());
+		Ok(())
+	}
+
+	/// Sets the cipher the session used, otherwise it is picked up from the
+	/// capture or each suite which fits the key is tried.
+	pub fn set_cipher(&mut self, cipher: Cipher) {
+		self.cipher = Some(cipher);
+	}
+
+	/// Sets the IV the receiver chose, for a capture w/o its `RepIV`.
+	pub fn set_iv(&mut self, iv: u32) {
+		self.iv = Some(iv);
+	}
+
+	/// Sets how many bytes of each payload are shown.
+	pub fn set_preview(&mut self, preview: usize) {
+		self.preview = preview;
+	}
+
+	/// Describes each message in `capture` to `out`, returning how many were
+	/// found. A capture which is cut short, or which stops making sense, is
+	/// described up to that point.
+	pub fn decode<R: Read, W: Write>(&mut self, capture: R, out: &mut W) -> Result<u64, ProtoError> {
+		let mut offset = 0;
+		let mut count = 0;
+
+		// a capture taken from the start of the connection opens w/ the banner,
+		// otherwise what was read is the start of the first header
+		let mut banner = [0u8; BANNER_LEN];
+		let mut capture = capture;
+		let len = read_full(&mut capture, &mut banner)?;
+		let unread = match banner::check(&banner[..len]) {
+			Ok(version) => {
+				write!(out, "{:>10}  {:<10} {}", offset, "banner", String::from_utf8_lossy(&BANNER_MAGIC))?;
+				if version != PROTOCOL_VERSION {
+					write!(out, " (version {}, not {})", version, PROTOCOL_VERSION)?;
+				}
+
+				writeln!(out)?;
+				offset += BANNER_LEN as u64;
+				vec![]
+			},
+
+			Err(_) => banner[..len].to_vec(),
+		};
+
+		let mut capture = Cursor::new(unread).chain(capture);
+
+		loop {
+			let mut header = [0u8; MESSAGE_SIZE];
+			match read_full(&mut capture, &mut header)? {
+				0 => break,
+				MESSAGE_SIZE => {},
+				len => {
+					writeln!(out, "{:>10}  header is cut short ({} of {} bytes)", offset, len, MESSAGE_SIZE)?;
+					break;
+				},
+			}
+
+			if NetworkEndian::read_u32(&header[0..4]) != HEADER_MAGIC {
+				writeln!(out, "{:>10}  not a message header, cannot follow the capture past here: {}", offset, hex(&header))?;
+				break;
+			}
+
+			let version = header[4];
+			let ty = MessageTy::from_u8(header[5]);
+			let len = NetworkEndian::read_u32(&header[6..10]) as usize;
+			let seq = NetworkEndian::read_u64(&header[10..18]);
+
+			let name = ty.map_or_else(|| format!("#{}", header[5]), |ty| format!("{:?}", ty));
+			write!(out, "{:>10}  {:<10} seq {:<8} len {}", offset, name, seq, len)?;
+			if version != PROTOCOL_VERSION {
+				write!(out, " (version {}, not {})", version, PROTOCOL_VERSION)?;
+			}
+
+			writeln!(out)?;
+			count += 1;
+
+			// the payload is read in pieces, so that a bogus length cannot make
+			// us allocate all of it up front
+			let mut payload = vec![];
+			(&mut capture).take(len as u64).read_to_end(&mut payload)?;
+			if payload.len() < len {
+				writeln!(out, "{:>10}  payload is cut short ({} of {} bytes)", "", payload.len(), len)?;
+				break;
+			}
+
+			match ty {
+				Some(ty) if version == PROTOCOL_VERSION => self.describe(out, ty, &header, seq, payload)?,
+				_ => self.show(out, "payload", &payload)?,
+			}
+
+			offset += (MESSAGE_SIZE + len) as u64;
+		}
+
+		Ok(count)
+	}
+
+	fn describe<W: Write>(&mut self, out: &mut W, ty: MessageTy, header: &[u8], seq: u64, payload: Vec<u8>) -> Result<(), ProtoError> {
+		// plaintext messages use zero, every sealed message is numbered
+		if seq == 0 {
+			return self.describe_plain(out, ty, &payload);
+		}
+
+		let opened = match ty {
+			MessageTy::Nack | MessageTy::Ack | MessageTy::Token => self.open_reply(header, ty, seq, payload.clone()),
+			_ => self.open(header, seq, payload.clone()),
+		};
+
+		let opened = match opened {
+			Ok(opened) => opened,
+			Err(reason) => return self.show(out, &format!("sealed ({})", reason), &payload),
+		};
+
+		match ty {
+			MessageTy::Hello if opened.len() >= mem::size_of::<u32>() + SESSION_ID_LEN => {
+				let (magic, rest) = opened.split_at(mem::size_of::<u32>());
+				let (session_id, features) = rest.split_at(SESSION_ID_LEN);
+				write!(out, "{:>10}  magic {}, session {}", "", hex(magic), hex(session_id))?;
+				self.reply_key = self.key.as_ref().map(|key| util::derive_reply_key(key, session_id));
+				match Features::from_hello(features) {
+					Ok(_) if features.is_empty() => writeln!(out, ", no features")?,
+					Ok(features) => writeln!(out, ", features: {}", features)?,
+					Err(_) => writeln!(out, ", features are malformed")?,
+				}
+			},
+
+			MessageTy::Goodbye if opened.len() >= 2 * mem::size_of::<u64>() => {
+				let bytes = NetworkEndian::read_u64(&opened[0..8]);
+				let blocks = NetworkEndian::read_u64(&opened[8..16]);
+				writeln!(out, "{:>10}  {} bytes in {} blocks, digest {}", "", bytes, blocks, hex(&opened[16..]))?;
+			},
+
+			MessageTy::FileEnd
+				| MessageTy::Skip
+				| MessageTy::TotalSize if opened.len() == mem::size_of::<u64>() => {
+				writeln!(out, "{:>10}  {} bytes", "", NetworkEndian::read_u64(&opened))?;
+			},
+
+			MessageTy::ReKey if opened.len() == REKEY_SALT_LEN => {
+				writeln!(out, "{:>10}  salt {}, later messages use the key derived from it", "", hex(&opened))?;
+				self.session_key = self.key.as_ref().map(|key| util::derive_key(key, &opened));
+			},
+
+			MessageTy::Nack if opened.len() == mem::size_of::<u64>() => {
+				writeln!(out, "{:>10}  from message #{}", "", NetworkEndian::read_u64(&opened))?;
+			},
+
+			MessageTy::Ack if opened.len() == ACK_LEN => {
+				writeln!(out, "{:>10}  expects message #{}, {} bytes handled", "",
+				         NetworkEndian::read_u64(&opened[0..8]), NetworkEndian::read_u64(&opened[8..16]))?;
+			},
+
+			MessageTy::Padded => {
+				let mut data = opened;
+				match padding::unpad(&mut data) {
+					Ok(()) if data.is_empty() => writeln!(out, "{:>10}  cover block", "")?,
+					Ok(()) => self.show(out, "opened", &data)?,
+					Err(_) => writeln!(out, "{:>10}  padding is malformed", "")?,
+				}
+			},
+
+			_ => self.show(out, "opened", &opened)?,
+		}
+
+		Ok(())
+	}
+
+	fn describe_plain<W: Write>(&mut self, out: &mut W, ty: MessageTy, payload: &[u8]) -> Result<(), ProtoError> {
+		match ty {
+			// each capture opens w/ a new handshake, under the pre-shared key
+			MessageTy::ReqIV | MessageTy::RepIV => {
+				self.session_key = self.key.clone();
+				self.mixed = false;
+			},
+
+			MessageTy::Identity => {
+				self.session_key = None;
+				self.reply_key = None;
+				self.mixed = true;
+			},
+
+			_ => {},
+		}
+
+		match ty {
+			MessageTy::ReqIV if payload.len() >= 2 + key::ID_LEN => {
+				let asked = match payload[0] {
+					CIPHER_AUTO => "any cipher".to_string(),
+					id => Cipher::from_u8(id).map_or_else(|| format!("cipher #{}", id), |cipher| cipher.to_string()),
+				};
+
+				writeln!(out, "{:>10}  asks for {} (aes accelerated: {}), key {}{}",
+				         "", asked, payload[1] != 0, hex(&payload[2..2 + key::ID_LEN]),
+				         if payload.len() > 2 + key::ID_LEN { ", w/ a challenge" } else { "" })?;
+
+				if self.cipher.is_none() && payload[0] != CIPHER_AUTO {
+					self.cipher = Cipher::from_u8(payload[0]);
+				}
+			},
+
+			MessageTy::RepIV if payload.len() > mem::size_of::<u32>() => {
+				let iv = NetworkEndian::read_u32(&payload[0..4]);
+				let id = payload[4];
+				match Cipher::from_u8(id) {
+					Some(cipher) => {
+						writeln!(out, "{:>10}  iv {:08x}, chose {}", "", iv, cipher)?;
+						self.cipher = Some(cipher);
+					},
+
+					None => writeln!(out, "{:>10}  iv {:08x}, chose cipher #{}", "", iv, id)?,
+				}
+
+				self.iv = Some(iv);
+			},
+
+			MessageTy::Resume if payload.len() == resume::CHALLENGE_LEN => {
+				writeln!(out, "{:>10}  challenge {}", "", hex(payload))?;
+			},
+
+			MessageTy::Resume if payload.len() == mem::size_of::<u64>() + resume::PROOF_LEN => {
+				writeln!(out, "{:>10}  from message #{}", "", NetworkEndian::read_u64(payload))?;
+			},
+
+			MessageTy::Resume if payload.len() == TOKEN_LEN + resume::CHALLENGE_LEN + resume::PROOF_LEN => {
+				writeln!(out, "{:>10}  token, challenge {}", "", hex(&payload[TOKEN_LEN..TOKEN_LEN + resume::CHALLENGE_LEN]))?;
+			},
+
+			MessageTy::Error if !payload.is_empty() => {
+				let code = FaultCode::from_u8(payload[0]);
+				writeln!(out, "{:>10}  {}: {}", "", code, String::from_utf8_lossy(&payload[1..]))?;
+			},
+
+			_ if payload.is_empty() => {},
+			_ => self.show(out, "payload", payload)?,
+		}
+
+		Ok(())
+	}
+
+	/// Opens a sealed payload, or returns why it could not be.
+	fn open(&mut self, header: &[u8], seq: u64, payload: Vec<u8>) -> Result<Vec<u8>, &'static str> {
+		let key = self.session_key.clone().ok_or(self.unknown_key())?;
+		let iv = self.iv.ok_or("the receiver's iv is not known")?;
+
+		let mut nonce = [0u8; 12];
+		NetworkEndian::write_u32(&mut nonce[0..4], iv);
+		NetworkEndian::write_u64(&mut nonce[4..12], seq);
+
+		self.open_with(&key, &nonce, header, payload)
+	}
+
+	/// Opens the sealed payload of a reply from the receiver, or returns why
+	/// it could not be. (See: `util::reply_nonce()`.)
+	fn open_reply(&mut self, header: &[u8], ty: MessageTy, seq: u64, payload: Vec<u8>) -> Result<Vec<u8>, &'static str> {
+		let key = self.reply_key.clone().ok_or(match self.mixed {
+			true => self.unknown_key(),
+			false => "no key was given, or the session is not known",
+		})?;
+		let nonce = util::reply_nonce(ty as u8, seq);
+
+		self.open_with(&key, &nonce, header, payload)
+	}
+
+	/// Why the session key is not known.
+	fn unknown_key(&self) -> &'static str {
+		match self.mixed {
+			true => "the key was mixed w/ the peers' ephemeral secret",
+			false => "no key was given",
+		}
+	}
+
+	fn open_with(&mut self, key: &[u8], nonce: &[u8], header: &[u8], payload: Vec<u8>) -> Result<Vec<u8>, &'static str> {
+		// w/o a cipher each suite which fits the key is tried, & the first
+		// which opens anything is kept
+		let candidates = match self.cipher {
+			Some(cipher) => vec![cipher],
+			None => vec![Cipher::Aes256Gcm, Cipher::Aes128Gcm, Cipher::ChaCha20Poly1305],
+		};
+
+		for cipher in candidates {
+			if cipher.check_key(key).is_err() {
+				continue;
+			}
+
+			let dec_key = OpeningKey::new(cipher.algorithm(), key).map_err(|_| "the key was rejected")?;
+			let mut buf = payload.clone();
+			if let Ok(opened) = aead::open_in_place(&dec_key, nonce, header, 0, &mut buf) {
+				let opened = opened.to_vec();
+				self.cipher = Some(cipher);
+				return Ok(opened);
+			}
+		}
+
+		Err("could not be opened w/ the key")
+	}
+
+	fn show<W: Write>(&self, out: &mut W, label: &str, payload: &[u8]) -> Result<(), ProtoError> {
+		let shown = payload.len().min(self.preview);
+		let more = if shown < payload.len() { " ..." } else { "" };
+		writeln!(out, "{:>10}  {}: {}{}", "", label, hex(&payload[..shown]), more)?;
+		Ok(())
+	}
+}
+
+/// Fills as much of `buf` as `capture` has left, returning how much that was.
+fn read_full<R: Read>(capture: &mut R, buf: &mut [u8]) -> Result<usize, io::Error> {
+	let mut len = 0;
+	while len < buf.len() {
+		match capture.read(&mut buf[len..]) {
+			Ok(0) => break,
+			Ok(read) => len += read,
+			Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+			Err(err) => return Err(err),
+		}
+	}
+
+	Ok(len)
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A range of addresses a listening peer accepts connections from, written
+/// as an address & prefix length. (i.e: `10.0.0.0/8` or `fd00::/8`.) An
+/// address w/o a prefix length is a range of just that address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+	addr: IpAddr,
+	prefix: u8,
+}
+
+impl Cidr {
+	/// Returns true if `addr` falls within this range. An IPv4 address mapped
+	/// into IPv6 is matched as the IPv4 address it carries.
+	pub fn contains(&self, addr: IpAddr) -> bool {
+		let addr = match addr {
+			IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+			v4 => v4,
+		};
+
+		match (self.addr, addr) {
+			(IpAddr::V4(net), IpAddr::V4(addr)) => {
+				prefix_matches(&net.octets(), &addr.octets(), self.prefix)
+			},
+
+			(IpAddr::V6(net), IpAddr::V6(addr)) => {
+				prefix_matches(&net.octets(), &addr.octets(), self.prefix)
+			},
+
+			_ => false,
+		}
+	}
+
+	/// Returns true if `addr` falls within any of the `ranges`, or if there
+	/// are no ranges at all.
+	pub fn allows(ranges: &[Cidr], addr: IpAddr) -> bool {
+		ranges.is_empty() || ranges.iter().any(|range| range.contains(addr))
+	}
+}
+
+impl FromStr for Cidr {
+	type Err = String;
+
+	fn from_str(text: &str) -> Result<Self, Self::Err> {
+		let text = text.trim();
+		let (addr, prefix) = match text.split_once('/') {
+			Some((addr, prefix)) => (addr, Some(prefix)),
+			None => (text, None),
+		};
+
+		let addr: IpAddr = addr.parse()
+			.map_err(|_| format!("invalid address: {}", addr))?;
+
+		let max = if addr.is_ipv4() { 32 } else { 128 };
+		let prefix = match prefix {
+			Some(prefix) => prefix.parse::<u8>().ok()
+				.filter(|&prefix| prefix <= max)
+				.ok_or_else(|| format!("invalid prefix length: {}", prefix))?,
+
+			None => max,
+		};
+
+		Ok(Cidr { addr, prefix })
+	}
+}
+
+impl fmt::Display for Cidr {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}/{}", self.addr, self.prefix)
+	}
+}
+
+/// Compares the first `prefix` bits of two addresses.
+fn prefix_matches(net: &[u8], addr: &[u8], prefix: u8) -> bool {
+	let whole = prefix as usize / 8;
+	if net[..whole] != addr[..whole] {
+		return false;
+	}
+
+	let bits = prefix % 8;
+	if bits == 0 {
+		return true;
+	}
+
+	let mask = 0xffu8 << (8 - bits);
+	net[whole] & mask == addr[whole] & mask
+}
@@ -0,0 +1,125 @@
+use crate::error::ProtoError;
+
+use ring::aead;
+use std::fmt;
+
+/// Sent in place of a cipher's id when the sender leaves the choice to the
+/// receiver. (See: `MessageTy::ReqIV`.)
+pub const CIPHER_AUTO: u8 = 0xff;
+
+/// The AEAD suite which seals every encrypted message of a session.
+///
+/// The peers agree on a suite during the handshake, its discriminant is the
+/// byte carried there so existing variants must never be renumbered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cipher {
+	/// AES-256 in Galois/Counter Mode, used w/ a 32-byte key.
+	Aes256Gcm = 0,
+
+	/// AES-128 in Galois/Counter Mode, used w/ a 16-byte key. This is cheaper
+	/// than AES-256 on cores w/o much help for AES. (e.g: low-power ARM.)
+	Aes128Gcm = 1,
+
+	/// ChaCha20-Poly1305, used w/ a 32-byte key. This is much quicker than AES
+	/// on a core which cannot accelerate AES & GHASH.
+	ChaCha20Poly1305 = 2,
+}
+
+impl Cipher {
+	/// Returns the suite which uses a key of `key.len()` bytes, absent any
+	/// other preference.
+	pub fn for_key(key: &[u8]) -> Result<Self, ProtoError> {
+		match key.len() {
+			32 => Ok(Cipher::Aes256Gcm),
+			16 => Ok(Cipher::Aes128Gcm),
+			len => Err(ProtoError::InvalidKeyLength { len }),
+		}
+	}
+
+	/// Returns the suite identified by `id` on the wire, if it is one we know.
+	pub fn from_u8(id: u8) -> Option<Self> {
+		match id {
+			0 => Some(Cipher::Aes256Gcm),
+			1 => Some(Cipher::Aes128Gcm),
+			2 => Some(Cipher::ChaCha20Poly1305),
+			_ => None,
+		}
+	}
+
+	pub fn algorithm(self) -> &'static aead::Algorithm {
+		match self {
+			Cipher::Aes256Gcm => &aead::AES_256_GCM,
+			Cipher::Aes128Gcm => &aead::AES_128_GCM,
+			Cipher::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+		}
+	}
+
+	/// Fails unless this suite can be used w/ `key`.
+	pub fn check_key(self, key: &[u8]) -> Result<(), ProtoError> {
+		if key.len() != self.algorithm().key_len() {
+			return Err(ProtoError::KeyLengthMismatch { cipher: self, len: key.len() });
+		}
+
+		Ok(())
+	}
+
+	/// Returns true if this machine can accelerate AES-GCM in hardware.
+	/// (i.e: AES-NI & CLMUL on x86, or the AES & PMULL extensions on ARM.)
+	pub fn aes_accelerated() -> bool {
+		#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+		{
+			is_x86_feature_detected!("aes") && is_x86_feature_detected!("pclmulqdq")
+		}
+
+		#[cfg(target_arch = "aarch64")]
+		{
+			std::arch::is_aarch64_feature_detected!("aes") && std::arch::is_aarch64_feature_detected!("pmull")
+		}
+
+		#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+		{
+			false
+		}
+	}
+}
+
+/// Returns the suite a peer asks for: the one it was told to use, otherwise
+/// the only one which fits its key. `None` leaves the choice to `choose()`.
+pub fn preference(forced: Option<Cipher>, key: &[u8]) -> Option<Cipher> {
+	match forced {
+		None if key.len() == 16 => Some(Cipher::Aes128Gcm),
+		forced => forced,
+	}
+}
+
+/// Chooses the suite for a session from what the sender (`theirs`) & the
+/// receiver (`ours`) asked for. When neither asks for one, AES-256-GCM is
+/// chosen only if both ends can accelerate it, and ChaCha20-Poly1305
+/// otherwise.
+pub fn choose(ours: Option<Cipher>, theirs: Option<Cipher>, accelerated: bool, key: &[u8]) -> Result<Cipher, ProtoError> {
+	let cipher = match (ours, theirs) {
+		(Some(ours), Some(theirs)) if ours != theirs => {
+			return Err(ProtoError::CipherMismatch { ours, theirs });
+		},
+
+		(Some(cipher), _) | (None, Some(cipher)) => cipher,
+		(None, None) if accelerated && Cipher::aes_accelerated() => Cipher::Aes256Gcm,
+		(None, None) => Cipher::ChaCha20Poly1305,
+	};
+
+	if cipher.check_key(key).is_err() {
+		return Err(ProtoError::CipherMismatch { ours: Cipher::for_key(key)?, theirs: cipher });
+	}
+
+	Ok(cipher)
+}
+
+impl fmt::Display for Cipher {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Cipher::Aes256Gcm => write!(f, "AES-256-GCM"),
+			Cipher::Aes128Gcm => write!(f, "AES-128-GCM"),
+			Cipher::ChaCha20Poly1305 => write!(f, "ChaCha20-Poly1305"),
+		}
+	}
+}
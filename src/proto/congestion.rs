@@ -0,0 +1,147 @@
+use crate::proto::udp::WINDOW;
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The congestion window an `Aimd` controller starts with, and the smallest
+/// it shrinks to.
+const INITIAL_CWND: f64 = 16.0;
+const MIN_CWND: f64 = 4.0;
+
+/// How long after a loss an `Aimd` controller ignores further losses, until
+/// a round-trip time has been measured.
+const INITIAL_RTT: Duration = Duration::from_millis(250);
+
+/// Decides how many packets a transport may have in flight.
+///
+/// The transport owns everything else (sequencing, acks & retransmission),
+/// it only asks its controller how large the window is and tells it when
+/// packets are acknowledged or lost. The window is clamped to what the
+/// transport can track (or over UDT, to the receiver's window), so a
+/// controller may simply return `usize::MAX` to never hold a write back.
+/// (See: `Congestion`.)
+pub trait Controller: Send {
+	/// The packets which may be unacknowledged before a write blocks.
+	fn window(&self) -> usize;
+
+	/// `acked` packets were acknowledged by the peer. `rtt` is the smoothed
+	/// round-trip time, once one has been measured.
+	fn on_ack(&mut self, acked: usize, rtt: Option<Duration>);
+
+	/// `lost` packets went unacknowledged for too long & are being sent
+	/// again. The udp transport calls this at most once per retransmission
+	/// tick, UDT once for each loss report (or timeout.)
+	fn on_loss(&mut self, lost: usize, rtt: Option<Duration>);
+}
+
+/// Builds a fresh `Controller` for each link. (See: `Congestion::Custom`.)
+pub type ControllerFactory = Arc<dyn Fn() -> Box<dyn Controller> + Send + Sync>;
+
+/// How a transport responds to packets being lost.
+///
+/// Over UDT `Adaptive` is UDT's own congestion control, which the others
+/// replace on ubuffer's own UDT. The `udt-sys` bindings do not expose UDT's
+/// pluggable congestion control, so a build w/ them fails a UDT session
+/// asked to use anything but `Adaptive` w/ `ProtoError::InvalidArgument`.
+#[derive(Clone, Default)]
+pub enum Congestion {
+	/// Back off when packets are lost, so the link is shared fairly w/ other
+	/// traffic. (The default, See: `Aimd`.)
+	#[default]
+	Adaptive,
+
+	/// Send at the full window regardless of loss, only resending what was
+	/// lost. This is for dedicated links, where backing off just leaves the
+	/// link idle. (See: `FixedWindow`.)
+	Fixed,
+
+	/// A controller supplied by the caller, one is built for every link.
+	Custom(ControllerFactory),
+}
+
+impl Congestion {
+	/// Builds the controller for a new link.
+	pub fn controller(&self) -> Box<dyn Controller> {
+		match self {
+			Congestion::Adaptive => Box::new(Aimd::default()),
+			Congestion::Fixed => Box::new(FixedWindow(usize::MAX)),
+			Congestion::Custom(factory) => factory(),
+		}
+	}
+
+	/// Returns true for the default, which stands for UDT's own congestion
+	/// control in a UDT session. (The only choice the `udt-sys` build takes.)
+	pub fn is_adaptive(&self) -> bool {
+		matches!(self, Congestion::Adaptive)
+	}
+}
+
+impl fmt::Debug for Congestion {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Congestion::Adaptive => write!(f, "Adaptive"),
+			Congestion::Fixed => write!(f, "Fixed"),
+			Congestion::Custom(_) => write!(f, "Custom"),
+		}
+	}
+}
+
+/// Additive increase, multiplicative decrease: the window grows by one per
+/// ack until `ssthresh` and by one per round trip after, and halves on a
+/// loss at most once per round trip.
+#[derive(Debug)]
+pub struct Aimd {
+	cwnd: f64,
+	ssthresh: f64,
+	last_loss: Option<Instant>,
+}
+
+impl Default for Aimd {
+	fn default() -> Self {
+		Self { cwnd: INITIAL_CWND, ssthresh: WINDOW as f64, last_loss: None }
+	}
+}
+
+impl Controller for Aimd {
+	fn window(&self) -> usize {
+		self.cwnd as usize
+	}
+
+	fn on_ack(&mut self, acked: usize, _rtt: Option<Duration>) {
+		for _ in 0..acked {
+			self.cwnd += match self.cwnd < self.ssthresh {
+				true => 1.0,
+				false => 1.0 / self.cwnd,
+			};
+		}
+
+		self.cwnd = self.cwnd.min(WINDOW as f64);
+	}
+
+	fn on_loss(&mut self, _lost: usize, rtt: Option<Duration>) {
+		let now = Instant::now();
+		let rtt = rtt.unwrap_or(INITIAL_RTT);
+		let recovering = self.last_loss.is_some_and(|last_loss| now - last_loss < rtt);
+		if recovering { return; }
+
+		self.ssthresh = (self.cwnd / 2.0).max(MIN_CWND);
+		self.cwnd = self.ssthresh;
+		self.last_loss = Some(now);
+		debug!("udp loss detected, window is now {:.0} packets", self.cwnd);
+	}
+}
+
+/// A window which never changes, lost packets are only sent again.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedWindow(pub usize);
+
+impl Controller for FixedWindow {
+	fn window(&self) -> usize {
+		self.0
+	}
+
+	fn on_ack(&mut self, _acked: usize, _rtt: Option<Duration>) {}
+
+	fn on_loss(&mut self, _lost: usize, _rtt: Option<Duration>) {}
+}
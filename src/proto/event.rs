@@ -0,0 +1,163 @@
+use crate::proto::Summary;
+use std::sync::mpsc;
+
+/// Notable points in the lifecycle of a session.
+///
+/// Events are handed to the observer registered with `set_observer()` (or
+/// sent to the channel returned by `subscribe()`) as
+/// the `Sender` or `Receiver` state machines make progress. They serialize
+/// to a flat JSON object tagged with an `event` field.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+	/// The IV & `Hello` exchange succeeded and blocks may now flow. Both
+	/// peers report the same `session_id`, and the same `fingerprint` unless
+	/// someone is in the middle of the session. (See: `SessionId`.)
+	HandshakeComplete {
+		session_id: String,
+		fingerprint: String,
+	},
+
+	/// The sender described the file it is sending.
+	Metadata {
+		name: String,
+		mode: u32,
+		mtime: u64,
+	},
+
+	/// The sender announced how many bytes its input is, or this sender
+	/// announced it to the receiver.
+	TotalSize {
+		bytes: u64,
+	},
+
+	/// A file in a multi-file session was started.
+	FileStart {
+		name: String,
+		mode: u32,
+		mtime: u64,
+	},
+
+	/// A file in a multi-file session was completed.
+	FileEnd {
+		name: String,
+		bytes: u64,
+	},
+
+	/// A block was sealed & sent, or received & opened.
+	Block {
+		plaintext_len: usize,
+		ciphertext_len: usize,
+		total_bytes: u64,
+	},
+
+	/// A periodic sample of the transfer's progress. (See: `set_stats_interval()`.)
+	Stats {
+		/// The plaintext bytes transferred so far.
+		total_bytes: u64,
+
+		/// The plaintext bytes the sender announced, if it did.
+		expected_bytes: Option<u64>,
+
+		/// The time since the previous sample.
+		interval_secs: f64,
+
+		/// The plaintext throughput since the previous sample.
+		throughput_bps: f64,
+
+		/// The connection's queues, if the transport reports them.
+		/// (See: `LinkStats`.)
+		send_queue: Option<u64>,
+		recv_queue: Option<u64>,
+
+		/// The round-trip time to the peer, and the packets lost so far, as
+		/// measured by the transport if it does.
+		rtt_ms: Option<f64>,
+		lost_packets: Option<u64>,
+	},
+
+	/// A hole in the input was skipped over rather than sent as a block.
+	Skip {
+		len: u64,
+		total_bytes: u64,
+	},
+
+	/// The block w/ this sequence number could not be opened by the receiver,
+	/// so it was asked for (or by the sender, resent) along w/ those after it.
+	Nack {
+		seq: u64,
+	},
+
+	/// The receiver acknowledged every message before the one w/ this
+	/// sequence number, having handled `total_bytes` of plaintext by then.
+	Ack {
+		seq: u64,
+		total_bytes: u64,
+	},
+
+	/// The connection dropped & the session was resumed over a new one, the
+	/// sender carried on from the message w/ this sequence number.
+	Resumed {
+		seq: u64,
+	},
+
+	/// The receiver has not heard from the sender for this long, which is
+	/// another multiple of its quiet threshold. (See: `set_quiet_threshold()`.)
+	Quiet {
+		idle_secs: f64,
+	},
+
+	/// The receiver heard from the sender again, after it was quiet for this
+	/// long.
+	Heard {
+		idle_secs: f64,
+	},
+
+	/// The peers switched to a freshly derived sub-key.
+	#[serde(rename = "rekey")]
+	ReKey {
+		epoch: u64,
+	},
+
+	/// The session was cut short by an `Abort` message.
+	Abort,
+
+	/// The closing handshake completed successfully.
+	Goodbye,
+
+	/// The session has ended, successfully or not. This is always the last
+	/// event and carries the final tally of the transfer. (See: `Summary`.)
+	Finished {
+		plaintext_bytes: u64,
+		ciphertext_bytes: u64,
+		blocks: u64,
+		elapsed_secs: f64,
+		throughput_bps: f64,
+	},
+}
+
+/// A callback which is invoked for each `Event` in a session.
+pub type Observer = Box<dyn FnMut(&Event) + Send>;
+
+impl<'a> From<&'a Summary> for Event {
+	fn from(summary: &'a Summary) -> Self {
+		Event::Finished {
+			plaintext_bytes: summary.plaintext_bytes,
+			ciphertext_bytes: summary.ciphertext_bytes,
+			blocks: summary.blocks,
+			elapsed_secs: summary.elapsed_secs(),
+			throughput_bps: summary.throughput(),
+		}
+	}
+}
+
+/// Returns an `Observer` which forwards a copy of each event to the returned
+/// channel. Events are dropped once the channel's receiver hangs up.
+pub(crate) fn channel() -> (Observer, mpsc::Receiver<Event>) {
+	let (tx, rx) = mpsc::channel();
+	let observer = Box::new(move |event: &Event| {
+		let _ = tx.send(event.clone());
+	});
+
+	(observer, rx)
+}
@@ -0,0 +1,118 @@
+use crate::error::ProtoError;
+use crate::proto::{Sender, BLOCK_SIZE};
+
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+/// The chunks which may be queued for each `Sender` before the input stops
+/// being read, so that one slow receiver cannot buffer the whole input.
+const QUEUE_DEPTH: usize = 16;
+
+/// The `FanOut` sends one input to several receivers at once.
+///
+/// Each `Sender` performs its own handshake and encrypts w/ its own session
+/// parameters, but the input is only read once: every chunk read from it is
+/// handed to all of the senders. The input is read as fast as the slowest
+/// receiver accepts it. A receiver which fails is dropped from the set, the
+/// others carry on without it.
+///
+pub struct FanOut {
+	senders: Vec<Sender>,
+}
+
+type Chunk = io::Result<Arc<Vec<u8>>>;
+
+impl FanOut {
+	/// Creates a `FanOut` from senders which are already connected to their
+	/// receivers, and configured as they should be. (e.g: w/ an observer.)
+	pub fn new(senders: Vec<Sender>) -> Self {
+		Self { senders }
+	}
+
+	/// The senders, in the order they were given.
+	pub fn senders(&self) -> &[Sender] {
+		&self.senders
+	}
+
+	/// Sends `input` to every receiver, returning how each session ended in
+	/// the order the senders were given.
+	pub fn run<R: Read + Send>(&mut self, mut input: R) -> Vec<Result<(), ProtoError>> {
+		let (taps, queues): (Vec<_>, Vec<_>) = self.senders.iter()
+			.map(|_| {
+				let (tx, rx) = mpsc::sync_channel(QUEUE_DEPTH);
+				(Tap { rx, chunk: Arc::new(vec![]), pos: 0 }, tx)
+			})
+			.unzip();
+
+		thread::scope(|scope| {
+			let sessions: Vec<_> = self.senders.iter_mut().zip(taps)
+				.map(|(sender, tap)| scope.spawn(move || sender.run(tap)))
+				.collect();
+
+			scope.spawn(move || distribute(&mut input, queues));
+
+			sessions.into_iter()
+				.map(|session| session.join().expect("fan-out sender panicked"))
+				.collect()
+		})
+	}
+}
+
+/// Reads `input` until it is exhausted, or until none of the `queues` have a
+/// sender left to read them.
+fn distribute<R: Read>(input: &mut R, mut queues: Vec<SyncSender<Chunk>>) {
+	loop {
+		let mut buf = vec![0u8; BLOCK_SIZE];
+		let chunk = match input.read(&mut buf) {
+			Ok(0) => return,
+			Ok(len) => {
+				buf.truncate(len);
+				Arc::new(buf)
+			},
+
+			Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+			Err(err) => {
+				for queue in &queues {
+					let _ = queue.send(Err(io::Error::new(err.kind(), err.to_string())));
+				}
+
+				return;
+			},
+		};
+
+		// a sender which hung up has dropped its end of the queue
+		queues.retain(|queue| queue.send(Ok(Arc::clone(&chunk))).is_ok());
+		if queues.is_empty() { return; }
+	}
+}
+
+/// One sender's view of the shared input.
+struct Tap {
+	rx: Receiver<Chunk>,
+	chunk: Arc<Vec<u8>>,
+	pos: usize,
+}
+
+impl Read for Tap {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+		if self.pos == self.chunk.len() {
+			match self.rx.recv() {
+				Ok(chunk) => {
+					self.chunk = chunk?;
+					self.pos = 0;
+				},
+
+				// the input has been exhausted
+				Err(_) => return Ok(0),
+			}
+		}
+
+		let len = buf.len().min(self.chunk.len() - self.pos);
+		buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+		self.pos += len;
+
+		Ok(len)
+	}
+}
@@ -0,0 +1,148 @@
+use crate::error::ProtoError;
+use crate::proto::{Message, MessageTy};
+
+use std::fmt;
+use std::io::{Read, Write};
+
+/// The longest explanation a `MessageTy::Error` carries, longer ones are cut
+/// short.
+pub const FAULT_MESSAGE_LEN: usize = 512;
+
+/// What kind of failure a peer reports in a `MessageTy::Error`, so that the
+/// other side can exit w/ a status which says what went wrong.
+///
+/// Its discriminant is the code carried on the wire, so existing variants
+/// must never be renumbered. A code this version does not know is `Other`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultCode {
+	/// Anything not covered below.
+	Other = 0,
+
+	/// A message could not be opened, most likely the keys do not match.
+	Crypto = 1,
+
+	/// The other side (i.e: us) sent something it should not have.
+	Protocol = 2,
+
+	/// The peer could not read its input or write its output. (e.g: its disk
+	/// is full.)
+	Io = 3,
+}
+
+impl FaultCode {
+	/// Returns the code identified by `code` on the wire.
+	pub fn from_u8(code: u8) -> Self {
+		match code {
+			1 => FaultCode::Crypto,
+			2 => FaultCode::Protocol,
+			3 => FaultCode::Io,
+			_ => FaultCode::Other,
+		}
+	}
+
+	/// Returns the code which describes `err` to the peer, or `None` if it
+	/// should not be reported at all: the connection is gone, the peer already
+	/// knows (i.e: it failed first, or we aborted), or it is none of its business.
+	pub fn of(err: &ProtoError) -> Option<Self> {
+		let code = match err {
+			_ if err.is_hangup() => return None,
+
+			ProtoError::Interrupted
+				| ProtoError::Cancelled
+				| ProtoError::PeerAborted
+				| ProtoError::PeerFailed { .. } => return None,
+
+			ProtoError::CryptoErr
+				| ProtoError::BlockLost { .. } => FaultCode::Crypto,
+
+			ProtoError::UnexpectedMessage
+				| ProtoError::UnknownMessage { .. }
+				| ProtoError::UnsupportedFeature { .. }
+				| ProtoError::MalformedMessage
+				| ProtoError::OversizedBlock { .. }
+				| ProtoError::ReplayOrReorder { .. }
+				| ProtoError::UnsafeFileName { .. }
+				| ProtoError::FileExists { .. }
+				| ProtoError::FileLengthMismatch { .. }
+				| ProtoError::TotalsMismatch { .. }
+				| ProtoError::SizeMismatch { .. }
+				| ProtoError::DigestMismatch
+				| ProtoError::ReceiptMismatch
+				| ProtoError::SerializeErr { .. } => FaultCode::Protocol,
+
+			ProtoError::IoErr { .. }
+				| ProtoError::NoSpace { .. }
+				| ProtoError::NoOutputDir => FaultCode::Io,
+
+			_ => FaultCode::Other,
+		};
+
+		Some(code)
+	}
+}
+
+impl fmt::Display for FaultCode {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			FaultCode::Other => write!(f, "error"),
+			FaultCode::Crypto => write!(f, "crypto error"),
+			FaultCode::Protocol => write!(f, "protocol error"),
+			FaultCode::Io => write!(f, "i/o error"),
+		}
+	}
+}
+
+/// Tells the peer why the session is about to fail, if it should be told.
+/// This is best effort, the connection may already be unusable.
+pub(super) fn report<W: Write + ?Sized>(stream: &mut W, err: &ProtoError) {
+	let code = match FaultCode::of(err) {
+		Some(code) => code,
+		None => return,
+	};
+
+	let mut message = err.to_string();
+	if message.len() > FAULT_MESSAGE_LEN {
+		let mut end = FAULT_MESSAGE_LEN;
+		while !message.is_char_boundary(end) {
+			end -= 1;
+		}
+
+		message.truncate(end);
+	}
+
+	let mut payload = vec![code as u8];
+	payload.extend_from_slice(message.as_bytes());
+
+	let fault_msg = Message {
+		ty: MessageTy::Error,
+		len: payload.len(),
+		seq: 0,
+	};
+
+	let sent = stream.write_all(&fault_msg.encode())
+		.and_then(|_| stream.write_all(&payload))
+		.and_then(|_| stream.flush());
+
+	match sent {
+		Ok(()) => debug!("told the peer about the {}: {}", code, message),
+		Err(send_err) => debug!("could not tell the peer about the {}: {}", code, send_err),
+	}
+}
+
+/// Reads the payload of the `MessageTy::Error` which `fault_msg` heads, and
+/// returns it as the peer's failure.
+pub(super) fn read<R: Read + ?Sized>(stream: &mut R, fault_msg: &Message) -> ProtoError {
+	if fault_msg.len == 0 {
+		return ProtoError::MalformedMessage;
+	}
+
+	let mut payload = vec![0u8; fault_msg.len];
+	if let Err(err) = stream.read_exact(&mut payload) {
+		return err.into();
+	}
+
+	ProtoError::PeerFailed {
+		code: FaultCode::from_u8(payload[0]),
+		message: String::from_utf8_lossy(&payload[1..]).into_owned(),
+	}
+}
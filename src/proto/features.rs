@@ -0,0 +1,124 @@
+use crate::error::ProtoError;
+
+use byteorder::{ByteOrder, NetworkEndian};
+use std::fmt;
+use std::ops::BitAnd;
+
+/// The length of the bitmask a `MessageTy::Hello` carries after the session's id.
+pub const FEATURES_LEN: usize = 4;
+
+/// The optional parts of the protocol a peer understands, which each peer
+/// advertises in its `MessageTy::Hello`.
+///
+/// A peer only uses a feature once both have advertised it, so that peers of
+/// different versions fall back to what they have in common. A `Hello` w/o
+/// the bitmask comes from a version which predates it, which is taken to
+/// understand the features that version had. (See: `Features::LEGACY`.)
+///
+/// Its bits are carried on the wire, so existing features must never be
+/// renumbered. Bits which this version does not know are ignored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Features(u32);
+
+impl Features {
+	/// The sender may compress its blocks. This is reserved: no version of
+	/// ubuffer compresses yet, so it is never advertised.
+	pub const COMPRESSION: Features = Features(1 << 0);
+
+	/// The receiver may issue a `MessageTy::Token` so that the sender can
+	/// resume the session on a new connection.
+	pub const RESUME: Features = Features(1 << 1);
+
+	/// The sender may pad its blocks w/ `MessageTy::Padded`.
+	pub const PADDING: Features = Features(1 << 2);
+
+	/// The sender may send a `MessageTy::Ping` while its input is idle.
+	pub const HEARTBEATS: Features = Features(1 << 3);
+
+	/// The sender may frame several files w/ `MessageTy::FileStart` &
+	/// `MessageTy::FileEnd`.
+	pub const MULTI_FILE: Features = Features(1 << 4);
+
+	/// The receiver acknowledges what it has handled w/ a `MessageTy::Ack`,
+	/// and the sender bounds what is unacknowledged.
+	pub const ACKS: Features = Features(1 << 5);
+
+	/// What a peer whose `Hello` carries no bitmask is taken to understand.
+	pub const LEGACY: Features = Features(Self::RESUME.0 | Self::PADDING.0 | Self::HEARTBEATS.0 | Self::MULTI_FILE.0);
+
+	const NAMES: [(Features, &'static str); 6] = [
+		(Self::COMPRESSION, "compression"),
+		(Self::RESUME, "resume"),
+		(Self::PADDING, "padding"),
+		(Self::HEARTBEATS, "heartbeats"),
+		(Self::MULTI_FILE, "multi-file"),
+		(Self::ACKS, "acks"),
+	];
+
+	/// Every feature this version understands, which is what it advertises
+	/// unless told otherwise.
+	pub fn all() -> Self {
+		Features(Self::LEGACY.0 | Self::ACKS.0)
+	}
+
+	pub fn empty() -> Self {
+		Features(0)
+	}
+
+	pub fn from_bits(bits: u32) -> Self {
+		Features(bits)
+	}
+
+	pub fn bits(self) -> u32 {
+		self.0
+	}
+
+	/// Returns true if every feature in `other` is in this set.
+	pub fn contains(self, other: Features) -> bool {
+		self.0 & other.0 == other.0
+	}
+
+	/// Returns this set w/o the features in `other`.
+	pub fn without(self, other: Features) -> Self {
+		Features(self.0 & !other.0)
+	}
+
+	/// Reads what follows the session's id in a `Hello`, which is empty if
+	/// the peer predates the bitmask.
+	pub(super) fn from_hello(extra: &[u8]) -> Result<Self, ProtoError> {
+		match extra.len() {
+			0 => Ok(Self::LEGACY),
+			FEATURES_LEN => Ok(Features(NetworkEndian::read_u32(extra))),
+			_ => Err(ProtoError::MalformedMessage),
+		}
+	}
+}
+
+impl Default for Features {
+	fn default() -> Self {
+		Self::all()
+	}
+}
+
+impl BitAnd for Features {
+	type Output = Self;
+
+	fn bitand(self, other: Self) -> Self {
+		Features(self.0 & other.0)
+	}
+}
+
+impl fmt::Display for Features {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let names: Vec<&str> = Self::NAMES.iter()
+			.filter(|(feature, _)| self.contains(*feature))
+			.map(|(_, name)| *name)
+			.collect();
+
+		if names.is_empty() {
+			write!(f, "none")
+		} else {
+			write!(f, "{}", names.join(", "))
+		}
+	}
+}
@@ -0,0 +1,67 @@
+use crate::proto::identity::Transcript;
+
+use ring::digest;
+
+/// The number of words in a session's fingerprint, each of which carries a
+/// byte of its digest.
+const FINGERPRINT_WORDS: usize = 5;
+
+/// Prefixed to everything a session's fingerprint is derived from.
+const FINGERPRINT_CONTEXT: &[u8] = b"ubuffer session fingerprint v1";
+
+/// One word for each value of a byte, chosen to be easy to tell apart when
+/// they are read out loud.
+const WORDS: [&str; 256] = [
+	"acid", "acorn", "actor", "adobe", "agent", "alarm", "album", "alley",
+	"amber", "anchor", "angle", "ankle", "apple", "apron", "arena", "armor",
+	"arrow", "aspen", "atlas", "attic", "badge", "bagel", "baker", "bamboo",
+	"banjo", "barn", "basil", "basin", "beach", "beacon", "bean", "bear",
+	"beetle", "bell", "bench", "berry", "bike", "birch", "bison", "blade",
+	"blanket", "blaze", "blimp", "bloom", "board", "boat", "bolt", "bone",
+	"bonus", "boot", "bottle", "bow", "bowl", "brick", "bridge", "broom",
+	"brush", "bubble", "bucket", "buffalo", "cabin", "cable", "cactus", "camel",
+	"candle", "canoe", "canvas", "canyon", "cargo", "carpet", "castle", "cedar",
+	"cello", "chalk", "cherry", "chess", "chimney", "cider", "cinema", "circus",
+	"clock", "cloud", "clover", "coast", "cobra", "cocoa", "comet", "compass",
+	"copper", "coral", "cotton", "cougar", "crane", "crater", "crayon", "cricket",
+	"crown", "cube", "cupboard", "dagger", "daisy", "dance", "delta", "denim",
+	"desert", "diamond", "dingo", "dinner", "dolphin", "donkey", "dragon", "drum",
+	"duck", "dune", "eagle", "easel", "echo", "elbow", "ember", "engine",
+	"falcon", "feather", "fern", "ferry", "fiddle", "field", "flag", "flame",
+	"flute", "forest", "fossil", "fountain", "fox", "frost", "galaxy", "garden",
+	"garlic", "gecko", "ginger", "glacier", "globe", "goat", "gold", "gopher",
+	"grape", "gravel", "guitar", "hammer", "harbor", "harp", "hawk", "hazel",
+	"helmet", "heron", "hill", "honey", "hornet", "horse", "igloo", "island",
+	"ivory", "jacket", "jaguar", "jelly", "jewel", "jungle", "kayak", "kettle",
+	"kiwi", "koala", "ladder", "lagoon", "lamp", "lantern", "lemon", "leopard",
+	"lily", "lime", "lion", "lizard", "llama", "lobster", "locket", "lotus",
+	"magnet", "mango", "maple", "marble", "meadow", "melon", "meteor", "mint",
+	"mirror", "moon", "moose", "mosaic", "moth", "mountain", "mule", "nectar",
+	"needle", "nest", "nickel", "noodle", "oak", "oasis", "ocean", "olive",
+	"onion", "orange", "orbit", "orchid", "otter", "owl", "paddle", "panda",
+	"panther", "paper", "parrot", "peach", "pearl", "pebble", "pencil", "pepper",
+	"piano", "pigeon", "pillow", "pine", "planet", "plum", "pony", "poppy",
+	"potato", "puzzle", "quartz", "quilt", "rabbit", "radar", "radish", "raven",
+	"reef", "ribbon", "river", "robin", "rocket", "rose", "ruby", "saddle",
+	"salmon", "sandal", "satin", "scarf", "shell", "shovel", "silver", "sketch",
+];
+
+/// Returns a short fingerprint of a session, as words to read out to the
+/// other side. (i.e: `cabin-mango-raven-bolt-pearl`.)
+///
+/// It is derived from the `key` & the `transcript` of the handshake, which
+/// includes the IV, so both peers only arrive at the same words if they
+/// hold the same key & saw the same messages. A peer in the middle of the
+/// session, or one holding a different key, cannot make them match.
+pub(super) fn session_fingerprint(key: &[u8], transcript: &Transcript) -> String {
+	let mut context = digest::Context::new(&digest::SHA256);
+	context.update(FINGERPRINT_CONTEXT);
+	context.update(key);
+	context.update(transcript.digest().as_ref());
+
+	context.finish().as_ref()[..FINGERPRINT_WORDS]
+		.iter()
+		.map(|&byte| WORDS[byte as usize])
+		.collect::<Vec<_>>()
+		.join("-")
+}
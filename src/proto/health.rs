@@ -0,0 +1,89 @@
+use crate::proto::{Event, Observer};
+
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// An observer which is shared w/ a `Watchdog`'s helper thread.
+pub(crate) type SharedObserver = Arc<Mutex<Option<Observer>>>;
+
+/// Notices a sender which has gone quiet.
+///
+/// The receiver is blocked reading from the sender while it waits for the
+/// next message, so it cannot notice for itself. A helper thread warns (and
+/// emits an `Event::Quiet`) each time another `threshold` passes w/o the
+/// sender being heard from. The helper stops once the watchdog is dropped.
+pub(crate) struct Watchdog {
+	shared: Arc<(Mutex<Heard>, Condvar)>,
+}
+
+struct Heard {
+	last: Instant,
+
+	/// How many thresholds have passed since `last`, which were warned about.
+	warned: u32,
+
+	stopped: bool,
+}
+
+impl Watchdog {
+	pub fn spawn(threshold: Duration, observer: SharedObserver) -> Self {
+		let heard = Heard { last: Instant::now(), warned: 0, stopped: false };
+		let shared = Arc::new((Mutex::new(heard), Condvar::new()));
+
+		let watched = shared.clone();
+		thread::spawn(move || watch(&watched, threshold, &observer));
+
+		Self { shared }
+	}
+
+	/// Notes that the sender was just heard from. Returns how long it was
+	/// quiet for, if that was long enough to be warned about.
+	pub fn heard(&self) -> Option<Duration> {
+		let mut heard = self.shared.0.lock().expect("watchdog lock poisoned");
+		let idle = heard.last.elapsed();
+		heard.last = Instant::now();
+
+		if mem::replace(&mut heard.warned, 0) > 0 {
+			Some(idle)
+		} else {
+			None
+		}
+	}
+}
+
+impl Drop for Watchdog {
+	fn drop(&mut self) {
+		let (lock, wake) = &*self.shared;
+		lock.lock().expect("watchdog lock poisoned").stopped = true;
+		wake.notify_one();
+	}
+}
+
+/// Waits for each threshold to pass w/o word from the sender, until the
+/// `Watchdog` is dropped.
+fn watch(shared: &(Mutex<Heard>, Condvar), threshold: Duration, observer: &SharedObserver) {
+	let (lock, wake) = shared;
+	let mut heard = lock.lock().expect("watchdog lock poisoned");
+
+	while !heard.stopped {
+		let due = heard.last + threshold * (heard.warned + 1);
+		let now = Instant::now();
+		if now < due {
+			heard = wake.wait_timeout(heard, due - now).expect("watchdog lock poisoned").0;
+			continue;
+		}
+
+		heard.warned += 1;
+		let idle = heard.last.elapsed();
+		drop(heard);
+
+		warn!("have not heard from the sender in {}s, it may be stalled or gone", idle.as_secs());
+		if let Some(observer) = observer.lock().expect("observer lock poisoned").as_mut() {
+			observer(&Event::Quiet { idle_secs: idle.as_secs_f64() });
+		}
+
+		heard = lock.lock().expect("watchdog lock poisoned");
+	}
+}
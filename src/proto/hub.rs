@@ -0,0 +1,481 @@
+use crate::error::ProtoError;
+use crate::proto::sink::{Seeking, Sink, Zeros};
+use crate::proto::{check_udt_addr, Cidr, Receiver, Stream, UdtMode};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use crate::udt::{Epoll, SocketFamily, SocketType, UdtOpts, UdtSocket, UDT_EPOLL_ERR, UDT_EPOLL_IN};
+
+/// The number of pending connections the listening socket will queue.
+const HUB_BACKLOG: i32 = 16;
+
+/// How long the hub waits for a new connection before checking its
+/// interrupt flag again. (in milliseconds.)
+const HUB_POLL_MS: i64 = 250;
+
+/// How long the hub waits instead while any session is w/ a worker. The
+/// `Waker` should wake it sooner, but UDT's C++ library can hold a send as
+/// small as a wakeup back for as long as 100ms. (in milliseconds.)
+const HUB_BUSY_POLL_MS: i64 = 5;
+
+/// How long a worker waits on a sender which has sent part of a message for
+/// the rest of it, before giving up on the session. (in milliseconds.)
+const HUB_READ_TIMEOUT_MS: i32 = 30_000;
+
+/// How long the event loop waits on a sender which has sent part of a
+/// handshake message for the rest of it, before giving up on the session.
+/// This is short, since every other session waits on the loop meanwhile.
+/// (in milliseconds.)
+const HUB_SHAKE_READ_TIMEOUT_MS: i32 = 500;
+
+/// How long a sender has to complete the handshake before the hub hangs up
+/// on it, no matter how slowly it trickles the handshake in.
+const HUB_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many handshakes may be in progress at once. A sender which connects
+/// while this many are waited on is hung up on straight away.
+const HUB_HANDSHAKES: usize = 64;
+
+/// How many wakeups the event loop reads from its `Waker` at once.
+const WAKER_BUF: usize = 64;
+
+/// Describes one of the connections accepted by a `Hub`.
+#[derive(Clone, Debug)]
+pub struct Session {
+	/// Sessions are numbered from one in the order they were accepted.
+	pub id: u64,
+
+	/// The address of the sender.
+	pub peer: SocketAddr,
+
+	/// When the connection was accepted.
+	pub accepted: SystemTime,
+}
+
+/// The `Hub` accepts any number of simultaneous senders on one address.
+///
+/// Each accepted connection gets its own `Receiver`, but the sessions do not
+/// get a thread each: a single event loop watches the listening socket & every
+/// session's socket w/ UDT's epoll, and a session is only handed to one of a
+/// few worker threads once its sender has sent something. The worker handles
+/// the blocks which have arrived and the session then goes back to waiting on
+/// its socket. The loop also notices when the hub's interrupt flag is raised,
+/// and stops accepting connections.
+///
+/// The handshake is run by the event loop itself: each time a sender's socket
+/// is readable the loop takes the steps of its handshake which have arrived,
+/// and goes back to waiting once the next step needs more from the sender. A
+/// sender which has not completed it within `HUB_HANDSHAKE_TIMEOUT` is hung up
+/// on, and no more than `HUB_HANDSHAKES` are waited on at once.
+///
+/// A worker which hands a session back wakes the loop through a `Waker`, so
+/// that its socket is waited on again straight away rather than once the
+/// loop next gives up waiting on the others. (It gives up sooner while any
+/// session is w/ a worker, in case the wakeup is slow to arrive.)
+///
+pub struct Hub {
+	key: Vec<u8>,
+	allow: Vec<Cidr>,
+	workers: usize,
+	listener: UdtSocket,
+	epoll: Epoll,
+	interrupt: Arc<AtomicBool>,
+}
+
+/// A session which is waiting on its sender, or being advanced by a worker.
+struct Slot {
+	sock: UdtSocket,
+	receiver: Receiver,
+	out: Box<dyn Sink + Send>,
+	session: Session,
+
+	/// When the sender is hung up on if it is still in the handshake, this is
+	/// `None` once the handshake is complete.
+	deadline: Option<Instant>,
+}
+
+/// What a worker hands back to the event loop once it is done w/ a session
+/// for now.
+enum Handled {
+	/// The session is waiting on its sender again.
+	Waiting(Box<Slot>),
+
+	/// The session is over, and was passed to the `close` callback.
+	Closed,
+}
+
+/// A connection the hub makes to itself, which the workers write to so the
+/// event loop (which waits on the other end) wakes up. UDT's epoll can only
+/// wait on UDT sockets through the bindings, so this is one as well.
+struct Waker {
+	rx: UdtSocket,
+	tx: UdtSocket,
+
+	/// Set once a wakeup has been sent which the loop has not handled yet, so
+	/// that a busy hub does not send one for every session handed back.
+	pending: AtomicBool,
+}
+
+impl Waker {
+	/// Connects a pair of sockets over the loopback interface.
+	fn new() -> Result<Self, ProtoError> {
+		let listener = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream)?;
+		let paired = listener.bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+			.and_then(|_| listener.listen(1))
+			.and_then(|_| listener.getsockname())
+			.and_then(|addr| {
+				let tx = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream)?;
+				tx.connect(addr)?;
+				let (rx, _) = listener.accept()?;
+				Ok((rx, tx))
+			});
+
+		let _ = listener.close();
+		let (rx, tx) = paired.map_err(|err| ProtoError::ConnectErr { inner: err })?;
+		Ok(Self { rx, tx, pending: AtomicBool::new(false) })
+	}
+
+	/// Wakes the loop, unless it has yet to handle the last wakeup.
+	fn wake(&self) {
+		if !self.pending.swap(true, Ordering::SeqCst) {
+			let _ = self.tx.send(&[0]);
+		}
+	}
+
+	/// Reads the wakeups which have arrived, once the loop is woken by them.
+	fn drain(&self) {
+		let mut buf = [0; WAKER_BUF];
+		let _ = self.rx.recv(&mut buf, WAKER_BUF);
+	}
+
+	fn close(self) {
+		let _ = self.tx.close();
+		let _ = self.rx.close();
+	}
+}
+
+impl Hub {
+	/// Creates a `Hub` listening on `addr` whose receivers will use `key` to
+	/// decrypt incoming blocks.
+	pub fn new<S: ToSocketAddrs>(addr: S, key: &[u8]) -> Result<Self, ProtoError> {
+		let sock_addr = addr.to_socket_addrs()?
+			.take(1).next()
+			.ok_or(ProtoError::NoSocketAddr)?;
+		check_udt_addr(sock_addr)?;
+
+		info!("setting up hub socket ...");
+		let listener = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream)
+			.map_err(|err| ProtoError::ConnectErr { inner: err })?;
+
+		listener.bind(sock_addr)
+			.map_err(|err| ProtoError::ConnectErr { inner: err })?;
+
+		listener.listen(HUB_BACKLOG)
+			.map_err(|err| ProtoError::ConnectErr { inner: err })?;
+
+		let mut epoll = Epoll::create()?;
+		epoll.add_usock(&listener, Some(UDT_EPOLL_IN))?;
+
+		Ok(Self {
+			key: key.to_vec(),
+			allow: vec![],
+			workers: thread::available_parallelism().map_or(1, |threads| threads.get()),
+			listener,
+			epoll,
+			interrupt: Arc::new(AtomicBool::new(false)),
+		})
+	}
+
+	/// Replaces the flag which is polled between connections to determine if
+	/// the hub should stop accepting senders. (e.g: one set by a signal handler.)
+	pub fn set_interrupt(&mut self, flag: Arc<AtomicBool>) {
+		self.interrupt = flag;
+	}
+
+	/// Only accepts senders from addresses within one of the `allow`ed
+	/// ranges, connections from anywhere else are dropped as they arrive.
+	pub fn set_allow(&mut self, allow: Vec<Cidr>) {
+		self.allow = allow;
+	}
+
+	/// Sets how many threads advance the sessions, no matter how many of them
+	/// there are. This defaults to the number of CPUs.
+	pub fn set_workers(&mut self, workers: usize) {
+		self.workers = workers.max(1);
+	}
+
+	/// Accepts senders until the interrupt flag is raised.
+	///
+	/// For each connection a `Receiver` is created and handed to `open`, which
+	/// is expected to configure it & return the file its output is written to.
+	/// Once the session is over the receiver is handed to `close`, along w/ how
+	/// it ended. (This is normally on a worker thread, but it is on the thread
+	/// running the hub if `open` failed or the session ended in its handshake.)
+	///
+	/// Once interrupted the hub waits for every open session to end before
+	/// stopping, this is how a hub is normally stopped so it is not treated as
+	/// an error.
+	pub fn run<F, G>(&mut self, mut open: F, close: G) -> Result<(), ProtoError>
+	where F: FnMut(&mut Receiver, &Session) -> Result<File, ProtoError>,
+	      G: Fn(Receiver, Session, Result<(), ProtoError>) + Send + Sync + 'static {
+		let close = Arc::new(close);
+		let (jobs_tx, jobs) = mpsc::channel::<Box<Slot>>();
+		let (handled_tx, handled) = mpsc::channel();
+		let jobs = Arc::new(Mutex::new(jobs));
+
+		let waker = Arc::new(Waker::new()?);
+		if let Err(err) = self.epoll.add_usock(&waker.rx, Some(UDT_EPOLL_IN)) {
+			if let Ok(waker) = Arc::try_unwrap(waker) { waker.close(); }
+			return Err(err.into());
+		}
+
+		let workers: Vec<JoinHandle<()>> = (0..self.workers)
+			.map(|_| {
+				let (jobs, handled_tx, close) = (Arc::clone(&jobs), handled_tx.clone(), Arc::clone(&close));
+				let waker = Arc::clone(&waker);
+				thread::spawn(move || work(&jobs, &handled_tx, &waker, &*close))
+			})
+			.collect();
+
+		let mut waiting: HashMap<UdtSocket, Box<Slot>> = HashMap::new();
+		let mut running = 0;
+		let mut accepting = true;
+		let mut next_id = 1;
+
+		let result = 'events: loop {
+			// cleared before the sessions are taken, so a worker which hands
+			// one back after this wakes the loop again
+			waker.pending.store(false, Ordering::SeqCst);
+			for handled in handled.try_iter() {
+				running -= 1;
+				if let Handled::Waiting(slot) = handled {
+					if let Err(err) = self.epoll.add_usock(&slot.sock, Some(UDT_EPOLL_IN | UDT_EPOLL_ERR)) {
+						break 'events Err(err.into());
+					}
+
+					waiting.insert(slot.sock, slot);
+				}
+			}
+
+			if accepting && self.interrupt.load(Ordering::SeqCst) {
+				info!("hub interrupted, waiting for {} sessions ...", waiting.len() + running);
+				if let Err(err) = self.epoll.remove_usock(&self.listener) {
+					break Err(err.into());
+				}
+
+				accepting = false;
+			}
+
+			if !accepting && waiting.is_empty() && running == 0 {
+				break Ok(());
+			}
+
+			let now = Instant::now();
+			let overdue: Vec<UdtSocket> = waiting.values()
+				.filter(|slot| slot.deadline.is_some_and(|deadline| deadline <= now))
+				.map(|slot| slot.sock)
+				.collect();
+
+			for sock in overdue {
+				if let Some(slot) = waiting.remove(&sock) {
+					warn!("hanging up on session #{}, which did not complete the handshake in time", slot.session.id);
+					let _ = self.epoll.remove_usock(&sock);
+					settle(slot, Err(ProtoError::ConnectTimeout), &*close);
+				}
+			}
+
+			let poll_ms = if running > 0 { HUB_BUSY_POLL_MS } else { HUB_POLL_MS };
+			let (readable, _) = match self.epoll.wait(poll_ms, false) {
+				Ok(events) => events,
+				Err(err) => break Err(err.into()),
+			};
+
+			for sock in readable {
+				if sock == waker.rx {
+					waker.drain();
+					continue;
+				}
+
+				let shaking = waiting.values().filter(|slot| slot.deadline.is_some()).count();
+
+				let mut slot = if sock == self.listener && shaking >= HUB_HANDSHAKES {
+					self.turn_away();
+					continue;
+				} else if sock == self.listener {
+					let slot = match self.accept(next_id, &mut open, &*close) {
+						Ok(Some(slot)) => slot,
+						Ok(None) => continue,
+						Err(err) => break 'events Err(err),
+					};
+
+					// a sender which hangs up is reported as readable too, so
+					// that its session is ended rather than left waiting
+					next_id += 1;
+					if let Err(err) = self.epoll.add_usock(&slot.sock, Some(UDT_EPOLL_IN | UDT_EPOLL_ERR)) {
+						break 'events Err(err.into());
+					}
+
+					slot
+				} else {
+					match waiting.remove(&sock) {
+						Some(slot) => slot,
+						None => continue,
+					}
+				};
+
+				// the loop runs the handshake itself, a step at a time
+				if slot.deadline.is_some() {
+					match shake(&mut slot) {
+						Ok(_) => { waiting.insert(slot.sock, slot); },
+						Err(err) => {
+							let _ = self.epoll.remove_usock(&slot.sock);
+							settle(slot, Err(err), &*close);
+						},
+					}
+
+					continue;
+				}
+
+				// a worker has it until it runs out of things to handle
+				if let Err(err) = self.epoll.remove_usock(&slot.sock) {
+					break 'events Err(err.into());
+				}
+
+				running += 1;
+				let _ = jobs_tx.send(slot);
+			}
+		};
+
+		drop(jobs_tx);
+		for worker in workers {
+			let _ = worker.join();
+		}
+
+		let _ = self.epoll.remove_usock(&waker.rx);
+		if let Ok(waker) = Arc::try_unwrap(waker) { waker.close(); }
+
+		if accepting {
+			self.epoll.remove_usock(&self.listener)?;
+		}
+
+		self.listener.close()?;
+		result
+	}
+
+	/// Hangs up on the sender waiting on the listener, since too many others
+	/// are still in the middle of their handshakes.
+	fn turn_away(&self) {
+		if let Ok((sock, peer)) = self.listener.accept() {
+			warn!("dropping a connection from {}, {} others are still in the middle of their handshakes", peer, HUB_HANDSHAKES);
+			let _ = sock.close();
+		}
+	}
+
+	/// Accepts the sender waiting on the listener, returning its session once
+	/// it was opened. A sender which is not allowed is dropped, and a session
+	/// which cannot be opened is handed straight to `close`.
+	fn accept<F, G>(&self, id: u64, open: &mut F, close: &G) -> Result<Option<Box<Slot>>, ProtoError>
+	where F: FnMut(&mut Receiver, &Session) -> Result<File, ProtoError>,
+	      G: Fn(Receiver, Session, Result<(), ProtoError>) {
+		let (sock, peer) = self.listener.accept()
+			.map_err(|err| ProtoError::ConnectErr { inner: err })?;
+
+		if !Cidr::allows(&self.allow, peer.ip()) {
+			warn!("dropping a connection from {}, which is not an allowed address", peer);
+			let _ = sock.close();
+			return Ok(None);
+		}
+
+		let session = Session { id, peer, accepted: SystemTime::now() };
+		info!("accepted session #{} from {}", session.id, session.peer);
+
+		// the event loop is only held up this long by a sender which stops
+		// halfway through a handshake message, rather than forever
+		let started = sock.setsockopt(UdtOpts::UDT_RCVTIMEO, HUB_SHAKE_READ_TIMEOUT_MS)
+			.map_err(ProtoError::from)
+			.and_then(|_| Receiver::with_transport(Stream::from_socket(sock, UdtMode::Stream), &self.key));
+
+		let mut receiver = match started {
+			Ok(receiver) => receiver,
+			Err(err) => {
+				warn!("could not start session #{}: {}", session.id, err);
+				let _ = sock.close();
+				return Ok(None);
+			},
+		};
+
+		let opened = open(&mut receiver, &session).and_then(|file| {
+			let out: Box<dyn Sink + Send> = if file.metadata()?.is_file() {
+				receiver.set_output_file(&file)?;
+				Box::new(Seeking::new(file))
+			} else {
+				Box::new(Zeros(file))
+			};
+
+			Ok(out)
+		});
+
+		match opened {
+			Ok(out) => {
+				let deadline = Some(Instant::now() + HUB_HANDSHAKE_TIMEOUT);
+				Ok(Some(Box::new(Slot { sock, receiver, out, session, deadline })))
+			},
+			Err(err) => {
+				let result = receiver.conclude(Err(err));
+				close(receiver, session, result);
+				Ok(None)
+			},
+		}
+	}
+}
+
+/// Takes the steps of a session's handshake which its sender has sent enough
+/// for, returning true once it is complete. The session is then left to a
+/// worker, which is held up for longer by a sender which stops halfway
+/// through a message. (See: `Receiver::advance_hello()`.)
+fn shake(slot: &mut Slot) -> Result<bool, ProtoError> {
+	if !slot.receiver.advance_hello()? {
+		return Ok(false);
+	}
+
+	slot.sock.setsockopt(UdtOpts::UDT_RCVTIMEO, HUB_READ_TIMEOUT_MS)?;
+	slot.deadline = None;
+	Ok(true)
+}
+
+/// Advances each session handed to this worker, until the hub is done.
+fn work<G>(jobs: &Mutex<mpsc::Receiver<Box<Slot>>>, handled: &mpsc::Sender<Handled>, waker: &Waker, close: &G)
+where G: Fn(Receiver, Session, Result<(), ProtoError>) {
+	loop {
+		let job = jobs.lock().expect("hub lock poisoned").recv();
+		let mut slot = match job {
+			Ok(slot) => slot,
+			Err(_) => return,
+		};
+
+		let advanced = slot.receiver.advance(&mut slot.out);
+		let _ = handled.send(settle(slot, advanced, close));
+		waker.wake();
+	}
+}
+
+/// Puts a session back to waiting on its sender, or passes it to `close`
+/// once `advanced` says it is over.
+fn settle<G>(slot: Box<Slot>, advanced: Result<bool, ProtoError>, close: &G) -> Handled
+where G: Fn(Receiver, Session, Result<(), ProtoError>) {
+	let result = match advanced {
+		Ok(false) => return Handled::Waiting(slot),
+		Ok(true) => Ok(()),
+		Err(err) => Err(err),
+	};
+
+	let Slot { mut receiver, session, .. } = *slot;
+	let result = receiver.conclude(result);
+	close(receiver, session, result);
+	Handled::Closed
+}
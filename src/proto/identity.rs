@@ -0,0 +1,203 @@
+use crate::error::ProtoError;
+use crate::key;
+use crate::proto::Mode;
+
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use rand::Rng;
+use ring::{digest, hkdf, hmac};
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair};
+use untrusted::Input;
+
+/// The length of the challenges peers which exchange identities append to
+/// their `MessageTy::ReqIV` & `MessageTy::RepIV`, each of which is the public
+/// half of an `Ephemeral` X25519 keypair.
+pub const CHALLENGE_LEN: usize = 32;
+
+/// The HKDF `info` string used when mixing the secret agreed on w/ the
+/// `Ephemeral` keypairs into the session key.
+const EPHEMERAL_INFO: &[u8] = b"ubuffer ephemeral";
+
+/// The length of an `MessageTy::Identity` payload: the peer's public key
+/// followed by its signature.
+pub const IDENTITY_LEN: usize = PUBLIC_KEY_LEN + SIGNATURE_LEN;
+
+/// Decides whether to trust a peer, given the fingerprint of the identity it
+/// proved it holds, or `None` if it has no identity.
+pub type IdentityCheck = Box<dyn FnMut(Option<&str>) -> Result<(), ProtoError> + Send>;
+
+pub(super) const PUBLIC_KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+/// Prefixed to everything a peer signs, so that its signature cannot be
+/// passed off as one made for some other purpose, or by the other peer.
+const SENDER_CONTEXT: &[u8] = b"ubuffer sender identity v3";
+const RECEIVER_CONTEXT: &[u8] = b"ubuffer receiver identity v3";
+
+/// A peer's long-term Ed25519 keypair.
+///
+/// Both peers already know the other holds the shared key, an identity lets
+/// them tell *which* peer they reached: each signs the handshake transcript,
+/// which includes a fresh `Ephemeral` share from each peer, and the other
+/// checks the fingerprint of its public key. (See: `IdentityCheck`.) The
+/// secret agreed on w/ those shares is mixed into the session key, so the
+/// session is bound to the identities which signed them: someone else who
+/// holds the shared key cannot sit in the middle & read the session.
+#[derive(Clone)]
+pub struct Identity {
+	pkcs8: Vec<u8>,
+	public_key: Vec<u8>,
+}
+
+impl Identity {
+	/// Generates a new keypair, returned as the PKCS#8 document which is
+	/// stored in an identity file.
+	pub fn generate() -> Result<Vec<u8>, ProtoError> {
+		let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())?;
+		Ok(pkcs8.to_vec())
+	}
+
+	/// Loads a keypair from its PKCS#8 document.
+	pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, ProtoError> {
+		let pair = Ed25519KeyPair::from_pkcs8(Input::from(pkcs8))
+			.map_err(|_| ProtoError::InvalidIdentity)?;
+
+		Ok(Self {
+			pkcs8: pkcs8.to_vec(),
+			public_key: pair.public_key_bytes().to_vec(),
+		})
+	}
+
+	/// The fingerprint the other peer checks. (e.g: against the one it expects.)
+	pub fn fingerprint(&self) -> String {
+		key::fingerprint(&self.public_key)
+	}
+
+	/// Returns the payload of a `MessageTy::Identity` sent by the peer in
+	/// `mode` at the end of the handshake recorded in `transcript`.
+	pub(super) fn sign(&self, mode: Mode, transcript: &Transcript) -> Result<Vec<u8>, ProtoError> {
+		self.sign_bytes(&transcript.signed_bytes(mode))
+	}
+
+	/// Returns our public key followed by our signature of `msg`.
+	pub(super) fn sign_bytes(&self, msg: &[u8]) -> Result<Vec<u8>, ProtoError> {
+		let pair = Ed25519KeyPair::from_pkcs8(Input::from(&self.pkcs8))?;
+		let signature = pair.sign(msg);
+
+		let mut buf = self.public_key.clone();
+		buf.extend_from_slice(signature.as_ref());
+		Ok(buf)
+	}
+}
+
+/// An X25519 keypair which is only used for one handshake. Its public half
+/// is the challenge a peer sends when identities are exchanged.
+pub(super) struct Ephemeral {
+	secret: [u8; 32],
+	share: [u8; CHALLENGE_LEN],
+}
+
+impl Ephemeral {
+	pub fn generate<R: Rng>(rng: &mut R) -> Self {
+		let mut secret = [0u8; 32];
+		rng.fill(&mut secret[..]);
+		let share = MontgomeryPoint::mul_base_clamped(secret).to_bytes();
+
+		Self { secret, share }
+	}
+
+	/// The public half, which is sent to the other peer.
+	pub fn share(&self) -> &[u8] {
+		&self.share
+	}
+
+	/// Returns the session key which replaces `key` once both identities are
+	/// checked: `key` mixed w/ the secret agreed on w/ the other peer, whose
+	/// share is `theirs`, using HKDF-SHA256.
+	pub fn mix(&self, key: &[u8], theirs: &[u8]) -> Result<Vec<u8>, ProtoError> {
+		if theirs.len() != CHALLENGE_LEN {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		let mut point = [0u8; CHALLENGE_LEN];
+		point.copy_from_slice(theirs);
+		let shared = MontgomeryPoint(point).mul_clamped(self.secret).to_bytes();
+
+		// a share of low order would agree on a secret anyone can compute
+		if shared.iter().all(|&byte| byte == 0) {
+			return Err(ProtoError::CryptoErr);
+		}
+
+		let salt = hmac::SigningKey::new(&digest::SHA256, &shared);
+		let mut mixed = vec![0u8; key.len()];
+		hkdf::extract_and_expand(&salt, key, EPHEMERAL_INFO, &mut mixed);
+
+		Ok(mixed)
+	}
+}
+
+/// A digest of every message exchanged during the handshake, headers and
+/// all, which is what each peer signs w/ its identity.
+pub(super) struct Transcript {
+	context: digest::Context,
+}
+
+impl Transcript {
+	pub fn new() -> Self {
+		Self { context: digest::Context::new(&digest::SHA256) }
+	}
+
+	/// Records a message as it was written to or read from the stream.
+	/// (i.e: before it is opened.)
+	pub fn update(&mut self, bytes: &[u8]) {
+		self.context.update(bytes);
+	}
+
+	/// Checks the payload of a `MessageTy::Identity` sent by the peer in
+	/// `mode`, returning the fingerprint of its public key if its signature
+	/// holds.
+	pub fn verify(&self, mode: Mode, identity: &[u8]) -> Result<String, ProtoError> {
+		verify(&self.signed_bytes(mode), identity)
+	}
+
+	/// The digest of the messages recorded so far.
+	pub fn digest(&self) -> digest::Digest {
+		self.context.clone().finish()
+	}
+
+	fn signed_bytes(&self, mode: Mode) -> Vec<u8> {
+		let context = match mode {
+			Mode::Sender => SENDER_CONTEXT,
+			Mode::Receiver => RECEIVER_CONTEXT,
+		};
+
+		[context, self.digest().as_ref()].concat()
+	}
+}
+
+/// Checks `identity`, a public key followed by its signature of `msg`,
+/// returning the fingerprint of the key if the signature holds.
+pub(super) fn verify(msg: &[u8], identity: &[u8]) -> Result<String, ProtoError> {
+	if identity.len() != IDENTITY_LEN {
+		return Err(ProtoError::MalformedMessage);
+	}
+
+	let (public_key, sig) = identity.split_at(PUBLIC_KEY_LEN);
+	signature::verify(&signature::ED25519, Input::from(public_key), Input::from(msg), Input::from(sig))?;
+
+	Ok(key::fingerprint(public_key))
+}
+
+/// Returns a check which only trusts a peer w/ one of the `expected`
+/// fingerprints.
+pub fn expect<S: AsRef<str>>(expected: &[S]) -> IdentityCheck {
+	let expected: Vec<String> = expected.iter()
+		.map(|fingerprint| fingerprint.as_ref().trim().to_lowercase())
+		.collect();
+
+	Box::new(move |actual| match actual {
+		Some(actual) if expected.iter().any(|fingerprint| key::fingerprints_match(fingerprint, actual)) => Ok(()),
+		Some(actual) => Err(ProtoError::IdentityMismatch { expected: expected.join(", "), actual: actual.to_string() }),
+		None => Err(ProtoError::NoIdentity { expected: expected.join(", ") }),
+	})
+}
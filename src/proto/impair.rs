@@ -0,0 +1,86 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Network conditions to simulate between the protocol and the UDT socket.
+///
+/// This is a testing aid: it lets the protocol's handling of a misbehaving
+/// network be exercised without setting up `tc`/`netem`. Since UDT is itself
+/// reliable the impairments are applied to whole writes (i.e: a message header
+/// or a payload) rather than to packets, so a dropped or reordered write shows
+/// up to the peer as a corrupt, replayed, or out-of-sequence message. The UDP
+/// transport applies them to its packets instead, and recovers from them.
+/// Corruption is only simulated over UDT, where it is applied to payloads so
+/// that the receiver asks for them to be resent. (See: `MessageTy::Nack`.)
+///
+/// It is parsed from a comma separated list such as `loss=1%,delay=50ms`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Impairment {
+	/// The probability (0.0 - 1.0) that a write is silently discarded.
+	pub loss: f64,
+
+	/// The probability (0.0 - 1.0) that a write is held back and sent after
+	/// the one which follows it.
+	pub reorder: f64,
+
+	/// The probability (0.0 - 1.0) that a payload has one of its bits flipped.
+	pub corrupt: f64,
+
+	/// Latency added before each write.
+	pub delay: Duration,
+}
+
+impl FromStr for Impairment {
+	type Err = String;
+
+	fn from_str(text: &str) -> Result<Self, Self::Err> {
+		let mut impairment = Impairment::default();
+
+		for setting in text.split(',').map(str::trim).filter(|setting| !setting.is_empty()) {
+			let mut parts = setting.splitn(2, '=');
+			let name = parts.next().unwrap_or_default();
+			let value = parts.next()
+				.ok_or_else(|| format!("expected name=value but got {:?}", setting))?;
+
+			match name {
+				"loss" => impairment.loss = parse_probability(value)?,
+				"reorder" => impairment.reorder = parse_probability(value)?,
+				"corrupt" => impairment.corrupt = parse_probability(value)?,
+				"delay" => impairment.delay = parse_duration(value)?,
+				_ => return Err(format!("unknown impairment {:?} (expected loss, reorder, corrupt, or delay)", name)),
+			}
+		}
+
+		Ok(impairment)
+	}
+}
+
+/// Parses a probability written as a percentage (`1.5%`) or a fraction (`0.015`).
+fn parse_probability(text: &str) -> Result<f64, String> {
+	let (number, scale) = match text.strip_suffix('%') {
+		Some(number) => (number, 100.0),
+		None => (text, 1.0),
+	};
+
+	let value = number.parse::<f64>()
+		.map_err(|_| format!("invalid probability {:?}", text))? / scale;
+
+	if !(0.0..=1.0).contains(&value) {
+		return Err(format!("probability {:?} is not between 0% and 100%", text));
+	}
+
+	Ok(value)
+}
+
+/// Parses a duration written in milliseconds (`50ms`) or seconds (`2s`).
+fn parse_duration(text: &str) -> Result<Duration, String> {
+	let (number, scale) = match text.strip_suffix("ms") {
+		Some(number) => (number, 1e-3),
+		None => (text.strip_suffix('s').unwrap_or(text), 1.0),
+	};
+
+	let secs = number.parse::<f64>()
+		.map_err(|_| format!("invalid duration {:?}", text))? * scale;
+
+	Duration::try_from_secs_f64(secs)
+		.map_err(|_| format!("invalid duration {:?}", text))
+}
@@ -0,0 +1,143 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Describes the file a `Sender` is reading from.
+///
+/// This is carried in an encrypted `MessageTy::Metadata` frame right after
+/// the handshake, so that the receiver can recreate the file faithfully.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FileMeta {
+	/// The final component of the original path. (i.e: no directories.)
+	pub name: String,
+
+	/// The unix permission bits of the original file.
+	pub mode: u32,
+
+	/// The numeric owner & group of the original file, on unix.
+	pub uid: Option<u32>,
+	pub gid: Option<u32>,
+
+	/// The modification time of the original file, in seconds & nanoseconds
+	/// since the unix epoch.
+	pub mtime: u64,
+	pub mtime_nanos: u32,
+
+	/// The length of the original file, if it is a regular file. The
+	/// receiver reserves this much space for it up front.
+	pub size: Option<u64>,
+}
+
+impl FileMeta {
+	/// Reads the name, permissions, and modification time of the file at `path`.
+	pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+		let path = path.as_ref();
+		let stat = fs::metadata(path)?;
+
+		let name = path.file_name()
+			.map(|name| name.to_string_lossy().into_owned())
+			.unwrap_or_default();
+
+		#[cfg(unix)]
+		let (mode, uid, gid) = {
+			use std::os::unix::fs::{MetadataExt, PermissionsExt};
+			(stat.permissions().mode() & 0o7777, Some(stat.uid()), Some(stat.gid()))
+		};
+
+		// assume the usual permissions on platforms without unix modes
+		#[cfg(not(unix))]
+		let (mode, uid, gid) = (0o644, None, None);
+
+		let mtime = stat.modified()?
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default();
+
+		Ok(Self {
+			name,
+			mode,
+			uid,
+			gid,
+			mtime: mtime.as_secs(),
+			mtime_nanos: mtime.subsec_nanos(),
+			size: if stat.is_file() { Some(stat.len()) } else { None },
+		})
+	}
+
+	/// Returns the original modification time.
+	pub fn modified(&self) -> SystemTime {
+		UNIX_EPOCH + Duration::new(self.mtime, self.mtime_nanos)
+	}
+
+	/// Applies the permissions & modification time to the file at `path`.
+	pub fn apply<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
+		self.apply_with(path, Preserve { perms: true, times: true, owner: false })
+	}
+
+	/// Applies whichever of the owner, permissions & modification time are
+	/// asked for to the file at `path`.
+	///
+	/// The owner & permissions are only applied on unix. The owner can only be
+	/// changed by a privileged receiver (i.e: root), otherwise the file is left
+	/// w/ the receiver's. The set-user-ID, set-group-ID, and sticky bits are
+	/// dropped rather than trusted from the remote peer, unless the owner was
+	/// applied as well.
+	pub fn apply_with<P: AsRef<Path>>(&self, path: P, preserve: Preserve) -> Result<(), io::Error> {
+		// the mode may make the file read-only, so everything is applied
+		// through a handle which is opened for writing beforehand.
+		let file = OpenOptions::new().write(true).open(path)?;
+		let owned = preserve.owner && self.apply_owner(&file)?;
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			if preserve.perms {
+				let mask = if owned { 0o7777 } else { 0o777 };
+				file.set_permissions(fs::Permissions::from_mode(self.mode & mask))?;
+			}
+		}
+
+		if preserve.times {
+			file.set_modified(self.modified())?;
+		}
+
+		Ok(())
+	}
+
+	/// Changes the owner & group of `file` to the original ones, returning
+	/// false if the receiver is not allowed to.
+	#[cfg(unix)]
+	fn apply_owner(&self, file: &File) -> Result<bool, io::Error> {
+		if self.uid.is_none() && self.gid.is_none() {
+			return Ok(false);
+		}
+
+		match std::os::unix::fs::fchown(file, self.uid, self.gid) {
+			Ok(()) => Ok(true),
+			Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+				warn!("not allowed to change the owner of the output to {:?}:{:?}", self.uid, self.gid);
+				Ok(false)
+			},
+
+			Err(err) => Err(err),
+		}
+	}
+
+	#[cfg(not(unix))]
+	fn apply_owner(&self, _file: &File) -> Result<bool, io::Error> {
+		Ok(false)
+	}
+}
+
+/// Which parts of a `FileMeta` the receiver applies to the files it writes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Preserve {
+	/// The permission bits.
+	pub perms: bool,
+
+	/// The modification time.
+	pub times: bool,
+
+	/// The numeric owner & group.
+	pub owner: bool,
+}
@@ -1,15 +1,96 @@
+#[cfg(feature = "async")]
+pub use self::asynchronous::{AsyncReceiver, AsyncSender};
+pub use self::budget::{MemoryBudget, MIN_MEMORY};
+pub use self::builder::{ReceiverBuilder, SenderBuilder};
+pub use self::cancel::CancelToken;
+pub use self::capture::CaptureDecoder;
+pub use self::cidr::Cidr;
+pub use self::cipher::Cipher;
+pub use self::congestion::{Aimd, Congestion, Controller, ControllerFactory, FixedWindow};
+pub use self::event::{Event, Observer};
+pub use self::fanout::FanOut;
+pub use self::fault::FaultCode;
+pub use self::features::Features;
+#[cfg(feature = "udt")]
+pub use self::hub::{Hub, Session};
+pub use self::identity::Identity;
+pub use self::impair::Impairment;
+pub use self::loopback::Loopback;
+pub use self::metadata::{FileMeta, Preserve};
+pub use self::pake::generate_code;
+pub use self::plaintext::ReceiverReader;
+pub use self::receipt::Receipt;
+#[cfg(feature = "udt")]
+pub use self::relay::Relay;
+pub use self::resume::{Checkpoint, Hangup, Reconnect};
+pub use self::session::SessionId;
 pub use self::receiver::Receiver;
 pub use self::sender::Sender;
+pub use self::summary::{human_bytes, Summary};
+pub use self::writer::SenderWriter;
+#[cfg(feature = "udt")]
+use self::stream::{check_udt_addr, Stream};
+// ubuffer's own UDT makes the same calls, w/o a C wrapper to make them through
+#[cfg(all(feature = "udt", not(feature = "udt-sys")))]
+use crate::udt::raw;
 
 use crate::error::ProtoError;
-use failure::Fail;
-use std::io::{self, Read, Write};
+use crate::key;
+use byteorder::{ByteOrder, NetworkEndian};
+use ring::aead;
+use std::fmt;
+use std::io::{Read, Write};
+use std::mem;
 use std::net::{SocketAddr, ToSocketAddrs};
-use udt::{SocketFamily, SocketType, UdtSocket};
-
+use std::thread;
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+mod asynchronous;
+mod banner;
+mod budget;
+mod builder;
+mod cancel;
+mod capture;
+mod cidr;
+mod cipher;
+mod congestion;
+mod event;
+mod fanout;
+mod fault;
+mod features;
+mod fingerprint;
+mod health;
+#[cfg(feature = "udt")]
+mod hub;
+mod identity;
+mod impair;
+mod loopback;
+mod metadata;
+#[cfg(feature = "udt")]
+mod multipath;
+mod pacing;
+mod padding;
+mod pake;
+mod plaintext;
+#[cfg(feature = "udt-sys")]
+mod raw;
+mod reader;
+mod receipt;
 mod receiver;
+#[cfg(feature = "udt")]
+mod relay;
+mod resume;
 mod sender;
+mod session;
+mod sink;
+#[cfg(feature = "udt")]
+mod stream;
+mod summary;
+mod udp;
 mod util;
+mod workers;
+mod writer;
 
 /// The block size used for the internal send/receiver buffers.
 pub const BLOCK_SIZE: usize = 8 * 1024;
@@ -18,41 +99,339 @@ pub const BLOCK_SIZE: usize = 8 * 1024;
 /// is set up successfully.
 pub const MAGIC_BYTES: u32 = 0xDEADBEEF;
 
-/// This is the size of a serialized `Message` in bytes when used with
-/// the `bincode` serializer.
-pub const MESSAGE_SIZE: usize = 12;
+/// Every message header begins w/ these bytes (`ubuf`), so that a peer which
+/// is not speaking this protocol at all is rejected straight away.
+pub const HEADER_MAGIC: u32 = 0x7562_7566;
+
+/// The version of the wire format, it is bumped whenever the layout of the
+/// header or the meaning of any message changes.
+pub const PROTOCOL_VERSION: u8 = 14;
+
+/// This is the size of an encoded `Message` header in bytes. (See: `Message`.)
+pub const MESSAGE_SIZE: usize = 18;
+
+/// The longest the dialing side waits between attempts to connect.
+/// (See: `StreamOpts::retry_delay`.)
+pub const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How long a session which dropped is waited on to be resumed, unless the
+/// sender or receiver is told otherwise. (See: `Sender::set_resume_timeout`.)
+pub const RESUME_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The size of the random salt carried by a `MessageTy::ReKey` message.
+pub const REKEY_SALT_LEN: usize = 32;
 
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+/// How many messages the receiver handles between each `MessageTy::Ack`, a
+/// sender must allow at least this many to be unacknowledged or it would
+/// wait on an ack which never comes.
+pub const ACK_INTERVAL: u64 = 64;
+
+/// The size of the payload of a `MessageTy::Ack`.
+const ACK_LEN: usize = 2 * mem::size_of::<u64>();
+
+/// The size of the payload of a `MessageTy::Hello`.
+const HELLO_LEN: usize = mem::size_of::<u32>() + session::SESSION_ID_LEN;
+
+/// The size of the totals & digest carried by the sender's `MessageTy::Goodbye`.
+const GOODBYE_LEN: usize = 2 * mem::size_of::<u64>() + receipt::DIGEST_LEN;
+
+/// The type of a `Message`, its discriminant is the type byte of the header
+/// so existing variants must never be renumbered.
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum MessageTy {
 	/// The data which follows is an incoming block of data from the sender.
 	/// The `len` bytes which follow this message are encrypted with the 
 	/// parameters agreed upon at the beginning of the session.
-	Block,
+	Block = 0,
 
 	/// The sender is informing the receiver that it would like initialization
 	/// parameters for the session's encryption. The sender will wait for four
 	/// bytes (32-bits) which will be prepended to a 64-bit counter for each 
-	/// message sent.
-	ReqIV,
+	/// message sent. The two bytes which follow are the `Cipher` the sender
+	/// asks for (or `CIPHER_AUTO`), and whether it can accelerate AES, then
+	/// the id of its key so that a receiver which accepts several knows which
+	/// to use. (See: `key::id()`.) A sender which has an identity, or checks
+	/// the receiver's, follows them w/ a challenge (an ephemeral X25519
+	/// share), asking for `Identity` messages. (See: `identity::Ephemeral`.)
+	ReqIV = 1,
 
 	/// The receiver chooses encryption parameters for the session and sends
-	/// them as the following four bytes.
-	RepIV,
+	/// them as the following five bytes: the IV, then the `Cipher` it chose.
+	/// The sender hangs up if that is not the one it asked for. If the
+	/// `ReqIV` carried a challenge the receiver follows them w/ its own.
+	RepIV = 2,
 
-	/// The sender acknowledges receipt of the nonce with an encrypted `Hello`.
-	Hello,
+	/// The sender acknowledges receipt of the nonce with an encrypted `Hello`,
+	/// which carries `MAGIC_BYTES`, a new `SessionId` & the `Features` it
+	/// understands. The receiver answers w/ a `Hello` of its own echoing the
+	/// same id, w/ its own features if the sender's `Hello` carried any.
+	Hello = 3,
 
 	/// The sender informs the receiver that it is done sending blocks with
-	/// a `Goodbye` message.
-	Goodbye,
+	/// a `Goodbye` message. The sender's is sealed, the `len` bytes which
+	/// follow are the plaintext bytes & blocks it sent as big-endian `u64`s
+	/// and the SHA-256 of its data, which the receiver checks against what it
+	/// received. The receiver's answer is empty, unless it proved its identity
+	/// during the handshake in which case it carries a signed `Receipt`.
+	Goodbye = 4,
+
+	/// The sender was interrupted before reaching the end of its input. The
+	/// receiver should flush what it has received so far and hang up.
+	Abort = 5,
+
+	/// The sender has chosen a new salt, encrypted w/ the current key in the
+	/// `len` bytes which follow. Both peers derive a new sub-key from the
+	/// master key and this salt, which is used for all subsequent messages.
+	ReKey = 6,
+
+	/// A keepalive sent by the sender while it is waiting on its input, so
+	/// that an idle session keeps traffic flowing through NATs & firewalls.
+	/// The receiver answers each `Ping` with a `Pong`.
+	Ping = 7,
+
+	/// The receiver's reply to a `Ping`.
+	Pong = 8,
+
+	/// A `FileMeta` describing the sender's input, encrypted in the `len`
+	/// bytes which follow. This is optional, if sent it precedes the first
+	/// `Block` of the session.
+	Metadata = 9,
+
+	/// The blocks which follow belong to a new file, it is described by the
+	/// `FileMeta` encrypted in the `len` bytes which follow.
+	FileStart = 10,
+
+	/// The current file is complete, its length in bytes is encrypted as a
+	/// big-endian `u64` in the `len` bytes which follow.
+	FileEnd = 11,
+
+	/// The sender's input has a hole here, its length in bytes is encrypted as
+	/// a big-endian `u64` in the `len` bytes which follow. The receiver seeks
+	/// past it if its output allows, otherwise it writes that many zeros.
+	Skip = 12,
+
+	/// The receiver could not open the message w/ the sequence number carried
+	/// as a big-endian `u64` in the `len` bytes which follow. The sender resends
+	/// it and every message it sent after it, which the receiver discards until
+	/// that message arrives again. A sender which no longer has it aborts.
+	/// Its `seq` is that of the message it answers, & it is sealed like an
+	/// `Ack`.
+	Nack = 13,
+
+	/// The receiver will let the sender resume the session if the connection
+	/// drops, the `len` bytes which follow are the opaque token the sender
+	/// must present to do so. (See: `resume::Tokens`.) It is sealed like an
+	/// `Ack`.
+	Token = 14,
+
+	/// The first message on a new connection to a sender which is resuming a
+	/// session, the `len` bytes which follow are the receiver's challenge.
+	/// The sender answers w/ a `Resume` carrying its token, a challenge of
+	/// its own, & a MAC over both challenges & the token. The receiver
+	/// answers w/ a `Resume` carrying the sequence number of the message it
+	/// expects next as a big-endian `u64` & a MAC over it & both challenges,
+	/// the sender resends from there. The receiver hangs up on a token it did
+	/// not issue, or a sender which cannot prove it holds the session key.
+	/// (See: `resume::read_resume()` & `resume::present()`.)
+	Resume = 15,
+
+	/// Sent by each peer after the `Hello`s when the handshake carried
+	/// challenges, the receiver's first. The `len` bytes which follow are its
+	/// public key & a signature over the handshake transcript, or nothing at
+	/// all if the peer has no identity. (See: `Identity`.)
+	Identity = 16,
+
+	/// A `Block` sent by a sender which pads its traffic, so that every one
+	/// is the same length. The `len` bytes which follow are sealed as for a
+	/// `Block`, they open to the length of the data as a big-endian `u32`,
+	/// the data, and zeros to fill out the block. A length of zero marks a
+	/// cover block, which the receiver discards. (See: `padding::pad()`.)
+	Padded = 17,
+
+	/// Sent first by peers which were given a short code rather than a key,
+	/// before the `ReqIV`. The `len` bytes which follow are the peer's half
+	/// of a SPAKE2 exchange, the sender's goes first & the receiver answers
+	/// w/ its own. Both then use the key derived from the exchange for the
+	/// rest of the session. (See: `pake::Pake`.)
+	Pake = 18,
+
+	/// The number of plaintext bytes the sender will send in all, encrypted as
+	/// a big-endian `u64` in the `len` bytes which follow. This is optional,
+	/// if sent it follows the handshake (& any `Metadata`) and the receiver
+	/// refuses a `Goodbye` w/ any other total.
+	TotalSize = 19,
+
+	/// Sent by either peer just before it hangs up on a failed session, so
+	/// that the other can report why. The `len` bytes which follow are a
+	/// `FaultCode` & a UTF-8 explanation. This is not sealed, since the peers
+	/// may not share a key, and is only sent once the handshake is through.
+	Error = 20,
+
+	/// Sent by the receiver every `ACK_INTERVAL` messages, once both peers
+	/// have advertised `Features::ACKS`. The `len` bytes which follow are the
+	/// sequence number of the message it expects next & the plaintext bytes
+	/// it has handled so far, as big-endian `u64`s. The sender stops sending
+	/// while too much is unacknowledged.
+	///
+	/// Its `seq` is that of the last message handled, and the payload is
+	/// sealed under a key derived from the session key & id w/ a nonce derived
+	/// from its type & `seq`. (See: `util::reply_nonce()`.)
+	Ack = 21,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl MessageTy {
+	/// Returns the type identified by `ty` on the wire, if it is one we know.
+	fn from_u8(ty: u8) -> Option<Self> {
+		let ty = match ty {
+			0 => MessageTy::Block,
+			1 => MessageTy::ReqIV,
+			2 => MessageTy::RepIV,
+			3 => MessageTy::Hello,
+			4 => MessageTy::Goodbye,
+			5 => MessageTy::Abort,
+			6 => MessageTy::ReKey,
+			7 => MessageTy::Ping,
+			8 => MessageTy::Pong,
+			9 => MessageTy::Metadata,
+			10 => MessageTy::FileStart,
+			11 => MessageTy::FileEnd,
+			12 => MessageTy::Skip,
+			13 => MessageTy::Nack,
+			14 => MessageTy::Token,
+			15 => MessageTy::Resume,
+			16 => MessageTy::Identity,
+			17 => MessageTy::Padded,
+			18 => MessageTy::Pake,
+			19 => MessageTy::TotalSize,
+			20 => MessageTy::Error,
+			21 => MessageTy::Ack,
+			_ => return None,
+		};
+
+		Some(ty)
+	}
+
+	/// The largest `len` which may follow a message of this type. This bounds
+	/// what a peer can make us allocate before anything has been authenticated.
+	fn max_len(&self) -> usize {
+		let tag_len = aead::AES_256_GCM.tag_len();
+
+		match *self {
+			MessageTy::Abort
+				| MessageTy::Ping
+				| MessageTy::Pong => 0,
+
+			MessageTy::ReqIV => 2 * mem::size_of::<u8>() + key::ID_LEN + identity::CHALLENGE_LEN,
+			MessageTy::RepIV => mem::size_of::<u32>() + mem::size_of::<u8>() + identity::CHALLENGE_LEN,
+			MessageTy::Nack => mem::size_of::<u64>() + tag_len,
+			MessageTy::Ack => ACK_LEN + tag_len,
+			MessageTy::Token => resume::TOKEN_LEN + tag_len,
+			MessageTy::Resume => resume::TOKEN_LEN + resume::CHALLENGE_LEN + resume::PROOF_LEN,
+			MessageTy::Identity => identity::IDENTITY_LEN,
+			MessageTy::Pake => pake::ELEMENT_LEN,
+			MessageTy::Error => mem::size_of::<u8>() + fault::FAULT_MESSAGE_LEN,
+			MessageTy::Hello => HELLO_LEN + features::FEATURES_LEN + tag_len,
+			MessageTy::ReKey => REKEY_SALT_LEN + tag_len,
+			MessageTy::FileEnd
+				| MessageTy::Skip
+				| MessageTy::TotalSize => mem::size_of::<u64>() + tag_len,
+			MessageTy::Goodbye => (GOODBYE_LEN + tag_len).max(receipt::RECEIPT_LEN),
+
+			MessageTy::Block
+				| MessageTy::Metadata
+				| MessageTy::FileStart => BLOCK_SIZE + tag_len,
+			MessageTy::Padded => padding::PADDED_LEN + tag_len,
+		}
+	}
+
+	/// Returns true if this message carries a block of the sender's input.
+	fn is_block(self) -> bool {
+		self == MessageTy::Block || self == MessageTy::Padded
+	}
+}
+
+/// The header which precedes every message.
+///
+/// It is encoded as the following fixed layout, w/ every integer in network
+/// (big-endian) byte order:
+///
+/// | offset | size | field                         |
+/// |--------|------|-------------------------------|
+/// | 0      | 4    | `HEADER_MAGIC`                |
+/// | 4      | 1    | `PROTOCOL_VERSION`            |
+/// | 5      | 1    | the `MessageTy` discriminant  |
+/// | 6      | 4    | `len`                         |
+/// | 10     | 8    | `seq`                         |
+///
+#[derive(Debug)]
 struct Message {
 	ty: MessageTy,
-	len: usize
+	len: usize,
+
+	/// The sequence number of an encrypted message, this is the counter used
+	/// to derive the message's nonce. Both peers count every encrypted message
+	/// so the receiver knows exactly which number comes next. Since the header
+	/// is authenticated, replayed, dropped, or reordered messages are detected
+	/// before any decryption is attempted. Plaintext messages use zero.
+	seq: u64,
+}
+
+impl Message {
+	/// Encodes the header for the wire.
+	fn encode(&self) -> [u8; MESSAGE_SIZE] {
+		// `max_len()` keeps every length well within a `u32`
+		let mut buf = [0u8; MESSAGE_SIZE];
+		NetworkEndian::write_u32(&mut buf[0..4], HEADER_MAGIC);
+		buf[4] = PROTOCOL_VERSION;
+		buf[5] = self.ty as u8;
+		NetworkEndian::write_u32(&mut buf[6..10], self.len as u32);
+		NetworkEndian::write_u64(&mut buf[10..18], self.seq);
+		buf
+	}
+
+	/// Decodes a header read from the peer.
+	///
+	/// Headers from another protocol or version, and unknown message types,
+	/// are rejected. A `len` larger than its type allows is rejected before
+	/// the caller allocates a buffer for it.
+	fn decode(buf: &[u8]) -> Result<Self, ProtoError> {
+		if buf.len() != MESSAGE_SIZE || NetworkEndian::read_u32(&buf[0..4]) != HEADER_MAGIC {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		if buf[4] != PROTOCOL_VERSION {
+			return Err(ProtoError::UnsupportedVersion { version: buf[4] });
+		}
+
+		let message = Message {
+			ty: MessageTy::from_u8(buf[5]).ok_or(ProtoError::UnknownMessage { ty: buf[5] })?,
+			len: NetworkEndian::read_u32(&buf[6..10]) as usize,
+			seq: NetworkEndian::read_u64(&buf[10..18]),
+		};
+
+		let max = message.ty.max_len();
+
+		match message.ty {
+			MessageTy::Block | MessageTy::Padded if message.len > max => {
+				Err(ProtoError::OversizedBlock { len: message.len, max })
+			},
+
+			_ if message.len > max => Err(ProtoError::MalformedMessage),
+			_ => Ok(message),
+		}
+	}
+
+	/// Passes the message through, unless it is a `MessageTy::Error` in which
+	/// case its explanation is read from `stream` & returned as the failure.
+	fn or_fault<R: Read + ?Sized>(self, stream: &mut R) -> Result<Self, ProtoError> {
+		match self.ty {
+			MessageTy::Error => Err(fault::read(stream, &self)),
+			_ => Ok(self),
+		}
+	}
 }
 
+#[derive(Clone, Copy)]
 enum Mode {
 	Sender,
 	Receiver,
@@ -64,93 +443,332 @@ enum State {
 	Transmit,
 }
 
-struct Stream {
-	inner: UdtSocket,
+/// Options which control how the underlying socket is set up.
+#[derive(Clone, Debug, Default)]
+pub struct StreamOpts {
+	/// The local address & port a sender binds to before connecting to
+	/// the receiver. If `None` the OS picks an ephemeral port.
+	pub bind: Option<SocketAddr>,
+
+	/// Reverses which side dials: the sender listens for the receiver to
+	/// connect to it. This is for networks where only the data source can
+	/// accept inbound connections, the handshake itself is unchanged.
+	pub reverse: bool,
+
+	/// Simulated network conditions applied to everything this side sends.
+	/// (For testing only.)
+	pub impairment: Option<Impairment>,
+
+	/// The protocol which carries the session between the peers.
+	pub transport: TransportKind,
+
+	/// How the transport responds to loss on the link. (A UDT session built
+	/// w/ the `udt-sys` feature always uses UDT's own congestion control.)
+	pub congestion: Congestion,
+
+	/// Whether a UDT socket carries a byte stream or whole messages. Both
+	/// peers must agree. (UDT only, and only over a single path.)
+	pub udt_mode: UdtMode,
+
+	/// How long a block sent as a UDT message may go undelivered before UDT
+	/// drops it. The receiver asks for a dropped block again, so this trades
+	/// retransmits for latency on a lossy link. If `None` a block never
+	/// expires. (`UdtMode::Message` only.)
+	pub message_ttl: Option<Duration>,
+
+	/// Lets UDT deliver a block sent as a message ahead of the blocks sent
+	/// before it. The receiver asks for the blocks it skipped over again.
+	/// (`UdtMode::Message` only.)
+	pub unordered: bool,
+
+	/// The size of each of a UDT socket's send & receive buffers, in bytes.
+	/// If `None` UDT's defaults are used, which come to several MiB each.
+	/// (See: `MemoryBudget`.)
+	pub socket_buffer: Option<usize>,
+
+	/// How long the listening side waits for its peer to connect. If `None`
+	/// it waits for as long as it takes.
+	pub accept_timeout: Option<Duration>,
+
+	/// How many more times the dialing side tries to reach its peer if it
+	/// cannot connect. (e.g: because the peer has not started listening yet.)
+	pub retries: u32,
+
+	/// How long the dialing side waits before it tries to connect again, this
+	/// doubles after each attempt up to `RETRY_MAX_DELAY`.
+	pub retry_delay: Duration,
+
+	/// The addresses the listening side accepts its peer from. A connection
+	/// from anywhere else is dropped before the handshake, and the listener
+	/// carries on waiting. If empty any address is accepted.
+	pub allow: Vec<Cidr>,
+
+	/// Which of the addresses the peer's name resolves to are used.
+	pub family: AddrFamily,
+
+	/// The local addresses a sender opens a path to the receiver from, it
+	/// stripes the session across all of them. If empty it opens just the one.
+	/// (UDT only, and only when the sender dials.)
+	pub paths: Vec<SocketAddr>,
 }
 
-/// The `Stream` represents an underlying UDT socket.
-/// 
-/// This is a wrapper type which implements `Read` and `Write` for the
-/// underlying socket. Additionally it implements some applicaiton level
-/// semantics. (Such as the `sender` vs `receiver` roles.)
-///
-impl Stream {
-	/// When created in the `Receiver` mode it begins listening on the
-	/// specified address. Otherwise if created in `Sender` mode it attempts
-	/// to reach a receiver at the specified remote address.
-	pub fn new<S: ToSocketAddrs>(mode: Mode, addr: S) -> Result<Self, ProtoError> {
-		let sock_addr = addr.to_socket_addrs()?
-			.take(1).next()
-			.expect("fatal: expected a socket address but did not get one.");
-
-		let stream = match mode {
-			Mode::Sender => Self::create_sender(sock_addr)?,
-			Mode::Receiver => Self::create_receiver(sock_addr)?,
-		};
+/// The address families a peer may be reached at, for a name which resolves
+/// to both IPv4 & IPv6 addresses.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AddrFamily {
+	/// Whichever the name resolves to. (The default.)
+	#[default]
+	Any,
+
+	/// Only IPv4 addresses.
+	V4,
+
+	/// Only IPv6 addresses.
+	V6,
+}
+
+impl AddrFamily {
+	/// Returns true if `addr` belongs to this family.
+	pub fn matches(self, addr: &SocketAddr) -> bool {
+		match self {
+			AddrFamily::Any => true,
+			AddrFamily::V4 => addr.is_ipv4(),
+			AddrFamily::V6 => addr.is_ipv6(),
+		}
+	}
+}
 
-		Ok(stream)
+impl fmt::Display for AddrFamily {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			AddrFamily::Any => write!(f, "ip"),
+			AddrFamily::V4 => write!(f, "ipv4"),
+			AddrFamily::V6 => write!(f, "ipv6"),
+		}
 	}
+}
 
+/// The protocols a session can be carried over. Both peers must agree.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TransportKind {
+	/// A UDT socket, w/ UDT's own congestion control. (The default, unless
+	/// ubuffer was built w/o the `udt` feature, in which case it is refused.)
+	#[cfg_attr(feature = "udt", default)]
+	Udt,
+
+	/// Plain UDP datagrams w/ a simple acknowledgement & retransmission
+	/// scheme, for links where UDT's congestion control is a poor fit.
+	#[cfg_attr(not(feature = "udt"), default)]
+	Udp,
+}
 
-	fn create_sender(addr: SocketAddr) -> Result<Self, ProtoError> {
-		info!("connecting to utp receiver ...");
-		let sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream)
-			.map_err(|err| ProtoError::SocketErr { inner: err })?;
+/// The kinds of socket UDT offers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UdtMode {
+	/// A byte stream, which is read back in whatever pieces it arrives in.
+	/// (The default.)
+	#[default]
+	Stream,
+
+	/// A UDT datagram socket, which carries each message of the protocol
+	/// (e.g: a sealed block & its header) as a single UDT message. A message
+	/// is read back whole w/ one call rather than reassembled from the stream,
+	/// which saves latency on small payloads. The other messages never expire
+	/// & are delivered in order, but the blocks may be allowed to. (See:
+	/// `StreamOpts::message_ttl` & `StreamOpts::unordered`.)
+	Message,
+}
 
-		sock.connect(addr)
-			.map_err(|err| ProtoError::SocketErr { inner: err })?;
+/// Connects to, or waits for, the peer at `addr` over the transport chosen
+/// by `opts`. The dialing side tries again up to `opts.retries` times, w/
+/// exponential backoff, if it cannot reach the peer.
+fn connect<S: ToSocketAddrs>(mode: Mode, addr: S, opts: &StreamOpts) -> Result<Box<dyn Transport>, ProtoError> {
+	let dials = matches!((mode, opts.reverse), (Mode::Sender, false) | (Mode::Receiver, true));
+	let mut delay = opts.retry_delay;
+	let mut retry = 0;
 
-		Ok(Self { inner: sock })
+	if opts.transport != TransportKind::Udt && opts.udt_mode != UdtMode::Stream {
+		warn!("only udt has message sockets, ignoring {:?}", opts.udt_mode);
 	}
 
-	fn create_receiver(addr: SocketAddr) -> Result<Self, ProtoError> {
-		info!("setting up receiver socket ...");
-		let sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream)
-			.map_err(|err| ProtoError::SocketErr { inner: err })?;
+	if opts.transport != TransportKind::Udt && opts.socket_buffer.is_some() {
+		warn!("only udt sockets can be given buffers of a size, ignoring it");
+	}
 
-		sock.bind(addr)
-			.map_err(|err| ProtoError::SocketErr { inner: err })?;
+	loop {
+		let connected: Result<Box<dyn Transport>, ProtoError> = match opts.transport {
+			#[cfg(feature = "udt")]
+			TransportKind::Udt => multipath::connect(mode, &addr, opts),
+			#[cfg(not(feature = "udt"))]
+			TransportKind::Udt => Err(ProtoError::InvalidArgument { reason: "ubuffer was built w/o the udt feature, only the udp transport is available" }),
+			TransportKind::Udp => udp::Datagram::new(mode, &addr, opts).map(|datagram| Box::new(datagram) as _),
+		};
 
-		sock.listen(1)
-			.map_err(|err| ProtoError::SocketErr { inner: err })?;
+		match connected {
+			Err(ref err) if err.is_unreachable() && dials && retry < opts.retries => {
+				retry += 1;
+				warn!("could not reach the peer, retrying in {:?} ({} of {}) ...", delay, retry, opts.retries);
+				thread::sleep(delay);
+				delay = (delay * 2).min(RETRY_MAX_DELAY);
+			},
 
-		let (sock, _addr) = sock.accept()?;
+			connected => return connected,
+		}
+	}
+}
+
+/// Resolves every socket address of `addr` in the `family`. The dialing side
+/// tries each of them in turn, the listening side listens on all of them.
+fn resolve<S: ToSocketAddrs>(addr: S, family: AddrFamily) -> Result<Vec<SocketAddr>, ProtoError> {
+	let mut addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+	addrs.dedup();
 
-		Ok(Self { inner: sock })
+	if addrs.is_empty() {
+		return Err(ProtoError::NoSocketAddr);
 	}
 
-	fn as_socket(&self) -> &UdtSocket { &self.inner }
+	addrs.retain(|addr| family.matches(addr));
+	if addrs.is_empty() {
+		return Err(ProtoError::NoAddrInFamily { family });
+	}
+
+	Ok(addrs)
 }
 
-impl Read for Stream {
-	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
-		let buf_len = buf.len();
-		let bytes_recvd = self.inner.recv(buf, buf_len)
-			.map_err(|err| ProtoError::SocketErr { inner: err }.compat())
-			.map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+/// Tries to reach the peer at each of `addrs` until one answers, alternating
+/// between address families (as in "happy eyeballs") so that a host whose
+/// IPv6 or IPv4 records are all dead costs one attempt before the other is tried.
+/// Returns the error from the last address tried if none of them answer.
+fn dial_any<T, F>(addrs: &[SocketAddr], mut dial: F) -> Result<T, ProtoError>
+where F: FnMut(SocketAddr) -> Result<T, ProtoError> {
+	let (first, second): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.iter()
+		.partition(|addr| addr.is_ipv6() == addrs[0].is_ipv6());
+
+	let mut candidates = vec![];
+	for idx in 0..first.len().max(second.len()) {
+		candidates.extend(first.get(idx));
+		candidates.extend(second.get(idx));
+	}
 
-		// TODO: check the sanity of this cast.
-		//       not sure why UDT has this as a signed integer.
-		Ok(bytes_recvd as usize)
+	let mut last_err = ProtoError::NoSocketAddr;
+	for (idx, &addr) in candidates.iter().enumerate() {
+		match dial(addr) {
+			Ok(dialed) => return Ok(dialed),
+			Err(err) if idx + 1 < candidates.len() => warn!("could not reach {} ({}), trying the next address ...", addr, err),
+			Err(err) => last_err = err,
+		}
 	}
+
+	Err(last_err)
+}
+
+/// Returns a `Reconnect` which reaches the peer at `addr` the same way
+/// `connect()` did the first time.
+fn reconnector(mode: Mode, addrs: Vec<SocketAddr>, opts: &StreamOpts) -> Reconnect {
+	// the session is resumed on its own schedule, which the retries would delay
+	let mut opts = StreamOpts { retries: 0, ..opts.clone() };
+	Box::new(move |timeout| {
+		opts.accept_timeout = Some(timeout);
+		connect(mode, &addrs[..], &opts)
+	})
 }
 
-impl Write for Stream {
-	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
-		let bytes_sent = self.inner.send(&buf)
-			.map_err(|err| ProtoError::SocketErr { inner: err }.compat())
-			.map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+/// A reliable, ordered byte stream between two peers which a `Sender` or
+/// `Receiver` can run over.
+///
+/// This is normally a UDT socket, but the protocol itself is not tied to
+/// UDT. (e.g: a `Loopback` runs it entirely in memory for testing.)
+pub trait Transport: Read + Write + Send {
+	/// Hangs up on the remote peer.
+	fn close(&mut self) -> Result<(), ProtoError>;
+
+	/// Returns a snapshot of the underlying connection, if the transport
+	/// has one to report.
+	fn link_stats(&self) -> Option<LinkStats> {
+		None
+	}
+
+	/// Returns true if the peer has sent something which has not been read
+	/// yet, so that reading it will not block. A transport which cannot tell
+	/// returns false, and is then only read when a reply is expected. (This is
+	/// how the sender notices a `MessageTy::Nack` in the middle of a transfer.)
+	fn has_pending(&mut self) -> bool {
+		false
+	}
 
-		// TODO: check the sanity of this cast.
-		//       not sure why UDT has this as a signed integer.
-		Ok(bytes_sent as usize)
+	/// Returns a handle which hangs up on the peer from another thread, so
+	/// that a read blocked on this side fails. A transport which cannot be
+	/// hung up on this way returns `None`.
+	fn hangup_handle(&self) -> Option<Hangup> {
+		None
 	}
 
-	fn flush(&mut self) -> Result<(), io::Error> {
-		// TODO: UDT bindings provides no means to flush, I believe it's buffering
-		// data internally and sending as fast as it can. (See: UDT_LINGER.)
-		// for now this is a no-op since data is immediately committed to the
-		// underlying UDT socket. 
-		Ok(())
+	/// Returns true if the transport may drop or reorder what is written to
+	/// it, in which case the receiver checks each message's sequence number
+	/// & asks for any it missed again.
+	fn lossy(&self) -> bool {
+		false
 	}
+
+	/// Hands over the message which is being read as a whole, once just its
+	/// header has been read, by swapping it w/ `buf`. (i.e: `buf` then holds
+	/// the header followed by the payload.) A transport which does not read
+	/// whole messages returns false, and the payload is read as usual.
+	fn swap_message(&mut self, _buf: &mut Vec<u8>) -> bool {
+		false
+	}
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+	fn close(&mut self) -> Result<(), ProtoError> {
+		(**self).close()
+	}
+
+	fn link_stats(&self) -> Option<LinkStats> {
+		(**self).link_stats()
+	}
+
+	fn has_pending(&mut self) -> bool {
+		(**self).has_pending()
+	}
+
+	fn hangup_handle(&self) -> Option<Hangup> {
+		(**self).hangup_handle()
+	}
+
+	fn lossy(&self) -> bool {
+		(**self).lossy()
+	}
+
+	fn swap_message(&mut self, buf: &mut Vec<u8>) -> bool {
+		(**self).swap_message(buf)
+	}
+}
+
+/// The state of a `Transport`'s underlying connection, as reported by UDT
+/// or the UDP transport.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkStats {
+	/// Packets which have been written but not yet acknowledged by the peer.
+	/// A queue which keeps growing means the network is the bottleneck.
+	pub send_queue: u64,
+
+	/// Packets which have arrived but not yet been read. A queue which keeps
+	/// growing means this side (e.g: its output) is the bottleneck.
+	pub recv_queue: u64,
+
+	/// The round-trip time to the peer, once it has been measured.
+	pub rtt: Option<Duration>,
+
+	/// The capacity of the link in bits per second, as estimated by UDT.
+	pub bandwidth_bps: Option<u64>,
+
+	/// Data packets sent, including retransmissions.
+	pub sent_packets: u64,
+
+	/// Packets which were found to be lost, in either direction.
+	pub lost_packets: u64,
+
+	/// Packets which had to be sent again.
+	pub retransmitted_packets: u64,
 }
@@ -0,0 +1,518 @@
+use crate::error::ProtoError;
+use crate::proto::{dial_any, resolve, Hangup, LinkStats, Mode, Stream, StreamOpts, Transport, UdtMode, PROTOCOL_VERSION};
+
+use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt, WriteBytesExt};
+use rand::Rng;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::udt::Epoll;
+
+/// Every path of a multipath session opens w/ these bytes (`ubmp`), where a
+/// session over a single path opens w/ the peer's banner. (See: `BANNER_MAGIC`.)
+const PATH_MAGIC: u32 = 0x7562_6d70;
+
+/// The length of the random id which ties a sender's paths together.
+const GROUP_LEN: usize = 16;
+
+/// The preface which opens each path: `PATH_MAGIC`, `PROTOCOL_VERSION`, the
+/// group id, then the index of the path & how many there are as single bytes.
+const PREFACE_LEN: usize = 4 + 1 + GROUP_LEN + 2;
+
+/// The header of a frame: its sequence number as a big-endian `u64`, and the
+/// length of the data which follows as a big-endian `u32`.
+const FRAME_HEADER_LEN: usize = 12;
+
+/// The most data a single frame carries, a longer write is split up.
+const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// How many frames the paths read ahead of the session, before they stop
+/// reading from the network & leave the rest to flow control. This also
+/// bounds how far past the frame which is read next a path may read, so
+/// that no more than this many frames wait on a gap left by a slower path.
+const FRAME_BACKLOG: usize = 256;
+
+/// How long a receiver waits for the rest of a sender's paths once the
+/// first one has arrived.
+const PATH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connects to, or waits for, the peer at `addr` over UDT. A sender w/ local
+/// `opts.paths` to dial from stripes the session across all of them, and a
+/// listening receiver answers such a sender over every path it opens.
+pub(super) fn connect<S: ToSocketAddrs>(mode: Mode, addr: S, opts: &StreamOpts) -> Result<Box<dyn Transport>, ProtoError> {
+	match (mode, opts.reverse) {
+		(Mode::Sender, false) if !opts.paths.is_empty() => {
+			// the frames of a path are not messages of the protocol
+			if opts.udt_mode != UdtMode::Stream {
+				return Err(ProtoError::InvalidArgument { reason: "a session striped across paths cannot use message sockets" });
+			}
+
+			let addrs = resolve(addr, opts.family)?;
+			Ok(Box::new(dial(&addrs, opts)?))
+		},
+
+		(Mode::Receiver, false) => accept(&resolve(addr, opts.family)?, opts),
+		_ => Ok(Box::new(Stream::new(mode, addr, opts)?)),
+	}
+}
+
+/// Dials the peer at `addrs` once from each of `opts.paths`, and stripes the
+/// session across every path which connects.
+fn dial(addrs: &[SocketAddr], opts: &StreamOpts) -> Result<Multipath, ProtoError> {
+	// the IPv6 addresses are not worth trying, unless there is nothing else
+	// to report an error for (See: `Stream::new()`.)
+	let v4: Vec<SocketAddr> = addrs.iter().copied().filter(SocketAddr::is_ipv4).collect();
+	let dialable = if v4.is_empty() { addrs } else { &v4 };
+
+	let mut paths = vec![];
+	let mut last_err = None;
+	for &local in opts.paths.iter().take(u8::MAX as usize) {
+		match dial_any(dialable, |addr| Stream::create_dialer(addr, Some(local), opts)) {
+			Ok(path) => paths.push(path.configured(opts)),
+			Err(err) => {
+				warn!("could not open a path from {}: {}", local, err);
+				last_err = Some(err);
+			},
+		}
+	}
+
+	if paths.is_empty() {
+		return Err(last_err.unwrap_or(ProtoError::NoSocketAddr));
+	}
+
+	let mut group = [0u8; GROUP_LEN];
+	rand::thread_rng().fill(&mut group[..]);
+
+	let count = paths.len() as u8;
+	for (idx, path) in paths.iter_mut().enumerate() {
+		let mut preface = Vec::with_capacity(PREFACE_LEN);
+		preface.write_u32::<NetworkEndian>(PATH_MAGIC)?;
+		preface.push(PROTOCOL_VERSION);
+		preface.extend_from_slice(&group);
+		preface.push(idx as u8);
+		preface.push(count);
+		path.write_all(&preface)?;
+	}
+
+	info!("striping the session across {} paths ...", count);
+	Ok(Multipath::new(paths))
+}
+
+/// Listens on each of `addrs`, and accepts the first sender from an allowed
+/// address along w/ the rest of its paths, if it opened several.
+fn accept(addrs: &[SocketAddr], opts: &StreamOpts) -> Result<Box<dyn Transport>, ProtoError> {
+	let listeners = Stream::listen_all(addrs, opts)?;
+	let mut epoll = Stream::watch(&listeners)?;
+	let accepted = accept_paths(&mut epoll, opts);
+	Stream::unwatch(epoll, listeners)?;
+
+	accepted
+}
+
+fn accept_paths(epoll: &mut Epoll, opts: &StreamOpts) -> Result<Box<dyn Transport>, ProtoError> {
+	let sock = Stream::accept_within(epoll, opts.accept_timeout, &opts.allow)?;
+	let mut first = Stream::from_socket(sock, opts.udt_mode).configured(opts);
+
+	let mut magic = [0u8; 4];
+	first.read_exact(&mut magic)?;
+	if NetworkEndian::read_u32(&magic) != PATH_MAGIC {
+		return Ok(Box::new(Rewound { inner: first, unread: magic.to_vec() }));
+	}
+
+	let (group, count) = read_preface(&mut first)?;
+	let mut paths = vec![first];
+	let deadline = Instant::now() + PATH_TIMEOUT;
+
+	while paths.len() < count {
+		let sock = Stream::accept_within(epoll, Some(deadline.saturating_duration_since(Instant::now())), &opts.allow)?;
+		let mut path = Stream::from_socket(sock, opts.udt_mode).configured(opts);
+
+		let mut magic = [0u8; 4];
+		let preface = path.read_exact(&mut magic)
+			.map_err(ProtoError::from)
+			.and_then(|_| match NetworkEndian::read_u32(&magic) {
+				PATH_MAGIC => read_preface(&mut path),
+				_ => Err(ProtoError::MalformedMessage),
+			});
+
+		match preface {
+			Ok((path_group, _)) if path_group == group => paths.push(path),
+			_ => {
+				warn!("dropping a connection which is not one of the sender's paths");
+				let _ = path.close();
+			},
+		}
+	}
+
+	info!("the sender striped the session across {} paths", count);
+	Ok(Box::new(Multipath::new(paths)))
+}
+
+/// Reads the rest of a preface, after its `PATH_MAGIC`, returning the group
+/// id & the number of paths in the group.
+fn read_preface(path: &mut Stream) -> Result<([u8; GROUP_LEN], usize), ProtoError> {
+	let version = path.read_u8()?;
+	if version != PROTOCOL_VERSION {
+		return Err(ProtoError::UnsupportedVersion { version });
+	}
+
+	let mut group = [0u8; GROUP_LEN];
+	path.read_exact(&mut group)?;
+	let _idx = path.read_u8()?;
+	let count = path.read_u8()?;
+
+	if count == 0 {
+		return Err(ProtoError::MalformedMessage);
+	}
+
+	Ok((group, count as usize))
+}
+
+/// A session striped across several UDT connections. (i.e: one per WAN
+/// uplink, to use the bandwidth of all of them.)
+///
+/// Each write is sent as a numbered frame on whichever path has the least
+/// data waiting to be sent, so that a faster path carries more of them. A
+/// helper thread reads the frames from each path, and they are put back in
+/// order before being read. The session fails if any one path fails.
+///
+/// A path which gets ahead of the others stops reading once its next frame
+/// is `FRAME_BACKLOG` past the one which is read next, until the slower paths
+/// fill the gap. (See: `Window`.)
+struct Multipath {
+	paths: Vec<Stream>,
+
+	/// The sequence number of the next frame written, & the path considered
+	/// first for it.
+	next_write: u64,
+	next_path: usize,
+
+	frames: Receiver<io::Result<(u64, Vec<u8>)>>,
+
+	/// Frames which arrived ahead of the one which is read next.
+	pending: BTreeMap<u64, Vec<u8>>,
+	next_read: u64,
+	window: Arc<Window>,
+
+	/// The frame being read, & how much of it has been.
+	current: Vec<u8>,
+	pos: usize,
+
+	/// The first error from a path. This is held back while the other paths
+	/// might still deliver the frame which is read next, since the peer closes
+	/// its paths one at a time. (i.e: after sending its goodbye on just one.)
+	failed: Option<io::Error>,
+}
+
+impl Multipath {
+	fn new(paths: Vec<Stream>) -> Self {
+		let (tx, frames) = mpsc::sync_channel(FRAME_BACKLOG);
+		let window = Arc::new(Window::default());
+
+		for path in &paths {
+			let mut reader = path.try_clone();
+			let tx = tx.clone();
+			let window = Arc::clone(&window);
+
+			// the thread stops once its path is closed, or the session is over
+			thread::spawn(move || loop {
+				let frame = read_frame(&mut reader);
+				let failed = frame.is_err();
+				if let Ok((seq, _)) = frame {
+					if !window.wait_for(seq) {
+						break;
+					}
+				}
+
+				if tx.send(frame).is_err() || failed {
+					break;
+				}
+			});
+		}
+
+		Self {
+			paths,
+			next_write: 0,
+			next_path: 0,
+			frames,
+			pending: BTreeMap::new(),
+			next_read: 0,
+			window,
+			current: vec![],
+			pos: 0,
+			failed: None,
+		}
+	}
+
+	/// Picks the path w/ the shortest send queue, taking turns between those
+	/// which are tied.
+	fn pick_path(&mut self) -> usize {
+		let count = self.paths.len();
+		let first = self.next_path;
+		self.next_path = (self.next_path + 1) % count;
+
+		(0..count)
+			.map(|offset| (first + offset) % count)
+			.min_by_key(|&idx| self.paths[idx].link_stats().map_or(0, |stats| stats.send_queue))
+			.expect("a multipath session has at least one path")
+	}
+
+	/// Files a frame which arrived, refusing one which was already read.
+	fn file_frame(&mut self, frame: io::Result<(u64, Vec<u8>)>) -> Result<(), io::Error> {
+		let (seq, buf) = match frame {
+			Ok(frame) => frame,
+			Err(err) => {
+				self.failed.get_or_insert(err);
+				return Ok(());
+			},
+		};
+
+		if seq < self.next_read || self.pending.contains_key(&seq) {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "a path repeated a frame"));
+		}
+
+		self.pending.insert(seq, buf);
+		Ok(())
+	}
+
+	/// Files every frame which has already arrived, w/o waiting.
+	fn poll_frames(&mut self) -> Result<(), io::Error> {
+		loop {
+			match self.frames.try_recv() {
+				Ok(frame) => self.file_frame(frame)?,
+				Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return Ok(()),
+			}
+		}
+	}
+
+	/// Waits for the frame which is read next.
+	fn next_frame(&mut self) -> Result<Vec<u8>, io::Error> {
+		loop {
+			if let Some(frame) = self.pending.remove(&self.next_read) {
+				self.next_read += 1;
+				self.window.advance(self.next_read);
+				return Ok(frame);
+			}
+
+			// once a path has failed the rest are given a while to catch up,
+			// or for all of them to fail, before the session does
+			let frame = match self.failed {
+				None => self.frames.recv()
+					.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "every path to the peer was closed"))?,
+
+				Some(_) => match self.frames.recv_timeout(PATH_TIMEOUT) {
+					Ok(frame) => frame,
+					Err(_) => return Err(self.failed.take().expect("a path failed")),
+				},
+			};
+
+			self.file_frame(frame)?;
+		}
+	}
+}
+
+impl Drop for Multipath {
+	fn drop(&mut self) {
+		self.window.close();
+	}
+}
+
+/// How far the paths of a `Multipath` may read ahead of it, which is shared
+/// w/ the threads reading them.
+#[derive(Default)]
+struct Window {
+	state: Mutex<WindowState>,
+	moved: Condvar,
+}
+
+#[derive(Default)]
+struct WindowState {
+	next_read: u64,
+	closed: bool,
+}
+
+impl Window {
+	/// Waits until frame `seq` is less than `FRAME_BACKLOG` past the frame
+	/// which is read next, returning false if the session ended first. Until
+	/// then the path it arrived on is left to flow control.
+	fn wait_for(&self, seq: u64) -> bool {
+		let mut state = self.state.lock().expect("window lock poisoned");
+		while !state.closed && seq.saturating_sub(state.next_read) >= FRAME_BACKLOG as u64 {
+			state = self.moved.wait(state).expect("window lock poisoned");
+		}
+
+		!state.closed
+	}
+
+	fn advance(&self, next_read: u64) {
+		self.state.lock().expect("window lock poisoned").next_read = next_read;
+		self.moved.notify_all();
+	}
+
+	fn close(&self) {
+		self.state.lock().expect("window lock poisoned").closed = true;
+		self.moved.notify_all();
+	}
+}
+
+/// Reads the next frame from a path.
+fn read_frame(path: &mut Stream) -> io::Result<(u64, Vec<u8>)> {
+	let mut header = [0u8; FRAME_HEADER_LEN];
+	path.read_exact(&mut header)?;
+
+	let seq = NetworkEndian::read_u64(&header[0..8]);
+	let len = NetworkEndian::read_u32(&header[8..12]) as usize;
+	if len > MAX_FRAME_LEN {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "a path sent an oversized frame"));
+	}
+
+	let mut buf = vec![0u8; len];
+	path.read_exact(&mut buf)?;
+	Ok((seq, buf))
+}
+
+impl Transport for Multipath {
+	fn close(&mut self) -> Result<(), ProtoError> {
+		let mut result = Ok(());
+		for path in &mut self.paths {
+			if let Err(err) = path.close() {
+				result = Err(err);
+			}
+		}
+
+		result
+	}
+
+	fn link_stats(&self) -> Option<LinkStats> {
+		self.paths.iter()
+			.map(Stream::link_stats)
+			.try_fold(LinkStats::default(), |total, stats| {
+				let stats = stats?;
+				Some(LinkStats {
+					send_queue: total.send_queue + stats.send_queue,
+					recv_queue: total.recv_queue + stats.recv_queue,
+
+					// the session is only as quick as its slowest path
+					rtt: total.rtt.max(stats.rtt),
+					bandwidth_bps: Some(total.bandwidth_bps.unwrap_or(0) + stats.bandwidth_bps.unwrap_or(0)),
+					sent_packets: total.sent_packets + stats.sent_packets,
+					lost_packets: total.lost_packets + stats.lost_packets,
+					retransmitted_packets: total.retransmitted_packets + stats.retransmitted_packets,
+				})
+			})
+	}
+
+	fn has_pending(&mut self) -> bool {
+		if let Err(err) = self.poll_frames() {
+			self.failed = Some(err);
+			return true;
+		}
+
+		self.pos < self.current.len() || self.pending.contains_key(&self.next_read)
+	}
+
+	fn hangup_handle(&self) -> Option<Hangup> {
+		let handles: Vec<Hangup> = self.paths.iter()
+			.filter_map(Stream::hangup_handle)
+			.collect();
+
+		Some(Box::new(move || handles.into_iter().for_each(|hangup| hangup())))
+	}
+}
+
+impl Read for Multipath {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+		// an empty frame would read as the end of the stream
+		while self.pos == self.current.len() {
+			self.current = self.next_frame()?;
+			self.pos = 0;
+		}
+
+		let len = buf.len().min(self.current.len() - self.pos);
+		buf[..len].copy_from_slice(&self.current[self.pos..self.pos + len]);
+		self.pos += len;
+		Ok(len)
+	}
+}
+
+impl Write for Multipath {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+
+		let len = buf.len().min(MAX_FRAME_LEN);
+		let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + len);
+		frame.write_u64::<NetworkEndian>(self.next_write)?;
+		frame.write_u32::<NetworkEndian>(len as u32)?;
+		frame.extend_from_slice(&buf[..len]);
+
+		let idx = self.pick_path();
+		self.paths[idx].write_all(&frame)?;
+		self.next_write += 1;
+		Ok(len)
+	}
+
+	fn flush(&mut self) -> Result<(), io::Error> {
+		for path in &mut self.paths {
+			path.flush()?;
+		}
+
+		Ok(())
+	}
+}
+
+/// A session over a single path, whose first bytes were read to tell it
+/// apart from a multipath one. They are handed back before anything else.
+struct Rewound {
+	inner: Stream,
+	unread: Vec<u8>,
+}
+
+impl Transport for Rewound {
+	fn close(&mut self) -> Result<(), ProtoError> {
+		self.inner.close()
+	}
+
+	fn link_stats(&self) -> Option<LinkStats> {
+		self.inner.link_stats()
+	}
+
+	fn has_pending(&mut self) -> bool {
+		!self.unread.is_empty() || self.inner.has_pending()
+	}
+
+	fn hangup_handle(&self) -> Option<Hangup> {
+		self.inner.hangup_handle()
+	}
+
+	fn lossy(&self) -> bool {
+		self.inner.lossy()
+	}
+
+	fn swap_message(&mut self, buf: &mut Vec<u8>) -> bool {
+		self.unread.is_empty() && self.inner.swap_message(buf)
+	}
+}
+
+impl Read for Rewound {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+		if self.unread.is_empty() {
+			return self.inner.read(buf);
+		}
+
+		let len = buf.len().min(self.unread.len());
+		buf[..len].copy_from_slice(&self.unread[..len]);
+		self.unread.drain(..len);
+		Ok(len)
+	}
+}
+
+impl Write for Rewound {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> { self.inner.write(buf) }
+	fn flush(&mut self) -> Result<(), io::Error> { self.inner.flush() }
+}
@@ -0,0 +1,46 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How far the sender may fall behind its rate (e.g: while its input was
+/// idle) before it stops trying to catch up, so that it never sends a burst
+/// of more than this much time's worth of data at once.
+const MAX_BURST: Duration = Duration::from_millis(250);
+
+/// Holds the sender to an average rate, by sleeping whenever it gets ahead of
+/// it. (See: `Sender::set_rate_limit()`.)
+pub(crate) struct RateLimit {
+	bytes_per_sec: u64,
+	started: Instant,
+	sent: u64,
+}
+
+impl RateLimit {
+	/// Creates a limit of `bytes_per_sec`, which must not be zero.
+	pub fn new(bytes_per_sec: u64) -> Self {
+		assert!(bytes_per_sec > 0, "a rate limit cannot be zero");
+		Self { bytes_per_sec, started: Instant::now(), sent: 0 }
+	}
+
+	/// Notes that `len` more bytes are about to be sent, sleeping until the
+	/// rate allows them to be.
+	pub fn pace(&mut self, len: u64) {
+		let elapsed = self.started.elapsed();
+		if elapsed > self.due() + MAX_BURST {
+			self.started = Instant::now().checked_sub(MAX_BURST).unwrap_or_else(Instant::now);
+			self.sent = 0;
+		}
+
+		self.sent += len;
+
+		let elapsed = self.started.elapsed();
+		let due = self.due();
+		if due > elapsed {
+			thread::sleep(due - elapsed);
+		}
+	}
+
+	/// Returns when everything sent so far is due to have been sent by.
+	fn due(&self) -> Duration {
+		Duration::from_secs_f64(self.sent as f64 / self.bytes_per_sec as f64)
+	}
+}
@@ -0,0 +1,42 @@
+use crate::error::ProtoError;
+use crate::proto::BLOCK_SIZE;
+
+use byteorder::{ByteOrder, NetworkEndian};
+use std::mem;
+use std::time::Duration;
+
+/// The plaintext length of every `MessageTy::Padded`: the length of the data
+/// it carries as a big-endian `u32`, followed by a whole block.
+pub const PADDED_LEN: usize = mem::size_of::<u32>() + BLOCK_SIZE;
+
+/// How long a padding sender's input may sit idle before it sends a cover
+/// block, and how often it sends another while it stays idle.
+pub const COVER_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Pads `chunk` in place to `PADDED_LEN` bytes. An empty chunk becomes a
+/// cover block, which the receiver discards.
+pub fn pad(chunk: &mut Vec<u8>) {
+	assert!(chunk.len() <= BLOCK_SIZE);
+
+	let mut len = [0u8; mem::size_of::<u32>()];
+	NetworkEndian::write_u32(&mut len, chunk.len() as u32);
+	chunk.splice(0..0, len.iter().cloned());
+	chunk.resize(PADDED_LEN, 0);
+}
+
+/// Strips the padding from an opened `MessageTy::Padded`, leaving just the
+/// data it carries. (i.e: nothing at all for a cover block.)
+pub fn unpad(buf: &mut Vec<u8>) -> Result<(), ProtoError> {
+	if buf.len() != PADDED_LEN {
+		return Err(ProtoError::MalformedMessage);
+	}
+
+	let len = NetworkEndian::read_u32(&buf[..mem::size_of::<u32>()]) as usize;
+	if len > BLOCK_SIZE {
+		return Err(ProtoError::MalformedMessage);
+	}
+
+	buf.drain(..mem::size_of::<u32>());
+	buf.truncate(len);
+	Ok(())
+}
@@ -0,0 +1,127 @@
+use crate::error::ProtoError;
+use crate::proto::Mode;
+
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
+use rand::Rng;
+use ring::digest;
+
+/// The length of the group element each peer sends in its `MessageTy::Pake`.
+pub const ELEMENT_LEN: usize = 32;
+
+/// The number of groups of four digits in a generated code.
+const CODE_GROUPS: usize = 3;
+
+/// Prefixed to the code before it is hashed to a scalar, & to everything the
+/// session key is derived from, so neither can be confused w/ another use.
+const PASSWORD_CONTEXT: &[u8] = b"ubuffer spake2 v1 password";
+const KEY_CONTEXT: &[u8] = b"ubuffer spake2 v1 key";
+
+/// The seeds of the points `M` & `N`, which are hashed to the group so that
+/// nobody knows their discrete logs.
+const SEED_M: &[u8] = b"ubuffer spake2 v1 M";
+const SEED_N: &[u8] = b"ubuffer spake2 v1 N";
+
+/// Returns a new random code for `Sender::set_code()`. (i.e: `0417-9253-6680`.)
+pub fn generate_code() -> String {
+	let mut rng = rand::thread_rng();
+
+	(0..CODE_GROUPS)
+		.map(|_| format!("{:04}", rng.gen_range(0, 10_000)))
+		.collect::<Vec<_>>()
+		.join("-")
+}
+
+/// One peer's half of a SPAKE2 exchange over ristretto255.
+///
+/// Both peers hash the code to a scalar `w`, then the sender sends `X = x*G +
+/// w*M` & the receiver `Y = y*G + w*N` for random `x` & `y`. Each removes the
+/// other's blinding to arrive at the same `x*y*G`, which an eavesdropper
+/// cannot compute, and an active attacker only learns whether its one guess
+/// at the code was right. The session key is a digest of the whole exchange.
+pub(super) struct Pake {
+	mode: Mode,
+	password: Scalar,
+	secret: Scalar,
+	element: [u8; ELEMENT_LEN],
+}
+
+impl Pake {
+	/// Picks our secret for an exchange in `mode` w/ a peer given `code`.
+	pub fn start(mode: Mode, code: &str) -> Self {
+		let password = hash_to_scalar(&[PASSWORD_CONTEXT, code.trim().to_lowercase().as_bytes()].concat());
+
+		let mut wide = [0u8; 64];
+		rand::thread_rng().fill(&mut wide[..]);
+		let secret = Scalar::from_bytes_mod_order_wide(&wide);
+
+		let element = RISTRETTO_BASEPOINT_POINT * secret + blinding(mode) * password;
+
+		Self {
+			mode,
+			password,
+			secret,
+			element: element.compress().to_bytes(),
+		}
+	}
+
+	/// The payload of our `MessageTy::Pake`.
+	pub fn element(&self) -> &[u8] {
+		&self.element
+	}
+
+	/// Combines our secret w/ the peer's element, returning a key of `len`
+	/// bytes which only matches the peer's if it was given the same code.
+	pub fn finish(self, theirs: &[u8], len: usize) -> Result<Vec<u8>, ProtoError> {
+		let peer = match self.mode {
+			Mode::Sender => Mode::Receiver,
+			Mode::Receiver => Mode::Sender,
+		};
+
+		let element = CompressedRistretto::from_slice(theirs)
+			.map_err(|_| ProtoError::MalformedMessage)?
+			.decompress()
+			.ok_or(ProtoError::MalformedMessage)?;
+
+		let shared = (element - blinding(peer) * self.password) * self.secret;
+		if shared.is_identity() {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		let (x, y) = match self.mode {
+			Mode::Sender => (&self.element[..], theirs),
+			Mode::Receiver => (theirs, &self.element[..]),
+		};
+
+		let mut context = digest::Context::new(&digest::SHA256);
+		context.update(KEY_CONTEXT);
+		context.update(x);
+		context.update(y);
+		context.update(shared.compress().as_bytes());
+		context.update(self.password.as_bytes());
+
+		let mut key = context.finish().as_ref().to_vec();
+		key.truncate(len);
+		Ok(key)
+	}
+}
+
+/// The point which blinds the element sent by the peer in `mode`.
+fn blinding(mode: Mode) -> RistrettoPoint {
+	let seed = match mode {
+		Mode::Sender => SEED_M,
+		Mode::Receiver => SEED_N,
+	};
+
+	let mut wide = [0u8; 64];
+	wide.copy_from_slice(digest::digest(&digest::SHA512, seed).as_ref());
+	RistrettoPoint::from_uniform_bytes(&wide)
+}
+
+fn hash_to_scalar(bytes: &[u8]) -> Scalar {
+	let mut wide = [0u8; 64];
+	wide.copy_from_slice(digest::digest(&digest::SHA512, bytes).as_ref());
+	Scalar::from_bytes_mod_order_wide(&wide)
+}
@@ -0,0 +1,153 @@
+use crate::proto::sink::Sink;
+use crate::proto::{Receiver, Summary};
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+
+/// A `ReceiverReader` yields the plaintext of a session as it arrives, so
+/// that a transfer can be consumed lazily instead of handing a `Receiver` an
+/// output to run to completion.
+///
+/// The handshake is performed on the first read. Each read then waits on the
+/// sender only until a block has arrived, and returns EOF once the sender has
+/// said goodbye. The holes a sparse sender skips over read as zeros.
+///
+pub struct ReceiverReader {
+	inner: Receiver,
+	pending: Pending,
+	state: ReadState,
+}
+
+#[derive(PartialEq)]
+enum ReadState {
+	Reading,
+	Finished,
+	Failed,
+}
+
+/// What has been received but not read yet.
+#[derive(Default)]
+pub(super) struct Pending {
+	runs: VecDeque<Run>,
+}
+
+enum Run {
+	Data(Vec<u8>),
+	Zeros(u64),
+}
+
+impl ReceiverReader {
+	/// Wraps a `Receiver` which has been configured, but not yet run.
+	pub fn new(receiver: Receiver) -> Self {
+		Self { inner: receiver, pending: Pending::default(), state: ReadState::Reading }
+	}
+
+	/// Returns the underlying `Receiver`. (e.g: to check its `summary()`.)
+	pub fn get_ref(&self) -> &Receiver {
+		&self.inner
+	}
+
+	/// Returns a tally of the transfer, which is complete once a read has
+	/// returned EOF.
+	pub fn summary(&self) -> &Summary {
+		self.inner.summary()
+	}
+}
+
+impl Read for ReceiverReader {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+		loop {
+			let len = self.pending.read_into(buf);
+			if len > 0 || buf.is_empty() {
+				return Ok(len);
+			}
+
+			match self.state {
+				ReadState::Reading => {},
+				ReadState::Finished => return Ok(0),
+				ReadState::Failed => return Err(io::Error::new(ErrorKind::BrokenPipe, "the session has failed")),
+			}
+
+			match self.inner.step(&mut self.pending) {
+				Ok(false) => {},
+
+				Ok(true) => {
+					self.state = ReadState::Finished;
+					self.inner.conclude(Ok(()))?;
+				},
+
+				Err(err) => {
+					self.state = ReadState::Failed;
+					return Err(self.inner.conclude(Err(err)).expect_err("the session has failed").into());
+				},
+			}
+		}
+	}
+}
+
+impl Pending {
+	/// Moves as much of what is pending as fits into `buf`.
+	pub(super) fn read_into(&mut self, buf: &mut [u8]) -> usize {
+		let mut len = 0;
+
+		while len < buf.len() {
+			let front = match self.runs.front_mut() {
+				Some(front) => front,
+				None => break,
+			};
+
+			let (run, left) = match front {
+				Run::Data(data) => {
+					let run = data.len().min(buf.len() - len);
+					buf[len..len + run].copy_from_slice(&data[..run]);
+					data.drain(..run);
+					(run, data.len() as u64)
+				},
+
+				Run::Zeros(zeros) => {
+					let run = (*zeros).min((buf.len() - len) as u64) as usize;
+					buf[len..len + run].iter_mut().for_each(|byte| *byte = 0);
+					*zeros -= run as u64;
+					(run, *zeros)
+				},
+			};
+
+			len += run;
+			if left == 0 {
+				self.runs.pop_front();
+			}
+		}
+
+		len
+	}
+}
+
+impl Write for Pending {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+		if !buf.is_empty() {
+			self.runs.push_back(Run::Data(buf.to_vec()));
+		}
+
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> Result<(), io::Error> {
+		Ok(())
+	}
+}
+
+impl Sink for Pending {
+	/// A hole is only filled in w/ zeros as it is read, since it may be far
+	/// larger than would be reasonable to buffer.
+	fn skip(&mut self, len: u64) -> Result<(), io::Error> {
+		if len > 0 {
+			self.runs.push_back(Run::Zeros(len));
+		}
+
+		Ok(())
+	}
+
+	fn finish(&mut self) -> Result<(), io::Error> {
+		Ok(())
+	}
+}
@@ -0,0 +1,112 @@
+//! The parts of UDT's C wrapper which the `udt` bindings link, but do not
+//! expose.
+
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::{c_char, c_int};
+use std::time::Duration;
+use udt::{UdtError, UdtSocket};
+
+/// UDT's `TRACEINFO`, as filled in by its performance monitor. Only a few of
+/// these are reported, the rest are here so the layout matches UDT's.
+#[repr(C)]
+#[derive(Default)]
+pub(super) struct TraceInfo {
+	pub ms_timestamp: i64,
+	pub pkt_sent_total: i64,
+	pub pkt_recv_total: i64,
+	pub pkt_snd_loss_total: c_int,
+	pub pkt_rcv_loss_total: c_int,
+	pub pkt_retrans_total: c_int,
+	pub pkt_sent_ack_total: c_int,
+	pub pkt_recv_ack_total: c_int,
+	pub pkt_sent_nak_total: c_int,
+	pub pkt_recv_nak_total: c_int,
+	pub us_snd_duration_total: i64,
+
+	pub pkt_sent: i64,
+	pub pkt_recv: i64,
+	pub pkt_snd_loss: c_int,
+	pub pkt_rcv_loss: c_int,
+	pub pkt_retrans: c_int,
+	pub pkt_sent_ack: c_int,
+	pub pkt_recv_ack: c_int,
+	pub pkt_sent_nak: c_int,
+	pub pkt_recv_nak: c_int,
+	pub mbps_send_rate: f64,
+	pub mbps_recv_rate: f64,
+	pub us_snd_duration: i64,
+
+	pub us_pkt_snd_period: f64,
+	pub pkt_flow_window: c_int,
+	pub pkt_congestion_window: c_int,
+	pub pkt_flight_size: c_int,
+	pub ms_rtt: f64,
+	pub mbps_bandwidth: f64,
+	pub byte_avail_snd_buf: c_int,
+	pub byte_avail_rcv_buf: c_int,
+}
+
+extern "C" {
+	fn udt_perfmon(sock: c_int, perf: *mut TraceInfo, clear: c_int) -> c_int;
+	fn udt_sendmsg(sock: c_int, buf: *const c_char, len: c_int, ttl: c_int, inorder: c_int) -> c_int;
+	fn udt_getlasterror_code() -> c_int;
+	fn udt_getlasterror_desc() -> *const c_char;
+}
+
+/// What UDT's calls return when they fail.
+const UDT_ERROR: c_int = -1;
+
+// `socket_id()` relies on a `UdtSocket` being laid out as UDT's id for the
+// socket, which these check for as far as the compiler can.
+const _: () = assert!(mem::size_of::<UdtSocket>() == mem::size_of::<c_int>());
+const _: () = assert!(mem::align_of::<UdtSocket>() == mem::align_of::<c_int>());
+
+/// Returns UDT's id for `sock`, which its C wrapper is called with.
+fn socket_id(sock: UdtSocket) -> c_int {
+	// SAFETY: the bindings keep UDT's id for the socket to themselves, w/ no
+	// accessor for it. In udt 0.2.0 a `UdtSocket` is a struct w/ that id (a
+	// `c_int`) as its only field. W/o `#[repr(transparent)]` its layout is
+	// not promised, but the asserts above fail the build unless it is the
+	// same size & alignment as a `c_int`, and then the id is all there is for
+	// its bytes to hold. The dependency is pinned to that exact release in
+	// Cargo.toml, so an upgrade which changes the struct has to come through
+	// here. A stale id is harmless, UDT just fails the call.
+	unsafe { mem::transmute::<UdtSocket, c_int>(sock) }
+}
+
+/// Reads the performance monitor of `sock` without clearing its counters, or
+/// returns `None` if UDT cannot. (e.g: the socket has been closed.)
+pub(super) fn perfmon(sock: UdtSocket) -> Option<TraceInfo> {
+	let mut info = TraceInfo::default();
+	match unsafe { udt_perfmon(socket_id(sock), &mut info, 0) } {
+		0 => Some(info),
+		_ => None,
+	}
+}
+
+/// Sends `buf` as a single message on the datagram socket `sock`, like
+/// `UdtSocket::sendmsg()`, which always sends a message which never expires
+/// & is delivered in order. Here the message is dropped if it has not been
+/// delivered within `ttl` (unless it is `None`), and `in_order` decides if
+/// it must wait on the messages sent before it.
+pub(super) fn sendmsg(sock: UdtSocket, buf: &[u8], ttl: Option<Duration>, in_order: bool) -> Result<i32, UdtError> {
+	// UDT counts the ttl in milliseconds, where -1 never expires
+	let ttl = ttl.map_or(-1, |ttl| ttl.as_millis().clamp(1, c_int::MAX as u128) as c_int);
+	let len = c_int::try_from(buf.len()).unwrap_or(c_int::MAX);
+
+	match unsafe { udt_sendmsg(socket_id(sock), buf.as_ptr() as *const c_char, len, ttl, in_order as c_int) } {
+		UDT_ERROR => Err(last_error()),
+		sent => Ok(sent),
+	}
+}
+
+/// The error UDT reported for the last call which failed on this thread.
+fn last_error() -> UdtError {
+	let desc = unsafe { CStr::from_ptr(udt_getlasterror_desc()) };
+	UdtError {
+		err_code: unsafe { udt_getlasterror_code() },
+		err_msg: desc.to_string_lossy().into_owned(),
+	}
+}
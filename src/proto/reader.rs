@@ -0,0 +1,575 @@
+use crate::proto::BLOCK_SIZE;
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::mem;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::thread;
+use std::time::Duration;
+
+/// How many chunks may be read ahead of the one being sent, so that a burst
+/// from a slow input is not held up waiting on the network, and vice versa.
+const READ_AHEAD: usize = 4;
+
+/// The shortest run of zeros which `spawn_zeros()` reports as a hole, a
+/// shorter one is not worth the `Skip` which would replace it.
+pub const ZERO_RUN_MIN: usize = 4096;
+
+/// The longest hole `spawn_zeros()` holds back while it waits to see where
+/// the run of zeros ends, a longer one is reported in pieces.
+const ZERO_RUN_MAX: u64 = 1 << 30;
+
+/// The result of waiting on a `ChunkReader`.
+pub enum Chunk {
+	/// Up to `BLOCK_SIZE` bytes were read from the input.
+	Data(Vec<u8>),
+
+	/// Up to `BLOCK_SIZE` bytes of a mapped file. (See: `spawn_mapped()`.)
+	Mapped(Region),
+
+	/// The input has a hole of this many bytes, which reads back as zeros.
+	Hole(u64),
+
+	/// The input has been exhausted.
+	Eof,
+
+	/// No data arrived before the timeout elapsed.
+	Idle,
+}
+
+/// The plaintext of a block, before it is sealed.
+pub enum Plain {
+	/// Data which was read into this buffer, & is sealed in place.
+	Read(Vec<u8>),
+
+	/// A region of a mapped file, which is copied into a buffer only as it
+	/// is sealed.
+	Mapped(Region),
+}
+
+impl Plain {
+	pub fn len(&self) -> usize {
+		self.as_slice().len()
+	}
+
+	pub fn as_slice(&self) -> &[u8] {
+		match self {
+			Plain::Read(buf) => buf,
+			Plain::Mapped(region) => region.as_slice(),
+		}
+	}
+}
+
+/// A region of a file mapped by `spawn_mapped()`, along w/ a spare buffer
+/// for it to be sealed in.
+pub struct Region {
+	mapping: Arc<Mapping>,
+	start: usize,
+	end: usize,
+	buf: Vec<u8>,
+}
+
+impl Region {
+	pub fn as_slice(&self) -> &[u8] {
+		&self.mapping.as_slice()[self.start..self.end]
+	}
+
+	/// Copies the region into its buffer, which it is sealed in.
+	pub fn into_buf(self) -> Vec<u8> {
+		let mut buf = self.buf;
+		buf.clear();
+		buf.extend_from_slice(&self.mapping.as_slice()[self.start..self.end]);
+		buf
+	}
+}
+
+/// Reads chunks of up to `BLOCK_SIZE` bytes from an input on a helper
+/// thread.
+///
+/// This frees the `Sender` to do other work (e.g: sending keepalives or
+/// noticing it was interrupted) while it waits on a slow input. Up to
+/// `READ_AHEAD` chunks are read while the previous chunk is being encrypted
+/// and sent. The buffers they are read into are handed back w/ `recycle()`
+/// once the sender is done w/ them, rather than allocating one per chunk.
+pub struct ChunkReader {
+	rx: Receiver<io::Result<Chunk>>,
+	recycle: Sender<Vec<u8>>,
+
+	/// A chunk taken by `next_data()` which was not data, or was unread.
+	peeked: Option<io::Result<Chunk>>,
+}
+
+impl ChunkReader {
+	pub fn spawn<R: Read + Send + 'static>(mut input: R) -> Self {
+		let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+		let (recycle, buffers) = mpsc::channel();
+
+		thread::spawn(move || loop {
+			let mut buf = next_buffer(&buffers);
+			let result = match input.read(&mut buf) {
+				// dropping `tx` tells the reader there is nothing left
+				Ok(0) => break,
+				Ok(len) => {
+					buf.truncate(len);
+					Ok(Chunk::Data(buf))
+				},
+
+				Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+				Err(err) => Err(err),
+			};
+
+			let is_err = result.is_err();
+			if tx.send(result).is_err() || is_err {
+				break;
+			}
+		});
+
+		Self { rx, recycle, peeked: None }
+	}
+
+	/// Like `spawn()`, but the holes in `file` are reported as `Chunk::Hole`
+	/// rather than being read as zeros.
+	pub fn spawn_sparse(mut file: File) -> Self {
+		let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+		let (recycle, buffers) = mpsc::channel();
+
+		thread::spawn(move || {
+			if let Err(err) = read_sparse(&mut file, &tx, &buffers) {
+				let _ = tx.send(Err(err));
+			}
+		});
+
+		Self { rx, recycle, peeked: None }
+	}
+
+	/// Like `spawn()`, but long runs of zeros in `input` are reported as
+	/// `Chunk::Hole` rather than as data. This finds the holes in a stream
+	/// which cannot be asked where they are. (i.e: a disk image piped through
+	/// `dd`.)
+	pub fn spawn_zeros<R: Read + Send + 'static>(mut input: R) -> Self {
+		let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+		let (recycle, buffers) = mpsc::channel();
+
+		thread::spawn(move || {
+			if let Err(err) = read_zeros(&mut input, &tx, &buffers) {
+				let _ = tx.send(Err(err));
+			}
+		});
+
+		Self { rx, recycle, peeked: None }
+	}
+
+	/// Like `spawn()`, but each chunk ends w/ a newline, and a partial line
+	/// is held back until the rest of it is read. (Or the chunk is full.) This
+	/// hands off each line of a log as soon as it is complete, rather than in
+	/// whatever pieces it was written.
+	pub fn spawn_lines<R: Read + Send + 'static>(mut input: R) -> Self {
+		let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+		let (recycle, buffers) = mpsc::channel();
+
+		thread::spawn(move || {
+			if let Err(err) = read_lines(&mut input, &tx, &buffers) {
+				let _ = tx.send(Err(err));
+			}
+		});
+
+		Self { rx, recycle, peeked: None }
+	}
+
+	/// Like `spawn()`, but `file` is mapped into memory, rather than being
+	/// read w/ a syscall per chunk. Each chunk is a `Chunk::Mapped` region of
+	/// the mapping, which is not copied until it is sealed: straight into the
+	/// buffer it is sealed & sent from, by the thread which seals it. (ring
+	/// only seals in place, so the ciphertext needs a buffer of its own.) This
+	/// falls back to `spawn()` if the file cannot be mapped.
+	///
+	/// The file must not be truncated while it is mapped, reading past its
+	/// end kills the process w/ `SIGBUS`. So this is only used if it is asked
+	/// for. (See: `Sender::set_mmap()`.)
+	pub fn spawn_mapped(file: File) -> Self {
+		let mapping = match Mapping::new(&file) {
+			Ok(Some(mapping)) => mapping,
+			Ok(None) => return Self::spawn(file),
+			Err(err) => {
+				debug!("could not map input, reading it instead: {}", err);
+				return Self::spawn(file);
+			},
+		};
+
+		let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+		let (recycle, buffers) = mpsc::channel();
+
+		thread::spawn(move || {
+			let mapping = Arc::new(mapping);
+			let len = mapping.as_slice().len();
+
+			for start in (0..len).step_by(BLOCK_SIZE) {
+				let region = Region {
+					mapping: Arc::clone(&mapping),
+					start,
+					end: len.min(start + BLOCK_SIZE),
+					buf: buffers.try_recv().unwrap_or_default(),
+				};
+
+				if tx.send(Ok(Chunk::Mapped(region))).is_err() {
+					break;
+				}
+			}
+		});
+
+		Self { rx, recycle, peeked: None }
+	}
+
+	/// Returns the buffer of a `Chunk::Data` so that it can be read into again.
+	pub fn recycle(&self, buf: Vec<u8>) {
+		// the helper thread is gone once the input is exhausted
+		let _ = self.recycle.send(buf);
+	}
+
+	/// Waits up to `timeout` for the next chunk of input.
+	pub fn next(&mut self, timeout: Duration) -> Result<Chunk, io::Error> {
+		if let Some(chunk) = self.peeked.take() {
+			return chunk;
+		}
+
+		match self.rx.recv_timeout(timeout) {
+			Ok(chunk) => chunk,
+			Err(RecvTimeoutError::Timeout) => Ok(Chunk::Idle),
+			Err(RecvTimeoutError::Disconnected) => Ok(Chunk::Eof),
+		}
+	}
+
+	/// Returns the next chunk of input without waiting, if it has already
+	/// been read and is data. Anything else is left for `next()`.
+	pub fn next_ready(&mut self) -> Option<Plain> {
+		if self.peeked.is_some() {
+			return None;
+		}
+
+		match self.rx.try_recv() {
+			Ok(Ok(Chunk::Data(buf))) => Some(Plain::Read(buf)),
+			Ok(Ok(Chunk::Mapped(region))) => Some(Plain::Mapped(region)),
+			Ok(chunk) => {
+				self.peeked = Some(chunk);
+				None
+			},
+
+			Err(_) => None,
+		}
+	}
+
+	/// Like `next_ready()`, but waits up to `timeout` for the chunk to be
+	/// read, & only takes a chunk which was read into a buffer.
+	pub fn next_data(&mut self, timeout: Duration) -> Option<Vec<u8>> {
+		if self.peeked.is_some() {
+			return None;
+		}
+
+		match self.rx.recv_timeout(timeout) {
+			Ok(Ok(Chunk::Data(buf))) => Some(buf),
+			Ok(chunk) => {
+				self.peeked = Some(chunk);
+				None
+			},
+
+			Err(_) => None,
+		}
+	}
+
+	/// Hands back a chunk of data taken by `next_data()`, which `next()`
+	/// returns again.
+	pub fn unread(&mut self, buf: Vec<u8>) {
+		self.peeked = Some(Ok(Chunk::Data(buf)));
+	}
+}
+
+/// Reads the data regions of `file` in order, and hands off the holes which
+/// separate them. This stops early, without an error, if the `ChunkReader`
+/// was dropped.
+fn read_sparse(file: &mut File, tx: &SyncSender<io::Result<Chunk>>, buffers: &Receiver<Vec<u8>>) -> Result<(), io::Error> {
+	let len = file.metadata()?.len();
+	let mut pos = 0;
+
+	while pos < len {
+		let (start, end) = data_region(file, pos, len)?
+			.unwrap_or((len, len));
+
+		if start > pos && tx.send(Ok(Chunk::Hole(start - pos))).is_err() {
+			return Ok(());
+		}
+
+		file.seek(SeekFrom::Start(start))?;
+		let mut region = (&mut *file).take(end - start);
+		loop {
+			let mut buf = next_buffer(buffers);
+			let read = match region.read(&mut buf) {
+				Ok(0) => break,
+				Ok(read) => read,
+				Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+				Err(err) => return Err(err),
+			};
+
+			buf.truncate(read);
+			if tx.send(Ok(Chunk::Data(buf))).is_err() {
+				return Ok(());
+			}
+		}
+
+		// the file shrank while it was being read
+		if region.limit() > 0 {
+			return Ok(());
+		}
+
+		pos = end;
+	}
+
+	Ok(())
+}
+
+/// Reads `input` until EOF, handing off everything up to the last newline
+/// read so far. This stops early, without an error, if the `ChunkReader` was
+/// dropped.
+fn read_lines<R: Read>(input: &mut R, tx: &SyncSender<io::Result<Chunk>>, buffers: &Receiver<Vec<u8>>) -> Result<(), io::Error> {
+	let mut buf = next_buffer(buffers);
+	let mut len = 0;
+
+	loop {
+		let read = match input.read(&mut buf[len..]) {
+			Ok(0) => break,
+			Ok(read) => read,
+			Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+			Err(err) => return Err(err),
+		};
+
+		// only the bytes just read can hold a newline, the rest were a partial line
+		let start = len;
+		len += read;
+		let end = match buf[start..len].iter().rposition(|&byte| byte == b'\n') {
+			Some(pos) => start + pos + 1,
+			None if len == buf.len() => len,
+			None => continue,
+		};
+
+		let mut next = next_buffer(buffers);
+		next[..len - end].copy_from_slice(&buf[end..len]);
+		buf.truncate(end);
+		len -= end;
+
+		if tx.send(Ok(Chunk::Data(mem::replace(&mut buf, next)))).is_err() {
+			return Ok(());
+		}
+	}
+
+	if len > 0 {
+		buf.truncate(len);
+		let _ = tx.send(Ok(Chunk::Data(buf)));
+	}
+
+	Ok(())
+}
+
+/// Reads `input` until EOF, handing off the data & the runs of zeros which
+/// separate it. This stops early, without an error, if the `ChunkReader` was
+/// dropped.
+fn read_zeros<R: Read>(input: &mut R, tx: &SyncSender<io::Result<Chunk>>, buffers: &Receiver<Vec<u8>>) -> Result<(), io::Error> {
+	let mut runs = ZeroRuns { tx, buffers, data: vec![], zeros: 0 };
+	let mut buf = vec![0u8; BLOCK_SIZE];
+
+	loop {
+		let read = match input.read(&mut buf) {
+			Ok(0) => break,
+			Ok(read) => read,
+			Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+			Err(err) => return Err(err),
+		};
+
+		if !runs.push(&buf[..read]) {
+			return Ok(());
+		}
+	}
+
+	runs.finish();
+	Ok(())
+}
+
+/// Splits the input of `spawn_zeros()` into data & holes. The length of a
+/// run of zeros is only known once it ends, so it is held back until then,
+/// while data is handed off as soon as it is read. Each method returns false
+/// once the `ChunkReader` is gone.
+struct ZeroRuns<'a> {
+	tx: &'a SyncSender<io::Result<Chunk>>,
+	buffers: &'a Receiver<Vec<u8>>,
+	data: Vec<u8>,
+	zeros: u64,
+}
+
+impl ZeroRuns<'_> {
+	fn push(&mut self, mut bytes: &[u8]) -> bool {
+		while !bytes.is_empty() {
+			let zeros = bytes.iter().position(|&byte| byte != 0).unwrap_or(bytes.len());
+			self.zeros += zeros as u64;
+			bytes = &bytes[zeros..];
+
+			if bytes.is_empty() {
+				break;
+			}
+
+			if !self.end_run() {
+				return false;
+			}
+
+			let data = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+			self.data.extend_from_slice(&bytes[..data]);
+			bytes = &bytes[data..];
+		}
+
+		if self.zeros >= ZERO_RUN_MAX && !self.end_run() {
+			return false;
+		}
+
+		self.send_data()
+	}
+
+	/// Hands off the run of zeros which just ended: a long one as a hole,
+	/// and a short one as part of the data.
+	fn end_run(&mut self) -> bool {
+		let zeros = mem::replace(&mut self.zeros, 0);
+		if zeros < ZERO_RUN_MIN as u64 {
+			self.data.resize(self.data.len() + zeros as usize, 0);
+			return true;
+		}
+
+		self.send_data() && self.tx.send(Ok(Chunk::Hole(zeros))).is_ok()
+	}
+
+	fn send_data(&mut self) -> bool {
+		for piece in self.data.chunks(BLOCK_SIZE) {
+			let mut buf = next_buffer(self.buffers);
+			buf.truncate(piece.len());
+			buf.copy_from_slice(piece);
+
+			if self.tx.send(Ok(Chunk::Data(buf))).is_err() {
+				return false;
+			}
+		}
+
+		self.data.clear();
+		true
+	}
+
+	fn finish(&mut self) -> bool {
+		self.end_run() && self.send_data()
+	}
+}
+
+/// Returns a buffer of `BLOCK_SIZE` bytes to read into, reusing one which
+/// was recycled by the sender if there is one.
+fn next_buffer(buffers: &Receiver<Vec<u8>>) -> Vec<u8> {
+	match buffers.try_recv() {
+		Ok(mut buf) => {
+			buf.resize(BLOCK_SIZE, 0);
+			buf
+		},
+
+		Err(_) => vec![0u8; BLOCK_SIZE],
+	}
+}
+
+/// A file mapped read-only into memory, which is unmapped once dropped.
+#[cfg(target_os = "linux")]
+struct Mapping {
+	ptr: *mut libc::c_void,
+	len: usize,
+}
+
+// the mapping is only ever read, by the threads which seal its regions
+#[cfg(target_os = "linux")]
+unsafe impl Send for Mapping {}
+
+#[cfg(target_os = "linux")]
+unsafe impl Sync for Mapping {}
+
+#[cfg(target_os = "linux")]
+impl Mapping {
+	/// Maps the whole of `file`, or returns `None` if it is empty. (i.e: there
+	/// is nothing to map.)
+	fn new(file: &File) -> Result<Option<Self>, io::Error> {
+		use std::os::unix::io::AsRawFd;
+		use std::ptr;
+
+		let len = file.metadata()?.len() as usize;
+		if len == 0 {
+			return Ok(None);
+		}
+
+		let ptr = unsafe {
+			libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+		};
+
+		if ptr == libc::MAP_FAILED {
+			return Err(io::Error::last_os_error());
+		}
+
+		// the kernel reads further ahead for a mapping read front to back
+		unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+
+		Ok(Some(Self { ptr, len }))
+	}
+
+	fn as_slice(&self) -> &[u8] {
+		unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+	}
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Mapping {
+	fn drop(&mut self) {
+		unsafe { libc::munmap(self.ptr, self.len) };
+	}
+}
+
+/// Files are only mapped on Linux, so every file is read instead.
+#[cfg(not(target_os = "linux"))]
+struct Mapping;
+
+#[cfg(not(target_os = "linux"))]
+impl Mapping {
+	fn new(_file: &File) -> Result<Option<Self>, io::Error> {
+		Ok(None)
+	}
+
+	fn as_slice(&self) -> &[u8] {
+		&[]
+	}
+}
+
+/// Returns the bounds of the first region of `file` at or after `pos` which
+/// holds data, or `None` if the rest of the file is a hole.
+#[cfg(target_os = "linux")]
+fn data_region(file: &File, pos: u64, _len: u64) -> Result<Option<(u64, u64)>, io::Error> {
+	use std::os::unix::io::AsRawFd;
+
+	let fd = file.as_raw_fd();
+	let start = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+	if start < 0 {
+		let err = io::Error::last_os_error();
+		return match err.raw_os_error() {
+			Some(libc::ENXIO) => Ok(None),
+			_ => Err(err),
+		};
+	}
+
+	let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+	if end < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	Ok(Some((start as u64, end as u64)))
+}
+
+/// Holes cannot be found on this platform, so the whole file is data.
+#[cfg(not(target_os = "linux"))]
+fn data_region(_file: &File, pos: u64, len: u64) -> Result<Option<(u64, u64)>, io::Error> {
+	Ok(if pos < len { Some((pos, len)) } else { None })
+}
@@ -0,0 +1,169 @@
+use crate::error::ProtoError;
+use crate::key;
+use crate::proto::identity::{self, Identity, PUBLIC_KEY_LEN};
+use crate::proto::session::{SessionId, SESSION_ID_LEN};
+use crate::proto::BLOCK_SIZE;
+
+use byteorder::{ByteOrder, NetworkEndian};
+use ring::digest;
+use std::fmt;
+use std::mem;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The length of the SHA-256 digest of a session's data.
+pub const DIGEST_LEN: usize = 32;
+
+/// The length of a receipt on the wire: the fields the receiver signs,
+/// followed by its public key & signature.
+pub const RECEIPT_LEN: usize = SIGNED_LEN + identity::IDENTITY_LEN;
+
+/// The session id, the digest, then the byte count & timestamp as big-endian
+/// `u64`s.
+const SIGNED_LEN: usize = SESSION_ID_LEN + DIGEST_LEN + 2 * mem::size_of::<u64>();
+
+/// Prefixed to the fields of a receipt when they are signed, so that the
+/// signature cannot be passed off as one made over a handshake.
+const RECEIPT_CONTEXT: &[u8] = b"ubuffer receipt v1";
+
+/// The SHA-256 of the data moved during a session, as it was read from the
+/// input or written to the output.
+///
+/// A hole skipped by a sparse sender counts as the zeros it stands for, so
+/// the digest matches the `sha256sum` of the file either end sees.
+pub(super) struct StreamDigest {
+	context: digest::Context,
+}
+
+impl StreamDigest {
+	pub fn new() -> Self {
+		Self { context: digest::Context::new(&digest::SHA256) }
+	}
+
+	pub fn update(&mut self, data: &[u8]) {
+		self.context.update(data);
+	}
+
+	pub fn update_zeros(&mut self, mut len: u64) {
+		let zeros = [0u8; BLOCK_SIZE];
+		while len > 0 {
+			let chunk = len.min(BLOCK_SIZE as u64) as usize;
+			self.context.update(&zeros[..chunk]);
+			len -= chunk as u64;
+		}
+	}
+
+	pub fn finish(&self) -> [u8; DIGEST_LEN] {
+		let mut digest = [0u8; DIGEST_LEN];
+		digest.copy_from_slice(self.context.clone().finish().as_ref());
+		digest
+	}
+}
+
+/// The receiver's signed statement that it received & checked a session's
+/// data, which it sends to the sender in its `MessageTy::Goodbye`.
+///
+/// Only a receiver w/ an identity which it proved during the handshake can
+/// issue one. The receipt carries the receiver's public key, so that it can
+/// be checked long after the session is over. (See: `fmt::Display`.)
+#[derive(Clone, Debug)]
+pub struct Receipt {
+	/// The session the receipt was issued for.
+	pub session_id: SessionId,
+
+	/// The SHA-256 of the data the receiver received.
+	pub digest: [u8; DIGEST_LEN],
+
+	/// The number of bytes the receiver received.
+	pub bytes: u64,
+
+	/// When the receiver issued the receipt, in seconds since the epoch.
+	pub timestamp: u64,
+
+	/// The receiver's public key followed by its signature.
+	identity: Vec<u8>,
+}
+
+impl Receipt {
+	/// Signs a receipt for the data which was received during `session_id`.
+	pub(super) fn issue(identity: &Identity, session_id: SessionId, digest: [u8; DIGEST_LEN], bytes: u64) -> Result<Self, ProtoError> {
+		let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+			.map(|since| since.as_secs())
+			.unwrap_or(0);
+
+		let mut receipt = Self { session_id, digest, bytes, timestamp, identity: vec![] };
+		receipt.identity = identity.sign_bytes(&receipt.signed_bytes())?;
+		Ok(receipt)
+	}
+
+	/// Decodes a receipt read from the receiver, checking its signature.
+	pub(super) fn decode(buf: &[u8]) -> Result<Self, ProtoError> {
+		if buf.len() != RECEIPT_LEN {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		let (signed, identity) = buf.split_at(SIGNED_LEN);
+		let (session_id, rest) = signed.split_at(SESSION_ID_LEN);
+		let (digest, counts) = rest.split_at(DIGEST_LEN);
+
+		let mut receipt = Self {
+			session_id: SessionId::from_bytes(session_id).ok_or(ProtoError::MalformedMessage)?,
+			digest: [0u8; DIGEST_LEN],
+			bytes: NetworkEndian::read_u64(&counts[..mem::size_of::<u64>()]),
+			timestamp: NetworkEndian::read_u64(&counts[mem::size_of::<u64>()..]),
+			identity: identity.to_vec(),
+		};
+
+		receipt.digest.copy_from_slice(digest);
+		identity::verify(&receipt.signed_bytes(), &receipt.identity)?;
+		Ok(receipt)
+	}
+
+	/// Encodes the receipt for the wire.
+	pub(super) fn encode(&self) -> Vec<u8> {
+		let mut buf = self.fields();
+		buf.extend_from_slice(&self.identity);
+		buf
+	}
+
+	/// The fingerprint of the receiver which signed the receipt.
+	pub fn fingerprint(&self) -> String {
+		key::fingerprint(&self.identity[..PUBLIC_KEY_LEN])
+	}
+
+	fn fields(&self) -> Vec<u8> {
+		let mut buf = Vec::with_capacity(RECEIPT_LEN);
+		buf.extend_from_slice(self.session_id.as_bytes());
+		buf.extend_from_slice(&self.digest);
+
+		let mut counts = [0u8; 2 * mem::size_of::<u64>()];
+		NetworkEndian::write_u64(&mut counts[..mem::size_of::<u64>()], self.bytes);
+		NetworkEndian::write_u64(&mut counts[mem::size_of::<u64>()..], self.timestamp);
+		buf.extend_from_slice(&counts);
+		buf
+	}
+
+	fn signed_bytes(&self) -> Vec<u8> {
+		[RECEIPT_CONTEXT, &self.fields()].concat()
+	}
+}
+
+/// Formats the receipt as it is stored in a receipt file, one field per line.
+/// The signature is made over `ubuffer receipt v1`, followed by the session
+/// id, the digest, and then the byte count & timestamp as big-endian `u64`s.
+impl fmt::Display for Receipt {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let (public_key, signature) = self.identity.split_at(PUBLIC_KEY_LEN);
+
+		writeln!(f, "session: {}", self.session_id)?;
+		writeln!(f, "sha256: {}", hex(&self.digest))?;
+		writeln!(f, "bytes: {}", self.bytes)?;
+		writeln!(f, "timestamp: {}", self.timestamp)?;
+		writeln!(f, "receiver: {}", self.fingerprint())?;
+		writeln!(f, "public-key: {}", hex(public_key))?;
+		writeln!(f, "signature: {}", hex(signature))
+	}
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
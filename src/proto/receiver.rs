@@ -1,14 +1,40 @@
 use crate::error::ProtoError;
-use crate::proto::util;
-use crate::proto::{MessageTy, Message, Mode, State, Stream};
-use crate::proto::{BLOCK_SIZE, MAGIC_BYTES, MESSAGE_SIZE};
+use crate::key;
+use crate::proto::cipher::{self, CIPHER_AUTO};
+use crate::proto::features::FEATURES_LEN;
+use crate::proto::fingerprint;
+use crate::proto::health::{SharedObserver, Watchdog};
+use crate::proto::identity::{self, Ephemeral, IdentityCheck, Transcript, CHALLENGE_LEN};
+use crate::proto::padding;
+use crate::proto::pake::{Pake, ELEMENT_LEN};
+use crate::proto::receipt::{StreamDigest, DIGEST_LEN};
+use crate::proto::sink::{self, Direct, Finished, Seeking, Sink, Zeros};
+use crate::proto::session::SESSION_ID_LEN;
+use crate::proto::summary::StatsTimer;
+use crate::proto::workers::{Block, Workers};
+use crate::proto::resume::{self, Checkpoint, Reconnect, Resuming, Standby, Tokens, CHECKPOINT_INTERVAL, CHECKPOINT_VERSION, RESUME_RETRY};
+use crate::proto::{banner, connect, event, fault, reconnector, resolve, util};
+use crate::proto::{CancelToken, Cipher, Event, Features, FileMeta, Identity, LinkStats, MessageTy, Message, Mode, Preserve, Receipt, SessionId, State, StreamOpts, Summary, Transport};
+use crate::proto::{ACK_INTERVAL, ACK_LEN, GOODBYE_LEN, HELLO_LEN, MAGIC_BYTES, MESSAGE_SIZE, REKEY_SALT_LEN, RESUME_TIMEOUT};
 
-use byteorder::{NetworkEndian, WriteBytesExt};
-use rand::Rng;
+use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt, WriteBytesExt};
+use rand::{FromEntropy, Rng, SeedableRng};
+use rand::rngs::StdRng;
 use ring::aead::{self, OpeningKey, SealingKey};
-use std::io::{Cursor, Read, Write};
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 use std::mem;
 use std::net::ToSocketAddrs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many times in a row the receiver asks for a block to be resent before
+/// it gives up, so that a sender w/ the wrong key is not retried forever.
+const MAX_NACKS: u32 = 3;
 
 /// The `Receiver` represents the listening half of a `ubuffer`.
 /// 
@@ -25,133 +51,1363 @@ use std::net::ToSocketAddrs;
 /// 2. `State:Transmit`: the receiver waits for incoming blocks. The only
 ///    messages which are legal during this point are either `MessageTy::Block`
 ///    which specifies the length of an encrypted payload, or `MessageTy::Goodbye`
-///    which indicates that the sender wants to hang up.
+///    which indicates that the sender wants to hang up. A `MessageTy::Ping`
+///    may arrive between blocks while the sender's input is idle, each one is
+///    answered with a `MessageTy::Pong`. A `MessageTy::Metadata` describing
+///    the sender's input may precede the first block. In a multi-file session
+///    the blocks of each file are bracketed by `MessageTy::FileStart` and
+///    `MessageTy::FileEnd`, and are written to a file in the output directory
+///    rather than to the receiver's output. A `MessageTy::Skip` stands in for
+///    a hole in the sender's input, the receiver seeks past it when writing
+///    to a file and writes zeros otherwise. (See: `run_seekable()`.) A
+///    sender which pads its traffic sends `MessageTy::Padded` in place of
+///    each block, the receiver strips the padding & discards the empty ones.
+///
+/// 3. `State::WaitHangup` the receiver enters this state after receiving a
+///    goodbye, once it has checked the totals sealed in it match what it
+///    received, so that a forged goodbye cannot cut the transfer short. In
+///    this state the receiver performs its end of the closing handshake, and
+///    then terminates the `run()` loop.
+///
+/// If the sender aborts the transfer, or the receiver's own interrupt flag is
+/// raised, the receiver flushes its output and closes the connection before
+/// stopping with `ProtoError::PeerAborted` or `ProtoError::Interrupted`. A
+/// receiver cancelled through its `CancelToken` likewise flushes its output,
+/// then makes a best-effort attempt to send the sender a `MessageTy::Abort`
+/// before stopping with `ProtoError::Cancelled`.
+///
+/// If a block fails to open, the receiver answers w/ a `MessageTy::Nack`
+/// asking the sender to resend it. Everything up to the resent block is
+/// discarded, after which the transfer carries on as if nothing happened.
+///
+/// A receiver w/ a resume timeout issues the sender a `MessageTy::Token` once
+/// the handshake is complete. If the connection then drops mid-transfer, the
+/// receiver waits that long for the sender to reconnect and present it in a
+/// `MessageTy::Resume`, signing the receiver's challenge to prove it holds the
+/// session key. It answers w/ the sequence number of the message after the
+/// last one it handled in full, and carries on from there.
+///
+/// A sender may send a challenge w/ its `MessageTy::ReqIV`, which the
+/// receiver answers w/ one of its own in the `MessageTy::RepIV`. Once their
+/// `MessageTy::Hello`s are through, each peer sends a `MessageTy::Identity`
+/// signing the handshake, which is empty for a peer w/o an `Identity`. A
+/// receiver w/ an identity check hangs up unless the sender's signature
+/// holds and the check accepts it, or if the sender sent no challenge. Each
+/// challenge is an ephemeral X25519 share, & the secret agreed on w/ them is
+/// mixed into the session key once the identities are checked.
+///
+/// A receiver which proved its identity this way answers the sender's
+/// `MessageTy::Goodbye` w/ a `Receipt`, signing the digest & length of the
+/// data it received once it has checked them against the sender's.
 ///
-/// 3. `State::WaitHangup` the receiver enters this state after receiving a goodbye.
-///    In this state the receiver performs its end of the closing handshake, and then
-///    terminates the `run()` loop.
+/// A receiver given a short code rather than a key expects the sender to
+/// open w/ a `MessageTy::Pake`, answers w/ its own, and uses the key they
+/// agree on from there on. (See: `Receiver::set_code()`.)
+///
+/// If both peers support `Features::ACKS` the receiver sends a
+/// `MessageTy::Ack` each time another `ACK_INTERVAL` messages were handled in
+/// full, carrying the message it expects next (i.e: where the session would
+/// resume from) & how many bytes it has written.
+///
+/// A receiver w/ a quiet threshold warns each time that long passes w/o a
+/// message from the sender once blocks are flowing, rather than waiting on it
+/// in silence. The sender's keepalives count, so a sender which is merely
+/// idle is not mistaken for one which is stalled or gone. (See: `Watchdog`.)
 ///
 pub struct Receiver {
-	dec_key: OpeningKey,
+	key: Vec<u8>,
+	keys: Vec<Vec<u8>>,
+	code: Option<String>,
+	cipher: Cipher,
+	forced_cipher: Option<Cipher>,
+	identity: Option<Identity>,
+	identity_check: Option<IdentityCheck>,
+	transcript: Transcript,
+	challenged: bool,
+
+	/// The session key once both identities are checked, mixed w/ the secret
+	/// agreed on w/ the challenges. (See: `identity::Ephemeral`.)
+	mixed_key: Option<Vec<u8>>,
+	advertised: Features,
+	features: Features,
+	dec_key: Arc<OpeningKey>,
 	enc_key: SealingKey,
+	epoch: u64,
 
-	stream: Stream,
+	/// Seals our replies, once the sender's hello named the session. (See:
+	/// `util::derive_reply_key()`.)
+	reply_key: Option<SealingKey>,
+
+	/// The salt of the last rekey, which the session key was derived from.
+	rekey_salt: Option<Vec<u8>>,
+
+	stream: Box<dyn Transport>,
 	state: State,
+	shake: Shake,
 
 	counter: u64,
 	nonce:   u32,
+	rng: StdRng,
+
+	interrupt: Arc<AtomicBool>,
+	cancel: CancelToken,
+
+	started: Instant,
+	summary: Summary,
+	stats: StatsTimer,
+	digest: StreamDigest,
+	receipt: Option<Receipt>,
+
+	observer: SharedObserver,
+	quiet_threshold: Duration,
+	watchdog: Option<Watchdog>,
+
+	metadata: Option<FileMeta>,
+
+	/// Another handle to the output, if it is a file, where space is reserved
+	/// for the file the sender describes & which is synced to disk.
+	output_file: Option<File>,
+
+	fsync_interval: Option<Duration>,
+	last_fsync: Instant,
+	sync_on_close: bool,
+
+	output_dir: Option<PathBuf>,
+	preserve: Preserve,
+	current: Option<OutputFile>,
+
+	tees: Vec<Box<dyn Write + Send>>,
+
+	lost: Option<u64>,
+	nacks: u32,
+
+	/// The furthest message which was passed over since the last nack, all of
+	/// which the sender is resending. (See: `Transport::lossy()`.)
+	passed: u64,
+
+	workers: Option<Workers>,
+	buffers: Vec<Vec<u8>>,
+
+	/// The header which ended the last batch of blocks, still to be handled.
+	peeked: Option<[u8; MESSAGE_SIZE]>,
+
+	/// The sequence number of the last message which was handled in full.
+	handled: u64,
+
+	/// The last message the sender was told was handled. (See: `MessageTy::Ack`.)
+	acked: u64,
+
+	tokens: Option<Tokens>,
+	reconnect: Option<Reconnect>,
+	resume_timeout: Duration,
+
+	/// Where the session was last resumed from, if it has been.
+	resumed_at: Option<u64>,
+
+	/// Set if the sender may resume from another address before the receiver
+	/// notices the connection dropped, which the `standby` accepts it on.
+	migrate: bool,
+	standby: Option<Standby>,
+
+	checkpoint: Option<PathBuf>,
+	checkpointed: u64,
+
+	/// Set once the session was restored from a checkpoint, until the sender
+	/// has presented its token.
+	restoring: bool,
+
+	/// Set if the session was restored from a checkpoint, in which case the
+	/// digest of what came before it is unknown.
+	restored: bool,
+}
+
+/// How far the handshake has got. Each step reads one message from the
+/// sender, then writes whatever answers it. (See: `Receiver::shake()`.)
+enum Shake {
+	Start,
+	Banner,
+	Pake(String),
+	ReqIV,
+	Hello,
+	Identity,
+	Done,
+}
+
+/// A file being written in a multi-file session.
+struct OutputFile {
+	file: File,
+	path: PathBuf,
+	metadata: FileMeta,
+	bytes: u64,
 }
 
 impl Receiver {
-	/// Creates a `Receiver` which listens on the specified network address (`addr`)
-	/// and will use the `key` to decrypt incoming packets. Note that a Receiver will
-	/// only `accept()` a single incoming connection, all other clients will be ignored.
+	/// Creates a `Receiver` which listens on the specified network address (`addr`),
+	/// or every address it resolves to, and will use the `key` to decrypt incoming
+	/// packets. Note that a Receiver will only `accept()` a single incoming connection
+	/// (on whichever address it arrives first), all other clients will be ignored.
 	/// If a client connects and fails to create the proper handshake the receiver will
-	/// eventually timeout and exit.
-	pub fn new<S: ToSocketAddrs>(addr: S, key: &[u8]) -> Result<Self, ProtoError> {
+	/// eventually timeout and exit. The `opts` control how the underlying socket
+	/// is set up. (e.g: to dial a listening sender instead.)
+	pub fn new<S: ToSocketAddrs>(addr: S, key: &[u8], opts: &StreamOpts) -> Result<Self, ProtoError> {
 		info!("starting receiver ...");
-		let stream = Stream::new(Mode::Receiver, addr)?;
-		let dec_key = OpeningKey::new(&aead::AES_256_GCM, key)?;
-		let enc_key = SealingKey::new(&aead::AES_256_GCM, key)?;
+		let addrs = resolve(addr, opts.family)?;
+		let stream = connect(Mode::Receiver, &addrs[..], opts)?;
+
+		let mut receiver = Self::with_transport(stream, key)?;
+		receiver.reconnect = Some(reconnector(Mode::Receiver, addrs, opts));
+		Ok(receiver)
+	}
+
+	/// Creates a `Receiver` which runs over an established `transport`
+	/// instead of a UDT socket of its own. (e.g: one accepted by a `Hub`.)
+	pub fn with_transport<T: Transport + 'static>(transport: T, key: &[u8]) -> Result<Self, ProtoError> {
+		let cipher = Cipher::for_key(key)?;
+		let dec_key = Arc::new(OpeningKey::new(cipher.algorithm(), key)?);
+		let enc_key = SealingKey::new(cipher.algorithm(), key)?;
 		info!("accepted connection ...");
 
 		Ok(Self {
-			dec_key: dec_key,
-			enc_key: enc_key,
+			key: key.to_vec(),
+			keys: vec![],
+			code: None,
+			cipher,
+			forced_cipher: None,
+			identity: None,
+			identity_check: None,
+			transcript: Transcript::new(),
+			challenged: false,
+			mixed_key: None,
+			advertised: Features::all(),
+			features: Features::empty(),
+			dec_key,
+			enc_key,
+			epoch: 0,
+			reply_key: None,
+			rekey_salt: None,
 
-			stream: stream,
+			stream: Box::new(transport),
 			state: State::WaitHello,
+			shake: Shake::Start,
 
 			counter: 0,
 			nonce:   0,
+			rng: StdRng::from_entropy(),
+
+			interrupt: Arc::new(AtomicBool::new(false)),
+			cancel: CancelToken::default(),
+
+			started: Instant::now(),
+			summary: Summary::default(),
+			stats: StatsTimer::new(Duration::from_secs(0)),
+			digest: StreamDigest::new(),
+			receipt: None,
+
+			observer: SharedObserver::default(),
+			quiet_threshold: Duration::from_secs(0),
+			watchdog: None,
+
+			metadata: None,
+
+			output_file: None,
+
+			fsync_interval: None,
+			last_fsync: Instant::now(),
+			sync_on_close: false,
+
+			output_dir: None,
+			preserve: Preserve::default(),
+			current: None,
+
+			tees: vec![],
+
+			lost: None,
+			nacks: 0,
+			passed: 0,
+
+			workers: None,
+			buffers: vec![],
+			peeked: None,
+
+			handled: 0,
+			acked: 0,
+
+			tokens: None,
+			reconnect: None,
+			resume_timeout: RESUME_TIMEOUT,
+			resumed_at: None,
+			migrate: false,
+			standby: None,
+
+			checkpoint: None,
+			checkpointed: 0,
+			restoring: false,
+			restored: false,
 		})
 	}
 
-	/// Starts the `Receiver` using the current thread.
-	///
-	/// The receiver will write all output to `out` as it is received. If the
-	/// result is `Ok(_)` then the sender successfully completed the transfer
-	/// and hung-up the connection gracefully. Any other response indicates the
-	/// message is either corrupt or incopmlete.
-	///
-	/// Note that if the receiver & sender successfully handshake (that is: they
-	/// exchange `MessageTy::Hello` with one another) and only later encounter
-	/// a crypto error it likely indicates a packet was corrupted or the sender
-	/// was interrupted.
-	///
-	pub fn run<W: Write>(&mut self, mut out: W) -> Result<(), ProtoError> {
-		let mut block_buf = vec![0u8; BLOCK_SIZE + self.enc_key.algorithm().tag_len()];
+	/// Limits the features advertised to the sender, which are all of them by
+	/// default. The sender gives up on those which are not advertised, and
+	/// fails if it needs them. (See: `Features`.)
+	pub fn set_features(&mut self, features: Features) {
+		self.advertised = features;
+	}
+
+	/// Returns the features both peers advertised, once the handshake is over.
+	pub fn features(&self) -> Features {
+		self.features
+	}
+
+	/// Registers a callback which is invoked for each `Event` in the session.
+	pub fn set_observer<F: FnMut(&Event) + Send + 'static>(&mut self, observer: F) {
+		*self.observer.lock().expect("observer lock poisoned") = Some(Box::new(observer));
+	}
+
+	/// Returns a channel which receives a copy of each `Event` in the session,
+	/// this replaces any observer which was registered previously.
+	pub fn subscribe(&mut self) -> mpsc::Receiver<Event> {
+		let (observer, events) = event::channel();
+		*self.observer.lock().expect("observer lock poisoned") = Some(observer);
+		events
+	}
+
+	/// Sets how long the sender may go w/o a word once blocks are flowing,
+	/// before the receiver warns that it has gone quiet & emits an
+	/// `Event::Quiet`. Zero (the default) disables the warning.
+	pub fn set_quiet_threshold(&mut self, threshold: Duration) {
+		self.quiet_threshold = threshold;
+	}
+
+	/// Sets how often an `Event::Stats` sampling the transfer's progress is
+	/// emitted. An interval of zero (the default) disables them.
+	pub fn set_stats_interval(&mut self, interval: Duration) {
+		self.stats = StatsTimer::new(interval);
+	}
+
+	/// Returns a tally of the data which has been transferred so far.
+	pub fn summary(&self) -> &Summary {
+		&self.summary
+	}
+
+	/// Returns the state of the connection to the sender: its round-trip time,
+	/// estimated bandwidth & how many packets were lost or retransmitted. Once
+	/// the session is over this is how the connection stood as it was closed.
+	/// (This is `None` if the transport does not report it.)
+	pub fn stats(&self) -> Option<LinkStats> {
+		self.stream.link_stats()
+	}
+
+	/// Returns the description of the sender's input, if it sent one.
+	pub fn metadata(&self) -> Option<&FileMeta> {
+		self.metadata.as_ref()
+	}
+
+	/// Returns the SHA-256 of the output written so far, unless the session
+	/// was restored from a checkpoint.
+	pub fn digest(&self) -> Option<[u8; DIGEST_LEN]> {
+		if self.restored { None } else { Some(self.digest.finish()) }
+	}
+
+	/// Tells the receiver that its output is `file`, so that space can be
+	/// reserved in it once the sender says how large its file is, and so that
+	/// it can be synced to disk. (The files written to an output directory are
+	/// always handled this way.)
+	pub fn set_output_file(&mut self, file: &File) -> Result<(), ProtoError> {
+		self.output_file = Some(file.try_clone()?);
+		Ok(())
+	}
+
+	/// Sets how often the data written to a file output is synced to disk,
+	/// rather than leaving it to the OS. `None` (the default) never does.
+	pub fn set_fsync_interval(&mut self, interval: Option<Duration>) {
+		self.fsync_interval = interval;
+	}
+
+	/// Sets whether a file output is synced to disk before the sender's
+	/// `Goodbye` is answered, so that a sender which saw the transfer succeed
+	/// knows the data is on stable storage.
+	pub fn set_sync_on_close(&mut self, sync: bool) {
+		self.sync_on_close = sync;
+	}
+
+	/// Sets the directory which the files of a multi-file session are written
+	/// to. Without one a sender which sends multiple files is rejected.
+	pub fn set_output_dir<P: Into<PathBuf>>(&mut self, dir: P) {
+		self.output_dir = Some(dir.into());
+	}
+
+	/// Sets whether the permissions & modification time sent by the sender are
+	/// applied to each file written to the output directory.
+	pub fn set_preserve(&mut self, preserve: bool) {
+		self.preserve.perms = preserve;
+		self.preserve.times = preserve;
+	}
+
+	/// Sets whether only the permissions sent by the sender are applied to
+	/// each file written to the output directory.
+	pub fn set_preserve_perms(&mut self, preserve: bool) {
+		self.preserve.perms = preserve;
+	}
+
+	/// Sets whether the owner & group sent by the sender are applied to each
+	/// file written to the output directory, which only a privileged receiver
+	/// may do. (See: `FileMeta::apply_with()`.)
+	pub fn set_preserve_owner(&mut self, preserve: bool) {
+		self.preserve.owner = preserve;
+	}
+
+	/// Insists on `cipher` for the session, rather than letting the peers
+	/// choose the quickest one they can both use w/ the key.
+	pub fn set_cipher(&mut self, cipher: Cipher) -> Result<(), ProtoError> {
+		cipher.check_key(&self.key)?;
+		self.forced_cipher = Some(cipher);
+		Ok(())
+	}
+
+	/// Also accepts senders using `key`, which name it by its id in their
+	/// `MessageTy::ReqIV`. (e.g: to give each sender its own key.) A sender
+	/// whose key is not one of these is tried w/ the key this receiver was
+	/// created with. (See: `key::id()`.)
+	pub fn add_key(&mut self, key: &[u8]) -> Result<(), ProtoError> {
+		Cipher::for_key(key)?;
+		self.keys.push(key.to_vec());
+		Ok(())
+	}
+
+	/// Agrees on the session key w/ a sender which was given the same short
+	/// `code`, rather than using the key this receiver was created with. The
+	/// agreed key is the same length, so it suits the same ciphers.
+	pub fn set_code(&mut self, code: &str) {
+		self.code = Some(code.to_string());
+	}
+
+	/// Proves to senders which ask that they reached this receiver, by
+	/// signing the handshake w/ `identity`.
+	pub fn set_identity(&mut self, identity: Identity) {
+		self.identity = Some(identity);
+	}
+
+	/// Refuses senders unless they prove they hold an identity w/ one of the
+	/// `fingerprints`. (See: `Identity::fingerprint()`.)
+	pub fn set_expected_fingerprints<S: AsRef<str>>(&mut self, fingerprints: &[S]) {
+		self.identity_check = Some(identity::expect(fingerprints));
+	}
+
+	/// Lets `check` refuse a sender during the handshake. The check is given
+	/// the fingerprint of the sender's identity, or `None` if it has none.
+	pub fn set_identity_check<F>(&mut self, check: F)
+	where F: FnMut(Option<&str>) -> Result<(), ProtoError> + Send + 'static {
+		self.identity_check = Some(Box::new(check));
+	}
+
+	/// Sets how long the receiver waits for the sender to resume the session
+	/// if the connection drops, `RESUME_TIMEOUT` by default. The sender is
+	/// issued a token it can resume with if this is more than zero, a timeout
+	/// of zero disables resuming.
+	pub fn set_resume_timeout(&mut self, timeout: Duration) {
+		self.resume_timeout = timeout;
+	}
+
+	/// Sets how the receiver waits for the sender to reconnect when resuming
+	/// the session. A receiver created by `new()` listens (or dials) the same
+	/// way it did at first.
+	pub fn set_reconnect<F>(&mut self, reconnect: F)
+	where F: FnMut(Duration) -> Result<Box<dyn Transport>, ProtoError> + Send + 'static {
+		self.reconnect = Some(Box::new(reconnect));
+	}
+
+	/// Lets the sender move the session to a new connection (i.e: from a new
+	/// address, once its own has changed) while the current one still seems
+	/// to be up. The receiver keeps accepting the sender in the background
+	/// using its reconnect, and switches to a connection which presents the
+	/// session's token as soon as it arrives. This is meant for a receiver
+	/// which listens for its sender, and only applies to a session which can
+	/// be resumed. (See: `set_resume_timeout()`.)
+	pub fn set_migrate(&mut self, migrate: bool) {
+		self.migrate = migrate;
+	}
+
+	/// Saves a `Checkpoint` to `path` every so often, so that a receiver
+	/// which crashes can be restarted & `restore()` the session. This only
+	/// applies to a session which can be resumed, written to an output file.
+	/// (See: `set_resume_timeout()` & `set_output_file()`.) The checkpoint is
+	/// removed once the session is over.
+	pub fn set_checkpoint<P: Into<PathBuf>>(&mut self, path: P) {
+		self.checkpoint = Some(path.into());
+	}
+
+	/// Picks up the session a crashed receiver saved in `checkpoint`, rather
+	/// than waiting on a new one. The output must already be cut back to
+	/// `checkpoint.bytes()` & positioned at its end, and the receiver must be
+	/// able to resume, the sender is expected to present its token once it
+	/// reconnects.
+	///
+	/// The digest of the data which arrived before the crash is lost, so it
+	/// is not checked at the end of the session and no receipt is signed.
+	pub fn restore(&mut self, checkpoint: Checkpoint) -> Result<(), ProtoError> {
+		let cipher = Cipher::from_u8(checkpoint.cipher)
+			.ok_or(ProtoError::InvalidCheckpoint)?;
+
+		let session_key = match checkpoint.rekey_salt {
+			Some(ref salt) => util::derive_key(&checkpoint.key, salt),
+			None => checkpoint.key.clone(),
+		};
+
+		self.cipher = cipher;
+		self.key = checkpoint.key;
+		self.dec_key = Arc::new(OpeningKey::new(cipher.algorithm(), &session_key)?);
+		self.enc_key = SealingKey::new(cipher.algorithm(), &session_key)?;
+		self.epoch = checkpoint.epoch;
+		self.rekey_salt = checkpoint.rekey_salt;
+
+		self.nonce = checkpoint.nonce;
+		self.counter = checkpoint.handled;
+		self.handled = checkpoint.handled;
+		self.acked = checkpoint.handled;
+		self.checkpointed = checkpoint.handled;
+		self.features = Features::from_bits(checkpoint.features);
+		self.tokens = Some(checkpoint.tokens);
+
+		let session_id = checkpoint.session_id.as_deref().and_then(SessionId::from_bytes)
+			.ok_or(ProtoError::InvalidCheckpoint)?;
+
+		self.summary.session_id = Some(session_id);
+		self.use_reply_key(session_id)?;
+		self.summary.plaintext_bytes = checkpoint.plaintext_bytes;
+		self.summary.ciphertext_bytes = checkpoint.ciphertext_bytes;
+		self.summary.blocks = checkpoint.blocks;
+		self.summary.expected_bytes = checkpoint.expected_bytes;
+		self.metadata = checkpoint.metadata;
+		SessionId::set_current(self.summary.session_id);
+
+		info!("restored the session from a checkpoint at message #{}", self.handled);
+		self.state = State::Transmit;
+		self.restoring = true;
+		self.restored = true;
+		Ok(())
+	}
+
+	/// Sets how many threads open blocks. Blocks which have already arrived are
+	/// opened in parallel when there is more than one, and are still written
+	/// in order.
+	pub fn set_crypto_threads(&mut self, threads: usize) {
+		self.workers = if threads > 1 { Some(Workers::new(threads)) } else { None };
+	}
+
+	/// Adds an output which is sent a copy of everything written to the
+	/// receiver's output, as it is received. The files of a multi-file session
+	/// are not copied to it.
+	pub fn add_tee<W: Write + Send + 'static>(&mut self, tee: W) {
+		self.tees.push(Box::new(tee));
+	}
+
+	/// Replaces the flag which is polled between blocks to determine if the
+	/// transfer should be cut short. (e.g: one set by a signal handler.)
+	pub fn set_interrupt(&mut self, flag: Arc<AtomicBool>) {
+		self.interrupt = flag;
+	}
+
+	/// Draws the IV & challenge from a generator seeded w/ `seed` rather than
+	/// the OS. (For testing only, See: `Sender::set_seed()`.)
+	pub fn set_seed(&mut self, seed: u64) {
+		self.rng = StdRng::seed_from_u64(seed);
+	}
+
+	/// Returns a handle which cancels this receiver from another thread.
+	pub fn cancel_token(&self) -> CancelToken {
+		self.cancel.clone()
+	}
+
+	/// Starts the `Receiver` using the current thread.
+	///
+	/// The receiver will write all output to `out` as it is received. If the
+	/// result is `Ok(_)` then the sender successfully completed the transfer
+	/// and hung-up the connection gracefully. Any other response indicates the
+	/// message is either corrupt or incopmlete.
+	///
+	/// Note that if the receiver & sender successfully handshake (that is: they
+	/// exchange `MessageTy::Hello` with one another) and only later encounter
+	/// a crypto error it likely indicates a packet was corrupted or the sender
+	/// was interrupted.
+	///
+	pub fn run<W: Write>(&mut self, out: W) -> Result<(), ProtoError> {
+		self.run_sink(Zeros(out))
+	}
+
+	/// Like `run()`, but the holes the sender skipped over are left as holes
+	/// in `out` by seeking past them, rather than being written as zeros.
+	pub fn run_seekable<W: Write + Seek>(&mut self, out: W) -> Result<(), ProtoError> {
+		self.run_sink(Seeking::new(out))
+	}
+
+	/// Like `run()`, but `finish` is called on `out` once the sender has said
+	/// goodbye & before it is answered. If it fails the session fails, & the
+	/// sender is told why, so that an output which only fails once it is
+	/// complete (e.g: a filter command, which must be waited for) never lets
+	/// the sender believe the transfer succeeded.
+	pub fn run_finished<W, F>(&mut self, out: W, finish: F) -> Result<(), ProtoError>
+	where W: Write, F: FnMut(&mut W) -> Result<(), io::Error> {
+		self.run_sink(Finished(out, finish))
+	}
+
+	/// Like `run()`, but `file` was opened w/ `O_DIRECT` so that the output
+	/// bypasses the page cache. The blocks are gathered into writes w/ the
+	/// alignment it requires. (See: `sink::Direct`.)
+	pub fn run_direct(&mut self, file: File) -> Result<(), ProtoError> {
+		self.run_sink(Direct::new(file))
+	}
+
+	fn run_sink<S: Sink>(&mut self, mut out: S) -> Result<(), ProtoError> {
+		let result = self.run_states(&mut out);
+		self.conclude(result)
+	}
+
+	/// Wraps up a session which ended w/ `result`, which is returned.
+	pub(super) fn conclude(&mut self, result: Result<(), ProtoError>) -> Result<(), ProtoError> {
+		self.watchdog = None;
+
+		// hang up on a sender after any failure, otherwise it will not notice
+		// until UDT gives up on the connection. it is told why first, once it
+		// has made it through the handshake.
+		if let Err(ref err) = result {
+			if !matches!(self.state, State::WaitHello) {
+				fault::report(&mut *self.stream, err);
+			}
+
+			let _ = self.stream.close();
+		}
+
+		self.summary.link = self.stream.link_stats();
+		let finished = Event::from(&self.summary);
+		self.emit(finished);
+		SessionId::set_current(None);
+		result
+	}
+
+	fn run_states<S: Sink>(&mut self, out: &mut S) -> Result<(), ProtoError> {
+		let finished = self.step_states(out);
+
+		// the sender has nothing left to move to another connection
+		self.standby = None;
+		finished
+	}
+
+	fn step_states<S: Sink>(&mut self, out: &mut S) -> Result<(), ProtoError> {
+		while !self.step(out)? {}
+		Ok(())
+	}
+
+	/// Handles whatever the sender has sent so far, but does not wait on it
+	/// for more once a message was handled in full. Returns true once the
+	/// session is over. (This is how a `Hub` runs many sessions on a few threads.)
+	#[cfg(feature = "udt")]
+	pub(super) fn advance<S: Sink>(&mut self, out: &mut S) -> Result<bool, ProtoError> {
+		// the session may have been advanced on another thread last time
+		SessionId::set_current(self.summary.session_id);
+
+		let advanced = loop {
+			match self.step(out) {
+				// nothing is read to answer the sender's goodbye w/ ours
+				Ok(false) if matches!(self.state, State::WaitHangup) => continue,
+				Ok(false) if self.peeked.is_some() || self.stream.has_pending() => continue,
+				advanced => break advanced,
+			}
+		};
+
+		SessionId::set_current(None);
+		advanced
+	}
+
+	/// Handles the next message from the sender, returning true once the
+	/// session is over.
+	pub(super) fn step<S: Sink>(&mut self, out: &mut S) -> Result<bool, ProtoError> {
+		if self.restoring {
+			self.wait_restore()?;
+		}
+
+		match self.state {
+			State::WaitHello => self.wait_hello()?,
+			State::Transmit if self.watchdog.is_none() && self.quiet_threshold > Duration::from_secs(0) => {
+				self.watchdog = Some(Watchdog::spawn(self.quiet_threshold, self.observer.clone()));
+			},
+
+			State::Transmit => match self.wait_chunk(out) {
+				Ok(()) => {
+					self.handled = self.counter;
+					self.save_checkpoint()?;
+					if let Err(err) = self.send_ack() {
+						self.resume_or(err)?;
+					}
+				},
+
+				Err(err) => self.resume_or(err)?,
+			},
+
+			State::WaitHangup => {
+				out.finish()?;
+				self.flush_current()?;
+				if self.sync_on_close {
+					self.sync_output()?;
+				}
+
+				self.wait_goodbye()?;
+				self.summary.elapsed = self.started.elapsed();
+				self.emit(Event::Goodbye);
+				self.stream.close()?;
+				self.remove_checkpoint();
+				return Ok(true);
+			},
+		}
+
+		Ok(false)
+	}
+
+	fn wait_chunk<S: Sink>(&mut self, out: &mut S) -> Result<(), ProtoError> {
+		if self.interrupt.load(Ordering::SeqCst) {
+			warn!("interrupted, closing connection ...");
+			out.flush()?;
+			self.flush_current()?;
+			self.stream.close()?;
+			self.emit(Event::Abort);
+			return Err(ProtoError::Interrupted);
+		}
+
+		if self.cancel.is_cancelled() {
+			warn!("cancelled, aborting transfer ...");
+			out.flush()?;
+			self.flush_current()?;
+			if let Err(err) = self.send_abort() {
+				debug!("could not deliver abort: {}", err);
+			}
+
+			self.stream.close()?;
+			self.emit(Event::Abort);
+			return Err(ProtoError::Cancelled);
+		}
+
+		self.sample_stats();
+		if self.fsync_interval.is_some_and(|interval| self.last_fsync.elapsed() >= interval) {
+			self.sync_output()?;
+		}
+
+		debug!("waiting for block from client ...");
+		let buf = match self.peeked.take() {
+			Some(buf) => buf,
+			None => {
+				let mut buf = [0u8; MESSAGE_SIZE];
+				self.stream.read_exact(&mut buf)?;
+				buf
+			},
+		};
+
+		if let Some(idle) = self.watchdog.as_ref().and_then(Watchdog::heard) {
+			info!("heard from the sender again after {}s", idle.as_secs());
+			self.emit(Event::Heard { idle_secs: idle.as_secs_f64() });
+		}
+
+		// read the block header, or why the sender failed
+		let message = Message::decode(&buf)?.or_fault(&mut *self.stream)?;
+
+		// after a nack everything up to the resent block is discarded, but an
+		// abort is still honored & keepalives are still answered.
+		let lossy = self.stream.lossy();
+		if let Some(lost) = self.lost {
+			match message.ty {
+				MessageTy::Ping | MessageTy::Abort => {},
+
+				// if the transport is lossy the message after it may have been
+				// passed over, in which case the resent copy is waited on too
+				_ if message.seq == lost => {
+					self.lost = Some(lost + 1).filter(|_| lossy && lost < self.passed);
+				},
+
+				_ => {
+					trace!("discarding {:?} #{} while waiting for #{}", message.ty, message.seq, lost);
+					io::copy(&mut (&mut self.stream).take(message.len as u64), &mut io::sink())?;
+
+					// a message after the lost one arriving twice means that the
+					// resent copy of the lost one was dropped as well
+					if lossy && message.seq > lost && message.seq <= self.passed {
+						self.send_nack(lost)?;
+					}
+
+					self.passed = self.passed.max(message.seq);
+					return Ok(());
+				},
+			}
+		}
+
+		// a transport which may drop or reorder messages can skip one, which
+		// is asked for again, or deliver one twice, which is discarded.
+		let expected = self.counter + 1;
+		if lossy && message.seq != expected && !matches!(message.ty, MessageTy::Ping | MessageTy::Abort) {
+			io::copy(&mut (&mut self.stream).take(message.len as u64), &mut io::sink())?;
+			if message.seq > expected {
+				self.send_nack(expected)?;
+				self.passed = message.seq;
+				return Ok(());
+			}
+
+			trace!("discarding {:?} #{} which was already handled", message.ty, message.seq);
+			return Ok(());
+		}
+
+		match message.ty {
+			MessageTy::Goodbye => {
+				if self.current.is_some() {
+					return Err(ProtoError::UnexpectedMessage);
+				}
+
+				self.recv_client_goodbye(&buf, &message)?;
+				self.state = State::WaitHangup;
+				return Ok(());
+			},
+
+			MessageTy::ReKey => {
+				return self.recv_rekey(&buf, &message);
+			},
+
+			MessageTy::Metadata => {
+				return self.recv_metadata(&buf, &message);
+			},
+
+			MessageTy::TotalSize => {
+				return self.recv_total_size(&buf, &message);
+			},
+
+			MessageTy::FileStart => {
+				return self.recv_file_start(&buf, &message);
+			},
+
+			MessageTy::FileEnd => {
+				return self.recv_file_end(&buf, &message);
+			},
+
+			MessageTy::Skip => {
+				return self.recv_skip(&buf, &message, out);
+			},
+
+			MessageTy::Ping => {
+				return self.send_pong();
+			},
+
+			MessageTy::Abort => {
+				warn!("sender aborted the transfer ...");
+				out.flush()?;
+				self.flush_current()?;
+				self.send_server_goodbye()?;
+				self.stream.close()?;
+				self.emit(Event::Abort);
+				return Err(ProtoError::PeerAborted);
+			},
+
+			_ => {},
+		}
+
+		if !message.ty.is_block() {
+			return Err(ProtoError::UnexpectedMessage);
+		}
+
+		// blocks which have already arrived are opened together, so that they
+		// can be shared out between the crypto workers.
+		let mut blocks = vec![self.read_block(&buf, &message)?];
+		if let Some(batch_len) = self.workers.as_ref().map(Workers::batch_len) {
+			while blocks.len() < batch_len && self.stream.has_pending() {
+				let mut next_buf = [0u8; MESSAGE_SIZE];
+				self.stream.read_exact(&mut next_buf)?;
+				let next = Message::decode(&next_buf)?;
+
+				if !next.ty.is_block() || self.lost.is_some() || next.seq != self.counter + 1 {
+					self.peeked = Some(next_buf);
+					break;
+				}
+
+				blocks.push(self.read_block(&next_buf, &next)?);
+			}
+		}
+
+		// the block header was sealed as associated data, so any tampering
+		// with its type or length will cause the block to fail to open.
+		let opened = match self.workers {
+			Some(ref workers) => workers.open(&self.dec_key, blocks),
+			None => blocks.into_iter()
+				.map(|mut block| {
+					let opened = block.open(&self.dec_key);
+					(block, opened)
+				})
+				.collect(),
+		};
+
+		// the blocks after one which failed are sent again along w/ it
+		let tag_len = self.dec_key.algorithm().tag_len();
+		for (mut block, opened) in opened {
+			if !opened {
+				return self.send_nack(block.seq);
+			}
+
+			self.nacks = 0;
+			let ciphertext_len = block.buf.len() + tag_len;
+			if Message::decode(&block.header)?.ty == MessageTy::Padded {
+				padding::unpad(&mut block.buf)?;
+			}
+
+			// an empty padded block is only there to cover for an idle sender
+			if block.buf.is_empty() {
+				trace!("discarding cover block #{}", block.seq);
+			} else {
+				self.write_block(&block.buf, ciphertext_len, out)?;
+			}
+
+			self.handled = block.seq;
+			self.buffers.push(block.buf);
+		}
+
+		Ok(())
+	}
+
+	/// Reads the sealed payload of the block `message`.
+	fn read_block(&mut self, header: &[u8; MESSAGE_SIZE], message: &Message) -> Result<Block, ProtoError> {
+		util::check_seq(self.counter, message.seq)?;
+		let nonce = util::get_next_nonce(&mut self.nonce, &mut self.counter)?;
+
+		// `Message::decode` has already checked the block fits in a buffer
+		let mut buf = self.buffers.pop().unwrap_or_default();
+		if self.stream.swap_message(&mut buf) {
+			return Ok(Block { seq: message.seq, nonce, header: *header, buf, prefix: MESSAGE_SIZE, mapped: None });
+		}
+
+		buf.resize(message.len, 0);
+
+		let mut pos = 0;
+		'copy: loop {
+			let bytes_read = self.stream.read(&mut buf[pos..])?;
+
+			if bytes_read == 0 {
+				debug!("stream reached EOF");
+				break 'copy;
+			}
+
+			trace!("recv {} bytes", bytes_read);
+			pos += bytes_read;
+			if pos >= message.len {
+				trace!("done copying encrypted block...");
+				break 'copy;
+			}
+		}
+
+		buf.truncate(pos);
+		Ok(Block { seq: message.seq, nonce, header: *header, buf, prefix: 0, mapped: None })
+	}
+
+	fn write_block<S: Sink>(&mut self, payload: &[u8], ciphertext_len: usize, out: &mut S) -> Result<(), ProtoError> {
+		match self.current.as_mut() {
+			Some(current) => {
+				current.file.write_all(payload)?;
+				current.bytes += payload.len() as u64;
+			},
+
+			None => {
+				out.write_all(payload)?;
+				out.flush()?;
+
+				for tee in &mut self.tees {
+					tee.write_all(payload)?;
+				}
+			},
+		}
+
+		self.digest.update(payload);
+		self.summary.plaintext_bytes += payload.len() as u64;
+		self.summary.ciphertext_bytes += ciphertext_len as u64;
+		self.summary.blocks += 1;
+		self.summary.elapsed = self.started.elapsed();
+
+		self.emit(Event::Block {
+			plaintext_len: payload.len(),
+			ciphertext_len,
+			total_bytes: self.summary.plaintext_bytes,
+		});
+
+		Ok(())
+	}
+
+	fn recv_skip<S: Sink>(&mut self, skip_buf: &[u8], skip_msg: &Message, out: &mut S) -> Result<(), ProtoError> {
+		if skip_msg.len != mem::size_of::<u64>() + self.dec_key.algorithm().tag_len() {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		let payload = self.recv_sealed(skip_buf, skip_msg)?;
+		let len = Cursor::new(payload).read_u64::<NetworkEndian>()?;
+		trace!("skipping hole of {} bytes", len);
+
+		match self.current.as_mut() {
+			Some(current) => {
+				let offset = i64::try_from(len)
+					.map_err(|_| ProtoError::MalformedMessage)?;
+
+				current.file.seek(SeekFrom::Current(offset))?;
+				current.bytes += len;
+			},
+
+			None => {
+				out.skip(len)?;
+
+				for tee in &mut self.tees {
+					Zeros(tee).skip(len)?;
+				}
+			},
+		}
+
+		self.digest.update_zeros(len);
+		self.summary.plaintext_bytes += len;
+		self.summary.elapsed = self.started.elapsed();
+		self.emit(Event::Skip { len, total_bytes: self.summary.plaintext_bytes });
+
+		Ok(())
+	}
+
+	/// Checks the totals & digest in the sender's goodbye against what was
+	/// received, and signs a receipt for them if we proved our identity.
+	fn recv_client_goodbye(&mut self, goodbye_buf: &[u8], goodbye_msg: &Message) -> Result<(), ProtoError> {
+		if goodbye_msg.len != GOODBYE_LEN + self.dec_key.algorithm().tag_len() {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		let mut payload = Cursor::new(self.recv_sealed(goodbye_buf, goodbye_msg)?);
+		let sent_bytes = payload.read_u64::<NetworkEndian>()?;
+		let sent_blocks = payload.read_u64::<NetworkEndian>()?;
+		let mut sent_digest = [0u8; DIGEST_LEN];
+		payload.read_exact(&mut sent_digest)?;
+
+		if let Some(expected) = self.summary.expected_bytes.filter(|&bytes| bytes != sent_bytes) {
+			return Err(ProtoError::SizeMismatch { expected, sent: sent_bytes });
+		}
+
+		if sent_bytes != self.summary.plaintext_bytes || sent_blocks != self.summary.blocks {
+			return Err(ProtoError::TotalsMismatch {
+				sent_bytes,
+				sent_blocks,
+				received_bytes: self.summary.plaintext_bytes,
+				received_blocks: self.summary.blocks,
+			});
+		}
+
+		if self.restored {
+			warn!("the session was restored from a checkpoint, its digest cannot be checked");
+			return Ok(());
+		}
+
+		let digest = self.digest.finish();
+		if sent_digest != digest {
+			return Err(ProtoError::DigestMismatch);
+		}
+
+		if let (true, Some(identity)) = (self.challenged, self.identity.as_ref()) {
+			info!("signing a receipt for {} bytes ...", self.summary.plaintext_bytes);
+			let session_id = self.summary.session_id.expect("the sender's hello names the session");
+			self.receipt = Some(Receipt::issue(identity, session_id, digest, self.summary.plaintext_bytes)?);
+		}
+
+		Ok(())
+	}
+
+	fn recv_rekey(&mut self, rekey_buf: &[u8], rekey_msg: &Message) -> Result<(), ProtoError> {
+		info!("sender is rotating session keys ...");
+		if rekey_msg.len != REKEY_SALT_LEN + self.dec_key.algorithm().tag_len() {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		let salt = self.recv_sealed(rekey_buf, rekey_msg)?;
+		self.apply_rekey(&salt)
+	}
+
+	fn recv_metadata(&mut self, meta_buf: &[u8], meta_msg: &Message) -> Result<(), ProtoError> {
+		let payload = self.recv_sealed(meta_buf, meta_msg)?;
+		let metadata: FileMeta = bincode::deserialize(&payload)?;
+		info!("got metadata: {:?}", metadata);
+
+		self.emit(Event::Metadata {
+			name: metadata.name.clone(),
+			mode: metadata.mode,
+			mtime: metadata.mtime,
+		});
+
+		if let (Some(file), Some(size)) = (&self.output_file, metadata.size) {
+			sink::preallocate(file, size)?;
+		}
+
+		self.metadata = Some(metadata);
+		Ok(())
+	}
+
+	fn recv_total_size(&mut self, size_buf: &[u8], size_msg: &Message) -> Result<(), ProtoError> {
+		if size_msg.len != mem::size_of::<u64>() + self.dec_key.algorithm().tag_len() {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		let payload = self.recv_sealed(size_buf, size_msg)?;
+		let bytes = Cursor::new(payload).read_u64::<NetworkEndian>()?;
+		info!("sender announced {} bytes", bytes);
+
+		self.summary.expected_bytes = Some(bytes);
+		self.emit(Event::TotalSize { bytes });
+		Ok(())
+	}
+
+	fn recv_file_start(&mut self, start_buf: &[u8], start_msg: &Message) -> Result<(), ProtoError> {
+		if self.current.is_some() {
+			return Err(ProtoError::UnexpectedMessage);
+		}
+
+		let payload = self.recv_sealed(start_buf, start_msg)?;
+		let metadata: FileMeta = bincode::deserialize(&payload)?;
+		let path = self.output_path(&metadata.name)?;
+		info!("receiving file {:?} into {}", metadata, path.display());
+
+		// never truncate an earlier file of the session which had the same
+		// name, nor follow a link someone left in the output directory
+		let file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+			Ok(file) => file,
+			Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+				return Err(ProtoError::FileExists { name: metadata.name });
+			},
+
+			Err(err) => return Err(err.into()),
+		};
+
+		if let Some(size) = metadata.size {
+			sink::preallocate(&file, size)?;
+		}
+
+		self.emit(Event::FileStart {
+			name: metadata.name.clone(),
+			mode: metadata.mode,
+			mtime: metadata.mtime,
+		});
+
+		self.current = Some(OutputFile {
+			file,
+			path,
+			metadata,
+			bytes: 0,
+		});
+
+		Ok(())
+	}
+
+	fn recv_file_end(&mut self, end_buf: &[u8], end_msg: &Message) -> Result<(), ProtoError> {
+		if end_msg.len != mem::size_of::<u64>() + self.dec_key.algorithm().tag_len() {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		let payload = self.recv_sealed(end_buf, end_msg)?;
+		let expected = Cursor::new(payload).read_u64::<NetworkEndian>()?;
+
+		let current = self.current.take()
+			.ok_or(ProtoError::UnexpectedMessage)?;
+
+		if current.bytes != expected {
+			return Err(ProtoError::FileLengthMismatch {
+				name: current.metadata.name,
+				expected,
+				received: current.bytes,
+			});
+		}
+
+		// a hole at the end of the file is only skipped over, not written
+		current.file.set_len(current.bytes)?;
+		current.file.sync_all()?;
+		drop(current.file);
+
+		if self.preserve != Preserve::default() {
+			current.metadata.apply_with(&current.path, self.preserve)?;
+		}
+
+		info!("finished file {}", current.path.display());
+		self.emit(Event::FileEnd { name: current.metadata.name.clone(), bytes: current.bytes });
+		self.metadata = Some(current.metadata);
+
+		Ok(())
+	}
+
+	/// Returns where the file `name` sent by the sender is written, it must be
+	/// a plain file name so that it cannot escape the output directory.
+	fn output_path(&self, name: &str) -> Result<PathBuf, ProtoError> {
+		let dir = self.output_dir.as_ref()
+			.ok_or(ProtoError::NoOutputDir)?;
+
+		let mut components = Path::new(name).components();
+		match (components.next(), components.next()) {
+			(Some(Component::Normal(file_name)), None) => Ok(dir.join(file_name)),
+			_ => Err(ProtoError::UnsafeFileName { name: name.to_string() }),
+		}
+	}
+
+	/// Syncs what has been written to the output file (or the current file of
+	/// a multi-file session) to disk.
+	fn sync_output(&mut self) -> Result<(), ProtoError> {
+		trace!("syncing the output to disk ...");
+		if let Some(file) = self.output_file.as_ref() {
+			file.sync_data()?;
+		}
+
+		if let Some(current) = self.current.as_ref() {
+			current.file.sync_data()?;
+		}
+
+		self.last_fsync = Instant::now();
+		Ok(())
+	}
+
+	/// Flushes the tees, and the partially written file of an interrupted
+	/// session.
+	fn flush_current(&mut self) -> Result<(), ProtoError> {
+		if let Some(current) = self.current.as_mut() {
+			current.file.sync_all()?;
+		}
+
+		for tee in &mut self.tees {
+			tee.flush()?;
+		}
+
+		Ok(())
+	}
+
+	/// Reads the `len` bytes which follow the header `msg` and opens them w/
+	/// the current key, using the serialized header as associated data.
+	fn recv_sealed(&mut self, msg_buf: &[u8], msg: &Message) -> Result<Vec<u8>, ProtoError> {
+		let mut enc_buf = vec![0u8; msg.len];
+		self.stream.read_exact(&mut enc_buf)?;
+
+		util::check_seq(self.counter, msg.seq)?;
+		let msg_nonce = util::get_next_nonce(&mut self.nonce, &mut self.counter)?;
+		let payload = aead::open_in_place(&self.dec_key, &msg_nonce, msg_buf, 0, &mut enc_buf)?;
 
-		loop {
-			match self.state {
-				State::WaitHello => self.wait_hello()?,
-				State::Transmit => self.wait_chunk(&mut block_buf, &mut out)?,
+		Ok(payload.to_vec())
+	}
 
-				State::WaitHangup => {
-					self.wait_goodbye()?;
-					self.stream.as_socket().close()?;
-					return Ok(());
-				},
-			}
+	fn wait_hello(&mut self) -> Result<(), ProtoError> {
+		// TODO: handle timeouts
+		while let State::WaitHello = self.state {
+			self.shake()?;
 		}
+
+		Ok(())
 	}
 
-	fn wait_chunk<W: Write>(&mut self, block_buf: &mut [u8], mut out: W) -> Result<(), ProtoError> {
-		debug!("waiting for block from client ...");
-		let mut buf = vec![0u8; MESSAGE_SIZE];
-		self.stream.read_exact(&mut buf)?;
+	/// Takes the next step of the handshake. (See: `Shake`.)
+	pub(super) fn shake(&mut self) -> Result<(), ProtoError> {
+		self.shake = match mem::replace(&mut self.shake, Shake::Done) {
+			Shake::Start => {
+				banner::send(&mut *self.stream)?;
+				Shake::Banner
+			},
 
-		// read the block header
-		let message: Message = bincode::deserialize(&buf)?;
-		match message.ty {
-			MessageTy::Goodbye => {
-				self.state = State::WaitHangup;
-				return Ok(());
+			Shake::Banner => {
+				banner::recv(&mut *self.stream)?;
+				match self.code.take() {
+					Some(code) => Shake::Pake(code),
+					None => Shake::ReqIV,
+				}
 			},
 
-			_ => {},
-		}
+			Shake::Pake(code) => {
+				self.agree_key(&code)?;
+				Shake::ReqIV
+			},
 
-		assert_eq!(message.ty, MessageTy::Block);
-		
-		let block_sz = message.len;
-		let msg_nonce = util::get_next_nonce(&mut self.nonce, &mut self.counter)?;
-		assert!(block_sz <= block_buf.len());
+			Shake::ReqIV => {
+				let challenge = self.recv_req_iv()?;
+				self.challenged = challenge.is_some();
+				self.send_rep_iv(challenge.as_deref())?;
+				Shake::Hello
+			},
 
-		// decrypt the message
-		let mut pos = 0;
-		'copy: loop {
-			let bytes_read = self.stream.read(&mut block_buf[pos..message.len])?;
+			Shake::Hello => {
+				let featured = self.recv_client_hello()
+					.map_err(|err| self.reject_hello(err))?;
+				self.send_server_hello(featured)?;
 
-			if bytes_read == 0 {
-				debug!("stream reached EOF");
-				break 'copy;
-			}
+				if self.challenged {
+					self.send_identity()?;
+					Shake::Identity
+				} else {
+					if let Some(check) = self.identity_check.as_mut() {
+						check(None)?;
+					}
 
-			trace!("recv {} bytes", bytes_read);
-			pos += bytes_read;
-			if pos >= block_sz {
-				trace!("done copying encrypted block...");
-				break 'copy;
-			}
-		}
+					self.complete_hello()?;
+					Shake::Done
+				}
+			},
+
+			Shake::Identity => {
+				self.recv_identity()?;
+				self.use_mixed_key()?;
+				self.complete_hello()?;
+				Shake::Done
+			},
 
-		let payload = aead::open_in_place(&self.dec_key, &msg_nonce, b"", 0, &mut block_buf[..pos])?;
-		out.write(&payload)?;
-		out.flush()?;
+			Shake::Done => Shake::Done,
+		};
 
 		Ok(())
 	}
 
-	fn wait_hello(&mut self) -> Result<(), ProtoError> {
-		// TODO: handle timeouts
-		self.recv_req_iv()?;
-		self.send_rep_iv()?;
-		self.recv_client_hello()?;
-		self.send_server_hello()?;
+	/// Takes the next step of the handshake, and every step after it which
+	/// the sender has already sent enough for, but does not wait on it for
+	/// more. Returns true once the handshake is complete. (This is how a `Hub`
+	/// runs every handshake on its event loop.)
+	#[cfg(feature = "udt")]
+	pub(super) fn advance_hello(&mut self) -> Result<bool, ProtoError> {
+		SessionId::set_current(self.summary.session_id);
+
+		let advanced = loop {
+			if !self.handshaking() { break Ok(true); }
+			if let Err(err) = self.shake() { break Err(err); }
+			if self.awaits_peer() && !self.stream.has_pending() { break Ok(!self.handshaking()); }
+		};
+
+		SessionId::set_current(None);
+		advanced
+	}
+
+	/// Returns true until the handshake is complete.
+	#[cfg(any(feature = "async", feature = "udt"))]
+	pub(super) fn handshaking(&self) -> bool {
+		matches!(self.state, State::WaitHello)
+	}
+
+	/// Returns true if the next step reads from the sender before it writes
+	/// anything. (See: `Receiver::shake()` & `Receiver::step()`.)
+	#[cfg(any(feature = "async", feature = "udt"))]
+	pub(super) fn awaits_peer(&self) -> bool {
+		match self.state {
+			State::WaitHello => !matches!(self.shake, Shake::Start | Shake::Done),
+			State::Transmit => self.peeked.is_none(),
+			State::WaitHangup => false,
+		}
+	}
+
+	/// Starts the transfer, once the handshake is complete.
+	fn complete_hello(&mut self) -> Result<(), ProtoError> {
+		self.handled = self.counter;
+		self.acked = self.handled;
+
+		let resumable = self.features.contains(Features::RESUME);
+		if self.resume_timeout > Duration::from_secs(0) && self.reconnect.is_some() && resumable {
+			self.send_token()?;
+			self.start_standby();
+		}
 
 		info!("handshake complete!");
 		self.state = State::Transmit;
+		self.started = Instant::now();
+		self.stats.restart();
+		let session_id = self.summary.session_id.map(|id| id.to_string()).unwrap_or_default();
+		let fingerprint = fingerprint::session_fingerprint(&self.key, &self.transcript);
+		info!("the session's fingerprint is {}", fingerprint);
+		self.emit(Event::HandshakeComplete { session_id, fingerprint });
 
 		Ok(())
 	}
@@ -160,110 +1416,646 @@ impl Receiver {
 		self.send_server_goodbye()
 	}
 
-	fn recv_req_iv(&mut self) -> Result<(), ProtoError> {
+	/// Answers the sender's half of the SPAKE2 exchange w/ ours, then switches
+	/// to the key agreed w/ the sender.
+	fn agree_key(&mut self, code: &str) -> Result<(), ProtoError> {
+		info!("waiting for the sender to agree on a key from the code ...");
+		let mut buf = [0u8; MESSAGE_SIZE];
+		self.stream.read_exact(&mut buf)?;
+		let message = Message::decode(&buf)?;
+
+		if message.ty != MessageTy::Pake {
+			return Err(ProtoError::UnexpectedMessage);
+		}
+
+		if message.len != ELEMENT_LEN {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		let mut element = [0u8; ELEMENT_LEN];
+		self.stream.read_exact(&mut element)?;
+		self.transcript.update(&buf);
+		self.transcript.update(&element);
+
+		let pake = Pake::start(Mode::Receiver, code);
+		let reply_msg = Message {
+			ty: MessageTy::Pake,
+			len: ELEMENT_LEN,
+			seq: 0,
+		};
+
+		let reply_buf = reply_msg.encode();
+		self.stream.write_all(&reply_buf)?;
+		self.stream.write_all(pake.element())?;
+		self.transcript.update(&reply_buf);
+		self.transcript.update(pake.element());
+
+		self.key = pake.finish(&element, self.key.len())?;
+		self.use_cipher(self.cipher)
+	}
+
+	/// Reads the sender's `ReqIV` & chooses the cipher, returning the sender's
+	/// challenge if it sent one so that the peers exchange identities.
+	fn recv_req_iv(&mut self) -> Result<Option<Vec<u8>>, ProtoError> {
 		// client should send us ReqIV
 		info!("waiting for client req iv");
 		let mut buf = vec![0u8; MESSAGE_SIZE];
 		self.stream.read_exact(&mut buf)?;
-		let message: Message = bincode::deserialize(&buf)?;
+		let message = Message::decode(&buf)?;
 		
-		assert_eq!(message.ty, MessageTy::ReqIV);
-		assert_eq!(message.len, 0);
+		if message.ty != MessageTy::ReqIV {
+			return Err(ProtoError::UnexpectedMessage);
+		}
+
+		let prefix_len = 2 * mem::size_of::<u8>() + key::ID_LEN;
+		if message.len != prefix_len && message.len != prefix_len + CHALLENGE_LEN {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		self.transcript.update(&buf);
+		let mut buf = vec![0u8; message.len];
+		self.stream.read_exact(&mut buf)?;
+		self.transcript.update(&buf);
+
+		let theirs = match buf[0] {
+			CIPHER_AUTO => None,
+			id => Some(Cipher::from_u8(id).ok_or(ProtoError::UnknownCipher { id })?),
+		};
+
+		self.select_key(&buf[2..prefix_len]);
+
+		let ours = cipher::preference(self.forced_cipher, &self.key);
+		let accelerated = buf[1] != 0;
+		self.use_cipher(cipher::choose(ours, theirs, accelerated, &self.key)?)?;
+
+		match buf.len() > prefix_len {
+			true => Ok(Some(buf[prefix_len..].to_vec())),
+			false => Ok(None),
+		}
+	}
+
+	/// Switches to the key the sender named by its `id`, if we accept it.
+	fn select_key(&mut self, id: &[u8]) {
+		let id_hex: String = id.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+		if let Some(key) = self.keys.iter().find(|key| key::id(key) == id) {
+			info!("the sender is using key {}", id_hex);
+			self.key = key.clone();
+		} else if key::id(&self.key) != id {
+			warn!("the sender's key {} is not one we accept, trying our own", id_hex);
+		}
+	}
 
+	/// Switches to the `cipher` agreed upon during the handshake.
+	fn use_cipher(&mut self, cipher: Cipher) -> Result<(), ProtoError> {
+		info!("using {} for this session", cipher);
+		self.cipher = cipher;
+		self.dec_key = Arc::new(OpeningKey::new(cipher.algorithm(), &self.key)?);
+		self.enc_key = SealingKey::new(cipher.algorithm(), &self.key)?;
 		Ok(())
 	}
 
-	fn send_rep_iv(&mut self) -> Result<(), ProtoError> {
+	/// Sends the IV & the chosen cipher, along w/ a challenge of our own if the
+	/// sender sent its `challenge`. The secret agreed on w/ the two is mixed
+	/// into the session key once the identities are checked.
+	fn send_rep_iv(&mut self, challenge: Option<&[u8]>) -> Result<(), ProtoError> {
 		// generate an IV and send it to the client
 		info!("sending client IV params ...");
-		let mut rng = rand::thread_rng();
-		let nonce: u32 = rng.gen();
+		let nonce: u32 = self.rng.gen();
 		self.nonce = nonce;
 
-		// write the nonce into a buffer
-		let mut cursor = Cursor::new(vec![0u8; 4]);
+		// write the nonce & the chosen cipher into a buffer
+		let mut cursor = Cursor::new(vec![0u8; 5]);
 		cursor.write_u32::<NetworkEndian>(nonce)?;
-		let buf = cursor.into_inner();
+		cursor.write_u8(self.cipher as u8)?;
+		let mut buf = cursor.into_inner();
+
+		if let Some(theirs) = challenge {
+			let ephemeral = Ephemeral::generate(&mut self.rng);
+			buf.extend_from_slice(ephemeral.share());
+			self.mixed_key = Some(ephemeral.mix(&self.key, theirs)?);
+		}
 
 		// create the message header
 		let rep_iv_msg = Message { 
 			ty: MessageTy::RepIV,
 			len: buf.len(),
+			seq: 0,
 		};
 
 		// send RepIV
 		info!("sending rep_iv {:?}", rep_iv_msg);
-		let rep_iv_buf = bincode::serialize(&rep_iv_msg)?;
+		let rep_iv_buf = rep_iv_msg.encode();
+		self.stream.write_all(&rep_iv_buf)?;
+		self.stream.write_all(&buf)?;
 
-		assert_eq!(MESSAGE_SIZE, rep_iv_buf.len());
-		self.stream.write(&rep_iv_buf)?;
-		self.stream.write(&buf)?;
+		self.transcript.update(&rep_iv_buf);
+		self.transcript.update(&buf);
 		Ok(())
 	}
 
-	fn recv_client_hello(&mut self) -> Result<(), ProtoError> {
+	/// Signs the handshake w/ our identity, or sends an empty `Identity` if
+	/// we have none.
+	fn send_identity(&mut self) -> Result<(), ProtoError> {
+		let buf = match self.identity {
+			Some(ref identity) => {
+				info!("proving our identity ({}) to the sender ...", identity.fingerprint());
+				identity.sign(Mode::Receiver, &self.transcript)?
+			},
+
+			None => {
+				debug!("the sender asked for our identity, but we do not have one");
+				vec![]
+			},
+		};
+
+		let identity_msg = Message {
+			ty: MessageTy::Identity,
+			len: buf.len(),
+			seq: 0,
+		};
+
+		self.stream.write_all(&identity_msg.encode())?;
+		self.stream.write_all(&buf)?;
+		Ok(())
+	}
+
+	/// Checks that the sender signed the handshake, and that its identity
+	/// passes our identity check if we have one.
+	fn recv_identity(&mut self) -> Result<(), ProtoError> {
+		info!("waiting for the sender's identity ...");
+		let mut buf = [0u8; MESSAGE_SIZE];
+		self.stream.read_exact(&mut buf)?;
+		let identity_msg = Message::decode(&buf)?;
+
+		if identity_msg.ty != MessageTy::Identity {
+			return Err(ProtoError::UnexpectedMessage);
+		}
+
+		let fingerprint = match identity_msg.len {
+			0 => None,
+			len => {
+				let mut identity = vec![0u8; len];
+				self.stream.read_exact(&mut identity)?;
+				Some(self.transcript.verify(Mode::Sender, &identity)?)
+			},
+		};
+
+		if let Some(check) = self.identity_check.as_mut() {
+			check(fingerprint.as_deref())?;
+		}
+
+		match fingerprint {
+			Some(fingerprint) => info!("the sender's identity is {}", fingerprint),
+			None => info!("the sender has no identity"),
+		}
+
+		Ok(())
+	}
+
+	/// Reads the sender's `Hello`, returning true if it carried the sender's
+	/// features so that ours are sent in reply.
+	fn recv_client_hello(&mut self) -> Result<bool, ProtoError> {
 		// read the hello message header
 		info!("waiting for client hello ...");
 		let mut hello_buf = vec![0u8; MESSAGE_SIZE];
 		self.stream.read_exact(&mut hello_buf)?;
 
-		let hello_msg: Message = bincode::deserialize(&hello_buf)?;
-		assert_eq!(hello_msg.ty, MessageTy::Hello);
+		let hello_msg = Message::decode(&hello_buf)?;
+		if hello_msg.ty != MessageTy::Hello {
+			return Err(ProtoError::UnexpectedMessage);
+		}
+
+		// a sender which predates `Features` does not send any
+		let hello_len = hello_msg.len.checked_sub(self.dec_key.algorithm().tag_len());
+		if hello_len != Some(HELLO_LEN) && hello_len != Some(HELLO_LEN + FEATURES_LEN) {
+			return Err(ProtoError::MalformedMessage);
+		}
 
 		// read the encrypted payload
 		let mut enc_payload = vec![0u8; hello_msg.len];
 		self.stream.read_exact(&mut enc_payload)?;
 
+		self.transcript.update(&hello_buf);
+		self.transcript.update(&enc_payload);
+
+		util::check_seq(self.counter, hello_msg.seq)?;
 		let msg_nonce = util::get_next_nonce(&mut self.nonce, &mut self.counter)?;
-		let payload = aead::open_in_place(&self.dec_key, &msg_nonce, b"", 0, &mut enc_payload)?;
+		let payload = aead::open_in_place(&self.dec_key, &msg_nonce, &hello_buf, 0, &mut enc_payload)?;
 		info!("got hello from client: {:?}", payload);
 
-		Ok(())
+		let (session_id, features) = payload[mem::size_of_val(&MAGIC_BYTES)..].split_at(SESSION_ID_LEN);
+		let session_id = SessionId::from_bytes(session_id)
+			.ok_or(ProtoError::MalformedMessage)?;
+
+		SessionId::set_current(Some(session_id));
+		self.summary.session_id = Some(session_id);
+		self.use_reply_key(session_id)?;
+		info!("joined session {}", session_id);
+
+		let featured = !features.is_empty();
+		let features = Features::from_hello(features)?;
+		info!("the sender supports: {}", features);
+		self.features = self.advertised & features;
+
+		Ok(featured)
+	}
+
+	/// Tells a sender whose `Hello` could not be opened that it was refused,
+	/// so that it can tell a key mismatch apart from the connection dropping.
+	/// (No other failure is reported before the handshake is complete.)
+	fn reject_hello(&mut self, err: ProtoError) -> ProtoError {
+		if let ProtoError::CryptoErr = err {
+			fault::report(&mut *self.stream, &err);
+		}
+
+		err
 	}
 
-	fn send_server_hello(&mut self) -> Result<(), ProtoError> {
+	/// Answers the sender's `Hello`, w/ our features if it sent its own.
+	fn send_server_hello(&mut self, featured: bool) -> Result<(), ProtoError> {
 		info!("sending hello ...");
 
-		// write the magic bytes to a buffer
+		// write the magic bytes, the session's id & our features to a buffer
+		let session_id = self.summary.session_id.expect("the sender's hello names the session");
 		let tag_len = self.enc_key.algorithm().tag_len();
-		let enc_buf = vec![0u8; mem::size_of_val(&MAGIC_BYTES) + tag_len];
+		let features_len = if featured { FEATURES_LEN } else { 0 };
+		let enc_buf = vec![0u8; HELLO_LEN + features_len + tag_len];
 		let mut enc_buf = {
 			let mut cursor = Cursor::new(enc_buf);
 			cursor.write_u32::<NetworkEndian>(MAGIC_BYTES)?;
+			cursor.write_all(session_id.as_bytes())?;
+			if featured {
+				cursor.write_u32::<NetworkEndian>(self.advertised.bits())?;
+			}
+
 			cursor.into_inner()
 		};
 
-		// encrypt the buffer in-place
-		let msg_nonce = util::get_next_nonce(&mut self.nonce, &mut self.counter)?;
-		let msg_sz = aead::seal_in_place(&self.enc_key, &msg_nonce, b"", &mut enc_buf, tag_len)?;
-
-		// send `Hello` followed by the encrypted payload
+		// the `Hello` header is authenticated along with the payload
 		let hello_msg = Message {
 			ty: MessageTy::Hello,
-			len: msg_sz,
+			len: enc_buf.len(),
+			seq: self.counter + 1,
 		};
 
-		let hello_buf = bincode::serialize(&hello_msg)?;
-		assert_eq!(hello_buf.len(), MESSAGE_SIZE);
+		let hello_buf = hello_msg.encode();
+
+		// encrypt the buffer in-place
+		let msg_nonce = util::get_next_nonce(&mut self.nonce, &mut self.counter)?;
+		let msg_sz = aead::seal_in_place(&self.enc_key, &msg_nonce, &hello_buf, &mut enc_buf, tag_len)?;
+
+		// send `Hello` followed by the encrypted payload
 
-		self.stream.write(&hello_buf)?;
-		self.stream.write(&enc_buf[..msg_sz])?;
+		self.stream.write_all(&hello_buf)?;
+		self.stream.write_all(&enc_buf[..msg_sz])?;
 
+		self.transcript.update(&hello_buf);
+		self.transcript.update(&enc_buf[..msg_sz]);
 		Ok(())
 	}
 
 	fn send_server_goodbye(&mut self) -> Result<(), ProtoError> {
 		info!("sending goodbye ...");
+		let receipt = self.receipt.as_ref().map(Receipt::encode).unwrap_or_default();
 
 		let goodbye_msg = Message {
 			ty: MessageTy::Goodbye,
+			len: receipt.len(),
+			seq: 0,
+		};
+
+		let goodbye_buf = goodbye_msg.encode();
+		self.stream.write_all(&goodbye_buf)?;
+		self.stream.write_all(&receipt)?;
+
+		Ok(())
+	}
+
+	fn send_abort(&mut self) -> Result<(), ProtoError> {
+		info!("sending abort ...");
+
+		let abort_msg = Message {
+			ty: MessageTy::Abort,
+			len: 0,
+			seq: 0,
+		};
+
+		let abort_buf = abort_msg.encode();
+		self.stream.write_all(&abort_buf)?;
+
+		Ok(())
+	}
+
+	/// Asks the sender to resend the block `seq`, which could not be opened.
+	fn send_nack(&mut self, seq: u64) -> Result<(), ProtoError> {
+		if self.nacks >= MAX_NACKS {
+			return Err(ProtoError::CryptoErr);
+		}
+
+		warn!("could not open block #{}, asking the sender to resend it ...", seq);
+		self.nacks += 1;
+		self.lost = Some(seq);
+		self.passed = seq;
+
+		// the resent block is sealed w/ the same nonce
+		self.counter = seq - 1;
+
+		let mut payload = [0u8; 8];
+		NetworkEndian::write_u64(&mut payload, seq);
+		self.send_reply(MessageTy::Nack, seq, &payload)?;
+
+		self.emit(Event::Nack { seq });
+		Ok(())
+	}
+
+	/// Tells the sender which message we expect next, once another
+	/// `ACK_INTERVAL` messages have been handled since the last time.
+	fn send_ack(&mut self) -> Result<(), ProtoError> {
+		if !self.features.contains(Features::ACKS) || self.handled < self.acked + ACK_INTERVAL {
+			return Ok(());
+		}
+
+		let mut payload = [0u8; ACK_LEN];
+		NetworkEndian::write_u64(&mut payload[0..8], self.handled + 1);
+		NetworkEndian::write_u64(&mut payload[8..16], self.summary.plaintext_bytes);
+		self.send_reply(MessageTy::Ack, self.handled, &payload)?;
+
+		trace!("acknowledged every message up to #{}", self.handled);
+		self.acked = self.handled;
+		Ok(())
+	}
+
+	/// Sends a reply of type `ty` to the message `seq`, w/ `payload` sealed
+	/// under the reply key & the header as associated data. (See:
+	/// `util::reply_nonce()`.)
+	fn send_reply(&mut self, ty: MessageTy, seq: u64, payload: &[u8]) -> Result<(), ProtoError> {
+		let reply_key = self.reply_key.as_ref().expect("replies follow the sender's hello");
+		let tag_len = reply_key.algorithm().tag_len();
+		let mut enc_buf = vec![0u8; payload.len() + tag_len];
+		enc_buf[..payload.len()].copy_from_slice(payload);
+
+		let msg = Message {
+			ty,
+			len: enc_buf.len(),
+			seq,
+		};
+
+		let msg_buf = msg.encode();
+		let msg_nonce = util::reply_nonce(ty as u8, seq);
+		let msg_sz = aead::seal_in_place(reply_key, &msg_nonce, &msg_buf, &mut enc_buf, tag_len)?;
+
+		self.stream.write_all(&msg_buf)?;
+		self.stream.write_all(&enc_buf[..msg_sz])?;
+		Ok(())
+	}
+
+	/// Issues the sender a token it can resume the session with.
+	/// It is sealed like an `Ack`, so that only the sender can read it.
+	fn send_token(&mut self) -> Result<(), ProtoError> {
+		let session_id = self.summary.session_id.expect("tokens follow the sender's hello");
+		let tokens = Tokens::new(util::derive_resume_key(&self.key, session_id.as_bytes()));
+		let token = tokens.issue()?;
+		self.tokens = Some(tokens);
+
+		self.send_reply(MessageTy::Token, self.handled, &token)
+	}
+
+	/// Starts accepting the sender in the background, if it may move the
+	/// session to a new connection. (See: `set_migrate()`.)
+	fn start_standby(&mut self) {
+		if !self.migrate || self.standby.is_some() {
+			return;
+		}
+
+		if let (Some(tokens), Some(reconnect)) = (self.tokens.clone(), self.reconnect.take()) {
+			let standby = Standby::spawn(reconnect, tokens);
+			standby.watch(&self.stream);
+			self.standby = Some(standby);
+		}
+	}
+
+	/// Resumes the session if `err` means the connection dropped during the
+	/// transfer, and the sender was issued a token. Otherwise `err` is returned.
+	fn resume_or(&mut self, err: ProtoError) -> Result<(), ProtoError> {
+		let resumable = err.is_hangup()
+			&& matches!(self.state, State::Transmit)
+			&& self.tokens.is_some()
+			&& (self.reconnect.is_some() || self.standby.is_some());
+
+		// a session which fails again before anything else was handled is not
+		// resumed, the connection is probably not at fault. (e.g: the output is.)
+		if !resumable || self.resumed_at == Some(self.handled) {
+			return Err(err);
+		}
+
+		warn!("lost the connection to the sender ({}), waiting for it to resume ...", err);
+		if let Err(resume_err) = self.resume() {
+			warn!("could not resume the session: {}", resume_err);
+			return Err(err);
+		}
+
+		let seq = self.handled + 1;
+		info!("resumed the session from message #{}", seq);
+		self.emit(Event::Resumed { seq });
+
+		Ok(())
+	}
+
+	/// Waits for the sender to reconnect & present its token, until it does
+	/// or the resume timeout expires.
+	fn resume(&mut self) -> Result<(), ProtoError> {
+		let deadline = Instant::now() + self.resume_timeout;
+
+		loop {
+			let _ = self.stream.close();
+
+			match self.accept_resume(deadline) {
+				Ok(()) => return Ok(()),
+				Err(err) if Instant::now() + RESUME_RETRY < deadline => {
+					debug!("could not resume the session yet: {}", err);
+					thread::sleep(RESUME_RETRY);
+				},
+
+				Err(err) => return Err(err),
+			}
+		}
+	}
+
+	fn accept_resume(&mut self, deadline: Instant) -> Result<(), ProtoError> {
+		// the standby has the only way to reconnect, and has checked the token
+		if let Some(standby) = self.standby.as_ref() {
+			let (stream, resuming) = standby.accept(deadline.saturating_duration_since(Instant::now()))?;
+			self.stream = stream;
+			standby.watch(&self.stream);
+			return self.reply_resume(&resuming);
+		}
+
+		let reconnect = self.reconnect.as_mut().expect("resuming requires a way to reconnect");
+		self.stream = reconnect(deadline.saturating_duration_since(Instant::now()))?;
+		self.answer_resume()
+	}
+
+	/// Picks up a restored session, once the sender presents its token on
+	/// the connection the receiver was created with. (Or a later one, until
+	/// the resume timeout expires.)
+	fn wait_restore(&mut self) -> Result<(), ProtoError> {
+		info!("waiting for the sender to resume the restored session ...");
+		self.restoring = false;
+
+		if let Err(err) = self.answer_resume() {
+			if self.reconnect.is_none() || self.resume_timeout == Duration::from_secs(0) {
+				return Err(err);
+			}
+
+			debug!("could not resume the session yet: {}", err);
+			self.resume()?;
+		}
+
+		self.start_standby();
+
+		let seq = self.handled + 1;
+		info!("resumed the session from message #{}", seq);
+		self.emit(Event::Resumed { seq });
+		self.started = Instant::now();
+		self.stats.restart();
+
+		Ok(())
+	}
+
+	/// Checks the token the sender presents on the current connection, and
+	/// answers w/ where it should carry on from.
+	fn answer_resume(&mut self) -> Result<(), ProtoError> {
+		let resuming = resume::read_resume(&mut self.stream, self.tokens.as_ref())?;
+		self.reply_resume(&resuming)
+	}
+
+	/// Answers a sender whose token was accepted w/ where it should carry on from.
+	fn reply_resume(&mut self, resuming: &Resuming) -> Result<(), ProtoError> {
+		// anything after the last message handled in full is sent again
+		self.counter = self.handled;
+		self.lost = None;
+		self.nacks = 0;
+		self.peeked = None;
+		self.resumed_at = Some(self.handled);
+
+		let tokens = self.tokens.as_ref().expect("only a session w/ tokens is resumed");
+		resume::write_reply(&mut self.stream, tokens, resuming, self.handled + 1)
+	}
+
+	/// Saves a checkpoint once another `CHECKPOINT_INTERVAL` messages have been
+	/// handled, if the receiver has somewhere to save one. The output is synced
+	/// first, so that the checkpoint never runs ahead of what is on disk.
+	fn save_checkpoint(&mut self) -> Result<(), ProtoError> {
+		if self.handled < self.checkpointed + CHECKPOINT_INTERVAL
+			|| self.lost.is_some()
+			|| self.current.is_some()
+			|| self.output_file.is_none() {
+			return Ok(());
+		}
+
+		let (path, tokens) = match (self.checkpoint.as_ref(), self.tokens.as_ref()) {
+			(Some(path), Some(tokens)) => (path.clone(), tokens),
+			_ => return Ok(()),
+		};
+
+		let checkpoint = Checkpoint {
+			version: CHECKPOINT_VERSION,
+			tokens: tokens.clone(),
+			cipher: self.cipher as u8,
+			key: self.key.clone(),
+			rekey_salt: self.rekey_salt.clone(),
+			epoch: self.epoch,
+			nonce: self.nonce,
+			handled: self.handled,
+			features: self.features.bits(),
+			session_id: self.summary.session_id.map(|id| id.as_bytes().to_vec()),
+			plaintext_bytes: self.summary.plaintext_bytes,
+			ciphertext_bytes: self.summary.ciphertext_bytes,
+			blocks: self.summary.blocks,
+			expected_bytes: self.summary.expected_bytes,
+			metadata: self.metadata.clone(),
+		};
+
+		self.sync_output()?;
+		checkpoint.save(&path)?;
+		self.checkpointed = self.handled;
+
+		trace!("saved a checkpoint at message #{}", self.handled);
+		Ok(())
+	}
+
+	/// Removes the checkpoint of a session which is over, a failure to do so
+	/// only leaves behind a checkpoint which cannot be resumed.
+	fn remove_checkpoint(&mut self) {
+		if let Some(path) = self.checkpoint.as_ref() {
+			if let Err(err) = fs::remove_file(path) {
+				if err.kind() != io::ErrorKind::NotFound {
+					warn!("could not remove the checkpoint at {}: {}", path.display(), err);
+				}
+			}
+		}
+	}
+
+	fn send_pong(&mut self) -> Result<(), ProtoError> {
+		trace!("answering keepalive ...");
+
+		let pong_msg = Message {
+			ty: MessageTy::Pong,
 			len: 0,
+			seq: 0,
 		};
 
-		let goodbye_buf = bincode::serialize(&goodbye_msg)?;
-		assert_eq!(goodbye_buf.len(), MESSAGE_SIZE);
-		self.stream.write(&goodbye_buf)?;
+		let pong_buf = pong_msg.encode();
+		self.stream.write_all(&pong_buf)?;
+
+		Ok(())
+	}
+
+	/// Switches to the session key mixed w/ the secret agreed on w/ the
+	/// sender, once both identities are checked. Everything which follows the
+	/// handshake is sealed under it.
+	fn use_mixed_key(&mut self) -> Result<(), ProtoError> {
+		let key = self.mixed_key.take().expect("identities are exchanged after the challenges");
+		let session_id = self.summary.session_id.expect("identities are exchanged after the hellos");
+
+		self.key = key;
+		self.dec_key = Arc::new(OpeningKey::new(self.cipher.algorithm(), &self.key)?);
+		self.enc_key = SealingKey::new(self.cipher.algorithm(), &self.key)?;
+		self.use_reply_key(session_id)
+	}
+
+	/// Derives the key our replies are sealed w/, once the session is named.
+	fn use_reply_key(&mut self, session_id: SessionId) -> Result<(), ProtoError> {
+		let reply_key = util::derive_reply_key(&self.key, session_id.as_bytes());
+		self.reply_key = Some(SealingKey::new(self.cipher.algorithm(), &reply_key)?);
+		Ok(())
+	}
+
+	/// Replaces the session keys w/ a sub-key derived from the master key
+	/// and the `salt` carried by a `MessageTy::ReKey` message.
+	fn apply_rekey(&mut self, salt: &[u8]) -> Result<(), ProtoError> {
+		let sub_key = util::derive_key(&self.key, salt);
+		self.dec_key = Arc::new(OpeningKey::new(self.cipher.algorithm(), &sub_key)?);
+		self.enc_key = SealingKey::new(self.cipher.algorithm(), &sub_key)?;
+		self.epoch += 1;
+		self.rekey_salt = Some(salt.to_vec());
+
+		info!("switched to session key epoch {}", self.epoch);
+		self.emit(Event::ReKey { epoch: self.epoch });
 
 		Ok(())
 	}
+
+	fn sample_stats(&mut self) {
+		if let Some(stats) = self.stats.sample(&self.summary, &*self.stream) {
+			self.emit(stats);
+		}
+	}
+
+	fn emit(&mut self, event: Event) {
+		if let Some(observer) = self.observer.lock().expect("observer lock poisoned").as_mut() {
+			observer(&event);
+		}
+	}
 }
@@ -0,0 +1,97 @@
+use crate::error::ProtoError;
+use crate::proto::{Mode, Stream, StreamOpts, Transport};
+
+use std::io::{self, Read, Write};
+use std::net::ToSocketAddrs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The `Relay` bridges a sender on one network to a receiver on another.
+///
+/// It forwards the byte stream in both directions without interpreting it,
+/// so it never needs (or has) the key: every block it handles is still
+/// encrypted & authenticated end-to-end between the sender and receiver.
+/// This lets a transfer cross a host (e.g: in a DMZ) which should not be able
+/// to read the data.
+///
+pub struct Relay {
+	upstream: Stream,
+	downstream: Stream,
+}
+
+/// The amount of data a `Relay` forwarded in each direction.
+#[derive(Clone, Debug, Default)]
+pub struct RelayStats {
+	/// Bytes forwarded from the sender to the receiver.
+	pub forwarded: u64,
+
+	/// Bytes forwarded from the receiver back to the sender.
+	pub returned: u64,
+
+	/// The time between accepting the sender and the session ending.
+	pub elapsed: Duration,
+}
+
+impl Relay {
+	/// Connects to the receiver at `target` and then waits for a sender to
+	/// connect on `listen`. The receiver is dialed first so that the relay
+	/// never accepts a sender it cannot forward.
+	pub fn new<L, T>(listen: L, target: T, opts: &StreamOpts) -> Result<Self, ProtoError>
+	where L: ToSocketAddrs, T: ToSocketAddrs {
+		info!("connecting relay to downstream receiver ...");
+		let downstream = Stream::new(Mode::Sender, target, opts)?;
+
+		info!("waiting for upstream sender ...");
+		let upstream = Stream::new(Mode::Receiver, listen, &StreamOpts::default())?;
+
+		Ok(Self { upstream, downstream })
+	}
+
+	/// Forwards data between the peers until either of them hangs up.
+	pub fn run(self) -> Result<RelayStats, ProtoError> {
+		let started = Instant::now();
+		let Relay { mut upstream, mut downstream } = self;
+
+		let mut up_rx = upstream.try_clone();
+		let mut down_tx = downstream.try_clone();
+		let returning = thread::spawn(move || forward(&mut down_tx, &mut up_rx));
+
+		let forwarded = forward(&mut upstream, &mut downstream);
+
+		// whichever direction finishes first closes both sockets, which also
+		// wakes the other direction if it is blocked in a read.
+		let _ = upstream.close();
+		let _ = downstream.close();
+
+		let returned = returning.join()
+			.unwrap_or_else(|_| Err(io::Error::other("relay thread panicked")));
+
+		Ok(RelayStats {
+			forwarded: forwarded?,
+			returned: returned?,
+			elapsed: started.elapsed(),
+		})
+	}
+}
+
+/// Copies bytes from `src` to `dst` until `src` hangs up, returning how many
+/// bytes were copied. A peer hanging up is the normal end of a session.
+fn forward(src: &mut Stream, dst: &mut Stream) -> Result<u64, io::Error> {
+	let mut buf = vec![0u8; 64 * 1024];
+	let mut total = 0;
+
+	loop {
+		let len = match src.read(&mut buf) {
+			Ok(0) => return Ok(total),
+			Ok(len) => len,
+			Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => return Ok(total),
+			Err(err) => return Err(err),
+		};
+
+		match dst.write_all(&buf[..len]) {
+			Ok(()) => total += len as u64,
+			Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => return Ok(total),
+			Err(err) => return Err(err),
+		}
+	}
+}
@@ -0,0 +1,492 @@
+use crate::error::ProtoError;
+use crate::proto::{banner, FileMeta, Message, MessageTy, Transport, MESSAGE_SIZE};
+
+use byteorder::{ByteOrder, NetworkEndian};
+use rand::Rng;
+use ring::aead::{self, OpeningKey, SealingKey};
+use ring::{digest, hmac};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The length of a resumption token: a random nonce, followed by the
+/// session's id sealed under the receiver's token key.
+pub const TOKEN_LEN: usize = NONCE_LEN + SESSION_ID_LEN + TAG_LEN;
+
+const NONCE_LEN: usize = 12;
+const SESSION_ID_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+
+/// The length of the random challenge each peer contributes to a resumed
+/// connection, so that neither peer's proof can be replayed on another.
+pub(super) const CHALLENGE_LEN: usize = 16;
+
+/// The length of a proof that a peer holds the session key. (See: `prove()`.)
+pub(super) const PROOF_LEN: usize = 32;
+
+/// Prefixed to what each peer proves, so that one's proof is never the other's.
+const SENDER_PROOF: &[u8] = b"ubuffer resume sender";
+const RECEIVER_PROOF: &[u8] = b"ubuffer resume receiver";
+
+/// How long to wait between attempts to reach the peer again.
+pub const RESUME_RETRY: Duration = Duration::from_secs(1);
+
+/// How many messages a receiver handles between checkpoints. This is well
+/// within the sender's retransmit window, so that whatever arrived since the
+/// last checkpoint can still be resent.
+pub const CHECKPOINT_INTERVAL: u64 = 1024;
+
+/// Bumped whenever the layout of a `Checkpoint` changes.
+pub(super) const CHECKPOINT_VERSION: u32 = 3;
+
+/// How long a `Standby` waits for the sender at a time, before it checks if
+/// the session is over.
+const STANDBY_POLL: Duration = Duration::from_secs(1);
+
+/// How long a `Standby` gives a new connection to resume the session before
+/// hanging up on it.
+const STANDBY_DEADLINE: Duration = Duration::from_secs(10);
+
+/// How many new connections a `Standby` checks at once. Any more are hung up
+/// on straight away, so that a flood of them cannot tie up a thread apiece.
+const STANDBY_CHECKS: usize = 4;
+
+/// A way to reach the peer again once the connection has dropped. It is
+/// given how long it may wait for the peer, in case it listens for it.
+pub type Reconnect = Box<dyn FnMut(Duration) -> Result<Box<dyn Transport>, ProtoError> + Send>;
+
+/// Hangs up on a transport's peer from another thread. (See:
+/// `Transport::hangup_handle()`.)
+pub type Hangup = Box<dyn FnOnce() + Send>;
+
+/// A resumed connection which the sender proved it may resume the session
+/// on, along w/ the challenges the receiver's answer is bound to.
+pub(super) struct Resuming {
+	challenge: [u8; CHALLENGE_LEN],
+	nonce: [u8; CHALLENGE_LEN],
+}
+
+/// Reads the `MessageTy::Resume` a sender opens a new connection w/ (after
+/// the banners & the receiver's challenge), and checks that its token was
+/// issued for the session & that the sender holds the session key.
+pub(super) fn read_resume<S: Read + Write + ?Sized>(stream: &mut S, tokens: Option<&Tokens>) -> Result<Resuming, ProtoError> {
+	banner::exchange(stream)?;
+
+	let mut challenge = [0u8; CHALLENGE_LEN];
+	rand::thread_rng().fill(&mut challenge[..]);
+
+	let challenge_msg = Message {
+		ty: MessageTy::Resume,
+		len: CHALLENGE_LEN,
+		seq: 0,
+	};
+
+	stream.write_all(&challenge_msg.encode())?;
+	stream.write_all(&challenge)?;
+	stream.flush()?;
+
+	let mut buf = [0u8; MESSAGE_SIZE];
+	stream.read_exact(&mut buf)?;
+	let resume_msg = Message::decode(&buf)?;
+
+	if resume_msg.ty != MessageTy::Resume {
+		return Err(ProtoError::UnexpectedMessage);
+	}
+
+	if resume_msg.len != TOKEN_LEN + CHALLENGE_LEN + PROOF_LEN {
+		return Err(ProtoError::MalformedMessage);
+	}
+
+	let mut payload = vec![0u8; resume_msg.len];
+	stream.read_exact(&mut payload)?;
+	let (token, rest) = payload.split_at(TOKEN_LEN);
+	let (nonce, proof) = rest.split_at(CHALLENGE_LEN);
+
+	let tokens = tokens.ok_or(ProtoError::InvalidToken)?;
+	if !tokens.check(token) || !verify(&tokens.proof_key, &[SENDER_PROOF, &challenge, token, nonce], proof) {
+		return Err(ProtoError::InvalidToken);
+	}
+
+	let mut resuming = Resuming { challenge, nonce: [0u8; CHALLENGE_LEN] };
+	resuming.nonce.copy_from_slice(nonce);
+	Ok(resuming)
+}
+
+/// Answers a sender which was accepted by `read_resume()` w/ `seq`, the
+/// sequence number of the message to carry on from, & a proof that the
+/// answer came from the receiver which challenged it.
+pub(super) fn write_reply<S: Write + ?Sized>(stream: &mut S, tokens: &Tokens, resuming: &Resuming, seq: u64) -> Result<(), ProtoError> {
+	let mut payload = vec![0u8; mem::size_of::<u64>() + PROOF_LEN];
+	let (seq_buf, proof) = payload.split_at_mut(mem::size_of::<u64>());
+	NetworkEndian::write_u64(seq_buf, seq);
+	proof.copy_from_slice(prove(&tokens.proof_key, &[RECEIVER_PROOF, &resuming.challenge, &resuming.nonce, seq_buf]).as_ref());
+
+	let reply_msg = Message {
+		ty: MessageTy::Resume,
+		len: payload.len(),
+		seq: 0,
+	};
+
+	stream.write_all(&reply_msg.encode())?;
+	stream.write_all(&payload)?;
+	Ok(())
+}
+
+/// Presents `token` on a new connection to the receiver, proving the sender
+/// holds the session key w/ `proof_key`, and returns the sequence number of
+/// the message the receiver expects next once its answer is checked.
+pub(super) fn present<S: Read + Write + ?Sized>(stream: &mut S, token: &[u8], proof_key: &[u8]) -> Result<u64, ProtoError> {
+	banner::exchange(stream)?;
+
+	let mut buf = [0u8; MESSAGE_SIZE];
+	stream.read_exact(&mut buf)?;
+	let challenge_msg = Message::decode(&buf)?.or_fault(stream)?;
+
+	if challenge_msg.ty != MessageTy::Resume {
+		return Err(ProtoError::UnexpectedMessage);
+	}
+
+	if challenge_msg.len != CHALLENGE_LEN {
+		return Err(ProtoError::MalformedMessage);
+	}
+
+	let mut challenge = [0u8; CHALLENGE_LEN];
+	stream.read_exact(&mut challenge)?;
+
+	let mut nonce = [0u8; CHALLENGE_LEN];
+	rand::thread_rng().fill(&mut nonce[..]);
+
+	let mut payload = Vec::with_capacity(TOKEN_LEN + CHALLENGE_LEN + PROOF_LEN);
+	payload.extend_from_slice(token);
+	payload.extend_from_slice(&nonce);
+	payload.extend_from_slice(prove(proof_key, &[SENDER_PROOF, &challenge, token, &nonce]).as_ref());
+
+	let resume_msg = Message {
+		ty: MessageTy::Resume,
+		len: payload.len(),
+		seq: 0,
+	};
+
+	stream.write_all(&resume_msg.encode())?;
+	stream.write_all(&payload)?;
+
+	stream.read_exact(&mut buf)?;
+	let reply = Message::decode(&buf)?.or_fault(stream)?;
+
+	if reply.ty != MessageTy::Resume {
+		return Err(ProtoError::UnexpectedMessage);
+	}
+
+	if reply.len != mem::size_of::<u64>() + PROOF_LEN {
+		return Err(ProtoError::MalformedMessage);
+	}
+
+	let mut reply_buf = vec![0u8; reply.len];
+	stream.read_exact(&mut reply_buf)?;
+	let (seq_buf, proof) = reply_buf.split_at(mem::size_of::<u64>());
+
+	if !verify(proof_key, &[RECEIVER_PROOF, &challenge, &nonce, seq_buf], proof) {
+		return Err(ProtoError::CryptoErr);
+	}
+
+	Ok(NetworkEndian::read_u64(seq_buf))
+}
+
+/// Signs the concatenation of `parts` (each of which has a fixed length)
+/// w/ a key derived from the session key. (See: `util::derive_resume_key()`.)
+fn prove(proof_key: &[u8], parts: &[&[u8]]) -> hmac::Signature {
+	let key = hmac::SigningKey::new(&digest::SHA256, proof_key);
+	let mut ctx = hmac::SigningContext::with_key(&key);
+	for part in parts {
+		ctx.update(part);
+	}
+
+	ctx.sign()
+}
+
+/// Returns true if `proof` is what `prove()` would return for `parts`.
+fn verify(proof_key: &[u8], parts: &[&[u8]], proof: &[u8]) -> bool {
+	let data = parts.concat();
+	let key = hmac::SigningKey::new(&digest::SHA256, proof_key);
+	hmac::verify_with_own_key(&key, &data, proof).is_ok()
+}
+
+/// Keeps accepting the sender while a session which can be resumed is
+/// running, so that a sender whose address changed (i.e: a laptop which
+/// moved to another network) picks the session up as soon as it reconnects,
+/// rather than once the receiver notices the old connection is gone.
+///
+/// A connection which resumes the session replaces the current one, which
+/// is hung up on so that the receiver resumes over the new one. Any other
+/// connection is dropped, the session carries on undisturbed. A connection
+/// which has not resumed the session within `STANDBY_DEADLINE` is hung up
+/// on, and no more than `STANDBY_CHECKS` are waited on at once.
+pub(super) struct Standby {
+	conns: mpsc::Receiver<(Box<dyn Transport>, Resuming)>,
+	current: Arc<Mutex<Option<Hangup>>>,
+	stop: Arc<AtomicBool>,
+	worker: Option<JoinHandle<()>>,
+}
+
+/// A new connection a `Standby` is waiting on to resume the session.
+struct Check {
+	deadline: Instant,
+	hangup: Arc<Mutex<Option<Hangup>>>,
+	worker: JoinHandle<()>,
+}
+
+impl Standby {
+	/// Starts accepting the sender w/ `reconnect`, for the session `tokens`
+	/// were issued for.
+	pub(super) fn spawn(mut reconnect: Reconnect, tokens: Tokens) -> Self {
+		let (tx, conns) = mpsc::channel();
+		let current: Arc<Mutex<Option<Hangup>>> = Arc::new(Mutex::new(None));
+		let stop = Arc::new(AtomicBool::new(false));
+
+		let (watched, stopped) = (Arc::clone(&current), Arc::clone(&stop));
+		let worker = thread::spawn(move || {
+			let mut checks: Vec<Check> = vec![];
+
+			while !stopped.load(Ordering::SeqCst) {
+				checks.retain(|check| !check.worker.is_finished());
+				for check in checks.iter().filter(|check| check.deadline <= Instant::now()) {
+					if let Some(hangup) = check.hangup.lock().expect("standby lock poisoned").take() {
+						hangup();
+					}
+				}
+
+				let mut conn = match reconnect(STANDBY_POLL) {
+					Ok(conn) => conn,
+					Err(ProtoError::ConnectTimeout) => continue,
+					Err(err) => {
+						debug!("could not accept the sender on standby: {}", err);
+						thread::sleep(RESUME_RETRY);
+						continue;
+					},
+				};
+
+				if checks.len() >= STANDBY_CHECKS {
+					warn!("dropping a connection, {} others are already trying to resume the session", checks.len());
+					let _ = conn.close();
+					continue;
+				}
+
+				// the token is read on another thread, so that a peer which
+				// never sends one cannot keep the sender from being accepted
+				let hangup = Arc::new(Mutex::new(conn.hangup_handle()));
+				let (tokens, tx, watched, timer) = (tokens.clone(), tx.clone(), Arc::clone(&watched), Arc::clone(&hangup));
+				let worker = thread::spawn(move || {
+					let checked = read_resume(&mut conn, Some(&tokens));
+
+					// a connection which was hung up on for taking too long
+					// is not resumed, even if it got through in the meantime
+					let timely = timer.lock().expect("standby lock poisoned").take().is_some()
+						|| conn.hangup_handle().is_none();
+
+					match checked {
+						Ok(resuming) if timely => {
+							info!("the sender reconnected, moving the session to its new connection ...");
+							if let Some(hangup) = watched.lock().expect("standby lock poisoned").take() {
+								hangup();
+							}
+
+							let _ = tx.send((conn, resuming));
+						},
+
+						Ok(_) => {
+							warn!("dropping a connection which took too long to resume the session");
+							let _ = conn.close();
+						},
+
+						Err(err) => {
+							warn!("dropping a connection which did not resume the session: {}", err);
+							let _ = conn.close();
+						},
+					}
+				});
+
+				checks.push(Check { deadline: Instant::now() + STANDBY_DEADLINE, hangup, worker });
+			}
+		});
+
+		Self { conns, current, stop, worker: Some(worker) }
+	}
+
+	/// Sets the connection which is hung up on once the sender reconnects.
+	pub(super) fn watch(&self, transport: &dyn Transport) {
+		*self.current.lock().expect("standby lock poisoned") = transport.hangup_handle();
+	}
+
+	/// Waits up to `timeout` for the sender to reconnect, returning its new
+	/// connection once it proved it may resume the session on it.
+	pub(super) fn accept(&self, timeout: Duration) -> Result<(Box<dyn Transport>, Resuming), ProtoError> {
+		self.conns.recv_timeout(timeout)
+			.map_err(|_| ProtoError::ConnectTimeout)
+	}
+}
+
+impl Drop for Standby {
+	fn drop(&mut self) {
+		// the addresses are free to be listened on again once this returns
+		self.stop.store(true, Ordering::SeqCst);
+		if let Some(worker) = self.worker.take() {
+			let _ = worker.join();
+		}
+	}
+}
+
+/// Issues & checks the resumption token of a receiver's session.
+///
+/// The token is the session's random id sealed under a key which never leaves
+/// the receiver, so the sender cannot forge or alter it, it can only hand it
+/// back. It is sent to the sender sealed under the session's reply key, so it
+/// cannot be read off the wire either.
+///
+/// The token does not stand in for the key: a sender which presents it must
+/// also sign the receiver's fresh challenge w/ `proof_key` (which is derived
+/// from the session key), so a token replayed by anyone else is refused.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Tokens {
+	key: [u8; 32],
+	session_id: [u8; SESSION_ID_LEN],
+	proof_key: Vec<u8>,
+}
+
+impl Tokens {
+	/// Creates the tokens for a session, which a sender proves it holds the
+	/// session key to w/ `proof_key`. (See: `util::derive_resume_key()`.)
+	pub fn new(proof_key: Vec<u8>) -> Self {
+		let mut rng = rand::thread_rng();
+		let mut tokens = Self { key: [0u8; 32], session_id: [0u8; SESSION_ID_LEN], proof_key };
+		rng.fill(&mut tokens.key[..]);
+		rng.fill(&mut tokens.session_id[..]);
+		tokens
+	}
+
+	/// Returns a new token for the session.
+	pub fn issue(&self) -> Result<Vec<u8>, ProtoError> {
+		let key = SealingKey::new(&aead::AES_256_GCM, &self.key)?;
+
+		let mut token = vec![0u8; TOKEN_LEN];
+		rand::thread_rng().fill(&mut token[..NONCE_LEN]);
+		token[NONCE_LEN..NONCE_LEN + SESSION_ID_LEN].copy_from_slice(&self.session_id);
+
+		let (nonce, sealed) = token.split_at_mut(NONCE_LEN);
+		aead::seal_in_place(&key, nonce, &[], sealed, TAG_LEN)?;
+		Ok(token)
+	}
+
+	/// Returns true if `token` was issued for this session.
+	pub fn check(&self, token: &[u8]) -> bool {
+		if token.len() != TOKEN_LEN {
+			return false;
+		}
+
+		let key = match OpeningKey::new(&aead::AES_256_GCM, &self.key) {
+			Ok(key) => key,
+			Err(_) => return false,
+		};
+
+		let mut sealed = token[NONCE_LEN..].to_vec();
+		match aead::open_in_place(&key, &token[..NONCE_LEN], &[], 0, &mut sealed) {
+			Ok(session_id) => session_id == &self.session_id[..],
+			Err(_) => false,
+		}
+	}
+}
+
+/// What a receiver needs to pick up its session after it crashed, which it
+/// saves every `CHECKPOINT_INTERVAL` messages. (See: `Receiver::set_checkpoint()`.)
+///
+/// The output is synced to disk before each checkpoint is saved, so the
+/// first `bytes()` of it are known to be intact & the rest is written again
+/// once the sender resumes. The checkpoint holds the session key, so it is
+/// only readable by its owner.
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+	pub(super) version: u32,
+	pub(super) tokens: Tokens,
+	pub(super) cipher: u8,
+	pub(super) key: Vec<u8>,
+	pub(super) rekey_salt: Option<Vec<u8>>,
+	pub(super) epoch: u64,
+	pub(super) nonce: u32,
+
+	/// The sequence number of the last message which was handled in full.
+	pub(super) handled: u64,
+
+	/// The `Features` both peers advertised.
+	pub(super) features: u32,
+
+	pub(super) session_id: Option<Vec<u8>>,
+	pub(super) plaintext_bytes: u64,
+	pub(super) ciphertext_bytes: u64,
+	pub(super) blocks: u64,
+	pub(super) expected_bytes: Option<u64>,
+	pub(super) metadata: Option<FileMeta>,
+}
+
+impl Checkpoint {
+	/// Reads the checkpoint at `path`, or returns `None` if there is none.
+	/// (i.e: the last session finished, or never got far enough to save one.)
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Self>, ProtoError> {
+		let buf = match fs::read(path) {
+			Ok(buf) => buf,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(err.into()),
+		};
+
+		let checkpoint: Self = bincode::deserialize(&buf)
+			.map_err(|_| ProtoError::InvalidCheckpoint)?;
+
+		if checkpoint.version != CHECKPOINT_VERSION {
+			return Err(ProtoError::InvalidCheckpoint);
+		}
+
+		Ok(Some(checkpoint))
+	}
+
+	/// The length of the output when the checkpoint was saved, which it must
+	/// be cut back to before the session is restored.
+	pub fn bytes(&self) -> u64 {
+		self.plaintext_bytes
+	}
+
+	/// Replaces the checkpoint at `path`, such that a crash part way through
+	/// leaves either the old one or the new one.
+	pub(super) fn save(&self, path: &Path) -> Result<(), ProtoError> {
+		let buf = bincode::serialize(self)?;
+
+		let mut name = path.file_name().unwrap_or_default().to_os_string();
+		name.push(".tmp");
+		let tmp = path.with_file_name(name);
+
+		// the checkpoint holds the session's key, so a file left behind by an
+		// earlier crash is replaced rather than reused w/ its permissions.
+		match fs::remove_file(&tmp) {
+			Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+			_ => {},
+		}
+
+		let mut options = OpenOptions::new();
+		options.write(true).create_new(true);
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::OpenOptionsExt;
+			options.mode(0o600);
+		}
+
+		let mut file = options.open(&tmp)?;
+		file.write_all(&buf)?;
+		file.sync_all()?;
+		fs::rename(&tmp, path)?;
+
+		Ok(())
+	}
+}
@@ -1,13 +1,49 @@
 use crate::error::ProtoError;
-use crate::proto::util;
-use crate::proto::{MessageTy, Message, Mode, State, Stream};
-use crate::proto::{BLOCK_SIZE, MAGIC_BYTES, MESSAGE_SIZE};
+use crate::key;
+use crate::proto::cipher::{self, CIPHER_AUTO};
+use crate::proto::features::FEATURES_LEN;
+use crate::proto::fingerprint;
+use crate::proto::identity::{self, Ephemeral, IdentityCheck, Transcript, CHALLENGE_LEN};
+use crate::proto::pacing::RateLimit;
+use crate::proto::padding::{self, COVER_INTERVAL};
+use crate::proto::pake::{Pake, ELEMENT_LEN};
+use crate::proto::reader::{Chunk, ChunkReader, Plain};
+use crate::proto::receipt::{StreamDigest, DIGEST_LEN};
+use crate::proto::workers::{Block, Workers};
+use crate::proto::summary::StatsTimer;
+use crate::proto::resume::{self, Reconnect, RESUME_RETRY, TOKEN_LEN};
+use crate::proto::session::SESSION_ID_LEN;
+use crate::proto::{banner, connect, event, fault, reconnector, resolve, util};
+use crate::proto::{CancelToken, Cipher, Event, FaultCode, Features, FileMeta, Identity, LinkStats, MessageTy, Message, Mode, Observer, Receipt, SessionId, State, StreamOpts, Summary, Transport};
+use crate::proto::{ACK_INTERVAL, ACK_LEN, BLOCK_SIZE, HELLO_LEN, MAGIC_BYTES, MESSAGE_SIZE, REKEY_SALT_LEN, RESUME_TIMEOUT};
 
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt, WriteBytesExt};
+use rand::{FromEntropy, Rng, SeedableRng};
+use rand::rngs::StdRng;
 use ring::aead::{self, OpeningKey, SealingKey};
-use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::collections::VecDeque;
+use std::io::{self, Cursor, Read, Write};
 use std::mem;
+use std::fs::File;
 use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the sender wakes up to check its interrupt flag & keepalive
+/// timer while it is waiting on its input.
+pub(super) const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How many sealed messages the sender keeps by default, so that it can
+/// resend them if the receiver cannot open one. These ~32 MiB of blocks cover
+/// what UDT's default send & receive buffers can hold in flight.
+pub(crate) const RETRANSMIT_WINDOW: usize = 4096;
+
+/// How many sealed messages the sender lets the receiver leave unacknowledged
+/// by default, which is ~16 MiB of blocks. (See: `Sender::set_max_unacked()`.)
+const MAX_UNACKED: usize = 2048;
 
 /// The `Sender` implements the sending half of the buffer, it encrypts
 /// blocks and sends them out over the UDT socket.
@@ -24,40 +60,551 @@ use std::net::ToSocketAddrs;
 ///    buffer and subsequently encrypted in-place.
 ///
 /// 3. `State:WaitHangup`: once the sender reaches EOF it sends the
-///    `MessageTy::Goodbye` packet to the receiver. It awaits a response
+///    `MessageTy::Goodbye` packet to the receiver, sealed & carrying the
+///    number of bytes & blocks it sent. It awaits a response
 ///    indicating the receiver has finished receiving data which is still
 ///    in-flight. Upon receiving this goodbye the sender closes the connection
 ///    and exits successfully.
 ///
+/// If the sender's interrupt flag is raised while transmitting it sends a
+/// `MessageTy::Abort` to the receiver at the next block boundary, waits for
+/// the receiver to acknowledge it, and stops with `ProtoError::Interrupted`.
+/// The transfer is aborted the same way if reading the input fails, so that
+/// the receiver does not mistake a partial input for a complete one. A sender
+/// cancelled through its `CancelToken` also aborts, but does not fail if the
+/// abort cannot be delivered, and stops with `ProtoError::Cancelled`.
+///
+/// If a rekey interval is set the sender sends a `MessageTy::ReKey` each time
+/// that many plaintext bytes have been sent, after which both peers switch to
+/// a sub-key derived from the master key and a fresh random salt.
+///
+/// If the sender was given a `FileMeta` it is sent in an encrypted
+/// `MessageTy::Metadata` frame immediately after the handshake.
+///
+/// A sender started with `run_files()` instead frames each file with a
+/// `MessageTy::FileStart` (carrying its `FileMeta`) and a `MessageTy::FileEnd`
+/// (carrying its length), so that the receiver can write them out separately.
+///
+/// If the sender is sparse, the holes in a file it sends are skipped w/ a
+/// `MessageTy::Skip` carrying their length, instead of being sent as blocks
+/// of zeros. `run_file()` & `run_files()` ask the filesystem where the holes
+/// are, while `run()` looks for long runs of zeros in its input instead.
+///
+/// If a keepalive interval is set and the input has not produced a block for
+/// that long, the sender sends a `MessageTy::Ping`. The receiver answers with
+/// a `MessageTy::Pong`, which the sender skips over while hanging up.
+///
+/// A sender which pads its traffic sends each block as a `MessageTy::Padded`
+/// filled out to the full block size, and sends empty ones (cover blocks)
+/// every `COVER_INTERVAL` while its input is idle, in place of keepalives.
+/// Holes are sent as zeros, so that a `MessageTy::Skip` does not give away
+/// their length.
+///
+/// A low latency sender seals & sends each chunk of input on its own, as soon
+/// as it is read, rather than gathering the chunks which were read ahead into
+/// a batch for the crypto workers. This costs throughput, but nothing waits on
+/// the rest of a batch. (e.g: a log being tailed, or an interactive shell.)
+///
+/// Conversely, a sender w/ a minimum block size holds on to a short chunk of
+/// input for a little while, and appends what it reads next, until the block
+/// is long enough or the wait is over. This saves an input which trickles in
+/// (e.g: a slow generator) from being sent as thousands of tiny blocks, each
+/// w/ its own header & tag.
+///
+/// A line buffered sender reading w/ `run()` sends each block up to the last
+/// newline in its input, and holds back a partial line until it is complete.
+/// (e.g: so that a log piped from `tail -f` arrives a line at a time.)
+///
+/// The sender keeps the last few sealed messages it sent. If the receiver
+/// cannot open one of them it answers w/ a `MessageTy::Nack`, and the sender
+/// resends that message & everything after it rather than failing the whole
+/// transfer. A message which has already left the window cannot be resent, so
+/// the sender aborts w/ `ProtoError::BlockLost` instead.
+///
+/// A receiver may issue a `MessageTy::Token` after the handshake. If a resume
+/// timeout is set and the connection drops mid-transfer, or before the
+/// receiver has answered the sender's `MessageTy::Goodbye`, the sender keeps
+/// trying to reach the receiver until it expires. It presents the token in a
+/// `MessageTy::Resume` on the new connection, signing the receiver's challenge
+/// to prove it holds the session key, and resends the messages from
+/// the one the receiver says it expects next. Those must still be in the
+/// window, as above.
+///
+/// If both peers support `Features::ACKS` the receiver acknowledges what it
+/// has handled every `ACK_INTERVAL` messages, and the sender stops to wait for
+/// a `MessageTy::Ack` while too many are unacknowledged. This keeps what is
+/// in flight within the retransmit window, so that a resumed session always
+/// finds the message it needs there, and lets the sender report how much the
+/// receiver has actually written. (See: `Sender::acked_bytes()`.)
+///
+/// A sender w/ an identity or an identity check sends an ephemeral X25519
+/// share as a challenge in its `MessageTy::ReqIV`, and the peers exchange a
+/// `MessageTy::Identity` signing the handshake once their `MessageTy::Hello`s
+/// are through. The sender hangs up unless the receiver's signature holds and
+/// the check accepts it. (e.g: because its public key has the fingerprint the
+/// sender expects.) The secret agreed on w/ the shares is then mixed into the
+/// session key. (See: `identity::Ephemeral`.)
+///
+/// A receiver which proved its identity answers the sender's goodbye w/ a
+/// signed `Receipt`. The sender checks it covers the data it sent, and may
+/// insist on one. (See: `Sender::set_require_receipt()`.)
+///
+/// A sender given a short code rather than a key opens the handshake w/ a
+/// `MessageTy::Pake`, and uses the key it agrees on w/ the receiver from
+/// there on. A receiver w/ a different code cannot open the `Hello` sealed
+/// w/ it. (See: `Sender::set_code()`.)
+///
 pub struct Sender {
+	key: Vec<u8>,
+	code: Option<String>,
+	cipher: Cipher,
+	forced_cipher: Option<Cipher>,
+	identity: Option<Identity>,
+	identity_check: Option<IdentityCheck>,
+	transcript: Transcript,
+	peer_fingerprint: Option<String>,
+
+	/// The session key once both identities are checked, mixed w/ the secret
+	/// agreed on w/ the challenges. (See: `identity::Ephemeral`.)
+	mixed_key: Option<Vec<u8>>,
+	ephemeral: Option<Ephemeral>,
+	advertised: Features,
+	features: Features,
 	dec_key: OpeningKey,
-	enc_key: SealingKey,
+	enc_key: Arc<SealingKey>,
+	epoch: u64,
 
-	stream: Stream,
+	/// Opens the receiver's replies, once our hello named the session. (See:
+	/// `util::derive_reply_key()`.)
+	reply_key: Option<OpeningKey>,
+
+	stream: Box<dyn Transport>,
 	state: State,
+	shake: Shake,
+	aborted: bool,
 
 	counter: u64,
 	nonce:   u32,
+	rng: StdRng,
+
+	interrupt: Arc<AtomicBool>,
+	cancel: CancelToken,
+
+	started: Instant,
+	summary: Summary,
+	stats: StatsTimer,
+	rtt: Duration,
+	digest: StreamDigest,
+
+	require_receipt: bool,
+	receipt: Option<Receipt>,
+
+	observer: Option<Observer>,
+
+	rekey_interval: u64,
+	rekey_bytes: u64,
+
+	keepalive: Duration,
+
+	metadata: Option<FileMeta>,
+
+	sparse: bool,
+	padding: bool,
+	mmap: bool,
+	low_latency: bool,
+	min_block: usize,
+	max_wait: Duration,
+	line_buffered: bool,
+	rate_limit: Option<RateLimit>,
+
+	sent: VecDeque<Sealed>,
+	window: usize,
+
+	/// The last message the receiver acknowledged, & how many bytes it had
+	/// handled by then. (See: `MessageTy::Ack`.)
+	acked: u64,
+	acked_bytes: u64,
+	max_unacked: usize,
+
+	workers: Option<Workers>,
+
+	token: Option<Vec<u8>>,
+
+	/// The key the sender proves it holds the session key w/ when it presents
+	/// its token. (See: `util::derive_resume_key()`.)
+	resume_key: Option<Vec<u8>>,
+	reconnect: Option<Reconnect>,
+	resume_timeout: Duration,
+}
+
+/// How far the handshake has got. Each step reads at most one message from
+/// the receiver, before it writes whatever answers it. (See: `Sender::shake()`.)
+enum Shake {
+	Start,
+	Banner,
+	Pake(Pake),
+	RepIV { challenged: bool },
+	Hello { challenged: bool, sent: Instant },
+	Identity,
+	Done,
+}
+
+/// A message which was sealed & sent, kept as it was written to the stream.
+/// (i.e: its header followed by its payload.)
+struct Sealed {
+	seq: u64,
+	message: Vec<u8>,
 }
 
 impl Sender {
-	pub fn new<S: ToSocketAddrs>(addr: S, key: &[u8]) -> Result<Self, ProtoError> {
-		let stream = Stream::new(Mode::Sender, addr)?;
-		let dec_key = OpeningKey::new(&aead::AES_256_GCM, key)?;
-		let enc_key = SealingKey::new(&aead::AES_256_GCM, key)?;
+	/// Creates a `Sender` connected to the receiver at `addr` which will use
+	/// `key` to encrypt outgoing blocks. The `opts` control how the underlying
+	/// socket is set up. (e.g: to bind to a specific local address.)
+	pub fn new<S: ToSocketAddrs>(addr: S, key: &[u8], opts: &StreamOpts) -> Result<Self, ProtoError> {
+		let addrs = resolve(addr, opts.family)?;
+		let stream = connect(Mode::Sender, &addrs[..], opts)?;
+
+		let mut sender = Self::with_transport(stream, key)?;
+		sender.reconnect = Some(reconnector(Mode::Sender, addrs, opts));
+		Ok(sender)
+	}
+
+	/// Creates a `Sender` which runs over an established `transport` instead
+	/// of a UDT socket of its own.
+	pub fn with_transport<T: Transport + 'static>(transport: T, key: &[u8]) -> Result<Self, ProtoError> {
+		let cipher = Cipher::for_key(key)?;
+		let dec_key = OpeningKey::new(cipher.algorithm(), key)?;
+		let enc_key = Arc::new(SealingKey::new(cipher.algorithm(), key)?);
 
 		Ok(Self {
-			dec_key: dec_key,
-			enc_key: enc_key,
+			key: key.to_vec(),
+			code: None,
+			cipher,
+			forced_cipher: None,
+			identity: None,
+			identity_check: None,
+			transcript: Transcript::new(),
+			peer_fingerprint: None,
+			mixed_key: None,
+			ephemeral: None,
+			advertised: Features::all(),
+			features: Features::empty(),
+			dec_key,
+			enc_key,
+			epoch: 0,
+			reply_key: None,
 
-			stream: stream,
+			stream: Box::new(transport),
 			state: State::WaitHello,
+			shake: Shake::Start,
+			aborted: false,
 
 			counter: 0,
 			nonce:   0,
+			rng: StdRng::from_entropy(),
+
+			interrupt: Arc::new(AtomicBool::new(false)),
+			cancel: CancelToken::default(),
+
+			started: Instant::now(),
+			summary: Summary::default(),
+			stats: StatsTimer::new(Duration::from_secs(0)),
+			rtt: Duration::default(),
+			digest: StreamDigest::new(),
+
+			require_receipt: false,
+			receipt: None,
+
+			observer: None,
+
+			rekey_interval: 0,
+			rekey_bytes: 0,
+
+			keepalive: Duration::from_secs(0),
+
+			metadata: None,
+
+			sparse: false,
+			padding: false,
+			mmap: false,
+			low_latency: false,
+			min_block: 0,
+			max_wait: Duration::from_secs(0),
+			line_buffered: false,
+			rate_limit: None,
+
+			sent: VecDeque::new(),
+			window: RETRANSMIT_WINDOW,
+
+			acked: 0,
+			acked_bytes: 0,
+			max_unacked: MAX_UNACKED,
+
+			workers: None,
+
+			token: None,
+			resume_key: None,
+			reconnect: None,
+			resume_timeout: RESUME_TIMEOUT,
 		})
 	}
 
+	/// Insists on `cipher` for the session, rather than letting the peers
+	/// choose the quickest one they can both use w/ the key.
+	pub fn set_cipher(&mut self, cipher: Cipher) -> Result<(), ProtoError> {
+		cipher.check_key(&self.key)?;
+		self.forced_cipher = Some(cipher);
+		Ok(())
+	}
+
+	/// Agrees on the session key w/ a receiver which was given the same short
+	/// `code`, rather than using the key this sender was created with. The
+	/// agreed key is the same length, so it suits the same ciphers. (See:
+	/// `proto::generate_code()`.)
+	pub fn set_code(&mut self, code: &str) {
+		self.code = Some(code.to_string());
+	}
+
+	/// Refuses to send to a receiver unless it proves it holds the identity
+	/// w/ this `fingerprint`. (See: `Identity::fingerprint()`.)
+	pub fn set_expected_fingerprint(&mut self, fingerprint: &str) {
+		self.identity_check = Some(identity::expect(&[fingerprint]));
+	}
+
+	/// Asks the receiver to prove its identity during the handshake, and lets
+	/// `check` refuse it. The check is given the fingerprint of the identity,
+	/// or `None` if the receiver has no identity.
+	pub fn set_identity_check<F>(&mut self, check: F)
+	where F: FnMut(Option<&str>) -> Result<(), ProtoError> + Send + 'static {
+		self.identity_check = Some(Box::new(check));
+	}
+
+	/// Signs the handshake w/ `identity`, so that a receiver which checks
+	/// the sender's identity can tell it reached this sender.
+	pub fn set_identity(&mut self, identity: Identity) {
+		self.identity = Some(identity);
+	}
+
+	/// Sets how many threads seal blocks. Blocks which have already been read
+	/// are sealed in parallel when there is more than one, and are still sent
+	/// in order.
+	pub fn set_crypto_threads(&mut self, threads: usize) {
+		self.workers = if threads > 1 { Some(Workers::new(threads)) } else { None };
+	}
+
+	/// Sets how many of the most recently sent messages are kept so they can
+	/// be resent if the receiver cannot open them. A window of zero disables
+	/// retransmission, so that a corrupted block fails the transfer.
+	pub fn set_retransmit_window(&mut self, messages: usize) {
+		self.window = messages;
+		while self.sent.len() > messages {
+			self.sent.pop_front();
+		}
+	}
+
+	/// Sets how many sealed messages may be unacknowledged before the sender
+	/// waits on the receiver, which bounds the data in flight to about that
+	/// many blocks. This is at least `ACK_INTERVAL`, and at most the retransmit
+	/// window unless that is disabled. It only applies if the receiver
+	/// supports `Features::ACKS`.
+	pub fn set_max_unacked(&mut self, messages: usize) {
+		self.max_unacked = messages;
+	}
+
+	/// Sets how long the sender keeps trying to resume the session if the
+	/// connection drops, `RESUME_TIMEOUT` by default. The receiver must have
+	/// issued a token for this to work, a timeout of zero disables resuming.
+	pub fn set_resume_timeout(&mut self, timeout: Duration) {
+		self.resume_timeout = timeout;
+	}
+
+	/// Sets how the sender reaches the receiver again to resume the session.
+	/// A sender created by `new()` connects the same way it did at first.
+	pub fn set_reconnect<F>(&mut self, reconnect: F)
+	where F: FnMut(Duration) -> Result<Box<dyn Transport>, ProtoError> + Send + 'static {
+		self.reconnect = Some(Box::new(reconnect));
+	}
+
+	/// Sets the number of plaintext bytes which may be sent before the
+	/// session keys are rotated. An interval of zero disables rekeying.
+	pub fn set_rekey_interval(&mut self, bytes: u64) {
+		self.rekey_interval = bytes;
+	}
+
+	/// Draws the challenge, rekey salts & session id from a generator seeded
+	/// w/ `seed` rather than the OS, so that sessions run w/ the same seeds &
+	/// keys are identical on the wire. (For testing only, this makes the
+	/// session predictable to anyone who knows the seed.)
+	pub fn set_seed(&mut self, seed: u64) {
+		self.rng = StdRng::seed_from_u64(seed);
+	}
+
+	/// Sets how long the input may sit idle before a keepalive is sent to the
+	/// receiver. An interval of zero disables keepalives.
+	pub fn set_keepalive_interval(&mut self, interval: Duration) {
+		self.keepalive = interval;
+	}
+
+	/// Sets the description of the input which is sent to the receiver before
+	/// the first block. (e.g: when the input is a regular file.)
+	pub fn set_metadata(&mut self, metadata: FileMeta) {
+		self.metadata = Some(metadata);
+	}
+
+	/// Announces that the input is `size` bytes long, so that the receiver can
+	/// report its progress & check that it got all of it. The session fails
+	/// if the input turns out to be any other length.
+	pub fn set_total_size(&mut self, size: u64) {
+		self.summary.expected_bytes = Some(size);
+	}
+
+	/// Sets whether the holes in a file input, or the long runs of zeros in
+	/// any other input, are skipped rather than sent as zeros. The receiver
+	/// must understand `MessageTy::Skip`, so this is off by default.
+	pub fn set_sparse(&mut self, sparse: bool) {
+		self.sparse = sparse;
+	}
+
+	/// Sets whether a file input is mapped into memory rather than read, which
+	/// saves a syscall per block. A file which is truncated while it is mapped
+	/// kills the sender w/ `SIGBUS`, so this is off by default. (See:
+	/// `ChunkReader::spawn_mapped()`.)
+	pub fn set_mmap(&mut self, mmap: bool) {
+		self.mmap = mmap;
+	}
+
+	/// Sets whether every block is padded to the full block size, w/ cover
+	/// blocks filling in while the input is idle, so that the length & timing
+	/// of the ciphertext do not give away how much data is sent. The receiver
+	/// must understand `MessageTy::Padded`, so this is off by default.
+	pub fn set_padding(&mut self, padding: bool) {
+		self.padding = padding;
+	}
+
+	/// Sets whether each chunk of input is sent as soon as it is read, rather
+	/// than batched w/ those read after it. This is off by default, since a
+	/// batch is sealed faster by several crypto workers. (See: `Sender`.)
+	pub fn set_low_latency(&mut self, low_latency: bool) {
+		self.low_latency = low_latency;
+	}
+
+	/// Returns whether the sender was set to low latency.
+	pub fn low_latency(&self) -> bool {
+		self.low_latency
+	}
+
+	/// Sets how long the sender waits on its input to fill out a block which
+	/// is shorter than `min_block` bytes, which is at most `BLOCK_SIZE`. The
+	/// block is sent as it is once `max_wait` has passed. This is off by
+	/// default, i.e: blocks are sent as soon as they are read.
+	pub fn set_coalescing(&mut self, min_block: usize, max_wait: Duration) {
+		self.min_block = min_block.min(BLOCK_SIZE);
+		self.max_wait = max_wait;
+	}
+
+	/// Sets whether each block the sender reads from a stream ends at a
+	/// newline, which suits a log. This is off by default, and is ignored if
+	/// the sender skips runs of zeros. (See: `ChunkReader::spawn_lines()`.)
+	pub fn set_line_buffered(&mut self, line_buffered: bool) {
+		self.line_buffered = line_buffered;
+	}
+
+	/// Returns whether the sender was set to line buffering.
+	pub fn line_buffered(&self) -> bool {
+		self.line_buffered
+	}
+
+	/// Sets the average rate, in bytes per second, which the sender's
+	/// messages are sent at. This counts their headers & tags, i.e: what
+	/// crosses the wire, but not what is resent. Zero (the default) sends
+	/// as fast as the transport will take them.
+	pub fn set_rate_limit(&mut self, bytes_per_sec: u64) {
+		self.rate_limit = Some(bytes_per_sec)
+			.filter(|&bytes_per_sec| bytes_per_sec > 0)
+			.map(RateLimit::new);
+	}
+
+	/// Sets whether the session fails unless the receiver signs a `Receipt`
+	/// for the data, which it can only do w/ an identity. This asks for the
+	/// receiver's identity even if it is not otherwise checked.
+	pub fn set_require_receipt(&mut self, require: bool) {
+		self.require_receipt = require;
+	}
+
+	/// Limits the features advertised to the receiver, which are all of them
+	/// by default. A feature the receiver does not advertise either is not
+	/// used: the blocks are sent w/o padding & w/o keepalives, while sending
+	/// several files fails. (See: `Features`.)
+	pub fn set_features(&mut self, features: Features) {
+		self.advertised = features;
+	}
+
+	/// Returns the features both peers advertised, once the handshake is over.
+	pub fn features(&self) -> Features {
+		self.features
+	}
+
+	/// Returns the receipt the receiver signed, once the session is over.
+	pub fn receipt(&self) -> Option<&Receipt> {
+		self.receipt.as_ref()
+	}
+
+	/// Returns the SHA-256 of the input read so far, which is what the
+	/// receiver checks once the session is over.
+	pub fn digest(&self) -> [u8; DIGEST_LEN] {
+		self.digest.finish()
+	}
+
+	/// Registers a callback which is invoked for each `Event` in the session.
+	pub fn set_observer<F: FnMut(&Event) + Send + 'static>(&mut self, observer: F) {
+		self.observer = Some(Box::new(observer));
+	}
+
+	/// Returns a channel which receives a copy of each `Event` in the session,
+	/// this replaces any observer which was registered previously.
+	pub fn subscribe(&mut self) -> mpsc::Receiver<Event> {
+		let (observer, events) = event::channel();
+		self.observer = Some(observer);
+		events
+	}
+
+	/// Sets how often an `Event::Stats` sampling the transfer's progress is
+	/// emitted. An interval of zero (the default) disables them.
+	pub fn set_stats_interval(&mut self, interval: Duration) {
+		self.stats = StatsTimer::new(interval);
+	}
+
+	/// Returns a tally of the data which has been transferred so far.
+	pub fn summary(&self) -> &Summary {
+		&self.summary
+	}
+
+	/// Returns how many plaintext bytes the receiver has acknowledged, which
+	/// trails `summary().plaintext_bytes` by what is still in flight. This is
+	/// zero unless the receiver supports `Features::ACKS`.
+	pub fn acked_bytes(&self) -> u64 {
+		self.acked_bytes
+	}
+
+	/// Returns the state of the connection to the receiver: its round-trip time,
+	/// estimated bandwidth & how many packets were lost or retransmitted. Once
+	/// the session is over this is how the connection stood as it was closed.
+	/// (This is `None` if the transport does not report it.)
+	pub fn stats(&self) -> Option<LinkStats> {
+		self.stream.link_stats()
+	}
+
+	/// Replaces the flag which is polled between blocks to determine if the
+	/// transfer should be cut short. (e.g: one set by a signal handler.)
+	pub fn set_interrupt(&mut self, flag: Arc<AtomicBool>) {
+		self.interrupt = flag;
+	}
+
+	/// Returns a handle which cancels this sender from another thread.
+	pub fn cancel_token(&self) -> CancelToken {
+		self.cancel.clone()
+	}
+
 	/// This runs the `Sender` state machine to completion.
 	/// 
 	/// First the sender attempts to connect to the remote peer and
@@ -65,210 +612,1417 @@ impl Sender {
 	///
 	/// Once the encrypted channel is setup the sender begins reading
 	/// chunks from stdin and encrypts them to be sent over the wire
-	/// to the receiver.
+	/// to the receiver. The input is read on a helper thread, which is
+	/// why it must be `Send`.
 	///
 	/// Once the end of `stdin` has been reached the sender performs a
 	/// closing handshake to attempt to cleanly shutdown the receiver
 	/// and ensure that it has flushed all contents to its output buffer.
-	pub fn run<R: Read>(&mut self, mut input: R) -> Result<(), ProtoError> {
+	pub fn run<R: Read + Send + 'static>(&mut self, input: R) -> Result<(), ProtoError> {
 		info!("starting sender ...");
+		let mut reader = if self.sparse && !self.padding {
+			ChunkReader::spawn_zeros(input)
+		} else if self.line_buffered {
+			ChunkReader::spawn_lines(input)
+		} else {
+			ChunkReader::spawn(input)
+		};
 
-		loop {
-			match self.state {
-				State::WaitHello => self.wait_hello()?,
-				State::Transmit => self.transmit(&mut input)?,
+		self.run_session(|sender| {
+			sender.transmit(&mut reader)?;
+			Ok(())
+		})
+	}
 
-				State::WaitHangup => {
-					self.wait_hup()?;
-					return Ok(());
-				}
+	/// This runs the `Sender` state machine to completion, like `run()`, but
+	/// w/ a `file` as its input so that its holes can be skipped if the sender
+	/// is sparse.
+	pub fn run_file(&mut self, file: File) -> Result<(), ProtoError> {
+		info!("starting sender ...");
+		let mut reader = self.file_reader(file);
+
+		self.run_session(|sender| {
+			sender.transmit(&mut reader)?;
+			Ok(())
+		})
+	}
+
+	/// This runs the `Sender` state machine to completion, sending each of
+	/// the files at `paths` in turn over a single session.
+	///
+	/// Each file is framed by a `FileStart` & `FileEnd` message so that the
+	/// receiver can demultiplex them into separate outputs.
+	pub fn run_files<P: AsRef<Path>>(&mut self, paths: &[P]) -> Result<(), ProtoError> {
+		info!("starting sender w/ {} files ...", paths.len());
+
+		self.run_session(|sender| {
+			if !sender.features.contains(Features::MULTI_FILE) {
+				return Err(ProtoError::UnsupportedFeature { feature: Features::MULTI_FILE });
 			}
-		}
+
+			for path in paths {
+				sender.send_file(path.as_ref())?;
+			}
+
+			Ok(())
+		})
 	}
 
-	fn transmit<R: Read>(&mut self, input: R) -> Result<(), ProtoError> {
-		let tag_len = self.enc_key.algorithm().tag_len();
-		let mut reader = BufReader::with_capacity(BLOCK_SIZE, input);
-		let mut enc_buffer = vec![0u8; BLOCK_SIZE + tag_len];
+	/// Checks that the receiver is reachable and has the same key, without
+	/// sending any data. The sender performs the handshake and then hangs up
+	/// straight away, the receiver sees an ordinary (empty) session.
+	///
+	/// Returns the round-trip time of the `Hello` exchange.
+	pub fn ping(&mut self) -> Result<Duration, ProtoError> {
+		info!("pinging receiver ...");
+		self.run_session(|_| Ok(()))?;
+		Ok(self.rtt)
+	}
 
-		'copy: loop {
-			let chunk = reader.fill_buf()?;
-			trace!("copying block from stdin {}", enc_buffer.len());
-			trace!("block size: {}", chunk.len());
-			let mut input_cursor = Cursor::new(&chunk);
-			let mut enc_cursor = Cursor::new(&mut enc_buffer[..BLOCK_SIZE]);
-			let bytes_read = io::copy(&mut input_cursor, &mut enc_cursor)? as usize;
-
-			// TODO: why is io::copy returning a u64?
-			trace!("copied {} bytes", bytes_read);
-			reader.consume(bytes_read);
-
-			if bytes_read == 0 {
-				debug!("buffer reached eof");
-				break 'copy;
+	fn run_session<F>(&mut self, transmit: F) -> Result<(), ProtoError>
+	where F: FnMut(&mut Self) -> Result<(), ProtoError> {
+		let result = self.run_states(transmit);
+		self.conclude(result)
+	}
+
+	/// Wraps up a session which ended w/ `result`, which is returned.
+	pub(super) fn conclude(&mut self, mut result: Result<(), ProtoError>) -> Result<(), ProtoError> {
+		// a receiver which failed may have said why before it hung up, while
+		// we were still busy sending.
+		if let Err(ref err) = result {
+			if err.is_hangup() {
+				if let Some(fault) = self.recv_fault() {
+					result = Err(fault);
+				}
 			}
+		}
 
-			trace!("encrypting block w/ tag {}", tag_len);
-			assert!(bytes_read <= BLOCK_SIZE);
-			let nonce = util::get_next_nonce(&mut self.nonce, &mut self.counter)?;
-			let enc_msg_len = bytes_read + tag_len;
-			let enc_size = aead::seal_in_place(&self.enc_key, &nonce, b"", &mut enc_buffer[..enc_msg_len], tag_len)?;
+		// hang up on the receiver after any failure, otherwise it will not
+		// notice until UDT gives up on the connection. it is told why first,
+		// unless it was already told w/ an abort.
+		if let Err(ref err) = result {
+			if !matches!(self.state, State::WaitHello) && !self.aborted {
+				fault::report(&mut *self.stream, err);
+			}
 
-			// create encrypted packet header
-			let block_msg = Message {
-				ty: MessageTy::Block,
-				len: enc_size,
-			};
+			let _ = self.stream.close();
+		}
 
-			trace!("sending block message: {:?}", block_msg);
-			let block_buf = bincode::serialize(&block_msg)?;
-			assert_eq!(block_buf.len(), MESSAGE_SIZE);
+		self.summary.link = self.stream.link_stats();
+		let finished = Event::from(&self.summary);
+		self.emit(finished);
+		SessionId::set_current(None);
+		result
+	}
 
-			self.stream.write(&block_buf)?;
+	fn run_states<F>(&mut self, mut transmit: F) -> Result<(), ProtoError>
+	where F: FnMut(&mut Self) -> Result<(), ProtoError> {
+		loop {
+			match self.state {
+				State::WaitHello => self.shake()?,
 
-			let mut pos = 0;
-			'write: loop {
-				let bytes_sent = self.stream.write(&enc_buffer[pos..enc_size])?;
-				pos += bytes_sent as usize;
+				State::Transmit => {
+					transmit(self)?;
+					self.state = State::WaitHangup;
+				},
 
-				trace!("pos: {}, sent: {}, len: {}", pos, bytes_sent, bytes_read);
-				if pos >= enc_size { break 'write; }
+				State::WaitHangup => return self.hang_up(),
 			}
 		}
+	}
+
+	/// Performs the handshake, unless it is already done. (This is how a
+	/// `SenderWriter` starts the session on its first write.)
+	pub(super) fn begin(&mut self) -> Result<(), ProtoError> {
+		while let State::WaitHello = self.state {
+			self.shake()?;
+		}
 
-		self.state = State::WaitHangup;
 		Ok(())
 	}
 
-	fn wait_hup(&mut self) -> Result<(), ProtoError> {
-		self.send_client_goodbye()?;
-		self.recv_server_goodbye()?;
-		Ok(())
+	/// Returns true until the handshake is complete.
+	#[cfg(feature = "async")]
+	pub(super) fn handshaking(&self) -> bool {
+		matches!(self.state, State::WaitHello)
 	}
 
-	fn wait_hello(&mut self) -> Result<(), ProtoError> {
-		self.req_iv()?;
-		self.recv_rep_iv()?;
-		self.send_hello()?;
-		self.recv_hello()?;
+	/// Returns true if the next step of the handshake reads from the receiver
+	/// before it writes anything. (See: `Sender::shake()`.)
+	#[cfg(feature = "async")]
+	pub(super) fn awaits_peer(&self) -> bool {
+		!matches!(self.shake, Shake::Start | Shake::Done)
+	}
 
-		info!("handshake complete!");
-		self.state = State::Transmit;
+	/// Says goodbye to the receiver once everything has been sent.
+	pub(super) fn hang_up(&mut self) -> Result<(), ProtoError> {
+		match self.send_goodbye() {
+			Err(err @ ProtoError::SizeMismatch { .. }) => {
+				self.abort()?;
+				return Err(err);
+			},
 
-		Ok(())
+			sent => sent?,
+		}
+
+		// the goodbye is resent w/ anything else the receiver missed if the
+		// connection drops before it answers.
+		while let Err(err) = self.recv_server_goodbye() {
+			self.resume_or(err)?;
+		}
+
+		self.hung_up()
 	}
 
-	fn req_iv(&mut self) -> Result<(), ProtoError> {
-		// ask the server for the IV
-		info!("sending IV request to remote peer ...");
-		let req_iv_msg = Message {
-			ty: MessageTy::ReqIV,
-			len: 0,
-		};
+	/// Tells the receiver how much was sent, unless that was not what was
+	/// announced in which case the transfer must be aborted instead.
+	pub(super) fn send_goodbye(&mut self) -> Result<(), ProtoError> {
+		self.state = State::WaitHangup;
+		if let Some(expected) = self.summary.expected_bytes.filter(|&bytes| bytes != self.summary.plaintext_bytes) {
+			warn!("input was not the announced length, aborting transfer ...");
+			return Err(ProtoError::SizeMismatch { expected, sent: self.summary.plaintext_bytes });
+		}
 
-		let req_iv_buf = bincode::serialize(&req_iv_msg)?;
+		self.send_client_goodbye()
+	}
+
+	/// Hangs up once the receiver has answered our goodbye.
+	pub(super) fn hung_up(&mut self) -> Result<(), ProtoError> {
+		// closing the socket (rather than just exiting) tells the receiver we
+		// are gone, otherwise its own `close()` may linger waiting for an ACK
+		// of its goodbye which the sender never got around to sending.
+		self.stream.close()?;
 
-		assert_eq!(MESSAGE_SIZE, req_iv_buf.len());
-		self.stream.write(&req_iv_buf)?;
+		if self.require_receipt && self.receipt.is_none() {
+			return Err(ProtoError::NoReceipt);
+		}
 
+		self.summary.elapsed = self.started.elapsed();
+		self.emit(Event::Goodbye);
 		Ok(())
 	}
 
-	fn recv_rep_iv(&mut self) -> Result<(), ProtoError> {
-		// read the IV from the server
-		info!("waiting for reply from server ...");
-		let mut buf = vec![0u8; MESSAGE_SIZE];
-		self.stream.read_exact(&mut buf)?;
-		let rep_iv_msg: Message= bincode::deserialize(&buf)?;
+	fn send_file(&mut self, path: &Path) -> Result<(), ProtoError> {
+		let file = File::open(path)?;
+		let mut metadata = FileMeta::from_path(path)?;
+		self.announce_size(&mut metadata);
+		info!("sending file: {:?}", metadata);
 
-		info!("got reply: {:?}", rep_iv_msg);
-		let mut buf = vec![0u8; rep_iv_msg.len];
-		self.stream.read_exact(&mut buf)?;
+		let payload = bincode::serialize(&metadata)?;
+		self.send_sealed(MessageTy::FileStart, &payload)?;
+		self.emit(Event::FileStart {
+			name: metadata.name.clone(),
+			mode: metadata.mode,
+			mtime: metadata.mtime,
+		});
 
-		let mut iv_cursor = Cursor::new(buf);
-		self.nonce = iv_cursor.read_u32::<NetworkEndian>()?;
-		info!("got iv: {:x}", self.nonce);
+		let mut reader = self.file_reader(file);
+		let bytes = self.transmit(&mut reader)?;
+
+		let mut payload = vec![];
+		payload.write_u64::<NetworkEndian>(bytes)?;
+		self.send_sealed(MessageTy::FileEnd, &payload)?;
+		self.emit(Event::FileEnd { name: metadata.name, bytes });
 
 		Ok(())
 	}
 
-	fn send_hello(&mut self) -> Result<(), ProtoError> {
-		info!("sending hello ...");
+	/// A sparse file stays sparse on the receiver, so it is not asked to
+	/// reserve space for the holes.
+	fn announce_size(&self, metadata: &mut FileMeta) {
+		if self.sparse && !self.padding {
+			metadata.size = None;
+		}
+	}
 
-		// write the magic bytes to a buffer
-		let tag_len = self.enc_key.algorithm().tag_len();
-		let enc_buf = vec![0u8; mem::size_of_val(&MAGIC_BYTES) + tag_len];
-		let mut enc_buf = {
-			let mut cursor = Cursor::new(enc_buf);
-			cursor.write_u32::<NetworkEndian>(MAGIC_BYTES)?;
-			cursor.into_inner()
-		};
+	fn file_reader(&self, file: File) -> ChunkReader {
+		if self.sparse && !self.padding {
+			ChunkReader::spawn_sparse(file)
+		} else if self.mmap {
+			ChunkReader::spawn_mapped(file)
+		} else {
+			ChunkReader::spawn(file)
+		}
+	}
 
-		// encrypt the buffer in-place
-		let msg_nonce = util::get_next_nonce(&mut self.nonce, &mut self.counter)?;
-		let msg_sz = aead::seal_in_place(&self.enc_key, &msg_nonce, b"", &mut enc_buf, tag_len)?;
+	/// Sends blocks read from `reader` until it reaches EOF, returning the
+	/// number of plaintext bytes which were sent.
+	fn transmit(&mut self, reader: &mut ChunkReader) -> Result<u64, ProtoError> {
+		let mut last_sent = Instant::now();
+		let mut bytes_sent = 0;
 
-		// send `Hello` followed by the encrypted payload
-		let hello_msg = Message {
-			ty: MessageTy::Hello,
-			len: msg_sz,
-		};
+		'copy: loop {
+			self.check_stopped()?;
+			let next = match reader.next(POLL_INTERVAL) {
+				Ok(next) => next,
+				Err(err) => {
+					warn!("could not read input, aborting transfer: {}", err);
+					self.abort()?;
+					return Err(err.into());
+				},
+			};
 
-		let hello_buf = bincode::serialize(&hello_msg)?;
-		assert_eq!(hello_buf.len(), MESSAGE_SIZE);
+			let chunk = match next {
+				Chunk::Data(chunk) => Plain::Read(self.coalesce(reader, chunk)),
 
-		self.stream.write(&hello_buf)?;
-		self.stream.write(&enc_buf[..msg_sz])?;
+				// a region is a whole block, unless it is the end of the file
+				Chunk::Mapped(region) => Plain::Mapped(region),
 
-		Ok(())
-	}
-	
-	fn send_client_goodbye(&mut self) -> Result<(), ProtoError> {
-		let goodbye_msg = Message {
-			ty: MessageTy::Goodbye,
-			len: 0,
-		};
+				Chunk::Hole(len) => {
+					self.send_skip(len)?;
+					last_sent = Instant::now();
+					bytes_sent += len;
+					continue 'copy;
+				},
 
-		let goodbye_buf = bincode::serialize(&goodbye_msg)?;
-		self.stream.write(&goodbye_buf)?;
+				Chunk::Eof => {
+					debug!("buffer reached eof");
+					break 'copy;
+				},
 
-		Ok(())
-	}
+				Chunk::Idle => {
+					if self.idle_interval().is_some_and(|interval| last_sent.elapsed() >= interval) {
+						self.send_idle()?;
+						last_sent = Instant::now();
+					}
 
-	fn recv_hello(&mut self) -> Result<(), ProtoError> {
-		info!("receiving hello ...");
+					continue 'copy;
+				},
+			};
 
-		let mut buf = vec![0u8; MESSAGE_SIZE];
-		self.stream.read_exact(&mut buf)?;
-		let hello_msg: Message= bincode::deserialize(&buf)?;
+			// blocks which have already been read are sealed together, so that
+			// they can be shared out between the crypto workers.
+			let mut chunks = vec![chunk];
+			let batch_len = self.workers.as_ref()
+				.filter(|_| !self.low_latency)
+				.map(Workers::batch_len);
 
-		if hello_msg.ty != MessageTy::Hello {
-			return Err(ProtoError::UnexpectedMessage);
-		}
+			if let Some(batch_len) = batch_len {
+				let mut batch_bytes = chunks[0].len() as u64;
+				while chunks.len() < batch_len && !self.rekey_due(batch_bytes) {
+					match reader.next_ready() {
+						Some(chunk) => {
+							batch_bytes += chunk.len() as u64;
+							chunks.push(chunk);
+						},
 
-		let mut buf = vec![0u8; hello_msg.len];
-		let msg_nonce = util::get_next_nonce(&mut self.nonce, &mut self.counter)?;
-		self.stream.read_exact(&mut buf)?;
-		let payload = aead::open_in_place(&self.dec_key, &msg_nonce, b"", 0, &mut buf)?;
+						None => break,
+					}
+				}
+			}
 
-		info!("decrypted hello of size: {}", payload.len());
-		info!("hello was: {:?}", &payload);
+			for buf in self.send_chunks(chunks, &mut bytes_sent)? {
+				reader.recycle(buf);
+			}
 
-		Ok(())
+			last_sent = Instant::now();
+		}
+
+		Ok(bytes_sent)
 	}
 
-	fn recv_server_goodbye(&mut self) -> Result<(), ProtoError> {
-		info!("receiving goodbye ...");
+	/// Appends the chunks `reader` reads next to `chunk`, until it is as long
+	/// as the minimum block size or the sender has waited long enough.
+	fn coalesce(&self, reader: &mut ChunkReader, mut chunk: Vec<u8>) -> Vec<u8> {
+		let deadline = Instant::now() + self.max_wait;
 
-		let mut buf = vec![0u8; MESSAGE_SIZE];
-		self.stream.read_exact(&mut buf)?;
-		let goodbye_msg: Message = bincode::deserialize(&buf)?;
+		while chunk.len() < self.min_block {
+			let timeout = deadline.saturating_duration_since(Instant::now());
+			if timeout == Duration::from_secs(0) {
+				break;
+			}
 
-		if goodbye_msg.ty != MessageTy::Goodbye {
-			return Err(ProtoError::UnexpectedMessage);
+			let next = match reader.next_data(timeout) {
+				Some(next) => next,
+				None => break,
+			};
+
+			// a chunk which would overflow the block starts the next one
+			if chunk.len() + next.len() > BLOCK_SIZE {
+				reader.unread(next);
+				break;
+			}
+
+			chunk.extend_from_slice(&next);
+			reader.recycle(next);
 		}
 
-		info!("goodbye world ...");
-		Ok(())
+		chunk
+	}
+
+	/// Returns how long the input may be idle before the receiver is sent a
+	/// cover block, if the sender pads its traffic, or a keepalive.
+	pub(super) fn idle_interval(&self) -> Option<Duration> {
+		if self.padding {
+			Some(COVER_INTERVAL)
+		} else {
+			Some(self.keepalive).filter(|&keepalive| keepalive > Duration::from_secs(0))
+		}
+	}
+
+	/// Sends what keeps the session alive while the input is idle. (See:
+	/// `Sender::idle_interval()`.)
+	pub(super) fn send_idle(&mut self) -> Result<(), ProtoError> {
+		match self.padding {
+			true => self.send_cover(),
+			false => self.send_ping(),
+		}
+	}
+
+	/// Returns why the transfer must be aborted, if it has been interrupted
+	/// or cancelled.
+	#[cfg(feature = "async")]
+	pub(super) fn stopped(&self) -> Option<ProtoError> {
+		if self.interrupt.load(Ordering::SeqCst) {
+			Some(ProtoError::Interrupted)
+		} else if self.cancel.is_cancelled() {
+			Some(ProtoError::Cancelled)
+		} else {
+			None
+		}
+	}
+
+	/// Fails if the transfer has been interrupted or cancelled, aborting it.
+	/// Otherwise this takes care of whatever is due between blocks.
+	pub(super) fn check_stopped(&mut self) -> Result<(), ProtoError> {
+		if self.interrupt.load(Ordering::SeqCst) {
+			warn!("interrupted, aborting transfer ...");
+			self.abort()?;
+			return Err(ProtoError::Interrupted);
+		}
+
+		if self.cancel.is_cancelled() {
+			warn!("cancelled, aborting transfer ...");
+			if let Err(err) = self.abort() {
+				debug!("could not deliver abort: {}", err);
+			}
+
+			return Err(ProtoError::Cancelled);
+		}
+
+		self.between_blocks()
+	}
+
+	/// Takes care of whatever is due between blocks, handling anything the
+	/// receiver has sent so far.
+	pub(super) fn between_blocks(&mut self) -> Result<(), ProtoError> {
+		self.sample_stats();
+		self.poll_receiver()
+	}
+
+	/// Seals & sends `chunks` as consecutive blocks, adding their length to
+	/// `bytes_sent` as each one goes out. The sealed buffers are returned so
+	/// that they may be reused.
+	pub(super) fn send_chunks(&mut self, chunks: Vec<Plain>, bytes_sent: &mut u64) -> Result<Vec<Vec<u8>>, ProtoError> {
+		for chunk in &chunks {
+			self.digest.update(chunk.as_slice());
+		}
+
+		let lens: Vec<usize> = chunks.iter().map(Plain::len).collect();
+		let mut spent = Vec::with_capacity(lens.len());
+
+		for (block, bytes_read) in self.seal_blocks(chunks)?.into_iter().zip(lens) {
+			let enc_size = block.buf.len();
+
+			self.write_sealed(block.seq, block.header, &block.buf)?;
+			trace!("sent: {}, len: {}", enc_size, bytes_read);
+			spent.push(block.buf);
+			*bytes_sent += bytes_read as u64;
+
+			self.summary.plaintext_bytes += bytes_read as u64;
+			self.summary.ciphertext_bytes += enc_size as u64;
+			self.summary.blocks += 1;
+			self.summary.elapsed = self.started.elapsed();
+
+			self.emit(Event::Block {
+				plaintext_len: bytes_read,
+				ciphertext_len: enc_size,
+				total_bytes: self.summary.plaintext_bytes,
+			});
+
+			self.rekey_bytes += bytes_read as u64;
+			if self.rekey_due(0) {
+				self.send_rekey()?;
+			}
+		}
+
+		Ok(spent)
+	}
+
+	/// Returns true if the session keys are due to be rotated once another
+	/// `pending` bytes have been sent.
+	fn rekey_due(&self, pending: u64) -> bool {
+		self.rekey_interval > 0 && self.rekey_bytes + pending >= self.rekey_interval
+	}
+
+	/// Numbers `chunks` as consecutive blocks and seals them, in parallel if
+	/// there are crypto workers. Each is padded first if the sender pads its
+	/// traffic.
+	fn seal_blocks(&mut self, chunks: Vec<Plain>) -> Result<Vec<Block>, ProtoError> {
+		let tag_len = self.enc_key.algorithm().tag_len();
+		let mut blocks = Vec::with_capacity(chunks.len());
+
+		for chunk in chunks {
+			assert!(chunk.len() <= BLOCK_SIZE);
+
+			// a padded block is padded in place, so it cannot wait to be
+			// copied out of the mapping until it is sealed.
+			let (mut buf, mapped) = match chunk {
+				Plain::Mapped(region) if !self.padding => (vec![], Some(region)),
+				Plain::Mapped(region) => (region.into_buf(), None),
+				Plain::Read(buf) => (buf, None),
+			};
+
+			let ty = if self.padding {
+				padding::pad(&mut buf);
+				MessageTy::Padded
+			} else {
+				MessageTy::Block
+			};
+
+			// create encrypted packet header, the serialized header is bound
+			// to the payload as associated data so it cannot be tampered with.
+			let plaintext_len = mapped.as_ref().map_or(buf.len(), |region| region.as_slice().len());
+			let block_msg = Message {
+				ty,
+				len: plaintext_len + tag_len,
+				seq: self.counter + 1,
+			};
+
+			trace!("sealing block message: {:?}", block_msg);
+			let nonce = util::get_next_nonce(&mut self.nonce, &mut self.counter)?;
+			if mapped.is_none() {
+				buf.resize(block_msg.len, 0);
+			}
+
+			blocks.push(Block { seq: self.counter, nonce, header: block_msg.encode(), buf, prefix: 0, mapped });
+		}
+
+		match self.workers {
+			Some(ref workers) => workers.seal(&self.enc_key, blocks),
+			None => {
+				for block in &mut blocks {
+					block.seal(&self.enc_key)?;
+				}
+
+				Ok(blocks)
+			},
+		}
+	}
+
+	/// Sends a padded block w/o any data, which the receiver discards.
+	fn send_cover(&mut self) -> Result<(), ProtoError> {
+		trace!("input is idle, sending cover block ...");
+		for block in self.seal_blocks(vec![Plain::Read(vec![])])? {
+			self.write_sealed(block.seq, block.header, &block.buf)?;
+		}
+
+		Ok(())
+	}
+
+	fn send_skip(&mut self, len: u64) -> Result<(), ProtoError> {
+		trace!("skipping hole of {} bytes", len);
+		let mut payload = vec![];
+		payload.write_u64::<NetworkEndian>(len)?;
+		self.send_sealed(MessageTy::Skip, &payload)?;
+
+		self.digest.update_zeros(len);
+		self.summary.plaintext_bytes += len;
+		self.summary.elapsed = self.started.elapsed();
+		self.emit(Event::Skip { len, total_bytes: self.summary.plaintext_bytes });
+
+		Ok(())
+	}
+
+	/// Takes the next step of the handshake. (See: `Shake`.)
+	pub(super) fn shake(&mut self) -> Result<(), ProtoError> {
+		self.shake = match mem::replace(&mut self.shake, Shake::Done) {
+			Shake::Start => {
+				banner::send(&mut *self.stream)?;
+				Shake::Banner
+			},
+
+			Shake::Banner => {
+				banner::recv(&mut *self.stream)?;
+				match self.code.take() {
+					Some(code) => Shake::Pake(self.send_pake(&code)?),
+					None => Shake::RepIV { challenged: self.req_iv()? },
+				}
+			},
+
+			Shake::Pake(pake) => {
+				self.recv_pake(pake)?;
+				Shake::RepIV { challenged: self.req_iv()? }
+			},
+
+			Shake::RepIV { challenged } => {
+				self.recv_rep_iv(challenged)?;
+				let sent = Instant::now();
+				self.send_hello()?;
+				Shake::Hello { challenged, sent }
+			},
+
+			Shake::Hello { challenged, sent } => {
+				self.recv_hello()?;
+				self.rtt = sent.elapsed();
+				self.use_features();
+
+				if challenged {
+					Shake::Identity
+				} else {
+					self.complete_hello()?;
+					Shake::Done
+				}
+			},
+
+			Shake::Identity => {
+				self.recv_identity()?;
+				self.send_identity()?;
+				self.use_mixed_key()?;
+				self.complete_hello()?;
+				Shake::Done
+			},
+
+			Shake::Done => Shake::Done,
+		};
+
+		Ok(())
+	}
+
+	/// Starts the transfer, once the handshake is complete.
+	fn complete_hello(&mut self) -> Result<(), ProtoError> {
+		info!("handshake complete!");
+		self.state = State::Transmit;
+
+		// the receiver only acknowledges what follows the handshake
+		self.acked = self.counter;
+		self.started = Instant::now();
+		self.stats.restart();
+		let session_id = self.summary.session_id.map(|id| id.to_string()).unwrap_or_default();
+		let fingerprint = fingerprint::session_fingerprint(&self.key, &self.transcript);
+		info!("the session's fingerprint is {}", fingerprint);
+		self.emit(Event::HandshakeComplete { session_id, fingerprint });
+
+		// an observer which did not like the fingerprint cancels before any
+		// data flows, and that includes the file's name
+		if self.cancel.is_cancelled() {
+			return Ok(());
+		}
+
+		if let Some(mut metadata) = self.metadata.take() {
+			self.announce_size(&mut metadata);
+			self.send_metadata(&metadata)?;
+		}
+
+		if let Some(bytes) = self.summary.expected_bytes {
+			let mut payload = vec![];
+			payload.write_u64::<NetworkEndian>(bytes)?;
+			self.send_sealed(MessageTy::TotalSize, &payload)?;
+			self.emit(Event::TotalSize { bytes });
+		}
+
+		Ok(())
+	}
+
+	/// Starts our half of the SPAKE2 exchange, which is finished once the
+	/// receiver answers. (See: `Sender::recv_pake()`.)
+	fn send_pake(&mut self, code: &str) -> Result<Pake, ProtoError> {
+		info!("agreeing on a key from the code ...");
+		let pake = Pake::start(Mode::Sender, code);
+		let pake_msg = Message {
+			ty: MessageTy::Pake,
+			len: ELEMENT_LEN,
+			seq: 0,
+		};
+
+		let pake_buf = pake_msg.encode();
+		self.stream.write_all(&pake_buf)?;
+		self.stream.write_all(pake.element())?;
+		self.transcript.update(&pake_buf);
+		self.transcript.update(pake.element());
+		Ok(pake)
+	}
+
+	/// Finishes the SPAKE2 exchange w/ the receiver's half, then switches to
+	/// the key agreed w/ it.
+	fn recv_pake(&mut self, pake: Pake) -> Result<(), ProtoError> {
+		let mut buf = [0u8; MESSAGE_SIZE];
+		self.stream.read_exact(&mut buf)?;
+		let reply_msg = Message::decode(&buf)?;
+
+		if reply_msg.ty != MessageTy::Pake {
+			return Err(ProtoError::UnexpectedMessage);
+		}
+
+		if reply_msg.len != ELEMENT_LEN {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		let mut element = [0u8; ELEMENT_LEN];
+		self.stream.read_exact(&mut element)?;
+		self.transcript.update(&buf);
+		self.transcript.update(&element);
+
+		self.key = pake.finish(&element, self.key.len())?;
+		self.use_cipher(self.cipher)
+	}
+
+	/// Asks the receiver for the IV, returning true if we sent a challenge so
+	/// that the peers exchange identities.
+	fn req_iv(&mut self) -> Result<bool, ProtoError> {
+		// ask the server for the IV
+		info!("sending IV request to remote peer ...");
+		let preference = cipher::preference(self.forced_cipher, &self.key);
+		let mut buf = vec![
+			preference.map_or(CIPHER_AUTO, |cipher| cipher as u8),
+			Cipher::aes_accelerated() as u8,
+		];
+
+		buf.extend_from_slice(&key::id(&self.key));
+		let challenged = self.identity.is_some() || self.identity_check.is_some() || self.require_receipt;
+		if challenged {
+			let ephemeral = Ephemeral::generate(&mut self.rng);
+			buf.extend_from_slice(ephemeral.share());
+			self.ephemeral = Some(ephemeral);
+		}
+
+		let req_iv_msg = Message {
+			ty: MessageTy::ReqIV,
+			len: buf.len(),
+			seq: 0,
+		};
+
+		let req_iv_buf = req_iv_msg.encode();
+		self.stream.write_all(&req_iv_buf)?;
+		self.stream.write_all(&buf)?;
+
+		self.transcript.update(&req_iv_buf);
+		self.transcript.update(&buf);
+		Ok(challenged)
+	}
+
+	/// Reads the receiver's IV & cipher, along w/ its challenge if we sent
+	/// one of our own.
+	fn recv_rep_iv(&mut self, challenged: bool) -> Result<(), ProtoError> {
+		// read the IV from the server
+		info!("waiting for reply from server ...");
+		let mut buf = vec![0u8; MESSAGE_SIZE];
+		self.stream.read_exact(&mut buf)?;
+		let rep_iv_msg = Message::decode(&buf)?;
+
+		info!("got reply: {:?}", rep_iv_msg);
+		if rep_iv_msg.ty != MessageTy::RepIV {
+			return Err(ProtoError::UnexpectedMessage);
+		}
+
+		let challenge_len = if challenged { CHALLENGE_LEN } else { 0 };
+		if rep_iv_msg.len != mem::size_of::<u32>() + mem::size_of::<u8>() + challenge_len {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		self.transcript.update(&buf);
+		let mut buf = vec![0u8; rep_iv_msg.len];
+		self.stream.read_exact(&mut buf)?;
+		self.transcript.update(&buf);
+
+		let mut iv_cursor = Cursor::new(&buf[..]);
+		self.nonce = iv_cursor.read_u32::<NetworkEndian>()?;
+		info!("got iv: {:x}", self.nonce);
+
+		// the receiver's choice must be the one we asked for, if we asked
+		let id = iv_cursor.read_u8()?;
+		let chosen = Cipher::from_u8(id).ok_or(ProtoError::UnknownCipher { id })?;
+		let preference = cipher::preference(self.forced_cipher, &self.key);
+		self.use_cipher(cipher::choose(preference, Some(chosen), false, &self.key)?)?;
+
+		if let Some(ephemeral) = self.ephemeral.take() {
+			self.mixed_key = Some(ephemeral.mix(&self.key, &buf[buf.len() - CHALLENGE_LEN..])?);
+		}
+
+		Ok(())
+	}
+
+	/// Checks that the receiver signed the handshake, and that its identity
+	/// passes our identity check if we have one.
+	fn recv_identity(&mut self) -> Result<(), ProtoError> {
+		info!("waiting for the receiver's identity ...");
+		let mut buf = [0u8; MESSAGE_SIZE];
+		self.stream.read_exact(&mut buf)?;
+		let identity_msg = Message::decode(&buf)?;
+
+		if identity_msg.ty != MessageTy::Identity {
+			return Err(ProtoError::UnexpectedMessage);
+		}
+
+		let fingerprint = match identity_msg.len {
+			0 => None,
+			len => {
+				let mut identity = vec![0u8; len];
+				self.stream.read_exact(&mut identity)?;
+				Some(self.transcript.verify(Mode::Receiver, &identity)?)
+			},
+		};
+
+		if let Some(check) = self.identity_check.as_mut() {
+			check(fingerprint.as_deref())?;
+		}
+
+		match fingerprint {
+			Some(ref fingerprint) => info!("the receiver's identity is {}", fingerprint),
+			None => info!("the receiver has no identity"),
+		}
+
+		self.peer_fingerprint = fingerprint;
+		Ok(())
+	}
+
+	/// Signs the handshake w/ our identity, or sends an empty `Identity` if
+	/// we have none.
+	fn send_identity(&mut self) -> Result<(), ProtoError> {
+		let buf = match self.identity {
+			Some(ref identity) => {
+				info!("proving our identity ({}) to the receiver ...", identity.fingerprint());
+				identity.sign(Mode::Sender, &self.transcript)?
+			},
+
+			None => vec![],
+		};
+
+		let identity_msg = Message {
+			ty: MessageTy::Identity,
+			len: buf.len(),
+			seq: 0,
+		};
+
+		self.stream.write_all(&identity_msg.encode())?;
+		self.stream.write_all(&buf)?;
+		Ok(())
+	}
+
+	fn send_hello(&mut self) -> Result<(), ProtoError> {
+		let session_id = SessionId::generate_from(&mut self.rng);
+		SessionId::set_current(Some(session_id));
+		self.summary.session_id = Some(session_id);
+		info!("sending hello for session {} ...", session_id);
+
+		self.use_reply_key(session_id)?;
+
+		// write the magic bytes, the session's id & our features to a buffer
+		let tag_len = self.enc_key.algorithm().tag_len();
+		let enc_buf = vec![0u8; HELLO_LEN + FEATURES_LEN + tag_len];
+		let mut enc_buf = {
+			let mut cursor = Cursor::new(enc_buf);
+			cursor.write_u32::<NetworkEndian>(MAGIC_BYTES)?;
+			cursor.write_all(session_id.as_bytes())?;
+			cursor.write_u32::<NetworkEndian>(self.advertised.bits())?;
+			cursor.into_inner()
+		};
+
+		// the `Hello` header is authenticated along with the payload
+		let hello_msg = Message {
+			ty: MessageTy::Hello,
+			len: enc_buf.len(),
+			seq: self.counter + 1,
+		};
+
+		let hello_buf = hello_msg.encode();
+
+		// encrypt the buffer in-place
+		let msg_nonce = util::get_next_nonce(&mut self.nonce, &mut self.counter)?;
+		let msg_sz = aead::seal_in_place(&self.enc_key, &msg_nonce, &hello_buf, &mut enc_buf, tag_len)?;
+
+		// send `Hello` followed by the encrypted payload
+
+		self.stream.write_all(&hello_buf)?;
+		self.stream.write_all(&enc_buf[..msg_sz])?;
+
+		self.transcript.update(&hello_buf);
+		self.transcript.update(&enc_buf[..msg_sz]);
+		Ok(())
+	}
+	
+	/// Tells the receiver how much was sent & its digest, so that it can tell
+	/// whether the transfer was cut short or tampered with.
+	fn send_client_goodbye(&mut self) -> Result<(), ProtoError> {
+		let mut payload = vec![];
+		payload.write_u64::<NetworkEndian>(self.summary.plaintext_bytes)?;
+		payload.write_u64::<NetworkEndian>(self.summary.blocks)?;
+		payload.extend_from_slice(&self.digest.finish());
+		self.send_sealed(MessageTy::Goodbye, &payload)
+	}
+
+	fn send_rekey(&mut self) -> Result<(), ProtoError> {
+		info!("rotating session keys ...");
+
+		// pick a new salt, it is sealed under the current key
+		let mut salt = [0u8; REKEY_SALT_LEN];
+		self.rng.fill(&mut salt[..]);
+		self.send_sealed(MessageTy::ReKey, &salt)?;
+
+		self.rekey_bytes = 0;
+		self.apply_rekey(&salt)
+	}
+
+	fn send_metadata(&mut self, metadata: &FileMeta) -> Result<(), ProtoError> {
+		info!("sending metadata: {:?}", metadata);
+		let payload = bincode::serialize(metadata)?;
+		self.send_sealed(MessageTy::Metadata, &payload)
+	}
+
+	/// Sends a message of type `ty` followed by `payload`, which is sealed
+	/// under the current key w/ the header as associated data.
+	fn send_sealed(&mut self, ty: MessageTy, payload: &[u8]) -> Result<(), ProtoError> {
+		let tag_len = self.enc_key.algorithm().tag_len();
+		let mut enc_buf = vec![0u8; payload.len() + tag_len];
+		enc_buf[..payload.len()].copy_from_slice(payload);
+
+		let msg = Message {
+			ty,
+			len: enc_buf.len(),
+			seq: self.counter + 1,
+		};
+
+		let msg_buf = msg.encode();
+
+		let msg_nonce = util::get_next_nonce(&mut self.nonce, &mut self.counter)?;
+		let msg_sz = aead::seal_in_place(&self.enc_key, &msg_nonce, &msg_buf, &mut enc_buf, tag_len)?;
+
+		self.write_sealed(msg.seq, msg_buf, &enc_buf[..msg_sz])
+	}
+
+	/// Sends a sealed message, keeping a copy of it in the retransmit window.
+	fn write_sealed(&mut self, seq: u64, header: [u8; MESSAGE_SIZE], payload: &[u8]) -> Result<(), ProtoError> {
+		self.wait_for_acks(seq)?;
+
+		if let Some(rate_limit) = self.rate_limit.as_mut() {
+			rate_limit.pace((header.len() + payload.len()) as u64);
+		}
+
+		// the copy is kept first, so that it is resent if the write fails. it
+		// is written in one piece, so that a message socket sends it as is.
+		let kept = self.keep_sealed(seq, header, payload);
+		let written = match self.sent.back() {
+			Some(sealed) if kept => self.stream.write_all(&sealed.message),
+			_ => self.stream.write_all(&header)
+				.and_then(|_| self.stream.write_all(payload)),
+		};
+
+		written.or_else(|err| self.resume_or(err.into()))
+	}
+
+	/// Returns false if there is no retransmit window to keep the message in.
+	fn keep_sealed(&mut self, seq: u64, header: [u8; MESSAGE_SIZE], payload: &[u8]) -> bool {
+		if self.window == 0 {
+			return false;
+		}
+
+		// the oldest message's buffer is reused once the window is full
+		let mut buf = if self.sent.len() >= self.window {
+			self.sent.pop_front().map(|sealed| sealed.message).unwrap_or_default()
+		} else {
+			Vec::with_capacity(header.len() + payload.len())
+		};
+
+		buf.clear();
+		buf.extend_from_slice(&header);
+		buf.extend_from_slice(payload);
+
+		self.sent.push_back(Sealed { seq, message: buf });
+		true
+	}
+
+	/// Handles anything the receiver sent during the transfer, without waiting
+	/// for it if there is nothing to read.
+	fn poll_receiver(&mut self) -> Result<(), ProtoError> {
+		self.read_replies().or_else(|err| self.resume_or(err))
+	}
+
+	fn read_replies(&mut self) -> Result<(), ProtoError> {
+		while self.stream.has_pending() {
+			self.read_reply()?;
+		}
+
+		Ok(())
+	}
+
+	/// Waits for the next thing the receiver sends during the transfer, and
+	/// handles it.
+	fn read_reply(&mut self) -> Result<(), ProtoError> {
+		let mut buf = [0u8; MESSAGE_SIZE];
+		self.stream.read_exact(&mut buf)?;
+		let msg = Message::decode(&buf)?.or_fault(&mut *self.stream)?;
+
+		match msg.ty {
+			MessageTy::Pong => trace!("skipping keepalive reply"),
+			MessageTy::Nack => self.recv_nack(&msg)?,
+			MessageTy::Ack => self.recv_ack(&msg)?,
+			MessageTy::Token => self.recv_token(&msg)?,
+			MessageTy::Abort => return Err(ProtoError::PeerAborted),
+			_ => return Err(ProtoError::UnexpectedMessage),
+		}
+
+		Ok(())
+	}
+
+	/// Waits on the receiver while too many messages are unacknowledged for
+	/// the message `seq` to be sent. (See: `Sender::set_max_unacked()`.) Only
+	/// the messages sent before it count, since a batch of blocks is numbered
+	/// before any of it is sent.
+	fn wait_for_acks(&mut self, seq: u64) -> Result<(), ProtoError> {
+		let limit = match self.unacked_limit() {
+			Some(limit) => limit,
+			None => return Ok(()),
+		};
+
+		while seq - 1 - self.acked >= limit {
+			trace!("{} messages are unacknowledged, waiting on the receiver ...", seq - 1 - self.acked);
+			self.read_reply().or_else(|err| self.resume_or(err))?;
+		}
+
+		Ok(())
+	}
+
+	/// Returns true if the next `messages` cannot all be sent until the
+	/// receiver has acknowledged more of those sent before them.
+	#[cfg(feature = "async")]
+	pub(super) fn awaits_acks(&self, messages: u64) -> bool {
+		self.unacked_limit().is_some_and(|limit| self.counter + messages - 1 - self.acked >= limit)
+	}
+
+	/// Returns how many messages may be unacknowledged at once, if the
+	/// receiver acknowledges them at all.
+	fn unacked_limit(&self) -> Option<u64> {
+		if !self.features.contains(Features::ACKS) {
+			return None;
+		}
+
+		let mut limit = self.max_unacked;
+		if self.window > 0 {
+			limit = limit.min(self.window);
+		}
+
+		Some((limit as u64).max(ACK_INTERVAL))
+	}
+
+	/// Notes how far the receiver has got. Acks may be overtaken by a resume,
+	/// so an old one is ignored.
+	fn recv_ack(&mut self, ack_msg: &Message) -> Result<(), ProtoError> {
+		let payload = self.open_reply(ack_msg, ACK_LEN)?;
+		let seq = NetworkEndian::read_u64(&payload[0..8]);
+		let bytes = NetworkEndian::read_u64(&payload[8..16]);
+
+		// the receiver cannot have handled a message which was never sent
+		if seq != ack_msg.seq + 1 || seq > self.counter + 1 {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		if seq - 1 > self.acked {
+			self.acked = seq - 1;
+			self.acked_bytes = bytes;
+			self.emit(Event::Ack { seq, total_bytes: bytes });
+		}
+
+		Ok(())
+	}
+
+	/// Looks through what the receiver sent before it hung up for the
+	/// `MessageTy::Error` which says why, skipping over anything else.
+	fn recv_fault(&mut self) -> Option<ProtoError> {
+		let mut buf = [0u8; MESSAGE_SIZE];
+		while self.stream.has_pending() {
+			self.stream.read_exact(&mut buf).ok()?;
+			match Message::decode(&buf).ok()?.or_fault(&mut *self.stream) {
+				Ok(msg) => io::copy(&mut (&mut *self.stream).take(msg.len as u64), &mut io::sink()).ok()?,
+				Err(fault) => return Some(fault),
+			};
+		}
+
+		None
+	}
+
+	/// Reads the sequence number carried by a `MessageTy::Nack`.
+	fn read_nack(&mut self, nack_msg: &Message) -> Result<u64, ProtoError> {
+		let payload = self.open_reply(nack_msg, mem::size_of::<u64>())?;
+		let seq = NetworkEndian::read_u64(&payload);
+
+		if seq != nack_msg.seq {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		Ok(seq)
+	}
+
+	/// Opens a reply from the receiver, which is sealed under the reply key w/
+	/// the header as associated data, returning its `len` byte payload.
+	fn open_reply(&mut self, reply_msg: &Message, len: usize) -> Result<Vec<u8>, ProtoError> {
+		let reply_key = self.reply_key.as_ref().ok_or(ProtoError::UnexpectedMessage)?;
+		if reply_msg.len != len + reply_key.algorithm().tag_len() {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		let mut buf = vec![0u8; reply_msg.len];
+		self.stream.read_exact(&mut buf)?;
+
+		let msg_nonce = util::reply_nonce(reply_msg.ty as u8, reply_msg.seq);
+		let payload = aead::open_in_place(reply_key, &msg_nonce, &reply_msg.encode(), 0, &mut buf)?;
+		Ok(payload.to_vec())
+	}
+
+	/// Resends the message the receiver could not open, and every message
+	/// which was sent after it (which the receiver has discarded.) A nack of
+	/// a message the receiver already acknowledged can only be a replay of
+	/// an old one, so it is ignored.
+	fn recv_nack(&mut self, nack_msg: &Message) -> Result<(), ProtoError> {
+		let seq = self.read_nack(nack_msg)?;
+		if seq <= self.acked {
+			debug!("ignoring a nack of message #{}, which was acknowledged", seq);
+			return Ok(());
+		}
+
+		self.emit(Event::Nack { seq });
+
+		warn!("receiver could not open message #{}, resending it ...", seq);
+		self.resend_from(seq)
+	}
+
+	/// Resends the message `seq` and every message which was sent after it,
+	/// aborting if it has already left the retransmit window.
+	fn resend_from(&mut self, seq: u64) -> Result<(), ProtoError> {
+		let start = match self.sent.iter().position(|sealed| sealed.seq == seq) {
+			Some(start) => start,
+			None => {
+				warn!("message #{} is too old to resend", seq);
+				if let Err(err) = self.abort() {
+					debug!("could not deliver abort: {}", err);
+				}
+
+				return Err(ProtoError::BlockLost { seq });
+			},
+		};
+
+		debug!("resending {} messages from #{} ...", self.sent.len() - start, seq);
+		for sealed in self.sent.iter().skip(start) {
+			self.stream.write_all(&sealed.message)?;
+		}
+
+		Ok(())
+	}
+
+	/// Keeps the token the receiver issued, so that the session can be resumed.
+	/// It is sealed like an `Ack`.
+	fn recv_token(&mut self, token_msg: &Message) -> Result<(), ProtoError> {
+		let token = self.open_reply(token_msg, TOKEN_LEN)?;
+
+		debug!("receiver issued a resumption token");
+		self.token = Some(token);
+		Ok(())
+	}
+
+	/// Resumes the session if `err` means the connection dropped during the
+	/// transfer or while hanging up, and the receiver issued a token.
+	/// Otherwise `err` is returned.
+	fn resume_or(&mut self, err: ProtoError) -> Result<(), ProtoError> {
+		let resumable = err.is_hangup()
+			&& matches!(self.state, State::Transmit | State::WaitHangup)
+			&& self.token.is_some()
+			&& self.reconnect.is_some()
+			&& self.resume_timeout > Duration::from_secs(0);
+
+		if !resumable {
+			return Err(err);
+		}
+
+		warn!("lost the connection to the receiver ({}), resuming the session ...", err);
+		let seq = match self.resume() {
+			Ok(seq) => seq,
+			Err(resume_err) => {
+				warn!("could not resume the session: {}", resume_err);
+				return Err(err);
+			},
+		};
+
+		info!("resumed the session from message #{}", seq);
+		self.emit(Event::Resumed { seq });
+		self.acked = self.acked.max(seq - 1);
+
+		// everything was received if the receiver expects the next message
+		if seq <= self.counter {
+			self.resend_from(seq)?;
+		}
+
+		Ok(())
+	}
+
+	/// Reconnects to the receiver & presents the token, until the receiver
+	/// accepts it or the resume timeout expires. Returns the sequence number of
+	/// the message the receiver expects next.
+	fn resume(&mut self) -> Result<u64, ProtoError> {
+		let deadline = Instant::now() + self.resume_timeout;
+
+		loop {
+			let _ = self.stream.close();
+
+			match self.present_token(deadline) {
+				Ok(seq) => return Ok(seq),
+				Err(err) if Instant::now() + RESUME_RETRY < deadline => {
+					debug!("could not resume the session yet: {}", err);
+					thread::sleep(RESUME_RETRY);
+				},
+
+				Err(err) => return Err(err),
+			}
+		}
+	}
+
+	fn present_token(&mut self, deadline: Instant) -> Result<u64, ProtoError> {
+		let reconnect = self.reconnect.as_mut().expect("resuming requires a way to reconnect");
+		self.stream = reconnect(deadline.saturating_duration_since(Instant::now()))?;
+
+		let token = self.token.as_ref().expect("resuming requires a token");
+		let resume_key = self.resume_key.as_ref().expect("tokens follow the sender's hello");
+		let seq = resume::present(&mut *self.stream, token, resume_key)?;
+
+		// the receiver cannot expect a message which was never sent
+		if seq == 0 || seq > self.counter + 1 {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		Ok(seq)
+	}
+
+	fn abort(&mut self) -> Result<(), ProtoError> {
+		self.send_abort()?;
+		self.recv_server_goodbye()
+	}
+
+	/// Tells the receiver the transfer is being abandoned, which it answers w/
+	/// its goodbye. (See: `Sender::recv_goodbye_reply()`.)
+	pub(super) fn send_abort(&mut self) -> Result<(), ProtoError> {
+		let abort_msg = Message {
+			ty: MessageTy::Abort,
+			len: 0,
+			seq: 0,
+		};
+
+		let abort_buf = abort_msg.encode();
+		self.stream.write_all(&abort_buf)?;
+		self.aborted = true;
+
+		Ok(())
+	}
+
+	fn send_ping(&mut self) -> Result<(), ProtoError> {
+		debug!("input is idle, sending keepalive ...");
+		let ping_msg = Message {
+			ty: MessageTy::Ping,
+			len: 0,
+			seq: 0,
+		};
+
+		let ping_buf = ping_msg.encode();
+		self.stream.write_all(&ping_buf)
+			.or_else(|err| self.resume_or(err.into()))
+	}
+
+	fn recv_hello(&mut self) -> Result<(), ProtoError> {
+		info!("receiving hello ...");
+
+		// a receiver which cannot open our `Hello` says so, or (if it predates
+		// that) closes the connection before it replies. anything else is the
+		// connection failing, which says nothing about the keys.
+		let mut hello_buf = vec![0u8; MESSAGE_SIZE];
+		match self.stream.read(&mut hello_buf[..1])? {
+			0 => return Err(ProtoError::HandshakeRejected),
+			_ => self.stream.read_exact(&mut hello_buf[1..])?,
+		}
+
+		let hello_msg = Message::decode(&hello_buf)?
+			.or_fault(&mut *self.stream)
+			.map_err(|err| match err {
+				ProtoError::PeerFailed { code: FaultCode::Crypto, .. } => ProtoError::HandshakeRejected,
+				err => err,
+			})?;
+
+		if hello_msg.ty != MessageTy::Hello {
+			return Err(ProtoError::UnexpectedMessage);
+		}
+
+		// a receiver which predates `Features` does not send any
+		let hello_len = hello_msg.len.checked_sub(self.dec_key.algorithm().tag_len());
+		if hello_len != Some(HELLO_LEN) && hello_len != Some(HELLO_LEN + FEATURES_LEN) {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		util::check_seq(self.counter, hello_msg.seq)?;
+		let mut buf = vec![0u8; hello_msg.len];
+		let msg_nonce = util::get_next_nonce(&mut self.nonce, &mut self.counter)?;
+		self.stream.read_exact(&mut buf)?;
+
+		self.transcript.update(&hello_buf);
+		self.transcript.update(&buf);
+		let payload = aead::open_in_place(&self.dec_key, &msg_nonce, &hello_buf, 0, &mut buf)?;
+
+		info!("decrypted hello of size: {}", payload.len());
+		info!("hello was: {:?}", &payload);
+
+		// the receiver echoes the id of the session we started
+		let (session_id, features) = payload[mem::size_of_val(&MAGIC_BYTES)..].split_at(SESSION_ID_LEN);
+		if SessionId::from_bytes(session_id) != self.summary.session_id {
+			return Err(ProtoError::MalformedMessage);
+		}
+
+		let features = Features::from_hello(features)?;
+		info!("the receiver supports: {}", features);
+		self.features = self.advertised & features;
+		Ok(())
+	}
+
+	/// Gives up on whatever the receiver does not support, except for sending
+	/// several files which cannot be done w/o it.
+	fn use_features(&mut self) {
+		if self.padding && !self.features.contains(Features::PADDING) {
+			warn!("the receiver does not support padding, blocks will be sent as they are");
+			self.padding = false;
+		}
+
+		if self.keepalive > Duration::from_secs(0) && !self.features.contains(Features::HEARTBEATS) {
+			warn!("the receiver does not support heartbeats, none will be sent");
+			self.keepalive = Duration::from_secs(0);
+		}
+	}
+
+	fn recv_server_goodbye(&mut self) -> Result<(), ProtoError> {
+		info!("receiving goodbye ...");
+		while !self.recv_goodbye_reply()? {}
+
+		info!("goodbye world ...");
+		Ok(())
+	}
+
+	/// Handles the next thing the receiver sends while it is waited on to say
+	/// goodbye, returning true once it has.
+	pub(super) fn recv_goodbye_reply(&mut self) -> Result<bool, ProtoError> {
+		// replies to any keepalives sent during the transfer are still queued
+		// ahead of the receiver's goodbye.
+		let mut buf = [0u8; MESSAGE_SIZE];
+		self.stream.read_exact(&mut buf)?;
+		let msg = Message::decode(&buf)?.or_fault(&mut *self.stream)?;
+
+		match msg.ty {
+			MessageTy::Pong => trace!("skipping keepalive reply"),
+			MessageTy::Token => self.recv_token(&msg)?,
+			MessageTy::Ack => self.recv_ack(&msg)?,
+
+			// the receiver discarded our goodbye along w/ the messages
+			// before it, it is resent w/ them.
+			MessageTy::Nack if matches!(self.state, State::WaitHangup) => self.recv_nack(&msg)?,
+
+			MessageTy::Nack => {
+				let seq = self.read_nack(&msg)?;
+				trace!("ignoring nack of message #{} while aborting", seq);
+			},
+
+			MessageTy::Goodbye => {
+				if msg.len > 0 {
+					self.recv_receipt(&msg)?;
+				}
+
+				if self.aborted {
+					self.emit(Event::Abort);
+				}
+
+				return Ok(true);
+			},
+
+			// a cancelled receiver aborts the session in place of a goodbye
+			MessageTy::Abort => return Err(ProtoError::PeerAborted),
+			_ => return Err(ProtoError::UnexpectedMessage),
+		}
+
+		Ok(false)
+	}
+
+	/// Checks the receipt carried by the receiver's goodbye was signed by the
+	/// identity it proved during the handshake, and covers what we sent.
+	fn recv_receipt(&mut self, goodbye_msg: &Message) -> Result<(), ProtoError> {
+		let mut buf = vec![0u8; goodbye_msg.len];
+		self.stream.read_exact(&mut buf)?;
+		let receipt = Receipt::decode(&buf)?;
+
+		match self.peer_fingerprint {
+			Some(ref expected) if key::fingerprints_match(expected, &receipt.fingerprint()) => {},
+			Some(ref expected) => {
+				return Err(ProtoError::IdentityMismatch { expected: expected.clone(), actual: receipt.fingerprint() });
+			},
+
+			None => return Err(ProtoError::UnexpectedMessage),
+		}
+
+		if Some(receipt.session_id) != self.summary.session_id
+			|| receipt.digest != self.digest.finish()
+			|| receipt.bytes != self.summary.plaintext_bytes {
+			return Err(ProtoError::ReceiptMismatch);
+		}
+
+		info!("the receiver signed a receipt for {} bytes", receipt.bytes);
+		self.receipt = Some(receipt);
+		Ok(())
+	}
+
+	/// Switches to the `cipher` agreed upon during the handshake.
+	fn use_cipher(&mut self, cipher: Cipher) -> Result<(), ProtoError> {
+		info!("using {} for this session", cipher);
+		self.cipher = cipher;
+		self.dec_key = OpeningKey::new(cipher.algorithm(), &self.key)?;
+		self.enc_key = Arc::new(SealingKey::new(cipher.algorithm(), &self.key)?);
+		Ok(())
+	}
+
+	/// Derives the keys which open the receiver's replies & prove we hold the
+	/// session key when resuming, once the session is named.
+	fn use_reply_key(&mut self, session_id: SessionId) -> Result<(), ProtoError> {
+		let reply_key = util::derive_reply_key(&self.key, session_id.as_bytes());
+		self.reply_key = Some(OpeningKey::new(self.cipher.algorithm(), &reply_key)?);
+		self.resume_key = Some(util::derive_resume_key(&self.key, session_id.as_bytes()));
+		Ok(())
+	}
+
+	/// Switches to the session key mixed w/ the secret agreed on w/ the
+	/// receiver, once both identities are checked. Everything which follows
+	/// the handshake is sealed under it.
+	fn use_mixed_key(&mut self) -> Result<(), ProtoError> {
+		let key = self.mixed_key.take().expect("identities are exchanged after the challenges");
+		let session_id = self.summary.session_id.expect("identities are exchanged after the hellos");
+
+		self.key = key;
+		self.dec_key = OpeningKey::new(self.cipher.algorithm(), &self.key)?;
+		self.enc_key = Arc::new(SealingKey::new(self.cipher.algorithm(), &self.key)?);
+		self.use_reply_key(session_id)
+	}
+
+	/// Replaces the session keys w/ a sub-key derived from the master key
+	/// and the `salt` carried by a `MessageTy::ReKey` message.
+	fn apply_rekey(&mut self, salt: &[u8]) -> Result<(), ProtoError> {
+		let sub_key = util::derive_key(&self.key, salt);
+		self.dec_key = OpeningKey::new(self.cipher.algorithm(), &sub_key)?;
+		self.enc_key = Arc::new(SealingKey::new(self.cipher.algorithm(), &sub_key)?);
+		self.epoch += 1;
+
+		info!("switched to session key epoch {}", self.epoch);
+		self.emit(Event::ReKey { epoch: self.epoch });
+
+		Ok(())
+	}
+
+	fn sample_stats(&mut self) {
+		if let Some(stats) = self.stats.sample(&self.summary, &*self.stream) {
+			self.emit(stats);
+		}
+	}
+
+	fn emit(&mut self, event: Event) {
+		if let Some(observer) = self.observer.as_mut() {
+			observer(&event);
+		}
 	}
 }
@@ -0,0 +1,77 @@
+use rand::Rng;
+use std::cell::Cell;
+use std::fmt;
+
+/// The length of a `SessionId` on the wire.
+pub const SESSION_ID_LEN: usize = 16;
+
+thread_local! {
+	static CURRENT: Cell<Option<SessionId>> = const { Cell::new(None) };
+}
+
+/// A random (version 4) UUID which names a session on both peers.
+///
+/// The sender picks it when it sends its `Hello`, and the receiver echoes it
+/// in its own, so that the logs & summaries of both ends of a session can be
+/// matched up. It is not a secret, and has nothing to do w/ a resumption
+/// token's session id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionId([u8; SESSION_ID_LEN]);
+
+impl SessionId {
+	/// Picks a new id for a session which is just starting.
+	pub fn generate() -> Self {
+		Self::generate_from(&mut rand::thread_rng())
+	}
+
+	/// Picks a new id drawn from `rng`. (See: `Sender::set_seed()`.)
+	pub fn generate_from<R: Rng + ?Sized>(rng: &mut R) -> Self {
+		let mut bytes = [0u8; SESSION_ID_LEN];
+		rng.fill(&mut bytes[..]);
+
+		// the version & variant bits of a random UUID. (See: RFC 4122.)
+		bytes[6] = (bytes[6] & 0x0f) | 0x40;
+		bytes[8] = (bytes[8] & 0x3f) | 0x80;
+		SessionId(bytes)
+	}
+
+	/// Returns the id carried in a `Hello`, if `bytes` is the right length.
+	pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+		if bytes.len() != SESSION_ID_LEN {
+			return None;
+		}
+
+		let mut id = [0u8; SESSION_ID_LEN];
+		id.copy_from_slice(bytes);
+		Some(SessionId(id))
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.0
+	}
+
+	/// Returns the id of the session running on this thread, if any. (i.e:
+	/// so that a logger can tag each message w/ the session it belongs to.)
+	pub fn current() -> Option<Self> {
+		CURRENT.with(Cell::get)
+	}
+
+	/// Marks `id` as the session running on this thread.
+	pub(crate) fn set_current(id: Option<Self>) {
+		CURRENT.with(|current| current.set(id));
+	}
+}
+
+impl fmt::Display for SessionId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for (i, byte) in self.0.iter().enumerate() {
+			if i == 4 || i == 6 || i == 8 || i == 10 {
+				write!(f, "-")?;
+			}
+
+			write!(f, "{:02x}", byte)?;
+		}
+
+		Ok(())
+	}
+}
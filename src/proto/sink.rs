@@ -0,0 +1,273 @@
+use crate::error::ProtoError;
+use crate::proto::BLOCK_SIZE;
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// The output of a `Receiver`, which must be able to leave a hole where the
+/// sender skipped one. (See: `MessageTy::Skip`.)
+pub trait Sink: Write {
+	/// Leaves a hole of `len` bytes at the current position.
+	fn skip(&mut self, len: u64) -> Result<(), io::Error>;
+
+	/// Called once the session is complete, so that a hole at the end of the
+	/// output still counts towards its length.
+	fn finish(&mut self) -> Result<(), io::Error>;
+}
+
+impl<S: Sink + ?Sized> Sink for Box<S> {
+	fn skip(&mut self, len: u64) -> Result<(), io::Error> { (**self).skip(len) }
+	fn finish(&mut self) -> Result<(), io::Error> { (**self).finish() }
+}
+
+/// An output which cannot seek, so holes are filled in w/ zeros.
+pub struct Zeros<W>(pub W);
+
+impl<W: Write> Write for Zeros<W> {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> { self.0.write(buf) }
+	fn flush(&mut self) -> Result<(), io::Error> { self.0.flush() }
+}
+
+impl<W: Write> Sink for Zeros<W> {
+	fn skip(&mut self, mut len: u64) -> Result<(), io::Error> {
+		let zeros = [0u8; BLOCK_SIZE];
+
+		while len > 0 {
+			let run = len.min(zeros.len() as u64) as usize;
+			self.0.write_all(&zeros[..run])?;
+			len -= run as u64;
+		}
+
+		Ok(())
+	}
+
+	fn finish(&mut self) -> Result<(), io::Error> {
+		Ok(())
+	}
+}
+
+/// An output which cannot seek, & which is finished by `F` once the session
+/// is complete. (See: `Receiver::run_finished()`.)
+pub struct Finished<W, F>(pub W, pub F);
+
+impl<W: Write, F> Write for Finished<W, F> {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> { self.0.write(buf) }
+	fn flush(&mut self) -> Result<(), io::Error> { self.0.flush() }
+}
+
+impl<W: Write, F: FnMut(&mut W) -> Result<(), io::Error>> Sink for Finished<W, F> {
+	fn skip(&mut self, len: u64) -> Result<(), io::Error> {
+		Zeros(&mut self.0).skip(len)
+	}
+
+	fn finish(&mut self) -> Result<(), io::Error> {
+		self.0.flush()?;
+		(self.1)(&mut self.0)
+	}
+}
+
+/// An output which seeks past holes, leaving them unallocated if the file
+/// system supports it.
+pub struct Seeking<W> {
+	inner: W,
+
+	/// Set when the last thing done to the output was to seek past a hole,
+	/// which does not extend the output until something is written after it.
+	trailing_hole: bool,
+}
+
+impl<W> Seeking<W> {
+	pub fn new(inner: W) -> Self {
+		Self { inner, trailing_hole: false }
+	}
+}
+
+impl<W: Write> Write for Seeking<W> {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+		self.trailing_hole = false;
+		self.inner.write(buf)
+	}
+
+	fn flush(&mut self) -> Result<(), io::Error> { self.inner.flush() }
+}
+
+impl<W: Write + Seek> Sink for Seeking<W> {
+	fn skip(&mut self, len: u64) -> Result<(), io::Error> {
+		if len == 0 { return Ok(()); }
+
+		let offset = i64::try_from(len)
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "hole is too long to seek past"))?;
+
+		self.inner.seek(SeekFrom::Current(offset))?;
+		self.trailing_hole = true;
+		Ok(())
+	}
+
+	fn finish(&mut self) -> Result<(), io::Error> {
+		if self.trailing_hole {
+			// rewriting the hole's last byte extends the output to its full length
+			self.inner.seek(SeekFrom::Current(-1))?;
+			self.inner.write_all(&[0])?;
+			self.trailing_hole = false;
+		}
+
+		self.inner.flush()
+	}
+}
+
+/// Reserves `len` bytes past the current end of `file`, w/o changing its
+/// length, so that running out of disk space fails now rather than partway
+/// through the transfer. A file system which cannot reserve space is left
+/// as it is.
+#[cfg(target_os = "linux")]
+pub(crate) fn preallocate(file: &File, len: u64) -> Result<(), ProtoError> {
+	use std::os::unix::io::AsRawFd;
+
+	let offset = file.metadata()?.len();
+	let (offset, len) = match (libc::off_t::try_from(offset), libc::off_t::try_from(len)) {
+		(Ok(offset), Ok(len)) if len > 0 => (offset, len),
+		_ => return Ok(()),
+	};
+
+	if unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, offset, len) } == 0 {
+		return Ok(());
+	}
+
+	let err = io::Error::last_os_error();
+	match err.raw_os_error() {
+		Some(libc::ENOSPC) | Some(libc::EFBIG) => Err(ProtoError::NoSpace { needed: len as u64 }),
+		Some(libc::EOPNOTSUPP) | Some(libc::ENODEV) | Some(libc::EINVAL) => {
+			debug!("could not reserve space for the output: {}", err);
+			Ok(())
+		},
+
+		_ => Err(err.into()),
+	}
+}
+
+/// Space cannot be reserved on this platform, so the output grows as it is
+/// written.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn preallocate(_file: &File, _len: u64) -> Result<(), ProtoError> {
+	Ok(())
+}
+
+/// The alignment `O_DIRECT` requires of the buffer, length & offset of each
+/// write. This covers the logical block size of any disk in common use.
+const DIRECT_ALIGN: usize = 4096;
+
+/// How much is gathered before each write to a `Direct` output.
+const DIRECT_BUFFER: usize = 256 * BLOCK_SIZE;
+
+/// An output opened w/ `O_DIRECT`, which bypasses the page cache.
+///
+/// Blocks are gathered into an aligned buffer & written out in whole chunks
+/// of `DIRECT_ALIGN` bytes. The tail which is not a whole chunk is written
+/// once the session ends (or the output is dropped) w/ `O_DIRECT` cleared.
+/// Holes are written out as zeros, since they could not be seeked past w/o
+/// breaking the alignment.
+pub struct Direct {
+	file: File,
+	raw: Vec<u8>,
+
+	/// Where the aligned buffer starts within `raw`, & how much it holds.
+	start: usize,
+	len: usize,
+}
+
+impl Direct {
+	pub fn new(file: File) -> Self {
+		let raw = vec![0u8; DIRECT_BUFFER + DIRECT_ALIGN];
+		let start = raw.as_ptr().align_offset(DIRECT_ALIGN);
+		Self { file, raw, start, len: 0 }
+	}
+
+	/// Writes out the whole chunks gathered so far, keeping the rest.
+	fn drain(&mut self) -> Result<(), io::Error> {
+		let aligned = self.len - self.len % DIRECT_ALIGN;
+		if aligned == 0 {
+			return Ok(());
+		}
+
+		let start = self.start;
+		self.file.write_all(&self.raw[start..start + aligned])?;
+		self.raw.copy_within(start + aligned..start + self.len, start);
+		self.len -= aligned;
+		Ok(())
+	}
+
+	/// Writes out everything gathered, the tail through the page cache.
+	fn write_tail(&mut self) -> Result<(), io::Error> {
+		self.drain()?;
+		if self.len == 0 {
+			return Ok(());
+		}
+
+		clear_direct(&self.file)?;
+		let start = self.start;
+		self.file.write_all(&self.raw[start..start + self.len])?;
+		self.len = 0;
+		Ok(())
+	}
+}
+
+impl Write for Direct {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+		let len = buf.len().min(DIRECT_BUFFER - self.len);
+		let at = self.start + self.len;
+		self.raw[at..at + len].copy_from_slice(&buf[..len]);
+		self.len += len;
+
+		if self.len == DIRECT_BUFFER {
+			self.drain()?;
+		}
+
+		Ok(len)
+	}
+
+	/// Only whole chunks can be written before the end of the session, so
+	/// this leaves them to be gathered.
+	fn flush(&mut self) -> Result<(), io::Error> {
+		Ok(())
+	}
+}
+
+impl Sink for Direct {
+	fn skip(&mut self, len: u64) -> Result<(), io::Error> {
+		Zeros(self).skip(len)
+	}
+
+	fn finish(&mut self) -> Result<(), io::Error> {
+		self.write_tail()
+	}
+}
+
+impl Drop for Direct {
+	fn drop(&mut self) {
+		// i.e: what arrived before an interrupted session is kept
+		if let Err(err) = self.write_tail() {
+			warn!("could not write the end of the output: {}", err);
+		}
+	}
+}
+
+/// Turns `O_DIRECT` off, so that a write which is not aligned can be made.
+#[cfg(target_os = "linux")]
+fn clear_direct(file: &File) -> Result<(), io::Error> {
+	use std::os::unix::io::AsRawFd;
+
+	let fd = file.as_raw_fd();
+	let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+	if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) } < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	Ok(())
+}
+
+/// `O_DIRECT` is only used on Linux, so there is nothing to turn off.
+#[cfg(not(target_os = "linux"))]
+fn clear_direct(_file: &File) -> Result<(), io::Error> {
+	Ok(())
+}
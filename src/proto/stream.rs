@@ -0,0 +1,523 @@
+use crate::error::ProtoError;
+use crate::proto::{dial_any, raw, resolve, Cidr, Hangup, Impairment, LinkStats, MessageTy, Mode, StreamOpts, Transport, UdtMode, BLOCK_SIZE, MESSAGE_SIZE};
+
+use byteorder::{ByteOrder, NetworkEndian};
+use failure::Fail;
+use rand::Rng;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::udt::{Epoll, SocketFamily, SocketType, UdtOpts, UdtSocket, UDT_EPOLL_IN};
+
+/// Room for the largest message the protocol sends, which is what a
+/// `UdtMode::Message` socket reads at once.
+const DATAGRAM_LEN: usize = MESSAGE_SIZE + 2 * BLOCK_SIZE;
+
+pub(super) struct Stream {
+	inner: UdtSocket,
+
+	/// Set if the socket is a `UdtMode::Message` one, in which case what is
+	/// written is held in `outgoing` until it adds up to a whole message, &
+	/// a message which was read is handed out of `incoming`. (A whole message
+	/// which is written at once is sent straight away, & one which is read
+	/// may be swapped out of `incoming` whole.)
+	messages: bool,
+	outgoing: Vec<u8>,
+	incoming: Vec<u8>,
+	read_pos: usize,
+
+	/// How long a block sent as a message may go undelivered, & whether it
+	/// has to wait on the messages before it. (See: `StreamOpts::message_ttl`
+	/// & `StreamOpts::unordered`.)
+	block_ttl: Option<Duration>,
+	blocks_in_order: bool,
+
+	impairment: Option<Impairment>,
+	held: Option<Vec<u8>>,
+
+	/// The socket's stats as it was closed, since UDT forgets them after.
+	closed_stats: Option<LinkStats>,
+}
+
+/// The `Stream` represents an underlying UDT socket.
+/// 
+/// This is a wrapper type which implements `Read` and `Write` for the
+/// underlying socket. Additionally it implements some applicaiton level
+/// semantics. (Such as the `sender` vs `receiver` roles.)
+///
+impl Stream {
+	/// When created in the `Receiver` mode it begins listening on every one
+	/// of the specified addresses, and accepts whichever peer arrives first.
+	/// Otherwise if created in `Sender` mode it attempts to reach a receiver
+	/// at each of them in turn. These roles are swapped if `opts.reverse` is set.
+	pub(super) fn new<S: ToSocketAddrs>(mode: Mode, addr: S, opts: &StreamOpts) -> Result<Self, ProtoError> {
+		let addrs = resolve(addr, opts.family)?;
+
+		// the IPv6 addresses are not worth trying, unless there is nothing else
+		// to report an error for
+		let v4: Vec<SocketAddr> = addrs.iter().copied().filter(SocketAddr::is_ipv4).collect();
+		let dialable = if v4.is_empty() { &addrs } else { &v4 };
+
+		let stream = match (mode, opts.reverse) {
+			(Mode::Sender, false) | (Mode::Receiver, true) => dial_any(dialable, |addr| Self::create_dialer(addr, opts.bind, opts))?,
+			(Mode::Receiver, false) | (Mode::Sender, true) => Self::create_listener(&addrs, opts)?,
+		};
+
+		Ok(stream.configured(opts))
+	}
+
+	/// Applies the parts of `opts` which take effect once connected.
+	pub(super) fn configured(mut self, opts: &StreamOpts) -> Self {
+		if let Some(impairment) = &opts.impairment {
+			warn!("simulating network impairment: {:?}", impairment);
+			self.impairment = Some(impairment.clone());
+		}
+
+		self.block_ttl = opts.message_ttl;
+		self.blocks_in_order = !opts.unordered;
+		self
+	}
+
+	pub(super) fn from_socket(sock: UdtSocket, mode: UdtMode) -> Self {
+		Self {
+			inner: sock,
+			messages: mode == UdtMode::Message,
+			outgoing: vec![],
+			incoming: vec![],
+			read_pos: 0,
+			block_ttl: None,
+			blocks_in_order: true,
+			impairment: None,
+			held: None,
+			closed_stats: None,
+		}
+	}
+
+	/// Opens a socket of the type `opts.udt_mode` calls for, w/ buffers of
+	/// the size `opts.socket_buffer` asks for. A socket which is accepted from
+	/// a listener has the listener's buffers.
+	///
+	/// Any `Congestion` but the default replaces UDT's own congestion control,
+	/// which only ubuffer's own UDT can do. W/ the `udt-sys` bindings it is
+	/// refused rather than quietly ignored.
+	fn open_socket(opts: &StreamOpts) -> Result<UdtSocket, ProtoError> {
+		#[cfg(feature = "udt-sys")]
+		if !opts.congestion.is_adaptive() {
+			return Err(ProtoError::InvalidArgument { reason: "choosing a congestion controller for UDT requires a build w/o the udt-sys feature, whose UDT's congestion control cannot be replaced" });
+		}
+
+		let ty = match opts.udt_mode {
+			UdtMode::Stream => SocketType::Stream,
+			UdtMode::Message => SocketType::Datagram,
+		};
+
+		let sock = UdtSocket::new(SocketFamily::AFInet, ty)
+			.map_err(|err| ProtoError::ConnectErr { inner: err })?;
+
+		if let Some(size) = opts.socket_buffer {
+			let size = i32::try_from(size).unwrap_or(i32::MAX);
+			sock.setsockopt(UdtOpts::UDT_SNDBUF, size)
+				.and_then(|_| sock.setsockopt(UdtOpts::UDT_RCVBUF, size))
+				.map_err(|err| ProtoError::ConnectErr { inner: err })?;
+		}
+
+		#[cfg(not(feature = "udt-sys"))]
+		if !opts.congestion.is_adaptive() {
+			let congestion = opts.congestion.clone();
+			sock.set_congestion(std::sync::Arc::new(move || congestion.controller()))
+				.map_err(|err| ProtoError::ConnectErr { inner: err })?;
+		}
+
+		Ok(sock)
+	}
+
+	pub(super) fn create_dialer(addr: SocketAddr, bind: Option<SocketAddr>, opts: &StreamOpts) -> Result<Self, ProtoError> {
+		info!("connecting to utp peer ...");
+		check_udt_addr(addr)?;
+		let sock = Self::open_socket(opts)?;
+
+		if let Some(bind_addr) = bind {
+			info!("binding to local address {} ...", bind_addr);
+			sock.bind(bind_addr)
+				.map_err(|err| ProtoError::ConnectErr { inner: err })?;
+		}
+
+		sock.connect(addr)
+			.map_err(|err| ProtoError::ConnectErr { inner: err })?;
+
+		Ok(Self::from_socket(sock, opts.udt_mode))
+	}
+
+	/// Listens on each of `addrs`, and accepts the first peer to arrive.
+	fn create_listener(addrs: &[SocketAddr], opts: &StreamOpts) -> Result<Self, ProtoError> {
+		let listeners = Self::listen_all(addrs, opts)?;
+		let mut epoll = Self::watch(&listeners)?;
+		let accepted = Self::accept_within(&mut epoll, opts.accept_timeout, &opts.allow);
+		Self::unwatch(epoll, listeners)?;
+
+		Ok(Self::from_socket(accepted?, opts.udt_mode))
+	}
+
+	/// Listens on each of `addrs`, skipping (but logging) those which cannot
+	/// be listened on so long as one of them can.
+	pub(super) fn listen_all(addrs: &[SocketAddr], opts: &StreamOpts) -> Result<Vec<UdtSocket>, ProtoError> {
+		info!("setting up listening socket ...");
+		let mut listeners = vec![];
+		let mut last_err = None;
+
+		for &addr in addrs {
+			match Self::listen_on(addr, opts) {
+				Ok(listener) => listeners.push(listener),
+				Err(err) => {
+					warn!("could not listen on {}: {}", addr, err);
+					last_err = Some(err);
+				},
+			}
+		}
+
+		if listeners.is_empty() {
+			return Err(last_err.unwrap_or(ProtoError::NoSocketAddr));
+		}
+
+		Ok(listeners)
+	}
+
+	fn listen_on(addr: SocketAddr, opts: &StreamOpts) -> Result<UdtSocket, ProtoError> {
+		check_udt_addr(addr)?;
+		let listener = Self::open_socket(opts)?;
+
+		listener.bind(addr)
+			.map_err(|err| ProtoError::ConnectErr { inner: err })?;
+
+		// a sender's paths (See: `multipath`) are all dialed before the first
+		// one is read from, so they wait in the backlog until then
+		listener.listen(i32::from(u8::MAX))
+			.map_err(|err| ProtoError::ConnectErr { inner: err })?;
+
+		Ok(listener)
+	}
+
+	/// Returns an `Epoll` which the `listeners` can be accepted from. It is
+	/// kept for as long as they are listened on, as UDT does not report a peer
+	/// which was already waiting when a listener is added to a new one.
+	pub(super) fn watch(listeners: &[UdtSocket]) -> Result<Epoll, ProtoError> {
+		let mut epoll = Epoll::create()?;
+		for listener in listeners {
+			epoll.add_usock(listener, Some(UDT_EPOLL_IN))?;
+		}
+
+		Ok(epoll)
+	}
+
+	/// Stops listening on the `listeners`, so that their addresses can be
+	/// listened on again (i.e: when a session is resumed.) The sockets which
+	/// were accepted from them are unaffected.
+	pub(super) fn unwatch(epoll: Epoll, listeners: Vec<UdtSocket>) -> Result<(), ProtoError> {
+		for listener in &listeners {
+			epoll.remove_usock(listener)?;
+		}
+
+		for listener in listeners {
+			let _ = listener.close();
+		}
+
+		Ok(())
+	}
+
+	/// Accepts the first peer from an `allow`ed address on any of the
+	/// listeners `watch`ed by the `epoll`, dropping any others.
+	pub(super) fn accept_within(epoll: &mut Epoll, timeout: Option<Duration>, allow: &[Cidr]) -> Result<UdtSocket, ProtoError> {
+		let deadline = timeout.map(|timeout| Instant::now() + timeout);
+		loop {
+			// w/o a deadline this waits for as long as it takes
+			let wait_ms = deadline.map_or(-1, |deadline| {
+				deadline.saturating_duration_since(Instant::now()).as_millis() as i64
+			});
+
+			let (readable, _) = epoll.wait(wait_ms, false)?;
+			let listener = match readable.first() {
+				Some(listener) => listener,
+				None if deadline.is_some() => break Err(ProtoError::ConnectTimeout),
+				None => continue,
+			};
+
+			let (sock, addr) = match listener.accept() {
+				Ok(accepted) => accepted,
+				Err(err) => break Err(ProtoError::ConnectErr { inner: err }),
+			};
+
+			if Cidr::allows(allow, addr.ip()) {
+				break Ok(sock);
+			}
+
+			warn!("dropping a connection from {}, which is not an allowed address", addr);
+			let _ = sock.close();
+		}
+	}
+
+	/// Returns another handle to the same socket, so that it may be read
+	/// and written from different threads.
+	pub(super) fn try_clone(&self) -> Self {
+		Self {
+			messages: self.messages,
+			block_ttl: self.block_ttl,
+			blocks_in_order: self.blocks_in_order,
+			impairment: self.impairment.clone(),
+			..Self::from_socket(self.inner, UdtMode::Stream)
+		}
+	}
+
+	/// Sends as much of `buf` as the socket will take, or all of it as a
+	/// single message if the socket sends messages.
+	fn send(&self, buf: &[u8]) -> Result<usize, io::Error> {
+		if self.messages {
+			// only a block may expire or arrive out of order, the receiver
+			// cannot ask for anything else again
+			let block = buf.len() >= MESSAGE_SIZE && MessageTy::from_u8(buf[5]).is_some_and(MessageTy::is_block);
+			let (ttl, in_order) = match block {
+				true => (self.block_ttl, self.blocks_in_order),
+				false => (None, true),
+			};
+
+			return raw::sendmsg(self.inner, buf, ttl, in_order)
+				.map_err(|err| ProtoError::SocketErr { inner: err }.compat())
+				.map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))
+				.and_then(|bytes_sent| match bytes_sent as usize {
+					len if len == buf.len() => Ok(len),
+					_ => Err(io::Error::new(io::ErrorKind::WriteZero, "the message was cut short")),
+				});
+		}
+
+		let bytes_sent = self.inner.send(buf)
+			.map_err(|err| ProtoError::SocketErr { inner: err }.compat())
+			.map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+
+		// TODO: check the sanity of this cast.
+		//       not sure why UDT has this as a signed integer.
+		Ok(bytes_sent as usize)
+	}
+
+	fn send_all(&self, mut buf: &[u8]) -> Result<(), io::Error> {
+		while !buf.is_empty() {
+			let len = self.send(buf)?;
+			buf = &buf[len..];
+		}
+
+		Ok(())
+	}
+
+	/// Sends a write which was held back by a simulated reordering.
+	fn release_held(&mut self) -> Result<(), io::Error> {
+		match self.held.take() {
+			Some(held) => self.send_all(&held),
+			None => Ok(()),
+		}
+	}
+
+	fn send_impaired(&mut self, impairment: &Impairment, buf: &[u8]) -> Result<usize, io::Error> {
+		thread::sleep(impairment.delay);
+
+		if rand::random::<f64>() < impairment.loss {
+			trace!("simulated loss of a {} byte write", buf.len());
+			return Ok(buf.len());
+		}
+
+		if self.held.is_none() && rand::random::<f64>() < impairment.reorder {
+			trace!("simulated reordering of a {} byte write", buf.len());
+			self.held = Some(buf.to_vec());
+			return Ok(buf.len());
+		}
+
+		// headers are left alone, a corrupt one could not be recovered from
+		let intact = match buf.len() {
+			_ if self.messages => MESSAGE_SIZE,
+			MESSAGE_SIZE => MESSAGE_SIZE,
+			_ => 0,
+		};
+
+		if intact < buf.len() && rand::random::<f64>() < impairment.corrupt {
+			trace!("simulated corruption of a {} byte write", buf.len());
+			let mut corrupt = buf.to_vec();
+			let bit = rand::thread_rng().gen_range(intact * 8, corrupt.len() * 8);
+			corrupt[bit / 8] ^= 1 << (bit % 8);
+
+			self.send_all(&corrupt)?;
+			self.release_held()?;
+			return Ok(buf.len());
+		}
+
+		self.send_all(buf)?;
+		self.release_held()?;
+		Ok(buf.len())
+	}
+
+	fn send_one(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+		match self.impairment.clone() {
+			Some(impairment) => self.send_impaired(&impairment, buf),
+			None => self.send(buf),
+		}
+	}
+
+	/// Sends each whole message which has been written so far, going by the
+	/// length in its header.
+	fn send_messages(&mut self) -> Result<(), io::Error> {
+		while self.outgoing.len() >= MESSAGE_SIZE {
+			let len = MESSAGE_SIZE + NetworkEndian::read_u32(&self.outgoing[6..10]) as usize;
+			if self.outgoing.len() < len {
+				break;
+			}
+
+			let rest = self.outgoing.split_off(len);
+			let message = mem::replace(&mut self.outgoing, rest);
+			self.send_one(&message)?;
+		}
+
+		Ok(())
+	}
+
+	/// Reads the next message, once the last one has been handed out.
+	fn recv_message(&mut self) -> Result<(), io::Error> {
+		self.incoming.resize(DATAGRAM_LEN, 0);
+		let bytes_recvd = self.inner.recvmsg(&mut self.incoming)
+			.map_err(|err| ProtoError::SocketErr { inner: err }.compat())
+			.map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+
+		self.incoming.truncate(bytes_recvd);
+		self.read_pos = 0;
+		Ok(())
+	}
+}
+
+impl Transport for Stream {
+	fn close(&mut self) -> Result<(), ProtoError> {
+		self.release_held()?;
+
+		// nothing is queued once the socket is gone
+		self.closed_stats = self.link_stats()
+			.map(|stats| LinkStats { send_queue: 0, recv_queue: 0, ..stats })
+			.or(self.closed_stats);
+
+		Ok(self.inner.close()?)
+	}
+
+	fn link_stats(&self) -> Option<LinkStats> {
+		// UDT reports both of these in packets rather than bytes
+		let send_queue = self.inner.getsockopt(UdtOpts::UDT_SNDDATA);
+		let recv_queue = self.inner.getsockopt(UdtOpts::UDT_RCVDATA);
+		let (send_queue, recv_queue) = match (send_queue, recv_queue) {
+			(Ok(send_queue), Ok(recv_queue)) => (send_queue, recv_queue),
+			_ => return self.closed_stats,
+		};
+
+		let perf = raw::perfmon(self.inner)?;
+
+		Some(LinkStats {
+			send_queue: send_queue.max(0) as u64,
+			recv_queue: recv_queue.max(0) as u64,
+			rtt: Some(Duration::from_secs_f64(perf.ms_rtt.max(0.0) / 1e3)),
+			bandwidth_bps: Some((perf.mbps_bandwidth.max(0.0) * 1e6) as u64),
+			sent_packets: perf.pkt_sent_total.max(0) as u64,
+			lost_packets: (perf.pkt_snd_loss_total.max(0) + perf.pkt_rcv_loss_total.max(0)) as u64,
+			retransmitted_packets: perf.pkt_retrans_total.max(0) as u64,
+		})
+	}
+
+	fn has_pending(&mut self) -> bool {
+		self.read_pos < self.incoming.len()
+			|| self.inner.getsockopt(UdtOpts::UDT_RCVDATA).is_ok_and(|queued| queued > 0)
+	}
+
+	fn hangup_handle(&self) -> Option<Hangup> {
+		let sock = self.inner;
+		Some(Box::new(move || { let _ = sock.close(); }))
+	}
+
+	fn lossy(&self) -> bool {
+		self.messages
+	}
+
+	fn swap_message(&mut self, buf: &mut Vec<u8>) -> bool {
+		if !self.messages || self.read_pos != MESSAGE_SIZE {
+			return false;
+		}
+
+		mem::swap(&mut self.incoming, buf);
+		self.incoming.clear();
+		self.read_pos = 0;
+		true
+	}
+}
+
+impl Read for Stream {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+		// a held back write might be the one the peer needs to reply to us
+		self.release_held()?;
+
+		if self.messages {
+			if self.read_pos >= self.incoming.len() {
+				self.recv_message()?;
+			}
+
+			let len = buf.len().min(self.incoming.len() - self.read_pos);
+			buf[..len].copy_from_slice(&self.incoming[self.read_pos..self.read_pos + len]);
+			self.read_pos += len;
+			return Ok(len);
+		}
+
+		let buf_len = buf.len();
+		let bytes_recvd = self.inner.recv(buf, buf_len)
+			.map_err(|err| ProtoError::SocketErr { inner: err }.compat())
+			.map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+
+		// TODO: check the sanity of this cast.
+		//       not sure why UDT has this as a signed integer.
+		Ok(bytes_recvd as usize)
+	}
+}
+
+impl Write for Stream {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+		if !self.messages {
+			return self.send_one(buf);
+		}
+
+		// a whole message is sent as is, rather than copied into `outgoing`
+		if self.outgoing.is_empty() && buf.len() >= MESSAGE_SIZE
+			&& buf.len() == MESSAGE_SIZE + NetworkEndian::read_u32(&buf[6..10]) as usize {
+			return self.send_one(buf);
+		}
+
+		self.outgoing.extend_from_slice(buf);
+		self.send_messages()?;
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> Result<(), io::Error> {
+		// whatever is left is not a message (i.e: the banner) but is still
+		// sent as one, so that it is not held back forever
+		if self.messages && !self.outgoing.is_empty() {
+			let outgoing = mem::take(&mut self.outgoing);
+			self.send_one(&outgoing)?;
+		}
+
+		self.release_held()?;
+
+		// TODO: UDT bindings provides no means to flush, I believe it's buffering
+		// data internally and sending as fast as it can. (See: UDT_LINGER.)
+		// for now this only sends a write held back by a simulated impairment,
+		// otherwise data is immediately committed to the underlying UDT socket.
+		Ok(())
+	}
+}
+
+/// The UDT bindings only speak IPv4 (and panic if given anything else) so
+/// an IPv6 address is refused before it reaches them.
+pub(super) fn check_udt_addr(addr: SocketAddr) -> Result<(), ProtoError> {
+	match addr {
+		SocketAddr::V4(_) => Ok(()),
+		SocketAddr::V6(_) => Err(ProtoError::Ipv6Unsupported { addr }),
+	}
+}
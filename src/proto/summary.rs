@@ -0,0 +1,120 @@
+use crate::proto::{Event, LinkStats, SessionId, Transport};
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// A running tally of the data moved during a session.
+///
+/// Both the `Sender` and `Receiver` update their summary as each block
+/// is encrypted or decrypted. The `elapsed` time is measured from the
+/// completion of the handshake.
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+	/// The number of bytes read from the input, or written to the output.
+	pub plaintext_bytes: u64,
+
+	/// The number of encrypted payload bytes (including auth tags) which
+	/// crossed the wire. This does not include message headers.
+	pub ciphertext_bytes: u64,
+
+	/// The number of `MessageTy::Block` messages exchanged.
+	pub blocks: u64,
+
+	/// The time spent transferring blocks.
+	pub elapsed: Duration,
+
+	/// The id both peers know the session by, once the `Hello`s are through.
+	pub session_id: Option<SessionId>,
+
+	/// The number of plaintext bytes the sender announced it would send, if
+	/// it knew. (See: `MessageTy::TotalSize`.)
+	pub expected_bytes: Option<u64>,
+
+	/// The state of the connection as the session ended, if the transport
+	/// reports it. (See: `Sender::stats()`.)
+	pub link: Option<LinkStats>,
+}
+
+impl Summary {
+	/// Returns the average plaintext throughput in bytes per second.
+	pub fn throughput(&self) -> f64 {
+		let secs = self.elapsed_secs();
+		if secs > 0.0 { self.plaintext_bytes as f64 / secs } else { 0.0 }
+	}
+
+	/// Returns the elapsed time as fractional seconds.
+	pub fn elapsed_secs(&self) -> f64 {
+		self.elapsed.as_secs() as f64 + f64::from(self.elapsed.subsec_nanos()) / 1e9
+	}
+}
+
+impl fmt::Display for Summary {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} ({} bytes) in {} blocks, {} bytes ciphertext, {:.2}s elapsed, {}/s",
+		       human_bytes(self.plaintext_bytes as f64),
+		       self.plaintext_bytes,
+		       self.blocks,
+		       self.ciphertext_bytes,
+		       self.elapsed_secs(),
+		       human_bytes(self.throughput()))
+	}
+}
+
+/// Produces an `Event::Stats` each time its interval elapses.
+pub(crate) struct StatsTimer {
+	interval: Duration,
+	last: Instant,
+	last_bytes: u64,
+}
+
+impl StatsTimer {
+	/// Creates a timer which samples every `interval`, zero disables it.
+	pub fn new(interval: Duration) -> Self {
+		Self { interval, last: Instant::now(), last_bytes: 0 }
+	}
+
+	/// Starts the first interval over, i.e: once the handshake is complete.
+	pub fn restart(&mut self) {
+		self.last = Instant::now();
+	}
+
+	/// Returns a sample of the transfer if the interval has elapsed.
+	pub fn sample(&mut self, summary: &Summary, transport: &dyn Transport) -> Option<Event> {
+		let elapsed = self.last.elapsed();
+		if self.interval == Duration::ZERO || elapsed < self.interval {
+			return None;
+		}
+
+		let interval_secs = elapsed.as_secs_f64();
+		let bytes = summary.plaintext_bytes - self.last_bytes;
+		let link = transport.link_stats();
+
+		self.last = Instant::now();
+		self.last_bytes = summary.plaintext_bytes;
+
+		Some(Event::Stats {
+			total_bytes: summary.plaintext_bytes,
+			expected_bytes: summary.expected_bytes,
+			interval_secs,
+			throughput_bps: bytes as f64 / interval_secs,
+			send_queue: link.map(|link| link.send_queue),
+			recv_queue: link.map(|link| link.recv_queue),
+			rtt_ms: link.and_then(|link| link.rtt).map(|rtt| rtt.as_secs_f64() * 1e3),
+			lost_packets: link.map(|link| link.lost_packets),
+		})
+	}
+}
+
+/// Formats a byte count using binary (1024) unit prefixes.
+pub fn human_bytes(bytes: f64) -> String {
+	const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+	let mut value = bytes;
+	let mut unit = 0;
+	while value >= 1024.0 && unit < UNITS.len() - 1 {
+		value /= 1024.0;
+		unit += 1;
+	}
+
+	format!("{:.1} {}", value, UNITS[unit])
+}
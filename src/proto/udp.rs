@@ -0,0 +1,674 @@
+use crate::error::ProtoError;
+use crate::proto::{Cidr, Controller, Impairment, LinkStats, Mode, StreamOpts, Transport};
+
+use byteorder::{ByteOrder, NetworkEndian};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The largest payload carried by one datagram, this keeps packets below a
+/// typical 1500 byte MTU so that they are never fragmented.
+const MAX_PAYLOAD: usize = 1400;
+
+/// The size of the type & sequence number which precede every packet.
+const HEADER_LEN: usize = 9;
+
+/// The size of an `Ack`, its header is followed by the selective ack bitmap.
+const ACK_LEN: usize = HEADER_LEN + 8;
+
+/// The most packets which may ever be in flight before a write blocks, a
+/// controller's window is clamped to this.
+pub(crate) const WINDOW: usize = 1024;
+
+/// The most bytes which may have arrived but not yet been read, packets are
+/// dropped (and later retransmitted) until the reader catches up.
+const RECV_BUFFER: usize = WINDOW * MAX_PAYLOAD;
+
+/// How often unacknowledged packets are checked for retransmission.
+const TICK: Duration = Duration::from_millis(10);
+
+/// The peer is sent an `Ack` at least this often, even when it has sent us
+/// nothing, so that an idle link is not mistaken for a dead one.
+const HEARTBEAT: Duration = Duration::from_millis(250);
+
+const INITIAL_RTO: Duration = Duration::from_millis(250);
+const MIN_RTO: Duration = Duration::from_millis(20);
+const MAX_RTO: Duration = Duration::from_secs(2);
+
+/// How long the dialer keeps sending `Syn`s before giving up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the dialer resends its `Syn`.
+const CONNECT_RETRY: Duration = Duration::from_millis(250);
+
+/// The link is considered dead after hearing nothing from the peer for this long.
+const LINK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest `close()` waits for the peer to acknowledge our `Fin` and send its own.
+const LINGER: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PacketTy {
+	/// The dialer asks the listener to accept it as its peer.
+	Syn = 0,
+
+	/// The listener's reply to a `Syn`.
+	SynAck = 1,
+
+	/// A payload, which occupies one sequence number.
+	Data = 2,
+
+	/// The sender will not write anything else, this also occupies a sequence
+	/// number so that it is delivered after all of the data.
+	Fin = 3,
+
+	/// Acknowledges every sequence number below the one in the header, and
+	/// the following 64 numbers which are set in the bitmap.
+	Ack = 4,
+}
+
+impl PacketTy {
+	fn from_u8(ty: u8) -> Option<Self> {
+		let ty = match ty {
+			0 => PacketTy::Syn,
+			1 => PacketTy::SynAck,
+			2 => PacketTy::Data,
+			3 => PacketTy::Fin,
+			4 => PacketTy::Ack,
+			_ => return None,
+		};
+
+		Some(ty)
+	}
+}
+
+fn packet(ty: PacketTy, seq: u64, body: &[u8]) -> Vec<u8> {
+	let mut buf = vec![0u8; HEADER_LEN + body.len()];
+	buf[0] = ty as u8;
+	NetworkEndian::write_u64(&mut buf[1..HEADER_LEN], seq);
+	buf[HEADER_LEN..].copy_from_slice(body);
+	buf
+}
+
+struct Unacked {
+	packet: Vec<u8>,
+	sent: Instant,
+	retransmitted: bool,
+}
+
+/// The state shared between the `Datagram` and its service thread.
+struct Link {
+	next_seq: u64,
+	unacked: BTreeMap<u64, Unacked>,
+	highest_sacked: u64,
+	srtt: Option<Duration>,
+	rto: Duration,
+
+	/// Packets sent again since they went unacknowledged, each of which is
+	/// counted as lost.
+	retransmitted: u64,
+
+	/// Decides how many packets may be in flight. (See: `Congestion`.)
+	controller: Box<dyn Controller>,
+
+	/// The next sequence number to be delivered to the reader.
+	recv_next: u64,
+
+	/// Packets which arrived ahead of `recv_next`, `None` is the peer's `Fin`.
+	out_of_order: BTreeMap<u64, Option<Vec<u8>>>,
+	readable: VecDeque<u8>,
+	peer_fin: bool,
+
+	last_heard: Instant,
+	last_ack: Instant,
+	held: Option<Vec<u8>>,
+	error: Option<(ErrorKind, &'static str)>,
+
+	/// When this side hung up, the service thread keeps acknowledging the
+	/// peer for a while after in case our ack of its `Fin` was lost.
+	closed: Option<Instant>,
+}
+
+struct Shared {
+	link: Mutex<Link>,
+	changed: Condvar,
+	socket: UdpSocket,
+	impairment: Option<Impairment>,
+}
+
+/// A reliable, ordered byte stream over plain UDP.
+///
+/// This is a deliberately simple ARQ: payloads are numbered, the peer answers
+/// each one w/ a cumulative ack & a bitmap of what it holds beyond that, and
+/// anything which goes unacknowledged for longer than the retransmission
+/// timeout is sent again. The packets in flight are bounded by the window of
+/// `opts.congestion`, which by default grows as acks arrive and halves on a
+/// loss. This is gentler than UDT's rate based congestion control on links
+/// where that is a poor fit, and on a dedicated link the window can be fixed
+/// at its largest instead.
+pub(crate) struct Datagram {
+	shared: Arc<Shared>,
+}
+
+impl Datagram {
+	/// Connects to, or waits for, the peer at `addr`. (See: `Stream::new`.)
+	pub fn new<S: ToSocketAddrs>(mode: Mode, addr: S, opts: &StreamOpts) -> Result<Self, ProtoError> {
+		let addrs = super::resolve(addr, opts.family)?;
+
+		let socket = match (mode, opts.reverse) {
+			(Mode::Sender, false) | (Mode::Receiver, true) => super::dial_any(&addrs, |addr| Self::dial(addr, opts.bind))?,
+			(Mode::Receiver, false) | (Mode::Sender, true) => Self::listen(&addrs, opts.accept_timeout, &opts.allow)?,
+		};
+
+		if let Some(impairment) = &opts.impairment {
+			warn!("simulating network impairment: {:?}", impairment);
+		}
+
+		socket.set_read_timeout(Some(TICK))?;
+
+		let now = Instant::now();
+		let shared = Arc::new(Shared {
+			link: Mutex::new(Link {
+				next_seq: 0,
+				unacked: BTreeMap::new(),
+				highest_sacked: 0,
+				srtt: None,
+				rto: INITIAL_RTO,
+				retransmitted: 0,
+
+				controller: opts.congestion.controller(),
+
+				recv_next: 0,
+				out_of_order: BTreeMap::new(),
+				readable: VecDeque::new(),
+				peer_fin: false,
+
+				last_heard: now,
+				last_ack: now,
+				held: None,
+				error: None,
+				closed: None,
+			}),
+
+			changed: Condvar::new(),
+			socket,
+			impairment: opts.impairment.clone(),
+		});
+
+		let service = Arc::clone(&shared);
+		thread::spawn(move || service.run());
+
+		Ok(Self { shared })
+	}
+
+	fn dial(addr: SocketAddr, bind: Option<SocketAddr>) -> Result<UdpSocket, ProtoError> {
+		info!("connecting to udp peer ...");
+		let local = match (bind, addr) {
+			(Some(bind_addr), _) => bind_addr,
+			(None, SocketAddr::V4(_)) => ([0, 0, 0, 0], 0).into(),
+			(None, SocketAddr::V6(_)) => ([0u16; 8], 0).into(),
+		};
+
+		let socket = UdpSocket::bind(local)?;
+		socket.connect(addr)?;
+		socket.set_read_timeout(Some(CONNECT_RETRY))?;
+
+		let started = Instant::now();
+		let mut buf = [0u8; HEADER_LEN];
+
+		while started.elapsed() < CONNECT_TIMEOUT {
+			match socket.send(&packet(PacketTy::Syn, 0, &[])) {
+				Err(err) if !is_transient(&err) => return Err(err.into()),
+				_ => {},
+			}
+
+			match socket.recv(&mut buf) {
+				Ok(len) if len == HEADER_LEN && buf[0] == PacketTy::SynAck as u8 => return Ok(socket),
+				Ok(_) => {},
+
+				// the listener may not be up yet
+				Err(err) if err.kind() == ErrorKind::ConnectionRefused => thread::sleep(CONNECT_RETRY),
+				Err(err) if is_transient(&err) => {},
+				Err(err) => return Err(err.into()),
+			}
+		}
+
+		Err(ProtoError::ConnectTimeout)
+	}
+
+	/// Binds every one of `addrs` which is available, then waits for a `Syn`
+	/// on any of them. The socket it arrived on is the one we keep.
+	fn listen(addrs: &[SocketAddr], timeout: Option<Duration>, allow: &[Cidr]) -> Result<UdpSocket, ProtoError> {
+		info!("setting up listening socket ...");
+		let mut sockets = vec![];
+		let mut last_err = None;
+
+		for &addr in addrs {
+			// i.e: `[::]` may already cover `0.0.0.0` on a dual-stack host
+			match UdpSocket::bind(addr) {
+				Ok(socket) => sockets.push(socket),
+				Err(err) => {
+					warn!("could not listen on {}: {}", addr, err);
+					last_err = Some(err);
+				},
+			}
+		}
+
+		if sockets.is_empty() {
+			return Err(last_err.map_or(ProtoError::NoSocketAddr, ProtoError::from));
+		}
+
+		for socket in &sockets {
+			socket.set_read_timeout(Some(TICK))?;
+		}
+
+		let started = Instant::now();
+		let mut buf = [0u8; HEADER_LEN];
+
+		for idx in (0..sockets.len()).cycle() {
+			// checked on every packet, so that a peer which is not allowed
+			// cannot keep us waiting past the timeout
+			if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+				return Err(ProtoError::ConnectTimeout);
+			}
+
+			let socket = &sockets[idx];
+			let (len, peer) = match socket.recv_from(&mut buf) {
+				Ok(received) => received,
+				Err(err) if is_transient(&err) => continue,
+				Err(err) => return Err(err.into()),
+			};
+
+			if len == HEADER_LEN && buf[0] == PacketTy::Syn as u8 && !Cidr::allows(allow, peer.ip()) {
+				warn!("ignoring a udp peer at {}, which is not an allowed address", peer);
+				continue;
+			}
+
+			if len == HEADER_LEN && buf[0] == PacketTy::Syn as u8 {
+				info!("accepted udp peer {}", peer);
+				socket.connect(peer)?;
+				socket.send(&packet(PacketTy::SynAck, 0, &[]))?;
+				return Ok(sockets.swap_remove(idx));
+			}
+		}
+
+		unreachable!("cycle() over a non-empty range never ends")
+	}
+
+	fn lock(&self) -> MutexGuard<'_, Link> {
+		self.shared.lock()
+	}
+}
+
+impl Shared {
+	fn lock(&self) -> MutexGuard<'_, Link> {
+		self.link.lock().unwrap_or_else(|err| err.into_inner())
+	}
+
+	/// Services the socket until it fails, or for a while after the link is
+	/// closed: packets from the peer are handled as they arrive, and every
+	/// `TICK` overdue packets are retransmitted.
+	fn run(&self) {
+		let mut buf = [0u8; HEADER_LEN + MAX_PAYLOAD];
+		let mut last_tick = Instant::now();
+
+		loop {
+			match self.socket.recv(&mut buf) {
+				Ok(len) => self.receive(&buf[..len]),
+
+				// the peer is gone (or not there yet), the link timeout decides which
+				Err(err) if err.kind() == ErrorKind::ConnectionRefused => thread::sleep(TICK),
+				Err(err) if is_transient(&err) => {},
+				Err(err) => {
+					debug!("udp socket failed: {}", err);
+					self.fail(ErrorKind::BrokenPipe, "the udp socket failed");
+					return;
+				},
+			}
+
+			if last_tick.elapsed() >= TICK {
+				last_tick = Instant::now();
+				if !self.tick() { return; }
+			}
+		}
+	}
+
+	fn receive(&self, buf: &[u8]) {
+		if buf.len() < HEADER_LEN { return; }
+
+		let ty = match PacketTy::from_u8(buf[0]) {
+			Some(ty) => ty,
+			None => return,
+		};
+
+		let seq = NetworkEndian::read_u64(&buf[1..HEADER_LEN]);
+		let mut link = self.lock();
+		link.last_heard = Instant::now();
+
+		match ty {
+			// our `SynAck` was lost, so the dialer is still knocking
+			PacketTy::Syn => self.send(&mut link, &packet(PacketTy::SynAck, 0, &[])),
+			PacketTy::SynAck => {},
+
+			PacketTy::Data | PacketTy::Fin => {
+				let in_window = seq >= link.recv_next && seq < link.recv_next + WINDOW as u64;
+				if in_window && link.readable.len() < RECV_BUFFER {
+					let payload = match ty {
+						PacketTy::Data => Some(buf[HEADER_LEN..].to_vec()),
+						_ => None,
+					};
+
+					link.out_of_order.insert(seq, payload);
+					self.deliver(&mut link);
+				}
+
+				self.send_ack(&mut link);
+			},
+
+			PacketTy::Ack if buf.len() == ACK_LEN => {
+				let bitmap = NetworkEndian::read_u64(&buf[HEADER_LEN..ACK_LEN]);
+				self.acknowledge(&mut link, seq, bitmap);
+			},
+
+			PacketTy::Ack => {},
+		}
+	}
+
+	/// Moves packets which are now in order to the reader.
+	fn deliver(&self, link: &mut Link) {
+		let mut delivered = false;
+
+		while let Some(payload) = link.out_of_order.remove(&link.recv_next) {
+			match payload {
+				Some(payload) => link.readable.extend(payload),
+				None => link.peer_fin = true,
+			}
+
+			link.recv_next += 1;
+			delivered = true;
+		}
+
+		if delivered { self.changed.notify_all(); }
+	}
+
+	fn acknowledge(&self, link: &mut Link, cumulative: u64, bitmap: u64) {
+		let now = Instant::now();
+		let mut sample = None;
+
+		let mut acked: Vec<u64> = link.unacked.range(..cumulative).map(|(seq, _)| *seq).collect();
+		for bit in 0..64 {
+			if bitmap & (1 << bit) != 0 {
+				let seq = cumulative + 1 + bit;
+				acked.push(seq);
+				link.highest_sacked = link.highest_sacked.max(seq);
+			}
+		}
+
+		let mut progress = 0;
+		for seq in acked {
+			if let Some(unacked) = link.unacked.remove(&seq) {
+				progress += 1;
+
+				// only packets sent once give an unambiguous round-trip time
+				if !unacked.retransmitted {
+					sample = Some(now - unacked.sent);
+				}
+			}
+		}
+
+		if let Some(sample) = sample {
+			link.srtt = Some(match link.srtt {
+				Some(srtt) => (srtt * 7 + sample) / 8,
+				None => sample,
+			});
+		}
+
+		// any progress undoes the backoff of an earlier timeout
+		if let (true, Some(srtt)) = (progress > 0, link.srtt) {
+			link.rto = (srtt * 2).clamp(MIN_RTO, MAX_RTO);
+		}
+
+		if progress > 0 {
+			let srtt = link.srtt;
+			link.controller.on_ack(progress, srtt);
+		}
+
+		self.changed.notify_all();
+	}
+
+	/// Retransmits overdue packets, sends a heartbeat if one is due, and
+	/// returns `false` once the link is finished.
+	fn tick(&self) -> bool {
+		let mut link = self.lock();
+		let lingered = link.closed.is_some_and(|closed| closed.elapsed() >= LINGER);
+		if lingered || link.error.is_some() { return false; }
+
+		let now = Instant::now();
+		if now - link.last_heard >= LINK_TIMEOUT {
+			drop(link);
+			self.fail(ErrorKind::ConnectionReset, "the udp peer stopped responding");
+			return false;
+		}
+
+		// a packet the peer has skipped over was most likely lost, so it is
+		// sent again after a round trip instead of waiting for the timeout.
+		let rto = link.rto;
+		let fast = link.srtt.unwrap_or(rto);
+		let highest_sacked = link.highest_sacked;
+
+		let overdue: Vec<u64> = link.unacked.iter()
+			.filter(|(seq, unacked)| {
+				let age = now - unacked.sent;
+				age >= rto || (**seq < highest_sacked && age >= fast)
+			})
+			.map(|(seq, _)| *seq)
+			.take(link.window())
+			.collect();
+
+		if overdue.iter().any(|seq| now - link.unacked[seq].sent >= rto) {
+			link.rto = (rto * 2).min(MAX_RTO);
+		}
+
+		if !overdue.is_empty() {
+			let srtt = link.srtt;
+			link.controller.on_loss(overdue.len(), srtt);
+		}
+
+		for seq in overdue {
+			let packet = match link.unacked.get_mut(&seq) {
+				Some(unacked) => {
+					unacked.sent = now;
+					unacked.retransmitted = true;
+					unacked.packet.clone()
+				},
+
+				None => continue,
+			};
+
+			trace!("retransmitting udp packet #{}", seq);
+			link.retransmitted += 1;
+			self.send(&mut link, &packet);
+		}
+
+		if now - link.last_ack >= HEARTBEAT {
+			self.send_ack(&mut link);
+		}
+
+		true
+	}
+
+	fn send_ack(&self, link: &mut Link) {
+		let mut bitmap = 0u64;
+		for bit in 0..64 {
+			if link.out_of_order.contains_key(&(link.recv_next + 1 + bit)) {
+				bitmap |= 1 << bit;
+			}
+		}
+
+		let mut body = [0u8; 8];
+		NetworkEndian::write_u64(&mut body, bitmap);
+
+		link.last_ack = Instant::now();
+		let ack = packet(PacketTy::Ack, link.recv_next, &body);
+		self.send(link, &ack);
+	}
+
+	/// Sends a packet, lost packets are recovered by retransmission so
+	/// errors are only logged here.
+	fn send(&self, link: &mut Link, packet: &[u8]) {
+		if let Some(impairment) = &self.impairment {
+			if rand::random::<f64>() < impairment.loss {
+				trace!("simulated loss of a {} byte packet", packet.len());
+				return;
+			}
+
+			if link.held.is_none() && rand::random::<f64>() < impairment.reorder {
+				trace!("simulated reordering of a {} byte packet", packet.len());
+				link.held = Some(packet.to_vec());
+				return;
+			}
+		}
+
+		if let Err(err) = self.socket.send(packet) {
+			trace!("could not send udp packet: {}", err);
+		}
+
+		if let Some(held) = link.held.take() {
+			let _ = self.socket.send(&held);
+		}
+	}
+
+	/// Fails the link, blocked reads & writes return an error of `kind`.
+	fn fail(&self, kind: ErrorKind, reason: &'static str) {
+		let mut link = self.lock();
+		if link.error.is_none() {
+			link.error = Some((kind, reason));
+		}
+
+		self.changed.notify_all();
+	}
+
+	fn wait<'a>(&self, link: MutexGuard<'a, Link>) -> MutexGuard<'a, Link> {
+		self.changed.wait_timeout(link, TICK)
+			.map(|(link, _)| link)
+			.unwrap_or_else(|err| err.into_inner().0)
+	}
+}
+
+impl Link {
+	/// The packets which may be in flight, never more than `WINDOW` nor
+	/// fewer than one.
+	fn window(&self) -> usize {
+		self.controller.window().clamp(1, WINDOW)
+	}
+
+	fn error(&self) -> Option<io::Error> {
+		self.error.map(|(kind, reason)| io::Error::new(kind, reason))
+	}
+}
+
+/// Errors which only mean that nothing arrived in time, or that the peer is
+/// not listening yet.
+fn is_transient(err: &io::Error) -> bool {
+	matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ConnectionRefused)
+}
+
+impl Transport for Datagram {
+	fn close(&mut self) -> Result<(), ProtoError> {
+		let mut link = self.lock();
+		if link.closed.is_some() { return Ok(()); }
+
+		let seq = link.next_seq;
+		let fin = packet(PacketTy::Fin, seq, &[]);
+		link.next_seq += 1;
+		link.unacked.insert(seq, Unacked { packet: fin.clone(), sent: Instant::now(), retransmitted: false });
+		self.shared.send(&mut link, &fin);
+
+		// wait for the peer to hang up too, so that it is still around to
+		// acknowledge its `Fin`.
+		let started = Instant::now();
+		while (!link.unacked.is_empty() || !link.peer_fin) && link.error.is_none() && started.elapsed() < LINGER {
+			link = self.shared.wait(link);
+		}
+
+		link.closed = Some(Instant::now());
+		self.shared.changed.notify_all();
+		Ok(())
+	}
+
+	fn link_stats(&self) -> Option<LinkStats> {
+		let link = self.lock();
+
+		Some(LinkStats {
+			send_queue: link.unacked.len() as u64,
+			recv_queue: (link.readable.len() / MAX_PAYLOAD + link.out_of_order.len()) as u64,
+			rtt: link.srtt,
+			bandwidth_bps: None,
+			sent_packets: link.next_seq + link.retransmitted,
+			lost_packets: link.retransmitted,
+			retransmitted_packets: link.retransmitted,
+		})
+	}
+
+	fn has_pending(&mut self) -> bool {
+		!self.lock().readable.is_empty()
+	}
+}
+
+impl Read for Datagram {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+		let mut link = self.lock();
+
+		while link.readable.is_empty() && !link.peer_fin && link.closed.is_none() {
+			if let Some(err) = link.error() { return Err(err); }
+			link = self.shared.wait(link);
+		}
+
+		let len = buf.len().min(link.readable.len());
+		for (dst, src) in buf.iter_mut().zip(link.readable.drain(..len)) {
+			*dst = src;
+		}
+
+		Ok(len)
+	}
+}
+
+impl Write for Datagram {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+		if let Some(impairment) = &self.shared.impairment {
+			thread::sleep(impairment.delay);
+		}
+
+		let mut link = self.lock();
+
+		for chunk in buf.chunks(MAX_PAYLOAD) {
+			while link.unacked.len() >= link.window() && link.error.is_none() && link.closed.is_none() {
+				link = self.shared.wait(link);
+			}
+
+			if let Some(err) = link.error() { return Err(err); }
+			if link.closed.is_some() { return Err(io::Error::from(ErrorKind::BrokenPipe)); }
+
+			let seq = link.next_seq;
+			let data = packet(PacketTy::Data, seq, chunk);
+			link.next_seq += 1;
+			link.unacked.insert(seq, Unacked { packet: data.clone(), sent: Instant::now(), retransmitted: false });
+			self.shared.send(&mut link, &data);
+		}
+
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> Result<(), io::Error> {
+		Ok(())
+	}
+}
+
+impl Drop for Datagram {
+	fn drop(&mut self) {
+		self.lock().closed.get_or_insert_with(Instant::now);
+	}
+}
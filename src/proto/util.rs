@@ -1,8 +1,19 @@
 use crate::error::ProtoError;
 
-use byteorder::{NetworkEndian, WriteBytesExt};
+use byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
+use ring::{digest, hkdf, hmac};
 use std::io::Cursor;
 
+/// The HKDF `info` string used when deriving session sub-keys.
+const REKEY_INFO: &[u8] = b"ubuffer rekey";
+
+/// The HKDF `info` string used when deriving the key replies are sealed w/.
+const REPLY_INFO: &[u8] = b"ubuffer replies";
+
+/// The HKDF `info` string used when deriving the key a resumed session is
+/// proven w/.
+const RESUME_INFO: &[u8] = b"ubuffer resume";
+
 pub fn get_next_nonce(nonce: &mut u32, counter: &mut u64) -> Result<Box<[u8]>, ProtoError> {
 	let buf = vec![0u8; 12];
 	let mut cursor = Cursor::new(buf);
@@ -14,3 +25,56 @@ pub fn get_next_nonce(nonce: &mut u32, counter: &mut u64) -> Result<Box<[u8]>, P
 
 	Ok(cursor.into_inner().into_boxed_slice())
 }
+
+/// Verifies that `seq` is the sequence number which follows `counter`.
+pub fn check_seq(counter: u64, seq: u64) -> Result<(), ProtoError> {
+	let expected = counter + 1;
+	if seq != expected {
+		return Err(ProtoError::ReplayOrReorder { expected, received: seq });
+	}
+
+	Ok(())
+}
+
+/// Derives a fresh sub-key from the session's master `key` and a random
+/// `salt` chosen by the sender, using HKDF-SHA256.
+pub fn derive_key(key: &[u8], salt: &[u8]) -> Vec<u8> {
+	let salt = hmac::SigningKey::new(&digest::SHA256, salt);
+	let mut sub_key = vec![0u8; key.len()];
+	hkdf::extract_and_expand(&salt, key, REKEY_INFO, &mut sub_key);
+
+	sub_key
+}
+
+/// Derives the key the receiver seals its replies w/ (i.e: a `MessageTy::Ack`)
+/// from the session's master `key` & its id. It is not rotated by a rekey,
+/// since a reply may cross the sender's `MessageTy::ReKey` in flight.
+pub fn derive_reply_key(key: &[u8], session_id: &[u8]) -> Vec<u8> {
+	let salt = hmac::SigningKey::new(&digest::SHA256, session_id);
+	let mut reply_key = vec![0u8; key.len()];
+	hkdf::extract_and_expand(&salt, key, REPLY_INFO, &mut reply_key);
+
+	reply_key
+}
+
+/// Derives the key each peer proves it holds the session key w/ when the
+/// session is resumed, from the session's master `key` & its id. (See:
+/// `resume::Tokens`.)
+pub fn derive_resume_key(key: &[u8], session_id: &[u8]) -> Vec<u8> {
+	let salt = hmac::SigningKey::new(&digest::SHA256, session_id);
+	let mut resume_key = vec![0u8; 32];
+	hkdf::extract_and_expand(&salt, key, RESUME_INFO, &mut resume_key);
+
+	resume_key
+}
+
+/// Returns the nonce a reply of type `ty` to the message `seq` is sealed w/.
+/// A reply to the same message always carries the same payload, so a reply
+/// which is sent again (e.g: once a crashed receiver was restored) is sealed
+/// exactly as it was the first time, rather than reusing its nonce.
+pub fn reply_nonce(ty: u8, seq: u64) -> [u8; 12] {
+	let mut nonce = [0u8; 12];
+	NetworkEndian::write_u32(&mut nonce[0..4], u32::from(ty));
+	NetworkEndian::write_u64(&mut nonce[4..12], seq);
+	nonce
+}
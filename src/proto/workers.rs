@@ -0,0 +1,148 @@
+use crate::error::ProtoError;
+use crate::proto::reader::Region;
+use crate::proto::MESSAGE_SIZE;
+
+use ring::aead::{self, OpeningKey, SealingKey};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// How many blocks are handed to the pool at once for each of its threads,
+/// so that no thread sits idle while the others finish their share.
+pub(crate) const BLOCKS_PER_THREAD: usize = 4;
+
+/// A block which is sealed or opened in place.
+pub struct Block {
+	pub seq: u64,
+	pub nonce: Box<[u8]>,
+	pub header: [u8; MESSAGE_SIZE],
+
+	/// The plaintext followed by room for the tag when sealing, or the
+	/// ciphertext when opening. This holds just the plaintext once opened.
+	pub buf: Vec<u8>,
+
+	/// How many bytes precede the ciphertext in `buf` when opening. (e.g: the
+	/// header, if the whole message was read into it.)
+	pub prefix: usize,
+
+	/// The region of a mapped file which is copied into `buf` as the block
+	/// is sealed, if it was not read into `buf` already.
+	pub mapped: Option<Region>,
+}
+
+impl Block {
+	/// Seals the block w/ its header as associated data.
+	pub fn seal(&mut self, key: &SealingKey) -> Result<(), ProtoError> {
+		let tag_len = key.algorithm().tag_len();
+		if let Some(region) = self.mapped.take() {
+			self.buf = region.into_buf();
+			self.buf.resize(self.buf.len() + tag_len, 0);
+		}
+
+		aead::seal_in_place(key, &self.nonce, &self.header, &mut self.buf, tag_len)?;
+		Ok(())
+	}
+
+	/// Opens the block, returning false if it was not sealed w/ `key` or
+	/// was tampered with.
+	pub fn open(&mut self, key: &OpeningKey) -> bool {
+		match aead::open_in_place(key, &self.nonce, &self.header, self.prefix, &mut self.buf) {
+			Ok(plaintext) => {
+				let len = plaintext.len();
+				self.buf.truncate(len);
+				true
+			},
+
+			Err(_) => false,
+		}
+	}
+}
+
+enum Key {
+	Seal(Arc<SealingKey>),
+	Open(Arc<OpeningKey>),
+}
+
+struct Job {
+	key: Key,
+	index: usize,
+	block: Block,
+	done: mpsc::Sender<(usize, Block, bool)>,
+}
+
+/// A pool of threads which seal or open blocks in parallel, so that the
+/// cipher is not limited to the speed of a single core.
+///
+/// Blocks are handed to the pool in batches, and the batch is returned in
+/// the order it was given once every block in it is done. The threads exit
+/// when the pool is dropped.
+pub struct Workers {
+	jobs: mpsc::Sender<Job>,
+	threads: usize,
+}
+
+impl Workers {
+	pub fn new(threads: usize) -> Self {
+		let (jobs, queue) = mpsc::channel::<Job>();
+		let queue = Arc::new(Mutex::new(queue));
+
+		for _ in 0..threads {
+			let queue = Arc::clone(&queue);
+			thread::spawn(move || loop {
+				let job = queue.lock().expect("crypto queue poisoned").recv();
+				let Job { key, index, mut block, done } = match job {
+					Ok(job) => job,
+					Err(_) => return,
+				};
+
+				let ok = match key {
+					Key::Seal(key) => block.seal(&key).is_ok(),
+					Key::Open(key) => block.open(&key),
+				};
+
+				let _ = done.send((index, block, ok));
+			});
+		}
+
+		Self { jobs, threads }
+	}
+
+	/// The most blocks worth handing to the pool at once.
+	pub fn batch_len(&self) -> usize {
+		self.threads * BLOCKS_PER_THREAD
+	}
+
+	/// Seals every block in `blocks`.
+	pub fn seal(&self, key: &Arc<SealingKey>, blocks: Vec<Block>) -> Result<Vec<Block>, ProtoError> {
+		let mut sealed = Vec::with_capacity(blocks.len());
+		for (block, ok) in self.run(blocks, || Key::Seal(Arc::clone(key))) {
+			if !ok { return Err(ProtoError::CryptoErr); }
+			sealed.push(block);
+		}
+
+		Ok(sealed)
+	}
+
+	/// Opens every block in `blocks`, pairing each w/ whether it opened.
+	pub fn open(&self, key: &Arc<OpeningKey>, blocks: Vec<Block>) -> Vec<(Block, bool)> {
+		self.run(blocks, || Key::Open(Arc::clone(key)))
+	}
+
+	fn run<F: Fn() -> Key>(&self, blocks: Vec<Block>, key: F) -> Vec<(Block, bool)> {
+		let (done, results) = mpsc::channel();
+		let len = blocks.len();
+
+		for (index, block) in blocks.into_iter().enumerate() {
+			let job = Job { key: key(), index, block, done: done.clone() };
+			self.jobs.send(job).expect("crypto workers exited");
+		}
+
+		// only the jobs hold a sender now, so a worker which panics ends the wait
+		drop(done);
+
+		let mut finished: Vec<_> = results.iter().take(len).collect();
+		assert_eq!(finished.len(), len, "crypto worker exited before finishing its block");
+
+		finished.sort_by_key(|(index, _, _)| *index);
+		finished.into_iter().map(|(_, block, ok)| (block, ok)).collect()
+	}
+}
@@ -0,0 +1,133 @@
+use crate::error::ProtoError;
+use crate::proto::reader::Plain;
+use crate::proto::{Sender, Summary, BLOCK_SIZE};
+
+use std::io::{self, ErrorKind, Write};
+use std::mem;
+
+/// A `SenderWriter` sends everything written to it, so that a `Sender` can
+/// be used anywhere an `io::Write` is expected.
+///
+/// The handshake is performed on the first write (or flush), after which the
+/// written bytes are gathered into blocks which are sealed & sent as soon as
+/// each one is full. A `flush()` sends a partial block straight away, as
+/// does every write to a low latency sender. (See: `Sender::set_low_latency()`.)
+/// A line buffered sender's block is sent up to the end of each line instead.
+///
+/// `finish()` sends whatever is left & says goodbye to the receiver. A writer
+/// which is dropped does the same, but since it cannot report a failure it
+/// is only logged.
+///
+pub struct SenderWriter {
+	inner: Sender,
+	buf: Vec<u8>,
+	done: bool,
+}
+
+impl SenderWriter {
+	/// Wraps a `Sender` which has been configured, but not yet run.
+	pub fn new(sender: Sender) -> Self {
+		Self { inner: sender, buf: Vec::with_capacity(BLOCK_SIZE), done: false }
+	}
+
+	/// Returns the underlying `Sender`. (e.g: to check its `summary()`.)
+	pub fn get_ref(&self) -> &Sender {
+		&self.inner
+	}
+
+	/// Sends the last block & waits for the receiver to say goodbye, returning
+	/// a tally of the transfer.
+	pub fn finish(mut self) -> Result<Summary, ProtoError> {
+		self.end()?;
+		Ok(self.inner.summary().clone())
+	}
+
+	fn end(&mut self) -> Result<(), ProtoError> {
+		if self.done {
+			return Err(session_over().into());
+		}
+
+		let result = self.send_buffered().and_then(|_| self.inner.hang_up());
+		self.done = true;
+		self.inner.conclude(result)
+	}
+
+	/// Sends the block which has been gathered so far, if there is one.
+	fn send_buffered(&mut self) -> Result<(), ProtoError> {
+		self.inner.begin()?;
+		if self.buf.is_empty() {
+			return Ok(());
+		}
+
+		let chunk = mem::replace(&mut self.buf, Vec::with_capacity(BLOCK_SIZE));
+		self.inner.check_stopped()?;
+		self.inner.send_chunks(vec![Plain::Read(chunk)], &mut 0)?;
+		Ok(())
+	}
+
+	/// Ends a session which failed w/ `err`, hanging up on the receiver.
+	fn fail(&mut self, err: ProtoError) -> io::Error {
+		self.done = true;
+		self.inner.conclude(Err(err))
+			.expect_err("the session has failed")
+			.into()
+	}
+}
+
+impl Write for SenderWriter {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+		if self.done {
+			return Err(session_over());
+		}
+
+		if let Err(err) = self.inner.begin() {
+			return Err(self.fail(err));
+		}
+
+		let mut len = buf.len().min(BLOCK_SIZE - self.buf.len());
+		let line_end = buf[..len].iter().rposition(|&byte| byte == b'\n')
+			.filter(|_| self.inner.line_buffered());
+
+		if let Some(pos) = line_end {
+			len = pos + 1;
+		}
+
+		self.buf.extend_from_slice(&buf[..len]);
+
+		if self.buf.len() == BLOCK_SIZE || line_end.is_some() || self.inner.low_latency() {
+			if let Err(err) = self.send_buffered() {
+				return Err(self.fail(err));
+			}
+		}
+
+		Ok(len)
+	}
+
+	fn flush(&mut self) -> Result<(), io::Error> {
+		if self.done {
+			return Err(session_over());
+		}
+
+		match self.send_buffered() {
+			Ok(()) => Ok(()),
+			Err(err) => Err(self.fail(err)),
+		}
+	}
+}
+
+impl Drop for SenderWriter {
+	fn drop(&mut self) {
+		if self.done {
+			return;
+		}
+
+		if let Err(err) = self.end() {
+			warn!("could not finish the session as the writer was dropped: {}", err);
+		}
+	}
+}
+
+/// The error for a writer whose session has already ended.
+fn session_over() -> io::Error {
+	io::Error::new(ErrorKind::BrokenPipe, "the session is already over")
+}
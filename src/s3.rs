@@ -0,0 +1,387 @@
+use crate::checksum::hex;
+use crate::http;
+use ring::{digest, hmac};
+use std::env;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The scheme which marks an input or output as an S3 object.
+const SCHEME: &str = "s3://";
+
+/// The size of the first parts an upload is split into. S3 requires every
+/// part but the last to be at least 5 MiB, and allows at most 10,000 of them.
+/// The length of what is received isn't known when the upload starts, so the
+/// part size doubles every `PARTS_PER_SIZE` parts instead: the last parts are
+/// 4 GiB, (under S3's 5 GiB limit) which leaves room for S3's largest object.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+const PARTS_PER_SIZE: usize = 1_000;
+const MAX_PARTS: usize = 10_000;
+
+/// Returns true if `text` names an S3 object rather than a local file.
+pub fn is_url(text: &str) -> bool {
+	text.starts_with(SCHEME)
+}
+
+/// Starts downloading the object at `url`, i.e: `s3://bucket/key`.
+pub fn get(url: &str) -> Result<Box<dyn Read + Send>, io::Error> {
+	let client = Client::from_env()?;
+	let object = Object::parse(url)?;
+
+	let response = client.send("GET", &object, &[], &[])?;
+	Ok(Box::new(response.into_reader()))
+}
+
+/// The location of an object within a bucket.
+#[derive(Clone)]
+struct Object {
+	bucket: String,
+	key: String,
+}
+
+impl Object {
+	fn parse(url: &str) -> Result<Self, io::Error> {
+		let path = url.strip_prefix(SCHEME).unwrap_or(url);
+
+		match path.split_once('/') {
+			Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => {
+				Ok(Self { bucket: bucket.to_string(), key: key.to_string() })
+			},
+
+			_ => Err(invalid(format!("expected an object url of the form s3://bucket/key, not {}", url))),
+		}
+	}
+}
+
+/// Signs requests to S3 w/ the credentials found in the environment.
+///
+/// These are the usual `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, and
+/// (optionally) `AWS_SESSION_TOKEN`. The region is `AWS_REGION` or
+/// `AWS_DEFAULT_REGION`, defaulting to `us-east-1`. A compatible store can be
+/// used by setting `AWS_ENDPOINT_URL`, (i.e: `http://minio:9000`) which is
+/// addressed w/ the bucket in the path rather than in the host name.
+#[derive(Clone)]
+struct Client {
+	agent: ureq::Agent,
+
+	endpoint: Option<String>,
+	region: String,
+
+	access_key: String,
+	secret_key: String,
+	session_token: Option<String>,
+}
+
+impl Client {
+	fn from_env() -> Result<Self, io::Error> {
+		let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+
+		let access_key = var("AWS_ACCESS_KEY_ID")
+			.ok_or_else(|| invalid("AWS_ACCESS_KEY_ID must be set to use S3"))?;
+
+		let secret_key = var("AWS_SECRET_ACCESS_KEY")
+			.ok_or_else(|| invalid("AWS_SECRET_ACCESS_KEY must be set to use S3"))?;
+
+		Ok(Self {
+			agent: http::agent()?,
+
+			endpoint: var("AWS_ENDPOINT_URL").map(|url| url.trim_end_matches('/').to_string()),
+			region: var("AWS_REGION").or_else(|| var("AWS_DEFAULT_REGION")).unwrap_or_else(|| "us-east-1".to_string()),
+
+			access_key,
+			secret_key,
+			session_token: var("AWS_SESSION_TOKEN"),
+		})
+	}
+
+	/// Sends a request for `object` w/ a SigV4 signature, failing if S3 does not
+	/// answer w/ a success.
+	fn send(&self, method: &str, object: &Object, query: &[(&str, &str)], body: &[u8]) -> Result<ureq::Response, io::Error> {
+		let key = encode(&object.key, false);
+		let (base, path) = match self.endpoint {
+			Some(ref endpoint) => (endpoint.clone(), format!("/{}/{}", encode(&object.bucket, true), key)),
+			None => (format!("https://{}.s3.{}.amazonaws.com", object.bucket, self.region), format!("/{}", key)),
+		};
+
+		let host = base.split_once("://").map_or(base.as_str(), |(_, host)| host);
+
+		let mut query: Vec<_> = query.iter()
+			.map(|(name, value)| format!("{}={}", encode(name, true), encode(value, true)))
+			.collect();
+		query.sort();
+		let query = query.join("&");
+
+		let (date, timestamp) = now();
+		let payload_hash = hex(digest::digest(&digest::SHA256, body).as_ref());
+
+		let mut headers = vec![
+			("host", host.to_string()),
+			("x-amz-content-sha256", payload_hash.clone()),
+			("x-amz-date", timestamp.clone()),
+		];
+
+		if let Some(ref token) = self.session_token {
+			headers.push(("x-amz-security-token", token.clone()));
+		}
+
+		let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+		let canonical_headers: String = headers.iter()
+			.map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+			.collect();
+
+		let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}",
+			method, path, query, canonical_headers, signed_headers, payload_hash);
+
+		let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+		let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}",
+			timestamp, scope, hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref()));
+
+		let signing_key = [date.as_str(), self.region.as_str(), "s3", "aws4_request"].iter()
+			.fold(format!("AWS4{}", self.secret_key).into_bytes(), |key, part| sign(&key, part.as_bytes()));
+
+		let authorization = format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+			self.access_key, scope, signed_headers, hex(&sign(&signing_key, string_to_sign.as_bytes())));
+
+		let url = if query.is_empty() {
+			format!("{}{}", base, path)
+		} else {
+			format!("{}{}?{}", base, path, query)
+		};
+
+		debug!("{} {}", method, url);
+		let mut request = self.agent.request(method, &url)
+			.set("authorization", &authorization);
+
+		for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+			request = request.set(name, value);
+		}
+
+		match request.send_bytes(body) {
+			Ok(response) => Ok(response),
+
+			Err(ureq::Error::Status(status, response)) => {
+				let body = response.into_string().unwrap_or_default();
+				let message = element(&body, "Message").unwrap_or(&body);
+				Err(io::Error::other(format!("S3 answered {} w/ {}: {}", method, status, message.trim())))
+			},
+
+			Err(err) => Err(io::Error::other(err)),
+		}
+	}
+}
+
+/// Writes what it is given to an S3 object.
+///
+/// The data is buffered into parts which are sent w/ a multipart upload as
+/// each one fills up, so the object never has to be stored locally. An
+/// object smaller than one part is sent in a single request instead. The
+/// parts are sent by a thread of their own, so the next part is filled while
+/// the last one is uploaded. The object only appears once `finish()` is
+/// called, if the `Upload` is dropped before then the parts which were sent
+/// are discarded.
+pub struct Upload {
+	client: Client,
+	object: Object,
+
+	buf: Vec<u8>,
+	upload_id: Option<String>,
+	parts: usize,
+
+	tx: Option<SyncSender<(usize, Vec<u8>)>>,
+	uploader: Option<JoinHandle<Result<Vec<String>, io::Error>>>,
+}
+
+impl Upload {
+	/// Prepares to write the object at `url`, i.e: `s3://bucket/key`. Nothing
+	/// is sent to S3 until the first part is full.
+	pub fn new(url: &str) -> Result<Self, io::Error> {
+		Ok(Self {
+			client: Client::from_env()?,
+			object: Object::parse(url)?,
+
+			buf: Vec::with_capacity(PART_SIZE),
+			upload_id: None,
+			parts: 0,
+
+			tx: None,
+			uploader: None,
+		})
+	}
+
+	/// Sends whatever remains buffered, and completes the object.
+	pub fn finish(mut self) -> Result<(), io::Error> {
+		if self.upload_id.is_none() {
+			let body = mem::take(&mut self.buf);
+			self.client.send("PUT", &self.object, &[], &body)?;
+			return Ok(());
+		}
+
+		if !self.buf.is_empty() {
+			self.send_part()?;
+		}
+
+		let etags = self.join()?;
+		let mut body = String::from("<CompleteMultipartUpload>");
+		for (number, etag) in etags.iter().enumerate() {
+			body.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", number + 1, etag));
+		}
+		body.push_str("</CompleteMultipartUpload>");
+
+		let upload_id = self.upload_id.clone().expect("multipart upload was started");
+		let response = self.client.send("POST", &self.object, &[("uploadId", &upload_id)], body.as_bytes());
+
+		// S3 may report a failure to complete the upload in a successful response
+		let response = response?.into_string()?;
+		if let Some(message) = element(&response, "Message") {
+			return Err(io::Error::other(format!("S3 could not complete the upload: {}", message)));
+		}
+
+		self.upload_id = None;
+		Ok(())
+	}
+
+	/// Hands the buffered part to the uploader, starting the multipart upload
+	/// (and the uploader) w/ the first part. This only waits for the uploader
+	/// to be done w/ the part before, an error it ran into is returned here.
+	fn send_part(&mut self) -> Result<(), io::Error> {
+		if self.upload_id.is_none() {
+			let response = self.client.send("POST", &self.object, &[("uploads", "")], &[])?.into_string()?;
+			let upload_id = element(&response, "UploadId")
+				.ok_or_else(|| io::Error::other("S3 did not return an upload id"))?
+				.to_string();
+
+			info!("started multipart upload {}", upload_id);
+			let (tx, rx) = mpsc::sync_channel(0);
+			let (client, object, id) = (self.client.clone(), self.object.clone(), upload_id.clone());
+			self.uploader = Some(thread::spawn(move || upload_parts(client, object, &id, rx)));
+			self.upload_id = Some(upload_id);
+			self.tx = Some(tx);
+		}
+
+		self.parts += 1;
+		if self.parts > MAX_PARTS {
+			return Err(io::Error::other(format!("the object is too large to upload, S3 allows at most {} parts", MAX_PARTS)));
+		}
+
+		let part = mem::replace(&mut self.buf, Vec::with_capacity(PART_SIZE));
+		let sent = self.tx.as_ref().map(|tx| tx.send((self.parts, part)));
+
+		match sent {
+			Some(Ok(())) => Ok(()),
+			_ => Err(self.join().err().unwrap_or_else(|| io::Error::other("the S3 uploader stopped before the upload was finished"))),
+		}
+	}
+
+	/// Waits for the uploader to send every part it was given, returning the
+	/// etags of the parts in order.
+	fn join(&mut self) -> Result<Vec<String>, io::Error> {
+		self.tx = None;
+
+		match self.uploader.take().map(|uploader| uploader.join()) {
+			Some(Ok(result)) => result,
+			Some(Err(_)) => Err(io::Error::other("the S3 uploader panicked")),
+			None => Err(io::Error::other("the S3 uploader has already stopped")),
+		}
+	}
+}
+
+/// Sends each part it receives, until the `Upload` hangs up or S3 refuses one.
+fn upload_parts(client: Client, object: Object, upload_id: &str, rx: mpsc::Receiver<(usize, Vec<u8>)>) -> Result<Vec<String>, io::Error> {
+	let mut etags = vec![];
+
+	for (number, part) in rx {
+		let query = [("partNumber", number.to_string()), ("uploadId", upload_id.to_string())];
+		let query: Vec<_> = query.iter().map(|(name, value)| (*name, value.as_str())).collect();
+
+		let response = client.send("PUT", &object, &query, &part)?;
+		let etag = response.header("etag")
+			.ok_or_else(|| io::Error::other("S3 did not return an etag for the part"))?;
+
+		debug!("sent part #{} ({} bytes)", number, part.len());
+		etags.push(etag.to_string());
+	}
+
+	Ok(etags)
+}
+
+/// The size of the `number`th part of an upload, counting from 1.
+fn part_size(number: usize) -> usize {
+	PART_SIZE << ((number - 1) / PARTS_PER_SIZE).min(MAX_PARTS / PARTS_PER_SIZE - 1)
+}
+
+impl Write for Upload {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+		let part_size = part_size(self.parts + 1);
+		let len = buf.len().min(part_size - self.buf.len());
+		self.buf.extend_from_slice(&buf[..len]);
+
+		if self.buf.len() == part_size {
+			self.send_part()?;
+		}
+
+		Ok(len)
+	}
+
+	fn flush(&mut self) -> Result<(), io::Error> {
+		Ok(())
+	}
+}
+
+impl Drop for Upload {
+	fn drop(&mut self) {
+		if let Some(upload_id) = self.upload_id.take() {
+			warn!("discarding incomplete upload of s3://{}/{}", self.object.bucket, self.object.key);
+
+			// the parts still being sent must be done before the upload is aborted
+			let _ = self.join();
+			if let Err(err) = self.client.send("DELETE", &self.object, &[("uploadId", &upload_id)], &[]) {
+				warn!("could not abort multipart upload {}: {}", upload_id, err);
+			}
+		}
+	}
+}
+
+/// Returns the current UTC date (`YYYYMMDD`) & time (`YYYYMMDDTHHMMSSZ`) in
+/// the forms SigV4 expects.
+fn now() -> (String, String) {
+	let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+		.map(|since| since.as_secs())
+		.unwrap_or_default();
+
+	let (year, month, day) = crate::civil_from_days((timestamp / 86_400) as i64);
+	let seconds = timestamp % 86_400;
+
+	let date = format!("{:04}{:02}{:02}", year, month, day);
+	let time = format!("{}T{:02}{:02}{:02}Z", date, seconds / 3600, seconds / 60 % 60, seconds % 60);
+	(date, time)
+}
+
+fn sign(key: &[u8], data: &[u8]) -> Vec<u8> {
+	let key = hmac::SigningKey::new(&digest::SHA256, key);
+	hmac::sign(&key, data).as_ref().to_vec()
+}
+
+/// Percent-encodes `text` as SigV4 requires, `/` is left alone in paths.
+fn encode(text: &str, encode_slash: bool) -> String {
+	text.bytes()
+		.map(|byte| match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+			b'/' if !encode_slash => "/".to_string(),
+			_ => format!("%{:02X}", byte),
+		})
+		.collect()
+}
+
+/// Returns the text of the first `<name>` element in an XML response. The
+/// responses we care about are simple enough not to need a parser.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+	let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+	let len = xml[start..].find(&format!("</{}>", name))?;
+	Some(&xml[start..start + len])
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidInput, err)
+}
@@ -0,0 +1,229 @@
+//! UDT's own congestion control, which paces each connection's packets.
+//! (See: UDT4's `CUDTCC`.)
+//!
+//! It starts in slow start, where the window grows by what each ACK
+//! acknowledges until it outgrows the receiver's. From then on the sender
+//! is paced to a gap between packets, which shrinks a little every rate
+//! control interval (by more when the link has room to spare) and grows by
+//! an eighth when a loss is reported.
+//!
+//! A connection can be given a `Controller` in its place (See:
+//! `UdtSocket::set_congestion()`), which is then told of what is acked &
+//! lost and decides the window alone, w/ the packets in it sent unpaced.
+
+use crate::proto::Controller;
+
+use rand::Rng;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+/// How often the rate is increased, in microseconds.
+const RC_INTERVAL: f64 = 10_000.0;
+
+/// The least the rate is increased by, in packets per interval.
+const MIN_INC: f64 = 0.01;
+
+pub(super) struct Cc {
+	mss: f64,
+
+	/// The gap between packets, in microseconds.
+	pub(super) period: f64,
+
+	/// How many packets may be unacknowledged.
+	pub(super) cwnd: f64,
+
+	/// The largest the window may be, which is the receiver's.
+	max_cwnd: f64,
+
+	/// When the rate was last increased, in microseconds since the
+	/// connection was made.
+	last_rc_time: u64,
+	slow_start: bool,
+	last_ack: u64,
+	loss: bool,
+	last_dec_seq: i64,
+	last_dec_period: f64,
+	nak_count: i32,
+	dec_random: i32,
+	avg_nak_num: i32,
+	dec_count: i32,
+
+	/// What decides the window in place of the above, if anything does.
+	controller: Option<Box<dyn Controller>>,
+}
+
+/// What the connection knows of the link, which the congestion control
+/// works from.
+pub(super) struct Link {
+	/// In microseconds, as are the times it is called at.
+	pub rtt: i32,
+
+	/// The rate packets are delivered at, & the link's capacity, both in
+	/// packets per second.
+	pub rcv_rate: i32,
+	pub bandwidth: i32,
+
+	/// The (absolute) sequence number of the last packet which was sent, or
+	/// -1 if none has been.
+	pub snd_curr: i64,
+}
+
+impl Cc {
+	pub(super) fn new(mss: i32, max_cwnd: i32, now: u64, controller: Option<Box<dyn Controller>>) -> Self {
+		Self {
+			mss: f64::from(mss),
+			period: 1.0,
+			cwnd: 16.0,
+			max_cwnd: f64::from(max_cwnd),
+			last_rc_time: now,
+			slow_start: true,
+			last_ack: 0,
+			loss: false,
+			last_dec_seq: -1,
+			last_dec_period: 1.0,
+			nak_count: 0,
+			dec_random: 1,
+			avg_nak_num: 0,
+			dec_count: 0,
+			controller,
+		}
+	}
+
+	/// Leaves slow start, pacing the sender to the rate packets are being
+	/// delivered at (or if that is not known yet, to its window.)
+	fn end_slow_start(&mut self, link: &Link) -> bool {
+		if !self.slow_start {
+			return false;
+		}
+
+		self.slow_start = false;
+		if link.rcv_rate > 0 {
+			self.period = 1e6 / f64::from(link.rcv_rate);
+			return true;
+		}
+
+		self.period = self.cwnd / (f64::from(link.rtt) + RC_INTERVAL);
+		false
+	}
+
+	/// Everything before `ack` was acknowledged.
+	pub(super) fn on_ack(&mut self, ack: u64, link: &Link, now: u64) {
+		if let Some(controller) = &mut self.controller {
+			controller.on_ack(ack.saturating_sub(self.last_ack) as usize, link.rtt());
+			self.last_ack = self.last_ack.max(ack);
+			return;
+		}
+
+		if now.saturating_sub(self.last_rc_time) < RC_INTERVAL as u64 {
+			return;
+		}
+
+		self.last_rc_time = now;
+
+		if self.slow_start {
+			self.cwnd += ack.saturating_sub(self.last_ack) as f64 + 1.0;
+			self.last_ack = ack;
+
+			if self.cwnd > self.max_cwnd {
+				self.slow_start = false;
+				self.period = match link.rcv_rate {
+					rate if rate > 0 => 1e6 / f64::from(rate),
+					_ => (f64::from(link.rtt) + RC_INTERVAL) / self.cwnd,
+				};
+			}
+
+			return;
+		}
+
+		self.cwnd = f64::from(link.rcv_rate) / 1e6 * (f64::from(link.rtt) + RC_INTERVAL) + 16.0;
+
+		if self.loss {
+			self.loss = false;
+			return;
+		}
+
+		let bandwidth = f64::from(link.bandwidth);
+		let mut spare = (bandwidth - 1e6 / self.period).trunc();
+		if self.period > self.last_dec_period && bandwidth / 9.0 < spare {
+			spare = (bandwidth / 9.0).trunc();
+		}
+
+		let inc = match spare {
+			spare if spare <= 0.0 => MIN_INC,
+			spare => (10f64.powf((spare * self.mss * 8.0).log10().ceil()) * 0.000_001_5 / self.mss).max(MIN_INC),
+		};
+
+		self.period = (self.period * RC_INTERVAL) / (self.period * inc + RC_INTERVAL);
+	}
+
+	/// The receiver reported that `lost` packets were lost, the first of
+	/// them being `first`.
+	pub(super) fn on_loss(&mut self, first: u64, lost: usize, link: &Link) {
+		if let Some(controller) = &mut self.controller {
+			controller.on_loss(lost, link.rtt());
+			return;
+		}
+
+		if self.end_slow_start(link) {
+			return;
+		}
+
+		self.loss = true;
+
+		if first as i64 > self.last_dec_seq {
+			self.last_dec_period = self.period;
+			self.period = (self.period * 1.125).ceil();
+
+			self.avg_nak_num = (f64::from(self.avg_nak_num) * 0.875 + f64::from(self.nak_count) * 0.125).ceil() as i32;
+			self.nak_count = 1;
+			self.dec_count = 1;
+			self.last_dec_seq = link.snd_curr;
+
+			// spreads out when the senders sharing a link slow down
+			let random = f64::from(self.avg_nak_num) * rand::thread_rng().gen::<f64>();
+			self.dec_random = (random.ceil() as i32).max(1);
+			return;
+		}
+
+		// 0.875^5 is about a half, which is as far as the rate falls in one
+		// congestion period
+		self.dec_count += 1;
+		if self.dec_count <= 5 {
+			self.nak_count += 1;
+			if self.nak_count % self.dec_random == 0 {
+				self.period = (self.period * 1.125).ceil();
+				self.last_dec_seq = link.snd_curr;
+			}
+		}
+	}
+
+	/// Nothing was heard from the receiver for a while, so the `in_flight`
+	/// packets are being sent again. (Which a controller is told of as a
+	/// loss.)
+	pub(super) fn on_timeout(&mut self, in_flight: usize, link: &Link) {
+		if let Some(controller) = &mut self.controller {
+			if in_flight > 0 {
+				controller.on_loss(in_flight, link.rtt());
+			}
+
+			return;
+		}
+
+		self.end_slow_start(link);
+	}
+
+	/// How many packets may be in flight, given the receiver's own window.
+	pub(super) fn window(&self, flow_window: i64) -> i64 {
+		match &self.controller {
+			Some(controller) => flow_window.min(i64::try_from(controller.window()).unwrap_or(i64::MAX)),
+			None => flow_window.min(self.cwnd as i64),
+		}
+	}
+}
+
+impl Link {
+	/// The round-trip time, as a controller is told it.
+	fn rtt(&self) -> Option<Duration> {
+		u64::try_from(self.rtt).ok().filter(|&rtt| rtt > 0).map(Duration::from_micros)
+	}
+}
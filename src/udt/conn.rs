@@ -0,0 +1,1111 @@
+//! A connected UDT socket: what it has sent & received, its timers, and the
+//! thread which paces its packets out. (See: UDT4's `core.cpp`.)
+//!
+//! Everything the connection knows is kept in its `State`, which is shared
+//! by the thread reading the socket's `Mux` (which hands it every packet
+//! from the peer & checks its timers), its sender thread, and whichever
+//! threads read & write the socket. The packets a change calls for are left
+//! in the state's outbox, and sent once it is unlocked.
+
+use super::cc::{Cc, Link};
+use super::epoll;
+use super::loss::LossList;
+use super::mux::Mux;
+use super::packet::{self, Control, Packet, MAX_MSG_NO, MAX_SEQ, MSG_FIRST, MSG_IN_ORDER, MSG_LAST};
+use super::raw::TraceInfo;
+use super::window::{AckWindow, TimeWindow};
+use super::{SocketType, UdtError};
+
+use crate::proto::Controller;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the receiver sends an ACK, and the rate is adjusted. (UDT's
+/// SYN interval.)
+pub(super) const SYN: Duration = Duration::from_millis(10);
+
+/// A light ACK (which only says how far the receiver has got) is sent after
+/// every this many packets, between the regular ones.
+const LIGHT_ACK_PACKETS: u32 = 64;
+
+/// The least time the peer is given to answer, before the packets it has
+/// not acknowledged are sent again. (This grows w/ each time it runs out.)
+const MIN_EXP: Duration = Duration::from_millis(300);
+
+/// The connection is broken once the peer has not answered this many
+/// times, and not for at least `EXP_IDLE`.
+const EXP_LIMIT: u32 = 16;
+const EXP_IDLE: Duration = Duration::from_secs(5);
+
+/// How long closing a socket waits for what was sent on it to arrive.
+const LINGER: Duration = Duration::from_secs(180);
+
+/// The largest flow window a receiver offers, in packets.
+pub(super) const FLIGHT_FLAG: i32 = 25_600;
+
+/// What the handshake settled on for a new connection.
+pub(super) struct Setup {
+	pub id: i32,
+	pub ty: SocketType,
+	pub peer: SocketAddr,
+	pub peer_id: i32,
+
+	/// The first sequence number, in either direction.
+	pub isn: u32,
+	pub mss: i32,
+
+	/// How many packets the peer can take at once.
+	pub flow_window: i32,
+
+	/// The size of the send & receive buffers, in packets.
+	pub snd_buf: usize,
+	pub rcv_buf: usize,
+
+	/// What replaces UDT's own congestion control, if anything does.
+	pub controller: Option<Box<dyn Controller>>,
+}
+
+pub(super) struct Conn {
+	pub(super) id: i32,
+	pub(super) peer: SocketAddr,
+	ty: SocketType,
+	mux: Arc<Mux>,
+	state: Mutex<State>,
+
+	/// Signalled when there is something to read, or the connection is over.
+	readable: Condvar,
+
+	/// Signalled when room is made in the send buffer, or it is over.
+	writable: Condvar,
+
+	/// Signalled when the sender thread may have something more to send.
+	sendable: Condvar,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+	Connected,
+
+	/// The peer shut the connection down, or stopped answering. What it sent
+	/// before then may still be read.
+	Broken,
+
+	/// The socket was closed.
+	Closed,
+}
+
+/// A packet's worth of what was written, which is kept until it has been
+/// acknowledged.
+struct Block {
+	payload: Vec<u8>,
+	msg: u32,
+	written: Instant,
+
+	/// How long the message may go undelivered before it is dropped, rather
+	/// than sent again. (`None` never expires.)
+	ttl: Option<Duration>,
+}
+
+/// A packet which arrived ahead of one before it, or a placeholder for one
+/// which was `dropped` by the sender.
+struct Unit {
+	msg: u32,
+	payload: Vec<u8>,
+	dropped: bool,
+}
+
+/// What arrived in order, and is waiting to be read.
+#[derive(Default)]
+struct Received {
+	/// Each packet of a stream, or each whole message of a datagram socket,
+	/// w/ how many packets it took.
+	ready: VecDeque<(Vec<u8>, usize)>,
+
+	/// How much of the first packet of a stream has been read.
+	pos: usize,
+
+	/// The message which is still arriving, w/ its number.
+	partial: Option<(u32, Vec<u8>, usize)>,
+
+	/// How many packets are held, which count against the receive buffer.
+	units: usize,
+	ready_units: usize,
+}
+
+impl Received {
+	/// Adds the next packet which arrived in order, returning true if there
+	/// is more to read as a result.
+	fn push(&mut self, ty: SocketType, unit: Unit) -> bool {
+		if ty == SocketType::Stream {
+			self.ready.push_back((unit.payload, 1));
+			self.units += 1;
+			self.ready_units += 1;
+			return true;
+		}
+
+		let msg_no = unit.msg & MAX_MSG_NO;
+		if unit.dropped {
+			if self.partial.as_ref().is_some_and(|&(partial, _, _)| partial == msg_no) {
+				self.discard_partial();
+			}
+
+			return false;
+		}
+
+		if unit.msg & MSG_FIRST != 0 {
+			self.discard_partial();
+			self.partial = Some((msg_no, vec![], 0));
+		}
+
+		// what is left of a message whose start was dropped
+		let (_, message, units) = match &mut self.partial {
+			Some(partial) if partial.0 == msg_no => partial,
+			_ => return false,
+		};
+
+		message.extend_from_slice(&unit.payload);
+		*units += 1;
+		self.units += 1;
+
+		if unit.msg & MSG_LAST == 0 {
+			return false;
+		}
+
+		let (_, message, units) = self.partial.take().expect("a message was just added to");
+		self.ready.push_back((message, units));
+		self.ready_units += units;
+		true
+	}
+
+	fn discard_partial(&mut self) {
+		if let Some((_, _, units)) = self.partial.take() {
+			self.units -= units;
+		}
+	}
+
+	/// Reads as much of the stream as fits in `buf`.
+	fn read(&mut self, buf: &mut [u8]) -> usize {
+		let mut len = 0;
+		while len < buf.len() {
+			let (chunk, _) = match self.ready.front() {
+				Some(chunk) => chunk,
+				None => break,
+			};
+
+			let n = (chunk.len() - self.pos).min(buf.len() - len);
+			buf[len..len + n].copy_from_slice(&chunk[self.pos..self.pos + n]);
+			len += n;
+			self.pos += n;
+
+			if self.pos == chunk.len() {
+				self.ready.pop_front();
+				self.pos = 0;
+				self.units -= 1;
+				self.ready_units -= 1;
+			}
+		}
+
+		len
+	}
+
+	/// Reads the next message, which is cut short if it does not fit in
+	/// `buf`.
+	fn read_message(&mut self, buf: &mut [u8]) -> Option<usize> {
+		let (message, units) = self.ready.pop_front()?;
+		self.units -= units;
+		self.ready_units -= units;
+
+		let len = message.len().min(buf.len());
+		buf[..len].copy_from_slice(&message[..len]);
+		Some(len)
+	}
+}
+
+/// Counts what the performance monitor reports.
+#[derive(Default)]
+struct Trace {
+	sent: i64,
+	snd_loss: i32,
+	rcv_loss: i32,
+	retrans: i32,
+}
+
+/// Everything the connection knows. Sequence numbers are counted from the
+/// first one (so they never wrap) and only turned into the 31 bit ones on
+/// the wire as packets are written & read.
+struct State {
+	status: Status,
+	peer_shutdown: bool,
+	ty: SocketType,
+	peer_id: i32,
+	isn: u32,
+	started: Instant,
+	payload_len: usize,
+	outbox: Vec<Vec<u8>>,
+
+	// sending
+	snd_buf: VecDeque<Block>,
+	snd_cap: usize,
+
+	/// The next packet which has not been sent yet.
+	snd_next: u64,
+
+	/// How far the peer said it had received, & how far that was in a full
+	/// ACK. (The send buffer is only freed up to the latter.)
+	snd_last_ack: u64,
+	snd_last_data_ack: u64,
+
+	snd_loss: LossList,
+	flow_window: i64,
+	next_msg: u32,
+	cc: Cc,
+
+	// the round trip time & its variance, in microseconds
+	rtt: i32,
+	rtt_var: i32,
+
+	// in packets per second
+	delivery_rate: i32,
+	bandwidth: i32,
+
+	// receiving
+	received: Received,
+
+	/// The next packet which has not arrived in order.
+	rcv_next: u64,
+
+	/// One past the furthest packet which has arrived.
+	rcv_max: u64,
+
+	/// The packets which arrived ahead of `rcv_next`.
+	rcv_units: BTreeMap<u64, Unit>,
+	rcv_cap: usize,
+
+	rcv_last_ack: u64,
+
+	/// The furthest ACK which the peer has acknowledged w/ an ACK2, which
+	/// does not need to be sent again.
+	rcv_last_ack_ack: u64,
+	ack_no: i32,
+	acks: AckWindow,
+	last_ack_time: Instant,
+	next_ack_time: Instant,
+	pkt_count: u32,
+	light_acks: u32,
+	arrivals: TimeWindow,
+
+	// timers
+	last_rsp: Instant,
+	exp_count: u32,
+
+	trace: Trace,
+}
+
+impl State {
+	fn micros(&self, now: Instant) -> u64 {
+		now.saturating_duration_since(self.started).as_micros() as u64
+	}
+
+	fn wire(&self, seq: u64) -> u32 {
+		packet::seq_add(self.isn, seq)
+	}
+
+	/// How far the sequence number `wire` is from `near`, which it is
+	/// assumed to be close to.
+	fn offset(&self, wire: u32, near: u64) -> i64 {
+		near as i64 + packet::seq_off(self.wire(near), wire)
+	}
+
+	fn control(&mut self, ty: Control, info: u32, body: &[u32]) {
+		let ts = self.micros(Instant::now()) as u32;
+		self.outbox.push(packet::control(ty, info, ts, self.peer_id, body));
+	}
+
+	fn link(&self) -> Link {
+		Link {
+			rtt: self.rtt,
+			rcv_rate: self.delivery_rate,
+			bandwidth: self.bandwidth,
+			snd_curr: self.snd_next as i64 - 1,
+		}
+	}
+
+	fn update_rtt(&mut self, rtt: i32) {
+		self.rtt_var = (self.rtt_var * 3 + (rtt - self.rtt).abs()) >> 2;
+		self.rtt = (self.rtt * 7 + rtt) >> 3;
+	}
+
+	fn round_trip(&self) -> Duration {
+		Duration::from_micros((self.rtt + 4 * self.rtt_var).max(0) as u64)
+	}
+
+	/// How much more the receive buffer can take, in packets.
+	fn rcv_room(&self) -> usize {
+		self.rcv_cap.saturating_sub(self.received.units + 1)
+	}
+
+	/// Queues `buf` to be sent as one message, in as many packets as it
+	/// takes.
+	fn write(&mut self, buf: &[u8], ttl: Option<Duration>, in_order: bool) {
+		let now = Instant::now();
+		let order = if in_order { MSG_IN_ORDER } else { 0 };
+		let chunks = buf.chunks(self.payload_len).count();
+
+		for (i, chunk) in buf.chunks(self.payload_len).enumerate() {
+			let mut msg = self.next_msg | order;
+			if i == 0 {
+				msg |= MSG_FIRST;
+			}
+
+			if i + 1 == chunks {
+				msg |= MSG_LAST;
+			}
+
+			self.snd_buf.push_back(Block { payload: chunk.to_vec(), msg, written: now, ttl });
+		}
+
+		self.next_msg = match self.next_msg + 1 {
+			MAX_MSG_NO => 1,
+			next => next,
+		};
+	}
+
+	/// Picks the next packet to send, returning it & whether the one after
+	/// it should be sent straight away. (i.e: this is a probe.) Anything the
+	/// peer reported lost goes before anything new.
+	fn pack(&mut self, now: Instant) -> Option<(Vec<u8>, bool)> {
+		let ts = self.micros(now) as u32;
+
+		while let Some(seq) = self.snd_loss.pop_first() {
+			if seq < self.snd_last_data_ack {
+				continue;
+			}
+
+			let index = (seq - self.snd_last_data_ack) as usize;
+			let block = match self.snd_buf.get(index) {
+				Some(block) => block,
+				None => continue,
+			};
+
+			if block.ttl.is_some_and(|ttl| now.saturating_duration_since(block.written) > ttl) {
+				self.drop_message(seq, index);
+				continue;
+			}
+
+			self.trace.retrans += 1;
+			self.trace.sent += 1;
+			return Some((packet::data(self.wire(seq), block.msg, ts, self.peer_id, &block.payload), false));
+		}
+
+		let in_flight = (self.snd_next + 1).saturating_sub(self.snd_last_ack) as i64;
+		if self.cc.window(self.flow_window) < in_flight {
+			return None;
+		}
+
+		let index = (self.snd_next - self.snd_last_data_ack) as usize;
+		let block = self.snd_buf.get(index)?;
+		let seq = self.snd_next;
+		self.snd_next += 1;
+		self.trace.sent += 1;
+
+		// every 16th packet is sent right before the next, as a probe pair
+		let wire = self.wire(seq);
+		Some((packet::data(wire, block.msg, ts, self.peer_id, &block.payload), wire & 0xF == 0))
+	}
+
+	/// Tells the peer to give up on the message whose packet `seq` expired
+	/// before it could be delivered, and skips the rest of it.
+	fn drop_message(&mut self, seq: u64, index: usize) {
+		let msg = self.snd_buf[index].msg;
+		let msg_no = msg & MAX_MSG_NO;
+		let len = self.snd_buf.iter().skip(index)
+			.take_while(|block| block.msg & MAX_MSG_NO == msg_no)
+			.count() as u64;
+
+		let last = seq + len - 1;
+		let body = [self.wire(seq), self.wire(last)];
+		self.control(Control::DropRequest, msg, &body);
+
+		self.snd_loss.remove_through(last);
+		self.snd_next = self.snd_next.max(last + 1);
+	}
+
+	fn on_data(&mut self, seq: u32, msg: u32, payload: &[u8], now: Instant) -> bool {
+		self.pkt_count += 1;
+		self.arrivals.on_arrival(now);
+
+		match seq & 0xF {
+			0 => self.arrivals.on_probe1(now),
+			1 => self.arrivals.on_probe2(now),
+			_ => {},
+		}
+
+		let seq = self.offset(seq, self.rcv_next);
+		if seq < self.rcv_next as i64 {
+			return false;
+		}
+
+		let seq = seq as u64;
+		if (seq - self.rcv_next) as usize >= self.rcv_room() || self.rcv_units.contains_key(&seq) {
+			return false;
+		}
+
+		// a gap is reported as soon as it is seen
+		if seq > self.rcv_max {
+			let (first, last) = (self.wire(self.rcv_max), self.wire(seq - 1));
+			match first == last {
+				true => self.control(Control::Nak, 0, &[last]),
+				false => self.control(Control::Nak, 0, &[first | 0x8000_0000, last]),
+			}
+
+			self.trace.rcv_loss += (seq - self.rcv_max) as i32;
+		}
+
+		self.rcv_max = self.rcv_max.max(seq + 1);
+
+		// a short packet is usually the end of a message, which is
+		// acknowledged straight away
+		if payload.len() != self.payload_len {
+			self.next_ack_time = now;
+		}
+
+		self.rcv_units.insert(seq, Unit { msg, payload: payload.to_vec(), dropped: false });
+		self.deliver()
+	}
+
+	/// Hands over each packet which is now in order, returning true if there
+	/// is more to read as a result.
+	fn deliver(&mut self) -> bool {
+		let mut delivered = false;
+		while let Some(unit) = self.rcv_units.remove(&self.rcv_next) {
+			self.rcv_next += 1;
+			delivered |= self.received.push(self.ty, unit);
+		}
+
+		delivered
+	}
+
+	/// The sender gave up on the message `msg`, whose packets are `first ..=
+	/// last`.
+	fn on_drop(&mut self, msg: u32, first: u32, last: u32) -> bool {
+		let msg_no = msg & MAX_MSG_NO;
+		let first = self.offset(first, self.rcv_next).max(self.rcv_next as i64) as u64;
+		let last = self.offset(last, self.rcv_next);
+		if last < self.rcv_next as i64 {
+			return self.received.push(self.ty, Unit { msg: msg_no, payload: vec![], dropped: true });
+		}
+
+		let last = (last as u64).min(self.rcv_next + self.rcv_cap as u64);
+		self.received.push(self.ty, Unit { msg: msg_no, payload: vec![], dropped: true });
+
+		for seq in first..=last {
+			let unit = self.rcv_units.entry(seq)
+				.or_insert(Unit { msg: msg_no, payload: vec![], dropped: true });
+
+			if unit.msg & MAX_MSG_NO == msg_no {
+				unit.dropped = true;
+			}
+		}
+
+		self.rcv_max = self.rcv_max.max(last + 1);
+		self.deliver()
+	}
+
+	/// Sends an ACK, unless the peer already has it. A light one only says
+	/// how far the receiver has got.
+	fn ack(&mut self, now: Instant, light: bool) {
+		let ack = self.rcv_next;
+		if ack == self.rcv_last_ack_ack {
+			return;
+		}
+
+		if light {
+			self.control(Control::Ack, 0, &[self.wire(ack)]);
+			return;
+		}
+
+		// the same ACK is only sent again once it could have been answered
+		if ack > self.rcv_last_ack {
+			self.rcv_last_ack = ack;
+		} else if ack < self.rcv_last_ack || now.saturating_duration_since(self.last_ack_time) < self.round_trip() {
+			return;
+		}
+
+		if self.rcv_last_ack <= self.rcv_last_ack_ack {
+			return;
+		}
+
+		self.ack_no = match self.ack_no {
+			no if no == MAX_SEQ as i32 => 0,
+			no => no + 1,
+		};
+
+		let mut body = vec![self.wire(ack), self.rtt as u32, self.rtt_var as u32, self.rcv_room().max(2) as u32];
+		if now.saturating_duration_since(self.last_ack_time) > SYN {
+			body.push(self.arrivals.recv_speed() as u32);
+			body.push(self.arrivals.bandwidth() as u32);
+			self.last_ack_time = now;
+		}
+
+		self.control(Control::Ack, self.ack_no as u32, &body);
+		self.acks.store(self.ack_no, ack, now);
+	}
+
+	/// Handles an ACK, returning true if it freed up some of the send buffer.
+	/// An error means the peer acknowledged what was never sent.
+	fn on_ack(&mut self, ack_no: u32, body: &[u32], now: Instant) -> Result<bool, ()> {
+		let ack = match body.first() {
+			Some(&ack) => self.offset(ack, self.snd_last_ack),
+			None => return Ok(false),
+		};
+
+		if ack > self.snd_next as i64 {
+			return Err(());
+		}
+
+		if body.len() == 1 {
+			if ack >= self.snd_last_ack as i64 {
+				self.flow_window -= ack - self.snd_last_ack as i64;
+				self.snd_last_ack = ack as u64;
+			}
+
+			return Ok(false);
+		}
+
+		self.control(Control::Ack2, ack_no, &[]);
+
+		if body.len() < 4 || ack < 0 {
+			return Ok(false);
+		}
+
+		let ack = ack as u64;
+		if ack >= self.snd_last_ack {
+			self.flow_window = i64::from(body[3] as i32);
+			self.snd_last_ack = ack;
+		}
+
+		if ack <= self.snd_last_data_ack {
+			return Ok(false);
+		}
+
+		let acked = ((ack - self.snd_last_data_ack) as usize).min(self.snd_buf.len());
+		self.snd_buf.drain(..acked);
+		self.snd_last_data_ack = ack;
+		self.snd_loss.remove_through(ack - 1);
+
+		self.update_rtt(body[1] as i32);
+
+		if let (Some(&rate), Some(&bandwidth)) = (body.get(4), body.get(5)) {
+			if rate as i32 > 0 {
+				self.delivery_rate = (self.delivery_rate * 7 + rate as i32) >> 3;
+			}
+
+			if bandwidth as i32 > 0 {
+				self.bandwidth = (self.bandwidth * 7 + bandwidth as i32) >> 3;
+			}
+		}
+
+		let link = self.link();
+		let micros = self.micros(now);
+		self.cc.on_ack(ack, &link, micros);
+		Ok(true)
+	}
+
+	/// Handles a loss report, which lists single packets & (w/ the high bit
+	/// of the first set) ranges of them. An error means the peer reported
+	/// the loss of what was never sent.
+	fn on_nak(&mut self, body: &[u32]) -> Result<(), ()> {
+		let mut lost = vec![];
+		let mut words = body.iter();
+		while let Some(&word) = words.next() {
+			let (first, last) = match word & 0x8000_0000 {
+				0 => (word, word),
+				_ => (word & MAX_SEQ, *words.next().ok_or(())?),
+			};
+
+			let (first, last) = (self.offset(first, self.snd_last_ack), self.offset(last, self.snd_last_ack));
+			if first > last || last >= self.snd_next as i64 {
+				return Err(());
+			}
+
+			lost.push((first, last));
+		}
+
+		if let Some(&(first, _)) = lost.first() {
+			let count = lost.iter().map(|(first, last)| (last - first + 1) as usize).sum();
+			let link = self.link();
+			self.cc.on_loss(first.max(0) as u64, count, &link);
+		}
+
+		for (first, last) in lost {
+			if last >= self.snd_last_ack as i64 {
+				let first = first.max(self.snd_last_ack as i64) as u64;
+				self.trace.snd_loss += self.snd_loss.insert(first, last as u64) as i32;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Handles an ACK2, which says how long an ACK took to be answered.
+	fn on_ack2(&mut self, ack_no: u32, now: Instant) {
+		if let Some((ack, rtt)) = self.acks.acknowledge(ack_no as i32, now) {
+			let rtt = rtt.as_micros().min(i32::MAX as u128) as i32;
+			if rtt > 0 {
+				self.update_rtt(rtt);
+			}
+
+			self.rcv_last_ack_ack = self.rcv_last_ack_ack.max(ack);
+		}
+	}
+
+	fn break_off(&mut self) {
+		if self.status == Status::Connected {
+			self.status = Status::Broken;
+		}
+	}
+
+	/// Sends the ACKs which are due, and anything the peer has not answered
+	/// for a while again. Returns true if there is more for the sender thread
+	/// to send.
+	fn check_timers(&mut self, now: Instant) -> bool {
+		if now >= self.next_ack_time {
+			self.ack(now, false);
+			self.next_ack_time = now + SYN;
+			self.pkt_count = 0;
+			self.light_acks = 1;
+		} else if self.pkt_count >= LIGHT_ACK_PACKETS * self.light_acks {
+			self.ack(now, true);
+			self.light_acks += 1;
+		}
+
+		let exp_count = self.exp_count;
+		let exp = (self.round_trip() * exp_count + SYN).max(MIN_EXP * exp_count);
+		if now.saturating_duration_since(self.last_rsp) <= exp {
+			return false;
+		}
+
+		if self.exp_count > EXP_LIMIT && now.saturating_duration_since(self.last_rsp) > EXP_IDLE {
+			warn!("udt: the peer of socket {} stopped answering", self.peer_id);
+			self.break_off();
+			return false;
+		}
+
+		// everything in flight is sent again, unless something already is
+		let resend = !self.snd_buf.is_empty();
+		if resend {
+			if self.snd_next != self.snd_last_ack && self.snd_loss.is_empty() {
+				self.trace.snd_loss += self.snd_loss.insert(self.snd_last_ack, self.snd_next - 1) as i32;
+			}
+
+			let link = self.link();
+			self.cc.on_timeout((self.snd_next - self.snd_last_ack) as usize, &link);
+		} else {
+			self.control(Control::KeepAlive, 0, &[]);
+		}
+
+		self.exp_count += 1;
+		self.last_rsp = now;
+		resend
+	}
+}
+
+impl Conn {
+	/// The mux the connection's packets go through.
+	pub(super) fn mux(&self) -> Arc<Mux> {
+		Arc::clone(&self.mux)
+	}
+
+	pub(super) fn new(setup: Setup, mux: Arc<Mux>) -> Arc<Self> {
+		let now = Instant::now();
+		let state = State {
+			status: Status::Connected,
+			peer_shutdown: false,
+			ty: setup.ty,
+			peer_id: setup.peer_id,
+			isn: setup.isn,
+			started: now,
+			payload_len: packet::payload_len(setup.mss),
+			outbox: vec![],
+
+			snd_buf: VecDeque::new(),
+			snd_cap: setup.snd_buf,
+			snd_next: 0,
+			snd_last_ack: 0,
+			snd_last_data_ack: 0,
+			snd_loss: LossList::default(),
+			flow_window: i64::from(setup.flow_window),
+			next_msg: 1,
+			cc: Cc::new(setup.mss, setup.flow_window, 0, setup.controller),
+			rtt: SYN.as_micros() as i32 * 10,
+			rtt_var: SYN.as_micros() as i32 * 5,
+			delivery_rate: 16,
+			bandwidth: 1,
+
+			received: Received::default(),
+			rcv_next: 0,
+			rcv_max: 0,
+			rcv_units: BTreeMap::new(),
+			rcv_cap: setup.rcv_buf,
+			rcv_last_ack: 0,
+			rcv_last_ack_ack: 0,
+			ack_no: 0,
+			acks: AckWindow::default(),
+			last_ack_time: now,
+			next_ack_time: now + SYN,
+			pkt_count: 0,
+			light_acks: 1,
+			arrivals: TimeWindow::new(now),
+
+			last_rsp: now,
+			exp_count: 1,
+
+			trace: Trace::default(),
+		};
+
+		Arc::new(Self {
+			id: setup.id,
+			peer: setup.peer,
+			ty: setup.ty,
+			mux,
+			state: Mutex::new(state),
+			readable: Condvar::new(),
+			writable: Condvar::new(),
+			sendable: Condvar::new(),
+		})
+	}
+
+	/// Starts the thread which sends the connection's packets, which runs
+	/// until the connection is over.
+	pub(super) fn start(self: &Arc<Self>) {
+		let conn = Arc::clone(self);
+		thread::spawn(move || conn.run_sender());
+	}
+
+	fn lock(&self) -> MutexGuard<'_, State> {
+		self.state.lock().expect("udt connection lock poisoned")
+	}
+
+	/// Sends what is in the outbox, once the state has been unlocked.
+	fn flush(&self, outbox: Vec<Vec<u8>>) {
+		for packet in outbox {
+			self.mux.send_to(&packet, self.peer);
+		}
+	}
+
+	/// Wakes anything waiting on the connection, which is over.
+	fn wake_all(&self) {
+		self.readable.notify_all();
+		self.writable.notify_all();
+		self.sendable.notify_all();
+		epoll::notify();
+	}
+
+	/// Handles a packet from the peer.
+	pub(super) fn process(&self, packet: Packet<'_>) {
+		let now = Instant::now();
+		let mut state = self.lock();
+		if state.status == Status::Closed {
+			return;
+		}
+
+		state.exp_count = 1;
+		state.last_rsp = now;
+
+		let (mut readable, mut writable, mut sendable, mut over) = (false, false, false, false);
+		match packet {
+			Packet::Data { seq, msg, payload, .. } => {
+				readable = state.on_data(seq, msg, payload, now);
+				sendable = state.check_timers(now);
+			},
+
+			Packet::Control { ty: Control::Ack, info, body, .. } => {
+				match state.on_ack(info, &packet::words(body), now) {
+					Ok(freed) => writable = freed,
+					Err(_) => { state.break_off(); over = true; },
+				}
+
+				sendable = true;
+			},
+
+			Packet::Control { ty: Control::Nak, body, .. } => {
+				if state.on_nak(&packet::words(body)).is_err() {
+					state.break_off();
+					over = true;
+				}
+
+				sendable = true;
+			},
+
+			Packet::Control { ty: Control::Ack2, info, .. } => state.on_ack2(info, now),
+
+			Packet::Control { ty: Control::DropRequest, info, body, .. } => {
+				if let [first, last, ..] = packet::words(body)[..] {
+					readable = state.on_drop(info, first, last);
+				}
+			},
+
+			Packet::Control { ty: Control::Shutdown, .. } => {
+				state.peer_shutdown = true;
+				state.break_off();
+				over = true;
+			},
+
+			// a listener answers repeated handshakes itself, and the keep
+			// alive only needs to have arrived
+			Packet::Control { ty: Control::Handshake, .. } | Packet::Control { ty: Control::KeepAlive, .. } => {},
+		}
+
+		let outbox = mem::take(&mut state.outbox);
+		drop(state);
+		self.flush(outbox);
+
+		if over {
+			self.wake_all();
+			return;
+		}
+
+		if readable {
+			self.readable.notify_all();
+			epoll::notify();
+		}
+
+		if writable {
+			self.writable.notify_all();
+		}
+
+		if sendable {
+			self.sendable.notify_one();
+		}
+	}
+
+	/// Runs the connection's timers, which the mux does every `SYN`.
+	pub(super) fn check_timers(&self) {
+		let mut state = self.lock();
+		if state.status != Status::Connected {
+			return;
+		}
+
+		let sendable = state.check_timers(Instant::now());
+		let broken = state.status != Status::Connected;
+		let outbox = mem::take(&mut state.outbox);
+		drop(state);
+		self.flush(outbox);
+
+		if broken {
+			self.wake_all();
+		} else if sendable {
+			self.sendable.notify_one();
+		}
+	}
+
+	/// Paces the packets out, as fast as the congestion control allows.
+	/// Anything which is late is made up for by sending the next packets
+	/// straight away, rather than falling behind the rate.
+	fn run_sender(&self) {
+		let mut next: Option<Instant> = None;
+		let mut late = Duration::ZERO;
+
+		loop {
+			let mut state = self.lock();
+			let (packet, probe, period) = loop {
+				if state.status != Status::Connected {
+					return;
+				}
+
+				let now = Instant::now();
+				if let Some(next) = next {
+					match next.checked_duration_since(now) {
+						Some(wait) if !wait.is_zero() => {
+							state = self.sendable.wait_timeout(state, wait).expect("udt connection lock poisoned").0;
+							continue;
+						},
+						_ => late += now - next,
+					}
+				}
+
+				next = None;
+				match state.pack(now) {
+					Some((packet, probe)) => break (packet, probe, state.cc.period),
+					None => {
+						late = Duration::ZERO;
+						state = self.sendable.wait_timeout(state, SYN).expect("udt connection lock poisoned").0;
+					},
+				}
+			};
+
+			let outbox = mem::take(&mut state.outbox);
+			drop(state);
+			self.flush(outbox);
+			self.mux.send_to(&packet, self.peer);
+
+			let now = Instant::now();
+			let period = Duration::from_secs_f64(period.max(0.0) / 1e6);
+			next = Some(if probe {
+				now
+			} else if late >= period {
+				late -= period;
+				now
+			} else {
+				let at = now + period - late;
+				late = Duration::ZERO;
+				at
+			});
+		}
+	}
+
+	/// Waits for `ready` to hold, or for the connection to end, or for the
+	/// `timeout` to pass.
+	fn wait_for<'a, F>(&self, mut state: MutexGuard<'a, State>, cond: &Condvar, timeout: Option<Duration>, ready: F) -> MutexGuard<'a, State>
+	where F: Fn(&State) -> bool {
+		let deadline = timeout.map(|timeout| Instant::now() + timeout);
+		while !ready(&state) && state.status == Status::Connected {
+			state = match deadline {
+				None => cond.wait(state).expect("udt connection lock poisoned"),
+				Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+					Some(wait) if !wait.is_zero() => cond.wait_timeout(state, wait).expect("udt connection lock poisoned").0,
+					_ => break,
+				},
+			};
+		}
+
+		state
+	}
+
+	/// Writes as much of `buf` as fits in the send buffer, once anything
+	/// does.
+	pub(super) fn send(&self, buf: &[u8]) -> Result<usize, UdtError> {
+		if self.ty == SocketType::Datagram {
+			return Err(UdtError::new(5, 10));
+		}
+
+		let state = self.lock();
+		let mut state = self.wait_for(state, &self.writable, None, |state| state.snd_buf.len() < state.snd_cap);
+		if state.status != Status::Connected {
+			return Err(UdtError::new(2, 1));
+		}
+
+		let room = (state.snd_cap - state.snd_buf.len()) * state.payload_len;
+		let len = buf.len().min(room);
+		state.write(&buf[..len], None, false);
+		drop(state);
+
+		self.sendable.notify_one();
+		Ok(len)
+	}
+
+	/// Writes `buf` as one message, once there is room for all of it.
+	pub(super) fn send_msg(&self, buf: &[u8], ttl: Option<Duration>, in_order: bool) -> Result<usize, UdtError> {
+		if self.ty == SocketType::Stream {
+			return Err(UdtError::new(5, 9));
+		}
+
+		let state = self.lock();
+		let packets = buf.len().div_ceil(state.payload_len).max(1);
+		if packets > state.snd_cap {
+			return Err(UdtError::new(5, 12));
+		}
+
+		let mut state = self.wait_for(state, &self.writable, None, |state| state.snd_cap - state.snd_buf.len() >= packets);
+		if state.status != Status::Connected {
+			return Err(UdtError::new(2, 1));
+		}
+
+		state.write(buf, ttl, in_order);
+		drop(state);
+
+		self.sendable.notify_one();
+		Ok(buf.len())
+	}
+
+	/// Reads what has arrived of the stream, waiting up to `timeout` for
+	/// anything to. What arrived before the connection broke is still read.
+	pub(super) fn recv(&self, buf: &mut [u8], timeout: Option<Duration>) -> Result<usize, UdtError> {
+		if self.ty == SocketType::Datagram {
+			return Err(UdtError::new(5, 10));
+		}
+
+		let state = self.lock();
+		let mut state = self.wait_for(state, &self.readable, timeout, |state| !state.received.ready.is_empty());
+		match state.received.read(buf) {
+			0 if state.status != Status::Connected => Err(UdtError::new(2, 1)),
+			0 if !buf.is_empty() => Err(UdtError::new(6, 3)),
+			len => Ok(len),
+		}
+	}
+
+	/// Reads the next message, waiting up to `timeout` for one.
+	pub(super) fn recv_msg(&self, buf: &mut [u8], timeout: Option<Duration>) -> Result<usize, UdtError> {
+		if self.ty == SocketType::Stream {
+			return Err(UdtError::new(5, 9));
+		}
+
+		let state = self.lock();
+		let mut state = self.wait_for(state, &self.readable, timeout, |state| !state.received.ready.is_empty());
+		match state.received.read_message(buf) {
+			Some(len) => Ok(len),
+			None if state.status != Status::Connected => Err(UdtError::new(2, 1)),
+			None => Err(UdtError::new(6, 3)),
+		}
+	}
+
+	/// Closes the connection, once what was written has been delivered (or
+	/// straight away, w/o `linger`.) Anything still waiting on it is woken.
+	pub(super) fn close(&self, linger: bool) {
+		let state = self.lock();
+		let timeout = if linger { LINGER } else { Duration::ZERO };
+		let mut state = self.wait_for(state, &self.writable, Some(timeout), |state| state.snd_buf.is_empty());
+
+		if state.status != Status::Closed && !state.peer_shutdown {
+			state.control(Control::Shutdown, 0, &[]);
+		}
+
+		state.status = Status::Closed;
+		let outbox = mem::take(&mut state.outbox);
+		drop(state);
+
+		self.flush(outbox);
+		self.mux.remove_conn(self.id);
+		self.wake_all();
+	}
+
+	/// Whether the socket can be read from, written to, or is broken.
+	pub(super) fn readiness(&self) -> (bool, bool, bool) {
+		let state = self.lock();
+		let broken = state.status != Status::Connected;
+		(!state.received.ready.is_empty() || broken, state.snd_buf.len() < state.snd_cap, broken)
+	}
+
+	/// How many packets are waiting to be sent (or acknowledged.)
+	pub(super) fn send_data(&self) -> usize {
+		self.lock().snd_buf.len()
+	}
+
+	/// How many packets are waiting to be read.
+	pub(super) fn recv_data(&self) -> usize {
+		self.lock().received.ready_units
+	}
+
+	/// What the performance monitor reports, which is nothing once the
+	/// connection is over.
+	pub(super) fn trace(&self) -> Option<TraceInfo> {
+		let state = self.lock();
+		if state.status != Status::Connected {
+			return None;
+		}
+
+		Some(TraceInfo {
+			pkt_sent_total: state.trace.sent,
+			pkt_snd_loss_total: state.trace.snd_loss,
+			pkt_rcv_loss_total: state.trace.rcv_loss,
+			pkt_retrans_total: state.trace.retrans,
+			ms_rtt: f64::from(state.rtt) / 1e3,
+			mbps_bandwidth: f64::from(state.bandwidth) * state.payload_len as f64 * 8.0 / 1e6,
+		})
+	}
+}
@@ -0,0 +1,140 @@
+//! Waits on any of several sockets at once, as UDT's epoll does.
+
+use super::{socket, UdtError, UdtSocket};
+
+use std::convert::TryFrom;
+use std::ops::BitOr;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Which events an `Epoll` reports for a socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EpollEvents(u8);
+
+/// The socket can be read from, or accepted from if it is listening.
+pub const UDT_EPOLL_IN: EpollEvents = EpollEvents(0x1);
+
+/// The connection is broken, or the socket was closed.
+pub const UDT_EPOLL_ERR: EpollEvents = EpollEvents(0x8);
+
+impl EpollEvents {
+	fn contains(self, other: EpollEvents) -> bool {
+		self.0 & other.0 == other.0
+	}
+}
+
+impl BitOr for EpollEvents {
+	type Output = EpollEvents;
+
+	fn bitor(self, other: EpollEvents) -> EpollEvents {
+		EpollEvents(self.0 | other.0)
+	}
+}
+
+/// Bumped whenever any socket may have become ready, so that a wait which
+/// found nothing ready can sleep until then.
+struct Readiness {
+	generation: Mutex<u64>,
+	changed: Condvar,
+}
+
+static READINESS: Readiness = Readiness {
+	generation: Mutex::new(0),
+	changed: Condvar::new(),
+};
+
+/// Wakes the `Epoll`s which are waiting, to check their sockets again.
+pub(super) fn notify() {
+	*READINESS.generation.lock().expect("epoll lock poisoned") += 1;
+	READINESS.changed.notify_all();
+}
+
+/// The sockets an `Epoll` waits on, w/ the events each is waited on for.
+/// Unlike UDT's this is level triggered: a socket is reported for as long as
+/// it is ready, rather than once each time it becomes ready.
+#[derive(Debug)]
+pub struct Epoll {
+	socks: Mutex<Vec<(UdtSocket, EpollEvents)>>,
+}
+
+impl Epoll {
+	pub fn create() -> Result<Epoll, UdtError> {
+		Ok(Epoll { socks: Mutex::new(vec![]) })
+	}
+
+	/// Waits on `sock` for the `events`, or for all of them if `None`.
+	pub fn add_usock(&mut self, sock: &UdtSocket, events: Option<EpollEvents>) -> Result<(), UdtError> {
+		socket(sock.id)?;
+
+		let events = events.unwrap_or(UDT_EPOLL_IN | UDT_EPOLL_ERR);
+		let mut socks = self.socks.lock().expect("epoll lock poisoned");
+		socks.retain(|&(waited, _)| waited != *sock);
+		socks.push((*sock, events));
+		Ok(())
+	}
+
+	/// Stops waiting on `sock`, which is not an error if it was not waited on.
+	pub fn remove_usock(&self, sock: &UdtSocket) -> Result<(), UdtError> {
+		self.socks.lock().expect("epoll lock poisoned")
+			.retain(|&(waited, _)| waited != *sock);
+
+		Ok(())
+	}
+
+	/// Returns the sockets which are readable (or broken) & those which are
+	/// writable, once any are. W/ a negative `timeout` this waits for as long
+	/// as it takes, otherwise it gives up after that many milliseconds and
+	/// returns nothing. The writable ones are only checked for if `write`.
+	pub fn wait(&mut self, timeout: i64, write: bool) -> Result<(Vec<UdtSocket>, Vec<UdtSocket>), UdtError> {
+		let deadline = u64::try_from(timeout).ok()
+			.map(|timeout| Instant::now() + Duration::from_millis(timeout));
+
+		loop {
+			let generation = *READINESS.generation.lock().expect("epoll lock poisoned");
+
+			let ready = self.ready(write);
+			if !ready.0.is_empty() || !ready.1.is_empty() {
+				return Ok(ready);
+			}
+
+			let mut current = READINESS.generation.lock().expect("epoll lock poisoned");
+			while *current == generation {
+				let wait = match deadline {
+					Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+						Some(wait) if !wait.is_zero() => wait,
+						_ => return Ok((vec![], vec![])),
+					},
+					None => Duration::from_secs(1),
+				};
+
+				current = READINESS.changed.wait_timeout(current, wait)
+					.expect("epoll lock poisoned").0;
+			}
+		}
+	}
+
+	fn ready(&self, write: bool) -> (Vec<UdtSocket>, Vec<UdtSocket>) {
+		let socks = self.socks.lock().expect("epoll lock poisoned");
+		let mut readable = vec![];
+		let mut writable = vec![];
+
+		for &(sock, events) in socks.iter() {
+			// a socket which was closed is as broken as it gets
+			let (can_read, can_write, broken) = match socket(sock.id) {
+				Ok(socket) => socket.readiness(),
+				Err(_) => (false, false, true),
+			};
+
+			let error = broken && events.contains(UDT_EPOLL_ERR);
+			if error || (can_read && events.contains(UDT_EPOLL_IN)) {
+				readable.push(sock);
+			}
+
+			if write && (error || can_write) {
+				writable.push(sock);
+			}
+		}
+
+		(readable, writable)
+	}
+}
@@ -0,0 +1,280 @@
+//! Setting up a connection, from either end. (See: UDT4's `CUDT::connect`
+//! & `CUDT::listen`.)
+//!
+//! The connecting socket first sends a request, which the listener answers
+//! w/ a cookie (a hash of the peer's address & the current minute.) It is
+//! only once the request is sent again w/ the cookie that the listener makes
+//! a connection for it, so a spoofed address gets nowhere.
+
+use super::conn::{Conn, Setup, FLIGHT_FLAG};
+use super::mux::Mux;
+use super::packet::{self, Control, Handshake, MAX_SEQ, MSS, REQ_CONNECT, REQ_REJECTED, REQ_RESPONSE, VERSION};
+use super::{Opts, Role, SocketType, UdtError};
+
+use rand::Rng;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How often a connecting socket sends its request again, while it has no
+/// answer.
+const REQ_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long a connecting socket waits for the connection to be accepted.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub(super) struct Listener {
+	ty: SocketType,
+	opts: Opts,
+	backlog: usize,
+	cookies: RandomState,
+	started: Instant,
+	queue: Mutex<Queue>,
+	arrived: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+	/// The connections which have not been accepted yet.
+	pending: VecDeque<(i32, SocketAddr)>,
+
+	/// The answer each peer was given, which is sent again if the peer asks
+	/// again. (i.e: because the answer was lost.)
+	answered: HashMap<(SocketAddr, i32), (i32, Handshake)>,
+	closed: bool,
+}
+
+impl Listener {
+	pub(super) fn new(ty: SocketType, opts: Opts, backlog: usize) -> Self {
+		Self {
+			ty,
+			opts,
+			backlog: backlog.max(1),
+			cookies: RandomState::new(),
+			started: Instant::now(),
+			queue: Mutex::new(Queue::default()),
+			arrived: Condvar::new(),
+		}
+	}
+
+	fn queue(&self) -> MutexGuard<'_, Queue> {
+		self.queue.lock().expect("udt listener lock poisoned")
+	}
+
+	fn cookie(&self, peer: SocketAddr, minute: u64) -> i32 {
+		self.cookies.hash_one((peer, minute)) as i32
+	}
+
+	/// Answers a handshake from `peer`, making a connection for it once it
+	/// has sent back its cookie.
+	pub(super) fn on_handshake(&self, mux: &Arc<Mux>, handshake: Handshake, peer: SocketAddr) {
+		let minute = self.started.elapsed().as_secs() / 60;
+		let reply = |answer: &Handshake| {
+			let packet = packet::control(Control::Handshake, 0, 0, handshake.id, &answer.words());
+			mux.send_to(&packet, peer);
+		};
+
+		if handshake.req == REQ_CONNECT {
+			reply(&Handshake { cookie: self.cookie(peer, minute), ..handshake.clone() });
+			return;
+		}
+
+		// a cookie from the minute before is still good, as it may have
+		// just turned over
+		let cookie_ok = handshake.cookie == self.cookie(peer, minute)
+			|| (minute > 0 && handshake.cookie == self.cookie(peer, minute - 1));
+
+		if handshake.req != REQ_RESPONSE || !cookie_ok {
+			return;
+		}
+
+		let mut queue = self.queue();
+		if queue.closed {
+			return;
+		}
+
+		let rejected = Handshake { req: REQ_REJECTED, ..handshake.clone() };
+		if handshake.version != VERSION || handshake.ty != self.ty.code() {
+			reply(&rejected);
+			return;
+		}
+
+		let key = (peer, handshake.id);
+		if let Some((id, answer)) = queue.answered.get(&key) {
+			if answer.isn == handshake.isn && super::socket(*id).is_ok() {
+				reply(answer);
+				return;
+			}
+		}
+
+		if queue.pending.len() >= self.backlog {
+			reply(&rejected);
+			return;
+		}
+
+		let id = super::next_id();
+		let answer = Handshake {
+			version: VERSION,
+			ty: self.ty.code(),
+			isn: handshake.isn,
+			mss: handshake.mss.min(MSS),
+			flight_flag: self.opts.rcv_buf.min(FLIGHT_FLAG as usize) as i32,
+			req: REQ_RESPONSE,
+			id,
+			cookie: handshake.cookie,
+			peer_ip: Handshake::ip_of(peer),
+		};
+
+		let conn = Conn::new(Setup {
+			id,
+			ty: self.ty,
+			peer,
+			peer_id: handshake.id,
+			isn: handshake.isn,
+			mss: answer.mss,
+			flow_window: handshake.flight_flag,
+			snd_buf: self.opts.snd_buf,
+			rcv_buf: self.opts.rcv_buf,
+			controller: self.opts.congestion.as_ref().map(|factory| factory()),
+		}, Arc::clone(mux));
+
+		super::register(id, self.ty, self.opts.clone(), Role::Connected(Arc::clone(&conn)));
+		mux.add_conn(Arc::clone(&conn));
+		conn.start();
+
+		queue.answered.retain(|_, (id, _)| super::socket(*id).is_ok());
+		queue.answered.insert(key, (id, answer.clone()));
+		queue.pending.push_back((id, peer));
+		drop(queue);
+
+		reply(&answer);
+		self.arrived.notify_all();
+		super::epoll::notify();
+	}
+
+	/// Waits for a connection which has not been accepted yet.
+	pub(super) fn accept(&self) -> Result<(i32, SocketAddr), UdtError> {
+		let mut queue = self.queue();
+		loop {
+			if queue.closed {
+				return Err(UdtError::new(5, 6));
+			}
+
+			if let Some(pending) = queue.pending.pop_front() {
+				return Ok(pending);
+			}
+
+			queue = self.arrived.wait(queue).expect("udt listener lock poisoned");
+		}
+	}
+
+	pub(super) fn is_pending(&self) -> bool {
+		!self.queue().pending.is_empty()
+	}
+
+	/// Stops accepting connections, returning those which were never
+	/// accepted (for them to be closed.)
+	pub(super) fn close(&self) -> Vec<i32> {
+		let mut queue = self.queue();
+		queue.closed = true;
+		self.arrived.notify_all();
+		queue.pending.drain(..).map(|(id, _)| id).collect()
+	}
+}
+
+/// A socket waiting on the answer to its handshake.
+#[derive(Default)]
+pub(super) struct Connecting {
+	answer: Mutex<Option<Handshake>>,
+	arrived: Condvar,
+}
+
+impl Connecting {
+	pub(super) fn on_handshake(&self, handshake: Handshake) {
+		*self.answer.lock().expect("udt connect lock poisoned") = Some(handshake);
+		self.arrived.notify_all();
+	}
+
+	fn wait(&self, timeout: Duration) -> Option<Handshake> {
+		let answer = self.answer.lock().expect("udt connect lock poisoned");
+		let (mut answer, _) = self.arrived.wait_timeout_while(answer, timeout, |answer| answer.is_none())
+			.expect("udt connect lock poisoned");
+
+		answer.take()
+	}
+}
+
+/// Connects the socket `id` (bound to `mux`) to the listener at `peer`.
+pub(super) fn connect(mux: &Arc<Mux>, id: i32, ty: SocketType, opts: Opts, peer: SocketAddr) -> Result<Arc<Conn>, UdtError> {
+	let isn = rand::thread_rng().gen_range(0, MAX_SEQ);
+	let mut request = Handshake {
+		version: VERSION,
+		ty: ty.code(),
+		isn,
+		mss: MSS,
+		flight_flag: opts.rcv_buf.min(FLIGHT_FLAG as usize) as i32,
+		req: REQ_CONNECT,
+		id,
+		cookie: 0,
+		peer_ip: Handshake::ip_of(peer),
+	};
+
+	let connecting = Arc::new(Connecting::default());
+	mux.add_connecting(id, Arc::clone(&connecting));
+
+	let deadline = Instant::now() + CONNECT_TIMEOUT;
+	let mut last_sent: Option<Instant> = None;
+	let answer = loop {
+		let now = Instant::now();
+		if now >= deadline {
+			break Err(UdtError::new(1, 1));
+		}
+
+		if last_sent.is_none_or(|sent| now - sent >= REQ_INTERVAL) {
+			mux.send_to(&packet::control(Control::Handshake, 0, 0, 0, &request.words()), peer);
+			last_sent = Some(now);
+		}
+
+		let answer = match connecting.wait(REQ_INTERVAL.min(deadline - now)) {
+			Some(answer) => answer,
+			None => continue,
+		};
+
+		match answer.req {
+			// the cookie, which goes back w/ the request
+			REQ_CONNECT => {
+				request.req = REQ_RESPONSE;
+				request.cookie = answer.cookie;
+				last_sent = None;
+			},
+
+			REQ_REJECTED => break Err(UdtError::new(1, 2)),
+			REQ_RESPONSE if answer.isn != isn => break Err(UdtError::new(1, 4)),
+			REQ_RESPONSE => break Ok(answer),
+			_ => {},
+		}
+	};
+
+	mux.remove_connecting(id);
+	let answer = answer?;
+
+	let conn = Conn::new(Setup {
+		id,
+		ty,
+		peer,
+		peer_id: answer.id,
+		isn,
+		mss: answer.mss.min(MSS),
+		flow_window: answer.flight_flag,
+		snd_buf: opts.snd_buf,
+		rcv_buf: opts.rcv_buf,
+		controller: opts.congestion.as_ref().map(|factory| factory()),
+	}, Arc::clone(mux));
+
+	mux.add_conn(Arc::clone(&conn));
+	conn.start();
+	Ok(conn)
+}
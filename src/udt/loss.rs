@@ -0,0 +1,75 @@
+//! The sequence numbers which were sent but are known to be lost, and so
+//! are sent again before anything new. (See: UDT4's `CSndLossList`.)
+
+use std::collections::BTreeMap;
+
+/// The lost packets, kept as ranges of (absolute) sequence numbers. A
+/// timeout marks everything in flight as lost at once, which is one range
+/// rather than thousands of packets.
+#[derive(Default)]
+pub(super) struct LossList {
+	/// Maps the first sequence number of each range to its last.
+	ranges: BTreeMap<u64, u64>,
+}
+
+impl LossList {
+	/// Marks `first ..= last` as lost, returning how many of them were not
+	/// already.
+	pub(super) fn insert(&mut self, mut first: u64, mut last: u64) -> u64 {
+		if first > last {
+			return 0;
+		}
+
+		let mut added = last - first + 1;
+
+		// merges w/ the range before this one, if they touch or overlap
+		if let Some((&start, &end)) = self.ranges.range(..=first).next_back() {
+			if end.saturating_add(1) >= first {
+				added -= (end.min(last) + 1).saturating_sub(first);
+				first = start;
+				last = last.max(end);
+				self.ranges.remove(&start);
+			}
+		}
+
+		// and then swallows any which start within it
+		while let Some((&start, &end)) = self.ranges.range(first..=last.saturating_add(1)).next() {
+			added -= (end.min(last) + 1).saturating_sub(start);
+			last = last.max(end);
+			self.ranges.remove(&start);
+		}
+
+		self.ranges.insert(first, last);
+		added
+	}
+
+	/// Takes the first of the lost packets out of the list.
+	pub(super) fn pop_first(&mut self) -> Option<u64> {
+		let (&first, &last) = self.ranges.iter().next()?;
+		self.ranges.remove(&first);
+		if first < last {
+			self.ranges.insert(first + 1, last);
+		}
+
+		Some(first)
+	}
+
+	/// Forgets everything up to (and including) `last`.
+	pub(super) fn remove_through(&mut self, last: u64) {
+		while let Some((&first, &end)) = self.ranges.iter().next() {
+			if first > last {
+				break;
+			}
+
+			self.ranges.remove(&first);
+			if end > last {
+				self.ranges.insert(last + 1, end);
+				break;
+			}
+		}
+	}
+
+	pub(super) fn is_empty(&self) -> bool {
+		self.ranges.is_empty()
+	}
+}
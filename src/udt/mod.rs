@@ -0,0 +1,447 @@
+//! UDT, written in Rust. This is what ubuffer's UDT transport (& the hub,
+//! relay and multipath sessions built on it) runs on when it is built w/o
+//! the `udt-sys` feature, which links the C++ library through the `udt`
+//! bindings instead. It speaks UDT4's protocol, so either build can talk to
+//! the other. (See: `tests/interop.rs`.)
+//!
+//! Only what ubuffer uses is here, w/ the same API as the bindings: stream
+//! & datagram sockets over IPv4, the options ubuffer sets, an epoll, and the
+//! two calls it makes through UDT's C wrapper. (See: `raw`.) A datagram
+//! socket always delivers its messages in order, and the epoll reports a
+//! socket for as long as it is ready (not only as it becomes ready.)
+//!
+//! Each socket bound to a port shares a `Mux` (a UDP socket & the thread
+//! which reads it) w/ the connections it accepts. Each connection runs its
+//! own sender thread, which paces its packets out as UDT's congestion
+//! control decides. (See: `cc`.)
+
+mod cc;
+mod conn;
+mod epoll;
+mod handshake;
+mod loss;
+mod mux;
+mod packet;
+pub mod raw;
+mod window;
+
+pub use self::epoll::{Epoll, EpollEvents, UDT_EPOLL_ERR, UDT_EPOLL_IN};
+
+use self::conn::Conn;
+use self::handshake::Listener;
+use self::mux::Mux;
+
+use crate::proto::ControllerFactory;
+use rand::Rng;
+use std::convert::TryFrom;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// A UDT socket, which is only the id it is known by. (So it can be copied,
+/// like a file descriptor.)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct UdtSocket {
+	id: i32,
+}
+
+/// An error UDT reported, w/ UDT4's code & description for it.
+#[derive(Debug)]
+pub struct UdtError {
+	pub err_code: i32,
+	pub err_msg: String,
+}
+
+impl UdtError {
+	/// The error UDT4 reports as `major * 1000 + minor`, w/ its description.
+	fn new(major: i32, minor: i32) -> Self {
+		let err_msg = match (major, minor) {
+			(1, 1) => "Connection setup failure: connection time out.",
+			(1, 2) => "Connection setup failure: connection rejected.",
+			(1, 3) => "Connection setup failure: unable to create/configure UDP socket.",
+			(1, 4) => "Connection setup failure: abort for security reasons.",
+			(2, 1) => "Connection was broken.",
+			(2, 2) => "Connection does not exist.",
+			(5, 1) => "Operation not supported: Cannot do this operation on a BOUND socket.",
+			(5, 2) => "Operation not supported: Cannot do this operation on a CONNECTED socket.",
+			(5, 3) => "Operation not supported: Bad parameters.",
+			(5, 4) => "Operation not supported: Invalid socket ID.",
+			(5, 5) => "Operation not supported: Cannot do this operation on an UNBOUND socket.",
+			(5, 6) => "Operation not supported: Socket is not in listening state.",
+			(5, 9) => "Operation not supported: Incorrect use of Message API (sendmsg/recvmsg).",
+			(5, 10) => "Operation not supported: Incorrect use of Stream API (send/recv).",
+			(5, 11) => "Operation not supported: Cannot listen on a port already in use.",
+			(5, 12) => "Operation not supported: Message is too large to send (it must be less than the UDT send buffer size).",
+			(6, 3) => "Non-blocking call failure.",
+			_ => "Unknown error.",
+		};
+
+		Self { err_code: major * 1000 + minor, err_msg: err_msg.to_string() }
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketFamily {
+	AFInet,
+}
+
+/// Whether a socket's connection is a stream of bytes, or carries messages
+/// which arrive whole. (Both ends have to agree.)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketType {
+	Stream,
+	Datagram,
+}
+
+impl SocketType {
+	/// The type as the handshake carries it.
+	fn code(self) -> i32 {
+		match self {
+			SocketType::Stream => 1,
+			SocketType::Datagram => 2,
+		}
+	}
+}
+
+/// The options a socket can be read or set w/, which are typed by what they
+/// take.
+pub trait UdtOption<T> {
+	fn opt(&self) -> Opt;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Opt {
+	SndBuf,
+	RcvBuf,
+	RcvTimeo,
+	SndData,
+	RcvData,
+}
+
+#[allow(non_camel_case_types)]
+#[allow(non_snake_case)]
+pub mod UdtOpts {
+	//! The options which can be passed to `getsockopt` or `setsockopt`.
+
+	use super::{Opt, UdtOption};
+
+	macro_rules! udt_opt {
+		($(#[$doc:meta])* $name:ident => $opt:ident) => {
+			$(#[$doc])*
+			pub struct $name;
+
+			impl UdtOption<i32> for $name {
+				fn opt(&self) -> Opt { Opt::$opt }
+			}
+		};
+	}
+
+	udt_opt! {
+		/// The size of the send buffer, in bytes.
+		UDT_SNDBUF => SndBuf
+	}
+
+	udt_opt! {
+		/// The size of the receive buffer, in bytes.
+		UDT_RCVBUF => RcvBuf
+	}
+
+	udt_opt! {
+		/// How long a read waits for something to arrive, in milliseconds.
+		/// (Or forever if it is negative.)
+		UDT_RCVTIMEO => RcvTimeo
+	}
+
+	udt_opt! {
+		/// How many packets are waiting to be sent. (Read only.)
+		UDT_SNDDATA => SndData
+	}
+
+	udt_opt! {
+		/// How many packets are waiting to be read. (Read only.)
+		UDT_RCVDATA => RcvData
+	}
+}
+
+/// The size of the send & receive buffers, in packets, unless they are set.
+const DEFAULT_BUF: usize = 8192;
+
+/// The least the receive buffer is made, in packets.
+const MIN_RCV_BUF: usize = 32;
+
+/// What a socket was set up w/, which its connections are made with.
+#[derive(Clone)]
+struct Opts {
+	snd_buf: usize,
+	rcv_buf: usize,
+	rcv_timeout: Option<Duration>,
+
+	/// Builds the controller which replaces UDT's own congestion control on
+	/// each connection, if one was set. (See: `set_congestion()`.)
+	congestion: Option<ControllerFactory>,
+}
+
+impl Default for Opts {
+	fn default() -> Self {
+		Self { snd_buf: DEFAULT_BUF, rcv_buf: DEFAULT_BUF, rcv_timeout: None, congestion: None }
+	}
+}
+
+enum Role {
+	Open,
+	Bound(Arc<Mux>),
+	Listening(Arc<Mux>, Arc<Listener>),
+	Connected(Arc<Conn>),
+}
+
+struct Socket {
+	ty: SocketType,
+	state: Mutex<(Opts, Role)>,
+}
+
+impl Socket {
+	fn lock(&self) -> MutexGuard<'_, (Opts, Role)> {
+		self.state.lock().expect("udt socket lock poisoned")
+	}
+
+	fn conn(&self) -> Result<Arc<Conn>, UdtError> {
+		match &self.lock().1 {
+			Role::Connected(conn) => Ok(Arc::clone(conn)),
+			_ => Err(UdtError::new(2, 2)),
+		}
+	}
+
+	/// Whether the socket can be read (or accepted) from, written to, or is
+	/// broken.
+	fn readiness(&self) -> (bool, bool, bool) {
+		match &self.lock().1 {
+			Role::Listening(_, listener) => (listener.is_pending(), false, false),
+			Role::Connected(conn) => conn.readiness(),
+			Role::Open | Role::Bound(_) => (false, false, false),
+		}
+	}
+}
+
+/// The open sockets, by id.
+static SOCKETS: LazyLock<Mutex<HashMap<i32, Arc<Socket>>>> = LazyLock::new(Default::default);
+
+/// Sockets are numbered down from a random id, as UDT4 numbers them.
+static NEXT_ID: LazyLock<AtomicI32> = LazyLock::new(|| AtomicI32::new(rand::thread_rng().gen_range(1 << 29, 1 << 30)));
+
+fn sockets() -> MutexGuard<'static, HashMap<i32, Arc<Socket>>> {
+	SOCKETS.lock().expect("udt socket table poisoned")
+}
+
+fn next_id() -> i32 {
+	NEXT_ID.fetch_sub(1, Ordering::Relaxed)
+}
+
+fn register(id: i32, ty: SocketType, opts: Opts, role: Role) {
+	sockets().insert(id, Arc::new(Socket { ty, state: Mutex::new((opts, role)) }));
+}
+
+fn socket(id: i32) -> Result<Arc<Socket>, UdtError> {
+	sockets().get(&id).cloned().ok_or_else(|| UdtError::new(5, 4))
+}
+
+/// Closes the socket `id`, waiting for what was sent on it to be delivered
+/// if `linger`.
+fn close(id: i32, linger: bool) {
+	let socket = match sockets().remove(&id) {
+		Some(socket) => socket,
+		None => return,
+	};
+
+	let role = std::mem::replace(&mut socket.lock().1, Role::Open);
+	match role {
+		Role::Connected(conn) => conn.close(linger),
+		Role::Listening(mux, listener) => {
+			mux.clear_listener();
+			for id in listener.close() {
+				close(id, false);
+			}
+		},
+
+		Role::Open | Role::Bound(_) => {},
+	}
+
+	epoll::notify();
+}
+
+impl UdtSocket {
+	pub fn new(_family: SocketFamily, ty: SocketType) -> Result<UdtSocket, UdtError> {
+		let id = next_id();
+		register(id, ty, Opts::default(), Role::Open);
+		Ok(UdtSocket { id })
+	}
+
+	pub fn bind(&self, addr: SocketAddr) -> Result<(), UdtError> {
+		let socket = socket(self.id)?;
+		let mut state = socket.lock();
+		if !matches!(state.1, Role::Open) {
+			return Err(UdtError::new(5, 1));
+		}
+
+		let mux = Mux::bind(addr).map_err(|err| UdtError {
+			err_msg: format!("Connection setup failure: unable to create/configure UDP socket: {}.", err),
+			..UdtError::new(1, 3)
+		})?;
+
+		state.1 = Role::Bound(mux);
+		Ok(())
+	}
+
+	pub fn listen(&self, backlog: i32) -> Result<(), UdtError> {
+		let socket = socket(self.id)?;
+		let mut state = socket.lock();
+		let mux = match &state.1 {
+			Role::Bound(mux) => Arc::clone(mux),
+			Role::Listening(..) => return Ok(()),
+			Role::Open => return Err(UdtError::new(5, 5)),
+			Role::Connected(_) => return Err(UdtError::new(5, 2)),
+		};
+
+		let listener = Arc::new(Listener::new(socket.ty, state.0.clone(), usize::try_from(backlog).unwrap_or(0)));
+		mux.set_listener(Arc::clone(&listener))?;
+		state.1 = Role::Listening(mux, listener);
+		Ok(())
+	}
+
+	/// Waits for a connection to the listening socket, and returns it.
+	pub fn accept(&self) -> Result<(UdtSocket, SocketAddr), UdtError> {
+		let listener = match &socket(self.id)?.lock().1 {
+			Role::Listening(_, listener) => Arc::clone(listener),
+			_ => return Err(UdtError::new(5, 6)),
+		};
+
+		let (id, peer) = listener.accept()?;
+		Ok((UdtSocket { id }, peer))
+	}
+
+	/// Connects to the listener at `addr`, from the port the socket is bound
+	/// to (or any free one.)
+	pub fn connect(&self, addr: SocketAddr) -> Result<(), UdtError> {
+		let socket = socket(self.id)?;
+		let (opts, mux) = {
+			let state = socket.lock();
+			let mux = match &state.1 {
+				Role::Open => None,
+				Role::Bound(mux) => Some(Arc::clone(mux)),
+				Role::Listening(..) | Role::Connected(_) => return Err(UdtError::new(5, 2)),
+			};
+
+			(state.0.clone(), mux)
+		};
+
+		let mux = match mux {
+			Some(mux) => mux,
+			None => self.bind_any()?,
+		};
+
+		let conn = handshake::connect(&mux, self.id, socket.ty, opts, addr)?;
+		socket.lock().1 = Role::Connected(conn);
+		epoll::notify();
+		Ok(())
+	}
+
+	fn bind_any(&self) -> Result<Arc<Mux>, UdtError> {
+		self.bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?;
+		match &socket(self.id)?.lock().1 {
+			Role::Bound(mux) => Ok(Arc::clone(mux)),
+			_ => Err(UdtError::new(5, 2)),
+		}
+	}
+
+	/// The address the socket is bound to.
+	pub fn getsockname(&self) -> Result<SocketAddr, UdtError> {
+		let mux = match &socket(self.id)?.lock().1 {
+			Role::Bound(mux) | Role::Listening(mux, _) => Arc::clone(mux),
+			Role::Connected(conn) => conn.mux(),
+			Role::Open => return Err(UdtError::new(5, 5)),
+		};
+
+		mux.local_addr().map_err(|_| UdtError::new(5, 5))
+	}
+
+	/// Closes the socket, once what was sent on it has been delivered.
+	pub fn close(self) -> Result<(), UdtError> {
+		socket(self.id)?;
+		close(self.id, true);
+		Ok(())
+	}
+
+	/// Sends as much of `buf` as fits in the send buffer, once any of it does.
+	pub fn send(&self, buf: &[u8]) -> Result<i32, UdtError> {
+		let sent = socket(self.id)?.conn()?.send(buf)?;
+		Ok(i32::try_from(sent).unwrap_or(i32::MAX))
+	}
+
+	/// Reads up to `len` bytes of the stream.
+	pub fn recv(&self, buf: &mut [u8], len: usize) -> Result<i32, UdtError> {
+		let socket = socket(self.id)?;
+		let timeout = socket.lock().0.rcv_timeout;
+		let len = len.min(buf.len());
+
+		let recvd = socket.conn()?.recv(&mut buf[..len], timeout)?;
+		Ok(i32::try_from(recvd).unwrap_or(i32::MAX))
+	}
+
+	/// Reads the next message, which is cut short if it does not fit in `buf`.
+	pub fn recvmsg(&self, buf: &mut [u8]) -> Result<usize, UdtError> {
+		let socket = socket(self.id)?;
+		let timeout = socket.lock().0.rcv_timeout;
+		socket.conn()?.recv_msg(buf, timeout)
+	}
+
+	pub fn getsockopt<T: UdtOption<i32>>(&self, opt: T) -> Result<i32, UdtError> {
+		let socket = socket(self.id)?;
+		let state = socket.lock();
+		let conn = match &state.1 {
+			Role::Connected(conn) => Some(conn),
+			_ => None,
+		};
+
+		let value = match opt.opt() {
+			Opt::SndBuf => state.0.snd_buf * packet::payload_len(packet::MSS),
+			Opt::RcvBuf => state.0.rcv_buf * packet::payload_len(packet::MSS),
+			Opt::RcvTimeo => return Ok(state.0.rcv_timeout.map_or(-1, |timeout| timeout.as_millis() as i32)),
+			Opt::SndData => conn.map_or(0, |conn| conn.send_data()),
+			Opt::RcvData => conn.map_or(0, |conn| conn.recv_data()),
+		};
+
+		Ok(i32::try_from(value).unwrap_or(i32::MAX))
+	}
+
+	/// Sets `opt`, which only applies to connections made after it is set.
+	/// (Except for the read timeout.)
+	pub fn setsockopt<T: UdtOption<i32>>(&self, opt: T, value: i32) -> Result<(), UdtError> {
+		let socket = socket(self.id)?;
+		let mut state = socket.lock();
+
+		// the buffers are counted in packets, of what each can carry
+		let packets = usize::try_from(value).ok()
+			.filter(|&bytes| bytes > 0)
+			.map(|bytes| bytes / (packet::MSS as usize - 28));
+
+		match (opt.opt(), packets) {
+			(Opt::SndBuf, Some(packets)) => state.0.snd_buf = packets.max(1),
+			(Opt::RcvBuf, Some(packets)) => state.0.rcv_buf = packets.clamp(MIN_RCV_BUF, conn::FLIGHT_FLAG as usize),
+			(Opt::RcvTimeo, _) => state.0.rcv_timeout = u64::try_from(value).ok().map(Duration::from_millis),
+			_ => return Err(UdtError::new(5, 3)),
+		}
+
+		Ok(())
+	}
+
+	/// Replaces UDT's congestion control w/ a controller built by `factory`
+	/// on each connection made after it is set, which decides how many
+	/// packets may be in flight. Its packets are then sent as fast as that
+	/// window allows. (UDT4 takes a `CCC` through its `UDT_CC` option for
+	/// this, which the `udt` bindings do not expose.)
+	pub fn set_congestion(&self, factory: ControllerFactory) -> Result<(), UdtError> {
+		socket(self.id)?.lock().0.congestion = Some(factory);
+		Ok(())
+	}
+}
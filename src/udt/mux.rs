@@ -0,0 +1,200 @@
+//! The UDP socket which a listener shares w/ the connections it accepted,
+//! and the thread which reads it. (See: UDT4's `CMultiplexer`.)
+//!
+//! Packets are handed to whichever socket their destination id names, or
+//! to the listener if it is zero. The thread also runs the timers of each
+//! connection, and exits once the last socket using it is closed.
+
+use super::conn::{Conn, SYN};
+use super::handshake::{Connecting, Listener};
+use super::packet::{Control, Handshake, Packet};
+use super::UdtError;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::Instant;
+
+/// How much the kernel is asked to buffer for the UDP socket, as UDT4 asks
+/// for its receive buffer's worth. (It is capped by `net.core.rmem_max`.)
+#[cfg(target_os = "linux")]
+const UDP_RCV_BUF: usize = 8192 * super::packet::MSS as usize;
+
+/// The muxes which are bound to a port, so that another socket bound to it
+/// shares the one there is. (e.g: a hub listening again, w/ the connections
+/// its last listener accepted still open.)
+static MUXES: LazyLock<Mutex<HashMap<SocketAddr, Weak<Mux>>>> = LazyLock::new(Default::default);
+
+pub(super) struct Mux {
+	sock: UdpSocket,
+	routes: Mutex<Routes>,
+}
+
+#[derive(Default)]
+struct Routes {
+	listener: Option<Arc<Listener>>,
+	conns: HashMap<i32, Arc<Conn>>,
+	connecting: HashMap<i32, Arc<Connecting>>,
+}
+
+impl Mux {
+	/// Returns the mux bound to `addr`, binding a new one if there is none
+	/// (or if it is any free port.)
+	pub(super) fn bind(addr: SocketAddr) -> io::Result<Arc<Mux>> {
+		let mut muxes = MUXES.lock().expect("udt mux lock poisoned");
+		if addr.port() != 0 {
+			if let Some(mux) = muxes.get(&addr).and_then(Weak::upgrade) {
+				return Ok(mux);
+			}
+		}
+
+		let sock = UdpSocket::bind(addr)?;
+		sock.set_read_timeout(Some(SYN))?;
+		#[cfg(target_os = "linux")]
+		set_rcv_buf(&sock, UDP_RCV_BUF);
+
+		let mux = Arc::new(Mux { sock, routes: Mutex::new(Routes::default()) });
+		muxes.insert(mux.sock.local_addr()?, Arc::downgrade(&mux));
+
+		let reader = Arc::clone(&mux);
+		thread::spawn(move || reader.run());
+		Ok(mux)
+	}
+
+	fn routes(&self) -> MutexGuard<'_, Routes> {
+		self.routes.lock().expect("udt mux lock poisoned")
+	}
+
+	pub(super) fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.sock.local_addr()
+	}
+
+	pub(super) fn send_to(&self, packet: &[u8], peer: SocketAddr) {
+		// as w/ any datagram this may be lost, which the protocol copes with
+		let _ = self.sock.send_to(packet, peer);
+	}
+
+	pub(super) fn set_listener(&self, listener: Arc<Listener>) -> Result<(), UdtError> {
+		let mut routes = self.routes();
+		if routes.listener.is_some() {
+			return Err(UdtError::new(5, 11));
+		}
+
+		routes.listener = Some(listener);
+		Ok(())
+	}
+
+	pub(super) fn clear_listener(&self) {
+		self.routes().listener = None;
+	}
+
+	pub(super) fn add_conn(&self, conn: Arc<Conn>) {
+		self.routes().conns.insert(conn.id, conn);
+	}
+
+	pub(super) fn remove_conn(&self, id: i32) {
+		self.routes().conns.remove(&id);
+	}
+
+	pub(super) fn add_connecting(&self, id: i32, connecting: Arc<Connecting>) {
+		self.routes().connecting.insert(id, connecting);
+	}
+
+	pub(super) fn remove_connecting(&self, id: i32) {
+		self.routes().connecting.remove(&id);
+	}
+
+	fn run(self: Arc<Self>) {
+		let mut buf = vec![0; 65_536];
+		let mut next_tick = Instant::now() + SYN;
+
+		loop {
+			if let Ok((len, from)) = self.sock.recv_from(&mut buf) {
+				self.dispatch(&buf[..len], from);
+			}
+
+			let now = Instant::now();
+			if now < next_tick {
+				continue;
+			}
+
+			next_tick = now + SYN;
+			let conns: Vec<_> = self.routes().conns.values().cloned().collect();
+			for conn in conns {
+				conn.check_timers();
+			}
+
+			if self.is_unused() {
+				return;
+			}
+		}
+	}
+
+	/// Whether this thread is all that is left using the mux, in which case
+	/// it is forgotten (so nothing else can pick it up.)
+	fn is_unused(self: &Arc<Self>) -> bool {
+		let mut muxes = MUXES.lock().expect("udt mux lock poisoned");
+		if Arc::strong_count(self) > 1 {
+			return false;
+		}
+
+		muxes.retain(|_, mux| mux.strong_count() > 0);
+		true
+	}
+
+	fn dispatch(self: &Arc<Self>, buf: &[u8], from: SocketAddr) {
+		let packet = match Packet::parse(buf) {
+			Some(packet) => packet,
+			None => return,
+		};
+
+		let handshake = match packet {
+			Packet::Control { ty: Control::Handshake, body, .. } => Handshake::parse(body),
+			_ => None,
+		};
+
+		let dest = packet.dest();
+		if dest == 0 {
+			let listener = self.routes().listener.clone();
+			if let (Some(listener), Some(handshake)) = (listener, handshake) {
+				listener.on_handshake(self, handshake, from);
+			}
+
+			return;
+		}
+
+		let (conn, connecting) = {
+			let routes = self.routes();
+			(routes.conns.get(&dest).cloned(), routes.connecting.get(&dest).cloned())
+		};
+
+		match (conn, connecting, handshake) {
+			(Some(conn), _, _) if conn.peer == from => conn.process(packet),
+			(None, Some(connecting), Some(handshake)) => connecting.on_handshake(handshake),
+			_ => {},
+		}
+	}
+}
+
+/// Asks the kernel to buffer up to `len` bytes of packets for `sock`, so a
+/// burst which arrives while the thread is busy is not dropped. This is only
+/// a hint, which is ignored if it cannot be had.
+#[cfg(target_os = "linux")]
+fn set_rcv_buf(sock: &UdpSocket, len: usize) {
+	use std::convert::TryFrom;
+	use std::mem;
+	use std::os::unix::io::AsRawFd;
+
+	let len = libc::c_int::try_from(len).unwrap_or(libc::c_int::MAX);
+	unsafe {
+		libc::setsockopt(
+			sock.as_raw_fd(),
+			libc::SOL_SOCKET,
+			libc::SO_RCVBUF,
+			&len as *const libc::c_int as *const libc::c_void,
+			mem::size_of::<libc::c_int>() as libc::socklen_t,
+		);
+	}
+}
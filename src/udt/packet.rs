@@ -0,0 +1,357 @@
+//! UDT's packets, laid out on the wire as UDT4 lays them out. (See: UDT4's
+//! `packet.cpp`.)
+//!
+//! Every packet starts w/ four big endian words. A data packet's are its
+//! sequence number, its message number (and the flags carried w/ it), a
+//! timestamp & the socket id it is sent to. A control packet's first word
+//! is its type (w/ the high bit set), and its second is whatever else the
+//! type needs. (e.g: which ACK an ACK2 acknowledges.)
+
+use byteorder::{ByteOrder, NetworkEndian};
+use std::net::{IpAddr, SocketAddr};
+
+/// The largest packet which is sent, including the IP & UDP headers. This
+/// is UDT's default, and the smaller of the two peers' is used.
+pub(super) const MSS: i32 = 1500;
+
+/// The room for UDT's own header in each packet.
+pub(super) const HEADER_LEN: usize = 16;
+
+/// How much of the stream each data packet carries. (See: `MSS`.)
+pub(super) fn payload_len(mss: i32) -> usize {
+	(mss as usize).saturating_sub(28 + HEADER_LEN)
+}
+
+/// The version of the protocol spoken, which is UDT4's.
+pub(super) const VERSION: i32 = 4;
+
+/// Sequence numbers are 31 bits, & wrap back to zero.
+pub(super) const MAX_SEQ: u32 = 0x7FFF_FFFF;
+
+/// Message numbers are 29 bits, & wrap back to one.
+pub(super) const MAX_MSG_NO: u32 = 0x1FFF_FFFF;
+
+/// Set on the message number of the first packet of a message.
+pub(super) const MSG_FIRST: u32 = 0x8000_0000;
+
+/// Set on the message number of the last packet of a message.
+pub(super) const MSG_LAST: u32 = 0x4000_0000;
+
+/// Set on the message number of a message which has to wait on the ones
+/// sent before it.
+pub(super) const MSG_IN_ORDER: u32 = 0x2000_0000;
+
+/// The types of control packet. (Data packets are told apart by the high
+/// bit of their first word, which is clear.)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Control {
+	Handshake = 0,
+	KeepAlive = 1,
+	Ack = 2,
+	Nak = 3,
+	Shutdown = 5,
+	Ack2 = 6,
+	DropRequest = 7,
+}
+
+impl Control {
+	fn from_u16(ty: u16) -> Option<Self> {
+		Some(match ty {
+			0 => Control::Handshake,
+			1 => Control::KeepAlive,
+			2 => Control::Ack,
+			3 => Control::Nak,
+			5 => Control::Shutdown,
+			6 => Control::Ack2,
+			7 => Control::DropRequest,
+			_ => return None,
+		})
+	}
+}
+
+/// A packet which was received, borrowed from the buffer it was read into.
+pub(super) enum Packet<'a> {
+	Data { seq: u32, msg: u32, dest: i32, payload: &'a [u8] },
+
+	/// A control packet of a type this implementation does anything with,
+	/// the rest (i.e: congestion warnings) are dropped as they are parsed.
+	Control { ty: Control, info: u32, dest: i32, body: &'a [u8] },
+}
+
+impl<'a> Packet<'a> {
+	pub(super) fn parse(buf: &'a [u8]) -> Option<Self> {
+		if buf.len() < HEADER_LEN {
+			return None;
+		}
+
+		let first = NetworkEndian::read_u32(&buf[0..4]);
+		let info = NetworkEndian::read_u32(&buf[4..8]);
+		let dest = NetworkEndian::read_i32(&buf[12..16]);
+		let body = &buf[HEADER_LEN..];
+
+		if first & 0x8000_0000 == 0 {
+			return Some(Packet::Data { seq: first, msg: info, dest, payload: body });
+		}
+
+		let ty = Control::from_u16(((first >> 16) & 0x7FFF) as u16)?;
+		Some(Packet::Control { ty, info, dest, body })
+	}
+
+	/// The id of the socket this packet was sent to, where zero is a
+	/// listener's.
+	pub(super) fn dest(&self) -> i32 {
+		match *self {
+			Packet::Data { dest, .. } | Packet::Control { dest, .. } => dest,
+		}
+	}
+}
+
+/// Writes a data packet carrying `payload`.
+pub(super) fn data(seq: u32, msg: u32, ts: u32, dest: i32, payload: &[u8]) -> Vec<u8> {
+	let mut buf = vec![0; HEADER_LEN + payload.len()];
+	NetworkEndian::write_u32(&mut buf[0..4], seq & MAX_SEQ);
+	NetworkEndian::write_u32(&mut buf[4..8], msg);
+	NetworkEndian::write_u32(&mut buf[8..12], ts);
+	NetworkEndian::write_i32(&mut buf[12..16], dest);
+	buf[HEADER_LEN..].copy_from_slice(payload);
+	buf
+}
+
+/// Writes a control packet whose body is `body`. UDT4 pads the types w/o a
+/// body out to one word, so that is done here too.
+pub(super) fn control(ty: Control, info: u32, ts: u32, dest: i32, body: &[u32]) -> Vec<u8> {
+	let words = body.len().max(1);
+	let mut buf = vec![0; HEADER_LEN + words * 4];
+	NetworkEndian::write_u32(&mut buf[0..4], 0x8000_0000 | (ty as u32) << 16);
+	NetworkEndian::write_u32(&mut buf[4..8], info);
+	NetworkEndian::write_u32(&mut buf[8..12], ts);
+	NetworkEndian::write_i32(&mut buf[12..16], dest);
+	NetworkEndian::write_u32_into(body, &mut buf[HEADER_LEN..HEADER_LEN + body.len() * 4]);
+	buf
+}
+
+/// Reads the words of a control packet's body.
+pub(super) fn words(body: &[u8]) -> Vec<u32> {
+	body.chunks_exact(4).map(NetworkEndian::read_u32).collect()
+}
+
+/// What the handshake's request type is set to by a connecting socket. It
+/// first asks for a cookie, & then sends it back w/ `REQ_RESPONSE`.
+pub(super) const REQ_CONNECT: i32 = 1;
+
+/// What a handshake's request type is set to by a connecting socket which
+/// has its cookie, and by a listener accepting it.
+pub(super) const REQ_RESPONSE: i32 = -1;
+
+/// What a listener sets the request type to when it refuses a connection.
+pub(super) const REQ_REJECTED: i32 = 1002;
+
+/// The handshake which sets up a connection. (See: UDT4's `CHandShake`.)
+#[derive(Clone, Debug)]
+pub(super) struct Handshake {
+	pub version: i32,
+	pub ty: i32,
+	pub isn: u32,
+	pub mss: i32,
+	pub flight_flag: i32,
+	pub req: i32,
+	pub id: i32,
+	pub cookie: i32,
+	pub peer_ip: [u32; 4],
+}
+
+impl Handshake {
+	const LEN: usize = 48;
+
+	pub(super) fn parse(body: &[u8]) -> Option<Self> {
+		if body.len() < Self::LEN {
+			return None;
+		}
+
+		let word = |i: usize| NetworkEndian::read_u32(&body[i * 4..i * 4 + 4]);
+		Some(Self {
+			version: word(0) as i32,
+			ty: word(1) as i32,
+			isn: word(2) & MAX_SEQ,
+			mss: word(3) as i32,
+			flight_flag: word(4) as i32,
+			req: word(5) as i32,
+			id: word(6) as i32,
+			cookie: word(7) as i32,
+			peer_ip: [word(8), word(9), word(10), word(11)],
+		})
+	}
+
+	pub(super) fn words(&self) -> [u32; 12] {
+		let [ip0, ip1, ip2, ip3] = self.peer_ip;
+		[
+			self.version as u32, self.ty as u32, self.isn, self.mss as u32,
+			self.flight_flag as u32, self.req as u32, self.id as u32, self.cookie as u32,
+			ip0, ip1, ip2, ip3,
+		]
+	}
+
+	/// The address the handshake tells the peer it was sent to. UDT4 copies
+	/// the `in_addr` as it sits in memory into the first word, which on the
+	/// little endian machines it is built for reverses the octets. Nothing
+	/// reads it back, but it is written the same way.
+	pub(super) fn ip_of(addr: SocketAddr) -> [u32; 4] {
+		match addr.ip() {
+			IpAddr::V4(ip) => [u32::from_le_bytes(ip.octets()), 0, 0, 0],
+			IpAddr::V6(_) => [0; 4],
+		}
+	}
+}
+
+/// How far it is from the sequence number `from` to `to`, which may be
+/// negative, going the short way around where they wrap.
+pub(super) fn seq_off(from: u32, to: u32) -> i64 {
+	let off = i64::from(to) - i64::from(from);
+	let span = i64::from(MAX_SEQ) + 1;
+
+	if off.abs() < span / 2 {
+		off
+	} else if off > 0 {
+		off - span
+	} else {
+		off + span
+	}
+}
+
+/// The sequence number `n` past `seq`.
+pub(super) fn seq_add(seq: u32, n: u64) -> u32 {
+	((u64::from(seq) + n) & u64::from(MAX_SEQ)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn bytes(words: &[u32]) -> Vec<u8> {
+		words.iter().flat_map(|word| word.to_be_bytes()).collect()
+	}
+
+	#[test]
+	fn data_header_round_trips() {
+		let buf = data(0x1234_5678, MSG_FIRST | 42, 0xAABB_CCDD, 0x0102_0304, b"payload");
+		assert_eq!(&buf[..HEADER_LEN], &bytes(&[0x1234_5678, 0x8000_002A, 0xAABB_CCDD, 0x0102_0304])[..]);
+
+		match Packet::parse(&buf) {
+			Some(Packet::Data { seq, msg, dest, payload }) => {
+				assert_eq!((seq, msg, dest), (0x1234_5678, MSG_FIRST | 42, 0x0102_0304));
+				assert_eq!(payload, b"payload");
+			},
+
+			_ => panic!("expected a data packet"),
+		}
+	}
+
+	#[test]
+	fn data_sequence_is_31_bits() {
+		// the high bit would make it a control packet
+		let buf = data(0xFFFF_FFFF, 1, 0, 1, &[]);
+		assert_eq!(&buf[0..4], &MAX_SEQ.to_be_bytes());
+		assert!(matches!(Packet::parse(&buf), Some(Packet::Data { seq: MAX_SEQ, .. })));
+	}
+
+	#[test]
+	fn sequence_numbers_wrap() {
+		assert_eq!(seq_add(MAX_SEQ, 1), 0);
+		assert_eq!(seq_add(MAX_SEQ - 1, 3), 1);
+		assert_eq!(seq_add(7, 0), 7);
+
+		assert_eq!(seq_off(5, 10), 5);
+		assert_eq!(seq_off(10, 5), -5);
+		assert_eq!(seq_off(MAX_SEQ, 0), 1);
+		assert_eq!(seq_off(0, MAX_SEQ), -1);
+		assert_eq!(seq_off(MAX_SEQ - 2, 2), 5);
+	}
+
+	#[test]
+	fn message_flags_match_udt4() {
+		// UDT4's message number word: 2 bits of boundary (first = 10, last =
+		// 01, a message in one packet = 11), 1 bit of in-order, 29 of number
+		assert_eq!(MSG_FIRST, 0b10 << 30);
+		assert_eq!(MSG_LAST, 0b01 << 30);
+		assert_eq!(MSG_IN_ORDER, 1 << 29);
+		assert_eq!(MAX_MSG_NO, (1 << 29) - 1);
+		assert_eq!(MSG_FIRST | MSG_LAST | MSG_IN_ORDER | MAX_MSG_NO, u32::MAX);
+
+		let solo = MSG_FIRST | MSG_LAST | MSG_IN_ORDER | 5;
+		let buf = data(1, solo, 0, 1, b"x");
+		assert_eq!(&buf[4..8], &[0xE0, 0, 0, 5]);
+
+		match Packet::parse(&buf) {
+			Some(Packet::Data { msg, .. }) => {
+				assert_eq!(msg & (MSG_FIRST | MSG_LAST), MSG_FIRST | MSG_LAST);
+				assert_eq!(msg & MSG_IN_ORDER, MSG_IN_ORDER);
+				assert_eq!(msg & MAX_MSG_NO, 5);
+			},
+
+			_ => panic!("expected a data packet"),
+		}
+
+		// a packet in the middle of a message has neither boundary bit
+		let buf = data(2, 6, 0, 1, b"y");
+		assert!(matches!(Packet::parse(&buf), Some(Packet::Data { msg: 6, .. })));
+	}
+
+	#[test]
+	fn control_header_round_trips() {
+		let buf = control(Control::Nak, 9, 100, 77, &[0x8000_0003, 5, 8]);
+		assert_eq!(&buf[..HEADER_LEN], &bytes(&[0x8003_0000, 9, 100, 77])[..]);
+
+		match Packet::parse(&buf) {
+			Some(Packet::Control { ty: Control::Nak, info: 9, dest: 77, body }) => {
+				assert_eq!(words(body), vec![0x8000_0003, 5, 8]);
+			},
+
+			_ => panic!("expected a nak"),
+		}
+
+		// a type w/o a body is padded out to a word, as UDT4 sends it
+		let buf = control(Control::Ack2, 3, 0, 1, &[]);
+		assert_eq!(buf.len(), HEADER_LEN + 4);
+		assert_eq!(&buf[0..4], &[0x80, 0x06, 0, 0]);
+		assert!(matches!(Packet::parse(&buf), Some(Packet::Control { ty: Control::Ack2, info: 3, .. })));
+	}
+
+	#[test]
+	fn unknown_and_short_packets_are_dropped() {
+		// a congestion warning, which is not acted on
+		let mut buf = control(Control::KeepAlive, 0, 0, 1, &[]);
+		buf[1] = 4;
+		assert!(Packet::parse(&buf).is_none());
+		assert!(Packet::parse(&buf[..HEADER_LEN - 1]).is_none());
+	}
+
+	#[test]
+	fn handshake_round_trips() {
+		let handshake = Handshake {
+			version: VERSION,
+			ty: 2,
+			isn: 0x0ABC_DEF0,
+			mss: MSS,
+			flight_flag: 8192,
+			req: REQ_RESPONSE,
+			id: 0x1234_5678,
+			cookie: -7,
+			peer_ip: Handshake::ip_of(SocketAddr::from(([127, 0, 0, 1], 9000))),
+		};
+
+		// UDT4's `CHandShake`, one word per field in this order
+		let buf = control(Control::Handshake, 0, 0, 0, &handshake.words());
+		assert_eq!(&buf[HEADER_LEN..], &bytes(&[
+			4, 2, 0x0ABC_DEF0, 1500, 8192, 0xFFFF_FFFF, 0x1234_5678, 0xFFFF_FFF9,
+			0x0100_007F, 0, 0, 0,
+		])[..]);
+
+		let parsed = match Packet::parse(&buf) {
+			Some(Packet::Control { ty: Control::Handshake, body, .. }) => Handshake::parse(body).expect("handshake was cut short"),
+			_ => panic!("expected a handshake"),
+		};
+
+		assert_eq!(parsed.words(), handshake.words());
+		assert!(Handshake::parse(&buf[HEADER_LEN..HEADER_LEN + Handshake::LEN - 1]).is_none());
+	}
+}
@@ -0,0 +1,34 @@
+//! The calls which the `udt` bindings leave out, and which ubuffer makes
+//! through UDT's C wrapper when it is built w/ them. (See: `proto::raw`.)
+
+use super::{socket, UdtError, UdtSocket};
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+/// What the performance monitor reports for a connection. Only the counters
+/// ubuffer reports are kept.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TraceInfo {
+	pub pkt_sent_total: i64,
+	pub pkt_snd_loss_total: i32,
+	pub pkt_rcv_loss_total: i32,
+	pub pkt_retrans_total: i32,
+	pub ms_rtt: f64,
+	pub mbps_bandwidth: f64,
+}
+
+/// Reads the performance monitor of `sock`, or returns `None` if it is not
+/// connected. (e.g: it has been closed.)
+pub fn perfmon(sock: UdtSocket) -> Option<TraceInfo> {
+	socket(sock.id).ok()?.conn().ok()?.trace()
+}
+
+/// Sends `buf` as a single message on the datagram socket `sock`, which is
+/// dropped if it has not been delivered within `ttl` (unless it is `None`.)
+/// Messages are always delivered in order here, so `in_order` only marks
+/// them as UDT4 would.
+pub fn sendmsg(sock: UdtSocket, buf: &[u8], ttl: Option<Duration>, in_order: bool) -> Result<i32, UdtError> {
+	let sent = socket(sock.id)?.conn()?.send_msg(buf, ttl, in_order)?;
+	Ok(i32::try_from(sent).unwrap_or(i32::MAX))
+}
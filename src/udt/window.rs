@@ -0,0 +1,125 @@
+//! What the receiving side of a connection measures: the round trip time
+//! of its ACKs, and the rate & spacing packets arrive at. (See: UDT4's
+//! `window.cpp`.)
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How many ACKs are remembered until their ACK2 arrives.
+const ACK_WINDOW: usize = 1024;
+
+/// How many packet arrivals the receiving rate is measured over.
+const ARRIVALS: usize = 16;
+
+/// How many probe pairs the link's capacity is measured over.
+const PROBES: usize = 64;
+
+/// The ACKs which were sent, so that the round trip time can be taken
+/// when their ACK2 arrives.
+#[derive(Default)]
+pub(super) struct AckWindow {
+	sent: VecDeque<(i32, u64, Instant)>,
+}
+
+impl AckWindow {
+	pub(super) fn store(&mut self, ack_no: i32, ack: u64, now: Instant) {
+		if self.sent.len() == ACK_WINDOW {
+			self.sent.pop_front();
+		}
+
+		self.sent.push_back((ack_no, ack, now));
+	}
+
+	/// Returns what the ACK numbered `ack_no` acknowledged & how long ago it
+	/// was sent, forgetting it and any sent before it.
+	pub(super) fn acknowledge(&mut self, ack_no: i32, now: Instant) -> Option<(u64, Duration)> {
+		let pos = self.sent.iter().position(|&(no, _, _)| no == ack_no)?;
+		let (_, ack, sent) = self.sent[pos];
+		self.sent.drain(..=pos);
+		Some((ack, now.saturating_duration_since(sent)))
+	}
+}
+
+/// The gaps between the packets which arrived, & between the packets of
+/// each probe pair. The sender sends every 16th packet right after the one
+/// before it, so how far apart those two arrive is how fast the link is.
+pub(super) struct TimeWindow {
+	arrivals: VecDeque<u64>,
+	last_arrival: Instant,
+	probes: VecDeque<u64>,
+	probe_start: Option<Instant>,
+}
+
+impl TimeWindow {
+	pub(super) fn new(now: Instant) -> Self {
+		// UDT4 starts from these, so its first measurements are made from
+		// them too
+		Self {
+			arrivals: vec![1_000_000; ARRIVALS].into(),
+			last_arrival: now,
+			probes: vec![1_000; PROBES].into(),
+			probe_start: None,
+		}
+	}
+
+	pub(super) fn on_arrival(&mut self, now: Instant) {
+		let gap = now.saturating_duration_since(self.last_arrival).as_micros() as u64;
+		self.arrivals.pop_front();
+		self.arrivals.push_back(gap);
+		self.last_arrival = now;
+	}
+
+	/// The first packet of a probe pair arrived.
+	pub(super) fn on_probe1(&mut self, now: Instant) {
+		self.probe_start = Some(now);
+	}
+
+	/// The second packet of a probe pair arrived.
+	pub(super) fn on_probe2(&mut self, now: Instant) {
+		if let Some(start) = self.probe_start.take() {
+			let gap = now.saturating_duration_since(start).as_micros() as u64;
+			self.probes.pop_front();
+			self.probes.push_back(gap);
+		}
+	}
+
+	/// How many packets arrive a second, or zero until it is known.
+	pub(super) fn recv_speed(&self) -> i32 {
+		let (sum, count) = filtered(&self.arrivals);
+		if count <= ARRIVALS / 2 || sum == 0 {
+			return 0;
+		}
+
+		(1e6 / (sum as f64 / count as f64)).ceil() as i32
+	}
+
+	/// How many packets a second the link can carry.
+	pub(super) fn bandwidth(&self) -> i32 {
+		// the median itself is counted too, unlike for the receiving speed
+		let (sum, count) = filtered(&self.probes);
+		let median = median(&self.probes);
+		let (sum, count) = (sum + median, count + 1);
+		if sum == 0 {
+			return 0;
+		}
+
+		(1e6 / (sum as f64 / count as f64)).ceil() as i32
+	}
+}
+
+fn median(window: &VecDeque<u64>) -> u64 {
+	let mut sorted: Vec<u64> = window.iter().copied().collect();
+	sorted.sort_unstable();
+	sorted[sorted.len() / 2]
+}
+
+/// Sums the gaps within a factor of eight of the median, as a packet which
+/// was held up (or sent in a burst) says nothing about the link.
+fn filtered(window: &VecDeque<u64>) -> (u64, usize) {
+	let median = median(window);
+	let (lower, upper) = (median / 8, median * 8);
+
+	window.iter()
+		.filter(|&&gap| gap > lower && gap < upper)
+		.fold((0, 0), |(sum, count), &gap| (sum + gap, count + 1))
+}
@@ -0,0 +1,88 @@
+//! Runs complete sessions between an `AsyncSender` & `AsyncReceiver` over an
+//! in-memory tokio duplex. (Requires the `async` feature.)
+#![cfg(feature = "async")]
+
+extern crate rand;
+extern crate tokio;
+extern crate ubuffer;
+
+use rand::RngCore;
+use std::io::Cursor;
+use ubuffer::error::ProtoError;
+use ubuffer::proto::{generate_code, AsyncReceiver, AsyncSender, Identity, BLOCK_SIZE};
+
+fn random_bytes(len: usize) -> Vec<u8> {
+	let mut buf = vec![0u8; len];
+	rand::thread_rng().fill_bytes(&mut buf);
+	buf
+}
+
+// a runtime w/ a single thread completes them all, so none of the sessions
+// can be blocking it while it waits on its peer.
+#[tokio::test(flavor = "current_thread")]
+async fn sessions_share_a_runtime() {
+	let key = random_bytes(32);
+	let mut sessions = vec![];
+
+	for len in &[0, 1, BLOCK_SIZE, 3 * BLOCK_SIZE + 1, 300 * BLOCK_SIZE] {
+		let payload = random_bytes(*len);
+		let (near, far) = tokio::io::duplex(16 * 1024);
+
+		let receiver = AsyncReceiver::new(far, &key).unwrap();
+		let mut sender = AsyncSender::new(near, &key).unwrap();
+		sender.get_mut().set_rekey_interval(BLOCK_SIZE as u64);
+
+		let receiving = tokio::spawn(receiver.run(Vec::new()));
+		let sending = tokio::spawn(sender.run(Cursor::new(payload.clone())));
+		sessions.push((payload, sending, receiving));
+	}
+
+	for (payload, sending, receiving) in sessions {
+		let sent = sending.await.unwrap().expect("sender failed");
+		let (received, output) = receiving.await.unwrap().expect("receiver failed");
+
+		assert_eq!(sent.plaintext_bytes, payload.len() as u64);
+		assert_eq!(received.plaintext_bytes, payload.len() as u64);
+		assert!(output == payload, "payload of {} bytes was corrupted", payload.len());
+	}
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn handshake_agrees_on_a_code_and_identities() {
+	let code = generate_code();
+	let identity = Identity::from_pkcs8(&Identity::generate().unwrap()).unwrap();
+	let payload = random_bytes(2 * BLOCK_SIZE + 3);
+	let (near, far) = tokio::io::duplex(16 * 1024);
+
+	let mut receiver = AsyncReceiver::new(far, &random_bytes(32)).unwrap();
+	receiver.get_mut().set_code(&code);
+	receiver.get_mut().set_identity(identity.clone());
+
+	let mut sender = AsyncSender::new(near, &random_bytes(32)).unwrap();
+	sender.get_mut().set_code(&code);
+	sender.get_mut().set_require_receipt(true);
+
+	let receiving = tokio::spawn(receiver.run(Vec::new()));
+	let sent = sender.run(Cursor::new(payload.clone())).await.expect("sender failed");
+	let (_, output) = receiving.await.unwrap().expect("receiver failed");
+
+	assert_eq!(sent.plaintext_bytes, payload.len() as u64);
+	assert!(output == payload, "payload was corrupted");
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn mismatched_keys_are_rejected() {
+	let (near, far) = tokio::io::duplex(64 * 1024);
+
+	let receiver = AsyncReceiver::new(far, &random_bytes(32)).unwrap();
+	let sender = AsyncSender::new(near, &random_bytes(32)).unwrap();
+
+	let receiving = tokio::spawn(receiver.run(Vec::new()));
+	let sent = sender.run(Cursor::new(random_bytes(BLOCK_SIZE))).await;
+
+	assert!(matches!(sent, Err(ProtoError::HandshakeRejected)), "got {:?}", sent.map(|_| ()));
+	match receiving.await.unwrap() {
+		Err(ProtoError::CryptoErr) => {},
+		other => panic!("expected a crypto error, got {:?}", other.map(|(summary, _)| summary)),
+	}
+}
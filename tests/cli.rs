@@ -0,0 +1,432 @@
+//! Runs the `ubuffer` binary between processes on the loopback interface, for
+//! the options which the command line handles rather than the library.
+//! (Requires the `udt` feature.)
+#![cfg(feature = "udt")]
+
+extern crate rand;
+extern crate ring;
+extern crate ubuffer;
+
+use rand::RngCore;
+use ring::digest;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use ubuffer::key;
+
+mod common;
+
+/// How long a process is given to finish before the test gives up on it.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn random_bytes(len: usize) -> Vec<u8> {
+	let mut buf = vec![0u8; len];
+	rand::thread_rng().fill_bytes(&mut buf);
+	buf
+}
+
+/// A directory of its own for each test, removed once the test is over.
+struct Scratch(PathBuf);
+
+impl Scratch {
+	fn new(name: &str) -> Self {
+		let dir = std::env::temp_dir().join(format!("ubuffer-cli-{}-{}", name, process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		Scratch(dir)
+	}
+
+	fn path(&self, name: &str) -> PathBuf {
+		self.0.join(name)
+	}
+}
+
+impl Drop for Scratch {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.0);
+	}
+}
+
+/// The `ubuffer` binary, w/ the key both peers share in its environment.
+fn ubuffer() -> Command {
+	let mut cmd = Command::new(env!("CARGO_BIN_EXE_ubuffer"));
+	cmd.env("UBUFFER_KEY", key::format(&[7u8; 32], None).trim())
+		.env_remove("RUST_LOG")
+		.stdin(Stdio::null())
+		.stdout(Stdio::null())
+		.stderr(Stdio::piped());
+
+	cmd
+}
+
+/// Starts a receiver listening on `addr` w/ `args`.
+fn receiver(addr: SocketAddr, args: &[&str]) -> Child {
+	ubuffer().arg("receiver").arg(addr.to_string()).args(args)
+		.spawn()
+		.expect("could not start the receiver")
+}
+
+/// Runs a sender to `addr` w/ `args`, which sends `input` if it reads stdin.
+fn send(addr: SocketAddr, args: &[&str], input: &[u8]) -> (ExitStatus, String) {
+	send_w(ubuffer(), addr, args, input)
+}
+
+/// Like `send()`, but runs `cmd`, which may be given the options which come
+/// before the subcommand.
+fn send_w(mut cmd: Command, addr: SocketAddr, args: &[&str], input: &[u8]) -> (ExitStatus, String) {
+	let mut sender = cmd.arg("sender").arg(addr.to_string())
+		.args(["--retry", "5", "--retry-delay", "200ms"])
+		.args(args)
+		.stdin(Stdio::piped())
+		.spawn()
+		.expect("could not start the sender");
+
+	// a sender reading from elsewhere may never read its stdin
+	let _ = sender.stdin.take().unwrap().write_all(input);
+	finish(sender)
+}
+
+/// Waits for `child` to exit, returning its status & what it wrote to stderr.
+fn finish(mut child: Child) -> (ExitStatus, String) {
+	let started = Instant::now();
+	let status = loop {
+		if let Some(status) = child.try_wait().unwrap() {
+			break status;
+		}
+
+		if started.elapsed() > TIMEOUT {
+			let _ = child.kill();
+			panic!("ubuffer did not exit within {:?}", TIMEOUT);
+		}
+
+		thread::sleep(Duration::from_millis(50));
+	};
+
+	let mut stderr = String::new();
+	child.stderr.take().unwrap().read_to_string(&mut stderr).unwrap();
+	(status, stderr)
+}
+
+/// Sends `payload` from a sender run w/ `send_args` to a receiver run w/
+/// `recv_args`, both of which must succeed.
+fn transfer(payload: &[u8], send_args: &[&str], recv_args: &[&str]) {
+	let addr = common::free_addr();
+	let receiving = receiver(addr, recv_args);
+
+	let (status, stderr) = send(addr, send_args, payload);
+	assert!(status.success(), "sender failed: {}", stderr);
+
+	let (status, stderr) = finish(receiving);
+	assert!(status.success(), "receiver failed: {}", stderr);
+}
+
+/// Returns the files beneath `dir`, relative to it & sorted.
+fn files_in(dir: &Path) -> Vec<String> {
+	let mut files = vec![];
+	for entry in fs::read_dir(dir).unwrap() {
+		let path = entry.unwrap().path();
+		let name = path.file_name().unwrap().to_str().unwrap().to_string();
+
+		match path.is_dir() {
+			true => files.extend(files_in(&path).into_iter().map(|file| format!("{}/{}", name, file))),
+			false => files.push(name),
+		}
+	}
+
+	files.sort();
+	files
+}
+
+/// Returns a port on `127.0.0.1` which no TCP listener is bound to.
+fn free_tcp_port() -> u16 {
+	TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Sends a GET for `path` to the HTTP server on `port`, returning the body.
+fn http_get(port: u16, path: &str) -> Option<String> {
+	let mut conn = TcpStream::connect(("127.0.0.1", port)).ok()?;
+	write!(conn, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).ok()?;
+
+	let mut response = String::new();
+	conn.read_to_string(&mut response).ok()?;
+	response.split_once("\r\n\r\n").map(|(_, body)| body.to_string())
+}
+
+/// Serves `body` over HTTP at `/file` on a port of its own, until the test
+/// exits. `/moved` redirects there, and anything else is not found.
+fn serve(body: Vec<u8>) -> u16 {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let port = listener.local_addr().unwrap().port();
+
+	thread::spawn(move || {
+		for conn in listener.incoming() {
+			let mut conn = match conn { Ok(conn) => conn, Err(_) => continue };
+			let mut request = vec![];
+			let mut buf = [0u8; 1024];
+
+			while !request.ends_with(b"\r\n\r\n") {
+				match conn.read(&mut buf) {
+					Ok(0) | Err(_) => break,
+					Ok(len) => request.extend_from_slice(&buf[..len]),
+				}
+			}
+
+			let request = String::from_utf8_lossy(&request);
+			let path = request.split(' ').nth(1).unwrap_or_default();
+			let _ = match path {
+				"/file" => write!(conn, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len())
+					.and_then(|_| conn.write_all(&body)),
+				"/moved" => write!(conn, "HTTP/1.1 302 Found\r\nLocation: /file\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+				_ => write!(conn, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+			};
+		}
+	});
+
+	port
+}
+
+/// Returns the value of the first sample of `metric` in an exposition.
+fn sample(exposition: &str, metric: &str) -> Option<u64> {
+	exposition.lines()
+		.filter(|line| !line.starts_with('#'))
+		.find_map(|line| line.strip_prefix(metric)?.strip_prefix(' ')?.trim().parse().ok())
+}
+
+/// Kills a receiver left running as a hub.
+fn stop(mut hub: Child) {
+	let _ = hub.kill();
+	let _ = hub.wait();
+}
+
+#[test]
+fn hub_serves_metrics() {
+	let scratch = Scratch::new("metrics");
+	let payload = random_bytes(300_000);
+	let addr = common::free_addr();
+	let port = free_tcp_port();
+
+	let template = scratch.path("{seq}.bin");
+	let hub = receiver(addr, &[
+		"--hub", template.to_str().unwrap(),
+		"--metrics-listen", &format!("127.0.0.1:{}", port),
+	]);
+
+	let (status, stderr) = send(addr, &[], &payload);
+	assert!(status.success(), "sender failed: {}", stderr);
+
+	// the session is counted once the hub has settled it
+	let started = Instant::now();
+	let exposition = loop {
+		let exposition = http_get(port, "/metrics").unwrap_or_default();
+		if sample(&exposition, "ubuffer_sessions_total") == Some(1) && sample(&exposition, "ubuffer_sessions_active") == Some(0) {
+			break exposition;
+		}
+
+		if started.elapsed() > TIMEOUT {
+			stop(hub);
+			panic!("the session was never counted:\n{}", exposition);
+		}
+
+		thread::sleep(Duration::from_millis(100));
+	};
+
+	stop(hub);
+	assert_eq!(sample(&exposition, "ubuffer_plaintext_bytes_total"), Some(payload.len() as u64));
+	assert_eq!(sample(&exposition, "ubuffer_session_failures_total"), Some(0));
+	assert!(exposition.contains("# TYPE ubuffer_sessions_total counter"));
+}
+
+#[test]
+fn logs_go_to_the_system_logger() {
+	let payload = random_bytes(1000);
+
+	let addr = common::free_addr();
+	let receiving = receiver(addr, &[]);
+	let mut cmd = ubuffer();
+	cmd.args(["-v", "--log", "stderr"]);
+
+	let (status, stderr) = send_w(cmd, addr, &[], &payload);
+	assert!(status.success(), "sender failed: {}", stderr);
+	assert!(stderr.contains("INFO"), "nothing was logged to stderr: {}", stderr);
+	assert!(finish(receiving).0.success());
+
+	// w/o the logger's socket there is nowhere to send the messages
+	for &(target, socket) in &[("syslog", "/dev/log"), ("journald", "/run/systemd/journal/socket")] {
+		let mut cmd = ubuffer();
+		cmd.args(["-v", "--log", target]);
+
+		if !Path::new(socket).exists() {
+			let (status, stderr) = send_w(cmd, common::free_addr(), &[], &payload);
+			assert!(!status.success(), "the sender ran w/o a {} socket", target);
+			assert!(stderr.contains(socket), "expected the missing {} socket to be named: {}", target, stderr);
+			continue;
+		}
+
+		let addr = common::free_addr();
+		let receiving = receiver(addr, &[]);
+		let (status, stderr) = send_w(cmd, addr, &[], &payload);
+		assert!(status.success(), "sender failed: {}", stderr);
+		assert!(!stderr.contains("INFO"), "messages for {} were logged to stderr: {}", target, stderr);
+		assert!(finish(receiving).0.success());
+	}
+}
+
+#[test]
+fn hub_output_names_are_expanded() {
+	let scratch = Scratch::new("template");
+	let addr = common::free_addr();
+
+	let template = scratch.path("{seq}-{addr}-{date}-{time}.bin");
+	let hub = receiver(addr, &["--hub", template.to_str().unwrap()]);
+
+	let payloads = [random_bytes(20_000), random_bytes(30_000)];
+	for payload in &payloads {
+		let (status, stderr) = send(addr, &[], payload);
+		if !status.success() {
+			stop(hub);
+			panic!("sender failed: {}", stderr);
+		}
+	}
+
+	stop(hub);
+
+	let mut names: Vec<String> = fs::read_dir(&scratch.0).unwrap()
+		.map(|entry| entry.unwrap().file_name().into_string().unwrap())
+		.collect();
+	names.sort();
+	assert_eq!(names.len(), payloads.len(), "expected a file per session: {:?}", names);
+
+	for (seq, (name, payload)) in names.iter().zip(&payloads).enumerate() {
+		let fields: Vec<&str> = name.trim_end_matches(".bin").split('-').collect();
+		assert_eq!(fields[..2], [(seq + 1).to_string().as_str(), "127.0.0.1"], "unexpected name {}", name);
+		assert!(fields[2].len() == 8 && fields[3].len() == 6, "unexpected date & time in {}", name);
+		assert!(fields[2..].iter().all(|field| field.bytes().all(|byte| byte.is_ascii_digit())), "unexpected date & time in {}", name);
+		assert!(fs::read(scratch.path(name)).unwrap() == *payload, "{} was corrupted", name);
+	}
+}
+
+#[test]
+fn archive_filters_pick_the_files_sent() {
+	let scratch = Scratch::new("filters");
+	let src = scratch.path("src");
+	let dst = scratch.path("dst");
+
+	for file in &["a.img", "notes.txt", "cache/c.img", "sub/b.img", "sub/b.txt"] {
+		let path = src.join(file);
+		fs::create_dir_all(path.parent().unwrap()).unwrap();
+		fs::write(&path, random_bytes(1000)).unwrap();
+	}
+
+	fs::create_dir_all(&dst).unwrap();
+	transfer(&[], &["--tar", src.to_str().unwrap(), "--include", "*.img", "--exclude", "cache"], &["--untar", dst.to_str().unwrap()]);
+
+	assert_eq!(files_in(&dst), ["a.img", "sub/b.img"]);
+	assert!(fs::read(dst.join("sub/b.img")).unwrap() == fs::read(src.join("sub/b.img")).unwrap(), "sub/b.img was corrupted");
+}
+
+#[test]
+fn append_keeps_what_the_output_held() {
+	let scratch = Scratch::new("append");
+	let out = scratch.path("log.bin");
+	let (head, tail) = (random_bytes(5_000), random_bytes(70_000));
+	fs::write(&out, &head).unwrap();
+
+	transfer(&tail, &[], &["--out", out.to_str().unwrap(), "--append"]);
+
+	let received = fs::read(&out).unwrap();
+	assert_eq!(received.len(), head.len() + tail.len());
+	assert!(received[..head.len()] == head[..] && received[head.len()..] == tail[..], "the output was not appended to");
+}
+
+#[test]
+fn url_input_is_sent() {
+	let scratch = Scratch::new("url");
+	let out = scratch.path("out.bin");
+	let payload = random_bytes(200_000);
+	let port = serve(payload.clone());
+
+	let url = format!("http://127.0.0.1:{}/moved", port);
+	transfer(&[], &["--input", &url], &["--out", out.to_str().unwrap()]);
+	assert!(fs::read(&out).unwrap() == payload, "the body was corrupted");
+
+	// the request fails before the receiver is ever dialed
+	let url = format!("http://127.0.0.1:{}/missing", port);
+	let (status, stderr) = send(common::free_addr(), &["--input", &url], &[]);
+	assert!(!status.success(), "a missing url was sent");
+	assert!(stderr.contains("404"), "expected the server's answer: {}", stderr);
+}
+
+#[test]
+fn checksum_is_written_next_to_the_output() {
+	let scratch = Scratch::new("checksum");
+	let out = scratch.path("out.bin");
+	let payload = random_bytes(150_000);
+
+	transfer(&payload, &[], &["--out", out.to_str().unwrap(), "--write-checksum"]);
+
+	let hash: String = digest::digest(&digest::SHA256, &payload).as_ref().iter()
+		.map(|byte| format!("{:02x}", byte))
+		.collect();
+
+	assert_eq!(fs::read_to_string(scratch.path("out.bin.sha256")).unwrap(), format!("{}  out.bin\n", hash));
+}
+
+#[test]
+fn profile_supplies_the_address_and_key() {
+	let scratch = Scratch::new("profile");
+	let out = scratch.path("out.bin");
+	let key_file = scratch.path("nightly.key");
+	let payload = random_bytes(100_000);
+	let addr = common::free_addr();
+
+	fs::write(&key_file, key::format(&[7u8; 32], None)).unwrap();
+	fs::create_dir_all(scratch.path("config/ubuffer")).unwrap();
+	fs::write(scratch.path("config/ubuffer/config.toml"), format!(concat!(
+		"[profile.nightly]\n",
+		"address = \"{}\"\n",
+		"key-file = {:?}\n",
+		"retry = 5\n",
+		"retry-delay = \"200ms\"\n",
+	), addr, key_file.to_str().unwrap())).unwrap();
+
+	let receiving = receiver(addr, &["--out", out.to_str().unwrap()]);
+
+	// w/o the profile the sender would have neither an address nor a key
+	let mut sender = ubuffer()
+		.env_remove("UBUFFER_KEY")
+		.env("XDG_CONFIG_HOME", scratch.path("config"))
+		.args(["--profile", "nightly", "sender"])
+		.stdin(Stdio::piped())
+		.spawn()
+		.expect("could not start the sender");
+
+	sender.stdin.take().unwrap().write_all(&payload).unwrap();
+	let (status, stderr) = finish(sender);
+	assert!(status.success(), "sender failed: {}", stderr);
+
+	let (status, stderr) = finish(receiving);
+	assert!(status.success(), "receiver failed: {}", stderr);
+	assert!(fs::read(&out).unwrap() == payload, "payload was corrupted");
+}
+
+#[test]
+fn existing_output_is_kept_when_asked() {
+	let scratch = Scratch::new("overwrite");
+	let out = scratch.path("out.bin");
+	let (old, new) = (random_bytes(10_000), random_bytes(40_000));
+	fs::write(&out, &old).unwrap();
+
+	// the receiver refuses to start, w/o waiting on a sender
+	let (status, stderr) = finish(receiver(common::free_addr(), &["--out", out.to_str().unwrap(), "--no-clobber"]));
+	assert!(!status.success(), "the existing output was clobbered");
+	assert!(stderr.contains("exists"), "expected the output to be named as existing: {}", stderr);
+	assert!(fs::read(&out).unwrap() == old, "the existing output was changed");
+
+	transfer(&new, &[], &["--out", out.to_str().unwrap(), "--backup-existing"]);
+	assert!(fs::read(&out).unwrap() == new, "payload was corrupted");
+	assert!(fs::read(scratch.path("out.bin.bak")).unwrap() == old, "the existing output was not backed up");
+}
@@ -0,0 +1,37 @@
+//! Shared by the tests which run sessions over the loopback interface.
+
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::ops::Range;
+use std::process;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+/// The ports `free_addr()` hands out. These are below the range the OS picks
+/// ephemeral ports from (32768 & up on Linux, 49152 & up elsewhere), so no
+/// socket bound to port 0 can be given one between the check & the receiver
+/// listening on it.
+const PORTS: Range<u16> = 20_000..32_000;
+
+/// How far into `PORTS` the next port is, shared by the tests which run at
+/// once. (It starts from the process id, so that two test runs at once are
+/// unlikely to try the same ports.)
+static NEXT_PORT: AtomicU16 = AtomicU16::new(u16::MAX);
+
+/// Returns an address on `127.0.0.1` w/ a port nothing else is bound to,
+/// trying the next port until one can be bound. The receiver should listen
+/// on it straight away, the sender can be started at once since it keeps
+/// trying to reach the receiver until it is up.
+pub fn free_addr() -> SocketAddr {
+	let len = PORTS.end - PORTS.start;
+	let _ = NEXT_PORT.compare_exchange(u16::MAX, (process::id() % u32::from(len)) as u16, Ordering::SeqCst, Ordering::SeqCst);
+
+	for _ in 0..len {
+		let offset = NEXT_PORT.fetch_add(1, Ordering::SeqCst) % len;
+		let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, PORTS.start + offset));
+		if UdpSocket::bind(addr).is_ok() {
+			return addr;
+		}
+	}
+
+	panic!("every port from {} to {} is taken", PORTS.start, PORTS.end);
+}
+
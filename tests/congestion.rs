@@ -0,0 +1,83 @@
+//! Runs sessions over ubuffer's own UDT w/ its congestion control replaced.
+//! (Requires the `udt` feature w/o `udt-sys`, whose UDT's cannot be.)
+#![cfg(all(feature = "udt", not(feature = "udt-sys")))]
+
+extern crate rand;
+extern crate ubuffer;
+
+use rand::RngCore;
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use ubuffer::error::ProtoError;
+use ubuffer::proto::{Congestion, Controller, FixedWindow, Receiver, Sender, StreamOpts, TransportKind};
+
+mod common;
+
+/// Sends a random payload from a sender using `congestion` over UDT.
+fn round_trip(congestion: Congestion) {
+	let mut key = vec![0u8; 32];
+	let mut payload = vec![0u8; 2 * 1024 * 1024];
+	rand::thread_rng().fill_bytes(&mut key);
+	rand::thread_rng().fill_bytes(&mut payload);
+
+	let addr = common::free_addr();
+	let opts = StreamOpts { transport: TransportKind::Udt, ..StreamOpts::default() };
+
+	let recv_key = key.clone();
+	let recv_opts = opts.clone();
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		let mut receiver = Receiver::new(addr, &recv_key, &recv_opts)?;
+		receiver.run(&mut output)?;
+		Ok::<_, ProtoError>(output)
+	});
+
+	let opts = StreamOpts { congestion, ..opts };
+	let mut sender = Sender::new(addr, &key, &opts).expect("could not connect");
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let received = receiving.join().expect("receiver thread panicked").expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+}
+
+#[test]
+fn round_trip_w_fixed_window() {
+	round_trip(Congestion::Fixed);
+}
+
+/// A fixed window which counts the packets it is told were acked.
+struct CountingWindow {
+	inner: FixedWindow,
+	acked: Arc<AtomicUsize>,
+}
+
+impl Controller for CountingWindow {
+	fn window(&self) -> usize {
+		self.inner.window()
+	}
+
+	fn on_ack(&mut self, acked: usize, rtt: Option<Duration>) {
+		self.acked.fetch_add(acked, Ordering::SeqCst);
+		self.inner.on_ack(acked, rtt);
+	}
+
+	fn on_loss(&mut self, lost: usize, rtt: Option<Duration>) {
+		self.inner.on_loss(lost, rtt);
+	}
+}
+
+#[test]
+fn round_trip_w_custom_controller() {
+	let acked = Arc::new(AtomicUsize::new(0));
+	let counted = Arc::clone(&acked);
+
+	round_trip(Congestion::Custom(Arc::new(move || {
+		Box::new(CountingWindow { inner: FixedWindow(8), acked: Arc::clone(&counted) })
+	})));
+
+	// a packet carries less than 1500 bytes, so 2 MiB is well over a thousand
+	assert!(acked.load(Ordering::SeqCst) >= 1024, "the controller was not told of the acks");
+}
@@ -0,0 +1,108 @@
+//! Checks how a sender reaches its receiver on the loopback interface: when
+//! the receiver is late to start, or is known by several addresses.
+//! (Requires the `udt` feature.)
+#![cfg(feature = "udt")]
+
+extern crate rand;
+extern crate ubuffer;
+
+use rand::RngCore;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use ubuffer::error::ProtoError;
+use ubuffer::proto::{AddrFamily, Receiver, Sender, StreamOpts};
+
+mod common;
+
+fn random_bytes(len: usize) -> Vec<u8> {
+	let mut buf = vec![0u8; len];
+	rand::thread_rng().fill_bytes(&mut buf);
+	buf
+}
+
+/// Starts a receiver on `addr` once `delay` has passed.
+fn receive_after(addr: SocketAddr, key: &[u8], delay: Duration) -> JoinHandle<Result<Vec<u8>, ProtoError>> {
+	let key = key.to_vec();
+	thread::spawn(move || {
+		thread::sleep(delay);
+
+		let mut output = vec![];
+		let mut receiver = Receiver::new(addr, &key, &StreamOpts::default())?;
+		receiver.run(&mut output)?;
+		Ok(output)
+	})
+}
+
+#[test]
+fn sender_retries_until_the_receiver_is_up() {
+	let key = random_bytes(32);
+	let payload = random_bytes(256 * 1024);
+
+	// w/o retries a receiver which is not up yet fails the sender
+	let unretried = thread::spawn({
+		let key = key.clone();
+		move || Sender::new(common::free_addr(), &key, &StreamOpts::default()).err()
+	});
+
+	// the receiver starts once the first attempt has timed out
+	let addr = common::free_addr();
+	let receiving = receive_after(addr, &key, Duration::from_millis(3500));
+
+	let opts = StreamOpts { retries: 5, retry_delay: Duration::from_millis(100), ..StreamOpts::default() };
+	let mut sender = Sender::new(addr, &key, &opts).expect("could not connect");
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let received = receiving.join().expect("receiver thread panicked").expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+
+	match unretried.join().unwrap() {
+		Some(ProtoError::ConnectErr { .. }) => {},
+		other => panic!("expected the sender to fail to connect, got {:?}", other),
+	}
+}
+
+#[test]
+fn every_resolved_address_is_tried() {
+	let key = random_bytes(32);
+	let payload = random_bytes(256 * 1024);
+
+	// the first address has nothing listening on it
+	let (dead, addr) = (common::free_addr(), common::free_addr());
+	let receiving = receive_after(addr, &key, Duration::from_secs(0));
+
+	let mut sender = Sender::new(&[dead, addr][..], &key, &StreamOpts::default()).expect("could not connect");
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let received = receiving.join().expect("receiver thread panicked").expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+}
+
+#[test]
+fn only_addresses_in_the_family_are_tried() {
+	let key = random_bytes(32);
+	let payload = random_bytes(256 * 1024);
+	let addr = common::free_addr();
+
+	let v6 = StreamOpts { family: AddrFamily::V6, ..StreamOpts::default() };
+	match Sender::new(addr, &key, &v6) {
+		Err(ProtoError::NoAddrInFamily { family: AddrFamily::V6 }) => {},
+		Err(err) => panic!("expected no ipv6 address to be found, got {}", err),
+		Ok(_) => panic!("expected no ipv6 address to be found"),
+	}
+
+	// the ipv6 address (which nothing listens on) is never dialed, so the
+	// sender is not held up waiting for it to time out
+	let receiving = receive_after(addr, &key, Duration::from_secs(0));
+	let unanswered = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], addr.port()));
+
+	let started = Instant::now();
+	let v4 = StreamOpts { family: AddrFamily::V4, ..StreamOpts::default() };
+	let mut sender = Sender::new(&[unanswered, addr][..], &key, &v4).expect("could not connect");
+	assert!(started.elapsed() < Duration::from_secs(3), "the ipv6 address was dialed");
+
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+	let received = receiving.join().expect("receiver thread panicked").expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+}
@@ -0,0 +1,71 @@
+//! Runs a session over UDT on the loopback interface through the C interface,
+//! as a program linked against `libubuffer` would.
+
+extern crate rand;
+extern crate ubuffer;
+
+use rand::RngCore;
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::thread;
+use ubuffer::ffi::*;
+
+mod common;
+
+#[test]
+fn file_round_trip() {
+	let mut key = vec![0u8; 32];
+	let mut payload = vec![0u8; 256 * 1024];
+	rand::thread_rng().fill_bytes(&mut key);
+	rand::thread_rng().fill_bytes(&mut payload);
+
+	let dir = env::temp_dir().join(format!("ubuffer-ffi-{}", std::process::id()));
+	fs::create_dir_all(&dir).unwrap();
+	let (input, output) = (dir.join("input"), dir.join("output"));
+	fs::write(&input, &payload).unwrap();
+
+	let addr = common::free_addr();
+
+	let addr = CString::new(addr.to_string()).unwrap();
+	let output_path = CString::new(output.to_str().unwrap()).unwrap();
+
+	let recv_addr = addr.clone();
+	let recv_key = key.clone();
+	let receiving = thread::spawn(move || unsafe {
+		let receiver = ubuffer_receiver_new(recv_addr.as_ptr(), recv_key.as_ptr(), recv_key.len());
+		assert!(!receiver.is_null());
+
+		let status = ubuffer_receiver_run_file(receiver, output_path.as_ptr());
+		let bytes = ubuffer_receiver_bytes(receiver);
+		ubuffer_receiver_free(receiver);
+		(status, bytes)
+	});
+
+	unsafe {
+		let sender = ubuffer_sender_new(addr.as_ptr(), key.as_ptr(), key.len());
+		assert!(!sender.is_null());
+
+		let input = CString::new(input.to_str().unwrap()).unwrap();
+		assert_eq!(ubuffer_sender_run_file(sender, input.as_ptr()), 0);
+		assert_eq!(ubuffer_sender_bytes(sender), payload.len() as u64);
+		ubuffer_sender_free(sender);
+	}
+
+	assert_eq!(receiving.join().unwrap(), (0, payload.len() as u64));
+	assert!(fs::read(&output).unwrap() == payload, "payload was corrupted");
+	fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn failure_is_described() {
+	let addr = CString::new("127.0.0.1:9").unwrap();
+
+	unsafe {
+		let sender = ubuffer_sender_new(addr.as_ptr(), std::ptr::null(), 32);
+		assert!(sender.is_null());
+
+		let err = CStr::from_ptr(ubuffer_last_error()).to_str().unwrap();
+		assert_eq!(err, "invalid argument: the key was NULL");
+	}
+}
@@ -0,0 +1,168 @@
+//! Runs sessions through a `Hub` on the loopback interface.
+//! (Requires the `udt` feature.)
+#![cfg(feature = "udt")]
+
+extern crate rand;
+extern crate ubuffer;
+#[cfg(feature = "udt-sys")] extern crate udt;
+
+use rand::RngCore;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Cursor, Read};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use ubuffer::proto::{Hub, Receiver, Sender, StreamOpts, PROTOCOL_VERSION};
+#[cfg(not(feature = "udt-sys"))] use ubuffer::udt;
+use udt::{SocketFamily, SocketType, UdtSocket};
+
+mod common;
+
+#[test]
+fn stalled_handshake_does_not_hold_up_the_hub() {
+	let mut key = vec![0u8; 32];
+	let mut payload = vec![0u8; 256 * 1024];
+	rand::thread_rng().fill_bytes(&mut key);
+	rand::thread_rng().fill_bytes(&mut payload);
+
+	let addr = common::free_addr();
+	let dir = env::temp_dir().join(format!("ubuffer-hub-{}", process::id()));
+	fs::create_dir_all(&dir).unwrap();
+
+	let mut hub = Hub::new(addr, &key).unwrap();
+	let interrupt = Arc::new(AtomicBool::new(false));
+	hub.set_interrupt(Arc::clone(&interrupt));
+	hub.set_workers(1);
+
+	let (closed_tx, closed) = mpsc::channel();
+	let running = thread::spawn({
+		let dir = dir.clone();
+		move || hub.run(
+			move |_, session| Ok(File::create(dir.join(session.id.to_string()))?),
+			move |_, session, result| { let _ = closed_tx.send((session.id, result.is_ok())); },
+		)
+	});
+
+	// a sender which stops after its banner, in the middle of the handshake
+	let stalled = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).unwrap();
+	stalled.connect(addr).unwrap();
+	let mut banner = b"ubuffer".to_vec();
+	banner.push(PROTOCOL_VERSION);
+	stalled.send(&banner).unwrap();
+	thread::sleep(Duration::from_millis(250));
+
+	let started = Instant::now();
+	let mut sender = Sender::new(addr, &key, &StreamOpts::default()).expect("could not connect");
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+	assert!(started.elapsed() < Duration::from_secs(5), "the sender waited on the stalled handshake");
+
+	assert_eq!(closed.recv_timeout(Duration::from_secs(5)).unwrap(), (2, true));
+	assert!(fs::read(dir.join("2")).unwrap() == payload, "payload was corrupted");
+
+	stalled.close().unwrap();
+	assert_eq!(closed.recv_timeout(Duration::from_secs(5)).unwrap(), (1, false));
+
+	interrupt.store(true, Ordering::SeqCst);
+	running.join().unwrap().expect("hub failed");
+	let _ = fs::remove_dir_all(&dir);
+}
+
+/// An input which yields each chunk it is sent, as it is sent.
+struct Trickle(mpsc::Receiver<Vec<u8>>);
+
+impl Read for Trickle {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		match self.0.recv() {
+			Ok(chunk) => {
+				buf[..chunk.len()].copy_from_slice(&chunk);
+				Ok(chunk.len())
+			},
+
+			Err(_) => Ok(0),
+		}
+	}
+}
+
+/// How many chunks `trickle()` sends.
+const CHUNKS: u64 = 20;
+
+/// Sends `CHUNKS` small chunks from a low latency sender to `addr`, each
+/// only once the last was written to `out`, and returns how long they took.
+fn trickle(addr: SocketAddr, key: &[u8], out: &Path) -> Duration {
+	let (chunks, input) = mpsc::channel();
+	let sending = thread::spawn({
+		let key = key.to_vec();
+		move || {
+			let mut sender = Sender::new(addr, &key, &StreamOpts::default()).expect("could not connect");
+			sender.set_low_latency(true);
+			sender.run(Trickle(input)).expect("sender failed");
+		}
+	});
+
+	let started = Instant::now();
+	for sent in 1..=CHUNKS {
+		chunks.send(vec![7u8; 100]).unwrap();
+		while fs::metadata(out).map_or(0, |meta| meta.len()) < sent * 100 {
+			assert!(started.elapsed() < Duration::from_secs(30), "chunk #{} never arrived", sent);
+			thread::sleep(Duration::from_millis(1));
+		}
+	}
+
+	let elapsed = started.elapsed();
+	drop(chunks);
+	sending.join().unwrap();
+	elapsed
+}
+
+#[test]
+fn hub_picks_a_session_up_as_soon_as_its_sender_sends_more() {
+	let key = vec![3u8; 32];
+	let dir = env::temp_dir().join(format!("ubuffer-hub-latency-{}", process::id()));
+	fs::create_dir_all(&dir).unwrap();
+
+	// the same chunks sent to a receiver of its own, which the hub is held to
+	let addr = common::free_addr();
+	let out = dir.join("plain");
+	let receiving = thread::spawn({
+		let (key, out) = (key.clone(), out.clone());
+		move || {
+			let mut receiver = Receiver::new(addr, &key, &StreamOpts::default()).unwrap();
+			receiver.run(File::create(&out).unwrap()).expect("receiver failed");
+		}
+	});
+
+	let plain = trickle(addr, &key, &out);
+	receiving.join().unwrap();
+
+	let addr = common::free_addr();
+	let out = dir.join("hub");
+	let mut hub = Hub::new(addr, &key).unwrap();
+	let interrupt = Arc::new(AtomicBool::new(false));
+	hub.set_interrupt(Arc::clone(&interrupt));
+
+	let (closed_tx, closed) = mpsc::channel();
+	let running = thread::spawn({
+		let out = out.clone();
+		move || hub.run(
+			move |_, _| Ok(File::create(&out)?),
+			move |_, _, result| { let _ = closed_tx.send(result.is_ok()); },
+		)
+	});
+
+	let hubbed = trickle(addr, &key, &out);
+	assert!(closed.recv_timeout(Duration::from_secs(5)).unwrap(), "the hub's session failed");
+	interrupt.store(true, Ordering::SeqCst);
+	running.join().unwrap().expect("hub failed");
+	let _ = fs::remove_dir_all(&dir);
+
+	// each chunk is only sent once the last was written, so each waits on
+	// the hub picking the session up again: which must not wait on the loop
+	// polling its sockets (`HUB_POLL_MS`), only on a worker handing it back
+	let slack = Duration::from_millis(100) * CHUNKS as u32;
+	assert!(hubbed < plain + slack, "the hub took {:?} for {} chunks, where a receiver took {:?}", hubbed, CHUNKS, plain);
+}
@@ -0,0 +1,139 @@
+//! Runs sessions between ubuffer's own UDT & UDT's C++ library, w/ the
+//! library in this process on the former, and a `ubuffer` binary built w/
+//! the `udt-sys` feature on the latter. (Requires the `udt` feature w/o
+//! `udt-sys`, and a C++ compiler to build the other binary w/.)
+#![cfg(all(feature = "udt", not(feature = "udt-sys")))]
+
+extern crate rand;
+extern crate ubuffer;
+
+use rand::RngCore;
+use std::env;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+use ubuffer::error::ProtoError;
+use ubuffer::key;
+use ubuffer::proto::{Receiver, Sender, StreamOpts, UdtMode, BLOCK_SIZE};
+
+mod common;
+
+/// How long the other binary is given to finish before the test gives up.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The `ubuffer` binary built w/ the `udt-sys` feature, which is built the
+/// first time it is asked for. (In a target directory of its own, so it
+/// does not replace the binary the other tests run.)
+fn udt_sys_ubuffer() -> &'static PathBuf {
+	static BIN: OnceLock<PathBuf> = OnceLock::new();
+	BIN.get_or_init(|| {
+		let target = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("udt-sys");
+		let status = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+			.args(["build", "--quiet", "--bin", "ubuffer", "--features", "udt-sys", "--manifest-path"])
+			.arg(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"))
+			.arg("--target-dir").arg(&target)
+			.status()
+			.expect("could not run cargo");
+
+		assert!(status.success(), "could not build ubuffer w/ the udt-sys feature");
+		target.join("debug").join(format!("ubuffer{}", env::consts::EXE_SUFFIX))
+	})
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+	let mut buf = vec![0u8; len];
+	rand::thread_rng().fill_bytes(&mut buf);
+	buf
+}
+
+/// The other binary, w/ `key` in its environment.
+fn ubuffer(key: &[u8]) -> Command {
+	let mut cmd = Command::new(udt_sys_ubuffer());
+	cmd.env("UBUFFER_KEY", key::format(key, None).trim())
+		.env_remove("RUST_LOG")
+		.stdin(Stdio::null())
+		.stdout(Stdio::null())
+		.stderr(Stdio::null());
+	cmd
+}
+
+/// Waits for `child` to exit, killing it if it takes longer than `TIMEOUT`.
+fn wait(mut child: Child) -> bool {
+	let started = Instant::now();
+	loop {
+		if let Some(status) = child.try_wait().unwrap() {
+			return status.success();
+		}
+
+		if started.elapsed() > TIMEOUT {
+			let _ = child.kill();
+			panic!("the udt-sys ubuffer did not finish in time");
+		}
+
+		thread::sleep(Duration::from_millis(50));
+	}
+}
+
+/// Sends a random payload from the other binary, started w/ `args`, to a
+/// receiver in this process using `opts`.
+fn send_to_native(args: &[&str], opts: StreamOpts) {
+	let key = random_bytes(32);
+	let payload = random_bytes(64 * BLOCK_SIZE + 17);
+	let addr = common::free_addr();
+
+	let recv_key = key.clone();
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		let mut receiver = Receiver::new(addr, &recv_key, &opts)?;
+		receiver.run(&mut output)?;
+		Ok::<_, ProtoError>(output)
+	});
+
+	let mut sender = ubuffer(&key)
+		.arg("sender").arg(addr.to_string()).args(args)
+		.stdin(Stdio::piped())
+		.spawn()
+		.unwrap();
+
+	sender.stdin.take().unwrap().write_all(&payload).unwrap();
+	assert!(wait(sender), "the udt-sys sender failed");
+
+	let received = receiving.join().expect("receiver thread panicked").expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+}
+
+#[test]
+fn udt_sys_sender_to_native_receiver() {
+	send_to_native(&[], StreamOpts::default());
+}
+
+#[test]
+fn udt_sys_sender_to_native_receiver_w_messages() {
+	send_to_native(&["--mode", "message"], StreamOpts { udt_mode: UdtMode::Message, ..StreamOpts::default() });
+}
+
+#[test]
+fn native_sender_to_udt_sys_receiver() {
+	let key = random_bytes(32);
+	let payload = random_bytes(64 * BLOCK_SIZE + 17);
+	let addr = common::free_addr();
+	let out = env::temp_dir().join(format!("ubuffer-interop-{}", process::id()));
+
+	let receiver = ubuffer(&key)
+		.arg("receiver").arg(addr.to_string())
+		.arg("--out").arg(&out)
+		.spawn()
+		.unwrap();
+
+	let mut sender = Sender::new(addr, &key, &StreamOpts::default()).expect("could not connect");
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+	assert!(wait(receiver), "the udt-sys receiver failed");
+
+	let received = fs::read(&out).unwrap();
+	let _ = fs::remove_file(&out);
+	assert!(received == payload, "payload was corrupted");
+}
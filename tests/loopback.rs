@@ -0,0 +1,2394 @@
+//! Runs complete sessions between a `Sender` & `Receiver` in one process by
+//! connecting them w/ an in-memory `Loopback` transport instead of UDT.
+
+extern crate libc;
+extern crate rand;
+extern crate ring;
+extern crate ubuffer;
+
+use rand::RngCore;
+use ring::digest;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use ubuffer::key;
+use ubuffer::error::ProtoError;
+use ubuffer::proto::{generate_code, CaptureDecoder, Checkpoint, Cipher, Event, FanOut, FaultCode, Features, FileMeta, Hangup, Identity, Loopback, MemoryBudget, Receiver, ReceiverBuilder, ReceiverReader, Sender, SenderBuilder, SenderWriter, Transport, ACK_INTERVAL, BLOCK_SIZE, MESSAGE_SIZE, MIN_MEMORY, PROTOCOL_VERSION};
+
+/// The length of the banner each peer opens a connection w/.
+const BANNER_LEN: usize = 8;
+
+fn random_bytes(len: usize) -> Vec<u8> {
+	let mut buf = vec![0u8; len];
+	rand::thread_rng().fill_bytes(&mut buf);
+	buf
+}
+
+/// Sends `payload` from a sender using `send_key` to a receiver using
+/// `recv_key`, returning what each side returned & what was received.
+fn transfer<F>(payload: Vec<u8>, send_key: &[u8], recv_key: &[u8], configure: F)
+	-> (Result<(), ProtoError>, Result<Vec<u8>, ProtoError>)
+where F: FnOnce(&mut Sender) {
+	let (near, far) = Loopback::pair();
+
+	let recv_key = recv_key.to_vec();
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		let mut receiver = Receiver::with_transport(far, &recv_key)?;
+		receiver.run(&mut output)?;
+		Ok(output)
+	});
+
+	let sent = Sender::with_transport(near, send_key).and_then(|mut sender| {
+		configure(&mut sender);
+		sender.run(Cursor::new(payload))
+	});
+
+	(sent, receiving.join().expect("receiver thread panicked"))
+}
+
+/// A `Loopback` which flips a bit in the `nth` full block written to it.
+struct Corrupting {
+	inner: Loopback,
+	nth: usize,
+}
+
+impl Transport for Corrupting {
+	fn close(&mut self) -> Result<(), ProtoError> { self.inner.close() }
+	fn has_pending(&mut self) -> bool { self.inner.has_pending() }
+}
+
+impl Read for Corrupting {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.inner.read(buf) }
+}
+
+impl Write for Corrupting {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if buf.len() <= BLOCK_SIZE || self.nth == 0 { return self.inner.write(buf); }
+
+		self.nth -= 1;
+		if self.nth > 0 { return self.inner.write(buf); }
+
+		let mut corrupt = buf.to_vec();
+		corrupt[buf.len() / 2] ^= 0x01;
+		self.inner.write(&corrupt)
+	}
+
+	fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+/// A `Loopback` (or another transport) which hangs up in place of writing
+/// its `nth` full block.
+struct Dropping<T = Loopback> {
+	inner: T,
+	nth: usize,
+}
+
+impl<T: Transport> Transport for Dropping<T> {
+	fn close(&mut self) -> Result<(), ProtoError> { self.inner.close() }
+	fn has_pending(&mut self) -> bool { self.inner.has_pending() }
+}
+
+impl<T: Read> Read for Dropping<T> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.inner.read(buf) }
+}
+
+impl<T: Transport> Write for Dropping<T> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if buf.len() > BLOCK_SIZE {
+			self.nth = self.nth.saturating_sub(1);
+			if self.nth == 0 {
+				let _ = self.inner.close();
+			}
+		}
+
+		self.inner.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+/// A `Loopback` which may drop or reorder what is written to it, like a UDT
+/// message socket w/ a ttl. It skips its `nth` full block, or holds it back
+/// until after the next write if `reorder` is set.
+struct Skipping {
+	inner: Loopback,
+	nth: usize,
+	reorder: bool,
+	held: Option<Vec<u8>>,
+}
+
+impl Transport for Skipping {
+	fn close(&mut self) -> Result<(), ProtoError> { self.inner.close() }
+	fn has_pending(&mut self) -> bool { self.inner.has_pending() }
+	fn lossy(&self) -> bool { true }
+}
+
+impl Read for Skipping {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.inner.read(buf) }
+}
+
+impl Write for Skipping {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if buf.len() > BLOCK_SIZE && self.nth > 0 {
+			self.nth -= 1;
+			if self.nth == 0 {
+				self.held = Some(buf.to_vec()).filter(|_| self.reorder);
+				return Ok(buf.len());
+			}
+		}
+
+		let len = self.inner.write(buf)?;
+		if let Some(held) = self.held.take() {
+			self.inner.write_all(&held)?;
+		}
+
+		Ok(len)
+	}
+
+	fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+/// Sends `payload` over a pair of `Skipping` transports, the sender's of
+/// which skips (or reorders) its 3rd block. Returns how many nacks the
+/// sender saw & what was received.
+fn skipped_transfer(payload: Vec<u8>, reorder: bool) -> (usize, Result<Vec<u8>, ProtoError>) {
+	let key = random_bytes(32);
+	let (near, far) = Loopback::pair();
+
+	let receiving = thread::spawn({
+		let key = key.clone();
+		move || {
+			let mut output = vec![];
+			let far = Skipping { inner: far, nth: 0, reorder: false, held: None };
+			Receiver::with_transport(far, &key)?.run(&mut output)?;
+			Ok(output)
+		}
+	});
+
+	let near = Skipping { inner: near, nth: 3, reorder, held: None };
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	let events = sender.subscribe();
+	sender.run(Cursor::new(payload)).expect("sender failed");
+
+	let nacks = events.try_iter()
+		.filter(|event| matches!(event, Event::Nack { .. }))
+		.count();
+
+	(nacks, receiving.join().expect("receiver thread panicked"))
+}
+
+/// A `Loopback` which delivers its `nth` full block twice, or holds it back
+/// until after the next write if `reorder` is set. Unlike `Skipping` it does
+/// not claim to be lossy, so the receiver must refuse what it does.
+struct Replaying {
+	inner: Loopback,
+	nth: usize,
+	reorder: bool,
+	held: Option<Vec<u8>>,
+}
+
+impl Transport for Replaying {
+	fn close(&mut self) -> Result<(), ProtoError> { self.inner.close() }
+	fn has_pending(&mut self) -> bool { self.inner.has_pending() }
+}
+
+impl Read for Replaying {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.inner.read(buf) }
+}
+
+impl Write for Replaying {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if buf.len() > BLOCK_SIZE && self.nth > 0 {
+			self.nth -= 1;
+			if self.nth == 0 && self.reorder {
+				self.held = Some(buf.to_vec());
+				return Ok(buf.len());
+			}
+
+			if self.nth == 0 {
+				self.inner.write_all(buf)?;
+			}
+		}
+
+		let len = self.inner.write(buf)?;
+		if let Some(held) = self.held.take() {
+			self.inner.write_all(&held)?;
+		}
+
+		Ok(len)
+	}
+
+	fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+/// A `Loopback` which hangs up in place of writing the sender's goodbye.
+struct HangingUp {
+	inner: Loopback,
+	hung_up: bool,
+}
+
+impl Transport for HangingUp {
+	fn close(&mut self) -> Result<(), ProtoError> { self.inner.close() }
+	fn has_pending(&mut self) -> bool { self.inner.has_pending() }
+}
+
+impl Read for HangingUp {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.inner.read(buf) }
+}
+
+impl Write for HangingUp {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if !self.hung_up && buf.starts_with(b"ubuf") && buf.get(5) == Some(&4) {
+			self.hung_up = true;
+			let _ = self.inner.close();
+		}
+
+		self.inner.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+/// A `Loopback` whose connection is reset once the sender's hello is written,
+/// as though the network dropped rather than the receiver refusing it.
+struct Resetting {
+	inner: Loopback,
+	reset: bool,
+}
+
+impl Transport for Resetting {
+	fn close(&mut self) -> Result<(), ProtoError> { self.inner.close() }
+}
+
+impl Read for Resetting {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match self.reset {
+			true => Err(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset")),
+			false => self.inner.read(buf),
+		}
+	}
+}
+
+impl Write for Resetting {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.reset |= buf.starts_with(b"ubuf") && buf.get(5) == Some(&3);
+		self.inner.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+/// A `Loopback` which lets through `keep` full blocks, then swallows the rest
+/// of the session in place of a forged (unsealed) goodbye.
+struct Truncating {
+	inner: Loopback,
+	keep: usize,
+	forged: bool,
+}
+
+impl Transport for Truncating {
+	fn close(&mut self) -> Result<(), ProtoError> { self.inner.close() }
+	fn has_pending(&mut self) -> bool { self.inner.has_pending() }
+}
+
+impl Read for Truncating {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.inner.read(buf) }
+}
+
+impl Write for Truncating {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if self.forged { return Ok(buf.len()); }
+
+		if self.keep == 0 {
+			let mut goodbye = b"ubuf".to_vec();
+			goodbye.extend_from_slice(&[PROTOCOL_VERSION, 4]);
+			goodbye.extend_from_slice(&[0u8; 12]);
+			self.inner.write_all(&goodbye)?;
+
+			self.forged = true;
+			return Ok(buf.len());
+		}
+
+		if buf.len() > BLOCK_SIZE { self.keep -= 1; }
+		self.inner.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+/// A `Loopback` which slips a forged (unsealed) reply of type `ty` to the
+/// first message in ahead of the receiver's first ack.
+struct Forging {
+	inner: Loopback,
+	ty: u8,
+	forged: bool,
+}
+
+impl Transport for Forging {
+	fn close(&mut self) -> Result<(), ProtoError> { self.inner.close() }
+	fn has_pending(&mut self) -> bool { self.inner.has_pending() }
+}
+
+impl Read for Forging {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.inner.read(buf) }
+}
+
+impl Write for Forging {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if !self.forged && buf.starts_with(b"ubuf") && buf.get(5) == Some(&21) {
+			let len = if self.ty == 21 { 32 } else { 24 };
+			let mut reply = b"ubuf".to_vec();
+			reply.extend_from_slice(&[PROTOCOL_VERSION, self.ty]);
+			reply.extend_from_slice(&(len as u32).to_be_bytes());
+			reply.extend_from_slice(&1u64.to_be_bytes());
+			reply.extend_from_slice(&vec![0u8; len]);
+			self.inner.write_all(&reply)?;
+
+			self.forged = true;
+		}
+
+		self.inner.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+/// Sends `payload` over a transport which corrupts its 3rd block, returning
+/// what each side returned & what was received.
+fn corrupted_transfer<F>(payload: Vec<u8>, configure: F) -> (Result<(), ProtoError>, Result<Vec<u8>, ProtoError>)
+where F: FnOnce(&mut Sender) {
+	let key = random_bytes(32);
+	let (near, far) = Loopback::pair();
+
+	let receiving = thread::spawn({
+		let key = key.clone();
+		move || {
+			let mut output = vec![];
+			Receiver::with_transport(far, &key)?.run(&mut output)?;
+			Ok(output)
+		}
+	});
+
+	let sent = Sender::with_transport(Corrupting { inner: near, nth: 3 }, &key).and_then(|mut sender| {
+		configure(&mut sender);
+		sender.run(Cursor::new(payload))
+	});
+
+	(sent, receiving.join().expect("receiver thread panicked"))
+}
+
+/// A `Loopback` which keeps a copy of everything written to it.
+struct Recording {
+	inner: Loopback,
+	written: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Transport for Recording {
+	fn close(&mut self) -> Result<(), ProtoError> { self.inner.close() }
+	fn has_pending(&mut self) -> bool { self.inner.has_pending() }
+}
+
+impl Read for Recording {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.inner.read(buf) }
+}
+
+impl Write for Recording {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let len = self.inner.write(buf)?;
+		self.written.lock().unwrap().extend_from_slice(&buf[..len]);
+		Ok(len)
+	}
+
+	fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+fn assert_round_trip(len: usize) {
+	let key = random_bytes(32);
+	let payload = random_bytes(len);
+
+	let (sent, received) = transfer(payload.clone(), &key, &key, |_| {});
+	sent.expect("sender failed");
+	assert!(received.expect("receiver failed") == payload, "payload of {} bytes was corrupted", len);
+}
+
+#[test]
+fn empty_input() {
+	assert_round_trip(0);
+}
+
+#[test]
+fn single_byte() {
+	assert_round_trip(1);
+}
+
+#[test]
+fn exactly_one_block() {
+	assert_round_trip(BLOCK_SIZE);
+}
+
+#[test]
+fn one_byte_past_a_block() {
+	assert_round_trip(BLOCK_SIZE + 1);
+}
+
+#[test]
+fn many_blocks() {
+	assert_round_trip(1024 * 1024 + 7);
+}
+
+#[test]
+fn rekeys_during_transfer() {
+	let key = random_bytes(32);
+	let payload = random_bytes(16 * BLOCK_SIZE);
+
+	let (sent, received) = transfer(payload.clone(), &key, &key, |sender| {
+		sender.set_rekey_interval(3 * BLOCK_SIZE as u64);
+	});
+
+	sent.expect("sender failed");
+	assert!(received.expect("receiver failed") == payload);
+}
+
+#[test]
+fn sender_writer_round_trips() {
+	let key = random_bytes(32);
+	let payload = random_bytes(3 * BLOCK_SIZE + 11);
+	let (near, far) = Loopback::pair();
+
+	let recv_key = key.clone();
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		Receiver::with_transport(far, &recv_key)?.run(&mut output)?;
+		Ok::<_, ProtoError>(output)
+	});
+
+	// odd sized writes, w/ a partial block flushed in the middle
+	let mut writer = SenderWriter::new(Sender::with_transport(near, &key).unwrap());
+	writer.write_all(&payload[..100]).unwrap();
+	writer.flush().unwrap();
+	for chunk in payload[100..].chunks(1000) {
+		writer.write_all(chunk).unwrap();
+	}
+
+	let summary = writer.finish().expect("sender failed");
+	assert_eq!(summary.plaintext_bytes, payload.len() as u64);
+	assert!(receiving.join().unwrap().expect("receiver failed") == payload);
+}
+
+#[test]
+fn low_latency_writer_sends_each_write() {
+	let key = random_bytes(32);
+	let (near, far) = Loopback::pair();
+	let (read, was_read) = mpsc::channel();
+
+	// the writer is neither flushed nor finished until its write is read
+	let send_key = key.clone();
+	let sending = thread::spawn(move || {
+		let mut sender = Sender::with_transport(near, &send_key)?;
+		sender.set_low_latency(true);
+
+		let mut writer = SenderWriter::new(sender);
+		writer.write_all(b"hello")?;
+		was_read.recv().expect("reader hung up");
+		writer.finish()
+	});
+
+	let mut reader = ReceiverReader::new(Receiver::with_transport(far, &key).unwrap());
+	let mut buf = [0u8; 5];
+	reader.read_exact(&mut buf).expect("receiver failed");
+	assert_eq!(&buf, b"hello");
+	read.send(()).unwrap();
+
+	assert_eq!(reader.read(&mut buf).unwrap(), 0);
+	sending.join().unwrap().expect("sender failed");
+}
+
+#[test]
+fn dropped_sender_writer_says_goodbye() {
+	let key = random_bytes(32);
+	let payload = random_bytes(BLOCK_SIZE / 2);
+	let (near, far) = Loopback::pair();
+
+	let recv_key = key.clone();
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		Receiver::with_transport(far, &recv_key)?.run(&mut output)?;
+		Ok::<_, ProtoError>(output)
+	});
+
+	let mut writer = SenderWriter::new(Sender::with_transport(near, &key).unwrap());
+	writer.write_all(&payload).unwrap();
+	drop(writer);
+
+	assert!(receiving.join().unwrap().expect("receiver failed") == payload);
+}
+
+#[test]
+fn receiver_reader_round_trips() {
+	let key = random_bytes(32);
+	let payload = random_bytes(5 * BLOCK_SIZE + 3);
+	let (near, far) = Loopback::pair();
+
+	let send_key = key.clone();
+	let sent = payload.clone();
+	let sending = thread::spawn(move || Sender::with_transport(near, &send_key)?.run(Cursor::new(sent)));
+
+	// small reads, so that each block is read in several pieces
+	let mut reader = ReceiverReader::new(Receiver::with_transport(far, &key).unwrap());
+	let mut received = vec![];
+	let mut buf = [0u8; 1000];
+	loop {
+		match reader.read(&mut buf).expect("receiver failed") {
+			0 => break,
+			len => received.extend_from_slice(&buf[..len]),
+		}
+	}
+
+	sending.join().unwrap().expect("sender failed");
+	assert!(received == payload);
+	assert_eq!(reader.summary().plaintext_bytes, payload.len() as u64);
+	assert_eq!(reader.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn receiver_reader_reports_a_failed_session() {
+	let (near, far) = Loopback::pair();
+
+	let sending = thread::spawn(move || {
+		Sender::with_transport(near, &random_bytes(32))?.run(Cursor::new(random_bytes(BLOCK_SIZE)))
+	});
+
+	let mut reader = ReceiverReader::new(Receiver::with_transport(far, &random_bytes(32)).unwrap());
+	assert!(reader.read_to_end(&mut vec![]).is_err());
+	assert!(sending.join().unwrap().is_err());
+}
+
+#[test]
+fn builders_apply_their_options() {
+	let key = random_bytes(32);
+	let extra_key = random_bytes(32);
+	let payload = random_bytes(8 * BLOCK_SIZE);
+	let (near, far) = Loopback::pair();
+
+	let (blocks, events) = mpsc::channel();
+	let receiver = ReceiverBuilder::new(&key)
+		.add_key(&extra_key)
+		.cipher(Cipher::ChaCha20Poly1305)
+		.crypto_threads(2)
+		.observer(move |event| if let Event::Block { .. } = event { let _ = blocks.send(()); })
+		.build_with_transport(far)
+		.expect("could not build the receiver");
+
+	let receiving = thread::spawn(move || {
+		let mut receiver = receiver;
+		let mut output = vec![];
+		receiver.run(&mut output)?;
+		Ok::<_, ProtoError>(output)
+	});
+
+	let mut sender = SenderBuilder::new(&extra_key)
+		.cipher(Cipher::ChaCha20Poly1305)
+		.rekey_interval(3 * BLOCK_SIZE as u64)
+		.total_size(payload.len() as u64)
+		.build_with_transport(near)
+		.expect("could not build the sender");
+
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+	assert!(receiving.join().unwrap().expect("receiver failed") == payload);
+	assert_eq!(events.try_iter().count(), 8);
+}
+
+#[test]
+fn memory_budget_shrinks_the_defaults() {
+	assert!(MemoryBudget::new(MIN_MEMORY - 1).is_err());
+
+	let small = MemoryBudget::new(16 << 20).unwrap();
+	assert_eq!(small.socket_buffer(), 4 << 20);
+	assert!(small.crypto_threads(64) < 64);
+	assert_eq!(small.crypto_threads(1), 1);
+	assert!(small.retransmit_window() * BLOCK_SIZE < 6 << 20);
+	assert!(small.retransmit_window() as u64 >= ACK_INTERVAL);
+
+	// a budget never grows what is kept past the defaults
+	let large = MemoryBudget::new(1 << 40).unwrap();
+	assert!(large.socket_buffer() < 16 << 20);
+	assert_eq!(large.retransmit_window(), 4096);
+}
+
+#[test]
+fn builder_fails_on_a_bad_option() {
+	let (near, _far) = Loopback::pair();
+
+	// a 128-bit key does not suit ChaCha20
+	match SenderBuilder::new(&random_bytes(16)).cipher(Cipher::ChaCha20Poly1305).build_with_transport(near) {
+		Err(_) => {},
+		Ok(_) => panic!("expected the cipher to be refused"),
+	}
+}
+
+#[test]
+fn aes_128_keys_round_trip() {
+	let key = random_bytes(16);
+	let payload = random_bytes(16 * BLOCK_SIZE);
+
+	let (sent, received) = transfer(payload.clone(), &key, &key, |sender| {
+		sender.set_rekey_interval(3 * BLOCK_SIZE as u64);
+	});
+
+	sent.expect("sender failed");
+	assert!(received.expect("receiver failed") == payload);
+}
+
+#[test]
+fn forced_chacha20_round_trips() {
+	let key = random_bytes(32);
+	let payload = random_bytes(16 * BLOCK_SIZE);
+
+	let (sent, received) = transfer(payload.clone(), &key, &key, |sender| {
+		sender.set_cipher(Cipher::ChaCha20Poly1305).expect("key fits the cipher");
+		sender.set_rekey_interval(3 * BLOCK_SIZE as u64);
+	});
+
+	sent.expect("sender failed");
+	assert!(received.expect("receiver failed") == payload);
+}
+
+#[test]
+fn mismatched_ciphers_are_rejected() {
+	let payload = random_bytes(BLOCK_SIZE);
+	let (sent, received) = transfer(payload, &random_bytes(16), &random_bytes(32), |_| {});
+
+	assert!(sent.is_err(), "sender should not complete once the receiver hangs up");
+	match received {
+		Err(ProtoError::CipherMismatch { .. }) => {},
+		other => panic!("expected a cipher mismatch, got {:?}", other.map(|output| output.len())),
+	}
+}
+
+#[test]
+fn receiver_identity_is_pinned() {
+	let key = random_bytes(32);
+	let identity = Identity::from_pkcs8(&Identity::generate().unwrap()).unwrap();
+	let impostor = Identity::from_pkcs8(&Identity::generate().unwrap()).unwrap();
+
+	// the first 64 bits of the right fingerprint are not enough to pin it
+	let truncated = identity.fingerprint()[..19].to_string();
+	assert!(key::is_fingerprint(&identity.fingerprint()) && !key::is_fingerprint(&truncated));
+
+	for expected in [identity.fingerprint(), impostor.fingerprint(), truncated] {
+		let (near, far) = Loopback::pair();
+
+		let mut receiver = Receiver::with_transport(far, &key).unwrap();
+		receiver.set_identity(identity.clone());
+		let receiving = thread::spawn(move || receiver.run(io::sink()));
+
+		let mut sender = Sender::with_transport(near, &key).unwrap();
+		sender.set_expected_fingerprint(&expected.to_uppercase());
+		let sent = sender.run(Cursor::new(random_bytes(BLOCK_SIZE)));
+
+		if expected == identity.fingerprint() {
+			sent.expect("sender should accept the receiver it pinned");
+			receiving.join().unwrap().expect("receiver failed");
+		} else {
+			match sent {
+				Err(ProtoError::IdentityMismatch { actual, .. }) => assert_eq!(actual, identity.fingerprint()),
+				other => panic!("expected an identity mismatch, got {:?}", other),
+			}
+
+			assert!(receiving.join().unwrap().is_err(), "receiver should see the sender hang up");
+		}
+	}
+}
+
+#[test]
+fn receipt_is_signed_by_the_receiver() {
+	let key = random_bytes(32);
+	let identity = Identity::from_pkcs8(&Identity::generate().unwrap()).unwrap();
+	let payload = random_bytes(3 * BLOCK_SIZE + 7);
+
+	for receiver_identity in [Some(identity.clone()), None] {
+		let (near, far) = Loopback::pair();
+
+		let mut receiver = Receiver::with_transport(far, &key).unwrap();
+		let signs = receiver_identity.is_some();
+		if let Some(receiver_identity) = receiver_identity {
+			receiver.set_identity(receiver_identity);
+		}
+
+		let receiving = thread::spawn(move || receiver.run(io::sink()));
+
+		let mut sender = Sender::with_transport(near, &key).unwrap();
+		sender.set_require_receipt(true);
+		let sent = sender.run(Cursor::new(payload.clone()));
+		receiving.join().unwrap().expect("receiver failed");
+
+		if !signs {
+			match sent {
+				Err(ProtoError::NoReceipt) => continue,
+				other => panic!("expected the sender to insist on a receipt, got {:?}", other),
+			}
+		}
+
+		sent.expect("sender failed");
+		let receipt = sender.receipt().expect("receiver should have signed a receipt");
+		assert_eq!(receipt.bytes, payload.len() as u64);
+		assert_eq!(&receipt.digest[..], digest::digest(&digest::SHA256, &payload).as_ref());
+		assert_eq!(receipt.fingerprint(), identity.fingerprint());
+		assert_eq!(Some(receipt.session_id), sender.summary().session_id);
+	}
+}
+
+#[test]
+fn digests_match_the_payload() {
+	let key = random_bytes(32);
+	let payload = random_bytes(5 * BLOCK_SIZE + 11);
+	let (near, far) = Loopback::pair();
+
+	let mut receiver = Receiver::with_transport(far, &key).unwrap();
+	let receiving = thread::spawn(move || receiver.run(io::sink()).map(|_| receiver));
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+	let receiver = receiving.join().unwrap().expect("receiver failed");
+
+	let expected = digest::digest(&digest::SHA256, &payload);
+	assert_eq!(&sender.digest()[..], expected.as_ref());
+	assert_eq!(receiver.digest().as_ref().map(|digest| &digest[..]), Some(expected.as_ref()));
+}
+
+#[test]
+fn sender_identity_is_pinned() {
+	let key = random_bytes(32);
+	let identity = Identity::from_pkcs8(&Identity::generate().unwrap()).unwrap();
+	let impostor = Identity::from_pkcs8(&Identity::generate().unwrap()).unwrap();
+
+	for sender_identity in [Some(identity.clone()), Some(impostor.clone()), None] {
+		let (near, far) = Loopback::pair();
+
+		let mut receiver = Receiver::with_transport(far, &key).unwrap();
+		receiver.set_expected_fingerprints(&[identity.fingerprint()]);
+		let receiving = thread::spawn(move || receiver.run(io::sink()));
+
+		let mut sender = Sender::with_transport(near, &key).unwrap();
+		let trusted = matches!(&sender_identity, Some(id) if id.fingerprint() == identity.fingerprint());
+		if let Some(sender_identity) = sender_identity {
+			sender.set_identity(sender_identity);
+		}
+
+		let sent = sender.run(Cursor::new(random_bytes(BLOCK_SIZE)));
+		let received = receiving.join().unwrap();
+
+		if trusted {
+			sent.expect("sender failed");
+			received.expect("receiver should accept the sender it pinned");
+		} else {
+			match received {
+				Err(ProtoError::IdentityMismatch { actual, .. }) => assert_eq!(actual, impostor.fingerprint()),
+				Err(ProtoError::NoIdentity { .. }) => {},
+				other => panic!("expected the sender to be refused, got {:?}", other),
+			}
+
+			assert!(sent.is_err(), "sender should see the receiver hang up");
+		}
+	}
+}
+
+#[test]
+fn events_are_delivered_in_order() {
+	let key = random_bytes(32);
+	let payload = random_bytes(2 * BLOCK_SIZE + 1);
+	let (near, far) = Loopback::pair();
+
+	let mut receiver = Receiver::with_transport(far, &key).unwrap();
+	let received = receiver.subscribe();
+	let receiving = thread::spawn(move || receiver.run(io::sink()));
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	let sent = sender.subscribe();
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+	receiving.join().unwrap().expect("receiver failed");
+
+	let session_id = sender.summary().session_id.expect("sender should have started a session").to_string();
+	let mut fingerprints = vec![];
+	for events in [sent, received] {
+		let events: Vec<Event> = events.try_iter().collect();
+		let blocks = events.iter().filter(|event| matches!(event, Event::Block { .. })).count();
+
+		match events.first() {
+			Some(Event::HandshakeComplete { session_id: id, fingerprint }) => {
+				assert_eq!(*id, session_id);
+				fingerprints.push(fingerprint.clone());
+			},
+
+			other => panic!("expected the handshake to complete first, got {:?}", other),
+		}
+
+		assert!(matches!(events[events.len() - 2], Event::Goodbye));
+		assert_eq!(blocks, 3);
+
+		match events.last() {
+			Some(Event::Finished { plaintext_bytes, blocks, .. }) => {
+				assert_eq!(*plaintext_bytes, payload.len() as u64);
+				assert_eq!(*blocks, 3);
+			},
+
+			other => panic!("expected the session to finish last, got {:?}", other),
+		}
+	}
+
+	assert_eq!(fingerprints[0], fingerprints[1]);
+	assert_eq!(fingerprints[0].split('-').count(), 5);
+}
+
+#[test]
+fn sender_can_be_cancelled() {
+	let key = random_bytes(32);
+	let (near, far) = Loopback::pair();
+
+	let receiver_key = key.clone();
+	let receiving = thread::spawn(move || {
+		let mut receiver = Receiver::with_transport(far, &receiver_key)?;
+		receiver.run(io::sink())
+	});
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	let token = sender.cancel_token();
+	thread::spawn(move || {
+		thread::sleep(Duration::from_millis(50));
+		token.cancel();
+	});
+
+	match sender.run(io::repeat(0)) {
+		Err(ProtoError::Cancelled) => {},
+		other => panic!("expected the sender to be cancelled, got {:?}", other),
+	}
+
+	match receiving.join().unwrap() {
+		Err(ProtoError::PeerAborted) => {},
+		other => panic!("expected the sender to abort, got {:?}", other),
+	}
+}
+
+#[test]
+fn receiver_can_be_cancelled() {
+	let key = random_bytes(32);
+	let (near, far) = Loopback::pair();
+
+	let mut receiver = Receiver::with_transport(far, &key).unwrap();
+	let token = receiver.cancel_token();
+	let receiving = thread::spawn(move || receiver.run(io::sink()));
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	let sending = thread::spawn(move || sender.run(io::repeat(0)));
+
+	thread::sleep(Duration::from_millis(50));
+	token.cancel();
+
+	match receiving.join().unwrap() {
+		Err(ProtoError::Cancelled) => {},
+		other => panic!("expected the receiver to be cancelled, got {:?}", other),
+	}
+
+	assert!(sending.join().unwrap().is_err(), "sender should not complete once the receiver is gone");
+}
+
+/// An output which fails once `room` bytes have been written to it, as a full
+/// disk would.
+struct FullDisk {
+	room: usize,
+}
+
+impl Write for FullDisk {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if buf.len() > self.room {
+			return Err(io::Error::other("no space left on device"));
+		}
+
+		self.room -= buf.len();
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+#[test]
+fn receiver_failure_reaches_the_sender() {
+	let key = random_bytes(32);
+	let payload = random_bytes(16 * BLOCK_SIZE);
+	let (near, far) = Loopback::pair();
+
+	let receiver_key = key.clone();
+	let receiving = thread::spawn(move || {
+		let mut receiver = Receiver::with_transport(far, &receiver_key)?;
+		receiver.run(FullDisk { room: 2 * BLOCK_SIZE })
+	});
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	match sender.run(Cursor::new(payload)) {
+		Err(ProtoError::PeerFailed { code: FaultCode::Io, message }) => assert!(message.contains("no space left on device"), "got {:?}", message),
+		other => panic!("expected the receiver's i/o error, got {:?}", other),
+	}
+
+	match receiving.join().unwrap() {
+		Err(ProtoError::IoErr { .. }) => {},
+		other => panic!("expected an i/o error, got {:?}", other),
+	}
+}
+
+#[test]
+fn output_which_fails_to_finish_fails_the_sender() {
+	let key = random_bytes(32);
+	let (near, far) = Loopback::pair();
+
+	let receiver_key = key.clone();
+	let receiving = thread::spawn(move || {
+		let mut receiver = Receiver::with_transport(far, &receiver_key)?;
+		receiver.run_finished(vec![], |_| Err(io::Error::other("the filter command failed")))
+	});
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	match sender.run(Cursor::new(random_bytes(3 * BLOCK_SIZE))) {
+		Err(ProtoError::PeerFailed { code: FaultCode::Io, message }) => assert!(message.contains("filter command failed"), "got {:?}", message),
+		other => panic!("expected the receiver's i/o error, got {:?}", other),
+	}
+
+	assert!(matches!(receiving.join().unwrap(), Err(ProtoError::IoErr { .. })));
+}
+
+#[test]
+fn mismatched_keys_are_rejected() {
+	let payload = random_bytes(BLOCK_SIZE);
+	let (sent, received) = transfer(payload, &random_bytes(32), &random_bytes(32), |_| {});
+
+	match sent {
+		Err(ProtoError::HandshakeRejected) => {},
+		other => panic!("expected the handshake to be rejected, got {:?}", other),
+	}
+
+	match received {
+		Err(ProtoError::CryptoErr) => {},
+		other => panic!("expected a crypto error, got {:?}", other.map(|output| output.len())),
+	}
+}
+
+#[test]
+fn dropped_connection_is_not_a_key_mismatch() {
+	let key = random_bytes(32);
+	let (near, far) = Loopback::pair();
+
+	let recv_key = key.clone();
+	let receiving = thread::spawn(move || {
+		let mut receiver = Receiver::with_transport(far, &recv_key)?;
+		receiver.run(io::sink())
+	});
+
+	let sent = Sender::with_transport(Resetting { inner: near, reset: false }, &key)
+		.and_then(|mut sender| sender.run(Cursor::new(random_bytes(16))));
+
+	match sent {
+		Err(ref err) if err.is_hangup() => {},
+		other => panic!("expected the connection to be reported lost, got {:?}", other),
+	}
+
+	assert!(receiving.join().unwrap().is_err());
+}
+
+#[test]
+fn strangers_are_rejected() {
+	let key = random_bytes(32);
+
+	// something other than a sender connects to the receiver
+	let (mut near, far) = Loopback::pair();
+	let mut receiver = Receiver::with_transport(far, &key).unwrap();
+	near.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+	match receiver.run(io::sink()) {
+		Err(ProtoError::NotAPeer) => {},
+		other => panic!("expected the stranger to be rejected, got {:?}", other),
+	}
+
+	// the sender connects to something other than a receiver
+	let (near, mut far) = Loopback::pair();
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	far.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").unwrap();
+	match sender.run(Cursor::new(random_bytes(16))) {
+		Err(ProtoError::NotAPeer) => {},
+		other => panic!("expected the stranger to be rejected, got {:?}", other),
+	}
+}
+
+#[test]
+fn announced_size_is_checked() {
+	let key = random_bytes(32);
+	let payload = random_bytes(3 * BLOCK_SIZE);
+
+	let len = payload.len() as u64;
+	let (sent, received) = transfer(payload.clone(), &key, &key, |sender| sender.set_total_size(len));
+	sent.expect("sender failed");
+	assert_eq!(received.expect("receiver failed"), payload);
+
+	let (sent, received) = transfer(payload, &key, &key, |sender| sender.set_total_size(len + 1));
+	match sent {
+		Err(ProtoError::SizeMismatch { expected, sent }) => assert_eq!((expected, sent), (len + 1, len)),
+		other => panic!("expected a size mismatch, got {:?}", other),
+	}
+
+	assert!(matches!(received, Err(ProtoError::PeerAborted)), "got {:?}", received.map(|output| output.len()));
+}
+
+#[test]
+fn receiver_picks_the_senders_key() {
+	let keys = [random_bytes(32), random_bytes(16), random_bytes(32)];
+	let payload = random_bytes(BLOCK_SIZE + 5);
+
+	for (i, send_key) in keys.iter().chain(Some(&random_bytes(32))).enumerate() {
+		let (near, far) = Loopback::pair();
+
+		let mut receiver = Receiver::with_transport(far, &keys[0]).unwrap();
+		for key in &keys[1..] {
+			receiver.add_key(key).unwrap();
+		}
+
+		let receiving = thread::spawn(move || {
+			let mut output = vec![];
+			receiver.run(&mut output).map(|_| output)
+		});
+
+		let sent = Sender::with_transport(near, send_key).and_then(|mut sender| sender.run(Cursor::new(payload.clone())));
+		let received = receiving.join().unwrap();
+
+		if i < keys.len() {
+			sent.expect("sender failed");
+			assert_eq!(received.expect("receiver failed"), payload);
+		} else {
+			assert!(matches!(sent, Err(ProtoError::HandshakeRejected)), "got {:?}", sent);
+			assert!(matches!(received, Err(ProtoError::CryptoErr)));
+		}
+	}
+}
+
+#[test]
+fn shared_code_agrees_on_a_key() {
+	let code = generate_code();
+	let payload = random_bytes(2 * BLOCK_SIZE + 3);
+
+	for recv_code in [code.clone(), generate_code()] {
+		let (near, far) = Loopback::pair();
+
+		// neither peer's own key is used once they have agreed on one
+		let mut receiver = Receiver::with_transport(far, &random_bytes(32)).unwrap();
+		receiver.set_code(&recv_code);
+		let receiving = thread::spawn(move || {
+			let mut output = vec![];
+			receiver.run(&mut output).map(|_| output)
+		});
+
+		let mut sender = Sender::with_transport(near, &random_bytes(32)).unwrap();
+		sender.set_code(&code);
+		let sent = sender.run(Cursor::new(payload.clone()));
+		let received = receiving.join().unwrap();
+
+		if recv_code == code {
+			sent.expect("sender failed");
+			assert_eq!(received.expect("receiver failed"), payload);
+		} else {
+			assert!(matches!(sent, Err(ProtoError::HandshakeRejected)), "got {:?}", sent);
+			assert!(received.is_err());
+		}
+	}
+}
+
+#[test]
+fn fan_out_reaches_every_receiver() {
+	let key = random_bytes(32);
+	let payload = random_bytes(8 * BLOCK_SIZE + 3);
+
+	let mut senders = vec![];
+	let mut receiving = vec![];
+
+	for _ in 0..3 {
+		let (near, far) = Loopback::pair();
+		senders.push(Sender::with_transport(near, &key).unwrap());
+
+		let recv_key = key.clone();
+		receiving.push(thread::spawn(move || {
+			let mut output = vec![];
+			Receiver::with_transport(far, &recv_key)?.run(&mut output)?;
+			Ok::<_, ProtoError>(output)
+		}));
+	}
+
+	let mut fan_out = FanOut::new(senders);
+	for result in fan_out.run(Cursor::new(payload.clone())) {
+		result.expect("sender failed");
+	}
+
+	for received in receiving {
+		assert!(received.join().unwrap().expect("receiver failed") == payload);
+	}
+}
+
+/// An output which can still be read once the receiver is done w/ it.
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0.lock().unwrap().extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+#[test]
+fn tees_get_a_copy_of_the_output() {
+	let key = random_bytes(32);
+
+	// the run of zeros is skipped by the sender, & must be filled in the tees
+	let mut payload = random_bytes(4 * BLOCK_SIZE + 3);
+	payload.extend_from_slice(&vec![0u8; 4 * BLOCK_SIZE]);
+	payload.extend_from_slice(&random_bytes(BLOCK_SIZE - 1));
+
+	let tees = [Shared::default(), Shared::default()];
+	let (near, far) = Loopback::pair();
+	let receiving = thread::spawn({
+		let (key, tees) = (key.clone(), tees.clone());
+		move || {
+			let mut output = vec![];
+			let mut receiver = Receiver::with_transport(far, &key)?;
+			for tee in tees {
+				receiver.add_tee(tee);
+			}
+
+			receiver.run(&mut output)?;
+			Ok::<_, ProtoError>(output)
+		}
+	});
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	sender.set_sparse(true);
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let received = receiving.join().unwrap().expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+
+	for tee in &tees {
+		assert!(*tee.0.lock().unwrap() == payload, "a tee was not given the same data as the output");
+	}
+}
+
+#[test]
+fn sparse_file_round_trips() {
+	let key = random_bytes(32);
+	let data = random_bytes(3 * BLOCK_SIZE + 5);
+
+	// a hole, some data, and another hole running to the end of the file
+	let path = std::env::temp_dir().join(format!("ubuffer-sparse-{}", std::process::id()));
+	let mut file = fs::File::create(&path).unwrap();
+	file.set_len(4 << 20).unwrap();
+	file.seek(SeekFrom::Start(1 << 20)).unwrap();
+	file.write_all(&data).unwrap();
+	drop(file);
+
+	let mut expected = vec![0u8; 4 << 20];
+	expected[1 << 20..(1 << 20) + data.len()].copy_from_slice(&data);
+
+	let (near, far) = Loopback::pair();
+	let receiving = thread::spawn({
+		let key = key.clone();
+		move || {
+			let mut output = Cursor::new(vec![]);
+			Receiver::with_transport(far, &key)?.run_seekable(&mut output)?;
+			Ok::<_, ProtoError>(output.into_inner())
+		}
+	});
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	sender.set_sparse(true);
+	let sent = sender.run_file(fs::File::open(&path).unwrap());
+	fs::remove_file(&path).unwrap();
+
+	sent.expect("sender failed");
+	let received = receiving.join().unwrap().expect("receiver failed");
+	assert_eq!(received.len(), expected.len());
+	assert!(received == expected, "sparse file was corrupted");
+}
+
+/// A file output which notes how much space had been set aside for it by the
+/// time the first data arrived.
+#[cfg(target_os = "linux")]
+struct Reserving {
+	file: fs::File,
+	reserved: Arc<Mutex<Option<u64>>>,
+}
+
+#[cfg(target_os = "linux")]
+impl Write for Reserving {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		use std::os::unix::fs::MetadataExt;
+
+		let mut reserved = self.reserved.lock().unwrap();
+		if reserved.is_none() {
+			*reserved = Some(self.file.metadata()?.blocks() * 512);
+		}
+
+		self.file.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.file.flush()
+	}
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn output_file_space_is_reserved() {
+	let key = random_bytes(32);
+	let data = random_bytes(64 * BLOCK_SIZE + 9);
+	let input = std::env::temp_dir().join(format!("ubuffer-reserve-in-{}", std::process::id()));
+	let output = std::env::temp_dir().join(format!("ubuffer-reserve-out-{}", std::process::id()));
+	fs::write(&input, &data).unwrap();
+
+	let reserved = Arc::new(Mutex::new(None));
+	let (near, far) = Loopback::pair();
+	let receiving = thread::spawn({
+		let (key, output, reserved) = (key.clone(), output.clone(), Arc::clone(&reserved));
+		move || {
+			let file = fs::File::create(&output)?;
+			let mut receiver = Receiver::with_transport(far, &key)?;
+			receiver.set_output_file(&file)?;
+			receiver.run(Reserving { file, reserved })
+		}
+	});
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	sender.set_metadata(FileMeta::from_path(&input).unwrap());
+	let sent = sender.run_file(fs::File::open(&input).unwrap());
+	let received = receiving.join().unwrap();
+	let written = fs::read(&output);
+	let _ = (fs::remove_file(&input), fs::remove_file(&output));
+
+	sent.expect("sender failed");
+	received.expect("receiver failed");
+	assert!(written.unwrap() == data, "file was corrupted");
+
+	let reserved = reserved.lock().unwrap().expect("nothing was written");
+	assert!(reserved >= data.len() as u64, "only {} of {} bytes were reserved before the data arrived", reserved, data.len());
+}
+
+/// The write end of a pipe, which cannot be synced to disk like a file can.
+#[cfg(unix)]
+fn unsyncable() -> fs::File {
+	use std::os::unix::io::FromRawFd;
+
+	let mut fds = [0; 2];
+	assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+	unsafe { libc::close(fds[0]); }
+	unsafe { fs::File::from_raw_fd(fds[1]) }
+}
+
+#[test]
+#[cfg(unix)]
+fn output_is_synced_when_asked() {
+	let key = random_bytes(32);
+	let payload = random_bytes(8 * BLOCK_SIZE + 1);
+
+	// an output which fails to sync only fails the session if it is synced
+	for &(sync_on_close, interval) in &[(false, None), (true, None), (false, Some(Duration::from_secs(0)))] {
+		let (near, far) = Loopback::pair();
+		let receiving = thread::spawn({
+			let key = key.clone();
+			move || {
+				let mut receiver = Receiver::with_transport(far, &key)?;
+				receiver.set_output_file(&unsyncable())?;
+				receiver.set_sync_on_close(sync_on_close);
+				receiver.set_fsync_interval(interval);
+				receiver.run(vec![])
+			}
+		});
+
+		let mut sender = Sender::with_transport(near, &key).unwrap();
+		let sent = sender.run(Cursor::new(payload.clone()));
+		let received = receiving.join().unwrap();
+
+		if !sync_on_close && interval.is_none() {
+			sent.expect("sender failed");
+			received.expect("receiver failed");
+			continue;
+		}
+
+		match sent {
+			Err(ProtoError::PeerFailed { code: FaultCode::Io, .. }) => {},
+			other => panic!("expected the receiver's failure to sync, got {:?}", other),
+		}
+
+		assert!(matches!(received, Err(ProtoError::IoErr { .. })), "got {:?}", received);
+	}
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn direct_output_round_trips() {
+	use std::os::unix::fs::OpenOptionsExt;
+
+	let key = random_bytes(32);
+
+	// neither the data nor the hole in it are a multiple of the alignment
+	let mut payload = random_bytes(300 * BLOCK_SIZE + 7);
+	payload.extend_from_slice(&vec![0u8; 3 * BLOCK_SIZE + 5]);
+	payload.extend_from_slice(&random_bytes(11));
+
+	let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("ubuffer-direct-{}", std::process::id()));
+	let file = match fs::OpenOptions::new().write(true).create(true).truncate(true).custom_flags(libc::O_DIRECT).open(&path) {
+		Ok(file) => file,
+
+		// i.e: tmpfs, which cannot bypass the page cache
+		Err(ref err) if err.raw_os_error() == Some(libc::EINVAL) => return,
+		Err(err) => panic!("could not open the output: {}", err),
+	};
+
+	let (near, far) = Loopback::pair();
+	let receiving = thread::spawn({
+		let key = key.clone();
+		move || Receiver::with_transport(far, &key)?.run_direct(file)
+	});
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	sender.set_sparse(true);
+	let sent = sender.run(Cursor::new(payload.clone()));
+	let received = receiving.join().unwrap();
+	let written = fs::read(&path);
+	let _ = fs::remove_file(&path);
+
+	sent.expect("sender failed");
+	received.expect("receiver failed");
+	assert!(written.unwrap() == payload, "payload was corrupted");
+}
+
+#[test]
+fn mapped_file_round_trips() {
+	let key = random_bytes(32);
+	let data = random_bytes(40 * BLOCK_SIZE + 11);
+	let path = std::env::temp_dir().join(format!("ubuffer-mapped-{}", std::process::id()));
+	fs::write(&path, &data).unwrap();
+
+	// sealed on the sender's thread, by the crypto workers, & padded first
+	for &(threads, padding) in &[(1, false), (4, false), (4, true)] {
+		let (near, far) = Loopback::pair();
+		let receiving = thread::spawn({
+			let key = key.clone();
+			move || {
+				let mut output = vec![];
+				Receiver::with_transport(far, &key)?.run(&mut output)?;
+				Ok::<_, ProtoError>(output)
+			}
+		});
+
+		let mut sender = Sender::with_transport(near, &key).unwrap();
+		sender.set_mmap(true);
+		sender.set_crypto_threads(threads);
+		sender.set_padding(padding);
+		sender.set_rekey_interval(16 * BLOCK_SIZE as u64);
+		sender.run_file(fs::File::open(&path).unwrap()).expect("sender failed");
+
+		let received = receiving.join().unwrap().expect("receiver failed");
+		assert!(received == data, "mapped file was corrupted");
+	}
+
+	fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn zeros_in_a_stream_are_skipped() {
+	let key = random_bytes(32);
+
+	// short runs of zeros stay in the data, long ones (even across reads) do not
+	let mut payload = random_bytes(BLOCK_SIZE + 3);
+	payload.extend_from_slice(&[0u8; 100]);
+	payload.extend_from_slice(&random_bytes(5));
+	payload.extend_from_slice(&vec![0u8; 1 << 20]);
+	payload.extend_from_slice(&random_bytes(2 * BLOCK_SIZE));
+	payload.extend_from_slice(&vec![0u8; 3 * BLOCK_SIZE + 1]);
+
+	let (near, far) = Loopback::pair();
+	let receiving = thread::spawn({
+		let key = key.clone();
+		move || {
+			let mut output = vec![];
+			Receiver::with_transport(far, &key)?.run(&mut output)?;
+			Ok::<_, ProtoError>(output)
+		}
+	});
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	sender.set_sparse(true);
+	let events = sender.subscribe();
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let skipped: u64 = events.try_iter()
+		.filter_map(|event| match event {
+			Event::Skip { len, .. } => Some(len),
+			_ => None,
+		})
+		.sum();
+
+	assert_eq!(skipped, (1 << 20) + 3 * BLOCK_SIZE as u64 + 1);
+
+	let received = receiving.join().unwrap().expect("receiver failed");
+	assert_eq!(received.len(), payload.len());
+	assert!(received == payload, "stream was corrupted");
+}
+
+/// An input which goes quiet for a while, then reaches EOF.
+struct Stall(Duration);
+
+impl Read for Stall {
+	fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+		thread::sleep(self.0);
+		Ok(0)
+	}
+}
+
+/// An input which only hands out a few bytes per read.
+struct Trickle(Cursor<Vec<u8>>);
+
+impl Read for Trickle {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let len = buf.len().min(1024);
+		self.0.read(&mut buf[..len])
+	}
+}
+
+#[test]
+fn short_reads_are_coalesced() {
+	let key = random_bytes(32);
+	let payload = random_bytes(2 * BLOCK_SIZE + 5);
+	let (near, far) = Loopback::pair();
+
+	let mut receiver = Receiver::with_transport(far, &key).unwrap();
+	let events = receiver.subscribe();
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		receiver.run(&mut output).map(|_| output)
+	});
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	sender.set_coalescing(BLOCK_SIZE, Duration::from_secs(5));
+	sender.run(Trickle(Cursor::new(payload.clone()))).expect("sender failed");
+
+	let received = receiving.join().unwrap().expect("receiver failed");
+	assert!(received == payload, "coalesced transfer was corrupted");
+
+	// two full blocks, & what was left at EOF
+	let lens: Vec<usize> = events.try_iter()
+		.filter_map(|event| match event {
+			Event::Block { plaintext_len, .. } => Some(plaintext_len),
+			_ => None,
+		})
+		.collect();
+
+	assert_eq!(lens, [BLOCK_SIZE, BLOCK_SIZE, 5]);
+}
+
+#[test]
+fn line_buffered_blocks_end_at_a_newline() {
+	let key = random_bytes(32);
+	let (near, far) = Loopback::pair();
+
+	let mut receiver = Receiver::with_transport(far, &key).unwrap();
+	let events = receiver.subscribe();
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		receiver.run(&mut output).map(|_| output)
+	});
+
+	// each piece is a separate read, which splits the lines up
+	let input = Cursor::new(b"first li".to_vec())
+		.chain(Cursor::new(b"ne\nsecond line\nthi".to_vec()))
+		.chain(Cursor::new(b"rd".to_vec()));
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	sender.set_line_buffered(true);
+	sender.run(input).expect("sender failed");
+
+	let received = receiving.join().unwrap().expect("receiver failed");
+	assert_eq!(received, b"first line\nsecond line\nthird");
+
+	// the partial line at the end is sent at EOF
+	let lens: Vec<usize> = events.try_iter()
+		.filter_map(|event| match event {
+			Event::Block { plaintext_len, .. } => Some(plaintext_len),
+			_ => None,
+		})
+		.collect();
+
+	assert_eq!(lens, [23, 5]);
+}
+
+#[test]
+fn rate_limit_paces_the_sender() {
+	let key = random_bytes(32);
+	let payload = random_bytes(512 * 1024);
+
+	// at 1M/s, half a megabyte cannot be sent in much less than half a second
+	let started = Instant::now();
+	let (sent, received) = transfer(payload.clone(), &key, &key, |sender| sender.set_rate_limit(1 << 20));
+	let elapsed = started.elapsed();
+
+	sent.expect("sender failed");
+	assert_eq!(received.expect("receiver failed"), payload);
+	assert!(elapsed >= Duration::from_millis(450), "sent in {:?}", elapsed);
+}
+
+#[test]
+fn padded_blocks_hide_their_length() {
+	let key = random_bytes(32);
+	let (head, tail) = (random_bytes(BLOCK_SIZE + 1), random_bytes(7));
+	let (near, far) = Loopback::pair();
+
+	let mut receiver = Receiver::with_transport(far, &key).unwrap();
+	let events = receiver.subscribe();
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		receiver.run(&mut output).map(|_| output)
+	});
+
+	// the input idles long enough in the middle for cover blocks to be sent
+	let input = Cursor::new(head.clone()).chain(Stall(Duration::from_millis(800))).chain(Cursor::new(tail.clone()));
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	sender.set_padding(true);
+	sender.run(input).expect("sender failed");
+
+	let received = receiving.join().unwrap().expect("receiver failed");
+	assert!(received == [head, tail].concat(), "padded transfer was corrupted");
+
+	let lens: Vec<usize> = events.try_iter()
+		.filter_map(|event| match event {
+			Event::Block { ciphertext_len, .. } => Some(ciphertext_len),
+			_ => None,
+		})
+		.collect();
+
+	assert_eq!(lens.len(), 3);
+	assert!(lens.iter().all(|&len| len == lens[0] && len > BLOCK_SIZE));
+}
+
+#[test]
+fn quiet_sender_is_noticed() {
+	let key = random_bytes(32);
+	let (head, tail) = (random_bytes(100), random_bytes(100));
+	let (near, far) = Loopback::pair();
+
+	let mut receiver = Receiver::with_transport(far, &key).unwrap();
+	receiver.set_quiet_threshold(Duration::from_millis(200));
+	let events = receiver.subscribe();
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		receiver.run(&mut output).map(|_| output)
+	});
+
+	// the sender goes quiet in the middle, w/o keepalives to cover for it
+	let input = Cursor::new(head.clone()).chain(Stall(Duration::from_millis(700))).chain(Cursor::new(tail.clone()));
+	Sender::with_transport(near, &key).unwrap().run(input).expect("sender failed");
+
+	let received = receiving.join().unwrap().expect("receiver failed");
+	assert!(received == [head, tail].concat());
+
+	let events: Vec<Event> = events.try_iter().collect();
+	let quiet = events.iter().filter(|event| matches!(event, Event::Quiet { .. })).count();
+	let heard: Vec<f64> = events.iter()
+		.filter_map(|event| match event {
+			Event::Heard { idle_secs } => Some(*idle_secs),
+			_ => None,
+		})
+		.collect();
+
+	assert!(quiet >= 2, "only warned {} times", quiet);
+	assert_eq!(heard.len(), 1);
+	assert!(heard[0] >= 0.6);
+}
+
+#[test]
+fn unsupported_features_are_not_used() {
+	let key = random_bytes(32);
+	let payload = random_bytes(BLOCK_SIZE + 7);
+	let (near, far) = Loopback::pair();
+
+	let mut receiver = Receiver::with_transport(far, &key).unwrap();
+	receiver.set_features(Features::all().without(Features::PADDING));
+	let events = receiver.subscribe();
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		receiver.run(&mut output).map(|_| (output, receiver.features()))
+	});
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	sender.set_padding(true);
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let (received, features) = receiving.join().unwrap().expect("receiver failed");
+	assert!(received == payload, "transfer was corrupted");
+	assert_eq!(features, sender.features());
+	assert!(!features.contains(Features::PADDING) && features.contains(Features::MULTI_FILE));
+
+	// the last block is sent as it is, rather than padded to the full size
+	let lens: Vec<usize> = events.try_iter()
+		.filter_map(|event| match event {
+			Event::Block { ciphertext_len, .. } => Some(ciphertext_len),
+			_ => None,
+		})
+		.collect();
+
+	assert_eq!(lens.len(), 2);
+	assert!(lens[1] < BLOCK_SIZE, "got {:?}", lens);
+}
+
+/// Sends a transfer over a transport which forges a reply of type `ty`,
+/// which the sender must refuse rather than act on.
+fn assert_forged_reply_refused(ty: u8) {
+	let key = random_bytes(32);
+	let payload = random_bytes(4 * ACK_INTERVAL as usize * BLOCK_SIZE);
+	let (near, far) = Loopback::pair();
+
+	let receiving = thread::spawn({
+		let key = key.clone();
+		move || {
+			let mut output = vec![];
+			Receiver::with_transport(Forging { inner: far, ty, forged: false }, &key)?.run(&mut output)?;
+			Ok::<_, ProtoError>(output)
+		}
+	});
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	let events = sender.subscribe();
+	let sent = sender.run(Cursor::new(payload));
+	assert!(matches!(sent, Err(ProtoError::CryptoErr)), "sender returned {:?}", sent.err());
+
+	let handled = events.try_iter().any(|event| matches!(event, Event::Ack { .. } | Event::Nack { .. }));
+	assert!(!handled, "the forged reply was handled");
+
+	drop(sender);
+	let _ = receiving.join().unwrap();
+}
+
+#[test]
+fn smallest_window_does_not_stall() {
+	let key = random_bytes(32);
+	let payload = random_bytes(4 * ACK_INTERVAL as usize * BLOCK_SIZE);
+
+	// the sender numbers a whole batch of blocks before it sends the first,
+	// which must not count against a limit of a single ack's worth
+	let (sent, received) = transfer(payload.clone(), &key, &key, |sender| sender.set_retransmit_window(1));
+
+	sent.expect("sender failed");
+	assert!(received.expect("receiver failed") == payload, "transfer was corrupted");
+}
+
+#[test]
+fn forged_ack_is_refused() {
+	assert_forged_reply_refused(21);
+}
+
+#[test]
+fn forged_nack_is_refused() {
+	assert_forged_reply_refused(13);
+}
+
+#[test]
+fn unacknowledged_data_is_bounded() {
+	let key = random_bytes(32);
+	let payload = random_bytes(600 * BLOCK_SIZE);
+	let (near, far) = Loopback::pair();
+
+	let receiving = thread::spawn({
+		let key = key.clone();
+		move || {
+			let mut output = vec![];
+			Receiver::with_transport(far, &key)?.run(&mut output)?;
+			Ok::<_, ProtoError>(output)
+		}
+	});
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	sender.set_max_unacked(2 * ACK_INTERVAL as usize);
+	let events = sender.subscribe();
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let received = receiving.join().unwrap().expect("receiver failed");
+	assert!(received == payload, "transfer was corrupted");
+
+	// the sender never ran further ahead of the receiver than it was allowed
+	let (mut acks, mut acked) = (0, 0);
+	for event in events.try_iter() {
+		match event {
+			Event::Ack { total_bytes, .. } => {
+				assert!(total_bytes >= acked, "acks went backwards");
+				acks += 1;
+				acked = total_bytes;
+			},
+
+			Event::Block { total_bytes, .. } => {
+				assert!(total_bytes - acked <= 2 * ACK_INTERVAL * BLOCK_SIZE as u64, "{} bytes were unacknowledged", total_bytes - acked);
+			},
+
+			_ => {},
+		}
+	}
+
+	assert!(acks >= 600 / ACK_INTERVAL as usize - 1, "only got {} acks", acks);
+	assert_eq!(sender.acked_bytes(), acked);
+}
+
+#[test]
+fn multiple_files_need_the_receivers_support() {
+	let key = random_bytes(32);
+	let path = std::env::temp_dir().join(format!("ubuffer-features-{}", std::process::id()));
+	fs::write(&path, random_bytes(16)).unwrap();
+
+	let (near, far) = Loopback::pair();
+	let receiver_key = key.clone();
+	let receiving = thread::spawn(move || {
+		let mut receiver = Receiver::with_transport(far, &receiver_key)?;
+		receiver.set_features(Features::all().without(Features::MULTI_FILE));
+		receiver.run(io::sink())
+	});
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	let sent = sender.run_files(&[&path]);
+	fs::remove_file(&path).unwrap();
+
+	match sent {
+		Err(ProtoError::UnsupportedFeature { feature }) => assert_eq!(feature, Features::MULTI_FILE),
+		other => panic!("expected multiple files to be refused, got {:?}", other),
+	}
+
+	assert!(receiving.join().unwrap().is_err(), "receiver should not complete w/o the files");
+}
+
+#[test]
+fn files_w_the_same_name_are_refused() {
+	let key = random_bytes(32);
+	let base = std::env::temp_dir().join(format!("ubuffer-same-name-{}", std::process::id()));
+	let (first, second, dir) = (base.join("a"), base.join("b"), base.join("out"));
+	for dir in [&first, &second, &dir] {
+		fs::create_dir_all(dir).unwrap();
+	}
+
+	let contents = random_bytes(3 * BLOCK_SIZE + 5);
+	fs::write(first.join("same.bin"), &contents).unwrap();
+	fs::write(second.join("same.bin"), random_bytes(16)).unwrap();
+
+	let (near, far) = Loopback::pair();
+	let receiver_key = key.clone();
+	let receiver_dir = dir.clone();
+	let receiving = thread::spawn(move || {
+		let mut receiver = Receiver::with_transport(far, &receiver_key)?;
+		receiver.set_output_dir(receiver_dir);
+		receiver.run(io::sink())
+	});
+
+	let mut sender = Sender::with_transport(near, &key).unwrap();
+	let sent = sender.run_files(&[first.join("same.bin"), second.join("same.bin")]);
+
+	match receiving.join().unwrap() {
+		Err(ProtoError::FileExists { name }) => assert_eq!(name, "same.bin"),
+		other => panic!("expected the second file to be refused, got {:?}", other),
+	}
+
+	// the first file is left as it was sent, rather than truncated
+	assert!(sent.is_err(), "sender should see the receiver hang up");
+	assert!(fs::read(dir.join("same.bin")).unwrap() == contents, "first file was overwritten");
+	fs::remove_dir_all(&base).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn owner_and_perms_are_preserved() {
+	use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+	let key = random_bytes(32);
+	let base = std::env::temp_dir().join(format!("ubuffer-preserve-{}", std::process::id()));
+	let input = base.join("setuid.bin");
+	fs::create_dir_all(&base).unwrap();
+	fs::write(&input, random_bytes(16)).unwrap();
+	fs::set_permissions(&input, fs::Permissions::from_mode(0o4750)).unwrap();
+
+	// the set-user-ID bit is only kept along w/ the owner, which is ours
+	for (owner, expected) in [(false, 0o750), (true, 0o4750)] {
+		let dir = base.join(format!("out-{}", owner));
+		fs::create_dir_all(&dir).unwrap();
+
+		let (near, far) = Loopback::pair();
+		let receiver_key = key.clone();
+		let receiver_dir = dir.clone();
+		let receiving = thread::spawn(move || {
+			let mut receiver = Receiver::with_transport(far, &receiver_key)?;
+			receiver.set_output_dir(receiver_dir);
+			receiver.set_preserve_perms(true);
+			receiver.set_preserve_owner(owner);
+			receiver.run(io::sink())
+		});
+
+		let mut sender = Sender::with_transport(near, &key).unwrap();
+		sender.run_files(&[&input]).expect("sender failed");
+		receiving.join().unwrap().expect("receiver failed");
+
+		let written = fs::metadata(dir.join("setuid.bin")).unwrap();
+		assert_eq!(written.permissions().mode() & 0o7777, expected);
+		assert_eq!(written.uid(), fs::metadata(&input).unwrap().uid());
+	}
+
+	fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn forged_goodbye_is_rejected() {
+	let key = random_bytes(32);
+	let (near, far) = Loopback::pair();
+
+	let receiving = thread::spawn({
+		let key = key.clone();
+		move || Receiver::with_transport(far, &key)?.run(io::sink())
+	});
+
+	let truncating = Truncating { inner: near, keep: 2, forged: false };
+	let sent = Sender::with_transport(truncating, &key).unwrap()
+		.run(Cursor::new(random_bytes(4 * BLOCK_SIZE)));
+
+	match receiving.join().unwrap() {
+		Err(ProtoError::MalformedMessage) => {},
+		other => panic!("expected the forged goodbye to be refused, got {:?}", other),
+	}
+
+	assert!(sent.is_err(), "sender should see the receiver hang up");
+}
+
+#[test]
+fn replayed_block_is_rejected() {
+	for reorder in [false, true] {
+		let key = random_bytes(32);
+		let (near, far) = Loopback::pair();
+
+		let receiving = thread::spawn({
+			let key = key.clone();
+			move || Receiver::with_transport(far, &key)?.run(io::sink())
+		});
+
+		let replaying = Replaying { inner: near, nth: 3, reorder, held: None };
+		let sent = Sender::with_transport(replaying, &key).unwrap()
+			.run(Cursor::new(random_bytes(8 * BLOCK_SIZE)));
+
+		match receiving.join().unwrap() {
+			Err(ProtoError::ReplayOrReorder { expected, received }) => {
+				assert_eq!(received > expected, reorder, "expected #{} but got #{}", expected, received);
+			},
+
+			other => panic!("expected the {} block to be refused, got {:?}", if reorder { "reordered" } else { "replayed" }, other),
+		}
+
+		assert!(sent.is_err(), "sender should see the receiver hang up");
+	}
+}
+
+#[test]
+fn corrupted_block_is_resent() {
+	let payload = random_bytes(16 * BLOCK_SIZE);
+	let mut events = None;
+
+	// the rekey falls between the corrupted block & the end of the window
+	let (sent, received) = corrupted_transfer(payload.clone(), |sender| {
+		sender.set_rekey_interval(4 * BLOCK_SIZE as u64);
+		events = Some(sender.subscribe());
+	});
+
+	sent.expect("sender failed");
+	assert!(received.expect("receiver failed") == payload, "payload was corrupted");
+
+	let nacks = events.unwrap().try_iter()
+		.filter(|event| matches!(event, Event::Nack { .. }))
+		.count();
+
+	assert_eq!(nacks, 1);
+}
+
+#[test]
+fn corrupted_block_outside_window_is_lost() {
+	let (sent, received) = corrupted_transfer(random_bytes(16 * BLOCK_SIZE), |sender| {
+		sender.set_retransmit_window(0);
+	});
+
+	match sent {
+		Err(ProtoError::BlockLost { .. }) => {},
+		other => panic!("expected the block to be lost, got {:?}", other),
+	}
+
+	assert!(received.is_err(), "receiver should not complete a transfer missing a block");
+}
+
+#[test]
+fn skipped_block_is_resent() {
+	let payload = random_bytes(16 * BLOCK_SIZE);
+	let (nacks, received) = skipped_transfer(payload.clone(), false);
+
+	assert!(received.expect("receiver failed") == payload, "payload was corrupted");
+	assert_eq!(nacks, 1);
+}
+
+#[test]
+fn reordered_block_is_resent() {
+	let payload = random_bytes(16 * BLOCK_SIZE);
+	let (nacks, received) = skipped_transfer(payload.clone(), true);
+
+	// the block which arrived late is kept, & the copy which was resent after
+	// it is discarded
+	assert!(received.expect("receiver failed") == payload, "payload was corrupted");
+	assert_eq!(nacks, 1);
+}
+
+#[test]
+fn parallel_crypto_round_trips() {
+	let key = random_bytes(32);
+	let payload = random_bytes(64 * BLOCK_SIZE + 7);
+	let (near, far) = Loopback::pair();
+
+	let receiving = thread::spawn({
+		let key = key.clone();
+		move || {
+			let mut output = vec![];
+			let mut receiver = Receiver::with_transport(far, &key)?;
+			receiver.set_crypto_threads(4);
+			receiver.run(&mut output)?;
+			Ok::<_, ProtoError>(output)
+		}
+	});
+
+	// a corrupted block in the middle of a batch is resent w/ those after it
+	let mut sender = Sender::with_transport(Corrupting { inner: near, nth: 20 }, &key).unwrap();
+	sender.set_crypto_threads(4);
+	sender.set_rekey_interval(16 * BLOCK_SIZE as u64);
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let received = receiving.join().unwrap().expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+}
+
+/// Sends `payload` w/ both peers able to resume the session, over a
+/// transport which `drop()` makes out of the sender's end of a `Loopback`.
+/// Resuming is on by default, so only a way to reconnect is given.
+fn resumed_transfer<T, F>(payload: &[u8], drop: F)
+where T: Transport + 'static, F: FnOnce(Loopback) -> T {
+	let key = random_bytes(32);
+	let (near, far) = Loopback::pair();
+
+	// each reconnection hands the receiver the far end of a new pair
+	let (dialed, accepted) = mpsc::channel::<Loopback>();
+
+	let receiving = thread::spawn({
+		let key = key.clone();
+		move || {
+			let mut output = vec![];
+			let mut receiver = Receiver::with_transport(far, &key)?;
+			receiver.set_reconnect(move |timeout| {
+				let end = accepted.recv_timeout(timeout).map_err(|_| ProtoError::ConnectTimeout)?;
+				Ok(Box::new(end) as Box<dyn Transport>)
+			});
+
+			receiver.run(&mut output)?;
+			Ok::<_, ProtoError>(output)
+		}
+	});
+
+	let mut sender = Sender::with_transport(drop(near), &key).unwrap();
+	sender.set_rekey_interval(16 * BLOCK_SIZE as u64);
+	sender.set_reconnect(move |_| {
+		let (near, far) = Loopback::pair();
+		dialed.send(far).map_err(|_| ProtoError::ConnectTimeout)?;
+		Ok(Box::new(near) as Box<dyn Transport>)
+	});
+
+	let events = sender.subscribe();
+	sender.run(Cursor::new(payload.to_vec())).expect("sender failed");
+
+	let resumed = events.try_iter().filter(|event| matches!(event, Event::Resumed { .. })).count();
+	assert_eq!(resumed, 1, "the session should have been resumed once");
+
+	let received = receiving.join().unwrap().expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+}
+
+#[test]
+fn dropped_connection_is_resumed() {
+	let payload = random_bytes(64 * BLOCK_SIZE + 7);
+	resumed_transfer(&payload, |near| Dropping { inner: near, nth: 40 });
+}
+
+#[test]
+fn dropped_goodbye_is_resumed() {
+	// long enough for the sender to have read its token before hanging up
+	let payload = random_bytes(64 * BLOCK_SIZE);
+	resumed_transfer(&payload, |near| HangingUp { inner: near, hung_up: false });
+}
+
+#[test]
+fn crashed_receiver_is_restored_from_its_checkpoint() {
+	let key = random_bytes(32);
+	let payload = random_bytes(1600 * BLOCK_SIZE + 5);
+	let output = std::env::temp_dir().join(format!("ubuffer-checkpoint-out-{}", std::process::id()));
+	let saved = std::env::temp_dir().join(format!("ubuffer-checkpoint-{}", std::process::id()));
+	let (near, far) = Loopback::pair();
+	let (dialed, accepted) = mpsc::channel::<Loopback>();
+
+	let receiving = thread::spawn({
+		let (key, output, saved) = (key.clone(), output.clone(), saved.clone());
+		move || {
+			// the first receiver gives up once the connection drops, as though
+			// it had crashed, leaving its checkpoint behind
+			let file = fs::File::create(&output)?;
+			let mut receiver = Receiver::with_transport(far, &key)?;
+			receiver.set_output_file(&file)?;
+			receiver.set_checkpoint(&saved);
+			receiver.set_resume_timeout(Duration::from_millis(500));
+			receiver.set_reconnect(|_| Err(ProtoError::ConnectTimeout));
+			assert!(receiver.run(&file).is_err(), "the session should not have been resumed");
+
+			let checkpoint = Checkpoint::load(&saved)?.expect("no checkpoint was saved");
+			let bytes = checkpoint.bytes();
+			let mut file = fs::OpenOptions::new().write(true).open(&output)?;
+			file.set_len(bytes)?;
+			file.seek(SeekFrom::End(0))?;
+
+			// a new receiver picks up the session on the sender's next connection
+			let end = accepted.recv_timeout(Duration::from_secs(10)).map_err(|_| ProtoError::ConnectTimeout)?;
+			let mut receiver = Receiver::with_transport(end, &key)?;
+			receiver.set_output_file(&file)?;
+			receiver.set_checkpoint(&saved);
+			receiver.restore(checkpoint)?;
+			receiver.run(&file)?;
+			Ok::<_, ProtoError>(bytes)
+		}
+	});
+
+	let mut sender = Sender::with_transport(Dropping { inner: near, nth: 1300 }, &key).unwrap();
+	sender.set_reconnect(move |_| {
+		let (near, far) = Loopback::pair();
+		dialed.send(far).map_err(|_| ProtoError::ConnectTimeout)?;
+		Ok(Box::new(near) as Box<dyn Transport>)
+	});
+
+	let events = sender.subscribe();
+	let sent = sender.run(Cursor::new(payload.clone()));
+	let restored = receiving.join().unwrap();
+	let written = fs::read(&output);
+	let leftover = saved.exists();
+	let _ = (fs::remove_file(&output), fs::remove_file(&saved));
+
+	sent.expect("sender failed");
+	let bytes = restored.expect("receiver failed");
+	assert!(bytes > 0 && bytes < payload.len() as u64, "the checkpoint was saved after {} bytes", bytes);
+	assert!(written.unwrap() == payload, "payload was corrupted");
+	assert!(!leftover, "the checkpoint was left behind once the session was over");
+
+	let resumed = events.try_iter().filter(|event| matches!(event, Event::Resumed { .. })).count();
+	assert_eq!(resumed, 1, "the session should have been resumed once");
+}
+
+/// The sender's end of a connection which it moves away from after its
+/// `nth` full block, as a sender which changed networks would. The receiver
+/// is not told: its end stays open, & silent, until it hangs up on it.
+struct Wandering {
+	inner: Option<Loopback>,
+	left: Arc<Mutex<Option<Loopback>>>,
+	nth: usize,
+}
+
+impl Transport for Wandering {
+	fn close(&mut self) -> Result<(), ProtoError> { self.inner.as_mut().map_or(Ok(()), Loopback::close) }
+	fn has_pending(&mut self) -> bool { self.inner.as_mut().is_some_and(Loopback::has_pending) }
+}
+
+impl Read for Wandering {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.inner.as_mut().map_or(Ok(0), |inner| inner.read(buf))
+	}
+}
+
+impl Write for Wandering {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if buf.len() > BLOCK_SIZE {
+			self.nth = self.nth.saturating_sub(1);
+			if self.nth == 0 {
+				*self.left.lock().unwrap() = self.inner.take();
+			}
+		}
+
+		match self.inner.as_mut() {
+			Some(inner) => inner.write(buf),
+			None => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+/// The receiver's end of a `Wandering` connection, which it can hang up on
+/// from another thread.
+struct Abandoned {
+	inner: Loopback,
+	left: Arc<Mutex<Option<Loopback>>>,
+}
+
+impl Transport for Abandoned {
+	fn close(&mut self) -> Result<(), ProtoError> { self.inner.close() }
+	fn has_pending(&mut self) -> bool { self.inner.has_pending() }
+
+	fn hangup_handle(&self) -> Option<Hangup> {
+		let left = Arc::clone(&self.left);
+		Some(Box::new(move || drop(left.lock().unwrap().take())))
+	}
+}
+
+impl Read for Abandoned {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.inner.read(buf) }
+}
+
+impl Write for Abandoned {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.inner.write(buf) }
+	fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+#[test]
+fn migrated_session_moves_to_the_new_connection() {
+	let key = random_bytes(32);
+	let payload = random_bytes(64 * BLOCK_SIZE + 3);
+	let (near, far) = Loopback::pair();
+	let left = Arc::new(Mutex::new(None));
+	let (dialed, accepted) = mpsc::channel::<Loopback>();
+
+	// the receiver would wait on the old connection forever, were it not
+	// still accepting the sender in the background
+	let receiving = thread::spawn({
+		let (key, left) = (key.clone(), Arc::clone(&left));
+		move || {
+			let mut output = vec![];
+			let mut receiver = Receiver::with_transport(Abandoned { inner: far, left }, &key)?;
+			receiver.set_migrate(true);
+			receiver.set_reconnect(move |timeout| {
+				let end = accepted.recv_timeout(timeout).map_err(|_| ProtoError::ConnectTimeout)?;
+				Ok(Box::new(end) as Box<dyn Transport>)
+			});
+
+			receiver.run(&mut output)?;
+			Ok::<_, ProtoError>(output)
+		}
+	});
+
+	let mut sender = Sender::with_transport(Wandering { inner: Some(near), left, nth: 40 }, &key).unwrap();
+	sender.set_rekey_interval(16 * BLOCK_SIZE as u64);
+	sender.set_reconnect(move |_| {
+		let (near, far) = Loopback::pair();
+		dialed.send(far).map_err(|_| ProtoError::ConnectTimeout)?;
+		Ok(Box::new(near) as Box<dyn Transport>)
+	});
+
+	let events = sender.subscribe();
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let resumed = events.try_iter().filter(|event| matches!(event, Event::Resumed { .. })).count();
+	assert_eq!(resumed, 1, "the session should have been resumed once");
+
+	let received = receiving.join().unwrap().expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+}
+
+#[test]
+fn replayed_resume_is_refused() {
+	let key = random_bytes(32);
+	let payload = random_bytes(96 * BLOCK_SIZE);
+	let (near, far) = Loopback::pair();
+
+	// what the sender wrote on the connection it first resumed the session on
+	let resumed = Arc::new(Mutex::new(vec![]));
+	let (dialed, accepted) = mpsc::channel::<Loopback>();
+
+	let receiving = thread::spawn({
+		let (key, resumed) = (key.clone(), Arc::clone(&resumed));
+		move || {
+			let mut output = vec![];
+			let mut receiver = Receiver::with_transport(far, &key)?;
+			receiver.set_resume_timeout(Duration::from_secs(10));
+
+			// the second time the connection drops, someone who watched the
+			// first resume gets in first & replays what the sender sent
+			let mut reconnects = 0;
+			let replayed = Arc::new(Mutex::new(None));
+			let replaying = Arc::clone(&replayed);
+			receiver.set_reconnect(move |timeout| {
+				let mut replaying = replaying.lock().unwrap();
+				if reconnects == 1 && replaying.is_none() {
+					let resumed = resumed.lock().unwrap().clone();
+					let (mut near, far) = Loopback::pair();
+
+					// the banner & the `Resume` which follows it
+					let len = BANNER_LEN + MESSAGE_SIZE;
+					let len = len + u32::from_be_bytes(resumed[len - 12..len - 8].try_into().unwrap()) as usize;
+
+					*replaying = Some(thread::spawn(move || {
+						near.write_all(&resumed[..len]).unwrap();
+						let mut answer = vec![];
+						near.read_to_end(&mut answer).unwrap();
+						answer
+					}));
+
+					return Ok(Box::new(far) as Box<dyn Transport>);
+				}
+
+				let end = accepted.recv_timeout(timeout).map_err(|_| ProtoError::ConnectTimeout)?;
+				reconnects += 1;
+				Ok(Box::new(end) as Box<dyn Transport>)
+			});
+
+			receiver.run(&mut output)?;
+			let answer = replayed.lock().unwrap().take().expect("the resume was not replayed").join().unwrap();
+			Ok::<_, ProtoError>((output, answer))
+		}
+	});
+
+	let mut sender = Sender::with_transport(Dropping { inner: near, nth: 30 }, &key).unwrap();
+	sender.set_resume_timeout(Duration::from_secs(10));
+
+	let mut reconnects = 0;
+	sender.set_reconnect(move |_| {
+		let (near, far) = Loopback::pair();
+		dialed.send(far).map_err(|_| ProtoError::ConnectTimeout)?;
+		reconnects += 1;
+
+		if reconnects == 1 {
+			let recording = Recording { inner: near, written: Arc::clone(&resumed) };
+			return Ok(Box::new(Dropping { inner: recording, nth: 30 }) as Box<dyn Transport>);
+		}
+
+		Ok(Box::new(near) as Box<dyn Transport>)
+	});
+
+	let events = sender.subscribe();
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let resumes = events.try_iter().filter(|event| matches!(event, Event::Resumed { .. })).count();
+	assert_eq!(resumes, 2, "the session should have been resumed twice");
+
+	let (received, answer) = receiving.join().unwrap().expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+
+	// the receiver's banner & challenge, but no answer
+	assert_eq!(answer.len(), BANNER_LEN + MESSAGE_SIZE + 16, "the replayed resume was answered");
+}
+
+/// Runs a session which sends `payload`, returning a copy of everything the
+/// sender & the receiver wrote to each other.
+fn recorded_transfer<F, G>(key: &[u8], payload: &[u8], configure_sender: F, configure_receiver: G) -> (Vec<u8>, Vec<u8>)
+where F: FnOnce(&mut Sender), G: FnOnce(&mut Receiver) + Send + 'static {
+	let (near, far) = Loopback::pair();
+	let (sent, received) = (Arc::new(Mutex::new(vec![])), Arc::new(Mutex::new(vec![])));
+
+	let receiving = thread::spawn({
+		let (key, written) = (key.to_vec(), received.clone());
+		move || {
+			let mut output = vec![];
+			let mut receiver = Receiver::with_transport(Recording { inner: far, written }, &key)?;
+			configure_receiver(&mut receiver);
+			receiver.run(&mut output)?;
+			Ok::<_, ProtoError>(output)
+		}
+	});
+
+	let mut sender = Sender::with_transport(Recording { inner: near, written: sent.clone() }, key).unwrap();
+	configure_sender(&mut sender);
+	sender.run(Cursor::new(payload.to_vec())).expect("sender failed");
+	assert!(receiving.join().unwrap().expect("receiver failed") == payload, "payload was corrupted");
+
+	let sent = sent.lock().unwrap().clone();
+	let received = received.lock().unwrap().clone();
+	(sent, received)
+}
+
+#[test]
+fn capture_is_decoded() {
+	let key = random_bytes(32);
+	let payload = b"a capture of a session".to_vec();
+	let (sent, received) = recorded_transfer(&key, &payload, |sender| sender.set_rekey_interval(4), |_| {});
+
+	// w/o the key only the headers & plaintext are described
+	let mut out = vec![];
+	let count = CaptureDecoder::new().decode(&sent[..], &mut out).unwrap();
+	let out = String::from_utf8(out).unwrap();
+	assert!(count >= 4, "expected the whole session, got:\n{}", out);
+	assert!(out.starts_with("         0  banner"), "got:\n{}", out);
+	assert!(out.contains("ReqIV") && out.contains("sealed (no key was given)"), "got:\n{}", out);
+
+	// the receiver's capture teaches the decoder its IV, which opens the sender's
+	let mut decoder = CaptureDecoder::new();
+	decoder.set_key(&key).unwrap();
+	decoder.set_preview(payload.len());
+
+	let mut out = vec![];
+	decoder.decode(&received[..], &mut out).unwrap();
+	decoder.decode(&sent[..], &mut out).unwrap();
+	let out = String::from_utf8(out).unwrap();
+
+	let preview: String = payload.iter().map(|byte| format!("{:02x}", byte)).collect();
+	assert!(out.contains("RepIV") && out.contains("ReKey"), "got:\n{}", out);
+	assert!(out.contains(&format!("opened: {}", preview)), "the block was not opened, got:\n{}", out);
+	assert!(out.contains(&format!("{} bytes in 1 blocks", payload.len())), "the goodbye was not opened, got:\n{}", out);
+	assert!(!out.contains("could not be opened"), "got:\n{}", out);
+}
+
+#[test]
+fn identities_seal_the_session_under_a_new_key() {
+	let key = random_bytes(32);
+	let identity = Identity::from_pkcs8(&Identity::generate().unwrap()).unwrap();
+	let payload = b"a session only the two peers can read".to_vec();
+	let (sent, received) = recorded_transfer(&key, &payload, |sender| sender.set_identity(identity), |_| {});
+
+	let mut decoder = CaptureDecoder::new();
+	decoder.set_key(&key).unwrap();
+	decoder.set_preview(payload.len());
+
+	let mut out = vec![];
+	decoder.decode(&received[..], &mut out).unwrap();
+	decoder.decode(&sent[..], &mut out).unwrap();
+	let out = String::from_utf8(out).unwrap();
+
+	// the hellos are sealed under the shared key, the data is not
+	let preview: String = payload.iter().map(|byte| format!("{:02x}", byte)).collect();
+	assert!(out.contains("Hello") && out.contains("magic"), "the hello was not opened, got:\n{}", out);
+	assert!(out.contains("sealed (the key was mixed"), "got:\n{}", out);
+	assert!(!out.contains(&preview), "the block was opened w/ the shared key, got:\n{}", out);
+}
+
+#[test]
+fn seeded_sessions_are_reproducible() {
+	let key = [7u8; 32];
+	let payload = vec![0x5a; 3 * BLOCK_SIZE + 11];
+
+	let seeded = |sender_seed: u64| recorded_transfer(&key, &payload, |sender| {
+		sender.set_seed(sender_seed);
+		sender.set_cipher(Cipher::ChaCha20Poly1305).unwrap();
+		sender.set_rekey_interval(2 * BLOCK_SIZE as u64);
+	}, |receiver| receiver.set_seed(2));
+
+	let (sent, received) = seeded(1);
+	let (sent_again, received_again) = seeded(1);
+	assert!(sent == sent_again, "the sender's transcript changed between runs");
+	assert!(received == received_again, "the receiver's transcript changed between runs");
+
+	let (sent_other, _) = seeded(3);
+	assert!(sent != sent_other, "the sender's seed made no difference");
+}
@@ -0,0 +1,55 @@
+//! Runs a session over UDT datagram sockets on the loopback interface, which
+//! carry each message of the protocol as a single UDT message.
+
+extern crate rand;
+extern crate ubuffer;
+
+use rand::RngCore;
+use std::io::Cursor;
+use std::thread;
+use std::time::Duration;
+use ubuffer::error::ProtoError;
+use ubuffer::proto::{Receiver, Sender, StreamOpts, UdtMode, BLOCK_SIZE};
+
+mod common;
+
+/// Sends a random payload from a sender using `opts` to a receiver which
+/// only asks for message sockets.
+fn round_trip(opts: StreamOpts) {
+	let mut key = vec![0u8; 32];
+	let mut payload = vec![0u8; 256 * BLOCK_SIZE + 17];
+	rand::thread_rng().fill_bytes(&mut key);
+	rand::thread_rng().fill_bytes(&mut payload);
+
+	let addr = common::free_addr();
+
+	let recv_key = key.clone();
+	let recv_opts = StreamOpts { udt_mode: UdtMode::Message, ..StreamOpts::default() };
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		let mut receiver = Receiver::new(addr, &recv_key, &recv_opts)?;
+		receiver.run(&mut output)?;
+		Ok::<_, ProtoError>(output)
+	});
+
+	let mut sender = Sender::new(addr, &key, &opts).expect("could not connect");
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let received = receiving.join().expect("receiver thread panicked").expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+}
+
+#[test]
+fn message_round_trip() {
+	round_trip(StreamOpts { udt_mode: UdtMode::Message, ..StreamOpts::default() });
+}
+
+#[test]
+fn unordered_message_round_trip() {
+	round_trip(StreamOpts {
+		udt_mode: UdtMode::Message,
+		message_ttl: Some(Duration::from_secs(1)),
+		unordered: true,
+		..StreamOpts::default()
+	});
+}
@@ -0,0 +1,44 @@
+//! Runs a session striped across two UDT connections on the loopback
+//! interface, one from each of two local addresses.
+//! (Requires the `udt` feature.)
+#![cfg(feature = "udt")]
+
+extern crate rand;
+extern crate ubuffer;
+
+use rand::RngCore;
+use std::io::Cursor;
+use std::thread;
+use ubuffer::error::ProtoError;
+use ubuffer::proto::{Receiver, Sender, StreamOpts};
+
+mod common;
+
+#[test]
+fn striped_round_trip() {
+	let mut key = vec![0u8; 32];
+	let mut payload = vec![0u8; 4 * 1024 * 1024];
+	rand::thread_rng().fill_bytes(&mut key);
+	rand::thread_rng().fill_bytes(&mut payload);
+
+	let addr = common::free_addr();
+
+	let recv_key = key.clone();
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		let mut receiver = Receiver::new(addr, &recv_key, &StreamOpts::default())?;
+		receiver.run(&mut output)?;
+		Ok::<_, ProtoError>(output)
+	});
+
+	let opts = StreamOpts {
+		paths: vec!["127.0.0.1:0".parse().unwrap(), "127.0.0.2:0".parse().unwrap()],
+		..StreamOpts::default()
+	};
+
+	let mut sender = Sender::new(addr, &key, &opts).expect("could not connect");
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let received = receiving.join().expect("receiver thread panicked").expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+}
@@ -0,0 +1,48 @@
+//! Runs a session from a sender to a receiver through a `Relay`, w/ all three
+//! on the loopback interface. (Requires the `udt` feature.)
+#![cfg(feature = "udt")]
+
+extern crate rand;
+extern crate ubuffer;
+
+use rand::RngCore;
+use std::io::Cursor;
+use std::thread;
+use ubuffer::error::ProtoError;
+use ubuffer::proto::{Receiver, Relay, Sender, StreamOpts};
+
+mod common;
+
+#[test]
+fn relayed_round_trip() {
+	let mut key = vec![0u8; 32];
+	let mut payload = vec![0u8; 4 * 1024 * 1024];
+	rand::thread_rng().fill_bytes(&mut key);
+	rand::thread_rng().fill_bytes(&mut payload);
+
+	let recv_addr = common::free_addr();
+	let relay_addr = common::free_addr();
+
+	let recv_key = key.clone();
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		let mut receiver = Receiver::new(recv_addr, &recv_key, &StreamOpts::default())?;
+		receiver.run(&mut output)?;
+		Ok::<_, ProtoError>(output)
+	});
+
+	// the relay dials the receiver before it listens for the sender
+	let relaying = thread::spawn(move || {
+		Relay::new(relay_addr, recv_addr, &StreamOpts::default())?.run()
+	});
+
+	let mut sender = Sender::new(relay_addr, &key, &StreamOpts::default()).expect("could not connect");
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let received = receiving.join().expect("receiver thread panicked").expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+
+	let stats = relaying.join().expect("relay thread panicked").expect("relay failed");
+	assert!(stats.forwarded > payload.len() as u64, "the relay forwarded {} bytes, less than the payload", stats.forwarded);
+	assert!(stats.returned > 0, "the relay returned nothing from the receiver");
+}
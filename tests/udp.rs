@@ -0,0 +1,180 @@
+//! Runs a session over the UDP transport on the loopback interface, w/ some
+//! of the packets dropped & reordered so that retransmission is exercised.
+
+extern crate rand;
+extern crate ubuffer;
+
+use rand::RngCore;
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use ubuffer::error::ProtoError;
+use ubuffer::proto::{Cidr, Congestion, Controller, FixedWindow, Impairment, Receiver, Sender, StreamOpts, TransportKind};
+
+mod common;
+
+#[test]
+fn lossy_round_trip() {
+	assert_lossy_round_trip(Congestion::Adaptive);
+}
+
+#[test]
+fn lossy_round_trip_w_fixed_window() {
+	assert_lossy_round_trip(Congestion::Fixed);
+}
+
+/// A fixed window which counts the losses it is told about.
+struct CountingWindow {
+	inner: FixedWindow,
+	losses: Arc<AtomicUsize>,
+}
+
+impl Controller for CountingWindow {
+	fn window(&self) -> usize {
+		self.inner.window()
+	}
+
+	fn on_ack(&mut self, acked: usize, rtt: Option<Duration>) {
+		self.inner.on_ack(acked, rtt);
+	}
+
+	fn on_loss(&mut self, lost: usize, rtt: Option<Duration>) {
+		self.losses.fetch_add(lost, Ordering::SeqCst);
+		self.inner.on_loss(lost, rtt);
+	}
+}
+
+#[test]
+fn lossy_round_trip_w_custom_controller() {
+	let losses = Arc::new(AtomicUsize::new(0));
+	let counted = Arc::clone(&losses);
+
+	assert_lossy_round_trip(Congestion::Custom(Arc::new(move || {
+		Box::new(CountingWindow { inner: FixedWindow(32), losses: Arc::clone(&counted) })
+	})));
+
+	assert!(losses.load(Ordering::SeqCst) > 0, "the controller was never told of a loss");
+}
+
+#[test]
+#[cfg(feature = "udt-sys")]
+fn fixed_window_is_refused_over_udt() {
+	let key = vec![0u8; 32];
+	let opts = StreamOpts { transport: TransportKind::Udt, congestion: Congestion::Fixed, ..StreamOpts::default() };
+
+	match Sender::new("127.0.0.1:9", &key, &opts) {
+		Err(ProtoError::InvalidArgument { .. }) => {},
+		Err(err) => panic!("expected the fixed window to be refused, got {}", err),
+		Ok(_) => panic!("expected the fixed window to be refused"),
+	}
+}
+
+fn assert_lossy_round_trip(congestion: Congestion) {
+	let mut key = vec![0u8; 32];
+	let mut payload = vec![0u8; 2 * 1024 * 1024];
+	rand::thread_rng().fill_bytes(&mut key);
+	rand::thread_rng().fill_bytes(&mut payload);
+
+	let addr = common::free_addr();
+
+	let opts = StreamOpts {
+		impairment: Some("loss=5%,reorder=5%".parse::<Impairment>().unwrap()),
+		transport: TransportKind::Udp,
+		congestion,
+		..StreamOpts::default()
+	};
+
+	let recv_key = key.clone();
+	let recv_opts = opts.clone();
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		let mut receiver = Receiver::new(addr, &recv_key, &recv_opts)?;
+		receiver.run(&mut output)?;
+		Ok::<_, ubuffer::error::ProtoError>(output)
+	});
+
+	let mut sender = Sender::new(addr, &key, &opts).expect("could not connect");
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let received = receiving.join().expect("receiver thread panicked").expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+
+	// the dropped packets had to be sent again
+	let stats = sender.stats().expect("the udp transport reports its stats");
+	assert!(stats.rtt.is_some());
+	assert!(stats.retransmitted_packets > 0);
+	assert_eq!(sender.summary().link, Some(stats));
+}
+
+#[test]
+#[cfg(not(feature = "udt"))]
+fn udt_is_refused_wo_the_feature() {
+	let key = vec![0u8; 32];
+	let opts = StreamOpts { transport: TransportKind::Udt, ..StreamOpts::default() };
+	assert_eq!(TransportKind::default(), TransportKind::Udp);
+
+	match Sender::new("127.0.0.1:9", &key, &opts) {
+		Err(ProtoError::InvalidArgument { .. }) => {},
+		Err(err) => panic!("expected udt to be refused, got {}", err),
+		Ok(_) => panic!("expected udt to be refused"),
+	}
+}
+
+#[test]
+fn disallowed_peer_is_ignored() {
+	let key = vec![7u8; 32];
+	let addr = common::free_addr();
+
+	let opts = StreamOpts {
+		transport: TransportKind::Udp,
+		accept_timeout: Some(Duration::from_secs(1)),
+		..StreamOpts::default()
+	};
+
+	let recv_opts = StreamOpts {
+		allow: vec!["10.0.0.0/8".parse::<Cidr>().unwrap(), "::1".parse::<Cidr>().unwrap()],
+		..opts.clone()
+	};
+
+	let receiving = thread::spawn(move || Receiver::new(addr, &key, &recv_opts).map(|_| ()));
+
+	// the sender is left trying to reach the receiver, it is never answered
+	thread::spawn(move || Sender::new(addr, &[7u8; 32], &opts).map(|_| ()));
+
+	match receiving.join().expect("receiver thread panicked") {
+		Err(ProtoError::ConnectTimeout) => {},
+		other => panic!("expected the sender to be ignored, got {:?}", other),
+	}
+}
+
+#[test]
+fn receiver_accepts_on_any_address() {
+	let mut key = vec![0u8; 32];
+	let mut payload = vec![0u8; 64 * 1024];
+	rand::thread_rng().fill_bytes(&mut key);
+	rand::thread_rng().fill_bytes(&mut payload);
+
+	let addrs = [common::free_addr(), common::free_addr()];
+	let opts = StreamOpts {
+		transport: TransportKind::Udp,
+		..StreamOpts::default()
+	};
+
+	let recv_key = key.clone();
+	let recv_opts = opts.clone();
+	let receiving = thread::spawn(move || {
+		let mut output = vec![];
+		let mut receiver = Receiver::new(&addrs[..], &recv_key, &recv_opts)?;
+		receiver.run(&mut output)?;
+		Ok::<_, ProtoError>(output)
+	});
+
+	// the sender only knows about the second address
+	let mut sender = Sender::new(addrs[1], &key, &opts).expect("could not connect");
+	sender.run(Cursor::new(payload.clone())).expect("sender failed");
+
+	let received = receiving.join().expect("receiver thread panicked").expect("receiver failed");
+	assert!(received == payload, "payload was corrupted");
+}